@@ -1,14 +1,12 @@
 use log::*;
 use std::net::*;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let handler =
-        Arc::new(Mutex::new(Box::new(usbip::cdc::UsbCdcAcmHandler::new())
-            as Box<dyn usbip::UsbInterfaceHandler + Send>));
+    let handler = usbip::shared_interface_handler(usbip::cdc::UsbCdcAcmHandler::new());
     let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
         usbip::UsbDevice::new(0).with_interface(
             usbip::ClassCode::CDC as u8,
@@ -25,7 +23,7 @@ async fn main() {
     loop {
         // sleep 1s
         tokio::time::sleep(Duration::new(1, 0)).await;
-        let mut handler = handler.lock().unwrap();
+        let mut handler = handler.lock().await;
         if let Some(acm) = handler
             .as_any()
             .downcast_mut::<usbip::cdc::UsbCdcAcmHandler>()
@@ -1,15 +1,13 @@
 use log::*;
 use std::net::*;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let handler = Arc::new(Mutex::new(
-        Box::new(usbip::hid::UsbHidKeyboardHandler::new_keyboard())
-            as Box<dyn usbip::UsbInterfaceHandler + Send>,
-    ));
+    let handler =
+        usbip::shared_interface_handler(usbip::hid::UsbHidKeyboardHandler::new_keyboard());
     let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
         usbip::UsbDevice::new(0).with_interface(
             usbip::ClassCode::HID as u8,
@@ -31,7 +29,7 @@ async fn main() {
     loop {
         // sleep 1s
         tokio::time::sleep(Duration::new(1, 0)).await;
-        let mut handler = handler.lock().unwrap();
+        let mut handler = handler.lock().await;
         if let Some(hid) = handler
             .as_any()
             .downcast_mut::<usbip::hid::UsbHidKeyboardHandler>()
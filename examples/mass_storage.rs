@@ -0,0 +1,23 @@
+use std::net::*;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Usage: mass_storage [image], a 16 MiB drive in memory without an image
+///
+/// An image ending in `.iso` is served as a CD-ROM.
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let handler = match std::env::args().nth(1).map(PathBuf::from) {
+        Some(path) if path.extension().is_some_and(|ext| ext == "iso") => {
+            usbip::msc::UsbMassStorageHandler::open_iso(&path).expect("ISO image")
+        }
+        Some(path) => usbip::msc::UsbMassStorageHandler::open(&path, false).expect("disk image"),
+        None => usbip::msc::UsbMassStorageHandler::from_memory(vec![0; 16 << 20]),
+    };
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
+        usbip::UsbDevice::mass_storage(handler),
+    ]));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    usbip::server(addr, server).await;
+}
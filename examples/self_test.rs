@@ -0,0 +1,18 @@
+use std::net::*;
+
+/// Usage: self_test [--self-test] [address:port], port 0 picks a free one
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let addr = std::env::args()
+        .skip(1)
+        .find(|arg| arg != "--self-test")
+        .map(|arg| arg.parse().expect("address:port"))
+        .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+
+    let report = usbip::self_test(addr).await;
+    println!("{report}");
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use usbip::usbip_protocol::{Parsed, UsbIpCommand};
+
+fuzz_target!(|command: UsbIpCommand| {
+    let bytes = command.to_bytes();
+    let len = bytes.len();
+    assert_eq!(
+        UsbIpCommand::parse(&bytes),
+        Ok(Parsed::Complete(command, len))
+    );
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use usbip::{ReplayOutcome, UsbDevice, replay};
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let devices = vec![UsbDevice::sample_composite()];
+    // long replies are fine, the limit only bounds memory
+    let outcome = runtime.block_on(replay(data.to_vec(), devices, 0x1000000));
+    if let ReplayOutcome::Panicked(message) = outcome {
+        panic!("{message}");
+    }
+});
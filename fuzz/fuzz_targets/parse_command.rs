@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use usbip::usbip_protocol::{Parsed, UsbIpCommand};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Parsed::Complete(command, len)) = UsbIpCommand::parse(data) {
+        assert!(len <= data.len());
+        // parsing is lenient, e.g. about the version, so only the length is kept
+        assert_eq!(command.to_bytes().len(), len);
+    }
+});
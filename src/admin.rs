@@ -0,0 +1,360 @@
+//! HTTP/JSON management of a running server
+//!
+//! [UsbIpAdmin] serves these endpoints for orchestration tools:
+//!
+//! - `GET /devices`: available and imported devices, with their clients
+//! - `POST /devices`: add a device made by the device factory from the body
+//! - `DELETE /devices/{bus_id}`: remove an available device
+//! - `POST /devices/{bus_id}/detach`: take an imported device from its client
+//! - `GET /stats`: [ServerStats] with latencies in microseconds
+//!
+//! Errors are answered with `{"error": "..."}`. The endpoint has no
+//! encryption, so bind it to the loopback interface or set a bearer token.
+use super::*;
+use crate::http::{HttpRequest, read_request, write_response};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Makes a device from the body of `POST /devices`
+type DeviceFactory = dyn Fn(&[u8]) -> core::result::Result<UsbDevice, String> + Send + Sync;
+
+/// Admin endpoint of a [UsbIpServer]
+pub struct UsbIpAdmin {
+    server: Arc<UsbIpServer>,
+    device_factory: Option<Box<DeviceFactory>>,
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeviceJson<'a> {
+    bus_id: &'a str,
+    vendor_id: u16,
+    product_id: u16,
+    tag: Option<&'a str>,
+}
+
+impl<'a> From<&'a UsbDevice> for DeviceJson<'a> {
+    fn from(device: &'a UsbDevice) -> Self {
+        Self {
+            bus_id: &device.bus_id,
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            tag: device.tag.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImportJson<'a> {
+    #[serde(flatten)]
+    device: DeviceJson<'a>,
+    peer: Option<SocketAddr>,
+    /// Seconds since the Unix epoch
+    imported_at: u64,
+    submitted_urbs: u64,
+    failed_urbs: u64,
+    unlinked_urbs: u64,
+}
+
+#[derive(Serialize)]
+struct DevicesJson<'a> {
+    available: Vec<DeviceJson<'a>>,
+    used: Vec<ImportJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct UrbStatsJson {
+    submits: u64,
+    completions: u64,
+    errors: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    latency_p50_us: u128,
+    latency_p99_us: u128,
+}
+
+impl From<&UrbStats> for UrbStatsJson {
+    fn from(stats: &UrbStats) -> Self {
+        Self {
+            submits: stats.submits,
+            completions: stats.completions,
+            errors: stats.errors,
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+            latency_p50_us: stats.latency_p50.as_micros(),
+            latency_p99_us: stats.latency_p99.as_micros(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceStatsJson<'a> {
+    bus_id: &'a str,
+    #[serde(flatten)]
+    urbs: UrbStatsJson,
+}
+
+#[derive(Serialize)]
+struct ConnectionStatsJson {
+    id: u64,
+    peer: Option<SocketAddr>,
+    #[serde(flatten)]
+    urbs: UrbStatsJson,
+}
+
+#[derive(Serialize)]
+struct StatsJson<'a> {
+    devices: Vec<DeviceStatsJson<'a>>,
+    connections: Vec<ConnectionStatsJson>,
+}
+
+/// Status and JSON body of a response
+type Response = (u16, serde_json::Value);
+
+fn error(status: u16, message: impl std::fmt::Display) -> Response {
+    (status, serde_json::json!({ "error": message.to_string() }))
+}
+
+fn json(value: impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+impl UsbIpAdmin {
+    pub fn new(server: Arc<UsbIpServer>) -> Self {
+        Self {
+            server,
+            device_factory: None,
+            token: None,
+        }
+    }
+
+    /// Enable `POST /devices`, which answers 501 otherwise
+    ///
+    /// `factory` gets the request body, and its error is sent back with
+    /// status 400.
+    pub fn with_device_factory(
+        mut self,
+        factory: impl Fn(&[u8]) -> core::result::Result<UsbDevice, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.device_factory = Some(Box::new(factory));
+        self
+    }
+
+    /// Only answer requests with `Authorization: Bearer <token>`
+    pub fn with_bearer_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Serve the endpoint at `addr`
+    ///
+    /// Only returns if binding to `addr` fails.
+    pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let admin = Arc::new(self);
+        loop {
+            match listener.accept().await {
+                Ok((mut socket, addr)) => {
+                    trace!("Admin request from {addr:?}");
+                    let admin = admin.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = admin.handle(&mut socket).await {
+                            debug!("Failed to serve admin request: {err}");
+                        }
+                    });
+                }
+                Err(err) => {
+                    warn!("Got error {err:?}");
+                }
+            }
+        }
+    }
+
+    async fn handle<T: AsyncReadExt + AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        let Some(request) = read_request(socket).await? else {
+            return Ok(());
+        };
+        let (status, body) = self.respond(&request).await;
+        let body = serde_json::to_vec(&body).map_err(std::io::Error::other)?;
+        write_response(socket, status, "application/json", &body).await
+    }
+
+    async fn respond(&self, request: &HttpRequest) -> Response {
+        if let Some(token) = &self.token {
+            let expected = format!("Bearer {token}");
+            if request.header("authorization") != Some(expected.as_str()) {
+                return error(401, "Missing or wrong bearer token");
+            }
+        }
+        let segments: Vec<_> = request.path.trim_matches('/').split('/').collect();
+        debug!("Admin request {} {}", request.method, request.path);
+        match (request.method.as_str(), &segments[..]) {
+            ("GET", ["devices"]) => self.devices().await,
+            ("POST", ["devices"]) => self.add_device(&request.body).await,
+            ("DELETE", ["devices", bus_id]) => match self.server.remove_device(bus_id).await {
+                Ok(()) => (200, serde_json::json!({})),
+                Err(err) => error_response(err),
+            },
+            ("POST", ["devices", bus_id, "detach"]) => {
+                match self.server.detach_device(bus_id).await {
+                    Ok(()) => (200, serde_json::json!({})),
+                    Err(err) => error_response(err),
+                }
+            }
+            ("GET", ["stats"]) => self.stats(),
+            (_, ["devices"] | ["devices", _] | ["devices", _, "detach"] | ["stats"]) => {
+                error(405, format!("{} is not allowed", request.method))
+            }
+            _ => error(404, format!("No endpoint at {}", request.path)),
+        }
+    }
+
+    async fn devices(&self) -> Response {
+        let available = self.server.available_devices().await;
+        let used = self.server.used_devices().await;
+        let body = DevicesJson {
+            available: available.iter().map(DeviceJson::from).collect(),
+            used: used
+                .iter()
+                .map(|import| ImportJson {
+                    device: DeviceJson::from(&import.device),
+                    peer: import.peer,
+                    imported_at: import
+                        .imported_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    submitted_urbs: import.submitted_urbs,
+                    failed_urbs: import.failed_urbs,
+                    unlinked_urbs: import.unlinked_urbs,
+                })
+                .collect(),
+        };
+        (200, json(body))
+    }
+
+    async fn add_device(&self, body: &[u8]) -> Response {
+        let Some(factory) = &self.device_factory else {
+            return error(501, "No device factory configured");
+        };
+        let device = match factory(body) {
+            Ok(device) => device,
+            Err(err) => return error(400, err),
+        };
+        let bus_id = device.bus_id.clone();
+        let exists = self
+            .server
+            .available_devices()
+            .await
+            .iter()
+            .chain(self.server.used_devices().await.iter().map(|i| &i.device))
+            .any(|d| d.bus_id == bus_id);
+        if exists {
+            return error(409, format!("Device {bus_id} already exists"));
+        }
+        info!("Adding device {bus_id} through the admin endpoint");
+        self.server.add_device(device).await;
+        (200, serde_json::json!({ "bus_id": bus_id }))
+    }
+
+    fn stats(&self) -> Response {
+        let stats = self.server.stats();
+        let body = StatsJson {
+            devices: stats
+                .devices
+                .iter()
+                .map(|device| DeviceStatsJson {
+                    bus_id: &device.bus_id,
+                    urbs: UrbStatsJson::from(&device.urbs),
+                })
+                .collect(),
+            connections: stats
+                .connections
+                .iter()
+                .map(|connection| ConnectionStatsJson {
+                    id: connection.id,
+                    peer: connection.peer,
+                    urbs: UrbStatsJson::from(&connection.urbs),
+                })
+                .collect(),
+        };
+        (200, json(body))
+    }
+}
+
+fn error_response(err: UsbIpError) -> Response {
+    let status = match err {
+        UsbIpError::DeviceNotFound(_) => 404,
+        UsbIpError::DeviceBusy(_) => 409,
+        _ => 500,
+    };
+    error(status, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    async fn request(admin: &UsbIpAdmin, request: &str) -> (u16, serde_json::Value) {
+        let (mut client, mut socket) = tokio::io::duplex(0x10000);
+        client.write_all(request.as_bytes()).await.unwrap();
+        admin.handle(&mut socket).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn manage_devices() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let admin = UsbIpAdmin::new(server.clone())
+            .with_device_factory(|body| match body {
+                b"composite" => Ok(UsbDevice::sample_composite()),
+                _ => Err("unknown device".to_string()),
+            })
+            .with_bearer_token("secret");
+        let auth = "Authorization: Bearer secret\r\n";
+
+        let (status, _) = request(&admin, "GET /devices HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, 401);
+
+        let add = format!("POST /devices HTTP/1.1\r\n{auth}Content-Length: 9\r\n\r\ncomposite");
+        let (status, body) = request(&admin, &add).await;
+        assert_eq!(status, 200);
+        let bus_id = body["bus_id"].as_str().unwrap().to_string();
+        let (status, _) = request(&admin, &add).await;
+        assert_eq!(status, 409);
+        let bad = format!("POST /devices HTTP/1.1\r\n{auth}Content-Length: 3\r\n\r\nfoo");
+        assert_eq!(request(&admin, &bad).await.0, 400);
+
+        let (status, body) = request(&admin, &format!("GET /devices HTTP/1.1\r\n{auth}\r\n")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["available"][0]["bus_id"], bus_id.as_str());
+        assert_eq!(body["available"][0]["vendor_id"], 0x1209);
+        assert!(body["used"].as_array().unwrap().is_empty());
+
+        let detach = format!("POST /devices/{bus_id}/detach HTTP/1.1\r\n{auth}\r\n");
+        assert_eq!(request(&admin, &detach).await.0, 200);
+        let (status, body) = request(&admin, &format!("GET /stats HTTP/1.1\r\n{auth}\r\n")).await;
+        assert_eq!(status, 200);
+        assert!(body["connections"].as_array().unwrap().is_empty());
+
+        let delete = format!("DELETE /devices/{bus_id} HTTP/1.1\r\n{auth}\r\n");
+        assert_eq!(request(&admin, &delete).await.0, 200);
+        assert_eq!(request(&admin, &delete).await.0, 404);
+        assert!(server.available_devices().await.is_empty());
+
+        let put = format!("PUT /devices HTTP/1.1\r\n{auth}\r\n");
+        assert_eq!(request(&admin, &put).await.0, 405);
+        let other = format!("GET /other HTTP/1.1\r\n{auth}\r\n");
+        assert_eq!(request(&admin, &other).await.0, 404);
+    }
+}
@@ -0,0 +1,115 @@
+//! Framing of USB/IP packets for tokio-util
+//!
+//! [UsbIpCodec] decodes commands and encodes responses, so a connection can
+//! be wrapped in a `Framed` stream instead of calling
+//! [UsbIpCommand::read_from_socket] and [UsbIpResponse::write_to_socket].
+use crate::UsbIpError;
+use crate::usbip_protocol::{Parsed, UsbIpCommand, UsbIpResponse};
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Codec for the server side of a USB/IP connection
+///
+/// Commands can be encoded too, for clients and tests. Responses cannot be
+/// decoded because their layout depends on the command they answer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UsbIpCodec;
+
+impl UsbIpCodec {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Decoder for UsbIpCodec {
+    type Item = UsbIpCommand;
+    type Error = UsbIpError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<UsbIpCommand>, UsbIpError> {
+        match UsbIpCommand::parse(src)? {
+            Parsed::Complete(command, len) => {
+                src.advance(len);
+                Ok(Some(command))
+            }
+            Parsed::Incomplete(len) => {
+                src.reserve(len - src.len());
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<UsbIpResponse> for UsbIpCodec {
+    type Error = UsbIpError;
+
+    fn encode(&mut self, item: UsbIpResponse, dst: &mut BytesMut) -> Result<(), UsbIpError> {
+        let bytes = item.to_bytes();
+        dst.extend_from_slice(&bytes);
+        crate::recycle_scratch_buffer(bytes);
+        item.recycle();
+        Ok(())
+    }
+}
+
+impl Encoder<UsbIpCommand> for UsbIpCodec {
+    type Error = UsbIpError;
+
+    fn encode(&mut self, item: UsbIpCommand, dst: &mut BytesMut) -> Result<(), UsbIpError> {
+        dst.extend_from_slice(&item.to_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usbip_protocol::{USBIP_CMD_UNLINK, USBIP_RET_UNLINK, UsbIpHeaderBasic};
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn decode_split_commands() {
+        setup_test_logger();
+        let unlink = UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum: 2,
+                devid: 0,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum: 1,
+        };
+        let mut bytes = UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes();
+        bytes.extend(unlink.to_bytes());
+
+        let mut codec = UsbIpCodec::new();
+        let mut src = BytesMut::from(&bytes[..6]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&bytes[6..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(UsbIpCommand::OpReqDevlist { status: 0 })
+        );
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(unlink));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn encode_response() {
+        setup_test_logger();
+        let header = UsbIpHeaderBasic {
+            command: USBIP_RET_UNLINK.into(),
+            seqnum: 2,
+            devid: 0,
+            direction: 0,
+            ep: 0,
+        };
+        let response = UsbIpResponse::usbip_ret_unlink_success(&header);
+        let mut dst = BytesMut::new();
+        UsbIpCodec::new()
+            .encode(response.clone(), &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..], &response.to_bytes()[..]);
+    }
+}
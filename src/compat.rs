@@ -0,0 +1,49 @@
+//! Running the server on other runtimes
+//!
+//! The server only needs tokio for its I/O traits and locks, which work on any
+//! runtime. [futures_handler] accepts sockets implementing the `futures::io`
+//! traits instead, as provided by async-std and smol.
+use super::*;
+use futures_io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+/// Like [handler](crate::handler), for a socket implementing the `futures::io` traits
+///
+/// [UsbIpDebugDelays] rely on the tokio timer and must stay disabled
+/// outside of a tokio runtime.
+pub async fn futures_handler<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: T,
+    server: Arc<UsbIpServer>,
+) -> core::result::Result<(), UsbIpError> {
+    crate::handler(&mut socket.compat(), server).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usbip_protocol::{UsbIpCommand, UsbIpResponse};
+    use crate::util::tests::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn futures_io_devlist() {
+        setup_test_logger();
+        let (mut client, socket) = tokio::io::duplex(0x1000);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let task = tokio::spawn(futures_handler(socket.compat(), server));
+
+        client
+            .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+            .await
+            .unwrap();
+        let expected = UsbIpResponse::op_rep_devlist(&[]).to_bytes();
+        let mut res = vec![0; expected.len()];
+        client.read_exact(&mut res).await.unwrap();
+        assert_eq!(res, expected);
+
+        drop(client);
+        assert!(task.await.unwrap().is_ok());
+    }
+}
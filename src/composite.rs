@@ -0,0 +1,456 @@
+//! Composite devices
+//!
+//! A composite device combines several functions, e.g. a CDC ACM serial
+//! port and a HID keyboard. [UsbDevice::with_function] takes care of the
+//! parts that must be coherent across functions: interface numbers,
+//! endpoint addresses, interface associations and the device class.
+use super::*;
+
+/// An interface of a [UsbFunction]
+#[derive(Clone, Debug)]
+pub struct UsbFunctionInterface {
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub name: Option<String>,
+    /// Endpoints of the interface, only direction and attributes are kept
+    pub endpoints: Vec<UsbEndpoint>,
+    pub handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+}
+
+/// A function of a composite device, made of one or more interfaces
+#[derive(Clone, Debug)]
+pub struct UsbFunction {
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub name: Option<String>,
+    pub interfaces: Vec<UsbFunctionInterface>,
+}
+
+impl UsbFunction {
+    pub fn new(
+        function_class: u8,
+        function_subclass: u8,
+        function_protocol: u8,
+        name: Option<&str>,
+    ) -> Self {
+        Self {
+            function_class,
+            function_subclass,
+            function_protocol,
+            name: name.map(str::to_string),
+            interfaces: vec![],
+        }
+    }
+
+    /// A function made of a single interface with the same class
+    pub fn single(
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: Option<&str>,
+        endpoints: Vec<UsbEndpoint>,
+        handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        Self::new(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            name,
+        )
+        .with_interface(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            name,
+            endpoints,
+            handler,
+        )
+    }
+
+    pub fn with_interface(
+        mut self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: Option<&str>,
+        endpoints: Vec<UsbEndpoint>,
+        handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        self.interfaces.push(UsbFunctionInterface {
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            name: name.map(str::to_string),
+            endpoints,
+            handler,
+        });
+        self
+    }
+}
+
+impl UsbDevice {
+    /// Create an empty composite device, add functions with [UsbDevice::with_function]
+    pub fn composite(index: u32) -> Self {
+        let mut device = Self::new(index);
+        // Miscellaneous Device Class, Interface Association Descriptor
+        device.device_class = ClassCode::Misc as u8;
+        device.device_subclass = 0x02;
+        device.device_protocol = 0x01;
+        device
+    }
+
+    /// Add a function after the existing interfaces
+    ///
+    /// Interfaces are numbered in order, and endpoints get the next free
+    /// endpoint number of their direction. Functions with more than one
+    /// interface get an interface association.
+    pub fn with_function(mut self, function: UsbFunction) -> Self {
+        let first_interface = self.interfaces.len() as u8;
+        let interface_count = function.interfaces.len() as u8;
+
+        for intf in function.interfaces {
+            let endpoints = self.allocate_endpoints(intf.endpoints);
+            self = self.with_interface(
+                intf.interface_class,
+                intf.interface_subclass,
+                intf.interface_protocol,
+                intf.name.as_deref(),
+                endpoints,
+                intf.handler,
+            );
+        }
+
+        if interface_count > 1 {
+            self = self.with_interface_association(
+                first_interface,
+                interface_count,
+                function.function_class,
+                function.function_subclass,
+                function.function_protocol,
+                function.name.as_deref(),
+            );
+        }
+        self
+    }
+
+    /// A CDC ACM serial port and a HID keyboard in one device
+    ///
+    /// Windows binds its inbox usbser and HID drivers to the two functions,
+    /// and the MS OS 1.0 descriptors list both functions without a compatible
+    /// ID so that no other driver is picked. It also serves as a template for
+    /// building other composite devices.
+    pub fn sample_composite() -> Self {
+        let mut device = Self::composite(0)
+            .with_function(
+                UsbFunction::new(
+                    ClassCode::CDC as u8,
+                    cdc::CDC_ACM_SUBCLASS,
+                    0x00,
+                    Some("Virtual COM Port"),
+                )
+                .with_interface(
+                    ClassCode::CDC as u8,
+                    cdc::CDC_ACM_SUBCLASS,
+                    0x00,
+                    None,
+                    cdc::UsbCdcAcmHandler::endpoints()[..1].to_vec(),
+                    shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
+                )
+                .with_interface(
+                    ClassCode::CDCData as u8,
+                    0x00,
+                    0x00,
+                    None,
+                    cdc::UsbCdcAcmHandler::endpoints()[1..].to_vec(),
+                    shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
+                ),
+            )
+            .with_function(UsbFunction::single(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                Some("Keyboard"),
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 0x08,
+                    interval: 10,
+                }],
+                shared_interface_handler(hid::UsbHidKeyboardHandler::new_keyboard()),
+            ));
+        // first interfaces of the CDC and HID functions
+        let compatible_ids = [0, 2]
+            .into_iter()
+            .map(|first_interface| MsCompatibleId {
+                first_interface,
+                compatible_id: String::new(),
+                sub_compatible_id: String::new(),
+            })
+            .collect();
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0001;
+        device.set_product_name("Virtual COM Port and Keyboard");
+        device.with_ms_os_descriptors(MsOsDescriptors {
+            vendor_code: 0x20,
+            compatible_ids,
+            descriptor_set: None,
+        })
+    }
+
+    /// A device with a CDC ACM serial port of its own for each of `handlers`
+    ///
+    /// Each port is a function of a communication and a data interface
+    /// served by the same handler, e.g. a [cdc::UsbCdcAcmHandler], which
+    /// sees the control requests and the data of its port only. The union
+    /// descriptors tell the host which interfaces belong together. Keep
+    /// clones of the handlers to reach them later.
+    pub fn cdc_acm_ports(
+        handlers: impl IntoIterator<Item = Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+    ) -> Self {
+        let mut device = Self::composite(0);
+        for (i, handler) in handlers.into_iter().enumerate() {
+            let name = format!("Serial Port {}", i + 1);
+            device = device.with_cdc_acm_function(Some(&name), handler);
+        }
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x000E;
+        device.set_product_name("Virtual Serial Ports");
+        device
+    }
+
+    /// Add a CDC ACM function served by `handler`, see [UsbDevice::cdc_acm_ports]
+    pub fn with_cdc_acm_function(
+        mut self,
+        name: Option<&str>,
+        handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        let control_interface = self.interfaces.len() as u8;
+        let data_interface = control_interface + 1;
+        self = self.with_function(
+            UsbFunction::new(ClassCode::CDC as u8, cdc::CDC_ACM_SUBCLASS, 0x00, name)
+                .with_interface(
+                    ClassCode::CDC as u8,
+                    cdc::CDC_ACM_SUBCLASS,
+                    0x00,
+                    None,
+                    cdc::UsbCdcAcmHandler::endpoints()[..1].to_vec(),
+                    handler.clone(),
+                )
+                .with_interface(
+                    ClassCode::CDCData as u8,
+                    0x00,
+                    0x00,
+                    None,
+                    cdc::UsbCdcAcmHandler::endpoints()[1..].to_vec(),
+                    handler,
+                ),
+        );
+        self.interfaces[control_interface as usize]
+            .class_specific_descriptor
+            .extend([
+                // Call Management
+                0x05, // bFunctionLength
+                0x24, // CS_INTERFACE
+                0x01, // Call Management
+                0x00, // Capabilities: no call management
+                data_interface,
+                // Union
+                0x05, // bFunctionLength
+                0x24, // CS_INTERFACE
+                0x06, // Union
+                control_interface,
+                data_interface,
+            ]);
+        // the functional descriptors belong to the communication interface only
+        self.interfaces[data_interface as usize]
+            .class_specific_descriptor
+            .clear();
+        self
+    }
+
+    /// `endpoints` with the next free endpoint numbers of their direction
+    ///
+    /// Only the direction bit of the original addresses is kept.
+    pub(crate) fn allocate_endpoints(&self, endpoints: Vec<UsbEndpoint>) -> Vec<UsbEndpoint> {
+        let mut next_in = self.next_endpoint_number(Direction::In);
+        let mut next_out = self.next_endpoint_number(Direction::Out);
+        endpoints
+            .into_iter()
+            .map(|endpoint| {
+                let next = match endpoint.direction() {
+                    Direction::In => &mut next_in,
+                    Direction::Out => &mut next_out,
+                };
+                assert!(*next <= 15, "Out of {:?} endpoints", endpoint.direction());
+                let address = (endpoint.address & 0x80) | *next;
+                *next += 1;
+                UsbEndpoint {
+                    address,
+                    ..endpoint
+                }
+            })
+            .collect()
+    }
+
+    /// Lowest endpoint number not used by any interface in `direction`
+    fn next_endpoint_number(&self, direction: Direction) -> u8 {
+        let used = self
+            .interfaces
+            .iter()
+            .flat_map(UsbInterface::all_endpoints)
+            .filter(|endpoint| endpoint.direction() == direction)
+            .map(|endpoint| endpoint.address & 0x0F)
+            .max()
+            .unwrap_or(0);
+        assert!(used < 15, "Out of {direction:?} endpoints");
+        used + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn handler() -> Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        Arc::new(AsyncMutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ))
+    }
+
+    #[test]
+    fn build_composite_device() {
+        setup_test_logger();
+        let keyboard = hid::UsbHidKeyboardHandler::new_keyboard();
+        let device = UsbDevice::composite(0)
+            .with_function(
+                UsbFunction::new(ClassCode::CDC as u8, cdc::CDC_ACM_SUBCLASS, 0x00, None)
+                    .with_interface(
+                        ClassCode::CDC as u8,
+                        cdc::CDC_ACM_SUBCLASS,
+                        0x00,
+                        None,
+                        cdc::UsbCdcAcmHandler::endpoints()[..1].to_vec(),
+                        handler(),
+                    )
+                    .with_interface(
+                        ClassCode::CDCData as u8,
+                        0x00,
+                        0x00,
+                        None,
+                        cdc::UsbCdcAcmHandler::endpoints()[1..].to_vec(),
+                        handler(),
+                    ),
+            )
+            .with_function(UsbFunction::single(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                Some("Keyboard"),
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 0x08,
+                    interval: 10,
+                }],
+                Arc::new(AsyncMutex::new(
+                    Box::new(keyboard) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            ));
+
+        assert_eq!(device.device_class, ClassCode::Misc as u8);
+        assert_eq!(device.interfaces.len(), 3);
+        let addresses: Vec<Vec<u8>> = device
+            .interfaces
+            .iter()
+            .map(|intf| intf.endpoints.iter().map(|ep| ep.address).collect())
+            .collect();
+        assert_eq!(addresses, [vec![0x81], vec![0x82, 0x01], vec![0x83]]);
+        for (i, intf) in device.interfaces.iter().enumerate() {
+            assert_eq!(intf.interface_number(), i as u8);
+        }
+
+        // only the CDC function needs an association
+        assert_eq!(device.interface_associations.len(), 1);
+        assert_eq!(device.interface_associations[0].first_interface, 0);
+        assert_eq!(device.interface_associations[0].interface_count, 2);
+    }
+
+    #[test]
+    fn sample_composite_device() {
+        setup_test_logger();
+        let device = UsbDevice::sample_composite();
+        assert_eq!(device.device_class, ClassCode::Misc as u8);
+        assert_eq!(device.interfaces.len(), 3);
+        assert_eq!(device.interfaces[2].interface_class, ClassCode::HID as u8);
+        assert_eq!(device.interface_associations.len(), 1);
+
+        let ms_os = device.ms_os_descriptors.as_ref().unwrap();
+        let firsts: Vec<u8> = ms_os
+            .compatible_ids
+            .iter()
+            .map(|id| id.first_interface)
+            .collect();
+        assert_eq!(firsts, [0, 2]);
+    }
+
+    #[tokio::test]
+    async fn cdc_acm_ports() {
+        setup_test_logger();
+        let handlers = [
+            shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
+            shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
+        ];
+        let device = UsbDevice::cdc_acm_ports(handlers.clone());
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        let addresses: Vec<Vec<u8>> = device
+            .interfaces
+            .iter()
+            .map(|intf| intf.endpoints.iter().map(|ep| ep.address).collect())
+            .collect();
+        assert_eq!(
+            addresses,
+            [vec![0x81], vec![0x82, 0x01], vec![0x83], vec![0x84, 0x02]]
+        );
+        let associations: Vec<(u8, u8)> = device
+            .interface_associations
+            .iter()
+            .map(|iad| (iad.first_interface, iad.interface_count))
+            .collect();
+        assert_eq!(associations, [(0, 2), (2, 2)]);
+
+        // union of the second port
+        assert!(
+            device.interfaces[2]
+                .class_specific_descriptor
+                .ends_with(&[0x05, 0x24, 0x06, 2, 3])
+        );
+        assert!(device.interfaces[3].class_specific_descriptor.is_empty());
+
+        // data of the second port only reaches its handler
+        handlers[1]
+            .lock()
+            .await
+            .as_any()
+            .downcast_mut::<cdc::UsbCdcAcmHandler>()
+            .unwrap()
+            .tx_buffer
+            .extend_from_slice(b"port 2");
+        for (intf, expected) in [(1, &b""[..]), (3, &b"port 2"[..])] {
+            let intf = &device.interfaces[intf];
+            let data = intf
+                .handler
+                .lock()
+                .await
+                .handle_urb(intf, intf.endpoints[0], 512, SetupPacket::default(), &[])
+                .unwrap();
+            assert_eq!(data, expected);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Servers described by a configuration file
+//!
+//! Deployments that only export host devices or built-in simulated devices
+//! need no Rust code of their own:
+//!
+//! ```toml
+//! listen = ["0.0.0.0:3240"]
+//! keepalive_secs = 60
+//!
+//! # export every host device of this vendor, needs the rusb feature
+//! [[host]]
+//! vendor_id = 0x1209
+//!
+//! [[simulated]]
+//! kind = "hid-keyboard"
+//! ```
+//!
+//! [UsbIpServer::from_config] creates the server, and
+//! [UsbIpConfig::builder] the listeners.
+use super::*;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Contents of a configuration file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbIpConfig {
+    /// Addresses to listen on, see [UsbIpServerBuilder::listen]
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+    /// See [UsbIpServer::with_keepalive]
+    pub keepalive_secs: Option<u64>,
+    /// See [UsbIpServer::with_idle_timeout]
+    pub idle_timeout_secs: Option<u64>,
+    /// Host devices matching any of these are exported
+    #[serde(default)]
+    pub host: Vec<HostDeviceFilter>,
+    #[serde(default)]
+    pub simulated: Vec<SimulatedDeviceConfig>,
+}
+
+/// Host devices to export, every given field has to match
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostDeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// Serial number string, devices without one never match
+    pub serial: Option<String>,
+}
+
+/// Built-in simulated devices
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SimulatedDeviceKind {
+    /// [hid::UsbHidKeyboardHandler]
+    HidKeyboard,
+    /// [cdc::UsbCdcAcmHandler]
+    CdcAcm,
+    /// [UsbDevice::sample_composite]
+    Composite,
+}
+
+/// A simulated device to instantiate
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedDeviceConfig {
+    pub kind: SimulatedDeviceKind,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// Bus number and hub ports, see [UsbDevice::with_location]
+    ///
+    /// Defaults to bus 0, port 1 for the first simulated device, port 2 for the second, and so on.
+    pub bus: Option<u32>,
+    pub ports: Option<Vec<u8>>,
+    pub tag: Option<String>,
+    /// Throughput cap, see [UsbLinkShaping]
+    pub bytes_per_second: Option<u64>,
+    /// Latency added to every URB
+    pub latency_ms: Option<u64>,
+}
+
+impl std::str::FromStr for UsbIpConfig {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl UsbIpConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Listeners at the configured addresses
+    pub fn builder(&self) -> UsbIpServerBuilder {
+        self.listen
+            .iter()
+            .fold(UsbIpServerBuilder::new(), |builder, &addr| {
+                builder.listen(addr)
+            })
+    }
+
+    /// Create the configured server
+    pub fn server(&self) -> Result<UsbIpServer> {
+        let mut devices = self.host_devices()?;
+        for (i, simulated) in self.simulated.iter().enumerate() {
+            devices.push(simulated.device(i));
+        }
+        let mut server = UsbIpServer::new_simulated(devices);
+        if let Some(secs) = self.keepalive_secs {
+            server = server.with_keepalive(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            server = server.with_idle_timeout(Duration::from_secs(secs));
+        }
+        Ok(server)
+    }
+
+    #[cfg(feature = "rusb")]
+    fn host_devices(&self) -> Result<Vec<UsbDevice>> {
+        if self.host.is_empty() {
+            return Ok(vec![]);
+        }
+        let list = rusb::devices().map_err(std::io::Error::other)?;
+        let devices: Vec<_> = list
+            .iter()
+            .filter(|dev| self.host.iter().any(|filter| filter.matches(dev)))
+            .collect();
+        info!("Exporting {} host devices", devices.len());
+        Ok(UsbIpServer::with_rusb_devices(devices))
+    }
+
+    #[cfg(not(feature = "rusb"))]
+    fn host_devices(&self) -> Result<Vec<UsbDevice>> {
+        if self.host.is_empty() {
+            Ok(vec![])
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Host devices need the rusb feature",
+            ))
+        }
+    }
+}
+
+impl HostDeviceFilter {
+    #[cfg(feature = "rusb")]
+    fn matches(&self, dev: &rusb::Device<rusb::GlobalContext>) -> bool {
+        let Ok(desc) = dev.device_descriptor() else {
+            return false;
+        };
+        if self.vendor_id.is_some_and(|id| id != desc.vendor_id())
+            || self.product_id.is_some_and(|id| id != desc.product_id())
+        {
+            return false;
+        }
+        match &self.serial {
+            None => true,
+            Some(serial) => dev
+                .open()
+                .and_then(|handle| handle.read_serial_number_string_ascii(&desc))
+                .is_ok_and(|s| &s == serial),
+        }
+    }
+}
+
+impl SimulatedDeviceConfig {
+    /// The device, as the `index`th simulated device
+    fn device(&self, index: usize) -> UsbDevice {
+        let mut device = match self.kind {
+            SimulatedDeviceKind::HidKeyboard => UsbDevice::new(0).with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                Some("Keyboard"),
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 0x08,
+                    interval: 10,
+                }],
+                shared_interface_handler(hid::UsbHidKeyboardHandler::new_keyboard()),
+            ),
+            SimulatedDeviceKind::CdcAcm => UsbDevice::new(0).with_interface(
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                Some("Serial"),
+                cdc::UsbCdcAcmHandler::endpoints(),
+                shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
+            ),
+            SimulatedDeviceKind::Composite => UsbDevice::sample_composite(),
+        };
+        if let Some(id) = self.vendor_id {
+            device.vendor_id = id;
+        }
+        if let Some(id) = self.product_id {
+            device.product_id = id;
+        }
+        let ports = match &self.ports {
+            Some(ports) => ports.clone(),
+            None => vec![index as u8 + 1],
+        };
+        device = device.with_location(self.bus.unwrap_or(0), &ports);
+        if let Some(tag) = &self.tag {
+            device = device.with_tag(tag);
+        }
+        if self.bytes_per_second.is_some() || self.latency_ms.is_some() {
+            device = device.with_shaping(UsbLinkShaping {
+                bytes_per_second: self.bytes_per_second,
+                latency: Duration::from_millis(self.latency_ms.unwrap_or(0)),
+            });
+        }
+        device
+    }
+}
+
+impl UsbIpServer {
+    /// Create the server described by the configuration file at `path`
+    ///
+    /// Use [UsbIpConfig] directly for the listen addresses.
+    pub fn from_config(path: &Path) -> Result<Self> {
+        UsbIpConfig::from_file(path)?.server()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn simulated_devices_from_config() {
+        setup_test_logger();
+        let config: UsbIpConfig = r#"
+            listen = ["127.0.0.1:3240"]
+            idle_timeout_secs = 600
+
+            [[simulated]]
+            kind = "hid-keyboard"
+            vendor_id = 0x1234
+
+            [[simulated]]
+            kind = "cdc-acm"
+            tag = "serial"
+            bytes_per_second = 1000000
+
+            [[simulated]]
+            kind = "composite"
+            bus = 2
+            ports = [1, 4]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.listen, vec!["127.0.0.1:3240".parse().unwrap()]);
+        assert_eq!(config.idle_timeout_secs, Some(600));
+
+        let devices = config.server().unwrap().available_devices().await;
+        let bus_ids: Vec<_> = devices.iter().map(|d| d.bus_id.as_str()).collect();
+        assert_eq!(bus_ids, ["0-1", "0-2", "2-1.4"]);
+        assert_eq!(devices[0].vendor_id, 0x1234);
+        assert_eq!(devices[1].tag.as_deref(), Some("serial"));
+        assert_eq!(devices[0].shaping, None);
+        assert_eq!(
+            devices[1].shaping,
+            Some(UsbLinkShaping::for_speed(UsbSpeed::Full))
+        );
+        assert_eq!(devices[2].interfaces.len(), 3);
+    }
+
+    #[test]
+    fn invalid_config() {
+        setup_test_logger();
+        let err = "listen = 3240".parse::<UsbIpConfig>().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            "[[simulated]]\nkind = \"mouse\""
+                .parse::<UsbIpConfig>()
+                .is_err()
+        );
+        assert!("port = 1".parse::<UsbIpConfig>().is_err());
+    }
+}
@@ -0,0 +1,417 @@
+//! Conformance checks for device and handler authors
+//!
+//! [check_conformance] serves a device over an in-memory connection and
+//! talks to it like a USB/IP client would. It checks the consistency of its
+//! descriptors, its handling of the standard requests of chapter 9 of the
+//! USB specification, and the replies of the server to unlink requests.
+//! Unlike [self_test()], a failed check does not stop the following ones,
+//! unless the device cannot be imported at all.
+use super::*;
+use crate::self_test::{AbortOnDrop, SelfTestClient};
+use crate::usbip_protocol::EPIPE;
+use tokio::io::DuplexStream;
+
+/// Result of a check of [check_conformance]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConformanceCheck {
+    pub name: &'static str,
+    /// Why the check failed, if it did
+    pub error: Option<String>,
+}
+
+/// Results of [check_conformance]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConformanceReport {
+    pub checks: Vec<ConformanceCheck>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> bool {
+        !self.checks.is_empty() && self.checks.iter().all(|check| check.error.is_none())
+    }
+
+    /// The checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &ConformanceCheck> {
+        self.checks.iter().filter(|check| check.error.is_some())
+    }
+}
+
+impl std::fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            match &check.error {
+                None => writeln!(f, "PASS {}", check.name)?,
+                Some(err) => writeln!(f, "FAIL {}: {err}", check.name)?,
+            }
+        }
+        write!(f, "{}", if self.passed() { "PASSED" } else { "FAILED" })
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Setup packet of a standard control IN request
+fn get(request_type: u8, request: StandardRequest, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let mut setup = [request_type, request as u8, 0, 0, 0, 0, 0, 0];
+    setup[2..4].copy_from_slice(&value.to_le_bytes());
+    setup[4..6].copy_from_slice(&index.to_le_bytes());
+    setup[6..8].copy_from_slice(&length.to_le_bytes());
+    setup
+}
+
+fn get_descriptor(kind: DescriptorType, index: u8, language: u16, length: u16) -> [u8; 8] {
+    let value = ((kind as u16) << 8) | index as u16;
+    get(
+        0x80,
+        StandardRequest::GetDescriptor,
+        value,
+        language,
+        length,
+    )
+}
+
+/// Check the fields of a device descriptor
+fn check_device_descriptor(desc: &[u8], device: &[u8]) -> Result<()> {
+    if desc.len() != 18 || desc[0] != 18 || desc[1] != DescriptorType::Device as u8 {
+        return Err(invalid(format!("Malformed device descriptor {desc:02x?}")));
+    }
+    let usb3 = u16::from_le_bytes([desc[2], desc[3]]) >= 0x0300;
+    let max_packet_size = desc[7];
+    if (usb3 && max_packet_size != 9) || (!usb3 && ![8, 16, 32, 64].contains(&max_packet_size)) {
+        return Err(invalid(format!(
+            "Invalid bMaxPacketSize0 {max_packet_size}"
+        )));
+    }
+    if desc[17] == 0 {
+        return Err(invalid("No configurations".to_string()));
+    }
+    // idVendor, idProduct and bcdDevice are big endian in OP_REP_DEVLIST
+    let listed = [
+        device[0x12D],
+        device[0x12C],
+        device[0x12F],
+        device[0x12E],
+        device[0x131],
+        device[0x130],
+    ];
+    if desc[8..14] != listed {
+        return Err(invalid(format!(
+            "Descriptor IDs {:02x?} differ from the device list {listed:02x?}",
+            &desc[8..14]
+        )));
+    }
+    if desc[4..7] != device[0x132..0x135] || desc[17] != device[0x136] {
+        return Err(invalid(
+            "Descriptor class or number of configurations differs from the device list".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the descriptors within a configuration descriptor, and return
+/// the indices of the strings it references
+fn check_configuration_descriptor(desc: &[u8], device: &[u8]) -> Result<Vec<u8>> {
+    if desc.len() < 9 || desc[0] != 9 || desc[1] != DescriptorType::Configuration as u8 {
+        return Err(invalid(format!(
+            "Malformed configuration header {desc:02x?}"
+        )));
+    }
+    let total_length = u16::from_le_bytes([desc[2], desc[3]]) as usize;
+    if total_length != desc.len() {
+        return Err(invalid(format!(
+            "wTotalLength is {total_length}, but {} bytes were returned",
+            desc.len()
+        )));
+    }
+    if desc[4] != device[0x137] {
+        return Err(invalid(format!(
+            "bNumInterfaces is {}, but the device list has {} interfaces",
+            desc[4], device[0x137]
+        )));
+    }
+
+    let mut strings = vec![desc[6]];
+    let mut interfaces = vec![];
+    // endpoints still expected by the last interface descriptor
+    let mut expected_endpoints = 0;
+    let mut offset = desc[0] as usize;
+    while offset < desc.len() {
+        let len = desc[offset] as usize;
+        if len < 2 || offset + len > desc.len() {
+            return Err(invalid(format!(
+                "Descriptor of {len} bytes at offset {offset}"
+            )));
+        }
+        let sub = &desc[offset..offset + len];
+        match FromPrimitive::from_u8(sub[1]) {
+            Some(DescriptorType::Interface) => {
+                if expected_endpoints != 0 {
+                    return Err(invalid(format!(
+                        "Interface before offset {offset} is missing {expected_endpoints} endpoints"
+                    )));
+                }
+                if len < 9 {
+                    return Err(invalid(format!(
+                        "Short interface descriptor at offset {offset}"
+                    )));
+                }
+                if !interfaces.contains(&sub[2]) {
+                    interfaces.push(sub[2]);
+                }
+                expected_endpoints = sub[4];
+                strings.push(sub[8]);
+            }
+            Some(DescriptorType::Endpoint) => {
+                if expected_endpoints == 0 {
+                    return Err(invalid(format!(
+                        "Endpoint at offset {offset} does not belong to an interface"
+                    )));
+                }
+                if len < 7 || sub[2] & 0x0F == 0 {
+                    return Err(invalid(format!("Invalid endpoint descriptor {sub:02x?}")));
+                }
+                expected_endpoints -= 1;
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    if expected_endpoints != 0 {
+        return Err(invalid(format!(
+            "Last interface is missing {expected_endpoints} endpoints"
+        )));
+    }
+    if interfaces.len() != desc[4] as usize {
+        return Err(invalid(format!(
+            "bNumInterfaces is {}, but {} interfaces are described",
+            desc[4],
+            interfaces.len()
+        )));
+    }
+    Ok(strings)
+}
+
+/// Serve `device` and check its descriptors, its replies to standard
+/// requests and the unlink replies of the server, like a client would
+///
+/// Run it in the tests of a handler to catch mistakes before real hosts do.
+pub async fn check_conformance(device: UsbDevice) -> ConformanceReport {
+    let bus_id = device.bus_id.clone();
+    let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+    check_server_conformance(server, &bus_id).await
+}
+
+/// Like [check_conformance], for the device `bus_id` of `server`
+///
+/// The checks change the configuration of the device, so host devices
+/// should not be in use by anything else.
+pub async fn check_server_conformance(server: Arc<UsbIpServer>, bus_id: &str) -> ConformanceReport {
+    let mut report = ConformanceReport::default();
+
+    macro_rules! check {
+        ($name:expr, $body:expr) => {{
+            let res: Result<_> = $body;
+            report.checks.push(ConformanceCheck {
+                name: $name,
+                error: res.as_ref().err().map(|err| err.to_string()),
+            });
+            res.ok()
+        }};
+    }
+
+    let (connection, mut socket) = tokio::io::duplex(0x10000);
+    let serve = tokio::spawn(async move {
+        handler(&mut socket, server)
+            .await
+            .map_err(std::io::Error::from)
+    });
+    let _abort = AbortOnDrop(serve.abort_handle());
+    let mut client: SelfTestClient<DuplexStream> = SelfTestClient {
+        connection,
+        seqnum: 0,
+    };
+
+    let Some(listed) = check!("list devices", client.devlist(bus_id).await) else {
+        return report;
+    };
+    let Some(imported) = check!("import device", client.import(bus_id).await) else {
+        return report;
+    };
+    check!(
+        "import matches device list",
+        if imported == listed {
+            Ok(())
+        } else {
+            Err(invalid(
+                "OP_REP_IMPORT differs from OP_REP_DEVLIST".to_string(),
+            ))
+        }
+    );
+
+    let device_descriptor = check!("device descriptor", {
+        let setup = get_descriptor(DescriptorType::Device, 0, 0, 18);
+        match client.submit(0x80, setup, vec![], 18).await {
+            Ok(desc) => check_device_descriptor(&desc, &listed).map(|()| desc),
+            Err(err) => Err(err),
+        }
+    });
+    check!("short device descriptor", {
+        // hosts read the first 8 bytes to learn bMaxPacketSize0
+        let setup = get_descriptor(DescriptorType::Device, 0, 0, 8);
+        match client.submit(0x80, setup, vec![], 8).await {
+            Ok(desc) if desc.len() == 8 => Ok(()),
+            Ok(desc) => Err(invalid(format!("{} bytes for wLength 8", desc.len()))),
+            Err(err) => Err(err),
+        }
+    });
+
+    let configuration = check!("configuration descriptor", {
+        let setup = get_descriptor(DescriptorType::Configuration, 0, 0, 9);
+        match client.submit(0x80, setup, vec![], 9).await {
+            Ok(header) if header.len() == 9 => {
+                let total_length = u16::from_le_bytes([header[2], header[3]]);
+                let setup = get_descriptor(DescriptorType::Configuration, 0, 0, total_length);
+                client
+                    .submit(0x80, setup, vec![], total_length.into())
+                    .await
+                    .and_then(|desc| {
+                        check_configuration_descriptor(&desc, &listed)
+                            .map(|strings| (desc, strings))
+                    })
+            }
+            Ok(header) => Err(invalid(format!("{} bytes for wLength 9", header.len()))),
+            Err(err) => Err(err),
+        }
+    });
+
+    check!("string descriptors", {
+        let setup = get_descriptor(DescriptorType::String, 0, 0, 0xFF);
+        match client.submit(0x80, setup, vec![], 0xFF).await {
+            Ok(languages) if languages.len() >= 4 && languages[0] as usize == languages.len() => {
+                let language = u16::from_le_bytes([languages[2], languages[3]]);
+                let mut indices = device_descriptor
+                    .iter()
+                    .flat_map(|desc| desc[14..17].to_vec())
+                    .collect::<Vec<_>>();
+                if let Some((_, strings)) = &configuration {
+                    indices.extend(strings);
+                }
+                indices.retain(|&index| index != 0);
+                indices.sort();
+                indices.dedup();
+                let mut res = Ok(());
+                for index in indices {
+                    let setup = get_descriptor(DescriptorType::String, index, language, 0xFF);
+                    match client.submit(0x80, setup, vec![], 0xFF).await {
+                        Ok(desc)
+                            if desc.len() >= 2
+                                && desc.len() % 2 == 0
+                                && desc[0] as usize == desc.len()
+                                && desc[1] == DescriptorType::String as u8 => {}
+                        Ok(desc) => {
+                            res = Err(invalid(format!("Malformed string {index}: {desc:02x?}")));
+                            break;
+                        }
+                        Err(err) => {
+                            res = Err(invalid(format!("String {index}: {err}")));
+                            break;
+                        }
+                    }
+                }
+                res
+            }
+            Ok(languages) => Err(invalid(format!("Malformed language IDs {languages:02x?}"))),
+            Err(err) => Err(err),
+        }
+    });
+
+    check!("get status", {
+        let setup = get(0x80, StandardRequest::GetStatus, 0, 0, 2);
+        match client.submit(0x80, setup, vec![], 2).await {
+            Ok(status) if status.len() == 2 => Ok(()),
+            Ok(status) => Err(invalid(format!("{} bytes of status", status.len()))),
+            Err(err) => Err(err),
+        }
+    });
+
+    check!("set configuration", {
+        let value = configuration.as_ref().map_or(1, |(desc, _)| desc[5]);
+        let setup = get(0x00, StandardRequest::SetConfiguration, value.into(), 0, 0);
+        let res = client.submit(0x00, setup, vec![], 0).await;
+        let setup = get(0x80, StandardRequest::GetConfiguration, 0, 0, 1);
+        match res.and(client.submit(0x80, setup, vec![], 1).await) {
+            Ok(current) if current == [value] => Ok(()),
+            Ok(current) => Err(invalid(format!(
+                "GET_CONFIGURATION returned {current:02x?} after SET_CONFIGURATION {value}"
+            ))),
+            Err(err) => Err(err),
+        }
+    });
+
+    check!("unsupported request stalls", {
+        // bRequest 0xFF is not a standard request
+        let setup = [0x80, 0xFF, 0, 0, 0, 0, 0, 0];
+        match client.urb(0x80, setup, vec![], 0).await {
+            Ok((status, _)) if status == -EPIPE => Ok(()),
+            Ok((status, _)) => Err(invalid(format!("Completed with status {status}"))),
+            Err(err) => Err(err),
+        }
+    });
+    check!("request after stall", {
+        let setup = get(0x80, StandardRequest::GetStatus, 0, 0, 2);
+        client.submit(0x80, setup, vec![], 2).await.map(|_| ())
+    });
+
+    check!("unlink completed URB", {
+        let setup = get_descriptor(DescriptorType::Device, 0, 0, 18);
+        match client.submit(0x80, setup, vec![], 18).await {
+            // the URB completed, so the unlink must not report it cancelled
+            Ok(_) => match client.unlink(client.seqnum).await {
+                Ok(0) => Ok(()),
+                Ok(status) => Err(invalid(format!("USBIP_RET_UNLINK with status {status}"))),
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    });
+    check!("unlink unknown URB", {
+        client.unlink(client.seqnum + 0x1000).await.map(|_| ())
+    });
+
+    drop(client);
+    check!(
+        "disconnect",
+        serve
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|res| res)
+    );
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sample_composite_conforms() {
+        setup_test_logger();
+        let report = check_conformance(UsbDevice::sample_composite()).await;
+        assert!(report.passed(), "{report}");
+        assert_eq!(report.failures().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_device_fails() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let report = check_server_conformance(server, "9-9").await;
+        assert!(!report.passed());
+        assert_eq!(report.checks.len(), 1);
+        assert_eq!(report.failures().next().unwrap().name, "list devices");
+    }
+}
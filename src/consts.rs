@@ -1,7 +1,9 @@
 use super::*;
 
 /// A list of known USB speeds
-#[derive(Copy, Clone, Debug)]
+///
+/// Values match `enum usb_device_speed` of the Linux kernel used by USB/IP.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum UsbSpeed {
     Unknown = 0x0,
@@ -13,6 +15,47 @@ pub enum UsbSpeed {
     SuperPlus,
 }
 
+impl UsbSpeed {
+    /// Usual max packet size of EP0 at this speed
+    ///
+    /// Full speed devices may use 8, 16 or 32 too.
+    pub fn default_ep0_max_packet_size(self) -> u16 {
+        match self {
+            Self::Low => 8,
+            Self::Super | Self::SuperPlus => 512,
+            _ => EP0_MAX_PACKET_SIZE,
+        }
+    }
+}
+
+#[cfg(feature = "rusb")]
+impl From<rusb::Speed> for UsbSpeed {
+    fn from(value: rusb::Speed) -> Self {
+        match value {
+            rusb::Speed::Low => Self::Low,
+            rusb::Speed::Full => Self::Full,
+            rusb::Speed::High => Self::High,
+            rusb::Speed::Super => Self::Super,
+            rusb::Speed::SuperPlus => Self::SuperPlus,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[cfg(feature = "nusb")]
+impl From<nusb::Speed> for UsbSpeed {
+    fn from(value: nusb::Speed) -> Self {
+        match value {
+            nusb::Speed::Low => Self::Low,
+            nusb::Speed::Full => Self::Full,
+            nusb::Speed::High => Self::High,
+            nusb::Speed::Super => Self::Super,
+            nusb::Speed::SuperPlus => Self::SuperPlus,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 /// A list of defined USB class codes
 // https://www.usb.org/defined-class-codes
 #[derive(Copy, Clone, Debug)]
@@ -83,6 +126,19 @@ pub enum StandardRequest {
     SynchFrame = 12,
 }
 
+/// A list of defined USB feature selectors
+/// from USB 2.0 standard Table 9.6. Standard Feature Selectors
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FeatureSelector {
+    /// ENDPOINT_HALT, recipient is endpoint
+    EndpointHalt = 0,
+    /// DEVICE_REMOTE_WAKEUP, recipient is device
+    DeviceRemoteWakeup = 1,
+    /// TEST_MODE, recipient is device
+    TestMode = 2,
+}
+
 /// A list of defined USB descriptor types
 /// from USB 2.0 standard Table 9.5. Descriptor Types
 #[derive(Copy, Clone, Debug, FromPrimitive)]
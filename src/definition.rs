@@ -0,0 +1,191 @@
+//! Simulated devices defined by data
+//!
+//! A [UsbDeviceDefinition] describes a simulated device with its strings,
+//! interfaces and endpoints, and names the built-in handler of every
+//! interface. It can be deserialized from JSON, TOML or any other serde
+//! format, so fixtures need no Rust code:
+//!
+//! ```json
+//! {
+//!   "vendor_id": 4617,
+//!   "product_id": 1,
+//!   "product": "Keyboard",
+//!   "interfaces": [{
+//!     "class": 3,
+//!     "endpoints": [{ "address": 129, "attributes": 3, "max_packet_size": 8, "interval": 10 }],
+//!     "handler": "hid-keyboard"
+//!   }]
+//! }
+//! ```
+use super::*;
+
+/// A simulated [UsbDevice], see [UsbDevice::from]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbDeviceDefinition {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// bcdDevice
+    #[serde(default)]
+    pub device_bcd: u16,
+    /// bcdUSB, USB 2.0 by default
+    pub usb_version: Option<u16>,
+    #[serde(default)]
+    pub device_class: u8,
+    #[serde(default)]
+    pub device_subclass: u8,
+    #[serde(default)]
+    pub device_protocol: u8,
+    /// High speed by default
+    pub speed: Option<UsbSpeed>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// Bus number, see [UsbDevice::with_location]
+    #[serde(default)]
+    pub bus: u32,
+    /// Hub ports leading to the device, `[1]` by default
+    pub ports: Option<Vec<u8>>,
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub self_powered: bool,
+    #[serde(default)]
+    pub remote_wakeup: bool,
+    /// bMaxPower in 2mA units
+    pub max_power: Option<u8>,
+    #[serde(default)]
+    pub interfaces: Vec<UsbInterfaceDefinition>,
+}
+
+/// An interface of a [UsbDeviceDefinition]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbInterfaceDefinition {
+    pub class: u8,
+    #[serde(default)]
+    pub subclass: u8,
+    #[serde(default)]
+    pub protocol: u8,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub endpoints: Vec<UsbEndpoint>,
+    pub handler: BuiltinInterfaceHandler,
+}
+
+/// Interface handlers that come with the crate
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuiltinInterfaceHandler {
+    /// [hid::UsbHidKeyboardHandler]
+    HidKeyboard,
+    /// [cdc::UsbCdcAcmHandler]
+    CdcAcm,
+}
+
+impl BuiltinInterfaceHandler {
+    fn handler(self) -> Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        match self {
+            Self::HidKeyboard => {
+                shared_interface_handler(hid::UsbHidKeyboardHandler::new_keyboard())
+            }
+            Self::CdcAcm => shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
+        }
+    }
+}
+
+impl From<UsbDeviceDefinition> for UsbDevice {
+    fn from(definition: UsbDeviceDefinition) -> Self {
+        let mut device = UsbDevice::new(0);
+        device.vendor_id = definition.vendor_id;
+        device.product_id = definition.product_id;
+        device.device_bcd = definition.device_bcd.into();
+        if let Some(version) = definition.usb_version {
+            device.usb_version = version.into();
+        }
+        device.device_class = definition.device_class;
+        device.device_subclass = definition.device_subclass;
+        device.device_protocol = definition.device_protocol;
+        device.self_powered = definition.self_powered;
+        device.remote_wakeup = definition.remote_wakeup;
+        if let Some(max_power) = definition.max_power {
+            device.max_power = max_power;
+        }
+        match &definition.manufacturer {
+            Some(name) => device.set_manufacturer_name(name),
+            None => device.unset_manufacturer_name(),
+        };
+        match &definition.product {
+            Some(name) => device.set_product_name(name),
+            None => device.unset_product_name(),
+        };
+        match &definition.serial {
+            Some(serial) => device.set_serial_number(serial),
+            None => device.unset_serial_number(),
+        };
+        if let Some(speed) = definition.speed {
+            device = device.with_speed(speed);
+        }
+        for interface in definition.interfaces {
+            device = device.with_interface(
+                interface.class,
+                interface.subclass,
+                interface.protocol,
+                interface.name.as_deref(),
+                interface.endpoints,
+                interface.handler.handler(),
+            );
+        }
+        let ports = definition.ports.unwrap_or_else(|| vec![1]);
+        device = device.with_location(definition.bus, &ports);
+        if let Some(tag) = &definition.tag {
+            device = device.with_tag(tag);
+        }
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn device_from_json() {
+        setup_test_logger();
+        let definition: UsbDeviceDefinition = serde_json::from_str(
+            r#"{
+                "vendor_id": 4617,
+                "product_id": 1,
+                "device_bcd": 256,
+                "speed": "Full",
+                "product": "Keyboard",
+                "bus": 3,
+                "ports": [1, 2],
+                "interfaces": [{
+                    "class": 3,
+                    "name": "Keys",
+                    "endpoints": [{ "address": 129, "attributes": 3, "max_packet_size": 8, "interval": 10 }],
+                    "handler": "hid-keyboard"
+                }]
+            }"#,
+        )
+        .unwrap();
+        let device = UsbDevice::from(definition);
+        assert_eq!(device.vendor_id, 0x1209);
+        assert_eq!(device.device_bcd.to_bcd(), 0x0100);
+        assert_eq!(device.speed, UsbSpeed::Full as u32);
+        assert_eq!(device.bus_id, "3-1.2");
+        assert_eq!(device.interfaces.len(), 1);
+        assert_eq!(device.interfaces[0].endpoints[0].address, 0x81);
+        // the report descriptor of the keyboard handler
+        assert!(!device.interfaces[0].class_specific_descriptor.is_empty());
+        assert_eq!(device.string_manufacturer, 0);
+        assert_ne!(device.string_product, 0);
+
+        let err = serde_json::from_str::<UsbDeviceDefinition>(
+            r#"{ "vendor_id": 1, "product_id": 1, "interfaces": [{ "class": 3, "handler": "mouse" }] }"#,
+        );
+        assert!(err.is_err());
+    }
+}
@@ -8,6 +8,13 @@ pub struct Version {
     pub patch: u8,
 }
 
+impl Version {
+    /// Encode as binary-coded decimal, e.g. `0x0210` for USB 2.1
+    pub fn to_bcd(&self) -> u16 {
+        ((self.major as u16) << 8) | (((self.minor & 0xF) as u16) << 4) | (self.patch & 0xF) as u16
+    }
+}
+
 /// bcdDevice
 impl From<u16> for Version {
     fn from(value: u16) -> Self {
@@ -19,6 +26,67 @@ impl From<u16> for Version {
     }
 }
 
+/// Runtime state of a [UsbDevice], changed by standard requests from the host
+#[derive(Clone, Default, Debug)]
+pub struct UsbDeviceState {
+    /// Addresses of endpoints with the halt feature set
+    pub halted_endpoints: HashSet<u8>,
+    /// Whether the host enabled the remote wakeup feature
+    pub remote_wakeup_enabled: bool,
+    /// Whether the device is suspended
+    pub suspended: bool,
+    /// Whether an interface handler requested remote wakeup while suspended,
+    /// see [UsbInterface::request_remote_wakeup]
+    pub wakeup_pending: bool,
+    /// bConfigurationValue of the current configuration, zero if not configured
+    pub configuration: u8,
+    /// Current alternate setting of each interface, zero if absent
+    pub alternate_settings: HashMap<u8, u8>,
+}
+
+impl UsbDeviceState {
+    /// Initial state of a device in the given configuration
+    pub fn configured(configuration: u8) -> Self {
+        Self {
+            configuration,
+            ..Self::default()
+        }
+    }
+
+    /// Current alternate setting of the interface
+    pub fn alternate_setting(&self, interface_number: u8) -> u8 {
+        self.alternate_settings
+            .get(&interface_number)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl UsbDeviceState {
+    /// Whether the host allows the device to wake it up
+    pub(crate) fn remote_wakeup(&self) -> bool {
+        if !self.remote_wakeup_enabled {
+            debug!("Remote wakeup requested but not enabled by host");
+        }
+        self.remote_wakeup_enabled
+    }
+}
+
+/// Platform capability for the BOS descriptor
+///
+/// `uuid` is the PlatformCapabilityUUID in little endian, `data` follows it.
+pub(crate) fn platform_capability_descriptor(uuid: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    let mut desc = vec![
+        20 + data.len() as u8,                  // bLength
+        DescriptorType::DeviceCapability as u8, // bDescriptorType
+        0x05,                                   // bDevCapabilityType: Platform
+        0x00,                                   // bReserved
+    ];
+    desc.extend_from_slice(uuid); // PlatformCapabilityUUID
+    desc.extend_from_slice(data);
+    desc
+}
+
 /// Represent a USB device
 #[derive(Clone, Default, Debug)]
 #[cfg_attr(feature = "serde", derive(Serialize))]
@@ -27,6 +95,12 @@ pub struct UsbDevice {
     pub bus_id: String,
     pub bus_num: u32,
     pub dev_num: u32,
+    /// Hub ports from the root hub to the device, see [UsbDevice::with_location]
+    pub(crate) ports: Vec<u8>,
+    /// Free-form tag, e.g. to export the device with [UsbIpShard::Tag]
+    pub tag: Option<String>,
+    /// Artificial bandwidth and latency, see [UsbDevice::with_shaping]
+    pub shaping: Option<UsbLinkShaping>,
     pub speed: u32,
     pub vendor_id: u16,
     pub product_id: u16,
@@ -37,12 +111,23 @@ pub struct UsbDevice {
     pub configuration_value: u8,
     pub num_configurations: u8,
     pub interfaces: Vec<UsbInterface>,
+    /// Functions made of several interfaces, see [UsbDevice::with_interface_association]
+    pub interface_associations: Vec<UsbInterfaceAssociation>,
+    /// Whether the device is self-powered, reported in bmAttributes and GET_STATUS
+    pub self_powered: bool,
+    /// Whether the device supports remote wakeup, reported in bmAttributes
+    pub remote_wakeup: bool,
+    /// bMaxPower in 2mA units
+    pub max_power: u8,
 
     #[cfg_attr(feature = "serde", serde(skip))]
-    pub device_handler: Option<Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>>,
+    pub device_handler: Option<Arc<AsyncMutex<Box<dyn UsbDeviceHandler + Send>>>>,
 
     pub usb_version: Version,
 
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) state: Arc<Mutex<UsbDeviceState>>,
+
     pub(crate) ep0_in: UsbEndpoint,
     pub(crate) ep0_out: UsbEndpoint,
     // strings
@@ -51,6 +136,9 @@ pub struct UsbDevice {
     pub(crate) string_manufacturer: u8,
     pub(crate) string_product: u8,
     pub(crate) string_serial: u8,
+
+    pub(crate) ms_os_descriptors: Option<MsOsDescriptors>,
+    pub(crate) webusb_descriptors: Option<WebUsbDescriptors>,
 }
 
 impl UsbDevice {
@@ -72,9 +160,13 @@ impl UsbDevice {
                 max_packet_size: EP0_MAX_PACKET_SIZE,
                 interval: 0,
             },
+            usb_version: Version::from(0x0200),
             // configured by default
             configuration_value: 1,
             num_configurations: 1,
+            state: Arc::new(Mutex::new(UsbDeviceState::configured(1))),
+            // 100mA
+            max_power: 0x32,
             ..Self::default()
         };
         res.string_configuration = res.new_string("Default Configuration");
@@ -163,10 +255,28 @@ impl UsbDevice {
         interface_protocol: u8,
         name: Option<&str>,
         endpoints: Vec<UsbEndpoint>,
-        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+        handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
     ) -> Self {
         let string_interface = name.map(|name| self.new_string(name)).unwrap_or(0);
-        let class_specific_descriptor = handler.lock().unwrap().get_class_specific_descriptor();
+        let (class_specific_descriptor, class_specific_endpoint_descriptors) = {
+            let handler = handler
+                .try_lock()
+                .expect("handler must not be locked while building the device");
+            let endpoint_descriptors = endpoints
+                .iter()
+                .map(|ep| {
+                    (
+                        ep.address,
+                        handler.get_class_specific_endpoint_descriptor(ep),
+                    )
+                })
+                .filter(|(_, desc)| !desc.is_empty())
+                .collect();
+            (
+                handler.get_class_specific_descriptor(),
+                endpoint_descriptors,
+            )
+        };
         self.interfaces.push(UsbInterface {
             interface_class,
             interface_subclass,
@@ -174,19 +284,551 @@ impl UsbDevice {
             endpoints,
             string_interface,
             class_specific_descriptor,
+            class_specific_endpoint_descriptors,
+            alternate_settings: vec![],
             handler,
+            interface_number: self.interfaces.len() as u8,
+            device_state: self.state.clone(),
+            unavailable_reason: None,
+        });
+        self
+    }
+
+    /// Add an alternate setting with `endpoints` to the last interface
+    ///
+    /// The settings are numbered from 1 in order. The class specific
+    /// descriptors of the endpoints come from the handler of the interface,
+    /// which sees the selected setting in [UsbInterfaceHandler::set_alternate_setting].
+    pub fn with_alternate_setting(
+        mut self,
+        endpoints: Vec<UsbEndpoint>,
+        class_specific_descriptor: Vec<u8>,
+    ) -> Self {
+        let intf = self
+            .interfaces
+            .last_mut()
+            .expect("alternate settings need an interface");
+        let class_specific_endpoint_descriptors = {
+            let handler = intf
+                .handler
+                .try_lock()
+                .expect("handler must not be locked while building the device");
+            endpoints
+                .iter()
+                .map(|ep| {
+                    (
+                        ep.address,
+                        handler.get_class_specific_endpoint_descriptor(ep),
+                    )
+                })
+                .filter(|(_, desc)| !desc.is_empty())
+                .collect()
+        };
+        intf.alternate_settings.push(UsbAlternateSetting {
+            endpoints,
+            class_specific_descriptor,
+            class_specific_endpoint_descriptors,
+        });
+        self
+    }
+
+    /// Group `interface_count` interfaces starting at `first_interface` into one function
+    ///
+    /// Devices with interface associations must use the IAD device class,
+    /// so the device class is set to it unless another class was chosen.
+    pub fn with_interface_association(
+        mut self,
+        first_interface: u8,
+        interface_count: u8,
+        function_class: u8,
+        function_subclass: u8,
+        function_protocol: u8,
+        name: Option<&str>,
+    ) -> Self {
+        let string_function = name.map(|name| self.new_string(name)).unwrap_or(0);
+        self.interface_associations.push(UsbInterfaceAssociation {
+            first_interface,
+            interface_count,
+            function_class,
+            function_subclass,
+            function_protocol,
+            string_function,
         });
+        if self.device_class == ClassCode::SeeInterface as u8 {
+            // Miscellaneous Device Class, Interface Association Descriptor
+            self.device_class = ClassCode::Misc as u8;
+            self.device_subclass = 0x02;
+            self.device_protocol = 0x01;
+        }
         self
     }
 
     pub fn with_device_handler(
         mut self,
-        handler: Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>,
+        handler: Arc<AsyncMutex<Box<dyn UsbDeviceHandler + Send>>>,
     ) -> Self {
         self.device_handler = Some(handler);
         self
     }
 
+    /// Expose Microsoft OS descriptors, see [MsOsDescriptors]
+    ///
+    /// MS OS 2.0 descriptors are only requested from USB 2.01 or later
+    /// devices, so bcdUSB is raised to 2.01 if needed.
+    pub fn with_ms_os_descriptors(mut self, descriptors: MsOsDescriptors) -> Self {
+        if descriptors.descriptor_set.is_some() && self.usb_version.to_bcd() < 0x0201 {
+            self.usb_version = Version::from(0x0201);
+        }
+        self.ms_os_descriptors = Some(descriptors);
+        self
+    }
+
+    /// Declare WebUSB support, see [WebUsbDescriptors]
+    ///
+    /// The BOS descriptor is only read from USB 2.1 or later devices, so
+    /// bcdUSB is raised to 2.1 if needed.
+    pub fn with_webusb(mut self, descriptors: WebUsbDescriptors) -> Self {
+        if self.usb_version.to_bcd() < 0x0210 {
+            self.usb_version = Version::from(0x0210);
+        }
+        self.webusb_descriptors = Some(descriptors);
+        self
+    }
+
+    /// Get a snapshot of the runtime state of this device
+    pub fn state(&self) -> UsbDeviceState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Reset the runtime state, as done by a bus reset when the device is imported
+    ///
+    /// The suspended flag is kept, see [UsbDevice::resume].
+    pub(crate) fn reset_state(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = UsbDeviceState {
+            suspended: state.suspended,
+            ..UsbDeviceState::configured(self.configuration_value)
+        };
+    }
+
+    /// Reset the device through its device handler, see [UsbDeviceHandler::reset]
+    pub async fn reset(&self) -> Result<()> {
+        let Some(handler) = &self.device_handler else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "No device handler to reset",
+            ));
+        };
+        debug!(target: &self.log_target(), "Reset device {}", self.bus_id);
+        handler.lock().await.reset()?;
+        self.reset_state();
+        Ok(())
+    }
+
+    /// Suspend the device and notify its handlers
+    ///
+    /// Does nothing if the device is already suspended.
+    pub async fn suspend(&self) {
+        if std::mem::replace(&mut self.state.lock().unwrap().suspended, true) {
+            return;
+        }
+        debug!(target: &self.log_target(), "Suspend device {}", self.bus_id);
+        for intf in &self.interfaces {
+            intf.handler.lock().await.on_suspend(intf);
+        }
+        if let Some(handler) = &self.device_handler {
+            handler.lock().await.on_suspend();
+        }
+    }
+
+    /// Resume the device from suspend and notify its handlers
+    ///
+    /// Does nothing if the device is not suspended.
+    pub async fn resume(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.wakeup_pending = false;
+            if !std::mem::replace(&mut state.suspended, false) {
+                return;
+            }
+        }
+        debug!(target: &self.log_target(), "Resume device {}", self.bus_id);
+        for intf in &self.interfaces {
+            intf.handler.lock().await.on_resume(intf);
+        }
+        if let Some(handler) = &self.device_handler {
+            handler.lock().await.on_resume();
+        }
+    }
+
+    /// bConfigurationValue of the current configuration, zero if not configured
+    pub fn current_configuration(&self) -> u8 {
+        self.state.lock().unwrap().configuration
+    }
+
+    /// Signal remote wakeup to the host
+    ///
+    /// Returns whether the wakeup is allowed, i.e. the host has enabled the
+    /// remote wakeup feature. A suspended device is resumed with
+    /// [UsbDevice::resume], which notifies its handlers.
+    pub async fn request_remote_wakeup(&self) -> bool {
+        if !self.state.lock().unwrap().remote_wakeup() {
+            return false;
+        }
+        if self.state().suspended {
+            info!(target: &self.log_target(), "Remote wakeup of device {}", self.bus_id);
+        }
+        self.resume().await;
+        true
+    }
+
+    /// Whether the endpoint at `address` is halted
+    pub fn is_endpoint_halted(&self, address: u8) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .halted_endpoints
+            .contains(&address)
+    }
+
+    /// Set or clear the halt feature of the endpoint at `address`
+    ///
+    /// Transfers to a halted endpoint fail until the host clears the feature.
+    /// The default control endpoint can not be halted.
+    pub fn set_endpoint_halted(&self, address: u8, halted: bool) {
+        if address & 0x7F == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if halted {
+            state.halted_endpoints.insert(address);
+        } else {
+            state.halted_endpoints.remove(&address);
+        }
+    }
+
+    /// Tag the device, see [UsbIpShard::Tag]
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    /// Log target of messages about this device, e.g. `usbip::device::1-2`
+    ///
+    /// This allows raising the verbosity for a single device, for example
+    /// with `RUST_LOG=usbip::device::1-2=trace`.
+    pub fn log_target(&self) -> String {
+        format!("usbip::device::{}", self.bus_id)
+    }
+
+    /// Operating speed of this device
+    pub fn usb_speed(&self) -> UsbSpeed {
+        FromPrimitive::from_u32(self.speed).unwrap_or(UsbSpeed::Unknown)
+    }
+
+    /// Whether this device operates at SuperSpeed or SuperSpeedPlus
+    pub fn is_superspeed(&self) -> bool {
+        matches!(self.usb_speed(), UsbSpeed::Super | UsbSpeed::SuperPlus)
+    }
+
+    /// Set the operating speed, adjusting bcdUSB and the max packet size of EP0 accordingly
+    pub fn with_speed(mut self, speed: UsbSpeed) -> Self {
+        let usb_version = match speed {
+            UsbSpeed::Low | UsbSpeed::Full => 0x0110,
+            UsbSpeed::Super => 0x0300,
+            UsbSpeed::SuperPlus => 0x0310,
+            _ => 0x0200,
+        };
+        self.speed = speed as u32;
+        self.usb_version = Version::from(usb_version);
+        self.with_ep0_max_packet_size(speed.default_ep0_max_packet_size())
+    }
+
+    /// Override the max packet size of EP0
+    ///
+    /// This is in bytes, also for SuperSpeed devices.
+    pub fn with_ep0_max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.ep0_in.max_packet_size = max_packet_size;
+        self.ep0_out.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// bMaxPacketSize0 of the device descriptor
+    ///
+    /// SuperSpeed devices report it as an exponent of two.
+    pub(crate) fn max_packet_size0(&self) -> u8 {
+        if self.is_superspeed() {
+            self.ep0_in.max_packet_size.max(1).ilog2() as u8
+        } else {
+            self.ep0_in.max_packet_size as u8
+        }
+    }
+
+    /// Whether the device qualifier and other speed configuration descriptors are defined
+    ///
+    /// Only high speed devices can operate at another (full) speed.
+    pub(crate) fn has_other_speed(&self) -> bool {
+        self.usb_speed() == UsbSpeed::High
+    }
+
+    /// Standard configuration descriptor, including interface and endpoint descriptors
+    ///
+    /// SuperSpeed devices get an endpoint companion descriptor after each endpoint,
+    /// followed by the class specific descriptor of the endpoint, if any.
+    pub(crate) fn configuration_descriptor(&self, capacity: u16) -> Vec<u8> {
+        self.write_configuration_descriptor(capacity, false)
+    }
+
+    /// Configuration descriptor of a high speed device when operating at full speed
+    ///
+    /// Max packet sizes and polling intervals of the endpoints are converted to
+    /// the limits and units of full speed.
+    pub(crate) fn other_speed_configuration_descriptor(&self, capacity: u16) -> Vec<u8> {
+        self.write_configuration_descriptor(capacity, true)
+    }
+
+    fn write_configuration_descriptor(&self, capacity: u16, other_speed: bool) -> Vec<u8> {
+        use DescriptorType::*;
+
+        let descriptor_type = if other_speed {
+            OtherSpeedConfiguration
+        } else {
+            Configuration
+        };
+        let mut desc = take_scratch_buffer(capacity as usize);
+        desc.extend_from_slice(&[
+            0x09,                            // bLength
+            descriptor_type as u8,           // bDescriptorType
+            0x00,                            // wTotalLength: to be filled below
+            0x00,                            //
+            self.interfaces.len() as u8,     // bNumInterfaces
+            self.configuration_value,        // bConfigurationValue
+            self.string_configuration,       // iConfiguration
+            self.configuration_attributes(), // bmAttributes
+            self.max_power,                  // bMaxPower
+        ]);
+        for intf in &self.interfaces {
+            // interface associations come right before their first interface
+            for association in &self.interface_associations {
+                if association.first_interface == intf.interface_number {
+                    desc.extend_from_slice(&association.to_bytes());
+                }
+            }
+            let settings = std::iter::once((
+                &intf.endpoints,
+                &intf.class_specific_descriptor,
+                &intf.class_specific_endpoint_descriptors,
+            ))
+            .chain(intf.alternate_settings.iter().map(|alt| {
+                (
+                    &alt.endpoints,
+                    &alt.class_specific_descriptor,
+                    &alt.class_specific_endpoint_descriptors,
+                )
+            }));
+            for (
+                alternate_setting,
+                (endpoints, class_specific_descriptor, class_specific_endpoint_descriptors),
+            ) in settings.enumerate()
+            {
+                desc.extend_from_slice(&[
+                    0x09,                    // bLength
+                    Interface as u8,         // bDescriptorType: Interface
+                    intf.interface_number,   // bInterfaceNum
+                    alternate_setting as u8, // bAlternateSettings
+                    endpoints.len() as u8,   // bNumEndpoints
+                    intf.interface_class,    // bInterfaceClass
+                    intf.interface_subclass, // bInterfaceSubClass
+                    intf.interface_protocol, // bInterfaceProtocol
+                    intf.string_interface,   //iInterface
+                ]);
+                // class specific endpoint
+                desc.extend_from_slice(class_specific_descriptor);
+                // endpoint descriptors
+                for endpoint in endpoints {
+                    self.write_endpoint_descriptor(
+                        &mut desc,
+                        endpoint,
+                        class_specific_endpoint_descriptors.get(&endpoint.address),
+                        other_speed,
+                    );
+                }
+            }
+        }
+        // length
+        let len = desc.len() as u16;
+        desc[2] = len as u8;
+        desc[3] = (len >> 8) as u8;
+        desc
+    }
+
+    /// Endpoint descriptor of `endpoint`, with its companion and class specific descriptors
+    fn write_endpoint_descriptor(
+        &self,
+        desc: &mut Vec<u8>,
+        endpoint: &UsbEndpoint,
+        class_specific: Option<&Vec<u8>>,
+        other_speed: bool,
+    ) {
+        use DescriptorType::*;
+
+        let endpoint = if other_speed {
+            &endpoint.to_full_speed()
+        } else {
+            endpoint
+        };
+        desc.extend_from_slice(&[
+            0x07,                // bLength
+            Endpoint as u8,      // bDescriptorType: Endpoint
+            endpoint.address,    // bEndpointAddress
+            endpoint.attributes, // bmAttributes
+            endpoint.max_packet_size as u8,
+            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
+            endpoint.interval,                     // bInterval
+        ]);
+        if self.is_superspeed() {
+            // no bursts or streams, periodic endpoints move one packet per interval
+            let bytes_per_interval = match endpoint.attributes & 0x3 {
+                1 | 3 => endpoint.max_packet_size,
+                _ => 0,
+            };
+            desc.extend_from_slice(&[
+                0x06,                                 // bLength
+                SuperspeedUsbEndpointCompanion as u8, // bDescriptorType
+                0x00,                                 // bMaxBurst
+                0x00,                                 // bmAttributes
+                bytes_per_interval as u8,
+                (bytes_per_interval >> 8) as u8, // wBytesPerInterval
+            ]);
+        }
+        if let Some(class_specific) = class_specific {
+            desc.extend_from_slice(class_specific);
+        }
+    }
+
+    /// Binary device object store descriptor with the device capabilities
+    ///
+    /// SuperSpeed devices must declare the USB 2.0 extension and SuperSpeed capabilities.
+    /// The MS OS 2.0 and WebUSB platform capabilities are added if enabled.
+    pub(crate) fn bos_descriptor(&self) -> Vec<u8> {
+        use DescriptorType::*;
+
+        let mut capabilities: Vec<Vec<u8>> = vec![];
+        if self.is_superspeed() {
+            capabilities.push(vec![
+                0x07,                   // bLength
+                DeviceCapability as u8, // bDescriptorType
+                0x02,                   // bDevCapabilityType: USB 2.0 Extension
+                0x02,                   // bmAttributes: LPM
+                0x00,
+                0x00,
+                0x00,
+            ]);
+            capabilities.push(vec![
+                0x0A,                   // bLength
+                DeviceCapability as u8, // bDescriptorType
+                0x03,                   // bDevCapabilityType: SuperSpeed USB
+                0x00,                   // bmAttributes
+                0x0E,                   // wSpeedsSupported: full, high and super speed
+                0x00,
+                0x01, // bFunctionalitySupport: full speed
+                0x0A, // bU1DevExitLat
+                0xFF, // wU2DevExitLat
+                0x07,
+            ]);
+        }
+        if let UsbSpeed::SuperPlus = self.usb_speed() {
+            capabilities.push(vec![
+                0x14,                   // bLength
+                DeviceCapability as u8, // bDescriptorType
+                0x0A,                   // bDevCapabilityType: SuperSpeedPlus
+                0x00,                   // bReserved
+                0x01,                   // bmAttributes: two sublink speed attributes
+                0x00,
+                0x00,
+                0x00,
+                0x00, // wFunctionalitySupport: attribute 0, one lane
+                0x11,
+                0x00, // wReserved
+                0x00,
+                0x30, // bmSublinkSpeedAttr[0]: 10 Gbps symmetric RX
+                0x40,
+                0x0A,
+                0x00,
+                0x70, // bmSublinkSpeedAttr[1]: 10 Gbps symmetric TX
+                0x40,
+                0x0A,
+                0x00,
+            ]);
+        }
+
+        if let Some(capability) = self
+            .ms_os_descriptors
+            .as_ref()
+            .and_then(MsOsDescriptors::platform_capability)
+        {
+            capabilities.push(capability);
+        }
+        if let Some(descriptors) = &self.webusb_descriptors {
+            capabilities.push(descriptors.platform_capability());
+        }
+
+        let len = 5 + capabilities.iter().map(Vec::len).sum::<usize>() as u16;
+        let mut desc = vec![
+            0x05,      // bLength
+            BOS as u8, // bDescriptorType: BOS
+            len as u8,
+            (len >> 8) as u8,         // wTotalLength
+            capabilities.len() as u8, // bNumCapabilities
+        ];
+        for capability in capabilities {
+            desc.extend_from_slice(&capability);
+        }
+        desc
+    }
+
+    /// bmAttributes of the configuration descriptor
+    pub(crate) fn configuration_attributes(&self) -> u8 {
+        // D7 is reserved and must be set
+        let mut attributes = 0x80;
+        if self.self_powered {
+            attributes |= 0x40;
+        }
+        if self.remote_wakeup {
+            attributes |= 0x20;
+        }
+        attributes
+    }
+
+    /// Status returned by GET_STATUS for the device, interface or endpoint recipient
+    pub(crate) async fn get_status(&self, setup_packet: &SetupPacket) -> Result<u16> {
+        match setup_packet.request_type & 0x1F {
+            0 => {
+                // D0: self powered, D1: remote wakeup
+                let mut status = 0;
+                if self.self_powered {
+                    status |= 0x1;
+                }
+                if self.state.lock().unwrap().remote_wakeup_enabled {
+                    status |= 0x2;
+                }
+                if let Some(handler) = &self.device_handler {
+                    status = handler.lock().await.get_status(status);
+                }
+                Ok(status)
+            }
+            1 if self.find_interface(setup_packet.index as u8).is_some() => Ok(0),
+            2 if self.find_ep(setup_packet.index as u8).is_some() => {
+                // D0: halt
+                Ok(self.is_endpoint_halted(setup_packet.index as u8) as u16)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid GET_STATUS recipient: {setup_packet:x?}"),
+            )),
+        }
+    }
+
     pub(crate) fn new_string(&mut self, s: &str) -> u8 {
         for i in 1.. {
             if let std::collections::hash_map::Entry::Vacant(e) = self.string_pool.entry(i) {
@@ -197,14 +839,31 @@ impl UsbDevice {
         panic!("string poll exhausted")
     }
 
+    /// The interface with bInterfaceNumber `interface_number`
+    ///
+    /// Host devices may number their interfaces with gaps.
+    pub(crate) fn find_interface(&self, interface_number: u8) -> Option<&UsbInterface> {
+        self.interfaces
+            .iter()
+            .find(|intf| intf.interface_number == interface_number)
+    }
+
     pub(crate) fn find_ep(&self, ep: u8) -> Option<(UsbEndpoint, Option<&UsbInterface>)> {
         if ep == self.ep0_in.address {
             Some((self.ep0_in, None))
         } else if ep == self.ep0_out.address {
             Some((self.ep0_out, None))
         } else {
+            // alternate settings may reuse an address with another packet size
+            for intf in &self.interfaces {
+                for endpoint in intf.current_endpoints() {
+                    if endpoint.address == ep {
+                        return Some((*endpoint, Some(intf)));
+                    }
+                }
+            }
             for intf in &self.interfaces {
-                for endpoint in &intf.endpoints {
+                for endpoint in intf.all_endpoints() {
                     if endpoint.address == ep {
                         return Some((*endpoint, Some(intf)));
                     }
@@ -214,48 +873,151 @@ impl UsbDevice {
         }
     }
 
-    pub(crate) fn to_bytes(&self) -> Vec<u8> {
-        let mut result = Vec::with_capacity(312);
-
-        let mut path = self.path.as_bytes().to_vec();
-        debug_assert!(path.len() <= 256);
-        path.resize(256, 0);
-        result.extend_from_slice(path.as_slice());
+    /// Pass a control request to the handler of the interface in wIndex
+    async fn handle_interface_request(
+        &self,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup_packet: SetupPacket,
+        out_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        // only low 8 bits are valid
+        let Some(intf) = self.find_interface(setup_packet.index as u8) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid interface: {setup_packet:x?}"),
+            ));
+        };
+        Self::handle_control_of(intf, ep, transfer_buffer_length, setup_packet, out_data).await
+    }
 
-        let mut bus_id = self.bus_id.as_bytes().to_vec();
-        debug_assert!(bus_id.len() <= 32);
-        bus_id.resize(32, 0);
-        result.extend_from_slice(bus_id.as_slice());
+    /// Pass a class or vendor request to the handler of the endpoint in wIndex
+    ///
+    /// Audio streaming endpoints, for one, have a sampling frequency control.
+    async fn handle_endpoint_request(
+        &self,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup_packet: SetupPacket,
+        out_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        // only low 8 bits are valid
+        let Some((_, Some(intf))) = self.find_ep(setup_packet.index as u8) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid endpoint: {setup_packet:x?}"),
+            ));
+        };
+        Self::handle_control_of(intf, ep, transfer_buffer_length, setup_packet, out_data).await
+    }
 
-        result.extend_from_slice(&self.bus_num.to_be_bytes());
-        result.extend_from_slice(&self.dev_num.to_be_bytes());
-        result.extend_from_slice(&self.speed.to_be_bytes());
-        result.extend_from_slice(&self.vendor_id.to_be_bytes());
-        result.extend_from_slice(&self.product_id.to_be_bytes());
-        result.push(self.device_bcd.major);
-        result.push(self.device_bcd.minor);
-        result.push(self.device_class);
-        result.push(self.device_subclass);
-        result.push(self.device_protocol);
-        result.push(self.configuration_value);
-        result.push(self.num_configurations);
-        result.push(self.interfaces.len() as u8);
+    /// Pass a control request to the handler of `intf`
+    ///
+    /// Class requests the handler does not support are stalled right away.
+    async fn handle_control_of(
+        intf: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup_packet: SetupPacket,
+        out_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut handler = intf.handler.lock().await;
+        let is_class_request = (setup_packet.request_type >> 5) & 0b11 == 1;
+        if is_class_request
+            && handler
+                .supported_requests()
+                .is_some_and(|requests| !requests.contains(&setup_packet.request))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Unsupported class request: {setup_packet:x?}"),
+            ));
+        }
+        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+    }
 
-        result
+    /// Pass a control request to the device handler
+    async fn handle_device_request(
+        &self,
+        transfer_buffer_length: u32,
+        setup_packet: SetupPacket,
+        out_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        // a copy, so that the handler cannot deadlock on the state
+        let state = self.state.lock().unwrap().clone();
+        let lock = self.device_handler.as_ref().unwrap();
+        let mut handler = lock.lock().await;
+        match handler.handle_control(&state, transfer_buffer_length, setup_packet, out_data)? {
+            UsbControlResponse::Data(data) => Ok(data),
+            UsbControlResponse::Ack => Ok(vec![]),
+            UsbControlResponse::Stall => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Device handler stalled request: {setup_packet:x?}"),
+            )),
+        }
     }
 
-    pub(crate) fn to_bytes_with_interfaces(&self) -> Vec<u8> {
-        let mut result = self.to_bytes();
-        result.reserve(4 * self.interfaces.len());
+    /// Handle a URB and fill in its completion
+    ///
+    /// Transfers on interface endpoints go to [UsbInterfaceHandler::submit_urb],
+    /// everything else to [UsbDevice::handle_urb].
+    /// This device in its current configuration, after SET_CONFIGURATION
+    ///
+    /// The interfaces come from [UsbDeviceHandler::configuration_interfaces],
+    /// so that the interfaces of host devices are claimed again, and follow
+    /// a switch to another configuration. `None` if the device keeps its
+    /// interfaces, or is not configured.
+    pub(crate) async fn reconfigured(&self) -> Result<Option<UsbDevice>> {
+        let configuration = self.current_configuration();
+        let Some(handler) = self.device_handler.as_ref().filter(|_| configuration != 0) else {
+            return Ok(None);
+        };
+        let Some(interfaces) = handler
+            .lock()
+            .await
+            .configuration_interfaces(configuration)?
+        else {
+            return Ok(None);
+        };
+        debug!(target: &self.log_target(), "Exporting the interfaces of configuration {configuration}");
+        let mut device = self.clone();
+        device.configuration_value = configuration;
+        device.interfaces = interfaces
+            .into_iter()
+            .map(|intf| UsbInterface {
+                device_state: self.state.clone(),
+                ..intf
+            })
+            .collect();
+        Ok(Some(device))
+    }
 
-        for intf in &self.interfaces {
-            result.push(intf.interface_class);
-            result.push(intf.interface_subclass);
-            result.push(intf.interface_protocol);
-            result.push(0); // padding
+    pub(crate) async fn submit_urb(
+        &self,
+        intf: Option<&UsbInterface>,
+        urb: &mut Urb,
+    ) -> Result<()> {
+        match intf {
+            Some(intf)
+                if urb.endpoint.attributes != EndpointAttributes::Control as u8
+                    && !self.is_endpoint_halted(urb.endpoint.address) =>
+            {
+                intf.handler.lock().await.submit_urb_async(intf, urb).await
+            }
+            _ => {
+                let data = self
+                    .handle_urb(
+                        urb.endpoint,
+                        intf,
+                        urb.transfer_buffer_length,
+                        urb.setup,
+                        &urb.buffer,
+                    )
+                    .await?;
+                urb.complete(data);
+                Ok(())
+            }
         }
-
-        result
     }
 
     pub(crate) async fn handle_urb(
@@ -274,7 +1036,24 @@ impl UsbDevice {
         match (FromPrimitive::from_u8(ep.attributes), ep.direction()) {
             (Some(Control), In) => {
                 // control in
-                debug!("Control IN setup={setup_packet:x?}");
+                debug!(target: &self.log_target(), "Control IN setup={setup_packet:x?}");
+                if let Some(mut desc) = self
+                    .ms_os_descriptors
+                    .as_ref()
+                    .and_then(|descriptors| descriptors.handle_request(&setup_packet))
+                    .or_else(|| {
+                        self.webusb_descriptors
+                            .as_ref()
+                            .and_then(|descriptors| descriptors.handle_request(&setup_packet))
+                    })
+                {
+                    debug!(target: &self.log_target(), "Get MS OS or WebUSB descriptor");
+                    // requested len too short: wLength < real length
+                    if setup_packet.length < desc.len() as u16 {
+                        desc.resize(setup_packet.length as usize, 0);
+                    }
+                    return Ok(desc);
+                }
                 match (
                     setup_packet.request_type,
                     FromPrimitive::from_u8(setup_packet.request),
@@ -283,23 +1062,25 @@ impl UsbDevice {
                         // high byte: type
                         match FromPrimitive::from_u16(setup_packet.value >> 8) {
                             Some(Device) => {
-                                debug!("Get device descriptor");
+                                debug!(target: &self.log_target(), "Get device descriptor");
                                 // Standard Device Descriptor
+                                let usb_version = self.usb_version.to_bcd();
+                                let device_bcd = self.device_bcd.to_bcd();
                                 let mut desc = vec![
                                     0x12,         // bLength
                                     Device as u8, // bDescriptorType: Device
-                                    self.usb_version.minor,
-                                    self.usb_version.major, // bcdUSB: USB 2.0
-                                    self.device_class,      // bDeviceClass
-                                    self.device_subclass,   // bDeviceSubClass
-                                    self.device_protocol,   // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.vendor_id as u8,   // idVendor
+                                    usb_version as u8,
+                                    (usb_version >> 8) as u8, // bcdUSB
+                                    self.device_class,        // bDeviceClass
+                                    self.device_subclass,     // bDeviceSubClass
+                                    self.device_protocol,     // bDeviceProtocol
+                                    self.max_packet_size0(),  // bMaxPacketSize0
+                                    self.vendor_id as u8,     // idVendor
                                     (self.vendor_id >> 8) as u8,
                                     self.product_id as u8, // idProduct
                                     (self.product_id >> 8) as u8,
-                                    self.device_bcd.minor, // bcdDevice
-                                    self.device_bcd.major,
+                                    device_bcd as u8, // bcdDevice
+                                    (device_bcd >> 8) as u8,
                                     self.string_manufacturer, // iManufacturer
                                     self.string_product,      // iProduct
                                     self.string_serial,       // iSerial
@@ -313,13 +1094,8 @@ impl UsbDevice {
                                 Ok(desc)
                             }
                             Some(BOS) => {
-                                debug!("Get BOS descriptor");
-                                let mut desc = vec![
-                                    0x05,      // bLength
-                                    BOS as u8, // bDescriptorType: BOS
-                                    0x05, 0x00, // wTotalLength
-                                    0x00, // bNumCapabilities
-                                ];
+                                debug!(target: &self.log_target(), "Get BOS descriptor");
+                                let mut desc = self.bos_descriptor();
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -328,53 +1104,8 @@ impl UsbDevice {
                                 Ok(desc)
                             }
                             Some(Configuration) => {
-                                debug!("Get configuration descriptor");
-                                // Standard Configuration Descriptor
-                                let mut desc = vec![
-                                    0x09,                // bLength
-                                    Configuration as u8, // bDescriptorType: Configuration
-                                    0x00,
-                                    0x00, // wTotalLength: to be filled below
-                                    self.interfaces.len() as u8, // bNumInterfaces
-                                    self.configuration_value, // bConfigurationValue
-                                    self.string_configuration, // iConfiguration
-                                    0x80, // bmAttributes: Bus Powered
-                                    0x32, // bMaxPower: 100mA
-                                ];
-                                for (i, intf) in self.interfaces.iter().enumerate() {
-                                    let mut intf_desc = vec![
-                                        0x09,                       // bLength
-                                        Interface as u8,            // bDescriptorType: Interface
-                                        i as u8,                    // bInterfaceNum
-                                        0x00,                       // bAlternateSettings
-                                        intf.endpoints.len() as u8, // bNumEndpoints
-                                        intf.interface_class,       // bInterfaceClass
-                                        intf.interface_subclass,    // bInterfaceSubClass
-                                        intf.interface_protocol,    // bInterfaceProtocol
-                                        intf.string_interface,      //iInterface
-                                    ];
-                                    // class specific endpoint
-                                    let mut specific = intf.class_specific_descriptor.clone();
-                                    intf_desc.append(&mut specific);
-                                    // endpoint descriptors
-                                    for endpoint in &intf.endpoints {
-                                        let mut ep_desc = vec![
-                                            0x07,                // bLength
-                                            Endpoint as u8,      // bDescriptorType: Endpoint
-                                            endpoint.address,    // bEndpointAddress
-                                            endpoint.attributes, // bmAttributes
-                                            endpoint.max_packet_size as u8,
-                                            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
-                                            endpoint.interval,                     // bInterval
-                                        ];
-                                        intf_desc.append(&mut ep_desc);
-                                    }
-                                    desc.append(&mut intf_desc);
-                                }
-                                // length
-                                let len = desc.len() as u16;
-                                desc[2] = len as u8;
-                                desc[3] = (len >> 8) as u8;
+                                debug!(target: &self.log_target(), "Get configuration descriptor");
+                                let mut desc = self.configuration_descriptor(setup_packet.length);
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -383,7 +1114,7 @@ impl UsbDevice {
                                 Ok(desc)
                             }
                             Some(String) => {
-                                debug!("Get string descriptor");
+                                debug!(target: &self.log_target(), "Get string descriptor");
                                 let index = setup_packet.value as u8;
                                 if index == 0 {
                                     // String Descriptor Zero, Specifying Languages Supported by the Device
@@ -399,6 +1130,15 @@ impl UsbDevice {
                                         desc.resize(setup_packet.length as usize, 0);
                                     }
                                     Ok(desc)
+                                } else if let (MS_OS_STRING_INDEX, Some(descriptors)) =
+                                    (index, &self.ms_os_descriptors)
+                                {
+                                    let mut desc = descriptors.string_descriptor();
+                                    // requested len too short: wLength < real length
+                                    if setup_packet.length < desc.len() as u16 {
+                                        desc.resize(setup_packet.length as usize, 0);
+                                    }
+                                    Ok(desc)
                                 } else if let Some(s) = &self.string_pool.get(&index) {
                                     // UNICODE String Descriptor
                                     let bytes: Vec<u16> = s.encode_utf16().collect();
@@ -424,19 +1164,27 @@ impl UsbDevice {
                                 }
                             }
                             Some(DeviceQualifier) => {
-                                debug!("Get device qualifier descriptor");
+                                debug!(target: &self.log_target(), "Get device qualifier descriptor");
+                                if !self.has_other_speed() {
+                                    return Err(std::io::Error::new(
+                                        std::io::ErrorKind::InvalidInput,
+                                        "Device qualifier is only defined for high speed devices",
+                                    ));
+                                }
                                 // Device_Qualifier Descriptor
+                                // describes the device when operating at full speed
+                                let usb_version = self.usb_version.to_bcd();
                                 let mut desc = vec![
                                     0x0A,                  // bLength
                                     DeviceQualifier as u8, // bDescriptorType: Device Qualifier
-                                    self.usb_version.minor,
-                                    self.usb_version.major, // bcdUSB
-                                    self.device_class,      // bDeviceClass
-                                    self.device_subclass,   // bDeviceSUbClass
-                                    self.device_protocol,   // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.num_configurations, // bNumConfigurations
-                                    0x00,                   // bReserved
+                                    usb_version as u8,
+                                    (usb_version >> 8) as u8, // bcdUSB
+                                    self.device_class,        // bDeviceClass
+                                    self.device_subclass,     // bDeviceSUbClass
+                                    self.device_protocol,     // bDeviceProtocol
+                                    self.ep0_in.max_packet_size.min(64) as u8, // bMaxPacketSize0
+                                    self.num_configurations,  // bNumConfigurations
+                                    0x00,                     // bReserved
                                 ];
 
                                 // requested len too short: wLength < real length
@@ -445,82 +1193,316 @@ impl UsbDevice {
                                 }
                                 Ok(desc)
                             }
+                            Some(OtherSpeedConfiguration) => {
+                                debug!(target: &self.log_target(), "Get other speed configuration descriptor");
+                                if !self.has_other_speed() {
+                                    return Err(std::io::Error::new(
+                                        std::io::ErrorKind::InvalidInput,
+                                        "Other speed configuration is only defined for high speed devices",
+                                    ));
+                                }
+                                let mut desc =
+                                    self.other_speed_configuration_descriptor(setup_packet.length);
+
+                                // requested len too short: wLength < real length
+                                if setup_packet.length < desc.len() as u16 {
+                                    desc.resize(setup_packet.length as usize, 0);
+                                }
+                                Ok(desc)
+                            }
                             _ => {
-                                warn!("unknown desc type: {setup_packet:x?}");
+                                warn!(target: &self.log_target(), "unknown desc type: {setup_packet:x?}");
                                 Ok(vec![])
                             }
                         }
                     }
-                    _ if setup_packet.request_type & 0xF == 1 => {
-                        // to interface
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        // only low 8 bits are valid
-                        let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
-                    }
-                    _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
-                        // to device
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        let lock = self.device_handler.as_ref().unwrap();
-                        let mut handler = lock.lock().unwrap();
-                        handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
-                    }
-                    _ => unimplemented!("control in"),
-                }
+                    (0b10000000, Some(GetConfiguration)) => {
+                        let mut desc = vec![self.current_configuration()];
+
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
+                    (0b10000001, Some(GetInterface)) => {
+                        // only low 8 bits are valid
+                        let interface_number = setup_packet.index as u8;
+                        if self.find_interface(interface_number).is_none() {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Invalid interface: {interface_number}"),
+                            ));
+                        }
+                        let state = self.state.lock().unwrap();
+                        let mut desc = vec![state.alternate_setting(interface_number)];
+
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
+                    (0b10000000..=0b10000010, Some(GetStatus)) => {
+                        let mut desc = self.get_status(&setup_packet).await?.to_le_bytes().to_vec();
+
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
+                    _ if setup_packet.request_type & 0xF == 1 => {
+                        // to interface
+                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
+                        self.handle_interface_request(
+                            ep,
+                            transfer_buffer_length,
+                            setup_packet,
+                            out_data,
+                        )
+                        .await
+                    }
+                    _ if setup_packet.request_type & 0xF == 2
+                        && setup_packet.request_type & 0x60 != 0 =>
+                    {
+                        // class or vendor request to endpoint
+                        self.handle_endpoint_request(
+                            ep,
+                            transfer_buffer_length,
+                            setup_packet,
+                            out_data,
+                        )
+                        .await
+                    }
+                    _ if matches!(setup_packet.request_type & 0xF, 0 | 3)
+                        && self.device_handler.is_some() =>
+                    {
+                        // to device, or other recipients like the ports of hubs
+                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
+                        self.handle_device_request(transfer_buffer_length, setup_packet, out_data)
+                            .await
+                    }
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unsupported control IN request: {setup_packet:x?}"),
+                    )),
+                }
             }
             (Some(Control), Out) => {
                 // control out
-                debug!("Control OUT setup={setup_packet:x?}");
+                debug!(target: &self.log_target(), "Control OUT setup={setup_packet:x?}");
                 match (
                     setup_packet.request_type,
                     FromPrimitive::from_u8(setup_packet.request),
                 ) {
                     (0b00000000, Some(SetConfiguration)) => {
-                        let mut desc = vec![
-                            self.configuration_value, // bConfigurationValue
-                        ];
-
-                        // requested len too short: wLength < real length
-                        if setup_packet.length < desc.len() as u16 {
-                            desc.resize(setup_packet.length as usize, 0);
+                        // only low 8 bits are valid
+                        let configuration = setup_packet.value as u8;
+                        // devices with several configurations, i.e. host devices, check themselves
+                        if configuration != 0
+                            && configuration != self.configuration_value
+                            && self.num_configurations <= 1
+                        {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Invalid configuration: {configuration}"),
+                            ));
+                        }
+                        debug!(target: &self.log_target(), "Set configuration to {configuration}");
+                        if let Some(handler) = &self.device_handler {
+                            for intf in &self.interfaces {
+                                intf.handler.lock().await.release(intf);
+                            }
+                            handler.lock().await.set_configuration(configuration)?;
+                        }
+                        // the halt feature and alternate settings are reset on configuration
+                        let reset: Vec<u8> = {
+                            let mut state = self.state.lock().unwrap();
+                            state.configuration = configuration;
+                            state.halted_endpoints.clear();
+                            state
+                                .alternate_settings
+                                .drain()
+                                .filter(|&(_, alt)| alt != 0)
+                                .map(|(interface_number, _)| interface_number)
+                                .collect()
+                        };
+                        for intf in reset.into_iter().filter_map(|n| self.find_interface(n)) {
+                            intf.handler.lock().await.set_alternate_setting(intf, 0);
+                        }
+                        Ok(vec![])
+                    }
+                    (0b00000001, Some(SetInterface)) => {
+                        // only low 8 bits are valid
+                        let interface_number = setup_packet.index as u8;
+                        let alternate_setting = setup_packet.value as u8;
+                        let Some(intf) = self.find_interface(interface_number) else {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Invalid interface: {interface_number}"),
+                            ));
+                        };
+                        // alternate setting zero is the interface itself
+                        if alternate_setting as usize > intf.alternate_settings.len() {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!(
+                                    "Invalid alternate setting {alternate_setting} of interface {interface_number}"
+                                ),
+                            ));
+                        }
+                        debug!(target: &self.log_target(),
+                            "Set alternate setting of interface {interface_number} to {alternate_setting}"
+                        );
+                        self.state
+                            .lock()
+                            .unwrap()
+                            .alternate_settings
+                            .insert(interface_number, alternate_setting);
+                        intf.handler
+                            .lock()
+                            .await
+                            .set_alternate_setting(intf, alternate_setting);
+                        Ok(vec![])
+                    }
+                    (0b00000000, Some(request @ (ClearFeature | SetFeature))) => {
+                        let enabled = matches!(request, SetFeature);
+                        match FromPrimitive::from_u16(setup_packet.value) {
+                            Some(FeatureSelector::DeviceRemoteWakeup) if self.remote_wakeup => {
+                                debug!(target: &self.log_target(), "Set remote wakeup to {enabled}");
+                                self.state.lock().unwrap().remote_wakeup_enabled = enabled;
+                                Ok(vec![])
+                            }
+                            Some(FeatureSelector::TestMode) if enabled => {
+                                // test modes are meaningless for simulated devices
+                                Ok(vec![])
+                            }
+                            _ => Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Unsupported device feature: {setup_packet:x?}"),
+                            )),
+                        }
+                    }
+                    (0b00000010, Some(request @ (ClearFeature | SetFeature)))
+                        if setup_packet.value == FeatureSelector::EndpointHalt as u16 =>
+                    {
+                        // only low 8 bits are valid
+                        let address = setup_packet.index as u8;
+                        let halted = matches!(request, SetFeature);
+                        match self.find_ep(address) {
+                            Some((target, intf)) => {
+                                debug!(target: &self.log_target(), "Set halt of endpoint {address:02x} to {halted}");
+                                self.set_endpoint_halted(address, halted);
+                                if let Some(intf) = intf {
+                                    let mut handler = intf.handler.lock().await;
+                                    handler.set_endpoint_halt(intf, target, halted);
+                                }
+                                Ok(vec![])
+                            }
+                            None => Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Invalid endpoint: {address:02x}"),
+                            )),
                         }
-                        Ok(desc)
                     }
                     _ if setup_packet.request_type & 0xF == 1 => {
                         // to interface
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        // only low 8 bits are valid
-                        let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                        self.handle_interface_request(
+                            ep,
+                            transfer_buffer_length,
+                            setup_packet,
+                            out_data,
+                        )
+                        .await
+                    }
+                    _ if setup_packet.request_type & 0xF == 2
+                        && setup_packet.request_type & 0x60 != 0 =>
+                    {
+                        // class or vendor request to endpoint
+                        self.handle_endpoint_request(
+                            ep,
+                            transfer_buffer_length,
+                            setup_packet,
+                            out_data,
+                        )
+                        .await
                     }
-                    _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
-                        // to device
+                    _ if matches!(setup_packet.request_type & 0xF, 0 | 3)
+                        && self.device_handler.is_some() =>
+                    {
+                        // to device, or other recipients like the ports of hubs
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        let lock = self.device_handler.as_ref().unwrap();
-                        let mut handler = lock.lock().unwrap();
-                        handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+                        self.handle_device_request(transfer_buffer_length, setup_packet, out_data)
+                            .await
                     }
-                    _ => unimplemented!("control out"),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unsupported control OUT request: {setup_packet:x?}"),
+                    )),
                 }
             }
             (Some(_), _) => {
                 // others
+                if self.is_endpoint_halted(ep.address) {
+                    return Err(std::io::Error::other(format!(
+                        "Endpoint {:02x} is halted",
+                        ep.address
+                    )));
+                }
                 let intf = intf.unwrap();
-                let mut handler = intf.handler.lock().unwrap();
+                let mut handler = intf.handler.lock().await;
                 handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
             }
-            _ => unimplemented!("transfer to {:?}", ep),
+            (None, _) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid attributes of endpoint {ep:x?}"),
+            )),
         }
     }
 }
 
+/// Outcome of a control request handled by a [UsbDeviceHandler]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UsbControlResponse {
+    /// Data stage of an IN request, at most `wLength` bytes
+    Data(Vec<u8>),
+    /// Accept the request without data
+    Ack,
+    /// Refuse the request, the host sees a STALL
+    Stall,
+}
+
+/// Wrap a device handler for [UsbDevice::with_device_handler]
+///
+/// Like [shared_interface_handler], the handler is behind a [tokio::sync::Mutex].
+pub fn shared_device_handler(
+    handler: impl UsbDeviceHandler + Send + 'static,
+) -> Arc<AsyncMutex<Box<dyn UsbDeviceHandler + Send>>> {
+    Arc::new(AsyncMutex::new(Box::new(handler)))
+}
+
 /// A handler for URB targeting the device
+///
+/// The library answers standard requests on EP0 itself. A device handler
+/// sees everything else sent to the device recipient, and is told about
+/// standard requests that change or report the device state:
+///
+/// - [handle_control](UsbDeviceHandler::handle_control): vendor and class
+///   requests, both IN and OUT, and standard requests the library does not
+///   handle. By default they are passed to [handle_urb](UsbDeviceHandler::handle_urb).
+/// - [get_status](UsbDeviceHandler::get_status): GET_STATUS to the device
+/// - [set_configuration](UsbDeviceHandler::set_configuration): SET_CONFIGURATION
+/// - [on_suspend](UsbDeviceHandler::on_suspend) and
+///   [on_resume](UsbDeviceHandler::on_resume): bus suspend and resume
+///
+/// Without a device handler, requests that would reach [handle_control](UsbDeviceHandler::handle_control) fail.
 pub trait UsbDeviceHandler: std::fmt::Debug {
     /// Handle a URB(USB Request Block) targeting at this device
     ///
-    /// When the lower 4 bits of `bmRequestType` is zero and the URB is not handled by the library, this function is called.
+    /// When the lower 4 bits of `bmRequestType` is zero (device) or three (other, e.g. the
+    /// ports of a hub) and the URB is not handled by the library, this function is called.
     /// The resulting data should not exceed `transfer_buffer_length`
     fn handle_urb(
         &mut self,
@@ -529,6 +1511,66 @@ pub trait UsbDeviceHandler: std::fmt::Debug {
         req: &[u8],
     ) -> Result<Vec<u8>>;
 
+    /// Handle a control request targeting at this device
+    ///
+    /// `state` is the state of the device when the request arrived, e.g. its
+    /// current configuration and alternate settings. Errors stall the request
+    /// too, but are logged as such.
+    fn handle_control(
+        &mut self,
+        _state: &UsbDeviceState,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<UsbControlResponse> {
+        self.handle_urb(transfer_buffer_length, setup, req)
+            .map(UsbControlResponse::Data)
+    }
+
+    /// Status returned by GET_STATUS to the device
+    ///
+    /// `status` is what the library computed from [UsbDevice::self_powered]
+    /// and the remote wakeup feature. Return another value to override it.
+    fn get_status(&mut self, status: u16) -> u16 {
+        status
+    }
+
+    /// Called when the host selects a configuration, zero means unconfigured
+    ///
+    /// The value has already been validated, unless the device has several
+    /// configurations. Returning an error stalls the request.
+    fn set_configuration(&mut self, _configuration: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Interfaces of `configuration`, after [set_configuration](Self::set_configuration) selected it
+    ///
+    /// Handlers of host devices claim and describe the interfaces of the
+    /// configuration, which replace those of the device, see
+    /// [UsbDevice::reconfigured]. `None`, the default, keeps the interfaces.
+    fn configuration_interfaces(
+        &mut self,
+        _configuration: u8,
+    ) -> Result<Option<Vec<UsbInterface>>> {
+        Ok(None)
+    }
+
+    /// Called when this device is suspended, e.g. when the client detaches
+    fn on_suspend(&mut self) {}
+
+    /// Called when this device resumes from suspend
+    fn on_resume(&mut self) {}
+
+    /// Reset the device, e.g. after it exceeded its [UsbErrorBudget]
+    ///
+    /// Unsupported by default.
+    fn reset(&mut self) -> Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Reset not supported",
+        ))
+    }
+
     /// Helper to downcast to actual struct
     ///
     /// Please implement it as:
@@ -614,4 +1656,994 @@ mod test {
 
         assert!(res.is_err());
     }
+
+    fn new_cdc_device() -> UsbDevice {
+        UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            None,
+            cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(AsyncMutex::new(
+                Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )
+    }
+
+    async fn set_endpoint_halt(device: &UsbDevice, address: u8, halted: bool) -> Result<Vec<u8>> {
+        device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                SetupPacket {
+                    request_type: 0b00000010,
+                    request: if halted {
+                        StandardRequest::SetFeature
+                    } else {
+                        StandardRequest::ClearFeature
+                    } as u8,
+                    value: FeatureSelector::EndpointHalt as u16,
+                    index: address as u16,
+                    length: 0,
+                },
+                &[],
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_halt() {
+        setup_test_logger();
+        let device = new_cdc_device();
+        let (ep, intf) = device.find_ep(0x02).unwrap();
+
+        set_endpoint_halt(&device, 0x02, true).await.unwrap();
+        assert!(device.is_endpoint_halted(0x02));
+        let res = device
+            .handle_urb(ep, intf, 0, SetupPacket::default(), &[1, 2, 3])
+            .await;
+        assert!(res.is_err());
+
+        set_endpoint_halt(&device, 0x02, false).await.unwrap();
+        assert!(!device.is_endpoint_halted(0x02));
+        let res = device
+            .handle_urb(ep, intf, 0, SetupPacket::default(), &[1, 2, 3])
+            .await;
+        assert!(res.is_ok());
+
+        // unknown endpoint
+        assert!(set_endpoint_halt(&device, 0x05, true).await.is_err());
+    }
+
+    async fn get_status(device: &UsbDevice, request_type: u8, index: u16) -> Result<Vec<u8>> {
+        device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                2,
+                SetupPacket {
+                    request_type,
+                    request: StandardRequest::GetStatus as u8,
+                    value: 0,
+                    index,
+                    length: 2,
+                },
+                &[],
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_get_status() {
+        setup_test_logger();
+        let mut device = new_cdc_device();
+        device.self_powered = true;
+        device.remote_wakeup = true;
+
+        // device
+        assert_eq!(
+            get_status(&device, 0b10000000, 0).await.unwrap(),
+            [0x01, 0x00]
+        );
+        device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                SetupPacket {
+                    request_type: 0b00000000,
+                    request: StandardRequest::SetFeature as u8,
+                    value: FeatureSelector::DeviceRemoteWakeup as u16,
+                    index: 0,
+                    length: 0,
+                },
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            get_status(&device, 0b10000000, 0).await.unwrap(),
+            [0x03, 0x00]
+        );
+
+        // interface
+        assert_eq!(
+            get_status(&device, 0b10000001, 0).await.unwrap(),
+            [0x00, 0x00]
+        );
+        assert!(get_status(&device, 0b10000001, 1).await.is_err());
+
+        // endpoint
+        assert_eq!(
+            get_status(&device, 0b10000010, 0x82).await.unwrap(),
+            [0x00, 0x00]
+        );
+        set_endpoint_halt(&device, 0x82, true).await.unwrap();
+        assert_eq!(
+            get_status(&device, 0b10000010, 0x82).await.unwrap(),
+            [0x01, 0x00]
+        );
+        assert!(get_status(&device, 0b10000010, 0x85).await.is_err());
+    }
+
+    async fn control(
+        device: &UsbDevice,
+        request_type: u8,
+        request: StandardRequest,
+        value: u16,
+        index: u16,
+    ) -> Result<Vec<u8>> {
+        let ep = if request_type & 0x80 != 0 {
+            device.ep0_in
+        } else {
+            device.ep0_out
+        };
+        device
+            .handle_urb(
+                ep,
+                None,
+                1,
+                SetupPacket {
+                    request_type,
+                    request: request as u8,
+                    value,
+                    index,
+                    length: (request_type >> 7) as u16,
+                },
+                &[],
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_configuration_and_alternate_setting() {
+        setup_test_logger();
+        use StandardRequest::*;
+        let device = new_cdc_device();
+        let intf = &device.interfaces[0];
+        assert_eq!(device.current_configuration(), 1);
+        assert_eq!(intf.current_configuration(), 1);
+
+        control(&device, 0b00000000, SetConfiguration, 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            control(&device, 0b10000000, GetConfiguration, 0, 0)
+                .await
+                .unwrap(),
+            [0]
+        );
+        assert!(
+            control(&device, 0b00000000, SetConfiguration, 2, 0)
+                .await
+                .is_err()
+        );
+        control(&device, 0b00000000, SetConfiguration, 1, 0)
+            .await
+            .unwrap();
+        assert_eq!(intf.current_configuration(), 1);
+
+        // the interface has no alternate settings
+        assert!(
+            control(&device, 0b00000001, SetInterface, 1, 0)
+                .await
+                .is_err()
+        );
+        assert_eq!(intf.alternate_setting(), 0);
+        assert_eq!(
+            control(&device, 0b10000001, GetInterface, 0, 0)
+                .await
+                .unwrap(),
+            [0]
+        );
+        assert!(
+            control(&device, 0b00000001, SetInterface, 0, 1)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_of_alternate_setting() {
+        setup_test_logger();
+        use StandardRequest::*;
+        let iso = |max_packet_size| UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            max_packet_size,
+            interval: 1,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                None,
+                vec![],
+                shared_interface_handler(crate::loopback::UsbLoopbackHandler::new()),
+            )
+            .with_alternate_setting(vec![iso(64)], vec![])
+            .with_alternate_setting(vec![iso(512)], vec![]);
+        assert!(device.interfaces[0].current_endpoints().is_empty());
+        assert_eq!(device.find_ep(0x81).unwrap().0.max_packet_size, 64);
+
+        control(&device, 0b00000001, SetInterface, 2, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            device.interfaces[0].current_endpoints()[0].max_packet_size,
+            512
+        );
+        assert_eq!(device.find_ep(0x81).unwrap().0.max_packet_size, 512);
+        assert_eq!(
+            control(&device, 0b10000001, GetInterface, 0, 0)
+                .await
+                .unwrap(),
+            [2]
+        );
+        assert!(
+            control(&device, 0b00000001, SetInterface, 3, 0)
+                .await
+                .is_err()
+        );
+        assert_eq!(device.interfaces[0].alternate_setting(), 2);
+
+        // configuration resets alternate settings
+        control(&device, 0b00000000, SetConfiguration, 1, 0)
+            .await
+            .unwrap();
+        assert_eq!(device.interfaces[0].alternate_setting(), 0);
+    }
+
+    #[test]
+    fn test_class_specific_endpoint_descriptors_of_alternate_settings() {
+        setup_test_logger();
+        let iso = |max_packet_size| UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            max_packet_size,
+            interval: 1,
+        };
+        let mut device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::Audio as u8,
+                0x02,
+                0x00,
+                None,
+                vec![iso(64)],
+                shared_interface_handler(crate::loopback::UsbLoopbackHandler::new()),
+            )
+            .with_alternate_setting(vec![iso(512)], vec![]);
+        // like a streaming interface of a host device, reusing the address
+        let intf = &mut device.interfaces[0];
+        intf.class_specific_endpoint_descriptors
+            .insert(0x81, vec![0x03, 0x25, 0x00]);
+        intf.alternate_settings[0]
+            .class_specific_endpoint_descriptors
+            .insert(0x81, vec![0x03, 0x25, 0x01]);
+
+        let desc = device.configuration_descriptor(0xFF);
+        let contains = |needle: &[u8]| desc.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(&[
+            0x07, 0x05, 0x81, 0x01, 64, 0, 1, 0x03, 0x25, 0x00
+        ]));
+        assert!(contains(&[
+            0x07, 0x05, 0x81, 0x01, 0, 2, 1, 0x03, 0x25, 0x01
+        ]));
+    }
+
+    #[tokio::test]
+    async fn test_interface_numbers_with_gaps() {
+        setup_test_logger();
+        use StandardRequest::*;
+        let mut device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                None,
+                vec![],
+                shared_interface_handler(crate::loopback::UsbLoopbackHandler::new()),
+            )
+            .with_alternate_setting(vec![], vec![]);
+        // like a host device whose interface 0 is not exported
+        device.interfaces[0].interface_number = 2;
+
+        let desc = device.configuration_descriptor(0xFF);
+        assert_eq!((desc[4], desc[9 + 2]), (1, 2));
+        assert!(
+            control(&device, 0b10000001, GetInterface, 0, 0)
+                .await
+                .is_err()
+        );
+        control(&device, 0b00000001, SetInterface, 1, 2)
+            .await
+            .unwrap();
+        assert_eq!(device.interfaces[0].alternate_setting(), 1);
+        assert_eq!(
+            control(&device, 0b10000001, GetInterface, 0, 2)
+                .await
+                .unwrap(),
+            [1]
+        );
+    }
+
+    #[test]
+    fn test_log_target() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_location(1, &[2]);
+        assert_eq!(device.log_target(), "usbip::device::1-2");
+    }
+
+    #[test]
+    fn test_version_bcd() {
+        setup_test_logger();
+        assert_eq!(Version::from(0x0210).to_bcd(), 0x0210);
+        assert_eq!(Version::from(0x0310).to_bcd(), 0x0310);
+    }
+
+    #[test]
+    fn test_superspeed_descriptors() {
+        setup_test_logger();
+        let device = new_cdc_device().with_speed(UsbSpeed::SuperPlus);
+        assert_eq!(device.max_packet_size0(), 9);
+        assert_eq!(device.usb_version.to_bcd(), 0x0310);
+        assert_eq!(
+            device
+                .clone()
+                .with_ep0_max_packet_size(256)
+                .max_packet_size0(),
+            8
+        );
+
+        let desc = device.configuration_descriptor(0);
+        verify_descriptor(&desc);
+        let companions = desc
+            .windows(2)
+            .filter(|w| w == &[0x06, DescriptorType::SuperspeedUsbEndpointCompanion as u8])
+            .count();
+        assert_eq!(companions, 3);
+
+        let desc = device.bos_descriptor();
+        verify_descriptor(&desc[5..]);
+        assert_eq!(u16::from_le_bytes([desc[2], desc[3]]) as usize, desc.len());
+        assert_eq!(desc[4], 3);
+
+        // high speed devices only get an empty BOS
+        let device = new_cdc_device();
+        assert_eq!(device.bos_descriptor(), [0x05, 0x0F, 0x05, 0x00, 0x00]);
+        verify_descriptor(&device.configuration_descriptor(0));
+    }
+
+    async fn vendor_in(device: &UsbDevice, request: u8, index: u16) -> Result<Vec<u8>> {
+        device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket {
+                    request_type: 0b11000000,
+                    request,
+                    value: 0,
+                    index,
+                    length: 0xFF,
+                },
+                &[],
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_ms_os_descriptors() {
+        setup_test_logger();
+        let device = new_cdc_device().with_ms_os_descriptors(MsOsDescriptors::winusb(0x20));
+        assert_eq!(device.usb_version.to_bcd(), 0x0201);
+
+        // MS OS 1.0
+        let desc = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket {
+                    request_type: 0b10000000,
+                    request: StandardRequest::GetDescriptor as u8,
+                    value: ((DescriptorType::String as u16) << 8) | MS_OS_STRING_INDEX as u16,
+                    index: 0,
+                    length: 0xFF,
+                },
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            desc,
+            [
+                0x12, 0x03, b'M', 0, b'S', 0, b'F', 0, b'T', 0, b'1', 0, b'0', 0, b'0', 0, 0x20,
+                0x00
+            ]
+        );
+        let desc = vendor_in(&device, 0x20, MS_OS_10_EXTENDED_COMPAT_ID_INDEX)
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 40);
+        assert_eq!(desc[0..4], 40u32.to_le_bytes());
+        assert_eq!(desc[16], 0); // bFirstInterfaceNumber
+        assert_eq!(&desc[18..26], b"WINUSB\0\0");
+
+        // MS OS 2.0
+        let bos = device.bos_descriptor();
+        assert_eq!(u16::from_le_bytes([bos[2], bos[3]]) as usize, bos.len());
+        assert_eq!(bos[4], 1);
+        verify_descriptor(&bos[5..]);
+        let set = vendor_in(&device, 0x20, MS_OS_20_DESCRIPTOR_INDEX)
+            .await
+            .unwrap();
+        assert_eq!(set, MsOsDescriptors::winusb_descriptor_set());
+        assert_eq!(u16::from_le_bytes([set[8], set[9]]) as usize, set.len());
+        // wMSOSDescriptorSetTotalLength and bMS_VendorCode
+        assert_eq!(bos[5 + 24..5 + 27], [set.len() as u8, 0x00, 0x20]);
+
+        // other vendor requests still go to the handlers
+        assert!(
+            device
+                .ms_os_descriptors
+                .as_ref()
+                .unwrap()
+                .handle_request(&SetupPacket {
+                    request_type: 0b11000000,
+                    request: 0x21,
+                    value: 0,
+                    index: MS_OS_20_DESCRIPTOR_INDEX,
+                    length: 0xFF,
+                })
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webusb_descriptors() {
+        setup_test_logger();
+        let device =
+            new_cdc_device().with_webusb(WebUsbDescriptors::new(0x30, Some("https://example.com")));
+        assert_eq!(device.usb_version.to_bcd(), 0x0210);
+
+        let bos = device.bos_descriptor();
+        assert_eq!(u16::from_le_bytes([bos[2], bos[3]]) as usize, bos.len());
+        assert_eq!(bos[4], 1);
+        verify_descriptor(&bos[5..]);
+        // bcdVersion, bVendorCode and iLandingPage
+        assert_eq!(bos[5 + 20..], [0x00, 0x01, 0x30, 0x01]);
+
+        let mut request = SetupPacket {
+            request_type: 0b11000000,
+            request: 0x30,
+            value: 1,
+            index: WEBUSB_GET_URL,
+            length: 0xFF,
+        };
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0xFF, request, &[])
+            .await
+            .unwrap();
+        assert_eq!(desc[..3], [3 + 11, 0x03, 0x01]);
+        assert_eq!(&desc[3..], b"example.com");
+
+        // unknown URL index
+        request.value = 2;
+        let descriptors = device.webusb_descriptors.as_ref().unwrap();
+        assert!(descriptors.handle_request(&request).is_none());
+    }
+
+    #[test]
+    fn test_interface_association() {
+        setup_test_logger();
+        let device = new_cdc_device()
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                None,
+                vec![],
+                Arc::new(AsyncMutex::new(
+                    Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .with_interface_association(
+                0,
+                2,
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                Some("Serial"),
+            );
+        assert_eq!(
+            (
+                device.device_class,
+                device.device_subclass,
+                device.device_protocol
+            ),
+            (0xEF, 0x02, 0x01)
+        );
+
+        let desc = device.configuration_descriptor(0);
+        verify_descriptor(&desc);
+        // the association sits between the configuration and the first interface
+        assert_eq!(
+            desc[9..17],
+            [0x08, 0x0B, 0x00, 0x02, 0x02, 0x02, 0x00, desc[16]]
+        );
+        assert_ne!(desc[16], 0);
+        assert_eq!(desc[17..19], [0x09, DescriptorType::Interface as u8]);
+    }
+
+    #[tokio::test]
+    async fn test_other_speed_descriptors() {
+        setup_test_logger();
+        let get_descriptor = |device: UsbDevice, descriptor_type: DescriptorType| async move {
+            device
+                .handle_urb(
+                    device.ep0_in,
+                    None,
+                    0xFF,
+                    SetupPacket {
+                        request_type: 0b10000000,
+                        request: StandardRequest::GetDescriptor as u8,
+                        value: (descriptor_type as u16) << 8,
+                        index: 0,
+                        length: 0xFF,
+                    },
+                    &[],
+                )
+                .await
+        };
+
+        let device = new_cdc_device();
+        let desc = get_descriptor(device.clone(), DescriptorType::DeviceQualifier)
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 0x0A);
+        assert_eq!(&desc[2..4], &[0x00, 0x02]);
+
+        let desc = get_descriptor(device.clone(), DescriptorType::OtherSpeedConfiguration)
+            .await
+            .unwrap();
+        verify_descriptor(&desc);
+        assert_eq!(desc[1], DescriptorType::OtherSpeedConfiguration as u8);
+        assert_eq!(desc.len(), device.configuration_descriptor(0).len());
+        // bulk endpoints are limited to 64 bytes at full speed
+        let bulk_in = desc.windows(4).position(|w| w == [0x07, 0x05, 0x82, 0x02]);
+        let bulk_in = bulk_in.unwrap();
+        assert_eq!(&desc[bulk_in + 4..bulk_in + 6], &[64, 0]);
+
+        let device = new_cdc_device().with_speed(UsbSpeed::Full);
+        assert!(
+            get_descriptor(device.clone(), DescriptorType::DeviceQualifier)
+                .await
+                .is_err()
+        );
+        assert!(
+            get_descriptor(device, DescriptorType::OtherSpeedConfiguration)
+                .await
+                .is_err()
+        );
+    }
+
+    #[derive(Debug, Default)]
+    struct SuspendCounter {
+        suspended: usize,
+        resumed: usize,
+    }
+
+    impl UsbInterfaceHandler for SuspendCounter {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn on_suspend(&mut self, _interface: &UsbInterface) {
+            self.suspended += 1;
+        }
+
+        fn on_resume(&mut self, _interface: &UsbInterface) {
+            self.resumed += 1;
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn test_suspend_resume() {
+        setup_test_logger();
+        let handler = Arc::new(AsyncMutex::new(
+            Box::new(SuspendCounter::default()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_interface(0xFF, 0, 0, None, vec![], handler.clone());
+        let counts = |handler: &Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>| {
+            let mut handler = handler.try_lock().unwrap();
+            let counter = handler.as_any().downcast_mut::<SuspendCounter>().unwrap();
+            (counter.suspended, counter.resumed)
+        };
+
+        device.resume().await;
+        assert_eq!(counts(&handler), (0, 0));
+        device.suspend().await;
+        device.suspend().await;
+        assert!(device.state().suspended);
+        assert_eq!(counts(&handler), (1, 0));
+        device.reset_state();
+        assert!(device.state().suspended);
+        device.resume().await;
+        assert!(!device.state().suspended);
+        assert_eq!(counts(&handler), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_remote_wakeup_resumes_handlers() {
+        setup_test_logger();
+        let handler = Arc::new(AsyncMutex::new(
+            Box::new(SuspendCounter::default()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let mut device =
+            UsbDevice::new(0).with_interface(0xFF, 0, 0, None, vec![], handler.clone());
+        device.remote_wakeup = true;
+        let counts = |handler: &Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>| {
+            let mut handler = handler.try_lock().unwrap();
+            let counter = handler.as_any().downcast_mut::<SuspendCounter>().unwrap();
+            (counter.suspended, counter.resumed)
+        };
+
+        control(
+            &device,
+            0b00000000,
+            StandardRequest::SetFeature,
+            FeatureSelector::DeviceRemoteWakeup as u16,
+            0,
+        )
+        .await
+        .unwrap();
+        device.suspend().await;
+        assert!(device.interfaces[0].request_remote_wakeup());
+        assert!(device.state().wakeup_pending);
+        assert!(device.request_remote_wakeup().await);
+        assert!(!device.state().suspended);
+        assert!(!device.state().wakeup_pending);
+        assert_eq!(counts(&handler), (1, 1));
+
+        // the client resuming later does not notify the handlers again
+        device.resume().await;
+        assert_eq!(counts(&handler), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_remote_wakeup_unsupported() {
+        setup_test_logger();
+        let device = new_cdc_device();
+        let res = device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                SetupPacket {
+                    request_type: 0b00000000,
+                    request: StandardRequest::SetFeature as u8,
+                    value: FeatureSelector::DeviceRemoteWakeup as u16,
+                    index: 0,
+                    length: 0,
+                },
+                &[],
+            )
+            .await;
+        assert!(res.is_err());
+        assert!(!device.state().remote_wakeup_enabled);
+        assert!(!device.request_remote_wakeup().await);
+    }
+
+    #[derive(Debug, Default)]
+    struct VendorDevice {
+        configuration: Option<u8>,
+        written: Vec<u8>,
+    }
+
+    impl UsbDeviceHandler for VendorDevice {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            setup: SetupPacket,
+            req: &[u8],
+        ) -> Result<Vec<u8>> {
+            assert_eq!(setup.request_type, 0b01000000);
+            self.written.extend_from_slice(req);
+            Ok(vec![])
+        }
+
+        fn get_status(&mut self, status: u16) -> u16 {
+            status | 0x1
+        }
+
+        fn set_configuration(&mut self, configuration: u8) -> Result<()> {
+            self.configuration = Some(configuration);
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn test_device_handler() {
+        setup_test_logger();
+        let vendor_out = SetupPacket {
+            request_type: 0b01000000,
+            request: 0x42,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+
+        // without a device handler, vendor requests fail
+        let device = UsbDevice::new(0);
+        let res = device
+            .handle_urb(device.ep0_out, None, 2, vendor_out, &[1, 2])
+            .await;
+        assert!(res.is_err());
+
+        let handler = Arc::new(AsyncMutex::new(
+            Box::new(VendorDevice::default()) as Box<dyn UsbDeviceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_device_handler(handler.clone());
+        device
+            .handle_urb(device.ep0_out, None, 2, vendor_out, &[1, 2])
+            .await
+            .unwrap();
+        assert_eq!(
+            get_status(&device, 0b10000000, 0).await.unwrap(),
+            [0x01, 0x00]
+        );
+        control(&device, 0b00000000, StandardRequest::SetConfiguration, 0, 0)
+            .await
+            .unwrap();
+
+        let mut handler = handler.lock().await;
+        let handler = handler.as_any().downcast_mut::<VendorDevice>().unwrap();
+        assert_eq!(handler.written, [1, 2]);
+        assert_eq!(handler.configuration, Some(0));
+    }
+
+    /// Only accepts requests while configured
+    #[derive(Debug, Default)]
+    struct ConfiguredOnlyDevice;
+
+    impl UsbDeviceHandler for ConfiguredOnlyDevice {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!()
+        }
+
+        fn handle_control(
+            &mut self,
+            state: &UsbDeviceState,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<UsbControlResponse> {
+            Ok(if state.configuration == 0 {
+                UsbControlResponse::Stall
+            } else {
+                UsbControlResponse::Ack
+            })
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn test_device_handler_control() {
+        setup_test_logger();
+        let vendor_out = SetupPacket {
+            request_type: 0b01000000,
+            request: 0x42,
+            value: 0,
+            index: 0,
+            length: 0,
+        };
+        let device = UsbDevice::new(0)
+            .with_device_handler(Arc::new(AsyncMutex::new(Box::new(ConfiguredOnlyDevice))));
+        let res = device
+            .handle_urb(device.ep0_out, None, 0, vendor_out, &[])
+            .await;
+        assert_eq!(res.unwrap(), [0u8; 0]);
+
+        control(&device, 0b00000000, StandardRequest::SetConfiguration, 0, 0)
+            .await
+            .unwrap();
+        let res = device
+            .handle_urb(device.ep0_out, None, 0, vendor_out, &[])
+            .await;
+        assert!(res.is_err());
+    }
+
+    /// Counts how often it was released
+    #[derive(Debug, Default)]
+    struct ReleaseCounter(Arc<std::sync::atomic::AtomicU32>);
+
+    impl UsbInterfaceHandler for ReleaseCounter {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!()
+        }
+
+        fn release(&mut self, _interface: &UsbInterface) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// Like a host device whose configuration `n` has `n` interfaces
+    #[derive(Debug)]
+    struct SwitchingDevice;
+
+    impl UsbDeviceHandler for SwitchingDevice {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!()
+        }
+
+        fn set_configuration(&mut self, configuration: u8) -> Result<()> {
+            if configuration > 2 {
+                return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+            }
+            Ok(())
+        }
+
+        fn configuration_interfaces(
+            &mut self,
+            configuration: u8,
+        ) -> Result<Option<Vec<UsbInterface>>> {
+            let device = (0..configuration).fold(UsbDevice::new(0), |device, _| {
+                device.with_interface(
+                    ClassCode::VendorSpecific as u8,
+                    0x00,
+                    0x00,
+                    None,
+                    vec![],
+                    shared_interface_handler(crate::loopback::UsbLoopbackHandler::new()),
+                )
+            });
+            Ok(Some(device.interfaces))
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn test_switch_configuration() {
+        setup_test_logger();
+        use StandardRequest::*;
+        let released = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let mut device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                None,
+                vec![],
+                shared_interface_handler(ReleaseCounter(released.clone())),
+            )
+            .with_device_handler(Arc::new(AsyncMutex::new(Box::new(SwitchingDevice))));
+        device.num_configurations = 2;
+
+        // left to the handler to validate
+        assert!(
+            control(&device, 0b00000000, SetConfiguration, 3, 0)
+                .await
+                .is_err()
+        );
+        control(&device, 0b00000000, SetConfiguration, 2, 0)
+            .await
+            .unwrap();
+        assert_eq!(released.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        let reconfigured = device.reconfigured().await.unwrap().unwrap();
+        assert_eq!(reconfigured.configuration_value, 2);
+        assert_eq!(reconfigured.interfaces.len(), 2);
+        assert_eq!(reconfigured.interfaces[1].current_configuration(), 2);
+        let desc = reconfigured.configuration_descriptor(0xFF);
+        // bNumInterfaces and bConfigurationValue
+        assert_eq!((desc[4], desc[5]), (2, 2));
+
+        control(&device, 0b00000000, SetConfiguration, 0, 0)
+            .await
+            .unwrap();
+        assert!(device.reconfigured().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_interface_handler() {
+        setup_test_logger();
+        let mut cdc = cdc::UsbCdcAcmHandler::new();
+        cdc.tx_buffer = vec![1, 2, 3];
+        let handler = BlockingInterfaceHandler::new(cdc, 1);
+        assert_eq!(
+            handler.supported_requests(),
+            Some(&[0x20, 0x21, 0x22, 0x23][..])
+        );
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            None,
+            cdc::UsbCdcAcmHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+
+        let (ep, intf) = device.find_ep(0x82).unwrap();
+        let mut urb = Urb {
+            endpoint: ep,
+            transfer_buffer_length: 0x40,
+            ..Default::default()
+        };
+        device.submit_urb(intf, &mut urb).await.unwrap();
+        assert_eq!(urb.buffer, [1, 2, 3]);
+        assert_eq!(urb.actual_length, 3);
+    }
 }
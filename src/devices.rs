@@ -1,4 +1,21 @@
+pub mod audio;
+pub mod ccid;
 pub mod cdc;
+pub mod cp210x;
+pub mod ctap;
+pub mod ftdi;
 pub mod hid;
 #[cfg(feature = "rusb")]
 pub mod host;
+pub mod hub;
+pub mod loopback;
+pub mod midi;
+pub mod msc;
+pub mod mtp;
+pub mod printer;
+pub mod raw;
+pub mod rndis;
+#[cfg(feature = "serial")]
+pub mod serial;
+pub mod usbtmc;
+pub mod uvc;
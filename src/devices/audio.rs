@@ -0,0 +1,622 @@
+//! Implement a USB Audio Class 1.0 device
+//!
+//! [UsbDevice::audio] builds an audio control interface and an audio
+//! streaming interface per [UsbAudioStreamHandler]: speakers play what the
+//! host sends on an isochronous OUT endpoint, microphones record what it
+//! reads on an isochronous IN endpoint. Every [UsbAudioFormat] of a stream
+//! is an alternate setting, and the host picks one of its sample rates with
+//! the sampling frequency control of the endpoint. PCM frames are passed to
+//! an [AudioBackend].
+use super::super::*;
+
+// reference:
+// USB Audio 1.0: https://www.usb.org/sites/default/files/audio10.pdf
+// USB Audio Data Formats 1.0: https://www.usb.org/sites/default/files/frmts10.pdf
+
+const AUDIO_CONTROL_SUBCLASS: u8 = 0x01;
+/// bInterfaceSubClass of the audio streaming interfaces
+pub const AUDIO_STREAMING_SUBCLASS: u8 = 0x02;
+
+// class specific descriptor types
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+// terminal types
+const TERMINAL_USB_STREAMING: u16 = 0x0101;
+const TERMINAL_MICROPHONE: u16 = 0x0201;
+const TERMINAL_SPEAKER: u16 = 0x0301;
+
+// class requests
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+/// Control selector of the sampling frequency of an endpoint
+const SAMPLING_FREQ_CONTROL: u8 = 0x01;
+
+/// A format offered by a stream, as one alternate setting
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbAudioFormat {
+    pub channels: u8,
+    /// 16, 24 or 32
+    pub bits_per_sample: u8,
+    /// In Hz, the first is the default
+    pub sample_rates: Vec<u32>,
+}
+
+impl UsbAudioFormat {
+    /// 16 bit stereo at 48 and 44.1 kHz
+    pub fn cd_quality() -> Self {
+        Self {
+            channels: 2,
+            bits_per_sample: 16,
+            sample_rates: vec![48000, 44100],
+        }
+    }
+}
+
+/// The format the host is streaming in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PcmFormat {
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    pub sample_rate: u32,
+}
+
+impl PcmFormat {
+    /// Bytes of a sample of one channel
+    pub fn subframe_len(&self) -> usize {
+        self.bits_per_sample.div_ceil(8) as usize
+    }
+
+    /// Bytes of a sample of all channels
+    pub fn frame_len(&self) -> usize {
+        self.subframe_len() * self.channels as usize
+    }
+}
+
+/// Plays and records the PCM frames of a [UsbAudioStreamHandler]
+///
+/// Frames are interleaved little endian samples, one packet per millisecond.
+pub trait AudioBackend: std::fmt::Debug {
+    /// The host started streaming in `format`, or stopped if `None`
+    fn set_format(&mut self, _format: Option<PcmFormat>) {}
+
+    /// Consume the frames `frames` played by the host on a speaker
+    fn play(&mut self, _format: &PcmFormat, _frames: &[u8]) {}
+
+    /// Fill `frames` with frames recorded by a microphone, silence by default
+    fn record(&mut self, _format: &PcmFormat, frames: &mut [u8]) {
+        frames.fill(0);
+    }
+}
+
+/// A handler of an audio streaming interface
+#[derive(Debug)]
+pub struct UsbAudioStreamHandler {
+    /// Out for speakers, In for microphones
+    direction: Direction,
+    formats: Vec<UsbAudioFormat>,
+    backend: Box<dyn AudioBackend + Send>,
+    /// bTerminalLink of the formats, set by [UsbDevice::audio]
+    terminal: u8,
+    sample_rate: u32,
+    /// The format of the selected alternate setting, none for setting 0
+    current: Option<PcmFormat>,
+    /// Thousandths of frames left over by previous IN packets
+    remainder: u32,
+}
+
+impl UsbAudioStreamHandler {
+    /// A stream the host plays on
+    pub fn speaker(
+        formats: Vec<UsbAudioFormat>,
+        backend: impl AudioBackend + Send + 'static,
+    ) -> Self {
+        Self::new(Direction::Out, formats, backend)
+    }
+
+    /// A stream the host records from
+    pub fn microphone(
+        formats: Vec<UsbAudioFormat>,
+        backend: impl AudioBackend + Send + 'static,
+    ) -> Self {
+        Self::new(Direction::In, formats, backend)
+    }
+
+    fn new(
+        direction: Direction,
+        formats: Vec<UsbAudioFormat>,
+        backend: impl AudioBackend + Send + 'static,
+    ) -> Self {
+        assert!(
+            !formats.is_empty() && formats.iter().all(|f| !f.sample_rates.is_empty()),
+            "Streams need formats with sample rates"
+        );
+        Self {
+            direction,
+            sample_rate: formats[0].sample_rates[0],
+            formats,
+            backend: Box::new(backend),
+            terminal: 0,
+            current: None,
+            remainder: 0,
+        }
+    }
+
+    pub fn backend(&mut self) -> &mut Box<dyn AudioBackend + Send> {
+        &mut self.backend
+    }
+
+    /// The format the host is streaming in, if it is
+    pub fn current_format(&self) -> Option<PcmFormat> {
+        self.current
+    }
+
+    /// The isochronous endpoint of the alternate setting of `format`
+    fn endpoint(&self, number: u8, format: &UsbAudioFormat) -> UsbEndpoint {
+        let max_rate = *format.sample_rates.iter().max().unwrap();
+        let frame_len = format.bits_per_sample.div_ceil(8) as u32 * format.channels as u32;
+        let (address, attributes) = match self.direction {
+            // adaptive
+            Direction::Out => (number, 0x09),
+            // synchronous
+            Direction::In => (0x80 | number, 0x0D),
+        };
+        UsbEndpoint {
+            address,
+            attributes,
+            max_packet_size: (max_rate.div_ceil(1000) * frame_len) as u16,
+            interval: 1,
+        }
+    }
+
+    /// The class specific descriptors of the alternate setting of `format`
+    fn format_descriptor(&self, format: &UsbAudioFormat) -> Vec<u8> {
+        let mut desc = vec![
+            0x07,
+            CS_INTERFACE,
+            0x01, // bDescriptorSubtype: AS_GENERAL
+            self.terminal,
+            0x01, // bDelay
+            0x01,
+            0x00, // wFormatTag: PCM
+            0x08 + 3 * format.sample_rates.len() as u8,
+            CS_INTERFACE,
+            0x02, // bDescriptorSubtype: FORMAT_TYPE
+            0x01, // bFormatType: FORMAT_TYPE_I
+            format.channels,
+            format.bits_per_sample.div_ceil(8), // bSubframeSize
+            format.bits_per_sample,
+            format.sample_rates.len() as u8, // bSamFreqType: discrete
+        ];
+        for rate in &format.sample_rates {
+            desc.extend_from_slice(&rate.to_le_bytes()[..3]);
+        }
+        desc
+    }
+
+    fn select(&mut self, alternate_setting: u8) {
+        let format = (alternate_setting as usize)
+            .checked_sub(1)
+            .and_then(|i| self.formats.get(i));
+        self.current = format.map(|format| {
+            if !format.sample_rates.contains(&self.sample_rate) {
+                self.sample_rate = format.sample_rates[0];
+            }
+            PcmFormat {
+                channels: format.channels,
+                bits_per_sample: format.bits_per_sample,
+                sample_rate: self.sample_rate,
+            }
+        });
+        self.remainder = 0;
+        debug!("Audio stream format {:?}", self.current);
+        self.backend.set_format(self.current);
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) -> Result<()> {
+        if !self.formats.iter().any(|f| f.sample_rates.contains(&rate)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported sample rate {rate}"),
+            ));
+        }
+        self.sample_rate = rate;
+        if let Some(current) = self.current.as_mut()
+            && current.sample_rate != rate
+        {
+            current.sample_rate = rate;
+            self.remainder = 0;
+            self.backend.set_format(self.current);
+        }
+        Ok(())
+    }
+}
+
+impl UsbInterfaceHandler for UsbAudioStreamHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if !ep.is_ep0() {
+            // isochronous transfers come with packets, see submit_urb
+            return Ok(vec![]);
+        }
+        match (setup.request_type, setup.request, (setup.value >> 8) as u8) {
+            (0b00100010, SET_CUR, SAMPLING_FREQ_CONTROL) if req.len() >= 3 => {
+                self.set_sample_rate(u32::from_le_bytes([req[0], req[1], req[2], 0]))?;
+                Ok(vec![])
+            }
+            (0b10100010, GET_CUR, SAMPLING_FREQ_CONTROL) => {
+                Ok(self.sample_rate.to_le_bytes()[..3].to_vec())
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported audio streaming request: {setup:x?}"),
+            )),
+        }
+    }
+
+    fn submit_urb(&mut self, interface: &UsbInterface, urb: &mut Urb) -> Result<()> {
+        if urb.endpoint.attributes & 0x03 != EndpointAttributes::Isochronous as u8 {
+            let data = self.handle_urb(
+                interface,
+                urb.endpoint,
+                urb.transfer_buffer_length,
+                urb.setup,
+                &urb.buffer,
+            )?;
+            urb.complete(data);
+            return Ok(());
+        }
+        let mut packets = urb.iso_packets();
+        let mut data = vec![];
+        for packet in &mut packets {
+            packet.status = 0;
+            packet.actual_length = 0;
+            let Some(format) = self.current else {
+                continue;
+            };
+            match self.direction {
+                Direction::Out => {
+                    let start = (packet.offset as usize).min(urb.buffer.len());
+                    let end = (start + packet.length as usize).min(urb.buffer.len());
+                    self.backend.play(&format, &urb.buffer[start..end]);
+                    packet.actual_length = (end - start) as u32;
+                }
+                Direction::In => {
+                    // one packet per millisecond, carrying the fractions of frames over
+                    self.remainder += format.sample_rate;
+                    let frames = self.remainder / 1000;
+                    self.remainder %= 1000;
+                    let len = (frames as usize * format.frame_len()).min(packet.length as usize);
+                    let start = data.len();
+                    data.resize(start + len, 0);
+                    self.backend.record(&format, &mut data[start..]);
+                    packet.actual_length = len as u32;
+                }
+            }
+        }
+        urb.complete_iso(&packets, data);
+        Ok(())
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[SET_CUR, GET_CUR])
+    }
+
+    fn set_alternate_setting(&mut self, _interface: &UsbInterface, alternate_setting: u8) {
+        self.select(alternate_setting);
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        // setting 0 has no endpoints
+        vec![]
+    }
+
+    fn get_class_specific_endpoint_descriptor(&self, _endpoint: &UsbEndpoint) -> Vec<u8> {
+        vec![
+            0x07,
+            CS_ENDPOINT,
+            0x01, // bDescriptorSubtype: EP_GENERAL
+            0x01, // bmAttributes: sampling frequency control
+            0x00, // bLockDelayUnits
+            0x00,
+            0x00, // wLockDelay
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The audio control interface, with a pair of terminals for every stream
+#[derive(Debug)]
+struct UsbAudioControlHandler {
+    descriptor: Vec<u8>,
+}
+
+impl UsbInterfaceHandler for UsbAudioControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported audio control request: {setup:x?}"),
+        ))
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.descriptor.clone()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A full speed audio device with the speakers and microphones of `streams`
+    ///
+    /// Interface 0 controls the audio function, stream `i` is interface `i + 1`.
+    pub fn audio(streams: Vec<UsbAudioStreamHandler>) -> Self {
+        assert!(!streams.is_empty(), "Audio devices need a stream");
+        let mut terminals = vec![];
+        let mut streams: Vec<_> = streams
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut stream)| {
+                let (input_id, output_id) = (2 * i as u8 + 1, 2 * i as u8 + 2);
+                let format = &stream.formats[0];
+                let (input_type, output_type) = match stream.direction {
+                    Direction::Out => (TERMINAL_USB_STREAMING, TERMINAL_SPEAKER),
+                    Direction::In => (TERMINAL_MICROPHONE, TERMINAL_USB_STREAMING),
+                };
+                // left and right front for stereo
+                let channel_config: u16 = if format.channels == 2 { 0x0003 } else { 0 };
+                terminals.extend([0x0C, CS_INTERFACE, 0x02, input_id]); // INPUT_TERMINAL
+                terminals.extend(input_type.to_le_bytes());
+                terminals.extend([0x00, format.channels]); // bAssocTerminal, bNrChannels
+                terminals.extend(channel_config.to_le_bytes());
+                terminals.extend([0x00, 0x00]); // iChannelNames, iTerminal
+                terminals.extend([0x09, CS_INTERFACE, 0x03, output_id]); // OUTPUT_TERMINAL
+                terminals.extend(output_type.to_le_bytes());
+                terminals.extend([0x00, input_id, 0x00]); // bAssocTerminal, bSourceID, iTerminal
+                // the terminal connected to the endpoint
+                stream.terminal = match stream.direction {
+                    Direction::Out => input_id,
+                    Direction::In => output_id,
+                };
+                stream
+            })
+            .collect();
+
+        let total_len = 8 + streams.len() + terminals.len();
+        let mut descriptor = vec![
+            8 + streams.len() as u8,
+            CS_INTERFACE,
+            0x01, // bDescriptorSubtype: HEADER
+            0x00,
+            0x01, // bcdADC 1.00
+            total_len as u8,
+            (total_len >> 8) as u8, // wTotalLength
+            streams.len() as u8,    // bInCollection
+        ];
+        descriptor.extend((1..=streams.len()).map(|i| i as u8));
+        descriptor.extend(terminals);
+
+        let mut device = Self::new(0).with_speed(UsbSpeed::Full).with_interface(
+            ClassCode::Audio as u8,
+            AUDIO_CONTROL_SUBCLASS,
+            0x00,
+            None,
+            vec![],
+            shared_interface_handler(UsbAudioControlHandler { descriptor }),
+        );
+        let (mut out_number, mut in_number) = (1, 1);
+        for stream in streams.drain(..) {
+            let number = match stream.direction {
+                Direction::Out => &mut out_number,
+                Direction::In => &mut in_number,
+            };
+            let settings: Vec<_> = stream
+                .formats
+                .iter()
+                .map(|format| {
+                    (
+                        stream.endpoint(*number, format),
+                        stream.format_descriptor(format),
+                    )
+                })
+                .collect();
+            *number += 1;
+            let name = match stream.direction {
+                Direction::Out => "Speaker",
+                Direction::In => "Microphone",
+            };
+            device = device.with_interface(
+                ClassCode::Audio as u8,
+                AUDIO_STREAMING_SUBCLASS,
+                0x00,
+                Some(name),
+                vec![],
+                shared_interface_handler(stream),
+            );
+            for (endpoint, descriptor) in settings {
+                device = device.with_alternate_setting(vec![endpoint], descriptor);
+            }
+        }
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0007;
+        device.set_product_name("Virtual Audio");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// Records the played frames, and records a recognizable pattern
+    #[derive(Debug)]
+    struct Recorder {
+        formats: Arc<Mutex<Vec<Option<PcmFormat>>>>,
+        played: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl AudioBackend for Recorder {
+        fn set_format(&mut self, format: Option<PcmFormat>) {
+            self.formats.lock().unwrap().push(format);
+        }
+
+        fn play(&mut self, _format: &PcmFormat, frames: &[u8]) {
+            self.played.lock().unwrap().extend_from_slice(frames);
+        }
+
+        fn record(&mut self, _format: &PcmFormat, frames: &mut [u8]) {
+            frames.fill(0x11);
+        }
+    }
+
+    fn control(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    fn iso_urb(endpoint: UsbEndpoint, packets: &[(u32, u32)], buffer: Vec<u8>) -> Urb {
+        let mut urb = Urb {
+            endpoint,
+            transfer_buffer_length: packets.iter().map(|(_, len)| len).sum(),
+            buffer,
+            ..Default::default()
+        };
+        let packets: Vec<IsoPacket> = packets
+            .iter()
+            .map(|&(offset, length)| IsoPacket {
+                offset,
+                length,
+                ..Default::default()
+            })
+            .collect();
+        urb.complete_iso(&packets, vec![]);
+        urb.number_of_packets = packets.len() as u32;
+        urb
+    }
+
+    #[tokio::test]
+    async fn audio_streams() {
+        setup_test_logger();
+        let formats = Arc::new(Mutex::new(vec![]));
+        let played = Arc::new(Mutex::new(vec![]));
+        let backend = || Recorder {
+            formats: formats.clone(),
+            played: played.clone(),
+        };
+        let mono = UsbAudioFormat {
+            channels: 1,
+            bits_per_sample: 24,
+            sample_rates: vec![16000],
+        };
+        let device = UsbDevice::audio(vec![
+            UsbAudioStreamHandler::speaker(vec![UsbAudioFormat::cd_quality()], backend()),
+            UsbAudioStreamHandler::microphone(vec![UsbAudioFormat::cd_quality(), mono], backend()),
+        ]);
+        let desc = device.configuration_descriptor(0xFFFF);
+        verify_descriptor(&desc);
+        assert_eq!(device.interfaces.len(), 3);
+        assert_eq!(device.interfaces[2].alternate_settings.len(), 2);
+        // the setting 0 and 1 of the speaker, 0 to 2 of the microphone
+        let interfaces = desc.windows(2).filter(|w| w == &[0x09, 0x04]).count();
+        assert_eq!(interfaces, 1 + 2 + 3);
+        let (speaker_ep, speaker) = device.find_ep(0x01).unwrap();
+        assert_eq!(speaker_ep.max_packet_size, 48 * 4);
+        let speaker = speaker.unwrap();
+
+        // select 44.1 kHz on the speaker and start playing
+        let set_interface = |interface, alt| control(0b00000001, 11, alt, interface, 0);
+        device
+            .handle_urb(device.ep0_out, None, 0, set_interface(1, 1), &[])
+            .await
+            .unwrap();
+        let set_rate = control(0b00100010, SET_CUR, 0x0100, 0x01, 3);
+        device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                set_rate,
+                &44100u32.to_le_bytes()[..3],
+            )
+            .await
+            .unwrap();
+        let get_rate = control(0b10100010, GET_CUR, 0x0100, 0x01, 3);
+        let rate = device.handle_urb(device.ep0_in, None, 3, get_rate, &[]);
+        assert_eq!(rate.await.unwrap(), 44100u32.to_le_bytes()[..3]);
+        let set_rate = control(0b00100010, SET_CUR, 0x0100, 0x01, 3);
+        let unsupported = device.handle_urb(device.ep0_out, None, 0, set_rate, &[0x10, 0, 0]);
+        assert!(unsupported.await.is_err());
+
+        let buffer: Vec<u8> = (0..=255).cycle().take(2 * 192).collect();
+        let mut urb = iso_urb(speaker_ep, &[(0, 176), (192, 180)], buffer.clone());
+        device.submit_urb(Some(speaker), &mut urb).await.unwrap();
+        assert_eq!(urb.actual_length, 176 + 180);
+        let packets = urb.iso_packets();
+        assert_eq!((packets[1].actual_length, packets[1].status), (180, 0));
+        let mut expected = buffer[..176].to_vec();
+        expected.extend_from_slice(&buffer[192..192 + 180]);
+        assert_eq!(*played.lock().unwrap(), expected);
+
+        // record mono 24 bit from the microphone
+        device
+            .handle_urb(device.ep0_out, None, 0, set_interface(2, 2), &[])
+            .await
+            .unwrap();
+        let (mic_ep, mic) = device.find_ep(0x81).unwrap();
+        let mut urb = iso_urb(mic_ep, &[(0, 144), (144, 144)], vec![]);
+        device.submit_urb(mic, &mut urb).await.unwrap();
+        assert_eq!(urb.actual_length, 2 * 16 * 3);
+        assert_eq!(urb.buffer, [0x11; 2 * 16 * 3]);
+        assert_eq!(urb.iso_packets()[1].offset, 144);
+
+        device
+            .handle_urb(device.ep0_out, None, 0, set_interface(1, 0), &[])
+            .await
+            .unwrap();
+        let cd = |sample_rate| {
+            Some(PcmFormat {
+                channels: 2,
+                bits_per_sample: 16,
+                sample_rate,
+            })
+        };
+        let mono = Some(PcmFormat {
+            channels: 1,
+            bits_per_sample: 24,
+            sample_rate: 16000,
+        });
+        assert_eq!(*formats.lock().unwrap(), [cd(48000), cd(44100), mono, None]);
+    }
+}
@@ -0,0 +1,468 @@
+//! Implement a CCID smart card reader
+//!
+//! [UsbCcidHandler] speaks the bulk protocol of the CCID class: it decodes
+//! PC_to_RDR messages, answers with RDR_to_PC messages and reports card
+//! insertion and removal on its interrupt endpoint. APDUs are exchanged
+//! with a [SmartCard], so software cards can be used by remote hosts.
+use super::super::*;
+
+// reference:
+// CCID 1.1: https://www.usb.org/sites/default/files/DWG_Smart-Card_CCID_Rev110.pdf
+
+/// Length of the header of every message
+const CCID_HEADER_LEN: usize = 10;
+/// Longest message, a short APDU and the header
+const CCID_MAX_MESSAGE_LEN: usize = 271;
+
+// PC_to_RDR messages
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6C;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6D;
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ESCAPE: u8 = 0x6B;
+const PC_TO_RDR_ICC_CLOCK: u8 = 0x6E;
+
+// RDR_to_PC messages
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+const RDR_TO_PC_ESCAPE: u8 = 0x83;
+const RDR_TO_PC_NOTIFY_SLOT_CHANGE: u8 = 0x50;
+
+// bError of failed commands
+const CMD_NOT_SUPPORTED: u8 = 0x00;
+const BAD_LENGTH: u8 = 0x01;
+/// Offset of bSlot, for a slot that does not exist
+const BAD_SLOT: u8 = 0x05;
+const ICC_MUTE: u8 = 0xFE;
+
+/// Command status of bStatus
+const COMMAND_FAILED: u8 = 0x40;
+
+/// Protocol data of T=1: Fi/Di, checksum, guard time, waiting times, clock stop, IFSC, NAD
+const T1_PARAMETERS: [u8; 7] = [0x11, 0x10, 0x00, 0x45, 0x00, 0xFE, 0x00];
+
+/// A card in the slot of a [UsbCcidHandler]
+pub trait SmartCard: std::fmt::Debug {
+    /// Activate the card, returning its Answer To Reset
+    fn power_on(&mut self) -> Vec<u8>;
+
+    fn power_off(&mut self) {}
+
+    /// Handle the command APDU `apdu`, returning the response APDU
+    fn transmit(&mut self, apdu: &[u8]) -> Vec<u8>;
+}
+
+/// A handler of a CCID reader with a single slot
+#[derive(Debug)]
+pub struct UsbCcidHandler {
+    card: Option<Box<dyn SmartCard + Send>>,
+    powered: bool,
+    /// Data of bulk OUT transfers, until a message is complete
+    pending: Vec<u8>,
+    /// Messages for the bulk IN endpoint
+    replies: VecDeque<Vec<u8>>,
+    /// Messages for the interrupt IN endpoint
+    notifications: VecDeque<Vec<u8>>,
+}
+
+impl Default for UsbCcidHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbCcidHandler {
+    /// A reader with an empty slot
+    pub fn new() -> Self {
+        Self {
+            card: None,
+            powered: false,
+            pending: vec![],
+            replies: VecDeque::new(),
+            notifications: VecDeque::new(),
+        }
+    }
+
+    pub fn with_card(mut self, card: impl SmartCard + Send + 'static) -> Self {
+        self.insert(card);
+        self
+    }
+
+    /// Put `card` into the slot, replacing the current card
+    pub fn insert(&mut self, card: impl SmartCard + Send + 'static) {
+        self.remove();
+        self.card = Some(Box::new(card));
+        self.notify_slot_change();
+    }
+
+    /// Take the card out of the slot
+    pub fn remove(&mut self) -> Option<Box<dyn SmartCard + Send>> {
+        let mut card = self.card.take()?;
+        if self.powered {
+            card.power_off();
+            self.powered = false;
+        }
+        self.notify_slot_change();
+        Some(card)
+    }
+
+    pub fn card(&mut self) -> Option<&mut Box<dyn SmartCard + Send>> {
+        self.card.as_mut()
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x82,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            // interrupt in
+            UsbEndpoint {
+                address: 0x83,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 8,
+                interval: 10,
+            },
+        ]
+    }
+
+    fn notify_slot_change(&mut self) {
+        // slot 0 changed, and whether a card is present
+        let state = 0x02 | u8::from(self.card.is_some());
+        self.notifications
+            .push_back(vec![RDR_TO_PC_NOTIFY_SLOT_CHANGE, state]);
+    }
+
+    /// bmICCStatus of bStatus
+    fn icc_status(&self) -> u8 {
+        match (&self.card, self.powered) {
+            (None, _) => 2,
+            (Some(_), true) => 0,
+            (Some(_), false) => 1,
+        }
+    }
+
+    /// Queue a reply to the message `header`, with `error` if the command failed
+    fn reply(&mut self, kind: u8, header: &[u8], error: Option<u8>, specific: u8, data: &[u8]) {
+        let mut status = self.icc_status();
+        if error.is_some() {
+            status |= COMMAND_FAILED;
+        }
+        let mut reply = Vec::with_capacity(CCID_HEADER_LEN + data.len());
+        reply.push(kind);
+        reply.extend((data.len() as u32).to_le_bytes());
+        // bSlot, bSeq
+        reply.extend_from_slice(&header[5..7]);
+        reply.extend([status, error.unwrap_or(0), specific]);
+        reply.extend_from_slice(data);
+        self.replies.push_back(reply);
+    }
+
+    fn parameters(&mut self, header: &[u8]) {
+        // bProtocolNum: T=1
+        self.reply(RDR_TO_PC_PARAMETERS, header, None, 0x01, &T1_PARAMETERS);
+    }
+
+    /// Handle the complete message `message`
+    fn handle_message(&mut self, message: &[u8]) {
+        let (header, data) = message.split_at(CCID_HEADER_LEN);
+        let kind = header[0];
+        debug!("CCID message {kind:#x} with {} bytes", data.len());
+        // messages answered with an RDR_to_PC_DataBlock
+        let data_block = matches!(kind, PC_TO_RDR_ICC_POWER_ON | PC_TO_RDR_XFR_BLOCK);
+        if header[5] != 0 {
+            let kind = match kind {
+                _ if data_block => RDR_TO_PC_DATA_BLOCK,
+                PC_TO_RDR_ESCAPE => RDR_TO_PC_ESCAPE,
+                _ => RDR_TO_PC_SLOT_STATUS,
+            };
+            return self.reply(kind, header, Some(BAD_SLOT), 0, &[]);
+        }
+        if data_block && self.card.is_none() {
+            return self.reply(RDR_TO_PC_DATA_BLOCK, header, Some(ICC_MUTE), 0, &[]);
+        }
+
+        match kind {
+            PC_TO_RDR_ICC_POWER_ON => {
+                let atr = self.card.as_mut().unwrap().power_on();
+                self.powered = true;
+                self.reply(RDR_TO_PC_DATA_BLOCK, header, None, 0, &atr);
+            }
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if self.powered
+                    && let Some(card) = self.card.as_mut()
+                {
+                    card.power_off();
+                }
+                self.powered = false;
+                // bClockStatus: stopped
+                self.reply(RDR_TO_PC_SLOT_STATUS, header, None, 0x01, &[]);
+            }
+            PC_TO_RDR_XFR_BLOCK if !self.powered => {
+                self.reply(RDR_TO_PC_DATA_BLOCK, header, Some(ICC_MUTE), 0, &[]);
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                let response = self.card.as_mut().unwrap().transmit(data);
+                self.reply(RDR_TO_PC_DATA_BLOCK, header, None, 0, &response);
+            }
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ICC_CLOCK => {
+                let clock = u8::from(!self.powered);
+                self.reply(RDR_TO_PC_SLOT_STATUS, header, None, clock, &[]);
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                // the T=1 parameters are fixed, APDUs are exchanged directly
+                self.parameters(header);
+            }
+            PC_TO_RDR_ESCAPE => {
+                self.reply(RDR_TO_PC_ESCAPE, header, Some(CMD_NOT_SUPPORTED), 0, &[]);
+            }
+            _ => {
+                warn!("Unsupported CCID message {kind:#x}");
+                self.reply(
+                    RDR_TO_PC_SLOT_STATUS,
+                    header,
+                    Some(CMD_NOT_SUPPORTED),
+                    0,
+                    &[],
+                );
+            }
+        }
+    }
+
+    /// Handle data of the bulk OUT endpoint
+    fn receive(&mut self, req: &[u8]) {
+        self.pending.extend_from_slice(req);
+        while self.pending.len() >= CCID_HEADER_LEN {
+            let len = u32::from_le_bytes(self.pending[1..5].try_into().unwrap()) as usize;
+            if CCID_HEADER_LEN + len > CCID_MAX_MESSAGE_LEN {
+                let header = self.pending[..CCID_HEADER_LEN].to_vec();
+                self.pending.clear();
+                let kind = match header[0] {
+                    PC_TO_RDR_XFR_BLOCK => RDR_TO_PC_DATA_BLOCK,
+                    PC_TO_RDR_ESCAPE => RDR_TO_PC_ESCAPE,
+                    _ => RDR_TO_PC_SLOT_STATUS,
+                };
+                return self.reply(kind, &header, Some(BAD_LENGTH), 0, &[]);
+            }
+            if self.pending.len() < CCID_HEADER_LEN + len {
+                return;
+            }
+            let message: Vec<u8> = self.pending.drain(..CCID_HEADER_LEN + len).collect();
+            self.handle_message(&message);
+        }
+    }
+
+    /// The CCID class descriptor
+    fn ccid_descriptor() -> Vec<u8> {
+        let mut desc = vec![
+            0x36, // bLength
+            0x21, // bDescriptorType: CCID
+            0x10, 0x01, // bcdCCID 1.10
+            0x00, // bMaxSlotIndex
+            0x07, // bVoltageSupport: 5V, 3V, 1.8V
+        ];
+        // dwProtocols: T=0, T=1
+        desc.extend(0x03u32.to_le_bytes());
+        // dwDefaultClock, dwMaximumClock in kHz, bNumClockSupported
+        desc.extend(3580u32.to_le_bytes());
+        desc.extend(3580u32.to_le_bytes());
+        desc.push(0);
+        // dwDataRate, dwMaxDataRate in bps, bNumDataRatesSupported
+        desc.extend(9600u32.to_le_bytes());
+        desc.extend(115200u32.to_le_bytes());
+        desc.push(0);
+        // dwMaxIFSD
+        desc.extend(254u32.to_le_bytes());
+        // dwSynchProtocols, dwMechanical
+        desc.extend(0u32.to_le_bytes());
+        desc.extend(0u32.to_le_bytes());
+        // dwFeatures: automatic parameters, activation, voltage, clock,
+        // baud rate and PPS, short APDU level exchange
+        desc.extend(0x0002_00BEu32.to_le_bytes());
+        desc.extend((CCID_MAX_MESSAGE_LEN as u32).to_le_bytes());
+        // bClassGetResponse, bClassEnvelope: echo the APDU class
+        desc.extend([0xFF, 0xFF]);
+        // wLcdLayout, bPINSupport, bMaxCCIDBusySlots
+        desc.extend([0x00, 0x00, 0x00, 0x01]);
+        desc
+    }
+}
+
+impl UsbInterfaceHandler for UsbCcidHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            // control transfers
+            return match (setup.request_type, setup.request) {
+                (0b00100001, 0x01) => {
+                    // ABORT, the bulk messages are never in progress
+                    Ok(vec![])
+                }
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Unsupported CCID request: {setup:x?}"),
+                )),
+            };
+        }
+        let queue = match ep.direction() {
+            Direction::Out => {
+                self.receive(req);
+                return Ok(vec![]);
+            }
+            Direction::In if ep.attributes == EndpointAttributes::Interrupt as u8 => {
+                &mut self.notifications
+            }
+            Direction::In => &mut self.replies,
+        };
+        let Some(mut message) = queue.pop_front() else {
+            return Ok(vec![]);
+        };
+        // the rest of a long message is left for the next transfer
+        let len = transfer_buffer_length as usize;
+        if message.len() > len {
+            queue.push_front(message.split_off(len));
+        }
+        Ok(message)
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        // ABORT
+        Some(&[0x01])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        Self::ccid_descriptor()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A smart card reader with the single interface of `handler`
+    pub fn ccid(handler: UsbCcidHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            0x00,
+            0x00,
+            None,
+            UsbCcidHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0005;
+        device.set_product_name("Virtual Smart Card Reader");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    const ATR: [u8; 5] = [0x3B, 0x80, 0x80, 0x01, 0x01];
+
+    /// Answers every APDU with its reverse and 90 00
+    #[derive(Debug)]
+    struct ReverseCard;
+
+    impl SmartCard for ReverseCard {
+        fn power_on(&mut self) -> Vec<u8> {
+            ATR.to_vec()
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Vec<u8> {
+            let mut response: Vec<u8> = apdu.iter().rev().copied().collect();
+            response.extend([0x90, 0x00]);
+            response
+        }
+    }
+
+    fn message(kind: u8, slot: u8, seq: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![kind];
+        message.extend((data.len() as u32).to_le_bytes());
+        message.extend([slot, seq, 0, 0, 0]);
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn ccid_messages() {
+        setup_test_logger();
+        let mut handler = UsbCcidHandler::new().with_card(ReverseCard);
+        verify_descriptor(&handler.get_class_specific_descriptor());
+        let device = UsbDevice::ccid(UsbCcidHandler::new());
+        let interface = &device.interfaces[0];
+        let [bulk_out, bulk_in, interrupt] = UsbCcidHandler::endpoints().try_into().unwrap();
+        let urb = |handler: &mut UsbCcidHandler, ep, req: &[u8]| {
+            handler
+                .handle_urb(interface, ep, 0x200, SetupPacket::default(), req)
+                .unwrap()
+        };
+        let exchange = |handler: &mut UsbCcidHandler, req: &[u8]| {
+            // split into packets
+            for packet in req.chunks(64) {
+                assert!(urb(handler, bulk_out, packet).is_empty());
+            }
+            urb(handler, bulk_in, &[])
+        };
+
+        assert_eq!(urb(&mut handler, interrupt, &[]), [0x50, 0x03]);
+        assert!(urb(&mut handler, interrupt, &[]).is_empty());
+
+        let mut status = message(RDR_TO_PC_SLOT_STATUS, 0, 1, &[]);
+        status[7..].copy_from_slice(&[0x01, 0x00, 0x01]);
+        let get_status = message(PC_TO_RDR_GET_SLOT_STATUS, 0, 1, &[]);
+        assert_eq!(exchange(&mut handler, &get_status), status);
+
+        // not powered
+        let apdu: Vec<u8> = (0..100).collect();
+        let reply = exchange(&mut handler, &message(PC_TO_RDR_XFR_BLOCK, 0, 2, &apdu));
+        assert_eq!(reply[..10], [0x80, 0, 0, 0, 0, 0, 2, 0x41, ICC_MUTE, 0]);
+
+        let reply = exchange(&mut handler, &message(PC_TO_RDR_ICC_POWER_ON, 0, 3, &[]));
+        assert_eq!(reply, message(RDR_TO_PC_DATA_BLOCK, 0, 3, &ATR));
+
+        let reply = exchange(&mut handler, &message(PC_TO_RDR_XFR_BLOCK, 0, 4, &apdu));
+        let mut expected: Vec<u8> = apdu.iter().rev().copied().collect();
+        expected.extend([0x90, 0x00]);
+        assert_eq!(reply, message(RDR_TO_PC_DATA_BLOCK, 0, 4, &expected));
+
+        let reply = exchange(&mut handler, &message(PC_TO_RDR_GET_PARAMETERS, 0, 5, &[]));
+        assert_eq!(reply[..10], [0x82, 7, 0, 0, 0, 0, 5, 0x00, 0, 0x01]);
+        let reply = exchange(&mut handler, &message(PC_TO_RDR_GET_SLOT_STATUS, 1, 6, &[]));
+        assert_eq!(reply[7..9], [0x40, BAD_SLOT]);
+        let reply = exchange(&mut handler, &message(0x71, 0, 7, &[]));
+        assert_eq!(reply[7..9], [0x40, CMD_NOT_SUPPORTED]);
+
+        assert!(handler.remove().is_some());
+        assert_eq!(urb(&mut handler, interrupt, &[]), [0x50, 0x02]);
+        let reply = exchange(&mut handler, &message(PC_TO_RDR_ICC_POWER_ON, 0, 8, &[]));
+        assert_eq!(reply[7..9], [0x42, ICC_MUTE]);
+        assert!(urb(&mut handler, bulk_in, &[]).is_empty());
+    }
+}
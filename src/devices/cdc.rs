@@ -1,10 +1,103 @@
 //! Implement CDC(Communications) device
 use super::super::*;
 
+/// The line coding of a CDC ACM port: baud rate, stop bits, parity and data bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    /// 0 for 1, 1 for 1.5 and 2 for 2 stop bits
+    pub stop_bits: u8,
+    /// 0 for none, 1 for odd, 2 for even, 3 for mark and 4 for space
+    pub parity: u8,
+    /// 5, 6, 7, 8 or 16
+    pub data_bits: u8,
+}
+
+impl Default for LineCoding {
+    /// 115200 8N1
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            stop_bits: 0,
+            parity: 0,
+            data_bits: 8,
+        }
+    }
+}
+
+impl LineCoding {
+    pub fn from_bytes(data: [u8; 7]) -> Self {
+        Self {
+            baud_rate: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            stop_bits: data[4],
+            parity: data[5],
+            data_bits: data[6],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let baud_rate = self.baud_rate.to_le_bytes();
+        [
+            baud_rate[0],
+            baud_rate[1],
+            baud_rate[2],
+            baud_rate[3],
+            self.stop_bits,
+            self.parity,
+            self.data_bits,
+        ]
+    }
+}
+
+/// A request of the host to a CDC ACM port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CdcAcmEvent {
+    /// SET_LINE_CODING
+    LineCoding(LineCoding),
+    /// SET_CONTROL_LINE_STATE
+    ControlLineState { dtr: bool, rts: bool },
+    /// SEND_BREAK, for the given milliseconds, 0xFFFF until the next one of 0
+    Break(u16),
+}
+
+// ACM class requests
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const SEND_BREAK: u8 = 0x23;
+
+/// SERIAL_STATE notification
+const SERIAL_STATE: u8 = 0x20;
+
+// bits of the SERIAL_STATE notification
+/// DCD
+pub const SERIAL_STATE_RX_CARRIER: u16 = 1 << 0;
+/// DSR
+pub const SERIAL_STATE_TX_CARRIER: u16 = 1 << 1;
+pub const SERIAL_STATE_BREAK: u16 = 1 << 2;
+pub const SERIAL_STATE_RING_SIGNAL: u16 = 1 << 3;
+pub const SERIAL_STATE_FRAMING: u16 = 1 << 4;
+pub const SERIAL_STATE_PARITY: u16 = 1 << 5;
+pub const SERIAL_STATE_OVERRUN: u16 = 1 << 6;
+
 /// A handler of a CDC ACM(Abstract Control Model)
+///
+/// Bytes in `tx_buffer` are read by the host. Line coding, control line and
+/// break requests of the host update the handler and are sent to the
+/// receiver of [UsbCdcAcmHandler::events], and [UsbCdcAcmHandler::send_serial_state]
+/// notifies the host of the state of the serial lines.
 #[derive(Clone, Debug)]
 pub struct UsbCdcAcmHandler {
     pub tx_buffer: Vec<u8>,
+    pub line_coding: LineCoding,
+    /// Data Terminal Ready, set by the host
+    pub dtr: bool,
+    /// Request To Send, set by the host
+    pub rts: bool,
+    events: Option<tokio::sync::mpsc::UnboundedSender<CdcAcmEvent>>,
+    /// SERIAL_STATE notifications not read by the host yet
+    serial_states: VecDeque<u16>,
 }
 
 impl Default for UsbCdcAcmHandler {
@@ -18,7 +111,71 @@ pub const CDC_ACM_SUBCLASS: u8 = 0x02;
 
 impl UsbCdcAcmHandler {
     pub fn new() -> Self {
-        Self { tx_buffer: vec![] }
+        Self {
+            tx_buffer: vec![],
+            line_coding: LineCoding::default(),
+            dtr: false,
+            rts: false,
+            events: None,
+            serial_states: VecDeque::new(),
+        }
+    }
+
+    /// The receiver of the requests of the host from now on
+    ///
+    /// A previous receiver gets no more events.
+    pub fn events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<CdcAcmEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// Notify the host of the serial line state, a set of `SERIAL_STATE_*` bits
+    pub fn send_serial_state(&mut self, state: u16) {
+        self.serial_states.push_back(state);
+    }
+
+    fn event(&mut self, event: CdcAcmEvent) {
+        debug!("CDC ACM {event:?}");
+        if let Some(events) = &self.events
+            && events.send(event).is_err()
+        {
+            self.events = None;
+        }
+    }
+
+    fn control(&mut self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b00100001, SET_LINE_CODING) => {
+                let Ok(line_coding) = req.try_into() else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid line coding {req:x?}"),
+                    ));
+                };
+                self.line_coding = LineCoding::from_bytes(line_coding);
+                self.event(CdcAcmEvent::LineCoding(self.line_coding));
+                Ok(vec![])
+            }
+            (0b10100001, GET_LINE_CODING) => Ok(self.line_coding.to_bytes().to_vec()),
+            (0b00100001, SET_CONTROL_LINE_STATE) => {
+                self.dtr = setup.value & 0x01 != 0;
+                self.rts = setup.value & 0x02 != 0;
+                self.event(CdcAcmEvent::ControlLineState {
+                    dtr: self.dtr,
+                    rts: self.rts,
+                });
+                Ok(vec![])
+            }
+            (0b00100001, SEND_BREAK) => {
+                self.event(CdcAcmEvent::Break(setup.value));
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported CDC ACM request: {setup:x?}"),
+            )),
+        }
     }
 
     pub fn endpoints() -> Vec<UsbEndpoint> {
@@ -27,7 +184,7 @@ impl UsbCdcAcmHandler {
             UsbEndpoint {
                 address: 0x81,                                   // IN
                 attributes: EndpointAttributes::Interrupt as u8, // Interrupt
-                max_packet_size: 0x08,                           // 8 bytes
+                max_packet_size: 0x0A,                           // 10 bytes
                 interval: 10,
             },
             // bulk in
@@ -51,17 +208,27 @@ impl UsbCdcAcmHandler {
 impl UsbInterfaceHandler for UsbCdcAcmHandler {
     fn handle_urb(
         &mut self,
-        _interface: &UsbInterface,
+        interface: &UsbInterface,
         ep: UsbEndpoint,
         _transfer_buffer_length: u32,
-        _setup: SetupPacket,
+        setup: SetupPacket,
         req: &[u8],
     ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return self.control(setup, req);
+        }
         if ep.attributes == EndpointAttributes::Interrupt as u8 {
             // interrupt
             if let Direction::In = ep.direction() {
                 // interrupt in
-                return Ok(vec![]);
+                let Some(state) = self.serial_states.pop_front() else {
+                    return Ok(vec![]);
+                };
+                let mut notification = vec![0b10100001, SERIAL_STATE, 0x00, 0x00];
+                notification.extend((interface.interface_number as u16).to_le_bytes());
+                notification.extend(2u16.to_le_bytes());
+                notification.extend(state.to_le_bytes());
+                return Ok(notification);
             }
         } else {
             // bulk
@@ -76,14 +243,23 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
             } else {
                 // bulk in
                 // TODO: handle max packet size
-                let resp = self.tx_buffer.clone();
-                self.tx_buffer.clear();
+                let mut resp = take_scratch_buffer(self.tx_buffer.len());
+                resp.append(&mut self.tx_buffer);
                 return Ok(resp);
             }
         }
         Ok(vec![])
     }
 
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[
+            SET_LINE_CODING,
+            GET_LINE_CODING,
+            SET_CONTROL_LINE_STATE,
+            SEND_BREAK,
+        ])
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         vec![
             // Header
@@ -95,15 +271,1016 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
             0x04, // bFunctionLength
             0x24, // CS_INTERFACE
             0x02, // ACM
-            0x00, // Capabilities
+            0x06, // Capabilities: line coding, control line state and break
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Sub class code for CDC ECM(Ethernet Control Model)
+pub const CDC_ECM_SUBCLASS: u8 = 0x06;
+
+// ECM class requests
+const SET_ETHERNET_MULTICAST_FILTERS: u8 = 0x40;
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+// ECM notifications
+const NETWORK_CONNECTION: u8 = 0x00;
+const CONNECTION_SPEED_CHANGE: u8 = 0x2A;
+
+// packet filter bits
+pub(crate) const PACKET_TYPE_PROMISCUOUS: u16 = 1 << 0;
+pub(crate) const PACKET_TYPE_ALL_MULTICAST: u16 = 1 << 1;
+pub(crate) const PACKET_TYPE_DIRECTED: u16 = 1 << 2;
+pub(crate) const PACKET_TYPE_BROADCAST: u16 = 1 << 3;
+pub(crate) const PACKET_TYPE_MULTICAST: u16 = 1 << 4;
+
+/// Sub class code for CDC NCM(Network Control Model)
+pub const CDC_NCM_SUBCLASS: u8 = 0x0D;
+/// Protocol code of the data interface of NCM
+const NCM_DATA_PROTOCOL: u8 = 0x01;
+
+// NCM class requests
+const GET_NTB_PARAMETERS: u8 = 0x80;
+const GET_NTB_FORMAT: u8 = 0x83;
+const SET_NTB_FORMAT: u8 = 0x84;
+const GET_NTB_INPUT_SIZE: u8 = 0x85;
+const SET_NTB_INPUT_SIZE: u8 = 0x86;
+
+/// NTH16 and NDP16 signatures
+const NTH16_SIGNATURE: &[u8; 4] = b"NCMH";
+const NDP16_SIGNATURE: &[u8; 4] = b"NCM0";
+const NTH16_LEN: usize = 12;
+/// The maximum length of NTBs in both directions
+const NTB_MAX_SIZE: u32 = 0x4000;
+/// The minimum of SET_NTB_INPUT_SIZE, see NCM 1.0 6.2.7
+const NTB_MIN_INPUT_SIZE: u32 = 2048;
+/// Alignment of datagrams and NDPs
+const NTB_ALIGNMENT: usize = 4;
+
+/// Sub class code for CDC EEM(Ethernet Emulation Model)
+pub const CDC_EEM_SUBCLASS: u8 = 0x0C;
+/// Protocol code of EEM
+const EEM_PROTOCOL: u8 = 0x07;
+
+/// The CRC of EEM packets whose sender did not compute one
+const EEM_CRC_SENTINEL: u32 = 0xDEADBEEF;
+
+/// The network behind a CDC ECM, NCM or EEM function, e.g. a TAP device or a channel
+///
+/// Frames are ethernet frames without the frame check sequence.
+pub trait EthernetBackend: std::fmt::Debug {
+    /// Send a frame transmitted by the host
+    fn transmit(&mut self, frame: &[u8]);
+
+    /// The next frame to deliver to the host, if any
+    fn receive(&mut self) -> Option<Vec<u8>>;
+
+    /// The speed of the link in bits per second, or `None` if it is down
+    fn link_speed(&mut self) -> Option<u32> {
+        Some(100_000_000)
+    }
+}
+
+/// An [EthernetBackend] exchanging frames over channels
+///
+/// The link goes down once all senders of frames to the host are dropped.
+#[derive(Debug)]
+pub struct ChannelEthernetBackend {
+    transmitted: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    received: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    connected: bool,
+}
+
+impl ChannelEthernetBackend {
+    /// A backend, the sender of frames to the host and the receiver of its frames
+    pub fn new() -> (
+        Self,
+        tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+        tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    ) {
+        let (transmitted, from_host) = tokio::sync::mpsc::unbounded_channel();
+        let (to_host, received) = tokio::sync::mpsc::unbounded_channel();
+        let backend = Self {
+            transmitted,
+            received,
+            connected: true,
+        };
+        (backend, to_host, from_host)
+    }
+}
+
+impl EthernetBackend for ChannelEthernetBackend {
+    fn transmit(&mut self, frame: &[u8]) {
+        // the receiver may be gone, like a cable without a peer
+        let _ = self.transmitted.send(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        match self.received.try_recv() {
+            Ok(frame) => Some(frame),
+            Err(tokio::sync::mpsc::error::TryRecvError::Empty) => None,
+            Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+
+    fn link_speed(&mut self) -> Option<u32> {
+        if self.connected && self.received.is_closed() && self.received.is_empty() {
+            self.connected = false;
+        }
+        self.connected.then_some(100_000_000)
+    }
+}
+
+/// State shared by the control and data interfaces of an ECM, NCM or RNDIS function
+#[derive(Debug)]
+pub(crate) struct NetworkState {
+    pub(crate) backend: Box<dyn EthernetBackend + Send>,
+    /// The CDC packet filter bits
+    pub(crate) packet_filter: u16,
+    /// The maximum length of NTBs to the host, for NCM
+    ntb_input_size: u32,
+}
+
+impl NetworkState {
+    pub(crate) fn new(backend: impl EthernetBackend + Send + 'static) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            backend: Box::new(backend),
+            // what hosts set when they bring up the interface
+            packet_filter: PACKET_TYPE_DIRECTED | PACKET_TYPE_BROADCAST | PACKET_TYPE_ALL_MULTICAST,
+            ntb_input_size: NTB_MAX_SIZE,
+        }))
+    }
+
+    /// Whether the packet filter lets `frame` for the host `mac_address` through
+    pub(crate) fn accepts(&self, mac_address: &[u8; 6], frame: &[u8]) -> bool {
+        let packet_filter = self.packet_filter;
+        let Some(destination) = frame.get(..6) else {
+            return false;
+        };
+        if packet_filter & PACKET_TYPE_PROMISCUOUS != 0 {
+            true
+        } else if destination == [0xFF; 6] {
+            packet_filter & PACKET_TYPE_BROADCAST != 0
+        } else if destination[0] & 0x01 != 0 {
+            // without a list of multicast filters, any group passes
+            packet_filter & (PACKET_TYPE_ALL_MULTICAST | PACKET_TYPE_MULTICAST) != 0
+        } else {
+            destination == mac_address && packet_filter & PACKET_TYPE_DIRECTED != 0
+        }
+    }
+}
+
+/// A handler of the data interface of a CDC ECM function
+///
+/// The frames of the bulk endpoints are exchanged with an [EthernetBackend].
+/// Use [UsbDevice::cdc_ecm] to add the control interface, which reports
+/// the link state of the backend to the host.
+#[derive(Debug)]
+pub struct UsbCdcEcmHandler {
+    /// The MAC address of the host side of the link
+    pub mac_address: [u8; 6],
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl UsbCdcEcmHandler {
+    pub fn new(mac_address: [u8; 6], backend: impl EthernetBackend + Send + 'static) -> Self {
+        Self {
+            mac_address,
+            state: NetworkState::new(backend),
+        }
+    }
+
+    /// The notification endpoint of the control interface, and the bulk
+    /// endpoints of the data interface
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // notification
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 0x10,
+                interval: 10,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x82,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
         ]
     }
+}
+
+impl UsbInterfaceHandler for UsbCdcEcmHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if ep.is_ep0() {
+            return Ok(vec![]);
+        }
+        if let Direction::Out = ep.direction() {
+            // hosts may pad frames of a multiple of the packet size by a byte
+            state.backend.transmit(req);
+            return Ok(vec![]);
+        }
+        while let Some(frame) = state.backend.receive() {
+            if frame.len() > transfer_buffer_length as usize {
+                warn!(
+                    "Dropping frame of {} bytes, longer than the transfer of {transfer_buffer_length}",
+                    frame.len()
+                );
+            } else if state.accepts(&self.mac_address, &frame) {
+                return Ok(frame);
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
 
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
 
+/// The response to GET_NTB_PARAMETERS
+fn ntb_parameters() -> Vec<u8> {
+    let mut res = vec![];
+    res.extend(0x1Cu16.to_le_bytes()); // wLength
+    res.extend(0x0001u16.to_le_bytes()); // bmNtbFormatsSupported: NTB16
+    res.extend(NTB_MAX_SIZE.to_le_bytes()); // dwNtbInMaxSize
+    res.extend((NTB_ALIGNMENT as u16).to_le_bytes()); // wNdpInDivisor
+    res.extend(0u16.to_le_bytes()); // wNdpInPayloadRemainder
+    res.extend((NTB_ALIGNMENT as u16).to_le_bytes()); // wNdpInAlignment
+    res.extend(0u16.to_le_bytes()); // wReserved
+    res.extend(NTB_MAX_SIZE.to_le_bytes()); // dwNtbOutMaxSize
+    res.extend((NTB_ALIGNMENT as u16).to_le_bytes()); // wNdpOutDivisor
+    res.extend(0u16.to_le_bytes()); // wNdpOutPayloadRemainder
+    res.extend((NTB_ALIGNMENT as u16).to_le_bytes()); // wNdpOutAlignment
+    res.extend(0u16.to_le_bytes()); // wNtbOutMaxDatagrams: no limit
+    res
+}
+
+fn align(len: usize) -> usize {
+    len.div_ceil(NTB_ALIGNMENT) * NTB_ALIGNMENT
+}
+
+fn invalid_ntb(msg: String) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid NTB: {msg}"),
+    )
+}
+
+/// The datagrams of the NTB16 `ntb`, from all of its NDPs
+pub fn parse_ntb16(ntb: &[u8]) -> Result<Vec<&[u8]>> {
+    let u16_at = |offset: usize| {
+        ntb.get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .ok_or_else(|| invalid_ntb(format!("truncated at {offset}")))
+    };
+    if ntb.len() < NTH16_LEN || &ntb[..4] != NTH16_SIGNATURE {
+        return Err(invalid_ntb("missing NTH16".to_string()));
+    }
+    if u16_at(4)? != NTH16_LEN {
+        return Err(invalid_ntb(format!("header length {}", u16_at(4)?)));
+    }
+    let block_len = u16_at(8)?;
+    if block_len > ntb.len() {
+        return Err(invalid_ntb(format!("block length {block_len}")));
+    }
+    // the datagrams and NDPs are within the block
+    let ntb = &ntb[..block_len];
+    let mut datagrams = vec![];
+    let mut ndp = u16_at(10)?;
+    // NDPs are 12 bytes at least, so the chain cannot be longer
+    for _ in 0..block_len / 12 + 1 {
+        if ndp == 0 {
+            return Ok(datagrams);
+        }
+        if ndp < NTH16_LEN || ndp % NTB_ALIGNMENT != 0 || ndp + 8 > block_len {
+            return Err(invalid_ntb(format!("NDP at {ndp}")));
+        }
+        if &ntb[ndp..ndp + 4] != NDP16_SIGNATURE {
+            return Err(invalid_ntb(format!("NDP signature at {ndp}")));
+        }
+        let ndp_len = u16_at(ndp + 4)?;
+        if ndp_len < 16 || ndp_len % 4 != 0 || ndp + ndp_len > block_len {
+            return Err(invalid_ntb(format!("NDP length {ndp_len} at {ndp}")));
+        }
+        for entry in (ndp + 8..ndp + ndp_len).step_by(4) {
+            let (index, len) = (u16_at(entry)?, u16_at(entry + 2)?);
+            if index == 0 || len == 0 {
+                break;
+            }
+            let datagram = ntb
+                .get(index..index + len)
+                .ok_or_else(|| invalid_ntb(format!("datagram at {index} of {len} bytes")))?;
+            datagrams.push(datagram);
+        }
+        ndp = u16_at(ndp + 6)?;
+    }
+    Err(invalid_ntb("loop of NDPs".to_string()))
+}
+
+/// An NTB16 with `datagrams`, followed by a single NDP
+pub fn build_ntb16(sequence: u16, datagrams: &[Vec<u8>]) -> Vec<u8> {
+    let mut ntb = vec![0; NTH16_LEN];
+    let mut entries = vec![];
+    for datagram in datagrams {
+        ntb.resize(align(ntb.len()), 0);
+        entries.push((ntb.len() as u16, datagram.len() as u16));
+        ntb.extend_from_slice(datagram);
+    }
+    ntb.resize(align(ntb.len()), 0);
+    let ndp = ntb.len();
+    // the datagram entries and the terminating null entry
+    let ndp_len = 8 + 4 * (entries.len() + 1);
+    ntb.extend(NDP16_SIGNATURE);
+    ntb.extend((ndp_len as u16).to_le_bytes());
+    ntb.extend(0u16.to_le_bytes()); // wNextNdpIndex
+    for (index, len) in entries {
+        ntb.extend(index.to_le_bytes());
+        ntb.extend(len.to_le_bytes());
+    }
+    ntb.extend([0; 4]);
+
+    let block_len = ntb.len() as u16;
+    ntb[..4].copy_from_slice(NTH16_SIGNATURE);
+    ntb[4..6].copy_from_slice(&(NTH16_LEN as u16).to_le_bytes());
+    ntb[6..8].copy_from_slice(&sequence.to_le_bytes());
+    ntb[8..10].copy_from_slice(&block_len.to_le_bytes());
+    ntb[10..12].copy_from_slice(&(ndp as u16).to_le_bytes());
+    ntb
+}
+
+/// The length of an NTB16 with datagrams of `datagram_lens` bytes
+fn ntb16_len(datagram_lens: impl Iterator<Item = usize>) -> usize {
+    let mut len = NTH16_LEN;
+    let mut count = 0;
+    for datagram_len in datagram_lens {
+        len = align(len) + datagram_len;
+        count += 1;
+    }
+    align(len) + 8 + 4 * (count + 1)
+}
+
+/// A handler of the data interface of a CDC NCM function
+///
+/// Like [UsbCdcEcmHandler], but the bulk transfers carry NTBs(Network
+/// Transfer Blocks): the datagrams the host sends in one NTB are passed to
+/// the [EthernetBackend] one by one, and the frames of the backend are
+/// aggregated into NTBs of up to the input size set by the host.
+#[derive(Debug)]
+pub struct UsbCdcNcmHandler {
+    /// The MAC address of the host side of the link
+    pub mac_address: [u8; 6],
+    state: Arc<Mutex<NetworkState>>,
+    /// wSequence of the next NTB to the host
+    sequence: u16,
+    /// A frame that did not fit into the last NTB
+    pending: Option<Vec<u8>>,
+}
+
+impl UsbCdcNcmHandler {
+    pub fn new(mac_address: [u8; 6], backend: impl EthernetBackend + Send + 'static) -> Self {
+        Self {
+            mac_address,
+            state: NetworkState::new(backend),
+            sequence: 0,
+            pending: None,
+        }
+    }
+
+    /// An NTB of the frames of the backend that fit into `max_len` bytes
+    fn aggregate(&mut self, max_len: usize) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        let mut datagrams: Vec<Vec<u8>> = vec![];
+        loop {
+            let frame = match self.pending.take() {
+                Some(frame) => frame,
+                None => match state.backend.receive() {
+                    Some(frame) if state.accepts(&self.mac_address, &frame) => frame,
+                    Some(_) => continue,
+                    None => break,
+                },
+            };
+            let lens = datagrams.iter().map(Vec::len).chain([frame.len()]);
+            if ntb16_len(lens) <= max_len {
+                datagrams.push(frame);
+            } else if datagrams.is_empty() {
+                warn!(
+                    "Dropping frame of {} bytes, longer than NTBs of {max_len}",
+                    frame.len()
+                );
+            } else {
+                self.pending = Some(frame);
+                break;
+            }
+        }
+        if datagrams.is_empty() {
+            return vec![];
+        }
+        let ntb = build_ntb16(self.sequence, &datagrams);
+        self.sequence = self.sequence.wrapping_add(1);
+        ntb
+    }
+}
+
+impl UsbInterfaceHandler for UsbCdcNcmHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return Ok(vec![]);
+        }
+        if let Direction::Out = ep.direction() {
+            if req.is_empty() {
+                return Ok(vec![]);
+            }
+            let datagrams = match parse_ntb16(req) {
+                Ok(datagrams) => datagrams,
+                Err(err) => {
+                    // the whole NTB is discarded
+                    warn!("{err}");
+                    return Ok(vec![]);
+                }
+            };
+            let mut state = self.state.lock().unwrap();
+            for datagram in datagrams {
+                state.backend.transmit(datagram);
+            }
+            return Ok(vec![]);
+        }
+        let ntb_input_size = self.state.lock().unwrap().ntb_input_size;
+        Ok(self.aggregate(transfer_buffer_length.min(ntb_input_size) as usize))
+    }
+
+    fn set_alternate_setting(&mut self, _interface: &UsbInterface, alternate_setting: u8) {
+        if alternate_setting == 0 {
+            // the data interface is reset, see NCM 1.0 7.2
+            self.state.lock().unwrap().ntb_input_size = NTB_MAX_SIZE;
+            self.sequence = 0;
+            self.pending = None;
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The control interface of an ECM or NCM function
+#[derive(Debug)]
+struct NetworkControlHandler {
+    state: Arc<Mutex<NetworkState>>,
+    /// Whether to handle the NTB requests of NCM
+    ncm: bool,
+    /// The string descriptor of the MAC address
+    mac_string: u8,
+    control_interface: u8,
+    data_interface: u8,
+    /// The link speed last reported to the host
+    reported: Option<Option<u32>>,
+    notifications: VecDeque<Vec<u8>>,
+}
+
+impl NetworkControlHandler {
+    fn notification(&self, notification: u8, value: u16, data: &[u8]) -> Vec<u8> {
+        let mut res = vec![0b10100001, notification];
+        res.extend(value.to_le_bytes());
+        res.extend((self.control_interface as u16).to_le_bytes());
+        res.extend((data.len() as u16).to_le_bytes());
+        res.extend_from_slice(data);
+        res
+    }
+}
+
+impl UsbInterfaceHandler for NetworkControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if !ep.is_ep0() {
+            // interrupt in
+            let speed = state.backend.link_speed();
+            if self.notifications.is_empty() && self.reported != Some(speed) {
+                debug!("Network link speed {:?}", speed);
+                self.reported = Some(speed);
+                let connection = self.notification(NETWORK_CONNECTION, speed.is_some() as u16, &[]);
+                self.notifications.push_back(connection);
+                if let Some(speed) = speed {
+                    let mut rates = speed.to_le_bytes().to_vec();
+                    rates.extend(speed.to_le_bytes());
+                    let change = self.notification(CONNECTION_SPEED_CHANGE, 0, &rates);
+                    self.notifications.push_back(change);
+                }
+            }
+            return Ok(self.notifications.pop_front().unwrap_or_default());
+        }
+        match (setup.request_type, setup.request) {
+            (0b00100001, SET_ETHERNET_PACKET_FILTER) => {
+                debug!("Network packet filter {:#06x}", setup.value);
+                state.packet_filter = setup.value;
+                Ok(vec![])
+            }
+            // frames of all groups are accepted
+            (0b00100001, SET_ETHERNET_MULTICAST_FILTERS) => Ok(vec![]),
+            (0b10100001, GET_NTB_PARAMETERS) if self.ncm => Ok(ntb_parameters()),
+            (0b10100001, GET_NTB_FORMAT) if self.ncm => Ok(vec![0x00, 0x00]),
+            // only NTB16 is supported
+            (0b00100001, SET_NTB_FORMAT) if self.ncm && setup.value == 0 => Ok(vec![]),
+            (0b10100001, GET_NTB_INPUT_SIZE) if self.ncm => {
+                Ok(state.ntb_input_size.to_le_bytes().to_vec())
+            }
+            (0b00100001, SET_NTB_INPUT_SIZE) if self.ncm && req.len() >= 4 => {
+                let size = u32::from_le_bytes(req[..4].try_into().unwrap());
+                if !(NTB_MIN_INPUT_SIZE..=NTB_MAX_SIZE).contains(&size) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unsupported NTB input size {size}"),
+                    ));
+                }
+                state.ntb_input_size = size;
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported network control request: {setup:x?}"),
+            )),
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        if self.ncm {
+            Some(&[
+                SET_ETHERNET_MULTICAST_FILTERS,
+                SET_ETHERNET_PACKET_FILTER,
+                GET_NTB_PARAMETERS,
+                GET_NTB_FORMAT,
+                SET_NTB_FORMAT,
+                GET_NTB_INPUT_SIZE,
+                SET_NTB_INPUT_SIZE,
+            ])
+        } else {
+            Some(&[SET_ETHERNET_MULTICAST_FILTERS, SET_ETHERNET_PACKET_FILTER])
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            // Header
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x00, // Header
+            0x10,
+            0x01, // CDC 1.2
+            // Union
+            0x05,                   // bFunctionLength
+            0x24,                   // CS_INTERFACE
+            0x06,                   // Union
+            self.control_interface, // bControlInterface
+            self.data_interface,    // bSubordinateInterface0
+            // Ethernet Networking
+            0x0D,            // bFunctionLength
+            0x24,            // CS_INTERFACE
+            0x0F,            // Ethernet Networking
+            self.mac_string, // iMACAddress
+            0x00,
+            0x00,
+            0x00,
+            0x00, // bmEthernetStatistics
+            0xEA,
+            0x05, // wMaxSegmentSize: 1514
+            0x00,
+            0x00, // wNumberMCFilters
+            0x00, // bNumberPowerFilters
+        ];
+        if self.ncm {
+            desc.extend([
+                0x06, // bFunctionLength
+                0x24, // CS_INTERFACE
+                0x1A, // NCM
+                0x00, 0x01, // NCM 1.0
+                0x00, // bmNetworkCapabilities
+            ]);
+        }
+        desc
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// CRC-32 of ethernet frames
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// A packet of the EEM stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EemPacket {
+    /// An ethernet frame, without the CRC
+    Frame(Vec<u8>),
+    Echo(Vec<u8>),
+    EchoResponse(Vec<u8>),
+    /// Any other command, with its bmEEMCmd and parameter
+    Command(u8, u16),
+}
+
+impl EemPacket {
+    /// The packets of the EEM stream `data`, checking the CRC of frames
+    pub fn parse(mut data: &[u8]) -> Result<Vec<Self>> {
+        let mut packets = vec![];
+        while data.len() >= 2 {
+            let header = u16::from_le_bytes([data[0], data[1]]);
+            data = &data[2..];
+            if header == 0 {
+                // in place of a zero length packet
+                continue;
+            }
+            let len = if header & 0x8000 == 0 {
+                header & 0x3FFF
+            } else if header >> 11 & 0x7 <= 1 {
+                // echo and echo response
+                header & 0x07FF
+            } else {
+                0
+            } as usize;
+            let Some(payload) = data.get(..len) else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Truncated EEM packet of {len} bytes"),
+                ));
+            };
+            data = &data[len..];
+            packets.push(match header >> 11 {
+                cmd if cmd & 0x10 == 0 => {
+                    let Some(frame_len) = len.checked_sub(4) else {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("EEM frame of {len} bytes without a CRC"),
+                        ));
+                    };
+                    let (frame, crc) = payload.split_at(frame_len);
+                    let crc = u32::from_le_bytes(crc.try_into().unwrap());
+                    // bmCRC
+                    let expected = if header & 0x4000 != 0 {
+                        crc32(frame)
+                    } else {
+                        EEM_CRC_SENTINEL
+                    };
+                    if crc != expected {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("EEM frame with CRC {crc:#010x} instead of {expected:#010x}"),
+                        ));
+                    }
+                    Self::Frame(frame.to_vec())
+                }
+                0x10 => Self::Echo(payload.to_vec()),
+                0x11 => Self::EchoResponse(payload.to_vec()),
+                cmd => Self::Command(cmd as u8 & 0x7, header & 0x07FF),
+            });
+        }
+        Ok(packets)
+    }
+
+    /// The packet in the EEM stream, with the CRC of frames
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (header, payload) = match self {
+            Self::Frame(frame) => {
+                let mut payload = frame.clone();
+                payload.extend(crc32(frame).to_le_bytes());
+                (0x4000 | payload.len() as u16, payload)
+            }
+            Self::Echo(data) => (0x8000 | data.len() as u16, data.clone()),
+            Self::EchoResponse(data) => (0x8800 | data.len() as u16, data.clone()),
+            Self::Command(cmd, param) => (0x8000 | (*cmd as u16) << 11 | param, vec![]),
+        };
+        let mut res = header.to_le_bytes().to_vec();
+        res.extend(payload);
+        res
+    }
+}
+
+/// A handler of a CDC EEM function
+///
+/// The bulk transfers carry a stream of EEM packets: ethernet frames
+/// prefixed by their length, which are exchanged with an [EthernetBackend],
+/// and commands. Frames to the host are aggregated up to the length of the
+/// transfer. EEM has no control requests, MAC address or link state.
+#[derive(Debug)]
+pub struct UsbCdcEemHandler {
+    backend: Box<dyn EthernetBackend + Send>,
+    /// Packets to the host, echo responses and frames that did not fit
+    pending: VecDeque<EemPacket>,
+}
+
+impl UsbCdcEemHandler {
+    pub fn new(backend: impl EthernetBackend + Send + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+        ]
+    }
+}
+
+impl UsbInterfaceHandler for UsbCdcEemHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return Ok(vec![]);
+        }
+        if let Direction::Out = ep.direction() {
+            // a malformed stream is dropped as a whole
+            for packet in EemPacket::parse(req).unwrap_or_else(|err| {
+                warn!("{err}");
+                vec![]
+            }) {
+                match packet {
+                    EemPacket::Frame(frame) => self.backend.transmit(&frame),
+                    EemPacket::Echo(data) => self.pending.push_back(EemPacket::EchoResponse(data)),
+                    packet => debug!("Ignoring EEM packet {packet:?}"),
+                }
+            }
+            return Ok(vec![]);
+        }
+        let max_len = transfer_buffer_length as usize;
+        let mut res = vec![];
+        while let Some(packet) = self
+            .pending
+            .pop_front()
+            .or_else(|| self.backend.receive().map(EemPacket::Frame))
+        {
+            let bytes = packet.to_bytes();
+            if res.len() + bytes.len() <= max_len {
+                res.extend(bytes);
+            } else if res.is_empty() {
+                warn!(
+                    "Dropping EEM packet of {} bytes, longer than the transfer of {max_len}",
+                    bytes.len()
+                );
+            } else {
+                self.pending.push_front(packet);
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A network adapter with the ECM control and data interfaces of `handler`
+    pub fn cdc_ecm(handler: UsbCdcEcmHandler) -> Self {
+        let (mac_address, state) = (handler.mac_address, handler.state.clone());
+        let mut device = Self::cdc_network(
+            mac_address,
+            state,
+            CDC_ECM_SUBCLASS,
+            0x00,
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0009;
+        device.set_product_name("Virtual Ethernet");
+        device
+    }
+
+    /// A network adapter with the NCM control and data interfaces of `handler`
+    pub fn cdc_ncm(handler: UsbCdcNcmHandler) -> Self {
+        let (mac_address, state) = (handler.mac_address, handler.state.clone());
+        let mut device = Self::cdc_network(
+            mac_address,
+            state,
+            CDC_NCM_SUBCLASS,
+            NCM_DATA_PROTOCOL,
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x000A;
+        device.set_product_name("Virtual NCM Ethernet");
+        device
+    }
+
+    /// A network adapter with the single EEM interface of `handler`
+    pub fn cdc_eem(handler: UsbCdcEemHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::CDC as u8,
+            CDC_EEM_SUBCLASS,
+            EEM_PROTOCOL,
+            Some("Ethernet"),
+            UsbCdcEemHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x000C;
+        device.set_product_name("Virtual EEM Ethernet");
+        device
+    }
+
+    fn cdc_network(
+        mac_address: [u8; 6],
+        state: Arc<Mutex<NetworkState>>,
+        subclass: u8,
+        data_protocol: u8,
+        data_handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        let mut device = Self::new(0).with_network_interfaces(
+            mac_address,
+            state,
+            subclass,
+            data_protocol,
+            data_handler,
+            UsbCdcEcmHandler::endpoints(),
+        );
+        device.device_class = ClassCode::CDC as u8;
+        device
+    }
+
+    /// Add the control and data interfaces of an ECM or NCM function after the existing interfaces
+    ///
+    /// `endpoints` are the notification endpoint and the bulk endpoints, like
+    /// [UsbCdcEcmHandler::endpoints].
+    fn with_network_interfaces(
+        mut self,
+        mac_address: [u8; 6],
+        state: Arc<Mutex<NetworkState>>,
+        subclass: u8,
+        data_protocol: u8,
+        data_handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+        endpoints: Vec<UsbEndpoint>,
+    ) -> Self {
+        let mac: String = mac_address.iter().map(|b| format!("{b:02X}")).collect();
+        let control_interface = self.interfaces.len() as u8;
+        let control = NetworkControlHandler {
+            state,
+            ncm: subclass == CDC_NCM_SUBCLASS,
+            mac_string: self.new_string(&mac),
+            control_interface,
+            data_interface: control_interface + 1,
+            reported: None,
+            notifications: VecDeque::new(),
+        };
+        self.with_interface(
+            ClassCode::CDC as u8,
+            subclass,
+            0x00,
+            None,
+            endpoints[..1].to_vec(),
+            shared_interface_handler(control),
+        )
+        // the bulk endpoints are in the second setting, which hosts select to start
+        .with_interface(
+            ClassCode::CDCData as u8,
+            0x00,
+            data_protocol,
+            Some("Ethernet"),
+            vec![],
+            data_handler,
+        )
+        .with_alternate_setting(endpoints[1..].to_vec(), vec![])
+    }
+}
+
+impl UsbGadgetFunction {
+    /// An ECM network adapter function with the interfaces of `handler`
+    pub fn ecm(handler: UsbCdcEcmHandler) -> Self {
+        Self::network(
+            "ecm",
+            CDC_ECM_SUBCLASS,
+            0x00,
+            handler.mac_address,
+            handler.state.clone(),
+            shared_interface_handler(handler),
+        )
+    }
+
+    /// An NCM network adapter function with the interfaces of `handler`
+    pub fn ncm(handler: UsbCdcNcmHandler) -> Self {
+        Self::network(
+            "ncm",
+            CDC_NCM_SUBCLASS,
+            NCM_DATA_PROTOCOL,
+            handler.mac_address,
+            handler.state.clone(),
+            shared_interface_handler(handler),
+        )
+    }
+
+    fn network(
+        name: &str,
+        subclass: u8,
+        data_protocol: u8,
+        mac_address: [u8; 6],
+        state: Arc<Mutex<NetworkState>>,
+        data_handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        Self::new(name, move |device| {
+            let first_interface = device.interfaces.len() as u8;
+            let endpoints = device.allocate_endpoints(UsbCdcEcmHandler::endpoints());
+            device
+                .with_network_interfaces(
+                    mac_address,
+                    state,
+                    subclass,
+                    data_protocol,
+                    data_handler,
+                    endpoints,
+                )
+                .with_interface_association(
+                    first_interface,
+                    2,
+                    ClassCode::CDC as u8,
+                    subclass,
+                    0x00,
+                    None,
+                )
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::util::tests::*;
@@ -116,4 +1293,324 @@ mod tests {
         let handler = UsbCdcAcmHandler::new();
         verify_descriptor(&handler.get_class_specific_descriptor());
     }
+
+    #[tokio::test]
+    async fn acm_control() {
+        setup_test_logger();
+        let mut acm = UsbCdcAcmHandler::new();
+        let mut events = acm.events();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            CDC_ACM_SUBCLASS,
+            0x00,
+            None,
+            UsbCdcAcmHandler::endpoints(),
+            shared_interface_handler(acm),
+        );
+        let request = |request_type, request, value, length| SetupPacket {
+            request_type,
+            request,
+            value,
+            index: 0,
+            length,
+        };
+
+        let coding = LineCoding {
+            baud_rate: 9600,
+            stop_bits: 2,
+            parity: 2,
+            data_bits: 7,
+        };
+        let set_coding = request(0b00100001, SET_LINE_CODING, 0, 7);
+        device
+            .handle_urb(device.ep0_out, None, 0, set_coding, &coding.to_bytes())
+            .await
+            .unwrap();
+        assert_eq!(events.recv().await, Some(CdcAcmEvent::LineCoding(coding)));
+        let get_coding = request(0b10100001, GET_LINE_CODING, 0, 7);
+        let res = device.handle_urb(device.ep0_in, None, 7, get_coding, &[]);
+        assert_eq!(
+            LineCoding::from_bytes(res.await.unwrap().try_into().unwrap()),
+            coding
+        );
+        let invalid = device.handle_urb(device.ep0_out, None, 0, set_coding, &[0; 3]);
+        assert!(invalid.await.is_err());
+
+        let set_lines = request(0b00100001, SET_CONTROL_LINE_STATE, 0x01, 0);
+        device
+            .handle_urb(device.ep0_out, None, 0, set_lines, &[])
+            .await
+            .unwrap();
+        let lines = CdcAcmEvent::ControlLineState {
+            dtr: true,
+            rts: false,
+        };
+        assert_eq!(events.recv().await, Some(lines));
+        let send_break = request(0b00100001, SEND_BREAK, 250, 0);
+        device
+            .handle_urb(device.ep0_out, None, 0, send_break, &[])
+            .await
+            .unwrap();
+        assert_eq!(events.recv().await, Some(CdcAcmEvent::Break(250)));
+
+        let intf = &device.interfaces[0];
+        let mut handler = intf.handler.lock().await;
+        let acm = handler.as_any().downcast_mut::<UsbCdcAcmHandler>().unwrap();
+        assert!(acm.dtr && !acm.rts);
+        acm.send_serial_state(SERIAL_STATE_RX_CARRIER | SERIAL_STATE_TX_CARRIER);
+        let notify = UsbCdcAcmHandler::endpoints()[0];
+        let mut read = || {
+            handler
+                .handle_urb(intf, notify, 10, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert_eq!(read(), [0xA1, SERIAL_STATE, 0, 0, 0, 0, 2, 0, 0x03, 0]);
+        assert!(read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ecm_frames() {
+        setup_test_logger();
+        let mac = [0x02, 0, 0, 0, 0, 0x01];
+        let (backend, to_host, mut from_host) = ChannelEthernetBackend::new();
+        let device = UsbDevice::cdc_ecm(UsbCdcEcmHandler::new(mac, backend));
+        let desc = device.configuration_descriptor(0xFFFF);
+        verify_descriptor(&desc);
+        let control = &device.interfaces[0];
+        let mac_string = control.class_specific_descriptor[13];
+        assert_eq!(device.string_pool[&mac_string], "020000000001");
+        assert_eq!(device.interfaces[1].alternate_settings.len(), 1);
+
+        let [notify, bulk_in, bulk_out] = UsbCdcEcmHandler::endpoints().try_into().unwrap();
+        let notification = || async {
+            let mut handler = control.handler.lock().await;
+            handler
+                .handle_urb(control, notify, 16, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert_eq!(notification().await, [0xA1, 0x00, 1, 0, 0, 0, 0, 0]);
+        let speed = notification().await;
+        assert_eq!(speed[..8], [0xA1, 0x2A, 0, 0, 0, 0, 8, 0]);
+        assert_eq!(speed[8..12], 100_000_000u32.to_le_bytes());
+        assert!(notification().await.is_empty());
+
+        let data = &device.interfaces[1];
+        let mut handler = data.handler.lock().await;
+        let frame = |destination: [u8; 6]| {
+            let mut frame = destination.to_vec();
+            frame.extend([0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00]);
+            frame.extend([0x45; 20]);
+            frame
+        };
+        handler
+            .handle_urb(
+                data,
+                bulk_out,
+                34,
+                SetupPacket::default(),
+                &frame([0xFF; 6]),
+            )
+            .unwrap();
+        assert_eq!(from_host.recv().await.unwrap(), frame([0xFF; 6]));
+
+        // frames to other hosts are filtered until the host turns on promiscuous mode
+        let other = [0x02, 0, 0, 0, 0, 0x03];
+        to_host.send(frame(other)).unwrap();
+        to_host.send(frame(mac)).unwrap();
+        let mut read = || {
+            handler
+                .handle_urb(data, bulk_in, 1514, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert_eq!(read(), frame(mac));
+        assert!(read().is_empty());
+        drop(handler);
+        let set_filter = SetupPacket {
+            request_type: 0b00100001,
+            request: SET_ETHERNET_PACKET_FILTER,
+            value: PACKET_TYPE_PROMISCUOUS,
+            index: 0,
+            length: 0,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, set_filter, &[])
+            .await
+            .unwrap();
+        to_host.send(frame(other)).unwrap();
+        let mut handler = data.handler.lock().await;
+        let frame_in = handler.handle_urb(data, bulk_in, 1514, SetupPacket::default(), &[]);
+        assert_eq!(frame_in.unwrap(), frame(other));
+        drop(handler);
+
+        // the link goes down with the channel
+        drop(to_host);
+        assert_eq!(notification().await, [0xA1, 0x00, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ntb16_framing() {
+        setup_test_logger();
+        let datagrams = vec![vec![1; 14], vec![2; 61], vec![3; 60]];
+        let ntb = build_ntb16(7, &datagrams);
+        assert_eq!(ntb.len(), ntb16_len(datagrams.iter().map(Vec::len)));
+        assert_eq!(ntb[..4], *NTH16_SIGNATURE);
+        assert_eq!(u16::from_le_bytes([ntb[6], ntb[7]]), 7);
+        assert_eq!(parse_ntb16(&ntb).unwrap(), datagrams);
+
+        let mut truncated = ntb.clone();
+        truncated.truncate(ntb.len() - 4);
+        assert!(parse_ntb16(&truncated).is_err());
+        let ndp = u16::from_le_bytes([ntb[10], ntb[11]]) as usize;
+        let mut outside = ntb.clone();
+        // the length of the first datagram
+        outside[ndp + 8 + 3] = 0xFF;
+        assert!(parse_ntb16(&outside).is_err());
+        let mut looped = ntb.clone();
+        looped[ndp + 6..ndp + 8].copy_from_slice(&(ndp as u16).to_le_bytes());
+        assert!(parse_ntb16(&looped).is_err());
+        assert!(parse_ntb16(b"NCMH").is_err());
+    }
+
+    #[tokio::test]
+    async fn ncm_frames() {
+        setup_test_logger();
+        let mac = [0x02, 0, 0, 0, 0, 0x01];
+        let (backend, to_host, mut from_host) = ChannelEthernetBackend::new();
+        let device = UsbDevice::cdc_ncm(UsbCdcNcmHandler::new(mac, backend));
+        let desc = device.configuration_descriptor(0xFFFF);
+        verify_descriptor(&desc);
+        assert_eq!(device.interfaces[1].interface_protocol, NCM_DATA_PROTOCOL);
+
+        let get = |request, length| SetupPacket {
+            request_type: 0b10100001,
+            request,
+            value: 0,
+            index: 0,
+            length,
+        };
+        let params = device.handle_urb(device.ep0_in, None, 28, get(GET_NTB_PARAMETERS, 28), &[]);
+        let params = params.await.unwrap();
+        assert_eq!(params.len(), 28);
+        assert_eq!(params[4..8], NTB_MAX_SIZE.to_le_bytes());
+        let set_input_size = SetupPacket {
+            request_type: 0b00100001,
+            request: SET_NTB_INPUT_SIZE,
+            value: 0,
+            index: 0,
+            length: 4,
+        };
+        device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                set_input_size,
+                &4096u32.to_le_bytes(),
+            )
+            .await
+            .unwrap();
+        let too_small = device.handle_urb(device.ep0_out, None, 0, set_input_size, &[0, 1, 0, 0]);
+        assert!(too_small.await.is_err());
+        let input_size = device.handle_urb(device.ep0_in, None, 4, get(GET_NTB_INPUT_SIZE, 4), &[]);
+        assert_eq!(input_size.await.unwrap(), 4096u32.to_le_bytes());
+
+        let frame = |destination: [u8; 6], len: usize| {
+            let mut frame = destination.to_vec();
+            frame.extend([0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00]);
+            frame.resize(len, 0x45);
+            frame
+        };
+        let [_, bulk_in, bulk_out] = UsbCdcEcmHandler::endpoints().try_into().unwrap();
+        let data = &device.interfaces[1];
+        let mut handler = data.handler.lock().await;
+        let ntb = build_ntb16(0, &[frame([0xFF; 6], 60), frame(mac, 1514)]);
+        handler
+            .handle_urb(
+                data,
+                bulk_out,
+                ntb.len() as u32,
+                SetupPacket::default(),
+                &ntb,
+            )
+            .unwrap();
+        assert_eq!(from_host.recv().await.unwrap(), frame([0xFF; 6], 60));
+        assert_eq!(from_host.recv().await.unwrap().len(), 1514);
+        // a malformed NTB is dropped
+        handler
+            .handle_urb(data, bulk_out, 12, SetupPacket::default(), &ntb[..12])
+            .unwrap();
+        assert!(from_host.try_recv().is_err());
+
+        // three full frames do not fit into an NTB of 4096 bytes
+        for _ in 0..3 {
+            to_host.send(frame(mac, 1514)).unwrap();
+        }
+        let mut read = || {
+            handler
+                .handle_urb(data, bulk_in, 0x4000, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        let first = read();
+        assert!(first.len() <= 4096);
+        assert_eq!(parse_ntb16(&first).unwrap().len(), 2);
+        let second = read();
+        assert_eq!(u16::from_le_bytes([second[6], second[7]]), 1);
+        assert_eq!(parse_ntb16(&second).unwrap(), [frame(mac, 1514)]);
+        assert!(read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn eem_packets() {
+        setup_test_logger();
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        let frame = vec![0x45; 60];
+        let packet = EemPacket::Frame(frame.clone());
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[..2], (0x4000u16 | 64).to_le_bytes());
+        assert_eq!(EemPacket::parse(&bytes).unwrap()[0], packet);
+        // without a CRC
+        let mut sentinel = (64u16).to_le_bytes().to_vec();
+        sentinel.extend(&frame);
+        sentinel.extend(EEM_CRC_SENTINEL.to_le_bytes());
+        assert_eq!(EemPacket::parse(&sentinel).unwrap()[0], packet);
+        let mut corrupted = bytes.clone();
+        corrupted[10] ^= 1;
+        assert!(EemPacket::parse(&corrupted).is_err());
+        assert!(EemPacket::parse(&bytes[..40]).is_err());
+
+        let (backend, to_host, mut from_host) = ChannelEthernetBackend::new();
+        let device = UsbDevice::cdc_eem(UsbCdcEemHandler::new(backend));
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+        let intf = &device.interfaces[0];
+        let [bulk_in, bulk_out] = UsbCdcEemHandler::endpoints().try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+
+        let mut stream = vec![0, 0];
+        stream.extend(EemPacket::Echo(vec![1, 2, 3]).to_bytes());
+        stream.extend(&bytes);
+        stream.extend(EemPacket::Command(5, 0).to_bytes());
+        handler
+            .handle_urb(intf, bulk_out, 512, SetupPacket::default(), &stream)
+            .unwrap();
+        assert_eq!(from_host.recv().await.unwrap(), frame);
+
+        // the echo response and as many frames as the transfer fits
+        for _ in 0..3 {
+            to_host.send(frame.clone()).unwrap();
+        }
+        let mut read = || {
+            let res = handler.handle_urb(intf, bulk_in, 150, SetupPacket::default(), &[]);
+            EemPacket::parse(&res.unwrap()).unwrap()
+        };
+        assert_eq!(
+            read(),
+            [
+                EemPacket::EchoResponse(vec![1, 2, 3]),
+                packet.clone(),
+                packet.clone()
+            ]
+        );
+        assert_eq!(read(), [packet]);
+        assert!(read().is_empty());
+    }
 }
@@ -0,0 +1,494 @@
+//! Implement a Silicon Labs CP2102 USB to UART bridge
+//!
+//! Toolchains hard-coded to the cp210x drivers, e.g. of many development
+//! boards, bind to the CP2102 by its VID/PID. The serial line is configured
+//! with vendor requests to the interface, and the bulk endpoints carry the
+//! plain data.
+use super::super::*;
+use super::cdc::{CdcAcmEvent, LineCoding};
+
+// reference:
+// Silicon Labs AN571, CP210x Virtual COM Port Interface
+// https://www.silabs.com/documents/public/application-notes/AN571.pdf
+
+// vendor requests
+const IFC_ENABLE: u8 = 0x00;
+const SET_BAUDDIV: u8 = 0x01;
+const GET_BAUDDIV: u8 = 0x02;
+const SET_LINE_CTL: u8 = 0x03;
+const GET_LINE_CTL: u8 = 0x04;
+const SET_BREAK: u8 = 0x05;
+const IMM_CHAR: u8 = 0x06;
+const SET_MHS: u8 = 0x07;
+const GET_MDMSTS: u8 = 0x08;
+const SET_XON: u8 = 0x09;
+const SET_XOFF: u8 = 0x0A;
+const SET_EVENTMASK: u8 = 0x0B;
+const GET_EVENTMASK: u8 = 0x0C;
+const SET_CHAR: u8 = 0x0D;
+const GET_CHARS: u8 = 0x0E;
+const GET_COMM_STATUS: u8 = 0x10;
+const RESET: u8 = 0x11;
+const PURGE: u8 = 0x12;
+const SET_FLOW: u8 = 0x13;
+const GET_FLOW: u8 = 0x14;
+const EMBED_EVENTS: u8 = 0x15;
+const GET_EVENTSTATE: u8 = 0x16;
+const SET_CHARS: u8 = 0x19;
+const GET_BAUDRATE: u8 = 0x1D;
+const SET_BAUDRATE: u8 = 0x1E;
+const VENDOR_SPECIFIC: u8 = 0xFF;
+
+/// wValue of VENDOR_SPECIFIC reading the part number
+const GET_PARTNUM: u16 = 0x370B;
+/// Part number of the CP2102
+const PARTNUM_CP2102: u8 = 0x02;
+
+/// Clock the baud rate divisors of SET_BAUDDIV divide
+const BAUDDIV_CLOCK: u32 = 3_686_400;
+
+/// Clear To Send bit of the modem status
+pub const MODEM_STATUS_CTS: u8 = 1 << 4;
+/// Data Set Ready bit of the modem status
+pub const MODEM_STATUS_DSR: u8 = 1 << 5;
+/// Ring Indicator bit of the modem status
+pub const MODEM_STATUS_RI: u8 = 1 << 6;
+/// Data Carrier Detect bit of the modem status
+pub const MODEM_STATUS_DCD: u8 = 1 << 7;
+
+#[derive(Debug)]
+struct Cp210xState {
+    enabled: bool,
+    line_coding: LineCoding,
+    dtr: bool,
+    rts: bool,
+    /// Upper bits of the modem status, see `MODEM_STATUS_*`
+    modem_status: u8,
+    event_mask: u16,
+    /// Special characters of SET_CHARS
+    chars: [u8; 6],
+    /// Flow control of SET_FLOW
+    flow: [u8; 16],
+    /// Data waiting for the host on the bulk IN endpoint
+    to_host: Vec<u8>,
+    /// Data the host sent on the bulk OUT endpoint
+    from_host: Vec<u8>,
+    events: Option<tokio::sync::mpsc::UnboundedSender<CdcAcmEvent>>,
+}
+
+impl Cp210xState {
+    fn event(&mut self, event: CdcAcmEvent) {
+        debug!("CP210x {event:?}");
+        if let Some(events) = &self.events
+            && events.send(event).is_err()
+        {
+            self.events = None;
+        }
+    }
+
+    fn line_control(&self) -> u16 {
+        // stop bits and parity are coded like the CDC line coding
+        self.line_coding.stop_bits as u16
+            | (self.line_coding.parity as u16) << 4
+            | (self.line_coding.data_bits as u16) << 8
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.line_coding.baud_rate = baud_rate;
+        self.event(CdcAcmEvent::LineCoding(self.line_coding));
+    }
+
+    fn control(&mut self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b01000001, IFC_ENABLE) => {
+                self.enabled = setup.value & 0x01 != 0;
+                Ok(vec![])
+            }
+            (0b01000001, SET_BAUDDIV) if setup.value != 0 => {
+                self.set_baud_rate(BAUDDIV_CLOCK / setup.value as u32);
+                Ok(vec![])
+            }
+            (0b11000001, GET_BAUDDIV) => {
+                let divisor = BAUDDIV_CLOCK / self.line_coding.baud_rate.max(1);
+                Ok((divisor as u16).to_le_bytes().to_vec())
+            }
+            (0b01000001, SET_BAUDRATE) if req.len() == 4 => {
+                self.set_baud_rate(u32::from_le_bytes(req.try_into().unwrap()));
+                Ok(vec![])
+            }
+            (0b11000001, GET_BAUDRATE) => Ok(self.line_coding.baud_rate.to_le_bytes().to_vec()),
+            (0b01000001, SET_LINE_CTL) => {
+                self.line_coding.stop_bits = (setup.value & 0x0F) as u8;
+                self.line_coding.parity = ((setup.value >> 4) & 0x0F) as u8;
+                self.line_coding.data_bits = (setup.value >> 8) as u8;
+                self.event(CdcAcmEvent::LineCoding(self.line_coding));
+                Ok(vec![])
+            }
+            (0b11000001, GET_LINE_CTL) => Ok(self.line_control().to_le_bytes().to_vec()),
+            (0b01000001, SET_BREAK) => {
+                let duration = if setup.value != 0 { 0xFFFF } else { 0 };
+                self.event(CdcAcmEvent::Break(duration));
+                Ok(vec![])
+            }
+            (0b01000001, SET_MHS) => {
+                // the high byte selects the lines to change
+                if setup.value & 0x0100 != 0 {
+                    self.dtr = setup.value & 0x01 != 0;
+                }
+                if setup.value & 0x0200 != 0 {
+                    self.rts = setup.value & 0x02 != 0;
+                }
+                self.event(CdcAcmEvent::ControlLineState {
+                    dtr: self.dtr,
+                    rts: self.rts,
+                });
+                Ok(vec![])
+            }
+            (0b11000001, GET_MDMSTS) => Ok(vec![
+                self.modem_status | self.dtr as u8 | (self.rts as u8) << 1,
+            ]),
+            (0b01000001, SET_EVENTMASK) => {
+                self.event_mask = setup.value;
+                Ok(vec![])
+            }
+            (0b11000001, GET_EVENTMASK) => Ok(self.event_mask.to_le_bytes().to_vec()),
+            (0b11000001, GET_EVENTSTATE) => Ok(vec![0; 2]),
+            (0b01000001, SET_CHARS) if req.len() == self.chars.len() => {
+                self.chars.copy_from_slice(req);
+                Ok(vec![])
+            }
+            (0b11000001, GET_CHARS) => Ok(self.chars.to_vec()),
+            (0b01000001, SET_FLOW) if req.len() == self.flow.len() => {
+                self.flow.copy_from_slice(req);
+                Ok(vec![])
+            }
+            (0b11000001, GET_FLOW) => Ok(self.flow.to_vec()),
+            (0b11000001, GET_COMM_STATUS) => {
+                let mut status = vec![];
+                status.extend(0u32.to_le_bytes()); // ulErrors
+                status.extend(0u32.to_le_bytes()); // ulHoldReasons
+                status.extend((self.to_host.len() as u32).to_le_bytes()); // ulAmountInInQueue
+                status.extend(0u32.to_le_bytes()); // ulAmountInOutQueue
+                status.extend([0, 0, 0]); // bEofReceived, bWaitForImmediate, bReserved
+                Ok(status)
+            }
+            (0b01000001, PURGE) => {
+                // transmit and receive queues of the bridge
+                if setup.value & 0x05 != 0 {
+                    self.from_host.clear();
+                }
+                if setup.value & 0x0A != 0 {
+                    self.to_host.clear();
+                }
+                Ok(vec![])
+            }
+            (0b01000001, IMM_CHAR | SET_XON | SET_XOFF | SET_CHAR | RESET | EMBED_EVENTS) => {
+                debug!("Ignoring CP210x request {setup:x?}");
+                Ok(vec![])
+            }
+            (0b11000001, VENDOR_SPECIFIC) if setup.value == GET_PARTNUM => Ok(vec![PARTNUM_CP2102]),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported CP210x request: {setup:x?}"),
+            )),
+        }
+    }
+}
+
+/// A handler of the serial line of a CP2102
+///
+/// The configuration of the line is reported as [CdcAcmEvent]s, like that of
+/// a [cdc::UsbCdcAcmHandler], so that the same code can serve both.
+#[derive(Clone, Debug)]
+pub struct UsbCp210xHandler {
+    state: Arc<Mutex<Cp210xState>>,
+}
+
+impl Default for UsbCp210xHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbCp210xHandler {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(Cp210xState {
+                enabled: false,
+                line_coding: LineCoding {
+                    baud_rate: 9600,
+                    ..Default::default()
+                },
+                dtr: false,
+                rts: false,
+                modem_status: 0,
+                event_mask: 0,
+                chars: [0; 6],
+                // DTR and RTS controlled by the host
+                flow: [0x01, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                to_host: vec![],
+                from_host: vec![],
+                events: None,
+            })),
+        }
+    }
+
+    /// The receiver of the requests of the host from now on
+    ///
+    /// A previous receiver gets no more events.
+    pub fn events(&self) -> tokio::sync::mpsc::UnboundedReceiver<CdcAcmEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.state.lock().unwrap().events = Some(sender);
+        receiver
+    }
+
+    /// Queue `data` for the host to read
+    pub fn write(&self, data: &[u8]) {
+        self.state.lock().unwrap().to_host.extend_from_slice(data);
+    }
+
+    /// Take the data the host has written so far
+    pub fn read(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.lock().unwrap().from_host)
+    }
+
+    /// The line coding set by the host
+    pub fn line_coding(&self) -> LineCoding {
+        self.state.lock().unwrap().line_coding
+    }
+
+    /// Whether the host has enabled the interface with IFC_ENABLE
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled
+    }
+
+    /// Set the modem lines reported to the host, a set of `MODEM_STATUS_*` bits
+    pub fn set_modem_status(&self, status: u8) {
+        self.state.lock().unwrap().modem_status = status & 0xF0;
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 64,                        // 64 bytes
+                interval: 0,
+            },
+            UsbEndpoint {
+                address: 0x01,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 64,                        // 64 bytes
+                interval: 0,
+            },
+        ]
+    }
+}
+
+impl UsbInterfaceHandler for UsbCp210xHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if ep.is_ep0() {
+            return state.control(setup, req);
+        }
+        if let Direction::Out = ep.direction() {
+            state.from_host.extend_from_slice(req);
+            return Ok(vec![]);
+        }
+        let len = state.to_host.len().min(transfer_buffer_length as usize);
+        Ok(state.to_host.drain(..len).collect())
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A CP2102 USB to UART bridge with the serial line of `handler`
+    pub fn cp210x(handler: UsbCp210xHandler) -> Self {
+        let mut device = Self::new(0).with_speed(UsbSpeed::Full).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            Some("CP2102 USB to UART Bridge Controller"),
+            UsbCp210xHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // the VID/PID of the CP210x, which the drivers bind to
+        device.vendor_id = 0x10C4;
+        device.product_id = 0xEA60;
+        device.device_bcd = 0x0100.into();
+        device.set_manufacturer_name("Silicon Labs");
+        device.set_product_name("CP2102 USB to UART Bridge Controller");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    async fn vendor(device: &UsbDevice, request: u8, value: u16, data: &[u8]) {
+        let setup = SetupPacket {
+            request_type: 0b01000001,
+            request,
+            value,
+            index: 0,
+            length: data.len() as u16,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, setup, data)
+            .await
+            .unwrap();
+    }
+
+    async fn vendor_in(device: &UsbDevice, request: u8, value: u16, length: u16) -> Vec<u8> {
+        let setup = SetupPacket {
+            request_type: 0b11000001,
+            request,
+            value,
+            index: 0,
+            length,
+        };
+        device
+            .handle_urb(device.ep0_in, None, length as u32, setup, &[])
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cp210x_requests() {
+        setup_test_logger();
+        let handler = UsbCp210xHandler::new();
+        let mut events = handler.events();
+        let device = UsbDevice::cp210x(handler.clone());
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        assert_eq!(
+            vendor_in(&device, VENDOR_SPECIFIC, GET_PARTNUM, 1).await,
+            [PARTNUM_CP2102]
+        );
+        vendor(&device, IFC_ENABLE, 1, &[]).await;
+        assert!(handler.enabled());
+
+        vendor(&device, SET_BAUDRATE, 0, &115200u32.to_le_bytes()).await;
+        // 7 data bits, odd parity, 2 stop bits
+        vendor(&device, SET_LINE_CTL, 0x0712, &[]).await;
+        let line_coding = LineCoding {
+            baud_rate: 115200,
+            stop_bits: 2,
+            parity: 1,
+            data_bits: 7,
+        };
+        assert_eq!(handler.line_coding(), line_coding);
+        assert_eq!(
+            vendor_in(&device, GET_BAUDRATE, 0, 4).await,
+            115200u32.to_le_bytes()
+        );
+        assert_eq!(vendor_in(&device, GET_LINE_CTL, 0, 2).await, [0x12, 0x07]);
+        assert_eq!(vendor_in(&device, GET_BAUDDIV, 0, 2).await, [32, 0]);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::LineCoding(LineCoding {
+                baud_rate: 115200,
+                ..
+            })
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::LineCoding(coding) if coding == line_coding
+        ));
+
+        // DTR and RTS on, then RTS off alone
+        vendor(&device, SET_MHS, 0x0303, &[]).await;
+        vendor(&device, SET_MHS, 0x0200, &[]).await;
+        handler.set_modem_status(MODEM_STATUS_CTS | MODEM_STATUS_DCD);
+        assert_eq!(vendor_in(&device, GET_MDMSTS, 0, 1).await, [0x91]);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::ControlLineState {
+                dtr: true,
+                rts: true
+            }
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::ControlLineState {
+                dtr: true,
+                rts: false
+            }
+        ));
+
+        vendor(&device, SET_BREAK, 1, &[]).await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::Break(0xFFFF)
+        ));
+
+        let flow = [0x09, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        vendor(&device, SET_FLOW, 0, &flow).await;
+        assert_eq!(vendor_in(&device, GET_FLOW, 0, 16).await, flow);
+
+        handler.write(b"queued");
+        let status = vendor_in(&device, GET_COMM_STATUS, 0, 19).await;
+        assert_eq!(status.len(), 19);
+        assert_eq!(status[8..12], 6u32.to_le_bytes());
+        vendor(&device, PURGE, 0x0A, &[]).await;
+        let status = vendor_in(&device, GET_COMM_STATUS, 0, 19).await;
+        assert_eq!(status[8..12], 0u32.to_le_bytes());
+
+        // GET_PROPS is not implemented
+        let setup = SetupPacket {
+            request_type: 0b11000001,
+            request: 0x0F,
+            value: 0,
+            index: 0,
+            length: 66,
+        };
+        assert!(
+            device
+                .handle_urb(device.ep0_in, None, 66, setup, &[])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn cp210x_bulk() {
+        setup_test_logger();
+        let handler = UsbCp210xHandler::new();
+        let device = UsbDevice::cp210x(handler.clone());
+        let intf = &device.interfaces[0];
+        let [bulk_in, bulk_out] = UsbCp210xHandler::endpoints().try_into().unwrap();
+        let mut intf_handler = intf.handler.lock().await;
+
+        handler.write(b"hello world");
+        let resp = intf_handler
+            .handle_urb(intf, bulk_in, 5, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp, b"hello");
+        let resp = intf_handler
+            .handle_urb(intf, bulk_in, 64, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp, b" world");
+
+        intf_handler
+            .handle_urb(intf, bulk_out, 4, SetupPacket::default(), b"ping")
+            .unwrap();
+        assert_eq!(handler.read(), b"ping");
+    }
+}
@@ -0,0 +1,563 @@
+//! Implement the CTAP-HID transport of FIDO2 authenticators
+//!
+//! [UsbCtapHidHandler] splits and joins CTAPHID messages into 64 byte
+//! reports and manages channels, while a [CtapBackend] performs the
+//! credential operations, so a software authenticator can be used by
+//! remote browsers.
+use super::super::*;
+use super::hid::{HidDescriptorType, hid_descriptor};
+use std::time::{Duration, Instant};
+
+// reference:
+// CTAP 2.1, section 11.2: https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#usb
+
+/// Length of every report, in both directions
+pub const CTAPHID_REPORT_LEN: usize = 64;
+/// Longest message: an initialization packet and 128 continuation packets
+pub const CTAPHID_MAX_MESSAGE_LEN: usize =
+    (CTAPHID_REPORT_LEN - 7) + 128 * (CTAPHID_REPORT_LEN - 5);
+const BROADCAST_CID: u32 = 0xFFFF_FFFF;
+
+// commands
+pub const CTAPHID_PING: u8 = 0x81;
+pub const CTAPHID_MSG: u8 = 0x83;
+pub const CTAPHID_LOCK: u8 = 0x84;
+pub const CTAPHID_INIT: u8 = 0x86;
+pub const CTAPHID_WINK: u8 = 0x88;
+pub const CTAPHID_CBOR: u8 = 0x90;
+pub const CTAPHID_CANCEL: u8 = 0x91;
+pub const CTAPHID_KEEPALIVE: u8 = 0xBB;
+pub const CTAPHID_ERROR: u8 = 0xBF;
+
+// errors of CTAPHID_ERROR
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0B;
+
+// capabilities of CTAPHID_INIT
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+const CAPABILITY_NMSG: u8 = 0x08;
+
+/// CTAP2 status of a cancelled CBOR request
+pub const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2D;
+
+/// Interval of KEEPALIVE messages while a request is processed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress of a CBOR request, see [CtapBackend::cbor]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CtapStatus {
+    /// The response: a CTAP2 status byte, followed by CBOR data
+    Done(Vec<u8>),
+    /// Still working, poll again
+    Processing,
+    /// Waiting for the user to touch the authenticator, poll again
+    UserPresenceNeeded,
+}
+
+/// The authenticator behind a [UsbCtapHidHandler]
+///
+/// Requests are handled while the interrupt endpoints are polled, so long
+/// operations should return [CtapStatus::Processing] and finish in
+/// [CtapBackend::poll], while the handler sends KEEPALIVE messages.
+pub trait CtapBackend: std::fmt::Debug {
+    /// Start the CTAP2 request `request`: a command byte, followed by CBOR data
+    fn cbor(&mut self, request: &[u8]) -> CtapStatus;
+
+    /// Continue the pending CBOR request
+    fn poll(&mut self) -> CtapStatus {
+        CtapStatus::Processing
+    }
+
+    /// Abandon the pending CBOR request, after CTAPHID_CANCEL
+    fn cancel(&mut self) {}
+
+    /// Whether U2F/CTAP1 messages are handled by [CtapBackend::msg]
+    fn supports_msg(&self) -> bool {
+        false
+    }
+
+    /// Handle the U2F/CTAP1 APDU `apdu`, returning the response APDU
+    fn msg(&mut self, _apdu: &[u8]) -> Vec<u8> {
+        // SW_INS_NOT_SUPPORTED
+        vec![0x6D, 0x00]
+    }
+
+    /// Show the user which authenticator this is, e.g. by logging
+    fn wink(&mut self) {}
+}
+
+/// A message being received
+#[derive(Clone, Debug)]
+struct CtapHidAssembly {
+    cid: u32,
+    command: u8,
+    len: usize,
+    data: Vec<u8>,
+    next_seq: u8,
+}
+
+/// A handler of the CTAP-HID interface of a FIDO2 authenticator
+#[derive(Debug)]
+pub struct UsbCtapHidHandler {
+    pub report_descriptor: Vec<u8>,
+    backend: Box<dyn CtapBackend + Send>,
+    /// Allocated channels are 1 to `next_cid - 1`
+    next_cid: u32,
+    assembly: Option<CtapHidAssembly>,
+    /// Channel of the CBOR request the backend is processing
+    pending: Option<u32>,
+    last_keepalive: Option<Instant>,
+    /// Reports for the interrupt IN endpoint
+    pending_reports: VecDeque<Vec<u8>>,
+}
+
+impl UsbCtapHidHandler {
+    pub fn new(backend: impl CtapBackend + Send + 'static) -> Self {
+        Self {
+            report_descriptor: vec![
+                0x06, 0xD0, 0xF1, // Usage Page (FIDO Alliance)
+                0x09, 0x01, // Usage (CTAPHID)
+                0xA1, 0x01, // Collection (Application)
+                0x09, 0x20, // Usage (Input Report Data)
+                0x15, 0x00, // Logic Min
+                0x26, 0xFF, 0x00, // Logic Max (255)
+                0x75, 0x08, // Report Size (8)
+                0x95, 0x40, // Report Count (64)
+                0x81, 0x02, // Input (Data, Variable, Absolute)
+                0x09, 0x21, // Usage (Output Report Data)
+                0x15, 0x00, // Logic Min
+                0x26, 0xFF, 0x00, // Logic Max (255)
+                0x75, 0x08, // Report Size (8)
+                0x95, 0x40, // Report Count (64)
+                0x91, 0x02, // Output (Data, Variable, Absolute)
+                0xC0, // End Collection
+            ],
+            backend: Box::new(backend),
+            next_cid: 1,
+            assembly: None,
+            pending: None,
+            last_keepalive: None,
+            pending_reports: VecDeque::new(),
+        }
+    }
+
+    /// The authenticator
+    pub fn backend(&mut self) -> &mut Box<dyn CtapBackend + Send> {
+        &mut self.backend
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // interrupt in
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: CTAPHID_REPORT_LEN as u16,
+                interval: 5,
+            },
+            // interrupt out
+            UsbEndpoint {
+                address: 0x01,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: CTAPHID_REPORT_LEN as u16,
+                interval: 5,
+            },
+        ]
+    }
+
+    /// Queue the reports of the message `data` to channel `cid`
+    fn respond(&mut self, cid: u32, command: u8, data: &[u8]) {
+        let (first, rest) = data.split_at(data.len().min(CTAPHID_REPORT_LEN - 7));
+        let mut report = cid.to_be_bytes().to_vec();
+        report.push(command);
+        report.extend((data.len() as u16).to_be_bytes());
+        report.extend_from_slice(first);
+        report.resize(CTAPHID_REPORT_LEN, 0);
+        self.pending_reports.push_back(report);
+        for (seq, chunk) in rest.chunks(CTAPHID_REPORT_LEN - 5).enumerate() {
+            let mut report = cid.to_be_bytes().to_vec();
+            report.push(seq as u8);
+            report.extend_from_slice(chunk);
+            report.resize(CTAPHID_REPORT_LEN, 0);
+            self.pending_reports.push_back(report);
+        }
+    }
+
+    fn error(&mut self, cid: u32, error: u8) {
+        debug!("CTAPHID error {error:#x} on channel {cid:#x}");
+        self.respond(cid, CTAPHID_ERROR, &[error]);
+    }
+
+    /// Respond to the CBOR request on `cid`, or keep it pending
+    fn cbor_status(&mut self, cid: u32, status: CtapStatus) {
+        let keepalive = match status {
+            CtapStatus::Done(response) => {
+                self.pending = None;
+                self.respond(cid, CTAPHID_CBOR, &response);
+                return;
+            }
+            CtapStatus::Processing => 1,
+            CtapStatus::UserPresenceNeeded => 2,
+        };
+        self.pending = Some(cid);
+        if self
+            .last_keepalive
+            .is_none_or(|last| last.elapsed() >= KEEPALIVE_INTERVAL)
+        {
+            self.last_keepalive = Some(Instant::now());
+            self.respond(cid, CTAPHID_KEEPALIVE, &[keepalive]);
+        }
+    }
+
+    fn init(&mut self, cid: u32, nonce: &[u8]) {
+        if nonce.len() != 8 {
+            return self.error(cid, ERR_INVALID_LEN);
+        }
+        let new_cid = if cid == BROADCAST_CID {
+            let new_cid = self.next_cid;
+            self.next_cid = self.next_cid.wrapping_add(1).max(1);
+            new_cid
+        } else {
+            // resynchronize the channel
+            if self.pending == Some(cid) {
+                self.backend.cancel();
+                self.pending = None;
+            }
+            cid
+        };
+        let mut response = nonce.to_vec();
+        response.extend(new_cid.to_be_bytes());
+        // protocol version, device version
+        response.extend([2, 0, 1, 0]);
+        let mut capabilities = CAPABILITY_WINK | CAPABILITY_CBOR;
+        if !self.backend.supports_msg() {
+            capabilities |= CAPABILITY_NMSG;
+        }
+        response.push(capabilities);
+        self.respond(cid, CTAPHID_INIT, &response);
+    }
+
+    /// Handle the complete message `data`
+    fn dispatch(&mut self, cid: u32, command: u8, data: &[u8]) {
+        debug!("CTAPHID command {command:#x} on channel {cid:#x}");
+        match command {
+            CTAPHID_PING => self.respond(cid, CTAPHID_PING, data),
+            CTAPHID_WINK => {
+                self.backend.wink();
+                self.respond(cid, CTAPHID_WINK, &[]);
+            }
+            CTAPHID_MSG if self.backend.supports_msg() => {
+                let response = self.backend.msg(data);
+                self.respond(cid, CTAPHID_MSG, &response);
+            }
+            CTAPHID_CBOR if data.is_empty() => self.error(cid, ERR_INVALID_LEN),
+            CTAPHID_CBOR => {
+                self.last_keepalive = None;
+                let status = self.backend.cbor(data);
+                self.cbor_status(cid, status);
+            }
+            _ => self.error(cid, ERR_INVALID_CMD),
+        }
+    }
+
+    /// Handle a report from the interrupt OUT endpoint
+    fn receive(&mut self, report: &[u8]) {
+        if report.len() < 5 {
+            return;
+        }
+        let cid = u32::from_be_bytes(report[..4].try_into().unwrap());
+        let command = report[4];
+
+        if command & 0x80 == 0 {
+            // continuation packet, others are ignored
+            let Some(assembly) = self.assembly.as_mut().filter(|a| a.cid == cid) else {
+                return;
+            };
+            if command != assembly.next_seq {
+                self.assembly = None;
+                return self.error(cid, ERR_INVALID_SEQ);
+            }
+            assembly.next_seq += 1;
+            let missing = assembly.len - assembly.data.len();
+            assembly
+                .data
+                .extend_from_slice(&report[5..report.len().min(5 + missing)]);
+        } else {
+            if report.len() < 7 {
+                return;
+            }
+            let len = u16::from_be_bytes([report[5], report[6]]) as usize;
+            let data = &report[7..report.len().min(7 + len)];
+            if cid == 0 || (cid != BROADCAST_CID && cid >= self.next_cid) {
+                return self.error(cid, ERR_INVALID_CHANNEL);
+            }
+            if command == CTAPHID_INIT {
+                if self.assembly.as_ref().is_some_and(|a| a.cid == cid) {
+                    self.assembly = None;
+                }
+                return self.init(cid, data);
+            }
+            if cid == BROADCAST_CID {
+                return self.error(cid, ERR_INVALID_CHANNEL);
+            }
+            if command == CTAPHID_CANCEL {
+                if self.pending == Some(cid) {
+                    self.backend.cancel();
+                    self.cbor_status(cid, CtapStatus::Done(vec![CTAP2_ERR_KEEPALIVE_CANCEL]));
+                }
+                return;
+            }
+            match &self.assembly {
+                Some(assembly) if assembly.cid == cid => {
+                    self.assembly = None;
+                    return self.error(cid, ERR_INVALID_SEQ);
+                }
+                Some(_) => return self.error(cid, ERR_CHANNEL_BUSY),
+                None if self.pending.is_some() => return self.error(cid, ERR_CHANNEL_BUSY),
+                None => {}
+            }
+            if len > CTAPHID_MAX_MESSAGE_LEN {
+                return self.error(cid, ERR_INVALID_LEN);
+            }
+            self.assembly = Some(CtapHidAssembly {
+                cid,
+                command,
+                len,
+                data: data.to_vec(),
+                next_seq: 0,
+            });
+        }
+
+        if let Some(assembly) = self.assembly.take_if(|a| a.data.len() == a.len) {
+            self.dispatch(assembly.cid, assembly.command, &assembly.data);
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbCtapHidHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            // control transfers
+            return match (setup.request_type, setup.request) {
+                (0b10000001, 0x06) => {
+                    // GET_DESCRIPTOR
+                    // high byte: type
+                    match FromPrimitive::from_u16(setup.value >> 8) {
+                        Some(HidDescriptorType::Report) => Ok(self.report_descriptor.clone()),
+                        _ => Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Unsupported HID descriptor: {setup:x?}"),
+                        )),
+                    }
+                }
+                (0b00100001, 0x0A) => {
+                    // SET_IDLE
+                    Ok(vec![])
+                }
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Unsupported HID request: {setup:x?}"),
+                )),
+            };
+        }
+        match ep.direction() {
+            Direction::Out => {
+                self.receive(req);
+                Ok(vec![])
+            }
+            Direction::In => {
+                if self.pending_reports.is_empty()
+                    && let Some(cid) = self.pending
+                {
+                    let status = self.backend.poll();
+                    self.cbor_status(cid, status);
+                }
+                Ok(self.pending_reports.pop_front().unwrap_or_default())
+            }
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        // SET_IDLE
+        Some(&[0x0A])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        hid_descriptor(&self.report_descriptor)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A FIDO2 security key with the single interface of `handler`
+    pub fn ctap_hid(handler: UsbCtapHidHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            Some("CTAPHID"),
+            UsbCtapHidHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0004;
+        device.set_product_name("Virtual Security Key");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hid::HidReportDescriptor;
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// Echoes CBOR requests, after waiting for the user once
+    #[derive(Debug, Default)]
+    struct EchoBackend {
+        waiting: Option<Vec<u8>>,
+    }
+
+    impl CtapBackend for EchoBackend {
+        fn cbor(&mut self, request: &[u8]) -> CtapStatus {
+            self.waiting = Some(request.to_vec());
+            CtapStatus::UserPresenceNeeded
+        }
+
+        fn poll(&mut self) -> CtapStatus {
+            let mut response = vec![0];
+            response.extend(self.waiting.take().unwrap());
+            CtapStatus::Done(response)
+        }
+
+        fn cancel(&mut self) {
+            self.waiting = None;
+        }
+    }
+
+    struct Client {
+        handler: UsbCtapHidHandler,
+        interface: UsbInterface,
+    }
+
+    impl Client {
+        fn urb(&mut self, address: u8, req: &[u8]) -> Vec<u8> {
+            let ep = UsbCtapHidHandler::endpoints()
+                .into_iter()
+                .find(|ep| ep.address == address)
+                .unwrap();
+            self.handler
+                .handle_urb(&self.interface, ep, 64, SetupPacket::default(), req)
+                .unwrap()
+        }
+
+        fn send(&mut self, cid: u32, command: u8, data: &[u8]) {
+            let (first, rest) = data.split_at(data.len().min(57));
+            let mut report = cid.to_be_bytes().to_vec();
+            report.push(command);
+            report.extend((data.len() as u16).to_be_bytes());
+            report.extend_from_slice(first);
+            report.resize(64, 0);
+            self.urb(0x01, &report);
+            for (seq, chunk) in rest.chunks(59).enumerate() {
+                let mut report = cid.to_be_bytes().to_vec();
+                report.push(seq as u8);
+                report.extend_from_slice(chunk);
+                report.resize(64, 0);
+                self.urb(0x01, &report);
+            }
+        }
+
+        /// Command and data of the next message, skipping keepalives
+        fn receive(&mut self, cid: u32) -> (u8, Vec<u8>) {
+            let report = self.urb(0x81, &[]);
+            assert_eq!(report.len(), 64);
+            assert_eq!(report[..4], cid.to_be_bytes());
+            let len = u16::from_be_bytes([report[5], report[6]]) as usize;
+            let mut data = report[7..64.min(7 + len)].to_vec();
+            let mut seq = 0;
+            while data.len() < len {
+                let report = self.urb(0x81, &[]);
+                assert_eq!(report[4], seq);
+                seq += 1;
+                data.extend_from_slice(&report[5..64.min(5 + len - data.len())]);
+            }
+            (report[4], data)
+        }
+    }
+
+    #[test]
+    fn ctaphid_messages() {
+        setup_test_logger();
+        let device = UsbDevice::ctap_hid(UsbCtapHidHandler::new(EchoBackend::default()));
+        let mut client = Client {
+            handler: UsbCtapHidHandler::new(EchoBackend::default()),
+            interface: device.interfaces[0].clone(),
+        };
+        verify_descriptor(&client.handler.get_class_specific_descriptor());
+        HidReportDescriptor::parse(&client.handler.report_descriptor).unwrap();
+        assert!(client.urb(0x81, &[]).is_empty());
+
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        client.send(BROADCAST_CID, CTAPHID_INIT, &nonce);
+        let (command, response) = client.receive(BROADCAST_CID);
+        assert_eq!(command, CTAPHID_INIT);
+        assert_eq!(response[..8], nonce);
+        let cid = u32::from_be_bytes(response[8..12].try_into().unwrap());
+        assert_eq!(cid, 1);
+        assert_eq!(
+            response[16],
+            CAPABILITY_WINK | CAPABILITY_CBOR | CAPABILITY_NMSG
+        );
+
+        let ping: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        client.send(cid, CTAPHID_PING, &ping);
+        assert_eq!(client.receive(cid), (CTAPHID_PING, ping.clone()));
+
+        client.send(cid, CTAPHID_CBOR, &[0x04]);
+        assert_eq!(client.receive(cid), (CTAPHID_KEEPALIVE, vec![2]));
+        assert_eq!(client.receive(cid), (CTAPHID_CBOR, vec![0, 0x04]));
+
+        client.send(cid, CTAPHID_CBOR, &[0x01, 0xA0]);
+        assert_eq!(client.receive(cid), (CTAPHID_KEEPALIVE, vec![2]));
+        client.send(cid + 1, CTAPHID_PING, &[]);
+        assert_eq!(
+            client.receive(cid + 1),
+            (CTAPHID_ERROR, vec![ERR_INVALID_CHANNEL])
+        );
+        client.send(cid, CTAPHID_CANCEL, &[]);
+        assert_eq!(
+            client.receive(cid),
+            (CTAPHID_CBOR, vec![CTAP2_ERR_KEEPALIVE_CANCEL])
+        );
+
+        client.send(cid, CTAPHID_MSG, &[0x00, 0x03, 0x00, 0x00]);
+        assert_eq!(client.receive(cid), (CTAPHID_ERROR, vec![ERR_INVALID_CMD]));
+
+        // a continuation packet out of order
+        let mut report = cid.to_be_bytes().to_vec();
+        report.extend([CTAPHID_PING, 0x01, 0x00]);
+        report.resize(64, 0);
+        client.urb(0x01, &report);
+        report[4] = 0x01;
+        client.urb(0x01, &report);
+        assert_eq!(client.receive(cid), (CTAPHID_ERROR, vec![ERR_INVALID_SEQ]));
+        assert!(client.urb(0x81, &[]).is_empty());
+    }
+}
@@ -0,0 +1,531 @@
+//! Implement a FTDI FT232R USB UART
+//!
+//! Tools and drivers that do not speak CDC ACM, like ftdi_sio, libftdi or
+//! the FTDI VCP driver of Windows, bind to the FT232R by its VID/PID. The
+//! serial line is configured with vendor requests to the device, and every
+//! packet on the bulk IN endpoint starts with the modem and line status.
+use super::super::*;
+use super::cdc::{CdcAcmEvent, LineCoding};
+
+// reference:
+// FTDI AN232B-05 and AN232B-04, and the vendor requests as used by ftdi_sio
+// https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h
+
+// vendor requests
+const RESET: u8 = 0x00;
+const MODEM_CTRL: u8 = 0x01;
+const SET_FLOW_CTRL: u8 = 0x02;
+const SET_BAUD_RATE: u8 = 0x03;
+const SET_DATA: u8 = 0x04;
+const GET_MODEM_STATUS: u8 = 0x05;
+const SET_EVENT_CHAR: u8 = 0x06;
+const SET_ERROR_CHAR: u8 = 0x07;
+const SET_LATENCY_TIMER: u8 = 0x09;
+const GET_LATENCY_TIMER: u8 = 0x0A;
+const SET_BITMODE: u8 = 0x0B;
+const READ_EEPROM: u8 = 0x90;
+const WRITE_EEPROM: u8 = 0x91;
+const ERASE_EEPROM: u8 = 0x92;
+
+// values of RESET
+const RESET_SIO: u16 = 0;
+const RESET_PURGE_RX: u16 = 1;
+const RESET_PURGE_TX: u16 = 2;
+
+/// Clear To Send bit of the modem status
+pub const MODEM_STATUS_CTS: u8 = 1 << 4;
+/// Data Set Ready bit of the modem status
+pub const MODEM_STATUS_DSR: u8 = 1 << 5;
+/// Ring Indicator bit of the modem status
+pub const MODEM_STATUS_RI: u8 = 1 << 6;
+/// Data Carrier Detect bit of the modem status
+pub const MODEM_STATUS_DCD: u8 = 1 << 7;
+
+/// Reserved bits of the modem status, as sent by the FT232R
+const MODEM_STATUS_RESERVED: u8 = 0x01;
+/// Line status of an idle line: transmitter holding register and transmitter empty
+const LINE_STATUS_IDLE: u8 = 0x60;
+
+/// Max packet size of the bulk endpoints, the FT232R is a full speed device
+const MAX_PACKET_SIZE: u16 = 64;
+/// Size of the EEPROM of the FT232R in words
+const EEPROM_WORDS: usize = 64;
+
+/// Decode the divisor of SET_BAUD_RATE of a FT232R into a baud rate
+///
+/// The divisor of the 3 MHz base clock has 14 integer bits in `value`, and a
+/// fraction of eighths coded in the top bits of `value` and bit 0 of `index`.
+pub fn decode_baud_rate(value: u16, index: u16) -> u32 {
+    // eighths of the fraction codes
+    const FRACTIONS: [u32; 8] = [0, 4, 2, 1, 3, 5, 6, 7];
+    let integer = (value & 0x3FFF) as u32;
+    let code = ((value >> 14) | ((index & 1) << 2)) as usize;
+    match (integer, code) {
+        // special divisors
+        (0, 0) => 3_000_000,
+        (1, 0) => 2_000_000,
+        _ => 3_000_000 * 8 / (integer * 8 + FRACTIONS[code]),
+    }
+}
+
+#[derive(Debug)]
+struct FtdiState {
+    line_coding: LineCoding,
+    dtr: bool,
+    rts: bool,
+    break_enabled: bool,
+    /// Upper bits of the modem status, see `MODEM_STATUS_*`
+    modem_status: u8,
+    latency_timer: u8,
+    eeprom: [u16; EEPROM_WORDS],
+    /// Data waiting for the host on the bulk IN endpoint
+    to_host: Vec<u8>,
+    /// Data the host sent on the bulk OUT endpoint
+    from_host: Vec<u8>,
+    events: Option<tokio::sync::mpsc::UnboundedSender<CdcAcmEvent>>,
+}
+
+impl FtdiState {
+    fn event(&mut self, event: CdcAcmEvent) {
+        debug!("FTDI {event:?}");
+        if let Some(events) = &self.events
+            && events.send(event).is_err()
+        {
+            self.events = None;
+        }
+    }
+
+    fn modem_status(&self) -> [u8; 2] {
+        [MODEM_STATUS_RESERVED | self.modem_status, LINE_STATUS_IDLE]
+    }
+
+    fn eeprom_word(&mut self, index: u16) -> Result<&mut u16> {
+        self.eeprom.get_mut(index as usize).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid EEPROM address {index:#x}"),
+            )
+        })
+    }
+
+    fn control(&mut self, setup: SetupPacket) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b01000000, RESET) => {
+                match setup.value {
+                    RESET_SIO => {
+                        self.to_host.clear();
+                        self.from_host.clear();
+                    }
+                    // the receive buffer of the chip feeds the host
+                    RESET_PURGE_RX => self.to_host.clear(),
+                    RESET_PURGE_TX => self.from_host.clear(),
+                    _ => {}
+                }
+                Ok(vec![])
+            }
+            (0b01000000, MODEM_CTRL) => {
+                // the high byte selects the lines to change
+                if setup.value & 0x0100 != 0 {
+                    self.dtr = setup.value & 0x01 != 0;
+                }
+                if setup.value & 0x0200 != 0 {
+                    self.rts = setup.value & 0x02 != 0;
+                }
+                self.event(CdcAcmEvent::ControlLineState {
+                    dtr: self.dtr,
+                    rts: self.rts,
+                });
+                Ok(vec![])
+            }
+            (0b01000000, SET_BAUD_RATE) => {
+                self.line_coding.baud_rate = decode_baud_rate(setup.value, setup.index);
+                self.event(CdcAcmEvent::LineCoding(self.line_coding));
+                Ok(vec![])
+            }
+            (0b01000000, SET_DATA) => {
+                // parity and stop bits are coded like the CDC line coding
+                self.line_coding.data_bits = setup.value as u8;
+                self.line_coding.parity = ((setup.value >> 8) & 0x07) as u8;
+                self.line_coding.stop_bits = ((setup.value >> 11) & 0x07) as u8;
+                self.event(CdcAcmEvent::LineCoding(self.line_coding));
+                let break_enabled = setup.value & (1 << 14) != 0;
+                if break_enabled != self.break_enabled {
+                    self.break_enabled = break_enabled;
+                    self.event(CdcAcmEvent::Break(if break_enabled { 0xFFFF } else { 0 }));
+                }
+                Ok(vec![])
+            }
+            (0b01000000, SET_FLOW_CTRL | SET_EVENT_CHAR | SET_ERROR_CHAR | SET_BITMODE) => {
+                debug!("Ignoring FTDI request {setup:x?}");
+                Ok(vec![])
+            }
+            (0b11000000, GET_MODEM_STATUS) => Ok(self.modem_status().to_vec()),
+            (0b01000000, SET_LATENCY_TIMER) => {
+                self.latency_timer = setup.value as u8;
+                Ok(vec![])
+            }
+            (0b11000000, GET_LATENCY_TIMER) => Ok(vec![self.latency_timer]),
+            (0b11000000, READ_EEPROM) => Ok(self.eeprom_word(setup.index)?.to_le_bytes().to_vec()),
+            (0b01000000, WRITE_EEPROM) => {
+                *self.eeprom_word(setup.index)? = setup.value;
+                Ok(vec![])
+            }
+            (0b01000000, ERASE_EEPROM) => {
+                self.eeprom = [0xFFFF; EEPROM_WORDS];
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported FTDI request: {setup:x?}"),
+            )),
+        }
+    }
+}
+
+/// The default EEPROM of a FT232R, with the checksum of libftdi in the last word
+fn default_eeprom() -> [u16; EEPROM_WORDS] {
+    let mut eeprom = [0; EEPROM_WORDS];
+    eeprom[1] = 0x0403; // idVendor
+    eeprom[2] = 0x6001; // idProduct
+    eeprom[3] = 0x0600; // bcdDevice
+    eeprom[4] = 0x2D80; // bus powered, 90 mA
+    let mut checksum: u16 = 0xAAAA;
+    for word in &eeprom[..EEPROM_WORDS - 1] {
+        checksum = (checksum ^ word).rotate_left(1);
+    }
+    eeprom[EEPROM_WORDS - 1] = checksum;
+    eeprom
+}
+
+/// A handler of the serial line of a FT232R
+///
+/// The configuration of the line is reported as [CdcAcmEvent]s, like that of
+/// a [cdc::UsbCdcAcmHandler], so that the same code can serve both.
+#[derive(Clone, Debug)]
+pub struct UsbFtdiHandler {
+    state: Arc<Mutex<FtdiState>>,
+}
+
+impl Default for UsbFtdiHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbFtdiHandler {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(FtdiState {
+                line_coding: LineCoding {
+                    baud_rate: 9600,
+                    ..Default::default()
+                },
+                dtr: false,
+                rts: false,
+                break_enabled: false,
+                modem_status: 0,
+                latency_timer: 16,
+                eeprom: default_eeprom(),
+                to_host: vec![],
+                from_host: vec![],
+                events: None,
+            })),
+        }
+    }
+
+    /// The receiver of the requests of the host from now on
+    ///
+    /// A previous receiver gets no more events.
+    pub fn events(&self) -> tokio::sync::mpsc::UnboundedReceiver<CdcAcmEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.state.lock().unwrap().events = Some(sender);
+        receiver
+    }
+
+    /// Queue `data` for the host to read
+    pub fn write(&self, data: &[u8]) {
+        self.state.lock().unwrap().to_host.extend_from_slice(data);
+    }
+
+    /// Take the data the host has written so far
+    pub fn read(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.lock().unwrap().from_host)
+    }
+
+    /// The line coding set by the host
+    pub fn line_coding(&self) -> LineCoding {
+        self.state.lock().unwrap().line_coding
+    }
+
+    /// Set the modem lines reported to the host, a set of `MODEM_STATUS_*` bits
+    pub fn set_modem_status(&self, status: u8) {
+        self.state.lock().unwrap().modem_status = status & 0xF0;
+    }
+
+    /// The content of the EEPROM, as written by the host
+    pub fn eeprom(&self) -> [u16; EEPROM_WORDS] {
+        self.state.lock().unwrap().eeprom
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: MAX_PACKET_SIZE,
+                interval: 0,
+            },
+            UsbEndpoint {
+                address: 0x02,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: MAX_PACKET_SIZE,
+                interval: 0,
+            },
+        ]
+    }
+}
+
+impl UsbInterfaceHandler for UsbFtdiHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if let Direction::Out = ep.direction() {
+            state.from_host.extend_from_slice(req);
+            return Ok(vec![]);
+        }
+        // every packet starts with the status, even without data
+        let status = state.modem_status();
+        let payload_len = ep.max_packet_size as usize - status.len();
+        let mut resp = vec![];
+        while resp.len() + status.len() <= transfer_buffer_length as usize {
+            let len = payload_len
+                .min(state.to_host.len())
+                .min(transfer_buffer_length as usize - resp.len() - status.len());
+            resp.extend_from_slice(&status);
+            resp.extend(state.to_host.drain(..len));
+            if state.to_host.is_empty() {
+                break;
+            }
+        }
+        Ok(resp)
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The vendor requests to the device
+#[derive(Debug)]
+struct FtdiDeviceHandler {
+    state: Arc<Mutex<FtdiState>>,
+}
+
+impl UsbDeviceHandler for FtdiDeviceHandler {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.state.lock().unwrap().control(setup)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A FT232R USB UART with the serial line of `handler`
+    pub fn ftdi(handler: UsbFtdiHandler) -> Self {
+        let device_handler = FtdiDeviceHandler {
+            state: handler.state.clone(),
+        };
+        let mut device = Self::new(0)
+            .with_speed(UsbSpeed::Full)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0xFF,
+                0xFF,
+                Some("FT232R USB UART"),
+                UsbFtdiHandler::endpoints(),
+                shared_interface_handler(handler),
+            )
+            .with_device_handler(shared_device_handler(device_handler));
+        // the VID/PID of the FT232R, which the drivers bind to
+        device.vendor_id = 0x0403;
+        device.product_id = 0x6001;
+        device.device_bcd = 0x0600.into();
+        device.set_manufacturer_name("FTDI");
+        device.set_product_name("FT232R USB UART");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    async fn vendor(device: &UsbDevice, request: u8, value: u16, index: u16) -> Vec<u8> {
+        let setup = SetupPacket {
+            request_type: 0b01000000,
+            request,
+            value,
+            index,
+            length: 0,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, setup, &[])
+            .await
+            .unwrap()
+    }
+
+    async fn vendor_in(device: &UsbDevice, request: u8, index: u16, length: u16) -> Vec<u8> {
+        let setup = SetupPacket {
+            request_type: 0b11000000,
+            request,
+            value: 0,
+            index,
+            length,
+        };
+        device
+            .handle_urb(device.ep0_in, None, length as u32, setup, &[])
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn baud_rates() {
+        assert_eq!(decode_baud_rate(0x4138, 0), 9600);
+        assert_eq!(decode_baud_rate(0x001A, 0), 115384);
+        assert_eq!(decode_baud_rate(0x0000, 0), 3_000_000);
+        assert_eq!(decode_baud_rate(0x0001, 0), 2_000_000);
+        // divisor 2.25 is 1333333 baud
+        assert_eq!(decode_baud_rate(0x8002, 0), 1_333_333);
+        // divisor 3.875 with the fraction bit in the index
+        assert_eq!(decode_baud_rate(0xC003, 1), 774_193);
+    }
+
+    #[tokio::test]
+    async fn ftdi_requests() {
+        setup_test_logger();
+        let handler = UsbFtdiHandler::new();
+        let mut events = handler.events();
+        let device = UsbDevice::ftdi(handler.clone());
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        vendor(&device, SET_BAUD_RATE, 0x001A, 0).await;
+        // 7 data bits, even parity, 2 stop bits
+        vendor(&device, SET_DATA, 0x1207, 0).await;
+        vendor(&device, MODEM_CTRL, 0x0101, 0).await;
+        let line_coding = LineCoding {
+            baud_rate: 115384,
+            stop_bits: 2,
+            parity: 2,
+            data_bits: 7,
+        };
+        assert_eq!(handler.line_coding(), line_coding);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::LineCoding(LineCoding {
+                baud_rate: 115384,
+                ..
+            })
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::LineCoding(coding) if coding == line_coding
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CdcAcmEvent::ControlLineState {
+                dtr: true,
+                rts: false
+            }
+        ));
+
+        // break on and off
+        vendor(&device, SET_DATA, 0x4008, 0).await;
+        vendor(&device, SET_DATA, 0x0008, 0).await;
+        let breaks: Vec<u16> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                CdcAcmEvent::Break(duration) => Some(duration),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(breaks, [0xFFFF, 0]);
+
+        handler.set_modem_status(MODEM_STATUS_CTS | MODEM_STATUS_DSR);
+        assert_eq!(
+            vendor_in(&device, GET_MODEM_STATUS, 0, 2).await,
+            [0x31, 0x60]
+        );
+        vendor(&device, SET_LATENCY_TIMER, 2, 0).await;
+        assert_eq!(vendor_in(&device, GET_LATENCY_TIMER, 0, 1).await, [2]);
+
+        // EEPROM
+        assert_eq!(vendor_in(&device, READ_EEPROM, 1, 2).await, [0x03, 0x04]);
+        vendor(&device, WRITE_EEPROM, 0x1234, 10).await;
+        assert_eq!(handler.eeprom()[10], 0x1234);
+        let setup = SetupPacket {
+            request_type: 0b11000000,
+            request: READ_EEPROM,
+            value: 0,
+            index: EEPROM_WORDS as u16,
+            length: 2,
+        };
+        assert!(
+            device
+                .handle_urb(device.ep0_in, None, 2, setup, &[])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn ftdi_bulk() {
+        setup_test_logger();
+        let handler = UsbFtdiHandler::new();
+        let device = UsbDevice::ftdi(handler.clone());
+        let intf = &device.interfaces[0];
+        let [bulk_in, bulk_out] = UsbFtdiHandler::endpoints().try_into().unwrap();
+        let mut intf_handler = intf.handler.lock().await;
+
+        // only the status without data
+        let resp = intf_handler
+            .handle_urb(intf, bulk_in, 64, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp, [0x01, 0x60]);
+
+        // the status starts every packet
+        let data: Vec<u8> = (0..70).collect();
+        handler.write(&data);
+        let resp = intf_handler
+            .handle_urb(intf, bulk_in, 512, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp.len(), 74);
+        assert_eq!(resp[..2], [0x01, 0x60]);
+        assert_eq!(resp[2..64], data[..62]);
+        assert_eq!(resp[64..66], [0x01, 0x60]);
+        assert_eq!(resp[66..], data[62..]);
+
+        intf_handler
+            .handle_urb(intf, bulk_out, 5, SetupPacket::default(), b"hello")
+            .unwrap();
+        assert_eq!(handler.read(), b"hello");
+        assert!(handler.read().is_empty());
+    }
+}
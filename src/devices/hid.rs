@@ -106,14 +106,24 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                         Some(HidDescriptorType::Report) => {
                             return Ok(self.report_descriptor.clone());
                         }
-                        _ => unimplemented!("hid descriptor {:?}", setup),
+                        _ => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Unsupported HID descriptor: {setup:x?}"),
+                            ));
+                        }
                     }
                 }
                 (0b00100001, 0x0A) => {
                     // SET_IDLE
                     return Ok(vec![]);
                 }
-                _ => unimplemented!("hid request {:?}", setup),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unsupported HID request: {setup:x?}"),
+                    ));
+                }
             }
         } else {
             // interrupt transfer
@@ -122,7 +132,8 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                 match self.state {
                     UsbHidKeyboardHandlerState::Idle => {
                         if let Some(report) = self.pending_key_events.pop_front() {
-                            let mut resp = vec![report.modifier, 0];
+                            let mut resp = take_scratch_buffer(8);
+                            resp.extend_from_slice(&[report.modifier, 0]);
                             resp.extend_from_slice(&report.keys);
                             info!("HID key down");
                             self.state = UsbHidKeyboardHandlerState::KeyDown;
@@ -141,18 +152,415 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
         Ok(vec![])
     }
 
+    fn supported_requests(&self) -> Option<&[u8]> {
+        // SET_IDLE
+        Some(&[0x0A])
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        vec![
-            0x09,                         // bLength
-            HidDescriptorType::Hid as u8, // bDescriptorType: HID
-            0x11,
-            0x01,                            // bcdHID 1.11
-            0x00,                            // bCountryCode
-            0x01,                            // bNumDescriptors
-            HidDescriptorType::Report as u8, // bDescriptorType[0] HID
-            self.report_descriptor.len() as u8,
-            (self.report_descriptor.len() >> 8) as u8, // wDescriptorLength[0]
-        ]
+        hid_descriptor(&self.report_descriptor)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The HID descriptor of an interface with `report_descriptor`
+pub(crate) fn hid_descriptor(report_descriptor: &[u8]) -> Vec<u8> {
+    vec![
+        0x09,                         // bLength
+        HidDescriptorType::Hid as u8, // bDescriptorType: HID
+        0x11,
+        0x01,                            // bcdHID 1.11
+        0x00,                            // bCountryCode
+        0x01,                            // bNumDescriptors
+        HidDescriptorType::Report as u8, // bDescriptorType[0] HID
+        report_descriptor.len() as u8,
+        (report_descriptor.len() >> 8) as u8, // wDescriptorLength[0]
+    ]
+}
+
+/// Left button of [UsbHidMouseReport::buttons]
+pub const MOUSE_BUTTON_LEFT: u8 = 0x01;
+/// Right button of [UsbHidMouseReport::buttons]
+pub const MOUSE_BUTTON_RIGHT: u8 = 0x02;
+/// Middle button of [UsbHidMouseReport::buttons]
+pub const MOUSE_BUTTON_MIDDLE: u8 = 0x04;
+
+/// A report of a HID mouse, the movement is relative to the last report
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbHidMouseReport {
+    /// Pressed buttons, e.g. [MOUSE_BUTTON_LEFT]
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    /// Vertical wheel, positive is away from the user
+    pub wheel: i8,
+}
+
+impl UsbHidMouseReport {
+    pub fn to_bytes(&self) -> [u8; 4] {
+        [self.buttons, self.x as u8, self.y as u8, self.wheel as u8]
+    }
+}
+
+/// A handler of a HID mouse with three buttons and a wheel
+///
+/// The first three bytes of its reports follow the boot protocol, so it can
+/// be used in interfaces of the boot subclass.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbHidMouseHandler {
+    pub report_descriptor: Vec<u8>,
+    pub pending_reports: VecDeque<UsbHidMouseReport>,
+    /// Buttons of the last queued report
+    buttons: u8,
+}
+
+impl UsbHidMouseHandler {
+    pub fn new_mouse() -> Self {
+        Self {
+            pending_reports: VecDeque::new(),
+            buttons: 0,
+            report_descriptor: vec![
+                0x05, 0x01, // Usage Page (Generic Desktop)
+                0x09, 0x02, // Usage (Mouse)
+                0xA1, 0x01, // Collection (Application)
+                0x09, 0x01, // Usage (Pointer)
+                0xA1, 0x00, // Collection (Physical)
+                // Buttons
+                0x05, 0x09, // Usage Page (Buttons)
+                0x19, 0x01, // Usage Min (1)
+                0x29, 0x03, // Usage Max (3)
+                0x15, 0x00, // Logic Min
+                0x25, 0x01, // Logic Max
+                0x95, 0x03, // Report Count (3)
+                0x75, 0x01, // Report Size (1)
+                0x81, 0x02, // Input (Data, Variable, Absolute)
+                // Padding
+                0x95, 0x01, // Report Count (1)
+                0x75, 0x05, // Report Size (5)
+                0x81, 0x01, // Input (Constant)
+                // Movement and wheel
+                0x05, 0x01, // Usage Page (Generic Desktop)
+                0x09, 0x30, // Usage (X)
+                0x09, 0x31, // Usage (Y)
+                0x09, 0x38, // Usage (Wheel)
+                0x15, 0x81, // Logic Min (-127)
+                0x25, 0x7F, // Logic Max (127)
+                0x75, 0x08, // Report Size (8)
+                0x95, 0x03, // Report Count (3)
+                0x81, 0x06, // Input (Data, Variable, Relative)
+                0xC0, // End Collection
+                0xC0, // End Collection
+            ],
+        }
+    }
+
+    /// Queue `report`, later movement keeps its buttons
+    pub fn queue_report(&mut self, report: UsbHidMouseReport) {
+        self.buttons = report.buttons;
+        self.pending_reports.push_back(report);
+    }
+
+    /// Queue reports moving by `x` and `y`, split into steps of at most 127
+    pub fn move_by(&mut self, mut x: i32, mut y: i32) {
+        while x != 0 || y != 0 {
+            let step_x = x.clamp(-127, 127);
+            let step_y = y.clamp(-127, 127);
+            self.queue_report(UsbHidMouseReport {
+                buttons: self.buttons,
+                x: step_x as i8,
+                y: step_y as i8,
+                wheel: 0,
+            });
+            x -= step_x;
+            y -= step_y;
+        }
+    }
+
+    /// Queue a report scrolling the wheel by `wheel`, split into steps of at most 127
+    pub fn scroll(&mut self, mut wheel: i32) {
+        while wheel != 0 {
+            let step = wheel.clamp(-127, 127);
+            self.queue_report(UsbHidMouseReport {
+                buttons: self.buttons,
+                wheel: step as i8,
+                ..Default::default()
+            });
+            wheel -= step;
+        }
+    }
+
+    /// Queue a report pressing `buttons` in addition to the pressed ones
+    pub fn press(&mut self, buttons: u8) {
+        self.queue_report(UsbHidMouseReport {
+            buttons: self.buttons | buttons,
+            ..Default::default()
+        });
+    }
+
+    /// Queue a report releasing `buttons`
+    pub fn release(&mut self, buttons: u8) {
+        self.queue_report(UsbHidMouseReport {
+            buttons: self.buttons & !buttons,
+            ..Default::default()
+        });
+    }
+
+    /// Queue reports pressing and releasing `buttons`
+    pub fn click(&mut self, buttons: u8) {
+        self.press(buttons);
+        self.release(buttons);
+    }
+}
+
+impl UsbInterfaceHandler for UsbHidMouseHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            // control transfers
+            return match (setup.request_type, setup.request) {
+                (0b10000001, 0x06) => {
+                    // GET_DESCRIPTOR
+                    // high byte: type
+                    match FromPrimitive::from_u16(setup.value >> 8) {
+                        Some(HidDescriptorType::Report) => Ok(self.report_descriptor.clone()),
+                        _ => Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Unsupported HID descriptor: {setup:x?}"),
+                        )),
+                    }
+                }
+                (0b00100001, 0x0A | 0x0B) => {
+                    // SET_IDLE, SET_PROTOCOL
+                    Ok(vec![])
+                }
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Unsupported HID request: {setup:x?}"),
+                )),
+            };
+        }
+        // interrupt in
+        if let Direction::In = ep.direction()
+            && let Some(report) = self.pending_reports.pop_front()
+        {
+            debug!("HID mouse report {report:?}");
+            let mut resp = take_scratch_buffer(4);
+            resp.extend_from_slice(&report.to_bytes());
+            return Ok(resp);
+        }
+        Ok(vec![])
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        // SET_IDLE, SET_PROTOCOL
+        Some(&[0x0A, 0x0B])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        hid_descriptor(&self.report_descriptor)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Type of a report in GET_REPORT and SET_REPORT
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum HidReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+// class requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0A;
+const SET_PROTOCOL: u8 = 0x0B;
+
+/// Receives the output and feature reports of a [GenericHidHandler]
+pub type HidOutputCallback = Box<dyn FnMut(HidReportType, &[u8]) + Send>;
+
+/// A handler of any HID device, described by its report descriptor
+///
+/// Input reports sent to `in_queue` are returned on the interrupt IN
+/// endpoint, and output reports from the interrupt OUT endpoint or
+/// SET_REPORT, as well as feature reports, are passed to `out_callback`.
+/// Reports include the report ID, if the descriptor uses IDs.
+pub struct GenericHidHandler {
+    pub report_descriptor: Vec<u8>,
+    in_queue: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    out_callback: HidOutputCallback,
+    /// The last input report, for GET_REPORT
+    last_input: Vec<u8>,
+    /// Feature reports by report ID, for GET_REPORT
+    features: HashMap<u8, Vec<u8>>,
+    idle: u8,
+    protocol: u8,
+}
+
+impl std::fmt::Debug for GenericHidHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GenericHidHandler")
+            .field("report_descriptor", &self.report_descriptor)
+            .field("features", &self.features)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GenericHidHandler {
+    pub fn new(
+        report_descriptor: Vec<u8>,
+        in_queue: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+        out_callback: impl FnMut(HidReportType, &[u8]) + Send + 'static,
+    ) -> Self {
+        if let Err(err) = HidReportDescriptor::parse(&report_descriptor) {
+            warn!("{err}");
+        }
+        Self {
+            report_descriptor,
+            in_queue,
+            out_callback: Box::new(out_callback),
+            last_input: vec![],
+            features: HashMap::new(),
+            idle: 0,
+            // report protocol
+            protocol: 1,
+        }
+    }
+
+    /// A handler with a new input queue, and the sender of its input reports
+    pub fn channel(
+        report_descriptor: Vec<u8>,
+        out_callback: impl FnMut(HidReportType, &[u8]) + Send + 'static,
+    ) -> (Self, tokio::sync::mpsc::UnboundedSender<Vec<u8>>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        (Self::new(report_descriptor, receiver, out_callback), sender)
+    }
+
+    /// Set the feature report `id` returned by GET_REPORT, 0 without report IDs
+    pub fn set_feature_report(&mut self, id: u8, report: Vec<u8>) {
+        self.features.insert(id, report);
+    }
+
+    fn control(&mut self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>> {
+        let report_type = FromPrimitive::from_u16(setup.value >> 8);
+        let report_id = setup.value as u8;
+        match (setup.request_type, setup.request) {
+            (0b10000001, 0x06) => {
+                // GET_DESCRIPTOR
+                match FromPrimitive::from_u16(setup.value >> 8) {
+                    Some(HidDescriptorType::Report) => Ok(self.report_descriptor.clone()),
+                    Some(HidDescriptorType::Hid) => Ok(hid_descriptor(&self.report_descriptor)),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unsupported HID descriptor: {setup:x?}"),
+                    )),
+                }
+            }
+            (0b10100001, GET_REPORT) => match report_type {
+                Some(HidReportType::Input) if !self.last_input.is_empty() => {
+                    Ok(self.last_input.clone())
+                }
+                Some(HidReportType::Feature) if self.features.contains_key(&report_id) => {
+                    Ok(self.features[&report_id].clone())
+                }
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("No report for {setup:x?}"),
+                )),
+            },
+            (0b00100001, SET_REPORT) => {
+                match report_type {
+                    Some(kind @ HidReportType::Output) => (self.out_callback)(kind, req),
+                    Some(kind @ HidReportType::Feature) => {
+                        self.features.insert(report_id, req.to_vec());
+                        (self.out_callback)(kind, req);
+                    }
+                    _ => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Unsupported report type: {setup:x?}"),
+                        ));
+                    }
+                }
+                Ok(vec![])
+            }
+            (0b10100001, GET_IDLE) => Ok(vec![self.idle]),
+            (0b00100001, SET_IDLE) => {
+                // upper byte: duration in 4ms units
+                self.idle = (setup.value >> 8) as u8;
+                Ok(vec![])
+            }
+            (0b10100001, GET_PROTOCOL) => Ok(vec![self.protocol]),
+            (0b00100001, SET_PROTOCOL) => {
+                self.protocol = setup.value as u8;
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported HID request: {setup:x?}"),
+            )),
+        }
+    }
+}
+
+impl UsbInterfaceHandler for GenericHidHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return self.control(setup, req);
+        }
+        match ep.direction() {
+            Direction::In => match self.in_queue.try_recv() {
+                Ok(mut report) => {
+                    report.truncate(transfer_buffer_length as usize);
+                    self.last_input.clone_from(&report);
+                    Ok(report)
+                }
+                Err(_) => Ok(vec![]),
+            },
+            Direction::Out => {
+                (self.out_callback)(HidReportType::Output, req);
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[
+            GET_REPORT,
+            GET_IDLE,
+            GET_PROTOCOL,
+            SET_REPORT,
+            SET_IDLE,
+            SET_PROTOCOL,
+        ])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        hid_descriptor(&self.report_descriptor)
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -169,6 +577,347 @@ pub enum HidDescriptorType {
     Physical = 0x23,
 }
 
+/// An Input, Output or Feature item of a report descriptor
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HidReportField {
+    pub kind: HidReportType,
+    /// 0 if the descriptor has no report IDs
+    pub report_id: u8,
+    /// Position of the field in its report, after the report ID
+    pub bit_offset: u32,
+    pub report_size: u32,
+    pub report_count: u32,
+    /// Data of the main item, e.g. 0x01 constant, 0x02 variable, 0x04 relative
+    pub flags: u32,
+    /// Usages with the usage page in the upper 16 bits
+    pub usages: Vec<u32>,
+    pub logical_minimum: i64,
+    pub logical_maximum: i64,
+}
+
+impl HidReportField {
+    /// Padding, or data that does not change
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// One value per usage, instead of an array of usages
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+}
+
+/// The reports described by a HID report descriptor
+///
+/// [HidReportDescriptor::parse] rejects descriptors that a host would
+/// likely refuse, e.g. with unbalanced collections, reports without a size
+/// or report ID 0.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HidReportDescriptor {
+    pub fields: Vec<HidReportField>,
+    /// Usages of the top level collections, like [HidReportField::usages]
+    pub applications: Vec<u32>,
+}
+
+/// Global items of a report descriptor, saved by Push
+#[derive(Clone, Copy, Debug, Default)]
+struct HidGlobalState {
+    usage_page: u16,
+    /// Raw data and size, the sign depends on the minimum
+    logical_minimum: (u32, usize),
+    logical_maximum: (u32, usize),
+    report_size: Option<u32>,
+    report_count: Option<u32>,
+    report_id: u8,
+}
+
+/// Usages of the next main item, unresolved if they don't include a page
+#[derive(Clone, Debug, Default)]
+struct HidLocalState {
+    usages: Vec<(u32, bool)>,
+    usage_minimum: Option<(u32, bool)>,
+}
+
+// limits of the Linux HID parser, hid-core.c
+const HID_GLOBAL_STACK_SIZE: usize = 4;
+const HID_MAX_REPORT_SIZE: u32 = 256;
+const HID_MAX_USAGES: u32 = 12288;
+const HID_MAX_BUFFER_SIZE: u32 = 16384;
+
+fn sign_extend((data, size): (u32, usize)) -> i64 {
+    match size {
+        0 => 0,
+        1 => data as u8 as i8 as i64,
+        2 => data as u16 as i16 as i64,
+        _ => data as i32 as i64,
+    }
+}
+
+impl HidReportDescriptor {
+    /// Parse and validate `descriptor`
+    ///
+    /// The error describes the first problem found, with its offset.
+    pub fn parse(descriptor: &[u8]) -> Result<Self> {
+        let invalid = |offset: usize, message: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid HID report descriptor at offset {offset}: {message}"),
+            )
+        };
+        if descriptor.is_empty() {
+            return Err(invalid(0, "empty descriptor"));
+        }
+
+        let mut result = Self::default();
+        let mut global = HidGlobalState::default();
+        let mut stack: Vec<HidGlobalState> = vec![];
+        let mut local = HidLocalState::default();
+        let mut collections = 0usize;
+        let mut uses_report_ids = false;
+        // next bit of each report
+        let mut offsets: HashMap<(HidReportType, u8), u32> = HashMap::new();
+
+        let mut offset = 0;
+        while offset < descriptor.len() {
+            let prefix = descriptor[offset];
+            if prefix == 0xFE {
+                // long item, no tags are defined
+                let Some(&size) = descriptor.get(offset + 1) else {
+                    return Err(invalid(offset, "truncated long item"));
+                };
+                offset += 3 + size as usize;
+                if offset > descriptor.len() {
+                    return Err(invalid(offset, "truncated long item"));
+                }
+                continue;
+            }
+            let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+            let Some(bytes) = descriptor.get(offset + 1..offset + 1 + size) else {
+                return Err(invalid(offset, "truncated item"));
+            };
+            let data = bytes
+                .iter()
+                .rev()
+                .fold(0u32, |data, &byte| (data << 8) | byte as u32);
+            let tag = prefix >> 4;
+            // resolve usages with the usage page of the main item
+            let resolve = |usage: (u32, bool), page: u16| match usage {
+                (usage, true) => usage,
+                (usage, false) => ((page as u32) << 16) | (usage & 0xFFFF),
+            };
+
+            match (prefix >> 2) & 0x03 {
+                // main items
+                0 => {
+                    if local.usage_minimum.is_some() {
+                        return Err(invalid(offset, "Usage Minimum without Usage Maximum"));
+                    }
+                    let usages: Vec<u32> = local
+                        .usages
+                        .iter()
+                        .map(|&usage| resolve(usage, global.usage_page))
+                        .collect();
+                    match tag {
+                        // Input, Output, Feature
+                        0x08 | 0x09 | 0x0B => {
+                            let kind = match tag {
+                                0x08 => HidReportType::Input,
+                                0x09 => HidReportType::Output,
+                                _ => HidReportType::Feature,
+                            };
+                            if collections == 0 {
+                                return Err(invalid(offset, "report outside of a collection"));
+                            }
+                            let (Some(report_size), Some(report_count)) =
+                                (global.report_size, global.report_count)
+                            else {
+                                return Err(invalid(offset, "missing Report Size or Report Count"));
+                            };
+                            let logical_minimum = sign_extend(global.logical_minimum);
+                            let logical_maximum = if logical_minimum < 0 {
+                                sign_extend(global.logical_maximum)
+                            } else {
+                                global.logical_maximum.0 as i64
+                            };
+                            if logical_maximum < logical_minimum {
+                                return Err(invalid(
+                                    offset,
+                                    "Logical Maximum below Logical Minimum",
+                                ));
+                            }
+                            let report_id = global.report_id;
+                            if report_id == 0 && uses_report_ids {
+                                return Err(invalid(offset, "report without Report ID"));
+                            }
+                            let bit_offset = offsets.entry((kind, report_id)).or_insert(0);
+                            let field = HidReportField {
+                                kind,
+                                report_id,
+                                bit_offset: *bit_offset,
+                                report_size,
+                                report_count,
+                                flags: data,
+                                usages,
+                                logical_minimum,
+                                logical_maximum,
+                            };
+                            *bit_offset += report_size * report_count;
+                            if *bit_offset > HID_MAX_BUFFER_SIZE * 8 {
+                                return Err(invalid(offset, "report too long"));
+                            }
+                            result.fields.push(field);
+                        }
+                        // Collection
+                        0x0A => {
+                            if (0x07..0x80).contains(&data) {
+                                return Err(invalid(offset, "reserved collection type"));
+                            }
+                            if collections == 0 {
+                                result.applications.extend(usages.first());
+                            }
+                            collections += 1;
+                        }
+                        // End Collection
+                        0x0C => {
+                            if collections == 0 {
+                                return Err(invalid(offset, "End Collection without Collection"));
+                            }
+                            collections -= 1;
+                        }
+                        _ => return Err(invalid(offset, "reserved main item")),
+                    }
+                    local = HidLocalState::default();
+                }
+                // global items
+                1 => match tag {
+                    0x00 => global.usage_page = data as u16,
+                    0x01 => global.logical_minimum = (data, size),
+                    0x02 => global.logical_maximum = (data, size),
+                    // physical minimum and maximum, unit exponent, unit
+                    0x03..=0x06 => {}
+                    0x07 => {
+                        if data > HID_MAX_REPORT_SIZE {
+                            return Err(invalid(offset, "Report Size above 256"));
+                        }
+                        global.report_size = Some(data);
+                    }
+                    0x08 => {
+                        if data == 0 || data > 0xFF {
+                            return Err(invalid(offset, "Report ID not in 1..=255"));
+                        }
+                        if !uses_report_ids && !result.fields.is_empty() {
+                            return Err(invalid(offset, "report without Report ID"));
+                        }
+                        uses_report_ids = true;
+                        global.report_id = data as u8;
+                    }
+                    0x09 => {
+                        if data > HID_MAX_USAGES {
+                            return Err(invalid(offset, "Report Count above 12288"));
+                        }
+                        global.report_count = Some(data);
+                    }
+                    0x0A => {
+                        if stack.len() == HID_GLOBAL_STACK_SIZE {
+                            return Err(invalid(offset, "Push beyond 4 levels"));
+                        }
+                        stack.push(global);
+                    }
+                    0x0B => {
+                        let Some(saved) = stack.pop() else {
+                            return Err(invalid(offset, "Pop without Push"));
+                        };
+                        global = saved;
+                    }
+                    _ => return Err(invalid(offset, "reserved global item")),
+                },
+                // local items
+                2 => match tag {
+                    0x00 => local.usages.push((data, size == 4)),
+                    0x01 => local.usage_minimum = Some((data, size == 4)),
+                    0x02 => {
+                        let Some(minimum) = local.usage_minimum.take() else {
+                            return Err(invalid(offset, "Usage Maximum without Usage Minimum"));
+                        };
+                        let (minimum, maximum) = (minimum.0, data);
+                        if maximum < minimum {
+                            return Err(invalid(offset, "Usage Maximum below Usage Minimum"));
+                        }
+                        let extended = size == 4;
+                        local
+                            .usages
+                            .extend((minimum..=maximum).map(|usage| (usage, extended)));
+                    }
+                    // designators, strings, delimiter
+                    0x03..=0x05 | 0x07..=0x0A => {}
+                    _ => return Err(invalid(offset, "reserved local item")),
+                },
+                _ => return Err(invalid(offset, "reserved item type")),
+            }
+            offset += 1 + size;
+        }
+
+        if collections != 0 {
+            return Err(invalid(offset, "Collection without End Collection"));
+        }
+        if result.fields.is_empty() {
+            return Err(invalid(offset, "no reports"));
+        }
+        Ok(result)
+    }
+
+    pub fn uses_report_ids(&self) -> bool {
+        self.fields.iter().any(|field| field.report_id != 0)
+    }
+
+    /// IDs of the reports of `kind`, `[0]` without report IDs
+    pub fn report_ids(&self, kind: HidReportType) -> Vec<u8> {
+        let mut ids: Vec<u8> = self
+            .fields
+            .iter()
+            .filter(|field| field.kind == kind)
+            .map(|field| field.report_id)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Length in bytes of a report, including its report ID, if there is such a report
+    pub fn report_len(&self, kind: HidReportType, report_id: u8) -> Option<usize> {
+        let bits = self
+            .fields
+            .iter()
+            .filter(|field| field.kind == kind && field.report_id == report_id)
+            .map(|field| field.bit_offset + field.report_size * field.report_count)
+            .max()?;
+        Some(bits.div_ceil(8) as usize + usize::from(report_id != 0))
+    }
+}
+
+impl UsbGadgetFunction {
+    /// A HID function with the reports of `handler` on a 64 byte interrupt IN endpoint
+    pub fn hid(handler: GenericHidHandler) -> Self {
+        Self::new("hid", move |device| {
+            device.with_function(UsbFunction::single(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                None,
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 64,
+                    interval: 10,
+                }],
+                shared_interface_handler(handler),
+            ))
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::util::tests::*;
@@ -181,4 +930,241 @@ mod tests {
         let handler = UsbHidKeyboardHandler::new_keyboard();
         verify_descriptor(&handler.get_class_specific_descriptor());
     }
+
+    #[test]
+    fn mouse_reports() {
+        setup_test_logger();
+        let mut handler = UsbHidMouseHandler::new_mouse();
+        verify_descriptor(&handler.get_class_specific_descriptor());
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x01,
+            0x02,
+            None,
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 0x04,
+                interval: 10,
+            }],
+            shared_interface_handler(UsbHidMouseHandler::new_mouse()),
+        );
+        let interface = &device.interfaces[0];
+        let ep = interface.endpoints[0];
+
+        handler.press(MOUSE_BUTTON_LEFT);
+        handler.move_by(200, -5);
+        handler.release(MOUSE_BUTTON_LEFT);
+        handler.scroll(-1);
+        let mut reports = vec![];
+        loop {
+            let report = handler
+                .handle_urb(interface, ep, 4, SetupPacket::default(), &[])
+                .unwrap();
+            if report.is_empty() {
+                break;
+            }
+            reports.push(report);
+        }
+        assert_eq!(
+            reports,
+            [
+                [1, 0, 0, 0],
+                [1, 127, 0xFB, 0],
+                [1, 73, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0xFF],
+            ]
+        );
+    }
+
+    #[test]
+    fn generic_reports() {
+        setup_test_logger();
+        let outputs = Arc::new(Mutex::new(vec![]));
+        let received = outputs.clone();
+        let descriptor = UsbHidMouseHandler::new_mouse().report_descriptor;
+        let (mut handler, sender) =
+            GenericHidHandler::channel(descriptor.clone(), move |kind, report| {
+                received.lock().unwrap().push((kind, report.to_vec()));
+            });
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            None,
+            vec![],
+            shared_interface_handler(UsbHidMouseHandler::new_mouse()),
+        );
+        let interface = &device.interfaces[0];
+        let interrupt = |address| UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 0x08,
+            interval: 10,
+        };
+        let request = |request_type, request, value, length| SetupPacket {
+            request_type,
+            request,
+            value,
+            index: 0,
+            length,
+        };
+        let control = |handler: &mut GenericHidHandler, setup: SetupPacket, req: &[u8]| {
+            let ep = if setup.request_type & 0x80 != 0 {
+                device.ep0_in
+            } else {
+                device.ep0_out
+            };
+            handler.handle_urb(interface, ep, setup.length.into(), setup, req)
+        };
+
+        let report = control(&mut handler, request(0b10000001, 0x06, 0x2200, 0xFF), &[]);
+        assert_eq!(report.unwrap(), descriptor);
+
+        sender.send(vec![1, 2, 3, 4]).unwrap();
+        let read = |handler: &mut GenericHidHandler| {
+            handler
+                .handle_urb(interface, interrupt(0x81), 8, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert_eq!(read(&mut handler), [1, 2, 3, 4]);
+        assert!(read(&mut handler).is_empty());
+        let input = control(
+            &mut handler,
+            request(0b10100001, GET_REPORT, 0x0100, 4),
+            &[],
+        );
+        assert_eq!(input.unwrap(), [1, 2, 3, 4]);
+
+        handler
+            .handle_urb(
+                interface,
+                interrupt(0x01),
+                1,
+                SetupPacket::default(),
+                &[0x02],
+            )
+            .unwrap();
+        control(
+            &mut handler,
+            request(0b00100001, SET_REPORT, 0x0305, 2),
+            &[5, 9],
+        )
+        .unwrap();
+        let feature = control(
+            &mut handler,
+            request(0b10100001, GET_REPORT, 0x0305, 2),
+            &[],
+        );
+        assert_eq!(feature.unwrap(), [5, 9]);
+        assert!(
+            control(
+                &mut handler,
+                request(0b10100001, GET_REPORT, 0x0301, 2),
+                &[]
+            )
+            .is_err()
+        );
+        assert_eq!(
+            *outputs.lock().unwrap(),
+            [
+                (HidReportType::Output, vec![0x02]),
+                (HidReportType::Feature, vec![5, 9])
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_report_descriptors() {
+        setup_test_logger();
+        let keyboard =
+            HidReportDescriptor::parse(&UsbHidKeyboardHandler::new_keyboard().report_descriptor)
+                .unwrap();
+        assert_eq!(keyboard.applications, [0x0001_0006]);
+        assert_eq!(keyboard.report_len(HidReportType::Input, 0), Some(8));
+        assert_eq!(keyboard.report_len(HidReportType::Output, 0), None);
+        assert_eq!(keyboard.fields[0].usages[0], 0x0007_00E0);
+        assert_eq!(keyboard.fields[2].usages.len(), 0x66);
+
+        let mouse =
+            HidReportDescriptor::parse(&UsbHidMouseHandler::new_mouse().report_descriptor).unwrap();
+        assert!(!mouse.uses_report_ids());
+        assert_eq!(mouse.report_ids(HidReportType::Input), [0]);
+        assert_eq!(mouse.report_len(HidReportType::Input, 0), Some(4));
+        let movement = &mouse.fields[2];
+        assert!(movement.is_variable() && !movement.is_constant());
+        assert_eq!(movement.usages, [0x0001_0030, 0x0001_0031, 0x0001_0038]);
+        assert_eq!((movement.bit_offset, movement.logical_minimum), (8, -127));
+        assert!(mouse.fields[1].is_constant());
+
+        // vendor defined, two reports
+        let reports = [
+            0x06, 0x00, 0xFF, // Usage Page (Vendor)
+            0x09, 0x01, // Usage (1)
+            0xA1, 0x01, // Collection (Application)
+            0x15, 0x00, // Logic Min
+            0x26, 0xFF, 0x00, // Logic Max (255)
+            0x75, 0x08, // Report Size (8)
+            0x85, 0x01, // Report ID (1)
+            0x95, 0x3F, // Report Count (63)
+            0x09, 0x02, // Usage (2)
+            0x81, 0x02, // Input
+            0x85, 0x02, // Report ID (2)
+            0x95, 0x04, // Report Count (4)
+            0x09, 0x03, // Usage (3)
+            0xB1, 0x02, // Feature
+            0xC0, // End Collection
+        ];
+        let vendor = HidReportDescriptor::parse(&reports).unwrap();
+        assert!(vendor.uses_report_ids());
+        assert_eq!(vendor.report_len(HidReportType::Input, 1), Some(64));
+        assert_eq!(vendor.report_len(HidReportType::Feature, 2), Some(5));
+        assert_eq!(vendor.fields[0].logical_maximum, 255);
+        assert_eq!(vendor.fields[1].usages, [0xFF00_0003]);
+
+        let malformed: [&[u8]; 8] = [
+            &[],
+            &reports[..reports.len() - 1],
+            &reports[..reports.len() - 2],
+            &[0xA1, 0x01, 0xC0, 0xC0],
+            &[0xA1, 0x01, 0x95, 0x01, 0x81, 0x02, 0xC0],
+            &[
+                0x85, 0x00, 0xA1, 0x01, 0x75, 0x08, 0x95, 0x01, 0x81, 0x02, 0xC0,
+            ],
+            &[0xB4, 0xA1, 0x01, 0x75, 0x08, 0x95, 0x01, 0x81, 0x02, 0xC0],
+            &[
+                0xA1, 0x01, 0x75, 0x08, 0x95, 0x01, 0x15, 0x05, 0x25, 0x01, 0x81, 0x02, 0xC0,
+            ],
+        ];
+        for descriptor in malformed {
+            let err = HidReportDescriptor::parse(descriptor).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_class_request() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            None,
+            vec![],
+            shared_interface_handler(UsbHidKeyboardHandler::new_keyboard()),
+        );
+        let request = |request| SetupPacket {
+            request_type: 0b10100001,
+            request,
+            value: 0,
+            index: 0,
+            length: 8,
+        };
+        // GET_REPORT is stalled instead of reaching the handler
+        let res = device
+            .handle_urb(device.ep0_in, None, 8, request(0x01), &[])
+            .await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+    }
 }
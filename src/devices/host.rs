@@ -1,21 +1,192 @@
 //! Host USB
-use rusb::{DeviceHandle, GlobalContext};
+use rusb::{DeviceHandle, GlobalContext, UsbContext, constants::*, ffi};
+use std::future::Future;
+use std::pin::Pin;
 
 use super::super::*;
 
 /// A handler to pass requests to interface of a rusb USB device of the host
-#[derive(Clone, Debug)]
-pub struct RusbUsbHostInterfaceHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+///
+/// Generic over the libusb context of the device, [GlobalContext] unless
+/// opened in a [rusb::Context] of your own.
+#[derive(Clone)]
+pub struct RusbUsbHostInterfaceHandler<T: UsbContext = GlobalContext> {
+    handle: Arc<Mutex<DeviceHandle<T>>>,
 }
 
-impl RusbUsbHostInterfaceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
+impl<T: UsbContext> std::fmt::Debug for RusbUsbHostInterfaceHandler<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RusbUsbHostInterfaceHandler")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl<T: UsbContext> RusbUsbHostInterfaceHandler<T> {
+    pub fn new(handle: Arc<Mutex<DeviceHandle<T>>>) -> Self {
         Self { handle }
     }
+
+    /// Pass an isochronous URB to the device and complete it with the packets libusb returns
+    ///
+    /// libusb lays out the packets back to back, while the packets of USB/IP
+    /// have offsets into the transfer buffer, so OUT data is gathered first.
+    /// Blocks until the transfer completes, see [Self::submit_urb_async].
+    fn submit_iso(&mut self, urb: &mut Urb) -> Result<()> {
+        let mut packets = urb.iso_packets();
+        let mut buffer = gather_iso_packets(urb.direction(), &packets, &urb.buffer);
+        debug!(
+            "To host device: ep={:?} {} iso packets",
+            urb.endpoint,
+            packets.len()
+        );
+        let results = rusb_iso_transfer(&self.handle, urb.endpoint.address, &packets, &mut buffer)?;
+        let data = complete_iso_packets(urb.direction(), &mut packets, &results, &buffer);
+        urb.complete_iso(&packets, data);
+        Ok(())
+    }
+}
+
+/// The packets back to back, with the OUT data at their offsets in `transfer_buffer`
+fn gather_iso_packets(
+    direction: Direction,
+    packets: &[IsoPacket],
+    transfer_buffer: &[u8],
+) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(packets.iter().map(|p| p.length as usize).sum());
+    for packet in packets {
+        let start = buffer.len();
+        buffer.resize(start + packet.length as usize, 0);
+        if let Direction::Out = direction {
+            let offset = (packet.offset as usize).min(transfer_buffer.len());
+            let end = (offset + packet.length as usize).min(transfer_buffer.len());
+            buffer[start..start + end - offset].copy_from_slice(&transfer_buffer[offset..end]);
+        }
+    }
+    buffer
+}
+
+/// Fill in the actual length and status of `packets` from libusb
+///
+/// Returns the IN data of the packets in `buffer`, without the gaps of
+/// short packets.
+fn complete_iso_packets(
+    direction: Direction,
+    packets: &mut [IsoPacket],
+    results: &[(u32, i32)],
+    buffer: &[u8],
+) -> Vec<u8> {
+    let mut data = vec![];
+    let mut start = 0;
+    for (packet, &(actual_length, status)) in packets.iter_mut().zip(results) {
+        packet.actual_length = actual_length.min(packet.length);
+        packet.status = status;
+        if let Direction::In = direction {
+            data.extend_from_slice(&buffer[start..start + packet.actual_length as usize]);
+        }
+        start += packet.length as usize;
+    }
+    data
+}
+
+/// Negated Linux error number for the status of a libusb transfer or packet
+fn libusb_status_to_errno(status: i32) -> i32 {
+    use crate::usbip_protocol::*;
+    match status {
+        LIBUSB_TRANSFER_COMPLETED => 0,
+        LIBUSB_TRANSFER_TIMED_OUT => -ETIMEDOUT,
+        LIBUSB_TRANSFER_CANCELLED => -ECONNRESET,
+        LIBUSB_TRANSFER_STALL => -EPIPE,
+        LIBUSB_TRANSFER_NO_DEVICE => -ENODEV,
+        LIBUSB_TRANSFER_OVERFLOW => -EOVERFLOW,
+        _ => -EPROTO,
+    }
+}
+
+extern "system" fn rusb_iso_callback(transfer: *mut ffi::libusb_transfer) {
+    // SAFETY: user_data points to the completion flag of rusb_iso_transfer,
+    // which waits for this callback before returning
+    unsafe { *((*transfer).user_data as *mut i32) = 1 };
+}
+
+/// Run an isochronous transfer with `packets` on `endpoint` to completion
+///
+/// `buffer` holds the packets back to back. Returns the actual length and
+/// the errno status of each packet. The handle is locked only to submit the
+/// transfer, not while waiting for it, so other transfers to the device go on.
+fn rusb_iso_transfer(
+    handle: &Mutex<DeviceHandle<impl UsbContext>>,
+    endpoint: u8,
+    packets: &[IsoPacket],
+    buffer: &mut [u8],
+) -> Result<Vec<(u32, i32)>> {
+    let (raw_handle, context) = {
+        let handle = handle.lock().unwrap();
+        (handle.as_raw(), handle.context().as_raw())
+    };
+    let mut completed = 0i32;
+    // SAFETY: the transfer, the buffer and the completion flag outlive the
+    // transfer, as it is freed only after the callback ran. The borrowed
+    // handle keeps the device open until then.
+    unsafe {
+        let transfer = ffi::libusb_alloc_transfer(packets.len() as i32);
+        if transfer.is_null() {
+            return Err(std::io::Error::other("Failed to allocate iso transfer"));
+        }
+        ffi::libusb_fill_iso_transfer(
+            transfer,
+            raw_handle,
+            endpoint,
+            buffer.as_mut_ptr(),
+            buffer.len() as i32,
+            packets.len() as i32,
+            rusb_iso_callback,
+            &mut completed as *mut i32 as *mut _,
+            1000,
+        );
+        let descriptors = (*transfer).iso_packet_desc.as_mut_ptr();
+        for (i, packet) in packets.iter().enumerate() {
+            (*descriptors.add(i)).length = packet.length;
+        }
+        let res = ffi::libusb_submit_transfer(transfer);
+        if res < 0 {
+            ffi::libusb_free_transfer(transfer);
+            return Err(std::io::Error::other(format!(
+                "Failed to submit iso transfer: {res}"
+            )));
+        }
+        while completed == 0 {
+            let res = ffi::libusb_handle_events_completed(context, &mut completed);
+            if res < 0 && res != LIBUSB_ERROR_INTERRUPTED {
+                warn!("Failed to handle libusb events: {res}");
+                ffi::libusb_cancel_transfer(transfer);
+            }
+        }
+        let status = (*transfer).status;
+        let results = (0..packets.len())
+            .map(|i| {
+                let descriptor = &*descriptors.add(i);
+                (
+                    descriptor.actual_length,
+                    libusb_status_to_errno(descriptor.status),
+                )
+            })
+            .collect();
+        ffi::libusb_free_transfer(transfer);
+        match status {
+            LIBUSB_TRANSFER_COMPLETED => Ok(results),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Device is gone",
+            )),
+            _ => Err(std::io::Error::other(format!(
+                "Iso transfer failed with status {status}"
+            ))),
+        }
+    }
 }
 
-impl UsbInterfaceHandler for RusbUsbHostInterfaceHandler {
+impl<T: UsbContext + 'static> UsbInterfaceHandler for RusbUsbHostInterfaceHandler<T> {
     fn handle_urb(
         &mut self,
         _interface: &UsbInterface,
@@ -82,28 +253,112 @@ impl UsbInterfaceHandler for RusbUsbHostInterfaceHandler {
         Ok(vec![])
     }
 
+    fn submit_urb(&mut self, interface: &UsbInterface, urb: &mut Urb) -> Result<()> {
+        if urb.endpoint.attributes & 0x03 == EndpointAttributes::Isochronous as u8 {
+            return self.submit_iso(urb);
+        }
+        let data = self.handle_urb(
+            interface,
+            urb.endpoint,
+            urb.transfer_buffer_length,
+            urb.setup,
+            &urb.buffer,
+        )?;
+        urb.complete(data);
+        Ok(())
+    }
+
+    fn submit_urb_async<'a>(
+        &'a mut self,
+        interface: &'a UsbInterface,
+        urb: &'a mut Urb,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            if urb.endpoint.attributes & 0x03 != EndpointAttributes::Isochronous as u8 {
+                return self.submit_urb(interface, urb);
+            }
+            // libusb events are handled until the packets complete, off the runtime
+            let mut handler = self.clone();
+            let mut owned = std::mem::take(urb);
+            let (res, owned) = tokio::task::spawn_blocking(move || {
+                let res = handler.submit_iso(&mut owned);
+                (res, owned)
+            })
+            .await
+            .map_err(std::io::Error::other)?;
+            *urb = owned;
+            res
+        })
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         vec![]
     }
 
+    fn set_endpoint_halt(&mut self, _interface: &UsbInterface, ep: UsbEndpoint, halted: bool) {
+        if halted {
+            return;
+        }
+        if let Err(err) = self.handle.lock().unwrap().clear_halt(ep.address) {
+            warn!("Failed to clear halt of endpoint {:02x}: {err}", ep.address);
+        }
+    }
+
+    fn release(&mut self, interface: &UsbInterface) {
+        let interface_number = interface.interface_number();
+        match self
+            .handle
+            .lock()
+            .unwrap()
+            .release_interface(interface_number)
+        {
+            Ok(()) | Err(rusb::Error::NotFound) => {}
+            Err(err) => warn!("Failed to release interface {interface_number}: {err}"),
+        }
+    }
+
+    fn set_alternate_setting(&mut self, interface: &UsbInterface, alternate_setting: u8) {
+        let interface_number = interface.interface_number();
+        let handle = self.handle.lock().unwrap();
+        // libusb only switches the settings of claimed interfaces
+        let res = handle
+            .claim_interface(interface_number)
+            .and_then(|()| handle.set_alternate_setting(interface_number, alternate_setting));
+        if let Err(err) = res {
+            warn!(
+                "Failed to set alternate setting of interface {interface_number} to {alternate_setting}: {err}"
+            );
+        }
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
 
 /// A handler to pass requests to device of a rusb USB device of the host
-#[derive(Clone, Debug)]
-pub struct RusbUsbHostDeviceHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+///
+/// Generic over the libusb context like [RusbUsbHostInterfaceHandler].
+#[derive(Clone)]
+pub struct RusbUsbHostDeviceHandler<T: UsbContext = GlobalContext> {
+    handle: Arc<Mutex<DeviceHandle<T>>>,
+}
+
+impl<T: UsbContext> std::fmt::Debug for RusbUsbHostDeviceHandler<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RusbUsbHostDeviceHandler")
+            .field("handle", &self.handle)
+            .finish()
+    }
 }
 
-impl RusbUsbHostDeviceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
+impl<T: UsbContext> RusbUsbHostDeviceHandler<T> {
+    pub fn new(handle: Arc<Mutex<DeviceHandle<T>>>) -> Self {
         Self { handle }
     }
 }
 
-impl UsbDeviceHandler for RusbUsbHostDeviceHandler {
+impl<T: UsbContext + 'static> UsbDeviceHandler for RusbUsbHostDeviceHandler<T> {
     fn handle_urb(
         &mut self,
         transfer_buffer_length: u32,
@@ -143,15 +398,76 @@ impl UsbDeviceHandler for RusbUsbHostDeviceHandler {
         Ok(vec![])
     }
 
+    fn get_status(&mut self, status: u16) -> u16 {
+        // the real device knows whether it is self powered right now
+        let mut buffer = [0u8; 2];
+        let timeout = std::time::Duration::new(1, 0);
+        let handle = self.handle.lock().unwrap();
+        match handle.read_control(0b10000000, 0x00, 0, 0, &mut buffer, timeout) {
+            Ok(2) => u16::from_le_bytes(buffer),
+            _ => status,
+        }
+    }
+
+    fn set_configuration(&mut self, configuration: u8) -> Result<()> {
+        let handle = self.handle.lock().unwrap();
+        if !switches_configuration(handle.active_configuration().ok(), configuration) {
+            return Ok(());
+        }
+        // the interface handlers released their interfaces before
+        handle
+            .set_active_configuration(configuration)
+            .map_err(std::io::Error::other)
+    }
+
+    fn configuration_interfaces(
+        &mut self,
+        _configuration: u8,
+    ) -> Result<Option<Vec<UsbInterface>>> {
+        let cfg = self
+            .handle
+            .lock()
+            .unwrap()
+            .device()
+            .active_config_descriptor()
+            .map_err(std::io::Error::other)?;
+        Ok(Some(crate::usbip_server::rusb_impl::rusb_interfaces(
+            &self.handle,
+            &cfg,
+            &Arc::default(),
+        )))
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.handle
+            .lock()
+            .unwrap()
+            .reset()
+            .map_err(std::io::Error::other)
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
 
+/// Whether SET_CONFIGURATION to `configuration` has to reach a host device
+/// with the configuration `active`
+///
+/// Selecting the active configuration again would reset the device.
+fn switches_configuration(active: Option<u8>, configuration: u8) -> bool {
+    active != Some(configuration)
+}
+
 /// A handler to pass requests to interface of a nusb USB device of the host
+///
+/// Bulk and interrupt transfers are awaited with the asynchronous API of
+/// nusb, so a slow device does not hold up the runtime. nusb has no API for
+/// isochronous transfers yet, so they fail; use the rusb backend for those.
 #[derive(Clone)]
 pub struct NusbUsbHostInterfaceHandler {
-    handle: Arc<Mutex<nusb::Interface>>,
+    /// `None` once released for SET_CONFIGURATION
+    handle: Option<nusb::Interface>,
 }
 
 impl std::fmt::Debug for NusbUsbHostInterfaceHandler {
@@ -163,11 +479,105 @@ impl std::fmt::Debug for NusbUsbHostInterfaceHandler {
 }
 
 impl NusbUsbHostInterfaceHandler {
-    pub fn new(handle: Arc<Mutex<nusb::Interface>>) -> Self {
-        Self { handle }
+    pub fn new(handle: nusb::Interface) -> Self {
+        Self {
+            handle: Some(handle),
+        }
+    }
+
+    fn handle(&self) -> Result<&nusb::Interface> {
+        self.handle.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Interface released for SET_CONFIGURATION",
+            )
+        })
+    }
+
+    /// Await a bulk or interrupt transfer on `ep`
+    async fn transfer(
+        &self,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let interrupt = ep.attributes == EndpointAttributes::Interrupt as u8;
+        let handle = self.handle()?;
+        match ep.direction() {
+            Direction::In => {
+                let buffer = nusb::transfer::RequestBuffer::new(transfer_buffer_length as usize);
+                let completion = if interrupt {
+                    handle.interrupt_in(ep.address, buffer).await
+                } else {
+                    handle.bulk_in(ep.address, buffer).await
+                };
+                Ok(completion.into_result()?)
+            }
+            Direction::Out => {
+                let completion = if interrupt {
+                    handle.interrupt_out(ep.address, req.to_vec()).await
+                } else {
+                    handle.bulk_out(ep.address, req.to_vec()).await
+                };
+                completion.into_result()?;
+                Ok(vec![])
+            }
+        }
     }
 }
 
+/// Run `future` to completion on the current thread
+///
+/// Only for the synchronous [UsbInterfaceHandler::handle_urb], the server
+/// awaits transfers with [UsbInterfaceHandler::submit_urb_async].
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(output) => return output,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// The control transfer of `setup`, which fails for reserved types and recipients
+fn nusb_control(setup: SetupPacket) -> Result<nusb::transfer::Control> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid bmRequestType: {:#04x}", setup.request_type),
+        )
+    };
+    Ok(nusb::transfer::Control {
+        control_type: match (setup.request_type >> 5) & 0b11 {
+            0 => nusb::transfer::ControlType::Standard,
+            1 => nusb::transfer::ControlType::Class,
+            2 => nusb::transfer::ControlType::Vendor,
+            _ => return Err(invalid()),
+        },
+        recipient: match setup.request_type & 0b11111 {
+            0 => nusb::transfer::Recipient::Device,
+            1 => nusb::transfer::Recipient::Interface,
+            2 => nusb::transfer::Recipient::Endpoint,
+            3 => nusb::transfer::Recipient::Other,
+            _ => return Err(invalid()),
+        },
+        request: setup.request,
+        value: setup.value,
+        index: setup.index,
+    })
+}
+
 impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
     fn handle_urb(
         &mut self,
@@ -180,26 +590,9 @@ impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
         debug!("To host device: ep={ep:?} setup={setup:?} req={req:?}",);
         let mut buffer = vec![0u8; transfer_buffer_length as usize];
         let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.lock().unwrap();
-        let control = nusb::transfer::Control {
-            control_type: match (setup.request_type >> 5) & 0b11 {
-                0 => nusb::transfer::ControlType::Standard,
-                1 => nusb::transfer::ControlType::Class,
-                2 => nusb::transfer::ControlType::Vendor,
-                _ => unimplemented!(),
-            },
-            recipient: match setup.request_type & 0b11111 {
-                0 => nusb::transfer::Recipient::Device,
-                1 => nusb::transfer::Recipient::Interface,
-                2 => nusb::transfer::Recipient::Endpoint,
-                3 => nusb::transfer::Recipient::Other,
-                _ => unimplemented!(),
-            },
-            request: setup.request,
-            value: setup.value,
-            index: setup.index,
-        };
         if ep.attributes == EndpointAttributes::Control as u8 {
+            let handle = self.handle()?;
+            let control = nusb_control(setup)?;
             // control
             if let Direction::In = ep.direction() {
                 // control in
@@ -210,16 +603,129 @@ impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
                 // control out
                 handle.control_out_blocking(control, req, timeout).ok();
             }
-        } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
-            // interrupt
-            todo!("Missing blocking api for interrupt transfer in nusb")
-        } else if ep.attributes == EndpointAttributes::Bulk as u8 {
-            // bulk
-            todo!("Missing blocking api for bulk transfer in nusb")
+        } else if ep.attributes == EndpointAttributes::Interrupt as u8
+            || ep.attributes == EndpointAttributes::Bulk as u8
+        {
+            return block_on(self.transfer(ep, transfer_buffer_length, req));
         }
         Ok(vec![])
     }
 
+    fn submit_urb(&mut self, interface: &UsbInterface, urb: &mut Urb) -> Result<()> {
+        if urb.endpoint.attributes & 0x03 == EndpointAttributes::Isochronous as u8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "Isochronous endpoint {:02x} is not supported by nusb",
+                    urb.endpoint.address
+                ),
+            ));
+        }
+        let data = self.handle_urb(
+            interface,
+            urb.endpoint,
+            urb.transfer_buffer_length,
+            urb.setup,
+            &urb.buffer,
+        )?;
+        urb.complete(data);
+        Ok(())
+    }
+
+    fn submit_urb_async<'a>(
+        &'a mut self,
+        interface: &'a UsbInterface,
+        urb: &'a mut Urb,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let ep = urb.endpoint;
+            if ep.attributes != EndpointAttributes::Interrupt as u8
+                && ep.attributes != EndpointAttributes::Bulk as u8
+            {
+                return self.submit_urb(interface, urb);
+            }
+            debug!(
+                "To host device: ep={ep:?} len={}",
+                urb.transfer_buffer_length
+            );
+            let data = self
+                .transfer(ep, urb.transfer_buffer_length, &urb.buffer)
+                .await?;
+            urb.complete(data);
+            Ok(())
+        })
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn set_endpoint_halt(&mut self, _interface: &UsbInterface, ep: UsbEndpoint, halted: bool) {
+        if halted {
+            return;
+        }
+        if let Err(err) = self
+            .handle()
+            .and_then(|handle| handle.clear_halt(ep.address))
+        {
+            warn!("Failed to clear halt of endpoint {:02x}: {err}", ep.address);
+        }
+    }
+
+    fn release(&mut self, _interface: &UsbInterface) {
+        // nusb releases the interface when its last handle is dropped
+        self.handle = None;
+    }
+
+    fn set_alternate_setting(&mut self, interface: &UsbInterface, alternate_setting: u8) {
+        if let Err(err) = self
+            .handle()
+            .and_then(|handle| handle.set_alt_setting(alternate_setting))
+        {
+            warn!(
+                "Failed to set alternate setting of interface {} to {alternate_setting}: {err}",
+                interface.interface_number()
+            );
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler for a host interface that cannot be passed through
+///
+/// Used for interfaces that could not be claimed, e.g. because a kernel driver
+/// holds them. Every transfer fails.
+#[derive(Clone, Debug)]
+pub struct UnavailableInterfaceHandler {
+    reason: String,
+}
+
+impl UnavailableInterfaceHandler {
+    pub fn new(reason: &str) -> Self {
+        Self {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UnavailableInterfaceHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::other(format!(
+            "Endpoint {:02x} is unavailable: {}",
+            ep.address, self.reason
+        )))
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         vec![]
     }
@@ -232,7 +738,7 @@ impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
 /// A handler to pass requests to device of a nusb USB device of the host
 #[derive(Clone)]
 pub struct NusbUsbHostDeviceHandler {
-    handle: Arc<Mutex<nusb::Device>>,
+    handle: nusb::Device,
 }
 
 impl std::fmt::Debug for NusbUsbHostDeviceHandler {
@@ -244,7 +750,7 @@ impl std::fmt::Debug for NusbUsbHostDeviceHandler {
 }
 
 impl NusbUsbHostDeviceHandler {
-    pub fn new(handle: Arc<Mutex<nusb::Device>>) -> Self {
+    pub fn new(handle: nusb::Device) -> Self {
         Self { handle }
     }
 }
@@ -259,25 +765,8 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
         debug!("To host device: setup={setup:?} req={req:?}");
         let mut buffer = vec![0u8; transfer_buffer_length as usize];
         let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.lock().unwrap();
-        let control = nusb::transfer::Control {
-            control_type: match (setup.request_type >> 5) & 0b11 {
-                0 => nusb::transfer::ControlType::Standard,
-                1 => nusb::transfer::ControlType::Class,
-                2 => nusb::transfer::ControlType::Vendor,
-                _ => unimplemented!(),
-            },
-            recipient: match setup.request_type & 0b11111 {
-                0 => nusb::transfer::Recipient::Device,
-                1 => nusb::transfer::Recipient::Interface,
-                2 => nusb::transfer::Recipient::Endpoint,
-                3 => nusb::transfer::Recipient::Other,
-                _ => unimplemented!(),
-            },
-            request: setup.request,
-            value: setup.value,
-            index: setup.index,
-        };
+        let handle = &self.handle;
+        let control = nusb_control(setup)?;
         // control
         if cfg!(not(target_os = "windows")) {
             if setup.request_type & 0x80 == 0 {
@@ -297,7 +786,189 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
         Ok(vec![])
     }
 
+    #[cfg(not(target_os = "windows"))]
+    fn get_status(&mut self, status: u16) -> u16 {
+        // the real device knows whether it is self powered right now
+        let mut buffer = [0u8; 2];
+        let timeout = std::time::Duration::new(1, 0);
+        let handle = &self.handle;
+        let control = nusb::transfer::Control {
+            control_type: nusb::transfer::ControlType::Standard,
+            recipient: nusb::transfer::Recipient::Device,
+            request: 0x00,
+            value: 0,
+            index: 0,
+        };
+        match handle.control_in_blocking(control, &mut buffer, timeout) {
+            Ok(2) => u16::from_le_bytes(buffer),
+            _ => status,
+        }
+    }
+
+    fn set_configuration(&mut self, configuration: u8) -> Result<()> {
+        let active = self
+            .handle
+            .active_configuration()
+            .ok()
+            .map(|cfg| cfg.configuration_value());
+        if !switches_configuration(active, configuration) {
+            return Ok(());
+        }
+        // the interface handlers released their interfaces before
+        self.handle.set_configuration(configuration)
+    }
+
+    fn configuration_interfaces(
+        &mut self,
+        _configuration: u8,
+    ) -> Result<Option<Vec<UsbInterface>>> {
+        let cfg = self
+            .handle
+            .active_configuration()
+            .map_err(std::io::Error::other)?;
+        Ok(Some(crate::usbip_server::nusb_impl::nusb_interfaces(
+            &self.handle,
+            &cfg,
+            &Arc::default(),
+        )))
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.handle.reset()
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn switch_only_to_another_configuration() {
+        setup_test_logger();
+        assert!(!switches_configuration(Some(1), 1));
+        assert!(switches_configuration(Some(1), 2));
+        assert!(switches_configuration(Some(1), 0));
+        // unknown, e.g. the device is unconfigured
+        assert!(switches_configuration(None, 1));
+    }
+
+    #[tokio::test]
+    async fn unavailable_interface_fails_transfers() {
+        setup_test_logger();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let handler = UnavailableInterfaceHandler::new("Failed to claim interface 1: Busy");
+        let mut device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                None,
+                vec![ep],
+                shared_interface_handler(crate::devices::loopback::UsbLoopbackHandler::new()),
+            )
+            .with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                None,
+                vec![ep],
+                shared_interface_handler(handler),
+            );
+        device.interfaces[1].unavailable_reason = Some("Busy".to_string());
+        // still described, so that the other interfaces keep their numbers
+        assert_eq!(device.configuration_descriptor(0xFF)[4], 2);
+        assert_eq!(device.interfaces[0].unavailable_reason(), None);
+
+        let intf = &device.interfaces[1];
+        let err = device
+            .handle_urb(ep, Some(intf), 8, SetupPacket::default(), &[])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Endpoint 81 is unavailable: Failed to claim interface 1: Busy"
+        );
+        assert_eq!(intf.unavailable_reason(), Some("Busy"));
+    }
+
+    #[test]
+    fn nusb_control_of_setup() {
+        setup_test_logger();
+        let control = nusb_control(SetupPacket {
+            request_type: 0b10100001,
+            request: 0x01,
+            value: 0x0200,
+            index: 3,
+            length: 8,
+        })
+        .unwrap();
+        assert!(matches!(
+            (control.control_type, control.recipient),
+            (
+                nusb::transfer::ControlType::Class,
+                nusb::transfer::Recipient::Interface
+            )
+        ));
+        assert_eq!(
+            (control.request, control.value, control.index),
+            (1, 0x0200, 3)
+        );
+        let reserved = SetupPacket {
+            request_type: 0b01100000,
+            ..Default::default()
+        };
+        assert!(nusb_control(reserved).is_err());
+    }
+
+    #[test]
+    fn libusb_status_of_packets() {
+        setup_test_logger();
+        use crate::usbip_protocol::*;
+        assert_eq!(libusb_status_to_errno(LIBUSB_TRANSFER_COMPLETED), 0);
+        assert_eq!(libusb_status_to_errno(LIBUSB_TRANSFER_STALL), -EPIPE);
+        assert_eq!(libusb_status_to_errno(LIBUSB_TRANSFER_NO_DEVICE), -ENODEV);
+        assert_eq!(libusb_status_to_errno(LIBUSB_TRANSFER_ERROR), -EPROTO);
+    }
+
+    #[test]
+    fn iso_packets_back_to_back() {
+        setup_test_logger();
+        use crate::usbip_protocol::EPROTO;
+        let packet = |offset, length| IsoPacket {
+            offset,
+            length,
+            ..Default::default()
+        };
+        // a gap between the packets of the transfer buffer
+        let mut packets = [packet(0, 2), packet(4, 3)];
+        let transfer_buffer = [1, 2, 0, 0, 3, 4, 5];
+        assert_eq!(
+            gather_iso_packets(Direction::Out, &packets, &transfer_buffer),
+            [1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            gather_iso_packets(Direction::In, &packets, &transfer_buffer),
+            [0; 5]
+        );
+
+        // the first packet is short, the second one failed
+        let results = [(1, 0), (3, -EPROTO)];
+        let data = complete_iso_packets(Direction::In, &mut packets, &results, &[9, 0, 7, 8, 6]);
+        assert_eq!(data, [9, 7, 8, 6]);
+        assert_eq!(
+            packets.map(|p| (p.actual_length, p.status)),
+            [(1, 0), (3, -EPROTO)]
+        );
+    }
+}
@@ -0,0 +1,441 @@
+//! Implement a USB hub
+//!
+//! The hub reports the simulated devices attached to its downstream ports:
+//! their connection, speed, reset and suspend, with changes signaled on its
+//! status change endpoint. USB/IP exports every device on its own, so the
+//! attached devices are placed behind the hub, e.g. `1-1.2` for port 2 of
+//! the hub at `1-1`, and exported next to it.
+use super::super::*;
+
+// reference:
+// Universal Serial Bus Specification, Revision 2.0, Chapter 11 Hub Specification
+// https://www.usb.org/document-library/usb-20-specification
+
+/// bDescriptorType of the hub class descriptor
+const HUB_DESCRIPTOR_TYPE: u8 = 0x29;
+
+// class requests, same values as the standard ones
+const GET_STATUS: u8 = 0x00;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const GET_DESCRIPTOR: u8 = 0x06;
+
+// port feature selectors
+const PORT_ENABLE: u16 = 1;
+const PORT_SUSPEND: u16 = 2;
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_ENABLE: u16 = 17;
+const C_PORT_SUSPEND: u16 = 18;
+const C_PORT_OVER_CURRENT: u16 = 19;
+const C_PORT_RESET: u16 = 20;
+
+/// wPortStatus bit of a port with a device attached
+pub const PORT_STATUS_CONNECTION: u16 = 1 << 0;
+/// wPortStatus bit of an enabled port
+pub const PORT_STATUS_ENABLE: u16 = 1 << 1;
+/// wPortStatus bit of a suspended port
+pub const PORT_STATUS_SUSPEND: u16 = 1 << 2;
+/// wPortStatus bit of a port being reset
+pub const PORT_STATUS_RESET: u16 = 1 << 4;
+/// wPortStatus bit of a powered port
+pub const PORT_STATUS_POWER: u16 = 1 << 8;
+/// wPortStatus bit of a low speed device
+pub const PORT_STATUS_LOW_SPEED: u16 = 1 << 9;
+/// wPortStatus bit of a high speed device
+pub const PORT_STATUS_HIGH_SPEED: u16 = 1 << 10;
+
+#[derive(Clone, Debug, Default)]
+struct HubPort {
+    status: u16,
+    change: u16,
+    /// Bus id of the attached device
+    device: Option<String>,
+}
+
+#[derive(Debug)]
+struct HubState {
+    ports: Vec<HubPort>,
+}
+
+impl HubState {
+    fn port(&mut self, setup: &SetupPacket) -> Result<&mut HubPort> {
+        // ports are numbered from one, the high byte is for PORT_TEST and PORT_INDICATOR
+        let port = (setup.index & 0xFF) as usize;
+        match port.checked_sub(1).and_then(|i| self.ports.get_mut(i)) {
+            Some(port) => Ok(port),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid hub port: {port}"),
+            )),
+        }
+    }
+
+    fn descriptor(&self) -> Vec<u8> {
+        let num_ports = self.ports.len();
+        // one bit per port and a reserved bit zero
+        let bitmap_len = num_ports / 8 + 1;
+        let mut desc = vec![
+            7 + 2 * bitmap_len as u8, // bLength
+            HUB_DESCRIPTOR_TYPE,      // bDescriptorType: Hub
+            num_ports as u8,          // bNbrPorts
+            0x09, // wHubCharacteristics: per port power switching and over-current
+            0x00, //
+            50,   // bPwrOn2PwrGood: 100ms
+            0,    // bHubContrCurrent
+        ];
+        desc.extend(std::iter::repeat_n(0x00, bitmap_len)); // DeviceRemovable
+        desc.extend(std::iter::repeat_n(0xFF, bitmap_len)); // PortPwrCtrlMask
+        desc
+    }
+
+    fn set_port_feature(&mut self, setup: &SetupPacket) -> Result<()> {
+        let port = self.port(setup)?;
+        match setup.value {
+            PORT_RESET if port.status & PORT_STATUS_CONNECTION != 0 => {
+                // the reset completes at once
+                port.status = (port.status & !PORT_STATUS_SUSPEND) | PORT_STATUS_ENABLE;
+                port.change |= PORT_STATUS_RESET;
+            }
+            PORT_SUSPEND => port.status |= PORT_STATUS_SUSPEND,
+            PORT_POWER => port.status |= PORT_STATUS_POWER,
+            // e.g. PORT_RESET without device, PORT_TEST, PORT_INDICATOR
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn clear_port_feature(&mut self, setup: &SetupPacket) -> Result<()> {
+        let port = self.port(setup)?;
+        match setup.value {
+            PORT_ENABLE => port.status &= !PORT_STATUS_ENABLE,
+            PORT_SUSPEND if port.status & PORT_STATUS_SUSPEND != 0 => {
+                port.status &= !PORT_STATUS_SUSPEND;
+                port.change |= PORT_STATUS_SUSPEND;
+            }
+            PORT_POWER => port.status &= !(PORT_STATUS_POWER | PORT_STATUS_ENABLE),
+            // the change bits are in the same order as the status bits
+            C_PORT_CONNECTION | C_PORT_ENABLE | C_PORT_SUSPEND | C_PORT_OVER_CURRENT
+            | C_PORT_RESET => port.change &= !(1 << (setup.value - C_PORT_CONNECTION)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn control(&mut self, setup: SetupPacket) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b10100000, GET_DESCRIPTOR) if (setup.value >> 8) as u8 == HUB_DESCRIPTOR_TYPE => {
+                let mut desc = self.descriptor();
+                desc.truncate(setup.length as usize);
+                Ok(desc)
+            }
+            // no local power or over-current changes
+            (0b10100000, GET_STATUS) => Ok(vec![0; 4]),
+            (0b00100000, CLEAR_FEATURE | SET_FEATURE) => Ok(vec![]),
+            (0b10100011, GET_STATUS) => {
+                let port = self.port(&setup)?;
+                let mut resp = port.status.to_le_bytes().to_vec();
+                resp.extend(port.change.to_le_bytes());
+                Ok(resp)
+            }
+            (0b00100011, SET_FEATURE) => self.set_port_feature(&setup).map(|_| vec![]),
+            (0b00100011, CLEAR_FEATURE) => self.clear_port_feature(&setup).map(|_| vec![]),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported hub request: {setup:x?}"),
+            )),
+        }
+    }
+}
+
+/// A handler of a hub, shared with its handle to attach and detach devices
+#[derive(Clone, Debug)]
+pub struct UsbHubHandler {
+    state: Arc<Mutex<HubState>>,
+}
+
+impl UsbHubHandler {
+    /// A powered hub with `num_ports` empty ports
+    pub fn new(num_ports: u8) -> Self {
+        let port = HubPort {
+            status: PORT_STATUS_POWER,
+            ..HubPort::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(HubState {
+                ports: vec![port; num_ports as usize],
+            })),
+        }
+    }
+
+    pub fn num_ports(&self) -> u8 {
+        self.state.lock().unwrap().ports.len() as u8
+    }
+
+    /// wPortStatus and wPortChange of a port, numbered from one
+    pub fn port_status(&self, port: u8) -> Option<(u16, u16)> {
+        let state = self.state.lock().unwrap();
+        let port = state.ports.get((port as usize).checked_sub(1)?)?;
+        Some((port.status, port.change))
+    }
+
+    /// Bus id of the device attached to a port, numbered from one
+    pub fn attached(&self, port: u8) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .ports
+            .get((port as usize).checked_sub(1)?)?
+            .device
+            .clone()
+    }
+
+    /// Attach `device` to a port of `hub`, the device of this handler
+    ///
+    /// The returned device is placed behind the hub, to be exported with
+    /// e.g. [UsbIpServer::add_device].
+    pub fn attach(&self, hub: &UsbDevice, port: u8, device: UsbDevice) -> Result<UsbDevice> {
+        let mut ports = hub.ports.clone();
+        ports.push(port);
+        let device = device.with_location(hub.bus_num, &ports);
+
+        let mut state = self.state.lock().unwrap();
+        let hub_port = (port as usize)
+            .checked_sub(1)
+            .and_then(|i| state.ports.get_mut(i))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid hub port: {port}"),
+                )
+            })?;
+        if let Some(bus_id) = &hub_port.device {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("Hub port {port} is used by {bus_id}"),
+            ));
+        }
+        let speed = match device.usb_speed() {
+            UsbSpeed::Low => PORT_STATUS_LOW_SPEED,
+            UsbSpeed::Full => 0,
+            _ => PORT_STATUS_HIGH_SPEED,
+        };
+        hub_port.status = (hub_port.status & PORT_STATUS_POWER) | PORT_STATUS_CONNECTION | speed;
+        hub_port.change |= PORT_STATUS_CONNECTION;
+        hub_port.device = Some(device.bus_id.clone());
+        Ok(device)
+    }
+
+    /// Detach the device of a port, returning its bus id
+    pub fn detach(&self, port: u8) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let hub_port = state.ports.get_mut((port as usize).checked_sub(1)?)?;
+        let bus_id = hub_port.device.take()?;
+        hub_port.status &= PORT_STATUS_POWER;
+        hub_port.change |= PORT_STATUS_CONNECTION;
+        Some(bus_id)
+    }
+
+    /// The status change endpoint of a hub with `num_ports` ports
+    pub fn endpoints(num_ports: u8) -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint {
+            address: 0x81,                                   // IN
+            attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+            max_packet_size: num_ports as u16 / 8 + 1,       // one bit per port and the hub
+            interval: 12,
+        }]
+    }
+}
+
+impl UsbInterfaceHandler for UsbHubHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported hub request: {setup:x?}"),
+            ));
+        }
+        // bit zero is for the hub itself, then one bit per port
+        let state = self.state.lock().unwrap();
+        let mut bitmap = vec![0u8; state.ports.len() / 8 + 1];
+        for (i, port) in state.ports.iter().enumerate() {
+            if port.change != 0 {
+                bitmap[(i + 1) / 8] |= 1 << ((i + 1) % 8);
+            }
+        }
+        if bitmap.iter().all(|b| *b == 0) {
+            return Ok(vec![]);
+        }
+        Ok(bitmap)
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Debug)]
+struct HubDeviceHandler {
+    state: Arc<Mutex<HubState>>,
+}
+
+impl UsbDeviceHandler for HubDeviceHandler {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.state.lock().unwrap().control(setup)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A high speed hub with the ports of `handler`
+    pub fn hub(handler: UsbHubHandler) -> Self {
+        let device_handler = HubDeviceHandler {
+            state: handler.state.clone(),
+        };
+        let num_ports = handler.num_ports();
+        let mut device = Self::new(0)
+            .with_interface(
+                ClassCode::Hub as u8,
+                0x00,
+                0x00,
+                Some("Hub"),
+                UsbHubHandler::endpoints(num_ports),
+                shared_interface_handler(handler),
+            )
+            .with_device_handler(shared_device_handler(device_handler));
+        device.device_class = ClassCode::Hub as u8;
+        // single transaction translator
+        device.device_protocol = 0x01;
+        device.self_powered = true;
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0012;
+        device.set_product_name("Virtual Hub");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn port_request(request_type: u8, request: u8, value: u16, port: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request,
+            value,
+            index: port,
+            length: if request_type & 0x80 != 0 { 4 } else { 0 },
+        }
+    }
+
+    #[tokio::test]
+    async fn hub_ports() {
+        setup_test_logger();
+        let handler = UsbHubHandler::new(4);
+        let hub = UsbDevice::hub(handler.clone()).with_location(1, &[1]);
+        verify_descriptor(&hub.configuration_descriptor(0xFFFF));
+
+        let get_hub_descriptor = SetupPacket {
+            request_type: 0b10100000,
+            request: GET_DESCRIPTOR,
+            value: (HUB_DESCRIPTOR_TYPE as u16) << 8,
+            index: 0,
+            length: 0xFF,
+        };
+        let desc = hub
+            .handle_urb(hub.ep0_in, None, 0xFF, get_hub_descriptor, &[])
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 9);
+        assert_eq!(desc[..3], [9, HUB_DESCRIPTOR_TYPE, 4]);
+
+        let intf = &hub.interfaces[0];
+        let [status_change] = UsbHubHandler::endpoints(4).try_into().unwrap();
+        let poll = || async {
+            intf.handler
+                .lock()
+                .await
+                .handle_urb(intf, status_change, 1, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert!(poll().await.is_empty());
+
+        // attach a full speed device to port 2
+        let device = UsbDevice::new(0).with_speed(UsbSpeed::Full);
+        let device = handler.attach(&hub, 2, device).unwrap();
+        assert_eq!(device.bus_id, "1-1.2");
+        assert!(handler.attach(&hub, 2, UsbDevice::new(0)).is_err());
+        assert!(handler.attach(&hub, 5, UsbDevice::new(0)).is_err());
+        assert_eq!(poll().await, [1 << 2]);
+
+        let get_port_status = port_request(0b10100011, GET_STATUS, 0, 2);
+        let status = hub
+            .handle_urb(hub.ep0_in, None, 4, get_port_status, &[])
+            .await
+            .unwrap();
+        let connected = PORT_STATUS_POWER | PORT_STATUS_CONNECTION;
+        assert_eq!(status[..2], connected.to_le_bytes());
+        assert_eq!(status[2..], PORT_STATUS_CONNECTION.to_le_bytes());
+
+        // acknowledge the connection and reset the port, like the hub driver
+        for setup in [
+            port_request(0b00100011, CLEAR_FEATURE, C_PORT_CONNECTION, 2),
+            port_request(0b00100011, SET_FEATURE, PORT_RESET, 2),
+        ] {
+            hub.handle_urb(hub.ep0_out, None, 0, setup, &[])
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            handler.port_status(2),
+            Some((connected | PORT_STATUS_ENABLE, PORT_STATUS_RESET))
+        );
+        hub.handle_urb(
+            hub.ep0_out,
+            None,
+            0,
+            port_request(0b00100011, CLEAR_FEATURE, C_PORT_RESET, 2),
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(poll().await.is_empty());
+
+        assert_eq!(handler.detach(2).as_deref(), Some("1-1.2"));
+        assert_eq!(handler.detach(2), None);
+        assert_eq!(
+            handler.port_status(2),
+            Some((PORT_STATUS_POWER, PORT_STATUS_CONNECTION))
+        );
+        assert_eq!(poll().await, [1 << 2]);
+
+        let invalid_port = port_request(0b10100011, GET_STATUS, 0, 5);
+        assert!(
+            hub.handle_urb(hub.ep0_in, None, 4, invalid_port, &[])
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Implement a vendor specific device echoing OUT data back on IN
+use super::super::*;
+
+/// Most bytes buffered per endpoint by default
+pub const LOOPBACK_MAX_BUFFERED: usize = 0x10000;
+
+/// A handler returning the data written to each OUT endpoint on the IN
+/// endpoint of the same number
+///
+/// OUT data beyond `max_buffered` unread bytes is dropped, and IN transfers
+/// with nothing buffered complete without data.
+#[derive(Clone, Debug)]
+pub struct UsbLoopbackHandler {
+    /// Unread data by endpoint number
+    pub buffers: HashMap<u8, VecDeque<u8>>,
+    pub max_buffered: usize,
+}
+
+impl Default for UsbLoopbackHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbLoopbackHandler {
+    pub fn new() -> Self {
+        Self {
+            buffers: HashMap::new(),
+            max_buffered: LOOPBACK_MAX_BUFFERED,
+        }
+    }
+
+    /// Pairs of OUT and IN endpoints, first `bulk` bulk pairs numbered from
+    /// 1, then `interrupt` interrupt pairs
+    ///
+    /// Panics if there are more than 15 pairs.
+    pub fn endpoints(bulk: u8, interrupt: u8) -> Vec<UsbEndpoint> {
+        assert!(
+            bulk as u16 + interrupt as u16 <= 15,
+            "At most 15 endpoint pairs, got {bulk} bulk and {interrupt} interrupt"
+        );
+        let mut endpoints = vec![];
+        for number in 1..=bulk + interrupt {
+            let (attributes, max_packet_size, interval) = if number <= bulk {
+                (EndpointAttributes::Bulk as u8, 512, 0)
+            } else {
+                (EndpointAttributes::Interrupt as u8, 64, 1)
+            };
+            for address in [number, 0x80 | number] {
+                endpoints.push(UsbEndpoint {
+                    address,
+                    attributes,
+                    max_packet_size,
+                    interval,
+                });
+            }
+        }
+        endpoints
+    }
+}
+
+impl UsbInterfaceHandler for UsbLoopbackHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return Ok(vec![]);
+        }
+        let buffer = self.buffers.entry(ep.address & 0x0F).or_default();
+        match ep.direction() {
+            Direction::Out => {
+                let len = req.len().min(self.max_buffered - buffer.len());
+                if len < req.len() {
+                    warn!(
+                        "Dropping {} bytes written to full endpoint {:02x}",
+                        req.len() - len,
+                        ep.address
+                    );
+                }
+                buffer.extend(&req[..len]);
+                Ok(vec![])
+            }
+            Direction::In => {
+                let len = buffer.len().min(transfer_buffer_length as usize);
+                let mut resp = take_scratch_buffer(len);
+                resp.extend(buffer.drain(..len));
+                Ok(resp)
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A vendor specific device with one [UsbLoopbackHandler] interface
+    ///
+    /// See [UsbLoopbackHandler::endpoints] for its endpoints. Useful for
+    /// benchmarks and end-to-end tests of clients without hardware.
+    pub fn loopback(bulk: u8, interrupt: u8) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            Some("Loopback"),
+            UsbLoopbackHandler::endpoints(bulk, interrupt),
+            shared_interface_handler(UsbLoopbackHandler::new()),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0002;
+        device.set_product_name("Loopback");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn echoes_out_data() {
+        setup_test_logger();
+        let device = UsbDevice::loopback(2, 1);
+        let endpoints = &device.interfaces[0].endpoints;
+        assert_eq!(endpoints.len(), 6);
+        assert_eq!(endpoints[5].address, 0x83);
+        assert_eq!(endpoints[5].attributes, EndpointAttributes::Interrupt as u8);
+
+        let interface = &device.interfaces[0];
+        let mut handler = UsbLoopbackHandler::new();
+        handler.max_buffered = 6;
+        let setup = SetupPacket::default();
+        handler
+            .handle_urb(interface, endpoints[0], 4, setup, b"ping")
+            .unwrap();
+        handler
+            .handle_urb(interface, endpoints[2], 4, setup, b"pong")
+            .unwrap();
+        handler
+            .handle_urb(interface, endpoints[0], 4, setup, b"ping")
+            .unwrap();
+        let read = |handler: &mut UsbLoopbackHandler, ep: UsbEndpoint, len| {
+            handler.handle_urb(interface, ep, len, setup, &[]).unwrap()
+        };
+        assert_eq!(read(&mut handler, endpoints[1], 3), b"pin");
+        assert_eq!(read(&mut handler, endpoints[1], 64), b"gpi");
+        assert_eq!(read(&mut handler, endpoints[1], 64), b"");
+        assert_eq!(read(&mut handler, endpoints[3], 64), b"pong");
+
+        assert!(check_conformance(device).await.passed());
+    }
+}
@@ -0,0 +1,459 @@
+//! Implement a USB MIDI 1.0 device
+//!
+//! [UsbDevice::midi] builds an audio control interface and a MIDI streaming
+//! interface with one embedded jack in each direction. MIDI event packets
+//! are exchanged with the application through the [UsbMidiPort] of the
+//! [UsbMidiHandler], so a virtual instrument can be played from a remote
+//! host.
+use super::super::*;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+// reference:
+// USB MIDI 1.0: https://www.usb.org/sites/default/files/midi10.pdf
+
+/// bInterfaceSubClass of the MIDI streaming interface
+pub const MIDI_STREAMING_SUBCLASS: u8 = 0x03;
+/// bInterfaceSubClass of the audio control interface
+const AUDIO_CONTROL_SUBCLASS: u8 = 0x01;
+
+// class specific descriptor types
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+// jacks of the MIDI streaming interface
+const EMBEDDED_IN_JACK: u8 = 1;
+const EXTERNAL_IN_JACK: u8 = 2;
+const EMBEDDED_OUT_JACK: u8 = 3;
+const EXTERNAL_OUT_JACK: u8 = 4;
+
+/// A USB-MIDI event packet
+///
+/// `code_index` (CIN) tells how many bytes of `data` belong to the message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbMidiEvent {
+    /// Virtual cable number, 0 to 15
+    pub cable: u8,
+    pub code_index: u8,
+    pub data: [u8; 3],
+}
+
+impl UsbMidiEvent {
+    pub fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self {
+            cable: bytes[0] >> 4,
+            code_index: bytes[0] & 0x0F,
+            data: [bytes[1], bytes[2], bytes[3]],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        [
+            (self.cable << 4) | (self.code_index & 0x0F),
+            self.data[0],
+            self.data[1],
+            self.data[2],
+        ]
+    }
+
+    /// The events of the complete MIDI message `message` on `cable`
+    ///
+    /// System exclusive messages take several events, others one. Empty if
+    /// `message` does not start with a status byte.
+    pub fn from_message(cable: u8, message: &[u8]) -> Vec<Self> {
+        let event = |code_index, bytes: &[u8]| {
+            let mut data = [0; 3];
+            data[..bytes.len()].copy_from_slice(bytes);
+            Self {
+                cable,
+                code_index,
+                data,
+            }
+        };
+        let Some(&status) = message.first().filter(|&&status| status & 0x80 != 0) else {
+            return vec![];
+        };
+        if status == 0xF0 {
+            let chunks: Vec<&[u8]> = message.chunks(3).collect();
+            return chunks
+                .iter()
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let code_index = if i + 1 < chunks.len() {
+                        // start or continuation
+                        0x4
+                    } else {
+                        // end with 1, 2 or 3 bytes
+                        0x4 + chunk.len() as u8
+                    };
+                    event(code_index, chunk)
+                })
+                .collect();
+        }
+        let code_index = match status {
+            0x80..=0xEF => status >> 4,
+            0xF1 | 0xF3 => 0x2,
+            0xF2 => 0x3,
+            0xF6 | 0xF7 => 0x5,
+            _ => 0xF,
+        };
+        let len = Self::message_len(code_index).min(message.len());
+        vec![event(code_index, &message[..len])]
+    }
+
+    pub fn note_on(cable: u8, channel: u8, note: u8, velocity: u8) -> Self {
+        Self::from_message(cable, &[0x90 | (channel & 0x0F), note, velocity])[0]
+    }
+
+    pub fn note_off(cable: u8, channel: u8, note: u8, velocity: u8) -> Self {
+        Self::from_message(cable, &[0x80 | (channel & 0x0F), note, velocity])[0]
+    }
+
+    /// The MIDI bytes of the event
+    pub fn message(&self) -> &[u8] {
+        &self.data[..Self::message_len(self.code_index)]
+    }
+
+    fn message_len(code_index: u8) -> usize {
+        match code_index {
+            // reserved
+            0x0 | 0x1 => 0,
+            0x5 | 0xF => 1,
+            0x2 | 0x6 | 0xC | 0xD => 2,
+            _ => 3,
+        }
+    }
+}
+
+/// The application side of a [UsbMidiHandler]
+#[derive(Debug)]
+pub struct UsbMidiPort {
+    to_host: UnboundedSender<UsbMidiEvent>,
+    from_host: UnboundedReceiver<UsbMidiEvent>,
+}
+
+impl UsbMidiPort {
+    /// Queue `event` for the host, which reads it from the bulk IN endpoint
+    pub fn send(&self, event: UsbMidiEvent) -> Result<()> {
+        self.to_host
+            .send(event)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+
+    /// The next event written by the host, `None` once the handler is dropped
+    pub async fn recv(&mut self) -> Option<UsbMidiEvent> {
+        self.from_host.recv().await
+    }
+
+    /// The next event written by the host, if there is one
+    pub fn try_recv(&mut self) -> Option<UsbMidiEvent> {
+        self.from_host.try_recv().ok()
+    }
+}
+
+/// A handler of a MIDI streaming interface
+#[derive(Debug)]
+pub struct UsbMidiHandler {
+    to_host: UnboundedReceiver<UsbMidiEvent>,
+    from_host: UnboundedSender<UsbMidiEvent>,
+}
+
+impl UsbMidiHandler {
+    /// A handler and the port to exchange its events
+    pub fn new() -> (Self, UsbMidiPort) {
+        let (to_host, to_host_receiver) = unbounded_channel();
+        let (from_host_sender, from_host) = unbounded_channel();
+        (
+            Self {
+                to_host: to_host_receiver,
+                from_host: from_host_sender,
+            },
+            UsbMidiPort { to_host, from_host },
+        )
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ]
+    }
+
+    /// The jack descriptors
+    fn jacks() -> Vec<u8> {
+        vec![
+            // MIDI IN jacks
+            0x06,
+            CS_INTERFACE,
+            0x02, // bDescriptorSubtype: MIDI_IN_JACK
+            0x01, // bJackType: embedded
+            EMBEDDED_IN_JACK,
+            0x00, // iJack
+            0x06,
+            CS_INTERFACE,
+            0x02, // bDescriptorSubtype: MIDI_IN_JACK
+            0x02, // bJackType: external
+            EXTERNAL_IN_JACK,
+            0x00, // iJack
+            // MIDI OUT jacks, each connected to the IN jack of the other type
+            0x09,
+            CS_INTERFACE,
+            0x03, // bDescriptorSubtype: MIDI_OUT_JACK
+            0x01, // bJackType: embedded
+            EMBEDDED_OUT_JACK,
+            0x01, // bNrInputPins
+            EXTERNAL_IN_JACK,
+            0x01, // baSourcePin
+            0x00, // iJack
+            0x09,
+            CS_INTERFACE,
+            0x03, // bDescriptorSubtype: MIDI_OUT_JACK
+            0x02, // bJackType: external
+            EXTERNAL_OUT_JACK,
+            0x01, // bNrInputPins
+            EMBEDDED_IN_JACK,
+            0x01, // baSourcePin
+            0x00, // iJack
+        ]
+    }
+}
+
+impl UsbInterfaceHandler for UsbMidiHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return Ok(vec![]);
+        }
+        match ep.direction() {
+            Direction::Out => {
+                for packet in req.chunks_exact(4) {
+                    let event = UsbMidiEvent::from_bytes(packet.try_into().unwrap());
+                    // padding
+                    if event == UsbMidiEvent::default() {
+                        continue;
+                    }
+                    if self.from_host.send(event).is_err() {
+                        debug!("Dropping MIDI event {event:?} without a port");
+                    }
+                }
+                Ok(vec![])
+            }
+            Direction::In => {
+                let max_events = transfer_buffer_length as usize / 4;
+                let mut resp = take_scratch_buffer(max_events * 4);
+                while resp.len() / 4 < max_events
+                    && let Ok(event) = self.to_host.try_recv()
+                {
+                    resp.extend_from_slice(&event.to_bytes());
+                }
+                Ok(resp)
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let jacks = Self::jacks();
+        // the header, jacks, and standard and class specific endpoint descriptors
+        let total_len = 7 + jacks.len() + 2 * (7 + 5);
+        let mut desc = vec![
+            0x07,
+            CS_INTERFACE,
+            0x01, // bDescriptorSubtype: MS_HEADER
+            0x00,
+            0x01, // bcdMSC 1.00
+            total_len as u8,
+            (total_len >> 8) as u8, // wTotalLength
+        ];
+        desc.extend(jacks);
+        desc
+    }
+
+    fn get_class_specific_endpoint_descriptor(&self, endpoint: &UsbEndpoint) -> Vec<u8> {
+        // the embedded jack of the endpoint
+        let jack = match endpoint.direction() {
+            Direction::Out => EMBEDDED_IN_JACK,
+            Direction::In => EMBEDDED_OUT_JACK,
+        };
+        vec![
+            0x05,
+            CS_ENDPOINT,
+            0x01, // bDescriptorSubtype: MS_GENERAL
+            0x01, // bNumEmbMIDIJack
+            jack,
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The audio control interface of a MIDI device, without audio functions
+#[derive(Debug)]
+struct MidiAudioControlHandler {
+    streaming_interface: u8,
+}
+
+impl UsbInterfaceHandler for MidiAudioControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported audio control request: {setup:x?}"),
+        ))
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![
+            0x09,
+            CS_INTERFACE,
+            0x01, // bDescriptorSubtype: HEADER
+            0x00,
+            0x01, // bcdADC 1.00
+            0x09,
+            0x00, // wTotalLength
+            0x01, // bInCollection
+            self.streaming_interface,
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A MIDI device with the MIDI streaming interface of `handler`
+    pub fn midi(handler: UsbMidiHandler) -> Self {
+        let mut device = Self::new(0)
+            .with_interface(
+                ClassCode::Audio as u8,
+                AUDIO_CONTROL_SUBCLASS,
+                0x00,
+                None,
+                vec![],
+                shared_interface_handler(MidiAudioControlHandler {
+                    streaming_interface: 1,
+                }),
+            )
+            .with_interface(
+                ClassCode::Audio as u8,
+                MIDI_STREAMING_SUBCLASS,
+                0x00,
+                Some("MIDI"),
+                UsbMidiHandler::endpoints(),
+                shared_interface_handler(handler),
+            );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0006;
+        device.set_product_name("Virtual MIDI Instrument");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn midi_events() {
+        setup_test_logger();
+        let note = UsbMidiEvent::note_on(1, 2, 60, 100);
+        assert_eq!(note.to_bytes(), [0x19, 0x92, 60, 100]);
+        assert_eq!(UsbMidiEvent::from_bytes(note.to_bytes()), note);
+        assert_eq!(note.message(), [0x92, 60, 100]);
+        let program = UsbMidiEvent::from_message(0, &[0xC0, 5]);
+        assert_eq!(program[0].to_bytes(), [0x0C, 0xC0, 5, 0]);
+        let clock = UsbMidiEvent::from_message(0, &[0xF8]);
+        assert_eq!(clock[0].to_bytes(), [0x0F, 0xF8, 0, 0]);
+        assert!(UsbMidiEvent::from_message(0, &[0x40]).is_empty());
+
+        let sysex = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+        let events = UsbMidiEvent::from_message(0, &sysex);
+        assert_eq!(
+            events
+                .iter()
+                .map(UsbMidiEvent::to_bytes)
+                .collect::<Vec<_>>(),
+            [[0x04, 0xF0, 0x7E, 0x7F], [0x07, 0x06, 0x01, 0xF7]]
+        );
+        let sysex = [0xF0, 0x01, 0x02, 0xF7];
+        let events = UsbMidiEvent::from_message(0, &sysex);
+        assert_eq!(events[1].to_bytes(), [0x05, 0xF7, 0, 0]);
+        assert_eq!(events.iter().flat_map(|e| e.message()).count(), 4);
+    }
+
+    #[tokio::test]
+    async fn midi_streaming() {
+        setup_test_logger();
+        let (handler, mut port) = UsbMidiHandler::new();
+        let device = UsbDevice::midi(handler);
+        let desc = device.configuration_descriptor(0xFFFF);
+        verify_descriptor(&desc);
+        // each endpoint is followed by its MS_GENERAL descriptor
+        let ms_general = desc
+            .windows(5)
+            .filter(|w| w[0] == 0x05 && w[1] == CS_ENDPOINT)
+            .count();
+        assert_eq!(ms_general, 2);
+        let streaming = &device.interfaces[1];
+        let total_len = streaming.class_specific_descriptor[5] as usize;
+        let start = desc.len() - total_len;
+        assert_eq!(desc[start..start + 3], [0x07, CS_INTERFACE, 0x01]);
+
+        let [bulk_out, bulk_in] = UsbMidiHandler::endpoints().try_into().unwrap();
+        let mut handler = streaming.handler.lock().await;
+        let mut packets = UsbMidiEvent::note_on(0, 0, 64, 127).to_bytes().to_vec();
+        packets.extend([0; 4]);
+        packets.extend(UsbMidiEvent::note_off(0, 0, 64, 0).to_bytes());
+        handler
+            .handle_urb(streaming, bulk_out, 12, SetupPacket::default(), &packets)
+            .unwrap();
+        assert_eq!(port.recv().await.unwrap().message(), [0x90, 64, 127]);
+        assert_eq!(port.recv().await.unwrap().message(), [0x80, 64, 0]);
+        assert!(port.try_recv().is_none());
+
+        for note in 0..3 {
+            port.send(UsbMidiEvent::note_on(0, 9, note, 1)).unwrap();
+        }
+        let mut read = |len| {
+            handler
+                .handle_urb(streaming, bulk_in, len, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert_eq!(read(8), [0x09, 0x99, 0, 1, 0x09, 0x99, 1, 1]);
+        assert_eq!(read(64), [0x09, 0x99, 2, 1]);
+        assert!(read(64).is_empty());
+    }
+}
@@ -0,0 +1,1002 @@
+//! Implement a Mass Storage Class device with Bulk-Only Transport and SCSI commands
+use super::super::*;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Sub class code of the SCSI transparent command set
+pub const MSC_SCSI_SUBCLASS: u8 = 0x06;
+/// Protocol code of Bulk-Only Transport
+pub const MSC_BOT_PROTOCOL: u8 = 0x50;
+/// Size of the logical blocks of the drive
+pub const MSC_BLOCK_SIZE: usize = 512;
+/// Size of the sectors of a CD-ROM
+pub const CD_BLOCK_SIZE: usize = 2048;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+
+// class requests
+const GET_MAX_LUN: u8 = 0xFE;
+const BULK_ONLY_RESET: u8 = 0xFF;
+
+// SCSI operation codes
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+const READ_12: u8 = 0xA8;
+
+// MMC operation codes of CD-ROM drives
+const READ_TOC: u8 = 0x43;
+const GET_CONFIGURATION: u8 = 0x46;
+const GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4A;
+const SET_CD_SPEED: u8 = 0xBB;
+
+/// MMC profile of a CD-ROM
+const PROFILE_CD_ROM: u16 = 0x0008;
+/// ADR and CONTROL of a data track
+const DATA_TRACK: u8 = 0x14;
+/// Mode page of the capabilities of a CD-ROM drive
+const CAPABILITIES_PAGE: u8 = 0x2A;
+
+// CSW status
+const COMMAND_PASSED: u8 = 0;
+const COMMAND_FAILED: u8 = 1;
+const PHASE_ERROR: u8 = 2;
+
+/// Sense key, additional sense code and its qualifier
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Sense(u8, u8, u8);
+
+const NO_SENSE: Sense = Sense(0x00, 0x00, 0x00);
+const MEDIUM_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+const WRITE_ERROR: Sense = Sense(0x03, 0x0C, 0x00);
+const INVALID_COMMAND: Sense = Sense(0x05, 0x20, 0x00);
+const LBA_OUT_OF_RANGE: Sense = Sense(0x05, 0x21, 0x00);
+const INVALID_FIELD: Sense = Sense(0x05, 0x24, 0x00);
+const WRITE_PROTECTED: Sense = Sense(0x07, 0x27, 0x00);
+
+/// Storage of the blocks of a [UsbMassStorageHandler]
+///
+/// Implement it to back a drive with e.g. sparse files, encrypted stores or
+/// network block devices. Errors are reported to the host as medium errors.
+pub trait BlockBackend: std::fmt::Debug {
+    /// Size of the blocks in bytes, usually 512 or 4096
+    fn block_size(&self) -> u32 {
+        MSC_BLOCK_SIZE as u32
+    }
+
+    fn block_count(&self) -> u64;
+
+    /// Fill `buf`, a multiple of the block size, starting at block `lba`
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Write `data`, a multiple of the block size, starting at block `lba`
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<()>;
+
+    /// Persist the written blocks, for SYNCHRONIZE CACHE
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Blocks kept in memory
+#[derive(Clone, Debug)]
+pub struct MemoryBackend {
+    image: Vec<u8>,
+    block_size: usize,
+}
+
+impl MemoryBackend {
+    /// Hold `image` in [MSC_BLOCK_SIZE] byte blocks, padded with zeros to whole blocks
+    pub fn new(image: Vec<u8>) -> Self {
+        Self::with_block_size(image, MSC_BLOCK_SIZE)
+    }
+
+    /// Hold `image` in `block_size` byte blocks, e.g. [CD_BLOCK_SIZE] for an ISO image
+    pub fn with_block_size(mut image: Vec<u8>, block_size: usize) -> Self {
+        image.resize(image.len().div_ceil(block_size) * block_size, 0);
+        Self { image, block_size }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    fn range(&self, lba: u64, len: usize) -> Result<std::ops::Range<usize>> {
+        let start = lba as usize * self.block_size;
+        match start.checked_add(len) {
+            Some(end) if end <= self.image.len() => Ok(start..end),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{len} bytes at block {lba} are out of range"),
+            )),
+        }
+    }
+}
+
+impl BlockBackend for MemoryBackend {
+    fn block_size(&self) -> u32 {
+        self.block_size as u32
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.image.len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(lba, buf.len())?;
+        buf.copy_from_slice(&self.image[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<()> {
+        let range = self.range(lba, data.len())?;
+        self.image[range].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Blocks kept in a file or block device, from its start
+///
+/// Holes of sparse files read as zeros and are only allocated when written.
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+    block_size: u32,
+    blocks: u64,
+}
+
+impl FileBackend {
+    /// Use `file` with blocks of `block_size` bytes, a trailing partial block is not used
+    pub fn new(file: File, block_size: u32) -> Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file,
+            block_size,
+            blocks: len / block_size as u64,
+        })
+    }
+}
+
+impl BlockBackend for FileBackend {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(lba * self.block_size as u64))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(lba * self.block_size as u64))?;
+        self.file.write_all(data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Command Block Wrapper
+#[derive(Clone, Copy, Debug)]
+struct Cbw {
+    tag: u32,
+    data_length: u32,
+    data_in: bool,
+    command: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != CBW_LEN
+            || u32::from_le_bytes(data[0..4].try_into().unwrap()) != CBW_SIGNATURE
+            || !(1..=16).contains(&data[14])
+        {
+            return None;
+        }
+        Some(Self {
+            tag: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            data_length: u32::from_le_bytes(data[8..12].try_into().unwrap()),
+            data_in: data[12] & 0x80 != 0,
+            command: data[15..31].try_into().unwrap(),
+        })
+    }
+
+    /// Command Status Wrapper
+    fn status(&self, residue: u32, status: u8) -> Vec<u8> {
+        let mut csw = Vec::with_capacity(13);
+        csw.extend(CSW_SIGNATURE.to_le_bytes());
+        csw.extend(self.tag.to_le_bytes());
+        csw.extend(residue.to_le_bytes());
+        csw.push(status);
+        csw
+    }
+
+    /// LBA and number of blocks of READ(10), READ(12), WRITE(10) and VERIFY(10)
+    fn blocks(&self) -> (u64, u64) {
+        let lba = u32::from_be_bytes(self.command[2..6].try_into().unwrap());
+        let count = match self.command[0] {
+            READ_12 => u32::from_be_bytes(self.command[6..10].try_into().unwrap()),
+            _ => u16::from_be_bytes(self.command[7..9].try_into().unwrap()).into(),
+        };
+        (lba.into(), count.into())
+    }
+
+    /// Allocation length of commands with it in bytes 7 and 8
+    fn allocation_length(&self) -> usize {
+        u16::from_be_bytes([self.command[7], self.command[8]]).into()
+    }
+}
+
+/// Phase of Bulk-Only Transport
+#[derive(Debug)]
+enum Phase {
+    /// Waiting for a CBW
+    Command,
+    /// Sending the data of a command to the host
+    DataIn {
+        cbw: Cbw,
+        data: Vec<u8>,
+        sent: usize,
+        status: u8,
+    },
+    /// Receiving the data of a command from the host, written from block
+    /// `target` on unless the command failed
+    DataOut {
+        cbw: Cbw,
+        target: core::result::Result<u64, Sense>,
+        received: u32,
+        /// Received data short of a whole block
+        pending: Vec<u8>,
+    },
+    /// Sending the CSW
+    Status(Vec<u8>),
+}
+
+/// A handler of a USB drive, speaking Bulk-Only Transport and SCSI
+///
+/// It has a single LUN, with the blocks of a [BlockBackend], and answers
+/// the commands Linux, macOS and Windows need to mount it. As a CD-ROM, see
+/// [cdrom](Self::cdrom), it answers the MMC commands of CD-ROM drives as well.
+#[derive(Debug)]
+pub struct UsbMassStorageHandler {
+    backend: Box<dyn BlockBackend + Send>,
+    read_only: bool,
+    cdrom: bool,
+    /// Vendor identification of INQUIRY, at most 8 characters
+    pub vendor: String,
+    /// Product identification of INQUIRY, at most 16 characters
+    pub product: String,
+    sense: Sense,
+    phase: Phase,
+}
+
+impl UsbMassStorageHandler {
+    pub fn new(backend: impl BlockBackend + Send + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            read_only: false,
+            cdrom: false,
+            vendor: "usbip".to_string(),
+            product: "Virtual Drive".to_string(),
+            sense: NO_SENSE,
+            phase: Phase::Command,
+        }
+    }
+
+    /// A drive holding `image`, see [MemoryBackend]
+    pub fn from_memory(image: Vec<u8>) -> Self {
+        Self::new(MemoryBackend::new(image))
+    }
+
+    /// A drive of [MSC_BLOCK_SIZE] byte blocks backed by `file`, see [FileBackend]
+    ///
+    /// The file must be opened for writing, unless the drive is made read-only.
+    pub fn from_file(file: File) -> Result<Self> {
+        Ok(Self::new(FileBackend::new(file, MSC_BLOCK_SIZE as u32)?))
+    }
+
+    /// A drive backed by the disk image at `path`
+    pub fn open(path: &Path, read_only: bool) -> Result<Self> {
+        let file = File::options().read(true).write(!read_only).open(path)?;
+        Ok(Self::from_file(file)?.with_read_only(read_only))
+    }
+
+    /// A read-only CD-ROM drive with a disc of the [CD_BLOCK_SIZE] byte blocks of `backend`
+    pub fn cdrom(backend: impl BlockBackend + Send + 'static) -> Self {
+        let mut handler = Self::new(backend).with_read_only(true);
+        handler.cdrom = true;
+        handler.product = "Virtual CD-ROM".to_string();
+        handler
+    }
+
+    /// A CD-ROM drive with the ISO image at `path`, e.g. to install an OS remotely
+    pub fn open_iso(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(Self::cdrom(FileBackend::new(file, CD_BLOCK_SIZE as u32)?))
+    }
+
+    /// Reject writes with DATA PROTECT, and report the drive write protected
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The storage of the drive
+    pub fn backend(&mut self) -> &mut Box<dyn BlockBackend + Send> {
+        &mut self.backend
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+        ]
+    }
+
+    /// First block of `cbw`, if its blocks are within the drive
+    fn range(&self, cbw: &Cbw) -> core::result::Result<u64, Sense> {
+        let (lba, count) = cbw.blocks();
+        if lba + count > self.backend.block_count() {
+            return Err(LBA_OUT_OF_RANGE);
+        }
+        Ok(lba)
+    }
+
+    /// Run a command without data from the host, and return the data for it
+    fn command(&mut self, cbw: &Cbw) -> core::result::Result<Vec<u8>, Sense> {
+        let command = &cbw.command;
+        match command[0] {
+            TEST_UNIT_READY | START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL => Ok(vec![]),
+            REQUEST_SENSE => {
+                let Sense(key, asc, ascq) = self.sense;
+                let mut sense = vec![
+                    0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0,
+                ];
+                sense.truncate(command[4].into());
+                Ok(sense)
+            }
+            INQUIRY => {
+                // vital product data pages are not supported
+                if command[1] & 0x01 != 0 {
+                    return Err(INVALID_FIELD);
+                }
+                // removable direct access block device or CD-ROM, SPC-2
+                let device_type = if self.cdrom { 0x05 } else { 0x00 };
+                let mut inquiry = vec![device_type, 0x80, 0x04, 0x02, 31, 0, 0, 0];
+                for (field, len) in [
+                    (&self.vendor, 8),
+                    (&self.product, 16),
+                    (&"1.00".to_string(), 4),
+                ] {
+                    let mut field = field.as_bytes()[..field.len().min(len)].to_vec();
+                    field.resize(len, b' ');
+                    inquiry.extend(field);
+                }
+                inquiry.truncate(u16::from_be_bytes([command[3], command[4]]).into());
+                Ok(inquiry)
+            }
+            MODE_SENSE_6 => {
+                let pages = self.mode_pages(command[2] & 0x3F);
+                let mut mode = vec![3 + pages.len() as u8, 0, self.device_specific(), 0];
+                mode.extend(pages);
+                mode.truncate(command[4].into());
+                Ok(mode)
+            }
+            MODE_SENSE_10 => {
+                let pages = self.mode_pages(command[2] & 0x3F);
+                let mut mode = (6 + pages.len() as u16).to_be_bytes().to_vec();
+                mode.extend([0, self.device_specific(), 0, 0, 0, 0]);
+                mode.extend(pages);
+                mode.truncate(cbw.allocation_length());
+                Ok(mode)
+            }
+            READ_FORMAT_CAPACITIES => {
+                let mut capacities = vec![0, 0, 0, 8];
+                capacities
+                    .extend((self.backend.block_count().min(u32::MAX.into()) as u32).to_be_bytes());
+                // formatted media
+                capacities.push(0x02);
+                capacities.extend(&self.backend.block_size().to_be_bytes()[1..]);
+                capacities.truncate(cbw.allocation_length());
+                Ok(capacities)
+            }
+            READ_CAPACITY_10 => {
+                let last = self
+                    .backend
+                    .block_count()
+                    .saturating_sub(1)
+                    .min(u32::MAX.into()) as u32;
+                let mut capacity = last.to_be_bytes().to_vec();
+                capacity.extend(self.backend.block_size().to_be_bytes());
+                Ok(capacity)
+            }
+            READ_10 | READ_12 => {
+                let lba = self.range(cbw)?;
+                // no more whole blocks than the host expects
+                let block_size = u64::from(self.backend.block_size());
+                let blocks = cbw.blocks().1.min(u64::from(cbw.data_length) / block_size);
+                let len = (blocks * block_size) as usize;
+                let mut data = vec![0; len];
+                self.backend.read_blocks(lba, &mut data).map_err(|err| {
+                    warn!("Failed to read {len} bytes at block {lba}: {err}");
+                    MEDIUM_ERROR
+                })?;
+                Ok(data)
+            }
+            VERIFY_10 => self.range(cbw).map(|_| vec![]),
+            SYNCHRONIZE_CACHE_10 => self.backend.flush().map(|()| vec![]).map_err(|err| {
+                warn!("Failed to flush: {err}");
+                WRITE_ERROR
+            }),
+            // WRITE(10) of no blocks
+            WRITE_10 if cbw.blocks().1 == 0 => Ok(vec![]),
+            READ_TOC if self.cdrom => self.toc(cbw),
+            GET_CONFIGURATION if self.cdrom => {
+                // the profile list, with the CD-ROM profile current
+                let mut configuration = 12u32.to_be_bytes().to_vec();
+                configuration.extend([0, 0]);
+                configuration.extend(PROFILE_CD_ROM.to_be_bytes());
+                configuration.extend([0x00, 0x00, 0x03, 0x04]);
+                configuration.extend(PROFILE_CD_ROM.to_be_bytes());
+                configuration.extend([0x01, 0x00]);
+                configuration.truncate(cbw.allocation_length());
+                Ok(configuration)
+            }
+            GET_EVENT_STATUS_NOTIFICATION if self.cdrom => {
+                // only polling is supported
+                if command[1] & 0x01 == 0 {
+                    return Err(INVALID_FIELD);
+                }
+                // media class: no change, disc present
+                let mut events = vec![0, 6, 0x04, 0x10, 0x00, 0x02, 0, 0];
+                events.truncate(cbw.allocation_length());
+                Ok(events)
+            }
+            SET_CD_SPEED if self.cdrom => Ok(vec![]),
+            op => {
+                debug!("Unsupported SCSI command {op:#04x}");
+                Err(INVALID_COMMAND)
+            }
+        }
+    }
+
+    /// Device-specific parameter of mode sense, with the write protection
+    fn device_specific(&self) -> u8 {
+        if self.read_only && !self.cdrom {
+            0x80
+        } else {
+            0
+        }
+    }
+
+    /// The mode pages `page` of the drive, none for disks
+    fn mode_pages(&self, page: u8) -> Vec<u8> {
+        if !self.cdrom || !matches!(page, CAPABILITIES_PAGE | 0x3F) {
+            return vec![];
+        }
+        let mut capabilities = vec![CAPABILITIES_PAGE, 20];
+        // reads CD-ROM only, no audio
+        capabilities.extend([0x00, 0x00, 0x00, 0x00]);
+        // tray, eject and lock
+        capabilities.push(0x29);
+        capabilities.resize(22, 0);
+        capabilities
+    }
+
+    /// TOC of a disc with a single data track, in READ TOC format 0 or 1
+    fn toc(&self, cbw: &Cbw) -> core::result::Result<Vec<u8>, Sense> {
+        let command = &cbw.command;
+        let msf = command[1] & 0x02 != 0;
+        // older hosts put the format in the vendor specific bits of the control byte
+        let format = match command[2] & 0x0F {
+            0 => command[9] >> 6,
+            format => format,
+        };
+        let address = |lba: u64| {
+            if msf {
+                // 2 seconds of lead-in, at 75 frames per second
+                let frames = lba + 150;
+                let (m, s, f) = (frames / (60 * 75), frames / 75 % 60, frames % 75);
+                [0, m.min(0xFF) as u8, s as u8, f as u8]
+            } else {
+                (lba.min(u32::MAX.into()) as u32).to_be_bytes()
+            }
+        };
+        let mut toc = match format {
+            0 => {
+                let mut toc = vec![0, 18, 1, 1];
+                toc.extend([0, DATA_TRACK, 1, 0]);
+                toc.extend(address(0));
+                // lead-out
+                toc.extend([0, DATA_TRACK, 0xAA, 0]);
+                toc.extend(address(self.backend.block_count()));
+                toc
+            }
+            1 => {
+                // the first track of the only session
+                let mut toc = vec![0, 10, 1, 1];
+                toc.extend([0, DATA_TRACK, 1, 0]);
+                toc.extend(address(0));
+                toc
+            }
+            _ => return Err(INVALID_FIELD),
+        };
+        toc.truncate(cbw.allocation_length());
+        Ok(toc)
+    }
+
+    /// Start the data or status phase of `cbw`
+    fn execute(&mut self, cbw: Cbw) {
+        debug!("SCSI command {:02x?}", &cbw.command[..10]);
+        if !cbw.data_in && cbw.data_length > 0 {
+            let target = match cbw.command[0] {
+                WRITE_10 if self.read_only => Err(WRITE_PROTECTED),
+                WRITE_10
+                    if cbw.blocks().1 * self.backend.block_size() as u64
+                        != u64::from(cbw.data_length) =>
+                {
+                    Err(INVALID_FIELD)
+                }
+                WRITE_10 => self.range(&cbw),
+                _ => Err(INVALID_COMMAND),
+            };
+            self.phase = Phase::DataOut {
+                cbw,
+                target,
+                received: 0,
+                pending: vec![],
+            };
+            return;
+        }
+
+        let (mut data, status) = match self.command(&cbw) {
+            Ok(data) => {
+                self.sense = NO_SENSE;
+                (data, COMMAND_PASSED)
+            }
+            Err(sense) => {
+                debug!("SCSI command failed with {sense:x?}");
+                self.sense = sense;
+                (vec![], COMMAND_FAILED)
+            }
+        };
+        data.truncate(cbw.data_length as usize);
+        // the host expects less than the blocks asked for (case 7 of the
+        // Bulk-Only Transport)
+        let status = match cbw.command[0] {
+            READ_10 | READ_12
+                if status == COMMAND_PASSED
+                    && cbw.blocks().1 * u64::from(self.backend.block_size())
+                        > u64::from(cbw.data_length) =>
+            {
+                PHASE_ERROR
+            }
+            _ => status,
+        };
+        self.phase = if cbw.data_length == 0 {
+            Phase::Status(cbw.status(0, status))
+        } else {
+            Phase::DataIn {
+                cbw,
+                data,
+                sent: 0,
+                status,
+            }
+        };
+    }
+
+    fn bulk_out(&mut self, req: &[u8]) -> Result<Vec<u8>> {
+        match &mut self.phase {
+            Phase::Command => {
+                let Some(cbw) = Cbw::parse(req) else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid CBW: {req:02x?}"),
+                    ));
+                };
+                self.execute(cbw);
+            }
+            Phase::DataOut {
+                cbw,
+                target,
+                received,
+                pending,
+            } => {
+                let len = req.len().min((cbw.data_length - *received) as usize);
+                *received += len as u32;
+                if let Ok(lba) = target {
+                    pending.extend_from_slice(&req[..len]);
+                    let block_size = self.backend.block_size() as usize;
+                    let blocks = pending.len() / block_size;
+                    if blocks > 0 {
+                        let data = &pending[..blocks * block_size];
+                        match self.backend.write_blocks(*lba, data) {
+                            Ok(()) => {
+                                *lba += blocks as u64;
+                                pending.drain(..blocks * block_size);
+                            }
+                            Err(err) => {
+                                warn!("Failed to write {} bytes at block {lba}: {err}", data.len());
+                                *target = Err(WRITE_ERROR);
+                            }
+                        }
+                    }
+                }
+                if *received == cbw.data_length {
+                    let (sense, status) = match *target {
+                        Ok(_) => (NO_SENSE, COMMAND_PASSED),
+                        Err(sense) => (sense, COMMAND_FAILED),
+                    };
+                    self.sense = sense;
+                    self.phase = Phase::Status(cbw.status(0, status));
+                }
+            }
+            Phase::DataIn { .. } | Phase::Status(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Bulk OUT while sending to the host",
+                ));
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn bulk_in(&mut self, transfer_buffer_length: u32) -> Result<Vec<u8>> {
+        match &mut self.phase {
+            Phase::DataIn {
+                cbw,
+                data,
+                sent,
+                status,
+            } => {
+                let len = (data.len() - *sent).min(transfer_buffer_length as usize);
+                let mut resp = take_scratch_buffer(len);
+                resp.extend_from_slice(&data[*sent..*sent + len]);
+                *sent += len;
+                if *sent == data.len() {
+                    let residue = cbw.data_length - data.len() as u32;
+                    self.phase = Phase::Status(cbw.status(residue, *status));
+                }
+                Ok(resp)
+            }
+            Phase::Status(csw) => {
+                let csw = std::mem::take(csw);
+                self.phase = Phase::Command;
+                Ok(csw)
+            }
+            Phase::Command | Phase::DataOut { .. } => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Bulk IN without data or status to send",
+            )),
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbMassStorageHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return match (setup.request_type, setup.request) {
+                (0b10100001, GET_MAX_LUN) => Ok(vec![0]),
+                (0b00100001, BULK_ONLY_RESET) => {
+                    self.phase = Phase::Command;
+                    Ok(vec![])
+                }
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Unsupported mass storage request: {setup:x?}"),
+                )),
+            };
+        }
+        match ep.direction() {
+            Direction::Out => self.bulk_out(req),
+            Direction::In => self.bulk_in(transfer_buffer_length),
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[GET_MAX_LUN, BULK_ONLY_RESET])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A USB drive with the single interface of `handler`
+    pub fn mass_storage(handler: UsbMassStorageHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::MassStorage as u8,
+            MSC_SCSI_SUBCLASS,
+            MSC_BOT_PROTOCOL,
+            None,
+            UsbMassStorageHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0003;
+        device.set_product_name("Virtual Drive");
+        // Bulk-Only Transport requires a serial number of at least 12 digits
+        device.set_serial_number("000000000001");
+        device
+    }
+}
+
+impl UsbGadgetFunction {
+    /// A mass storage function with the single interface of `handler`
+    ///
+    /// Bulk-Only Transport requires a serial number of at least 12 digits.
+    pub fn mass_storage(handler: UsbMassStorageHandler) -> Self {
+        Self::new("mass_storage", move |device| {
+            device.with_function(UsbFunction::single(
+                ClassCode::MassStorage as u8,
+                MSC_SCSI_SUBCLASS,
+                MSC_BOT_PROTOCOL,
+                None,
+                UsbMassStorageHandler::endpoints(),
+                shared_interface_handler(handler),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn cbw(tag: u32, data_length: u32, data_in: bool, command: &[u8]) -> Vec<u8> {
+        let mut cbw = CBW_SIGNATURE.to_le_bytes().to_vec();
+        cbw.extend(tag.to_le_bytes());
+        cbw.extend(data_length.to_le_bytes());
+        cbw.push(if data_in { 0x80 } else { 0 });
+        cbw.push(0);
+        cbw.push(command.len() as u8);
+        cbw.extend(command);
+        cbw.resize(CBW_LEN, 0);
+        cbw
+    }
+
+    struct Drive {
+        device: UsbDevice,
+        handler: UsbMassStorageHandler,
+    }
+
+    impl Drive {
+        fn out(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+            let ep = UsbMassStorageHandler::endpoints()[1];
+            let interface = &self.device.interfaces[0];
+            self.handler.handle_urb(
+                interface,
+                ep,
+                data.len() as u32,
+                SetupPacket::default(),
+                data,
+            )
+        }
+
+        fn read(&mut self, len: u32) -> Vec<u8> {
+            let ep = UsbMassStorageHandler::endpoints()[0];
+            let interface = &self.device.interfaces[0];
+            self.handler
+                .handle_urb(interface, ep, len, SetupPacket::default(), &[])
+                .unwrap()
+        }
+
+        /// Run a command with data to the device, or of `len` bytes from it
+        fn command(&mut self, command: &[u8], len: u32, data: &[u8]) -> (Vec<u8>, u32, u8) {
+            let tag = 0x1234;
+            let data_in = data.is_empty();
+            let length = if data_in { len } else { data.len() as u32 };
+            self.out(&cbw(tag, length, data_in, command)).unwrap();
+            let mut received = vec![];
+            if data_in && len > 0 {
+                received = self.read(len);
+            } else if !data_in {
+                self.out(data).unwrap();
+            }
+            let csw = self.read(13);
+            assert_eq!(csw[0..4], CSW_SIGNATURE.to_le_bytes());
+            assert_eq!(csw[4..8], tag.to_le_bytes());
+            let residue = u32::from_le_bytes(csw[8..12].try_into().unwrap());
+            (received, residue, csw[12])
+        }
+    }
+
+    #[test]
+    fn scsi_commands() {
+        setup_test_logger();
+        let mut image = vec![0; 4 * MSC_BLOCK_SIZE];
+        image[MSC_BLOCK_SIZE] = 0xAA;
+        let mut drive = Drive {
+            device: UsbDevice::mass_storage(UsbMassStorageHandler::from_memory(vec![])),
+            handler: UsbMassStorageHandler::from_memory(image),
+        };
+
+        let (inquiry, residue, status) = drive.command(&[INQUIRY, 0, 0, 0, 36, 0], 36, &[]);
+        assert_eq!((inquiry.len(), residue, status), (36, 0, COMMAND_PASSED));
+        assert_eq!(&inquiry[16..29], b"Virtual Drive");
+
+        let (capacity, _, _) =
+            drive.command(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], 8, &[]);
+        assert_eq!(capacity, [0, 0, 0, 3, 0, 0, 2, 0]);
+
+        let (data, _, status) = drive.command(&[READ_10, 0, 0, 0, 0, 1, 0, 0, 1, 0], 512, &[]);
+        assert_eq!((data[0], status), (0xAA, COMMAND_PASSED));
+
+        let block = vec![0x55; MSC_BLOCK_SIZE];
+        let (_, _, status) = drive.command(&[WRITE_10, 0, 0, 0, 0, 3, 0, 0, 1, 0], 0, &block);
+        assert_eq!(status, COMMAND_PASSED);
+        let (data, _, _) = drive.command(&[READ_10, 0, 0, 0, 0, 3, 0, 0, 1, 0], 512, &[]);
+        assert_eq!(data, block);
+
+        // past the end, the host learns why with REQUEST SENSE
+        let (data, residue, status) =
+            drive.command(&[READ_10, 0, 0, 0, 0, 4, 0, 0, 1, 0], 512, &[]);
+        assert_eq!((data.len(), residue, status), (0, 512, COMMAND_FAILED));
+        let (sense, _, _) = drive.command(&[REQUEST_SENSE, 0, 0, 0, 18, 0], 18, &[]);
+        assert_eq!((sense[2], sense[12]), (0x05, 0x21));
+
+        // fewer bytes than blocks asked for, only whole blocks are read
+        let (data, residue, status) =
+            drive.command(&[READ_10, 0, 0, 0, 0, 0, 0, 0, 3, 0], 700, &[]);
+        assert_eq!((data.len(), residue, status), (512, 188, PHASE_ERROR));
+        let (data, residue, status) =
+            drive.command(&[READ_12, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0], 0, &[]);
+        assert_eq!((data.len(), residue, status), (0, 0, PHASE_ERROR));
+
+        drive.handler.read_only = true;
+        let (_, _, status) = drive.command(&[WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0], 0, &block);
+        assert_eq!(status, COMMAND_FAILED);
+        let (data, _, _) = drive.command(&[READ_10, 0, 0, 0, 0, 0, 0, 0, 1, 0], 512, &[]);
+        assert_eq!(data, [0; MSC_BLOCK_SIZE]);
+        let (mode, _, _) = drive.command(&[MODE_SENSE_6, 0, 0x3F, 0, 4, 0], 4, &[]);
+        assert_eq!(mode[2], 0x80);
+
+        let (_, _, status) = drive.command(&[0xFF, 0, 0, 0, 0, 0], 0, &[]);
+        assert_eq!(status, COMMAND_FAILED);
+        assert!(drive.out(b"not a CBW").is_err());
+    }
+
+    #[test]
+    fn file_backend_blocks() {
+        setup_test_logger();
+        let path = std::env::temp_dir().join(format!("usbip-msc-{}.img", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(4 * 4096).unwrap();
+        let backend = FileBackend::new(file, 4096).unwrap();
+        let mut drive = Drive {
+            device: UsbDevice::mass_storage(UsbMassStorageHandler::from_memory(vec![])),
+            handler: UsbMassStorageHandler::new(backend),
+        };
+
+        let (capacity, _, _) =
+            drive.command(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], 8, &[]);
+        assert_eq!(capacity, [0, 0, 0, 3, 0, 0, 0x10, 0]);
+
+        // a block in packets smaller than it
+        let block = (0..4096).map(|i| i as u8).collect::<Vec<_>>();
+        drive
+            .out(&cbw(1, 4096, false, &[WRITE_10, 0, 0, 0, 0, 2, 0, 0, 1, 0]))
+            .unwrap();
+        for packet in block.chunks(512) {
+            drive.out(packet).unwrap();
+        }
+        assert_eq!(drive.read(13)[12], COMMAND_PASSED);
+        drive.handler.backend().flush().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[2 * 4096..3 * 4096], block);
+
+        let (data, _, status) = drive.command(&[READ_10, 0, 0, 0, 0, 2, 0, 0, 1, 0], 4096, &[]);
+        assert_eq!((data, status), (block, COMMAND_PASSED));
+        drop(drive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cdrom_commands() {
+        setup_test_logger();
+        let mut image = vec![0; 3 * CD_BLOCK_SIZE];
+        image[CD_BLOCK_SIZE..2 * CD_BLOCK_SIZE].fill(0xCD);
+        let backend = MemoryBackend::with_block_size(image, CD_BLOCK_SIZE);
+        let mut drive = Drive {
+            device: UsbDevice::mass_storage(UsbMassStorageHandler::from_memory(vec![])),
+            handler: UsbMassStorageHandler::cdrom(backend),
+        };
+
+        let (inquiry, _, _) = drive.command(&[INQUIRY, 0, 0, 0, 36, 0], 36, &[]);
+        assert_eq!(inquiry[0], 0x05);
+        let (capacity, _, _) =
+            drive.command(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], 8, &[]);
+        assert_eq!(capacity, [0, 0, 0, 2, 0, 0, 8, 0]);
+
+        let (toc, _, status) = drive.command(&[READ_TOC, 0, 0, 0, 0, 0, 0, 0, 20, 0], 20, &[]);
+        assert_eq!(status, COMMAND_PASSED);
+        assert_eq!(toc[..4], [0, 18, 1, 1]);
+        assert_eq!(toc[12..20], [0, DATA_TRACK, 0xAA, 0, 0, 0, 0, 3]);
+        let (toc, _, _) = drive.command(&[READ_TOC, 0x02, 0, 0, 0, 0, 0, 0, 20, 0], 20, &[]);
+        assert_eq!(toc[8..12], [0, 0, 2, 0]);
+        assert_eq!(toc[16..20], [0, 0, 2, 3]);
+        let (session, _, _) = drive.command(&[READ_TOC, 0, 1, 0, 0, 0, 0, 0, 12, 0], 12, &[]);
+        assert_eq!(session[..4], [0, 10, 1, 1]);
+
+        let (configuration, _, _) =
+            drive.command(&[GET_CONFIGURATION, 0, 0, 0, 0, 0, 0, 0, 16, 0], 16, &[]);
+        assert_eq!(configuration[6..8], PROFILE_CD_ROM.to_be_bytes());
+        let (mode, _, _) = drive.command(
+            &[MODE_SENSE_10, 0, CAPABILITIES_PAGE, 0, 0, 0, 0, 0, 30, 0],
+            30,
+            &[],
+        );
+        assert_eq!((mode[1], mode[8]), (28, CAPABILITIES_PAGE));
+
+        let (data, _, status) =
+            drive.command(&[READ_12, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0], 2048, &[]);
+        assert_eq!(status, COMMAND_PASSED);
+        assert!(data.iter().all(|&byte| byte == 0xCD));
+
+        let block = vec![0; CD_BLOCK_SIZE];
+        let (_, _, status) = drive.command(&[WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0], 0, &block);
+        assert_eq!(status, COMMAND_FAILED);
+        let (sense, _, _) = drive.command(&[REQUEST_SENSE, 0, 0, 0, 18, 0], 18, &[]);
+        assert_eq!(sense[2], 0x07);
+    }
+
+    #[tokio::test]
+    async fn drive_conforms() {
+        setup_test_logger();
+        let handler = UsbMassStorageHandler::from_memory(vec![0; 0x10000]);
+        let report = check_conformance(UsbDevice::mass_storage(handler)).await;
+        assert!(report.passed(), "{report}");
+    }
+}
@@ -0,0 +1,1208 @@
+//! Implement a MTP(Media Transfer Protocol) responder
+//!
+//! The host sees a phone-like device with one storage, whose files and
+//! folders come from a [MtpStorage]. Operations are exchanged as PTP
+//! containers on the bulk endpoints: a command from the host, an optional
+//! data phase in either direction, and a response from the device.
+use super::super::*;
+use std::collections::BTreeMap;
+
+// reference:
+// Media Transfer Protocol v.1.1, which extends PIMA 15740:2000 (PTP)
+// https://www.usb.org/document-library/media-transfer-protocol-v11-spec-and-mtp-v11-adopters-agreement
+
+/// bInterfaceSubClass of still image capture devices
+pub const STILL_IMAGE_SUBCLASS: u8 = 0x01;
+/// bInterfaceProtocol of PIMA 15740 bulk-only devices
+pub const PTP_PROTOCOL: u8 = 0x01;
+
+// class requests
+const CANCEL_REQUEST: u8 = 0x64;
+const DEVICE_RESET_REQUEST: u8 = 0x66;
+const GET_DEVICE_STATUS: u8 = 0x67;
+
+// container types
+const CONTAINER_COMMAND: u16 = 1;
+const CONTAINER_DATA: u16 = 2;
+const CONTAINER_RESPONSE: u16 = 3;
+/// Length of the header of a container before the parameters or data
+const CONTAINER_HEADER_LEN: usize = 12;
+
+// operations
+const GET_DEVICE_INFO: u16 = 0x1001;
+const OPEN_SESSION: u16 = 0x1002;
+const CLOSE_SESSION: u16 = 0x1003;
+const GET_STORAGE_IDS: u16 = 0x1004;
+const GET_STORAGE_INFO: u16 = 0x1005;
+const GET_NUM_OBJECTS: u16 = 0x1006;
+const GET_OBJECT_HANDLES: u16 = 0x1007;
+const GET_OBJECT_INFO: u16 = 0x1008;
+const GET_OBJECT: u16 = 0x1009;
+const DELETE_OBJECT: u16 = 0x100B;
+const SEND_OBJECT_INFO: u16 = 0x100C;
+const SEND_OBJECT: u16 = 0x100D;
+const GET_DEVICE_PROP_DESC: u16 = 0x1014;
+const GET_DEVICE_PROP_VALUE: u16 = 0x1015;
+const GET_PARTIAL_OBJECT: u16 = 0x101B;
+const GET_OBJECT_PROPS_SUPPORTED: u16 = 0x9801;
+const GET_OBJECT_PROP_DESC: u16 = 0x9802;
+const GET_OBJECT_PROP_VALUE: u16 = 0x9803;
+
+const OPERATIONS: [u16; 18] = [
+    GET_DEVICE_INFO,
+    OPEN_SESSION,
+    CLOSE_SESSION,
+    GET_STORAGE_IDS,
+    GET_STORAGE_INFO,
+    GET_NUM_OBJECTS,
+    GET_OBJECT_HANDLES,
+    GET_OBJECT_INFO,
+    GET_OBJECT,
+    DELETE_OBJECT,
+    SEND_OBJECT_INFO,
+    SEND_OBJECT,
+    GET_DEVICE_PROP_DESC,
+    GET_DEVICE_PROP_VALUE,
+    GET_PARTIAL_OBJECT,
+    GET_OBJECT_PROPS_SUPPORTED,
+    GET_OBJECT_PROP_DESC,
+    GET_OBJECT_PROP_VALUE,
+];
+
+// response codes
+const OK: u16 = 0x2001;
+const GENERAL_ERROR: u16 = 0x2002;
+const SESSION_NOT_OPEN: u16 = 0x2003;
+const OPERATION_NOT_SUPPORTED: u16 = 0x2005;
+const INVALID_STORAGE_ID: u16 = 0x2008;
+const INVALID_OBJECT_HANDLE: u16 = 0x2009;
+const DEVICE_PROP_NOT_SUPPORTED: u16 = 0x200A;
+const STORE_FULL: u16 = 0x200C;
+const ACCESS_DENIED: u16 = 0x200F;
+const NO_VALID_OBJECT_INFO: u16 = 0x2015;
+const INVALID_PARENT_OBJECT: u16 = 0x201A;
+const INVALID_PARAMETER: u16 = 0x201D;
+const SESSION_ALREADY_OPEN: u16 = 0x201E;
+const INVALID_OBJECT_PROP_CODE: u16 = 0xA801;
+
+// object formats
+const FORMAT_UNDEFINED: u16 = 0x3000;
+const FORMAT_ASSOCIATION: u16 = 0x3001;
+/// Association type of folders
+const GENERIC_FOLDER: u16 = 0x0001;
+
+// device properties
+const DEVICE_FRIENDLY_NAME: u16 = 0xD402;
+
+// object properties
+const PROP_STORAGE_ID: u16 = 0xDC01;
+const PROP_OBJECT_FORMAT: u16 = 0xDC02;
+const PROP_OBJECT_SIZE: u16 = 0xDC04;
+const PROP_OBJECT_FILE_NAME: u16 = 0xDC07;
+const PROP_PARENT_OBJECT: u16 = 0xDC0B;
+const PROP_PERSISTENT_UID: u16 = 0xDC41;
+const PROP_NAME: u16 = 0xDC44;
+
+const OBJECT_PROPS: [u16; 7] = [
+    PROP_STORAGE_ID,
+    PROP_OBJECT_FORMAT,
+    PROP_OBJECT_SIZE,
+    PROP_OBJECT_FILE_NAME,
+    PROP_PARENT_OBJECT,
+    PROP_PERSISTENT_UID,
+    PROP_NAME,
+];
+
+// data types
+const TYPE_UINT16: u16 = 0x0004;
+const TYPE_UINT32: u16 = 0x0006;
+const TYPE_UINT64: u16 = 0x0008;
+const TYPE_UINT128: u16 = 0x000A;
+const TYPE_STR: u16 = 0xFFFF;
+
+/// ID of the only storage
+const STORAGE_ID: u32 = 0x0001_0001;
+/// Parent of the objects in the root folder
+pub const MTP_ROOT: u32 = 0;
+/// Object handle and storage ID meaning all of them, or the root folder as a parent
+const ALL: u32 = 0xFFFF_FFFF;
+
+/// A file or folder of a [MtpStorage]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtpObjectInfo {
+    /// Handle of the folder holding the object, [MTP_ROOT] for the root folder
+    pub parent: u32,
+    pub name: String,
+    pub folder: bool,
+    /// Size of a file in bytes
+    pub size: u64,
+}
+
+/// The files and folders of a [UsbMtpHandler]
+///
+/// Objects are named by handles the storage picks, other than [MTP_ROOT] and
+/// `0xFFFFFFFF`. Errors of the kinds `NotFound`, `PermissionDenied` and
+/// `StorageFull` are reported to the host as such.
+pub trait MtpStorage: std::fmt::Debug {
+    /// Description of the storage shown by the host
+    fn description(&self) -> String {
+        "Internal Storage".to_string()
+    }
+
+    /// Size of the storage in bytes
+    fn capacity(&self) -> u64;
+
+    fn free_space(&self) -> u64;
+
+    /// Handles of the objects in the folder `parent`
+    fn children(&mut self, parent: u32) -> Result<Vec<u32>>;
+
+    fn info(&mut self, handle: u32) -> Result<MtpObjectInfo>;
+
+    /// Read up to `len` bytes of the file `handle` from `offset`
+    fn read(&mut self, handle: u32, offset: u64, len: usize) -> Result<Vec<u8>>;
+
+    /// Create an empty file or a folder, returning its handle
+    fn create(&mut self, info: &MtpObjectInfo) -> Result<u32>;
+
+    /// Replace the content of the file `handle`
+    fn write(&mut self, handle: u32, data: &[u8]) -> Result<()>;
+
+    /// Delete the file or folder `handle`, with the content of the folder
+    fn delete(&mut self, handle: u32) -> Result<()>;
+}
+
+fn not_found(handle: u32) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No MTP object {handle:#x}"),
+    )
+}
+
+#[derive(Debug, Default)]
+struct MemoryObjects {
+    capacity: u64,
+    objects: BTreeMap<u32, (MtpObjectInfo, Vec<u8>)>,
+    last_handle: u32,
+}
+
+impl MemoryObjects {
+    fn get(&self, handle: u32) -> Result<&(MtpObjectInfo, Vec<u8>)> {
+        self.objects.get(&handle).ok_or_else(|| not_found(handle))
+    }
+
+    fn used(&self) -> u64 {
+        self.objects.values().map(|(info, _)| info.size).sum()
+    }
+}
+
+/// Objects kept in memory
+///
+/// Clones share the objects, keep one to reach the files the host sends.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<MemoryObjects>>,
+}
+
+impl MemoryStorage {
+    /// An empty storage of `capacity` bytes
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(MemoryObjects {
+                capacity,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Add the file `name` to the folder `parent`, returning its handle
+    pub fn add_file(&self, parent: u32, name: &str, data: Vec<u8>) -> u32 {
+        let info = MtpObjectInfo {
+            parent,
+            name: name.to_string(),
+            folder: false,
+            size: data.len() as u64,
+        };
+        self.add(info, data)
+    }
+
+    /// Add the folder `name` to the folder `parent`, returning its handle
+    pub fn add_folder(&self, parent: u32, name: &str) -> u32 {
+        let info = MtpObjectInfo {
+            parent,
+            name: name.to_string(),
+            folder: true,
+            size: 0,
+        };
+        self.add(info, vec![])
+    }
+
+    fn add(&self, info: MtpObjectInfo, data: Vec<u8>) -> u32 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_handle += 1;
+        let handle = inner.last_handle;
+        inner.objects.insert(handle, (info, data));
+        handle
+    }
+
+    /// The handle of the object `name` in the folder `parent`
+    pub fn find(&self, parent: u32, name: &str) -> Option<u32> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .objects
+            .iter()
+            .find(|(_, (info, _))| info.parent == parent && info.name == name)
+            .map(|(handle, _)| *handle)
+    }
+
+    /// The content of the file `handle`
+    pub fn content(&self, handle: u32) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        inner.objects.get(&handle).map(|(_, data)| data.clone())
+    }
+}
+
+impl MtpStorage for MemoryStorage {
+    fn capacity(&self) -> u64 {
+        self.inner.lock().unwrap().capacity
+    }
+
+    fn free_space(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.capacity.saturating_sub(inner.used())
+    }
+
+    fn children(&mut self, parent: u32) -> Result<Vec<u32>> {
+        let inner = self.inner.lock().unwrap();
+        if parent != MTP_ROOT && !inner.get(parent)?.0.folder {
+            return Err(not_found(parent));
+        }
+        Ok(inner
+            .objects
+            .iter()
+            .filter(|(_, (info, _))| info.parent == parent)
+            .map(|(handle, _)| *handle)
+            .collect())
+    }
+
+    fn info(&mut self, handle: u32) -> Result<MtpObjectInfo> {
+        Ok(self.inner.lock().unwrap().get(handle)?.0.clone())
+    }
+
+    fn read(&mut self, handle: u32, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        let data = &inner.get(handle)?.1;
+        let start = (offset as usize).min(data.len());
+        let end = start.saturating_add(len).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn create(&mut self, info: &MtpObjectInfo) -> Result<u32> {
+        {
+            let inner = self.inner.lock().unwrap();
+            if info.parent != MTP_ROOT && !inner.get(info.parent)?.0.folder {
+                return Err(not_found(info.parent));
+            }
+        }
+        let info = MtpObjectInfo {
+            size: 0,
+            ..info.clone()
+        };
+        Ok(self.add(info, vec![]))
+    }
+
+    fn write(&mut self, handle: u32, data: &[u8]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let old_size = inner.get(handle)?.0.size;
+        if inner.used() - old_size + data.len() as u64 > inner.capacity {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "MTP storage is full",
+            ));
+        }
+        let (info, content) = inner.objects.get_mut(&handle).unwrap();
+        info.size = data.len() as u64;
+        *content = data.to_vec();
+        Ok(())
+    }
+
+    fn delete(&mut self, handle: u32) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.get(handle)?;
+        let mut doomed = vec![handle];
+        while let Some(handle) = doomed.pop() {
+            inner.objects.remove(&handle);
+            doomed.extend(
+                inner
+                    .objects
+                    .iter()
+                    .filter(|(_, (info, _))| info.parent == handle)
+                    .map(|(handle, _)| *handle),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The response code for an error of a [MtpStorage]
+fn response_code(err: std::io::Error) -> u16 {
+    warn!("MTP storage error: {err}");
+    match err.kind() {
+        std::io::ErrorKind::NotFound => INVALID_OBJECT_HANDLE,
+        std::io::ErrorKind::PermissionDenied => ACCESS_DENIED,
+        std::io::ErrorKind::StorageFull => STORE_FULL,
+        _ => GENERAL_ERROR,
+    }
+}
+
+/// Append a PTP string: the number of UTF-16 characters with the terminating null
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    let chars: Vec<u16> = s.encode_utf16().take(254).collect();
+    if chars.is_empty() {
+        buf.push(0);
+        return;
+    }
+    buf.push(chars.len() as u8 + 1);
+    for c in chars.into_iter().chain([0]) {
+        buf.extend(c.to_le_bytes());
+    }
+}
+
+/// Parse the PTP string at the start of `data`
+fn parse_string(data: &[u8]) -> Option<String> {
+    let len = *data.first()? as usize;
+    let chars: Vec<u16> = data
+        .get(1..1 + len * 2)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16(&chars).ok()
+}
+
+fn push_u16_array(buf: &mut Vec<u8>, values: &[u16]) {
+    buf.extend((values.len() as u32).to_le_bytes());
+    for value in values {
+        buf.extend(value.to_le_bytes());
+    }
+}
+
+fn push_u32_array(buf: &mut Vec<u8>, values: &[u32]) {
+    buf.extend((values.len() as u32).to_le_bytes());
+    for value in values {
+        buf.extend(value.to_le_bytes());
+    }
+}
+
+fn container(kind: u16, code: u16, transaction_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut res = ((CONTAINER_HEADER_LEN + payload.len()) as u32)
+        .to_le_bytes()
+        .to_vec();
+    res.extend(kind.to_le_bytes());
+    res.extend(code.to_le_bytes());
+    res.extend(transaction_id.to_le_bytes());
+    res.extend_from_slice(payload);
+    res
+}
+
+/// Data type and size of the object property `prop`
+fn prop_type(prop: u16) -> Option<(u16, usize)> {
+    match prop {
+        PROP_OBJECT_FORMAT => Some((TYPE_UINT16, 2)),
+        PROP_STORAGE_ID | PROP_PARENT_OBJECT => Some((TYPE_UINT32, 4)),
+        PROP_OBJECT_SIZE => Some((TYPE_UINT64, 8)),
+        PROP_PERSISTENT_UID => Some((TYPE_UINT128, 16)),
+        PROP_OBJECT_FILE_NAME | PROP_NAME => Some((TYPE_STR, 0)),
+        _ => None,
+    }
+}
+
+fn object_format(info: &MtpObjectInfo) -> u16 {
+    if info.folder {
+        FORMAT_ASSOCIATION
+    } else {
+        FORMAT_UNDEFINED
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Command {
+    code: u16,
+    transaction_id: u32,
+    params: Vec<u32>,
+}
+
+impl Command {
+    fn param(&self, index: usize) -> u32 {
+        self.params.get(index).copied().unwrap_or(0)
+    }
+}
+
+/// The data and parameters of a successful operation
+#[derive(Default)]
+struct Reply {
+    data: Option<Vec<u8>>,
+    params: Vec<u32>,
+}
+
+impl Reply {
+    fn data(data: Vec<u8>) -> Self {
+        Self {
+            data: Some(data),
+            params: vec![],
+        }
+    }
+}
+
+/// A handler of a MTP device with the objects of a [MtpStorage]
+#[derive(Debug)]
+pub struct UsbMtpHandler {
+    storage: Box<dyn MtpStorage + Send>,
+    /// Manufacturer in the device info
+    pub manufacturer: String,
+    /// Model in the device info
+    pub model: String,
+    pub serial_number: String,
+    /// The name of the device shown by the host
+    pub friendly_name: String,
+    session: Option<u32>,
+    /// A command waiting for its data from the host, with the data so far
+    pending: Option<(Command, Vec<u8>)>,
+    /// Containers for the host, in order
+    outgoing: VecDeque<Vec<u8>>,
+    /// The file created by the last SendObjectInfo, for SendObject
+    sent_object: Option<u32>,
+}
+
+impl UsbMtpHandler {
+    pub fn new(storage: impl MtpStorage + Send + 'static) -> Self {
+        Self {
+            storage: Box::new(storage),
+            manufacturer: "usbip".to_string(),
+            model: "Virtual MTP Device".to_string(),
+            serial_number: "0001".to_string(),
+            friendly_name: "Virtual MTP Device".to_string(),
+            session: None,
+            pending: None,
+            outgoing: VecDeque::new(),
+            sent_object: None,
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // events
+            UsbEndpoint {
+                address: 0x83,                                   // IN
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: 0x1C,                           // 28 bytes
+                interval: 10,
+            },
+        ]
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+        self.outgoing.clear();
+        self.sent_object = None;
+    }
+
+    fn control(&mut self, setup: SetupPacket) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b00100001, CANCEL_REQUEST) => {
+                debug!("MTP transaction cancelled");
+                self.reset();
+                Ok(vec![])
+            }
+            (0b00100001, DEVICE_RESET_REQUEST) => {
+                self.reset();
+                self.session = None;
+                Ok(vec![])
+            }
+            (0b10100001, GET_DEVICE_STATUS) => {
+                let mut status = 4u16.to_le_bytes().to_vec();
+                status.extend(OK.to_le_bytes());
+                Ok(status)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported MTP request: {setup:x?}"),
+            )),
+        }
+    }
+
+    fn bulk_out(&mut self, req: &[u8]) -> Result<()> {
+        if let Some((command, mut data)) = self.pending.take() {
+            data.extend_from_slice(req);
+            let Some(header) = data.get(..CONTAINER_HEADER_LEN) else {
+                self.pending = Some((command, data));
+                return Ok(());
+            };
+            let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let kind = u16::from_le_bytes(header[4..6].try_into().unwrap());
+            if kind != CONTAINER_DATA || len < CONTAINER_HEADER_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid MTP data container {header:x?}"),
+                ));
+            }
+            if data.len() < len {
+                self.pending = Some((command, data));
+                return Ok(());
+            }
+            self.execute(command, &data[CONTAINER_HEADER_LEN..len]);
+            return Ok(());
+        }
+        // a zero length packet ending a data phase
+        if req.is_empty() {
+            return Ok(());
+        }
+        let header = req.get(..CONTAINER_HEADER_LEN).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Short MTP container {req:x?}"),
+            )
+        })?;
+        let kind = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        if kind != CONTAINER_COMMAND {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Expected a MTP command, got {header:x?}"),
+            ));
+        }
+        let command = Command {
+            code: u16::from_le_bytes(header[6..8].try_into().unwrap()),
+            transaction_id: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+            params: req[CONTAINER_HEADER_LEN..]
+                .chunks_exact(4)
+                .take(5)
+                .map(|param| u32::from_le_bytes(param.try_into().unwrap()))
+                .collect(),
+        };
+        debug!("MTP command {command:x?}");
+        if matches!(command.code, SEND_OBJECT_INFO | SEND_OBJECT) {
+            self.pending = Some((command, vec![]));
+        } else {
+            self.execute(command, &[]);
+        }
+        Ok(())
+    }
+
+    /// Run `command` and queue its data and response for the host
+    fn execute(&mut self, command: Command, data: &[u8]) {
+        let needs_session = !matches!(command.code, GET_DEVICE_INFO | OPEN_SESSION);
+        let res = if needs_session && self.session.is_none() {
+            Err(SESSION_NOT_OPEN)
+        } else {
+            self.operation(&command, data)
+        };
+        let (code, params) = match res {
+            Ok(reply) => {
+                if let Some(data) = reply.data {
+                    self.outgoing.push_back(container(
+                        CONTAINER_DATA,
+                        command.code,
+                        command.transaction_id,
+                        &data,
+                    ));
+                }
+                (OK, reply.params)
+            }
+            Err(code) => (code, vec![]),
+        };
+        let payload: Vec<u8> = params.iter().flat_map(|p| p.to_le_bytes()).collect();
+        self.outgoing.push_back(container(
+            CONTAINER_RESPONSE,
+            code,
+            command.transaction_id,
+            &payload,
+        ));
+    }
+
+    fn operation(&mut self, command: &Command, data: &[u8]) -> std::result::Result<Reply, u16> {
+        match command.code {
+            GET_DEVICE_INFO => Ok(Reply::data(self.device_info())),
+            OPEN_SESSION => match (self.session, command.param(0)) {
+                (Some(session), _) => {
+                    warn!("MTP session {session} is already open");
+                    Err(SESSION_ALREADY_OPEN)
+                }
+                (None, 0) => Err(INVALID_PARAMETER),
+                (None, session) => {
+                    self.session = Some(session);
+                    Ok(Reply::default())
+                }
+            },
+            CLOSE_SESSION => {
+                self.session = None;
+                self.reset();
+                Ok(Reply::default())
+            }
+            GET_STORAGE_IDS => {
+                let mut ids = vec![];
+                push_u32_array(&mut ids, &[STORAGE_ID]);
+                Ok(Reply::data(ids))
+            }
+            GET_STORAGE_INFO => {
+                if command.param(0) != STORAGE_ID {
+                    return Err(INVALID_STORAGE_ID);
+                }
+                Ok(Reply::data(self.storage_info()))
+            }
+            GET_NUM_OBJECTS | GET_OBJECT_HANDLES => {
+                if !matches!(command.param(0), STORAGE_ID | ALL) {
+                    return Err(INVALID_STORAGE_ID);
+                }
+                let handles = self.object_handles(command.param(2), command.param(1))?;
+                if command.code == GET_NUM_OBJECTS {
+                    return Ok(Reply {
+                        data: None,
+                        params: vec![handles.len() as u32],
+                    });
+                }
+                let mut data = vec![];
+                push_u32_array(&mut data, &handles);
+                Ok(Reply::data(data))
+            }
+            GET_OBJECT_INFO => Ok(Reply::data(self.object_info(command.param(0))?)),
+            GET_OBJECT => {
+                let handle = command.param(0);
+                let info = self.storage.info(handle).map_err(response_code)?;
+                let data = self
+                    .storage
+                    .read(handle, 0, info.size as usize)
+                    .map_err(response_code)?;
+                Ok(Reply::data(data))
+            }
+            GET_PARTIAL_OBJECT => {
+                let data = self
+                    .storage
+                    .read(
+                        command.param(0),
+                        command.param(1) as u64,
+                        command.param(2) as usize,
+                    )
+                    .map_err(response_code)?;
+                Ok(Reply {
+                    params: vec![data.len() as u32],
+                    data: Some(data),
+                })
+            }
+            DELETE_OBJECT => {
+                let handles = match command.param(0) {
+                    ALL => self.storage.children(MTP_ROOT).map_err(response_code)?,
+                    handle => vec![handle],
+                };
+                for handle in handles {
+                    self.storage.delete(handle).map_err(response_code)?;
+                }
+                Ok(Reply::default())
+            }
+            SEND_OBJECT_INFO => self.send_object_info(command, data),
+            SEND_OBJECT => {
+                let handle = self.sent_object.take().ok_or(NO_VALID_OBJECT_INFO)?;
+                self.storage.write(handle, data).map_err(response_code)?;
+                Ok(Reply::default())
+            }
+            GET_DEVICE_PROP_DESC | GET_DEVICE_PROP_VALUE => {
+                if command.param(0) != DEVICE_FRIENDLY_NAME as u32 {
+                    return Err(DEVICE_PROP_NOT_SUPPORTED);
+                }
+                let mut data = vec![];
+                if command.code == GET_DEVICE_PROP_DESC {
+                    data.extend(DEVICE_FRIENDLY_NAME.to_le_bytes());
+                    data.extend(TYPE_STR.to_le_bytes());
+                    data.push(0); // get only
+                    push_string(&mut data, &self.friendly_name); // factory default
+                }
+                push_string(&mut data, &self.friendly_name);
+                if command.code == GET_DEVICE_PROP_DESC {
+                    data.push(0); // no form
+                }
+                Ok(Reply::data(data))
+            }
+            GET_OBJECT_PROPS_SUPPORTED => {
+                let mut data = vec![];
+                push_u16_array(&mut data, &OBJECT_PROPS);
+                Ok(Reply::data(data))
+            }
+            GET_OBJECT_PROP_DESC => {
+                let prop = command.param(0) as u16;
+                let (data_type, size) = prop_type(prop).ok_or(INVALID_OBJECT_PROP_CODE)?;
+                let mut data = prop.to_le_bytes().to_vec();
+                data.extend(data_type.to_le_bytes());
+                data.push(0); // get only
+                // default value
+                if data_type == TYPE_STR {
+                    push_string(&mut data, "");
+                } else {
+                    data.extend(std::iter::repeat_n(0, size));
+                }
+                data.extend(0u32.to_le_bytes()); // group code
+                data.push(0); // no form
+                Ok(Reply::data(data))
+            }
+            GET_OBJECT_PROP_VALUE => {
+                let handle = command.param(0);
+                let info = self.storage.info(handle).map_err(response_code)?;
+                let mut data = vec![];
+                match command.param(1) as u16 {
+                    PROP_STORAGE_ID => data.extend(STORAGE_ID.to_le_bytes()),
+                    PROP_OBJECT_FORMAT => data.extend(object_format(&info).to_le_bytes()),
+                    PROP_OBJECT_SIZE => data.extend(info.size.to_le_bytes()),
+                    PROP_OBJECT_FILE_NAME | PROP_NAME => push_string(&mut data, &info.name),
+                    PROP_PARENT_OBJECT => data.extend(info.parent.to_le_bytes()),
+                    PROP_PERSISTENT_UID => data.extend((handle as u128).to_le_bytes()),
+                    _ => return Err(INVALID_OBJECT_PROP_CODE),
+                }
+                Ok(Reply::data(data))
+            }
+            _ => Err(OPERATION_NOT_SUPPORTED),
+        }
+    }
+
+    fn device_info(&self) -> Vec<u8> {
+        let mut info = vec![];
+        info.extend(100u16.to_le_bytes()); // StandardVersion
+        info.extend(6u32.to_le_bytes()); // VendorExtensionID: Microsoft
+        info.extend(100u16.to_le_bytes()); // VendorExtensionVersion
+        push_string(&mut info, "microsoft.com: 1.0;");
+        info.extend(0u16.to_le_bytes()); // FunctionalMode
+        push_u16_array(&mut info, &OPERATIONS);
+        push_u16_array(&mut info, &[]); // EventsSupported
+        push_u16_array(&mut info, &[DEVICE_FRIENDLY_NAME]);
+        push_u16_array(&mut info, &[]); // CaptureFormats
+        push_u16_array(&mut info, &[FORMAT_UNDEFINED, FORMAT_ASSOCIATION]);
+        push_string(&mut info, &self.manufacturer);
+        push_string(&mut info, &self.model);
+        push_string(&mut info, "1.0"); // DeviceVersion
+        push_string(&mut info, &self.serial_number);
+        info
+    }
+
+    fn storage_info(&self) -> Vec<u8> {
+        let mut info = vec![];
+        info.extend(0x0003u16.to_le_bytes()); // StorageType: fixed RAM
+        info.extend(0x0002u16.to_le_bytes()); // FilesystemType: generic hierarchical
+        info.extend(0x0000u16.to_le_bytes()); // AccessCapability: read-write
+        info.extend(self.storage.capacity().to_le_bytes());
+        info.extend(self.storage.free_space().to_le_bytes());
+        info.extend(ALL.to_le_bytes()); // FreeSpaceInObjects: unused
+        push_string(&mut info, &self.storage.description());
+        push_string(&mut info, ""); // VolumeIdentifier
+        info
+    }
+
+    /// Handles of the objects in `parent`, all objects for 0, with an optional `format`
+    fn object_handles(&mut self, parent: u32, format: u32) -> std::result::Result<Vec<u32>, u16> {
+        let mut handles = match parent {
+            ALL => self.storage.children(MTP_ROOT).map_err(response_code)?,
+            MTP_ROOT => {
+                let mut all = vec![];
+                let mut folders = vec![MTP_ROOT];
+                while let Some(folder) = folders.pop() {
+                    for handle in self.storage.children(folder).map_err(response_code)? {
+                        if self.storage.info(handle).is_ok_and(|info| info.folder) {
+                            folders.push(handle);
+                        }
+                        all.push(handle);
+                    }
+                }
+                all
+            }
+            parent => self
+                .storage
+                .children(parent)
+                .map_err(|_| INVALID_PARENT_OBJECT)?,
+        };
+        if format != 0 {
+            handles.retain(|handle| {
+                self.storage
+                    .info(*handle)
+                    .is_ok_and(|info| object_format(&info) as u32 == format)
+            });
+        }
+        Ok(handles)
+    }
+
+    fn object_info(&mut self, handle: u32) -> std::result::Result<Vec<u8>, u16> {
+        let info = self.storage.info(handle).map_err(response_code)?;
+        let mut data = vec![];
+        data.extend(STORAGE_ID.to_le_bytes());
+        data.extend(object_format(&info).to_le_bytes());
+        data.extend(0u16.to_le_bytes()); // ProtectionStatus
+        data.extend((info.size.min(u32::MAX as u64) as u32).to_le_bytes());
+        data.extend(0u16.to_le_bytes()); // ThumbFormat
+        // thumbnail size, width and height, image width, height and bit depth
+        data.extend([0; 24]);
+        data.extend(info.parent.to_le_bytes());
+        let association = if info.folder { GENERIC_FOLDER } else { 0 };
+        data.extend(association.to_le_bytes());
+        data.extend(0u32.to_le_bytes()); // AssociationDesc
+        data.extend(0u32.to_le_bytes()); // SequenceNumber
+        push_string(&mut data, &info.name);
+        push_string(&mut data, ""); // CaptureDate
+        push_string(&mut data, ""); // ModificationDate
+        push_string(&mut data, ""); // Keywords
+        Ok(data)
+    }
+
+    fn send_object_info(
+        &mut self,
+        command: &Command,
+        data: &[u8],
+    ) -> std::result::Result<Reply, u16> {
+        if !matches!(command.param(0), STORAGE_ID | 0) {
+            return Err(INVALID_STORAGE_ID);
+        }
+        let parent = match command.param(1) {
+            ALL => MTP_ROOT,
+            parent => parent,
+        };
+        let (Some(format), Some(size), Some(name)) = (
+            data.get(4..6),
+            data.get(8..12),
+            data.get(52..).and_then(parse_string),
+        ) else {
+            return Err(INVALID_PARAMETER);
+        };
+        let info = MtpObjectInfo {
+            parent,
+            name,
+            folder: u16::from_le_bytes(format.try_into().unwrap()) == FORMAT_ASSOCIATION,
+            size: u32::from_le_bytes(size.try_into().unwrap()) as u64,
+        };
+        if !info.folder && info.size > self.storage.free_space() {
+            return Err(STORE_FULL);
+        }
+        let handle = self
+            .storage
+            .create(&info)
+            .map_err(|err| match response_code(err) {
+                INVALID_OBJECT_HANDLE => INVALID_PARENT_OBJECT,
+                code => code,
+            })?;
+        self.sent_object = (!info.folder).then_some(handle);
+        Ok(Reply {
+            data: None,
+            params: vec![STORAGE_ID, command.param(1), handle],
+        })
+    }
+}
+
+impl UsbInterfaceHandler for UsbMtpHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return self.control(setup);
+        }
+        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+            // no events
+            return Ok(vec![]);
+        }
+        if let Direction::Out = ep.direction() {
+            self.bulk_out(req)?;
+            return Ok(vec![]);
+        }
+        // a container longer than the transfer is read in several
+        let Some(mut resp) = self.outgoing.pop_front() else {
+            return Ok(vec![]);
+        };
+        if resp.len() > transfer_buffer_length as usize {
+            let rest = resp.split_off(transfer_buffer_length as usize);
+            self.outgoing.push_front(rest);
+        }
+        Ok(resp)
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[CANCEL_REQUEST, DEVICE_RESET_REQUEST, GET_DEVICE_STATUS])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A MTP device with the files of the storage of `handler`
+    pub fn mtp(handler: UsbMtpHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::Image as u8,
+            STILL_IMAGE_SUBCLASS,
+            PTP_PROTOCOL,
+            Some("MTP"),
+            UsbMtpHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0010;
+        device.set_product_name("Virtual MTP Device");
+        // Windows picks its MTP driver by the compatible ID
+        device.with_ms_os_descriptors(MsOsDescriptors {
+            vendor_code: 0x20,
+            compatible_ids: vec![MsCompatibleId {
+                first_interface: 0,
+                compatible_id: "MTP".to_string(),
+                sub_compatible_id: String::new(),
+            }],
+            descriptor_set: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// Run an operation, returning the data, response code and parameters
+    async fn transact(
+        device: &UsbDevice,
+        code: u16,
+        params: &[u32],
+        data: Option<&[u8]>,
+    ) -> (Option<Vec<u8>>, u16, Vec<u32>) {
+        let intf = &device.interfaces[0];
+        let [bulk_in, bulk_out, _] = UsbMtpHandler::endpoints().try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+        let payload: Vec<u8> = params.iter().flat_map(|p| p.to_le_bytes()).collect();
+        let command = container(CONTAINER_COMMAND, code, 7, &payload);
+        handler
+            .handle_urb(intf, bulk_out, 0, SetupPacket::default(), &command)
+            .unwrap();
+        if let Some(data) = data {
+            // split the data phase over two transfers
+            let data = container(CONTAINER_DATA, code, 7, data);
+            let (first, second) = data.split_at(data.len() / 2);
+            for part in [first, second] {
+                handler
+                    .handle_urb(intf, bulk_out, 0, SetupPacket::default(), part)
+                    .unwrap();
+            }
+        }
+        let mut data = None;
+        loop {
+            let resp = handler
+                .handle_urb(intf, bulk_in, 0x10000, SetupPacket::default(), &[])
+                .unwrap();
+            assert_eq!(
+                u32::from_le_bytes(resp[..4].try_into().unwrap()) as usize,
+                resp.len()
+            );
+            assert_eq!(resp[8..12], 7u32.to_le_bytes());
+            let kind = u16::from_le_bytes(resp[4..6].try_into().unwrap());
+            if kind == CONTAINER_DATA {
+                data = Some(resp[CONTAINER_HEADER_LEN..].to_vec());
+                continue;
+            }
+            assert_eq!(kind, CONTAINER_RESPONSE);
+            let params = resp[CONTAINER_HEADER_LEN..]
+                .chunks_exact(4)
+                .map(|p| u32::from_le_bytes(p.try_into().unwrap()))
+                .collect();
+            return (
+                data,
+                u16::from_le_bytes(resp[6..8].try_into().unwrap()),
+                params,
+            );
+        }
+    }
+
+    fn handles(data: &[u8]) -> Vec<u32> {
+        data[4..]
+            .chunks_exact(4)
+            .map(|h| u32::from_le_bytes(h.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn ptp_strings() {
+        let mut buf = vec![];
+        push_string(&mut buf, "héllo");
+        assert_eq!(buf[0], 6);
+        assert_eq!(buf.len(), 1 + 6 * 2);
+        assert_eq!(parse_string(&buf).unwrap(), "héllo");
+        let mut buf = vec![];
+        push_string(&mut buf, "");
+        assert_eq!(buf, [0]);
+        assert_eq!(parse_string(&buf).unwrap(), "");
+        assert!(parse_string(&[3, b'a', 0]).is_none());
+    }
+
+    #[tokio::test]
+    async fn mtp_objects() {
+        setup_test_logger();
+        let storage = MemoryStorage::new(1 << 20);
+        let hello = storage.add_file(MTP_ROOT, "hello.txt", b"hello world".to_vec());
+        let docs = storage.add_folder(MTP_ROOT, "docs");
+        let device = UsbDevice::mtp(UsbMtpHandler::new(storage.clone()));
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        let (info, code, _) = transact(&device, GET_DEVICE_INFO, &[], None).await;
+        assert_eq!(code, OK);
+        assert_eq!(info.unwrap()[..2], 100u16.to_le_bytes());
+        let (data, code, _) = transact(&device, GET_STORAGE_IDS, &[], None).await;
+        assert_eq!((data, code), (None, SESSION_NOT_OPEN));
+        assert_eq!(transact(&device, OPEN_SESSION, &[1], None).await.1, OK);
+        assert_eq!(
+            transact(&device, OPEN_SESSION, &[2], None).await.1,
+            SESSION_ALREADY_OPEN
+        );
+
+        let (data, _, _) = transact(&device, GET_STORAGE_IDS, &[], None).await;
+        assert_eq!(handles(&data.unwrap()), [STORAGE_ID]);
+        let (data, code, _) = transact(&device, GET_STORAGE_INFO, &[STORAGE_ID], None).await;
+        assert_eq!(code, OK);
+        assert_eq!(data.unwrap()[6..14], (1u64 << 20).to_le_bytes());
+
+        // the root folder
+        let (data, _, _) = transact(&device, GET_OBJECT_HANDLES, &[ALL, 0, ALL], None).await;
+        assert_eq!(handles(&data.unwrap()), [hello, docs]);
+        let (data, _, _) = transact(&device, GET_OBJECT_INFO, &[hello], None).await;
+        let data = data.unwrap();
+        assert_eq!(data[8..12], 11u32.to_le_bytes());
+        assert_eq!(parse_string(&data[52..]).unwrap(), "hello.txt");
+        let (data, _, _) = transact(&device, GET_OBJECT, &[hello], None).await;
+        assert_eq!(data.unwrap(), b"hello world");
+        let (data, _, params) = transact(&device, GET_PARTIAL_OBJECT, &[hello, 6, 100], None).await;
+        assert_eq!((data.unwrap(), params), (b"world".to_vec(), vec![5]));
+        let (_, code, _) = transact(&device, GET_OBJECT_INFO, &[0x1234], None).await;
+        assert_eq!(code, INVALID_OBJECT_HANDLE);
+
+        // upload a file into the folder
+        let mut info = vec![0; 52];
+        info[4..6].copy_from_slice(&FORMAT_UNDEFINED.to_le_bytes());
+        info[8..12].copy_from_slice(&5u32.to_le_bytes());
+        push_string(&mut info, "upload.bin");
+        info.extend([0, 0, 0]);
+        let (_, code, params) =
+            transact(&device, SEND_OBJECT_INFO, &[STORAGE_ID, docs], Some(&info)).await;
+        assert_eq!(code, OK);
+        let upload = params[2];
+        assert_eq!(params, [STORAGE_ID, docs, upload]);
+        let (_, code, _) = transact(&device, SEND_OBJECT, &[], Some(b"abcde")).await;
+        assert_eq!(code, OK);
+        assert_eq!(storage.find(docs, "upload.bin"), Some(upload));
+        assert_eq!(storage.content(upload).unwrap(), b"abcde");
+        let (_, code, _) = transact(&device, SEND_OBJECT, &[], Some(b"again")).await;
+        assert_eq!(code, NO_VALID_OBJECT_INFO);
+
+        // every object
+        let (data, _, _) = transact(&device, GET_OBJECT_HANDLES, &[ALL, 0, 0], None).await;
+        let mut all = handles(&data.unwrap());
+        all.sort();
+        assert_eq!(all, [hello, docs, upload]);
+
+        // properties
+        let (data, _, _) = transact(
+            &device,
+            GET_OBJECT_PROP_VALUE,
+            &[upload, PROP_NAME as u32],
+            None,
+        )
+        .await;
+        assert_eq!(parse_string(&data.unwrap()).unwrap(), "upload.bin");
+        let (data, _, _) = transact(
+            &device,
+            GET_OBJECT_PROP_VALUE,
+            &[upload, PROP_PARENT_OBJECT as u32],
+            None,
+        )
+        .await;
+        assert_eq!(data.unwrap(), docs.to_le_bytes());
+        let (data, _, _) = transact(
+            &device,
+            GET_OBJECT_PROP_DESC,
+            &[PROP_OBJECT_SIZE as u32, 0],
+            None,
+        )
+        .await;
+        assert_eq!(data.unwrap().len(), 2 + 2 + 1 + 8 + 4 + 1);
+        let (data, _, _) = transact(
+            &device,
+            GET_DEVICE_PROP_VALUE,
+            &[DEVICE_FRIENDLY_NAME as u32],
+            None,
+        )
+        .await;
+        assert_eq!(parse_string(&data.unwrap()).unwrap(), "Virtual MTP Device");
+
+        // deleting the folder deletes its files
+        assert_eq!(transact(&device, DELETE_OBJECT, &[docs], None).await.1, OK);
+        assert_eq!(storage.content(upload), None);
+        let (_, code, params) = transact(&device, GET_NUM_OBJECTS, &[ALL, 0, 0], None).await;
+        assert_eq!((code, params), (OK, vec![1]));
+
+        let get_device_status = SetupPacket {
+            request_type: 0b10100001,
+            request: GET_DEVICE_STATUS,
+            value: 0,
+            index: 0,
+            length: 4,
+        };
+        let status = device
+            .handle_urb(device.ep0_in, None, 4, get_device_status, &[])
+            .await
+            .unwrap();
+        assert_eq!(status, [0x04, 0x00, 0x01, 0x20]);
+    }
+
+    #[tokio::test]
+    async fn mtp_long_data() {
+        setup_test_logger();
+        let storage = MemoryStorage::new(1 << 20);
+        let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let file = storage.add_file(MTP_ROOT, "long.bin", data.clone());
+        let device = UsbDevice::mtp(UsbMtpHandler::new(storage));
+        transact(&device, OPEN_SESSION, &[1], None).await;
+
+        // read the data container in packets
+        let intf = &device.interfaces[0];
+        let [bulk_in, bulk_out, _] = UsbMtpHandler::endpoints().try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+        let command = container(CONTAINER_COMMAND, GET_OBJECT, 8, &file.to_le_bytes());
+        handler
+            .handle_urb(intf, bulk_out, 0, SetupPacket::default(), &command)
+            .unwrap();
+        let mut received = vec![];
+        while received.len() < CONTAINER_HEADER_LEN + data.len() {
+            let resp = handler
+                .handle_urb(intf, bulk_in, 512, SetupPacket::default(), &[])
+                .unwrap();
+            assert!(resp.len() <= 512);
+            received.extend(resp);
+        }
+        assert_eq!(received[CONTAINER_HEADER_LEN..], data);
+        let resp = handler
+            .handle_urb(intf, bulk_in, 512, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp[6..8], OK.to_le_bytes());
+    }
+}
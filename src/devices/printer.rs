@@ -0,0 +1,245 @@
+//! Implement a USB printer
+//!
+//! The host identifies the printer by its IEEE 1284 device ID, e.g. to pick
+//! a driver in CUPS, and sends the print jobs in the page description
+//! language of the printer on the bulk OUT endpoint.
+use super::super::*;
+
+// reference:
+// Universal Serial Bus Device Class Definition for Printing Devices, version 1.1
+// https://www.usb.org/sites/default/files/usbprint11a021811.pdf
+
+/// bInterfaceSubClass of printers
+pub const PRINTER_SUBCLASS: u8 = 0x01;
+/// bInterfaceProtocol of bi-directional printers, with a bulk IN endpoint
+pub const PRINTER_PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+// class requests
+const GET_DEVICE_ID: u8 = 0x00;
+const GET_PORT_STATUS: u8 = 0x01;
+const SOFT_RESET: u8 = 0x02;
+
+/// Port status bit of a printer without errors
+pub const PORT_STATUS_NOT_ERROR: u8 = 1 << 3;
+/// Port status bit of a selected, i.e. online, printer
+pub const PORT_STATUS_SELECT: u8 = 1 << 4;
+/// Port status bit of a printer out of paper
+pub const PORT_STATUS_PAPER_EMPTY: u8 = 1 << 5;
+
+/// Receives the print stream of a [UsbPrinterHandler]
+pub type PrinterCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+/// A handler of a printer, passing the print stream to a callback
+pub struct UsbPrinterHandler {
+    /// IEEE 1284 device ID, e.g. `MFG:Acme;MDL:Printer;CMD:PS;CLS:PRINTER;`
+    pub device_id: String,
+    /// Status returned by GET_PORT_STATUS, a set of `PORT_STATUS_*` bits
+    pub port_status: u8,
+    /// Data returned on the bulk IN endpoint, e.g. replies to PJL queries
+    pub tx_buffer: Vec<u8>,
+    callback: PrinterCallback,
+}
+
+impl std::fmt::Debug for UsbPrinterHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbPrinterHandler")
+            .field("device_id", &self.device_id)
+            .field("port_status", &self.port_status)
+            .field("tx_buffer", &self.tx_buffer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UsbPrinterHandler {
+    pub fn new(device_id: &str, callback: impl FnMut(&[u8]) + Send + 'static) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            port_status: PORT_STATUS_NOT_ERROR | PORT_STATUS_SELECT,
+            tx_buffer: vec![],
+            callback: Box::new(callback),
+        }
+    }
+
+    /// A handler sending the print stream to the returned channel
+    ///
+    /// Every bulk OUT transfer is a message, the end of a job is up to the
+    /// page description language, e.g. a PJL UEL or the end of a PostScript file.
+    pub fn channel(device_id: &str) -> (Self, tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let callback = move |data: &[u8]| {
+            let _ = sender.send(data.to_vec());
+        };
+        (Self::new(device_id, callback), receiver)
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+        ]
+    }
+
+    fn control(&mut self, setup: SetupPacket, transfer_buffer_length: u32) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b10100001, GET_DEVICE_ID) => {
+                // the length is big endian and includes itself
+                let len = self.device_id.len() + 2;
+                let mut resp = (len as u16).to_be_bytes().to_vec();
+                resp.extend_from_slice(self.device_id.as_bytes());
+                resp.truncate(transfer_buffer_length as usize);
+                Ok(resp)
+            }
+            (0b10100001, GET_PORT_STATUS) => Ok(vec![self.port_status]),
+            (0b00100001, SOFT_RESET) => {
+                debug!("Printer soft reset");
+                self.tx_buffer.clear();
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported printer request: {setup:x?}"),
+            )),
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbPrinterHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return self.control(setup, transfer_buffer_length);
+        }
+        if let Direction::Out = ep.direction() {
+            if !req.is_empty() {
+                (self.callback)(req);
+            }
+            return Ok(vec![]);
+        }
+        let len = self.tx_buffer.len().min(transfer_buffer_length as usize);
+        Ok(self.tx_buffer.drain(..len).collect())
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[GET_DEVICE_ID, GET_PORT_STATUS, SOFT_RESET])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A bi-directional printer passing its print jobs to `handler`
+    pub fn printer(handler: UsbPrinterHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::Printer as u8,
+            PRINTER_SUBCLASS,
+            PRINTER_PROTOCOL_BIDIRECTIONAL,
+            Some("Printer"),
+            UsbPrinterHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x000F;
+        device.set_product_name("Virtual Printer");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn printer_jobs() {
+        setup_test_logger();
+        let device_id = "MFG:Generic;MDL:Virtual Printer;CMD:PS;CLS:PRINTER;";
+        let (handler, mut jobs) = UsbPrinterHandler::channel(device_id);
+        let device = UsbDevice::printer(handler);
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        let get_device_id = SetupPacket {
+            request_type: 0b10100001,
+            request: GET_DEVICE_ID,
+            value: 0,
+            index: 0,
+            length: 1024,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 1024, get_device_id, &[])
+            .await
+            .unwrap();
+        assert_eq!(resp[..2], ((device_id.len() + 2) as u16).to_be_bytes());
+        assert_eq!(&resp[2..], device_id.as_bytes());
+
+        let get_port_status = SetupPacket {
+            request_type: 0b10100001,
+            request: GET_PORT_STATUS,
+            value: 0,
+            index: 0,
+            length: 1,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 1, get_port_status, &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [PORT_STATUS_NOT_ERROR | PORT_STATUS_SELECT]);
+
+        let intf = &device.interfaces[0];
+        let [bulk_out, bulk_in] = UsbPrinterHandler::endpoints().try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+        handler
+            .handle_urb(
+                intf,
+                bulk_out,
+                9,
+                SetupPacket::default(),
+                b"%!PS\nshowpage\n",
+            )
+            .unwrap();
+        assert_eq!(jobs.try_recv().unwrap(), b"%!PS\nshowpage\n");
+
+        let printer = handler
+            .as_any()
+            .downcast_mut::<UsbPrinterHandler>()
+            .unwrap();
+        printer.port_status |= PORT_STATUS_PAPER_EMPTY;
+        printer.tx_buffer.extend_from_slice(b"@PJL INFO STATUS\r\n");
+        let resp = handler
+            .handle_urb(intf, bulk_in, 512, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp, b"@PJL INFO STATUS\r\n");
+        drop(handler);
+
+        let resp = device
+            .handle_urb(device.ep0_in, None, 1, get_port_status, &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [0x38]);
+    }
+}
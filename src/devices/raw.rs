@@ -0,0 +1,281 @@
+//! Implement a vendor specific device with channels for its endpoints
+//!
+//! Prototyping a proprietary protocol only needs the endpoints and a
+//! callback for the control requests: the data of each OUT endpoint comes
+//! out of a channel, and the data sent to the channel of an IN endpoint
+//! goes to the host.
+use super::super::*;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, error::SendError};
+
+/// Handles the class and vendor control requests of a [RawDeviceHandler]
+///
+/// Called with the setup packet and the data of OUT requests, returns the
+/// data of IN requests. Errors stall the request.
+pub type RawControlCallback = Box<dyn FnMut(SetupPacket, &[u8]) -> Result<Vec<u8>> + Send>;
+
+/// The other ends of the channels of a [RawDeviceHandler]
+#[derive(Debug, Default)]
+pub struct RawEndpoints {
+    /// Senders of the data for the host, by IN endpoint address
+    pub inputs: HashMap<u8, UnboundedSender<Vec<u8>>>,
+    /// Receivers of the data from the host, by OUT endpoint address
+    pub outputs: HashMap<u8, UnboundedReceiver<Vec<u8>>>,
+}
+
+impl RawEndpoints {
+    /// Queue data for the host on an IN endpoint
+    ///
+    /// Fails if there is no such endpoint or the device is gone.
+    pub fn send(&self, address: u8, data: Vec<u8>) -> std::result::Result<(), SendError<Vec<u8>>> {
+        match self.inputs.get(&address) {
+            Some(sender) => sender.send(data),
+            None => Err(SendError(data)),
+        }
+    }
+
+    /// Wait for the next transfer from the host on an OUT endpoint
+    ///
+    /// Returns `None` if there is no such endpoint or the device is gone.
+    pub async fn recv(&mut self, address: u8) -> Option<Vec<u8>> {
+        self.outputs.get_mut(&address)?.recv().await
+    }
+}
+
+#[derive(Debug)]
+struct RawInEndpoint {
+    receiver: UnboundedReceiver<Vec<u8>>,
+    /// Rest of a message longer than the last transfer
+    pending: VecDeque<u8>,
+}
+
+/// A handler of a vendor specific interface, connecting its endpoints to channels
+///
+/// IN transfers with no data queued complete without data.
+pub struct RawDeviceHandler {
+    endpoints: Vec<UsbEndpoint>,
+    inputs: HashMap<u8, RawInEndpoint>,
+    outputs: HashMap<u8, UnboundedSender<Vec<u8>>>,
+    control: Arc<Mutex<RawControlCallback>>,
+}
+
+impl std::fmt::Debug for RawDeviceHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawDeviceHandler")
+            .field("endpoints", &self.endpoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RawDeviceHandler {
+    /// A handler for `endpoints`, with a channel for each of them
+    pub fn new(
+        endpoints: Vec<UsbEndpoint>,
+        control: impl FnMut(SetupPacket, &[u8]) -> Result<Vec<u8>> + Send + 'static,
+    ) -> (Self, RawEndpoints) {
+        let mut channels = RawEndpoints::default();
+        let mut inputs = HashMap::new();
+        let mut outputs = HashMap::new();
+        for ep in &endpoints {
+            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            match ep.direction() {
+                Direction::In => {
+                    channels.inputs.insert(ep.address, sender);
+                    let pending = VecDeque::new();
+                    inputs.insert(ep.address, RawInEndpoint { receiver, pending });
+                }
+                Direction::Out => {
+                    channels.outputs.insert(ep.address, receiver);
+                    outputs.insert(ep.address, sender);
+                }
+            }
+        }
+        let handler = Self {
+            endpoints,
+            inputs,
+            outputs,
+            control: Arc::new(Mutex::new(Box::new(control))),
+        };
+        (handler, channels)
+    }
+
+    pub fn endpoints(&self) -> Vec<UsbEndpoint> {
+        self.endpoints.clone()
+    }
+}
+
+fn handle_control(
+    control: &Mutex<RawControlCallback>,
+    setup: SetupPacket,
+    req: &[u8],
+) -> Result<Vec<u8>> {
+    let mut resp = (control.lock().unwrap())(setup, req)?;
+    resp.truncate(setup.length as usize);
+    Ok(resp)
+}
+
+impl UsbInterfaceHandler for RawDeviceHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return handle_control(&self.control, setup, req);
+        }
+        match ep.direction() {
+            Direction::Out => {
+                if let Some(sender) = self.outputs.get(&ep.address)
+                    && sender.send(req.to_vec()).is_err()
+                {
+                    debug!("Dropping data written to endpoint {:02x}", ep.address);
+                }
+                Ok(vec![])
+            }
+            Direction::In => {
+                let Some(input) = self.inputs.get_mut(&ep.address) else {
+                    return Ok(vec![]);
+                };
+                if input.pending.is_empty()
+                    && let Ok(data) = input.receiver.try_recv()
+                {
+                    input.pending.extend(data);
+                }
+                let len = input.pending.len().min(transfer_buffer_length as usize);
+                Ok(input.pending.drain(..len).collect())
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Passes the control requests to the device to the callback too
+struct RawControlHandler {
+    control: Arc<Mutex<RawControlCallback>>,
+}
+
+impl std::fmt::Debug for RawControlHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawControlHandler").finish_non_exhaustive()
+    }
+}
+
+impl UsbDeviceHandler for RawControlHandler {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        handle_control(&self.control, setup, req)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A vendor specific device with the endpoints and control requests of `handler`
+    ///
+    /// Class and vendor requests to both the device and the interface go to
+    /// the control callback.
+    pub fn raw(vendor_id: u16, product_id: u16, handler: RawDeviceHandler) -> Self {
+        let device_handler = RawControlHandler {
+            control: handler.control.clone(),
+        };
+        let mut device = Self::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                None,
+                handler.endpoints(),
+                shared_interface_handler(handler),
+            )
+            .with_device_handler(shared_device_handler(device_handler));
+        device.vendor_id = vendor_id;
+        device.product_id = product_id;
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loopback::UsbLoopbackHandler;
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn raw_endpoints() {
+        setup_test_logger();
+        let endpoints = UsbLoopbackHandler::endpoints(1, 0);
+        let (handler, mut channels) =
+            RawDeviceHandler::new(endpoints.clone(), |setup, req| {
+                match (setup.request_type, setup.request) {
+                    (0xC0, 0x01) => Ok(b"v1.0".to_vec()),
+                    (0x40, 0x02) => Ok(req.to_vec()),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Unsupported request",
+                    )),
+                }
+            });
+        let device = UsbDevice::raw(0x1234, 0x5678, handler);
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        let get_version = SetupPacket {
+            request_type: 0xC0,
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 2, get_version, &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, b"v1");
+        let unsupported = SetupPacket {
+            request: 0x03,
+            ..get_version
+        };
+        assert!(
+            device
+                .handle_urb(device.ep0_in, None, 2, unsupported, &[])
+                .await
+                .is_err()
+        );
+
+        let intf = &device.interfaces[0];
+        let [bulk_out, bulk_in] = endpoints.try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+        handler
+            .handle_urb(intf, bulk_out, 4, SetupPacket::default(), b"ping")
+            .unwrap();
+        assert_eq!(channels.recv(0x01).await.unwrap(), b"ping");
+
+        let read = |handler: &mut Box<dyn UsbInterfaceHandler + Send>, len| {
+            handler
+                .handle_urb(intf, bulk_in, len, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert_eq!(read(&mut handler, 64), b"");
+        channels.send(0x81, b"pong".to_vec()).unwrap();
+        channels.send(0x81, b"!".to_vec()).unwrap();
+        assert_eq!(read(&mut handler, 3), b"pon");
+        assert_eq!(read(&mut handler, 64), b"g");
+        assert_eq!(read(&mut handler, 64), b"!");
+        assert!(channels.send(0x82, vec![]).is_err());
+    }
+}
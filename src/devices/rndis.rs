@@ -0,0 +1,728 @@
+//! Implement a RNDIS(Remote NDIS) network adapter
+//!
+//! Windows binds its inbox RNDIS driver to [UsbDevice::rndis], while it
+//! needs a third party driver for CDC ECM. RNDIS messages are exchanged as
+//! encapsulated commands and responses on the control endpoint, announced by
+//! notifications on the interrupt endpoint, and every frame on the bulk
+//! endpoints is wrapped in a packet message. The frames go to the same
+//! [EthernetBackend] as the CDC network functions.
+use super::super::*;
+use super::cdc::*;
+
+// reference:
+// Remote NDIS Specification, revision 1.1
+// https://learn.microsoft.com/en-us/windows-hardware/drivers/network/remote-ndis--rndis-2
+
+// bInterfaceClass, bInterfaceSubClass and bInterfaceProtocol of RNDIS
+const RNDIS_SUBCLASS: u8 = 0x01;
+const RNDIS_PROTOCOL: u8 = 0x03;
+
+// class requests
+const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+
+// message types
+const PACKET_MSG: u32 = 0x0000_0001;
+const INITIALIZE_MSG: u32 = 0x0000_0002;
+const HALT_MSG: u32 = 0x0000_0003;
+const QUERY_MSG: u32 = 0x0000_0004;
+const SET_MSG: u32 = 0x0000_0005;
+const RESET_MSG: u32 = 0x0000_0006;
+const INDICATE_STATUS_MSG: u32 = 0x0000_0007;
+const KEEPALIVE_MSG: u32 = 0x0000_0008;
+/// Set in the type of the completion of a message
+const COMPLETION: u32 = 0x8000_0000;
+
+// status codes
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_NOT_SUPPORTED: u32 = 0xC000_00BB;
+const STATUS_INVALID_DATA: u32 = 0xC001_0015;
+const STATUS_MEDIA_CONNECT: u32 = 0x4001_000B;
+const STATUS_MEDIA_DISCONNECT: u32 = 0x4001_000C;
+
+// object identifiers
+const OID_GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+const OID_GEN_HARDWARE_STATUS: u32 = 0x0001_0102;
+const OID_GEN_MEDIA_SUPPORTED: u32 = 0x0001_0103;
+const OID_GEN_MEDIA_IN_USE: u32 = 0x0001_0104;
+const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+const OID_GEN_LINK_SPEED: u32 = 0x0001_0107;
+const OID_GEN_TRANSMIT_BLOCK_SIZE: u32 = 0x0001_010A;
+const OID_GEN_RECEIVE_BLOCK_SIZE: u32 = 0x0001_010B;
+const OID_GEN_VENDOR_ID: u32 = 0x0001_010C;
+const OID_GEN_VENDOR_DESCRIPTION: u32 = 0x0001_010D;
+const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010E;
+const OID_GEN_MAXIMUM_TOTAL_SIZE: u32 = 0x0001_0111;
+const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+const OID_GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+const OID_GEN_RNDIS_CONFIG_PARAMETER: u32 = 0x0001_021B;
+const OID_GEN_XMIT_OK: u32 = 0x0002_0101;
+const OID_GEN_RCV_OK: u32 = 0x0002_0102;
+const OID_GEN_XMIT_ERROR: u32 = 0x0002_0103;
+const OID_GEN_RCV_ERROR: u32 = 0x0002_0104;
+const OID_GEN_RCV_NO_BUFFER: u32 = 0x0002_0105;
+const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+const OID_802_3_CURRENT_ADDRESS: u32 = 0x0101_0102;
+const OID_802_3_MULTICAST_LIST: u32 = 0x0101_0103;
+const OID_802_3_MAXIMUM_LIST_SIZE: u32 = 0x0101_0104;
+const OID_802_3_MAC_OPTIONS: u32 = 0x0101_0105;
+const OID_802_3_RCV_ERROR_ALIGNMENT: u32 = 0x0102_0101;
+const OID_802_3_XMIT_ONE_COLLISION: u32 = 0x0102_0102;
+const OID_802_3_XMIT_MORE_COLLISIONS: u32 = 0x0102_0103;
+
+const SUPPORTED_OIDS: &[u32] = &[
+    OID_GEN_SUPPORTED_LIST,
+    OID_GEN_HARDWARE_STATUS,
+    OID_GEN_MEDIA_SUPPORTED,
+    OID_GEN_MEDIA_IN_USE,
+    OID_GEN_MAXIMUM_FRAME_SIZE,
+    OID_GEN_LINK_SPEED,
+    OID_GEN_TRANSMIT_BLOCK_SIZE,
+    OID_GEN_RECEIVE_BLOCK_SIZE,
+    OID_GEN_VENDOR_ID,
+    OID_GEN_VENDOR_DESCRIPTION,
+    OID_GEN_CURRENT_PACKET_FILTER,
+    OID_GEN_MAXIMUM_TOTAL_SIZE,
+    OID_GEN_MEDIA_CONNECT_STATUS,
+    OID_GEN_PHYSICAL_MEDIUM,
+    OID_GEN_XMIT_OK,
+    OID_GEN_RCV_OK,
+    OID_GEN_XMIT_ERROR,
+    OID_GEN_RCV_ERROR,
+    OID_GEN_RCV_NO_BUFFER,
+    OID_802_3_PERMANENT_ADDRESS,
+    OID_802_3_CURRENT_ADDRESS,
+    OID_802_3_MULTICAST_LIST,
+    OID_802_3_MAXIMUM_LIST_SIZE,
+    OID_802_3_MAC_OPTIONS,
+    OID_802_3_RCV_ERROR_ALIGNMENT,
+    OID_802_3_XMIT_ONE_COLLISION,
+    OID_802_3_XMIT_MORE_COLLISIONS,
+];
+
+// NDIS packet filter bits
+const NDIS_PACKET_TYPE_DIRECTED: u32 = 0x01;
+const NDIS_PACKET_TYPE_MULTICAST: u32 = 0x02;
+const NDIS_PACKET_TYPE_ALL_MULTICAST: u32 = 0x04;
+const NDIS_PACKET_TYPE_BROADCAST: u32 = 0x08;
+const NDIS_PACKET_TYPE_PROMISCUOUS: u32 = 0x20;
+
+/// Length of the header of a packet message
+const PACKET_HEADER_LEN: usize = 44;
+/// The longest frame, without the frame check sequence
+const MAX_FRAME_LEN: u32 = 1514;
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// The CDC packet filter with the bits of the NDIS packet filter `filter`
+fn cdc_packet_filter(filter: u32) -> u16 {
+    [
+        (NDIS_PACKET_TYPE_DIRECTED, PACKET_TYPE_DIRECTED),
+        (NDIS_PACKET_TYPE_MULTICAST, PACKET_TYPE_MULTICAST),
+        (NDIS_PACKET_TYPE_ALL_MULTICAST, PACKET_TYPE_ALL_MULTICAST),
+        (NDIS_PACKET_TYPE_BROADCAST, PACKET_TYPE_BROADCAST),
+        (NDIS_PACKET_TYPE_PROMISCUOUS, PACKET_TYPE_PROMISCUOUS),
+    ]
+    .into_iter()
+    .filter(|(ndis, _)| filter & ndis != 0)
+    .fold(0, |res, (_, cdc)| res | cdc)
+}
+
+/// The frames of the packet messages in the bulk transfer `data`
+fn parse_packets(data: &[u8]) -> Result<Vec<&[u8]>> {
+    let invalid = |offset: usize, msg: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid RNDIS packet message at {offset}: {msg}"),
+        )
+    };
+    let mut frames = vec![];
+    let mut offset = 0;
+    // hosts may pad transfers of a multiple of the packet size by a byte
+    while offset + 8 <= data.len() {
+        let msg_type = u32_at(data, offset).unwrap();
+        let msg_len = u32_at(data, offset + 4).unwrap() as usize;
+        if msg_type != PACKET_MSG {
+            return Err(invalid(offset, &format!("type {msg_type:#x}")));
+        }
+        let msg = data
+            .get(offset..offset + msg_len)
+            .filter(|_| msg_len >= PACKET_HEADER_LEN)
+            .ok_or_else(|| invalid(offset, &format!("length {msg_len}")))?;
+        // DataOffset is relative to its own field
+        let data_offset = u32_at(msg, 8).unwrap() as usize + 8;
+        let data_len = u32_at(msg, 12).unwrap() as usize;
+        let frame = msg
+            .get(data_offset..data_offset + data_len)
+            .ok_or_else(|| {
+                invalid(
+                    offset,
+                    &format!("data of {data_len} bytes at {data_offset}"),
+                )
+            })?;
+        frames.push(frame);
+        offset += msg_len;
+    }
+    Ok(frames)
+}
+
+/// A packet message with `frame`
+fn packet(frame: &[u8]) -> Vec<u8> {
+    let mut msg = vec![];
+    msg.extend(PACKET_MSG.to_le_bytes());
+    msg.extend(((PACKET_HEADER_LEN + frame.len()) as u32).to_le_bytes());
+    // DataOffset, relative to its own field
+    msg.extend((PACKET_HEADER_LEN as u32 - 8).to_le_bytes());
+    msg.extend((frame.len() as u32).to_le_bytes());
+    // no out of band data, per packet info, VC handle or reserved
+    msg.extend([0; PACKET_HEADER_LEN - 16]);
+    msg.extend_from_slice(frame);
+    msg
+}
+
+/// A handler of the data interface of a RNDIS function
+///
+/// The frames of the packet messages on the bulk endpoints are exchanged
+/// with an [EthernetBackend]. Use [UsbDevice::rndis] to add the control
+/// interface, which answers the RNDIS messages of the host.
+#[derive(Debug)]
+pub struct UsbRndisHandler {
+    /// The MAC address of the host side of the link
+    pub mac_address: [u8; 6],
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl UsbRndisHandler {
+    pub fn new(mac_address: [u8; 6], backend: impl EthernetBackend + Send + 'static) -> Self {
+        let state = NetworkState::new(backend);
+        // no frames until the host sets a packet filter
+        state.lock().unwrap().packet_filter = 0;
+        Self { mac_address, state }
+    }
+}
+
+impl UsbInterfaceHandler for UsbRndisHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if ep.is_ep0() {
+            return Ok(vec![]);
+        }
+        if let Direction::Out = ep.direction() {
+            match parse_packets(req) {
+                Ok(frames) => {
+                    for frame in frames {
+                        state.backend.transmit(frame);
+                    }
+                }
+                Err(err) => warn!("{err}"),
+            }
+            return Ok(vec![]);
+        }
+        while let Some(frame) = state.backend.receive() {
+            if PACKET_HEADER_LEN + frame.len() > transfer_buffer_length as usize {
+                warn!(
+                    "Dropping frame of {} bytes, longer than the transfer of {transfer_buffer_length}",
+                    frame.len()
+                );
+            } else if state.accepts(&self.mac_address, &frame) {
+                return Ok(packet(&frame));
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The control interface of a RNDIS function
+#[derive(Debug)]
+struct RndisControlHandler {
+    state: Arc<Mutex<NetworkState>>,
+    mac_address: [u8; 6],
+    /// The NDIS packet filter set by the host
+    packet_filter: u32,
+    initialized: bool,
+    /// The link speed last indicated to the host
+    reported: Option<u32>,
+    responses: VecDeque<Vec<u8>>,
+    /// RESPONSE_AVAILABLE notifications not sent yet
+    notifications: usize,
+}
+
+impl RndisControlHandler {
+    fn respond(&mut self, response: Vec<u8>) {
+        self.responses.push_back(response);
+        self.notifications += 1;
+    }
+
+    /// The completion of `msg_type` with `fields` after the request ID and status
+    fn completion(msg_type: u32, request_id: u32, status: u32, fields: &[u32]) -> Vec<u8> {
+        let mut res = vec![];
+        res.extend((COMPLETION | msg_type).to_le_bytes());
+        res.extend(((16 + 4 * fields.len()) as u32).to_le_bytes());
+        res.extend(request_id.to_le_bytes());
+        res.extend(status.to_le_bytes());
+        for field in fields {
+            res.extend(field.to_le_bytes());
+        }
+        res
+    }
+
+    /// The value of `oid`, or `None` if not supported
+    fn query(&self, state: &mut NetworkState, oid: u32) -> Option<Vec<u8>> {
+        let speed = state.backend.link_speed();
+        let value: u32 = match oid {
+            OID_GEN_SUPPORTED_LIST => {
+                return Some(
+                    SUPPORTED_OIDS
+                        .iter()
+                        .flat_map(|oid| oid.to_le_bytes())
+                        .collect(),
+                );
+            }
+            OID_GEN_VENDOR_DESCRIPTION => return Some(b"Virtual RNDIS\0".to_vec()),
+            OID_802_3_PERMANENT_ADDRESS | OID_802_3_CURRENT_ADDRESS => {
+                return Some(self.mac_address.to_vec());
+            }
+            OID_802_3_MULTICAST_LIST => return Some(vec![]),
+            // ready
+            OID_GEN_HARDWARE_STATUS => 0,
+            // 802.3
+            OID_GEN_MEDIA_SUPPORTED | OID_GEN_MEDIA_IN_USE => 0,
+            OID_GEN_MAXIMUM_FRAME_SIZE => MAX_FRAME_LEN - 14,
+            // in units of 100 bps
+            OID_GEN_LINK_SPEED => speed.unwrap_or(0) / 100,
+            OID_GEN_TRANSMIT_BLOCK_SIZE | OID_GEN_RECEIVE_BLOCK_SIZE => MAX_FRAME_LEN,
+            // no IEEE OUI
+            OID_GEN_VENDOR_ID => 0x00FF_FFFF,
+            OID_GEN_CURRENT_PACKET_FILTER => self.packet_filter,
+            OID_GEN_MAXIMUM_TOTAL_SIZE => PACKET_HEADER_LEN as u32 + MAX_FRAME_LEN,
+            // connected or disconnected
+            OID_GEN_MEDIA_CONNECT_STATUS => speed.is_none() as u32,
+            // unspecified
+            OID_GEN_PHYSICAL_MEDIUM => 0,
+            // all groups are accepted
+            OID_802_3_MAXIMUM_LIST_SIZE => 1,
+            OID_GEN_XMIT_OK
+            | OID_GEN_RCV_OK
+            | OID_GEN_XMIT_ERROR
+            | OID_GEN_RCV_ERROR
+            | OID_GEN_RCV_NO_BUFFER
+            | OID_802_3_MAC_OPTIONS
+            | OID_802_3_RCV_ERROR_ALIGNMENT
+            | OID_802_3_XMIT_ONE_COLLISION
+            | OID_802_3_XMIT_MORE_COLLISIONS => 0,
+            _ => return None,
+        };
+        Some(value.to_le_bytes().to_vec())
+    }
+
+    /// Handle the encapsulated command `msg`, queueing its completion
+    fn command(&mut self, msg: &[u8]) -> Result<()> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid RNDIS message: {msg:x?}"),
+            )
+        };
+        let msg_type = u32_at(msg, 0).ok_or_else(invalid)?;
+        let request_id = u32_at(msg, 8).unwrap_or_default();
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        match msg_type {
+            INITIALIZE_MSG => {
+                self.initialized = true;
+                self.reported = state.backend.link_speed();
+                let fields = [
+                    1,                                        // MajorVersion
+                    0,                                        // MinorVersion
+                    1,                                        // DeviceFlags: connectionless
+                    0,                                        // Medium: 802.3
+                    1,                                        // MaxPacketsPerTransfer
+                    PACKET_HEADER_LEN as u32 + MAX_FRAME_LEN, // MaxTransferSize
+                    0,                                        // PacketAlignmentFactor
+                    0,                                        // AFListOffset
+                    0,                                        // AFListSize
+                ];
+                self.respond(Self::completion(
+                    msg_type,
+                    request_id,
+                    STATUS_SUCCESS,
+                    &fields,
+                ));
+            }
+            HALT_MSG => {
+                // no completion
+                self.initialized = false;
+                self.packet_filter = 0;
+                state.packet_filter = 0;
+            }
+            QUERY_MSG => {
+                let oid = u32_at(msg, 12).ok_or_else(invalid)?;
+                let response = match self.query(&mut state, oid) {
+                    Some(value) => {
+                        // InformationBufferOffset is relative to the request ID
+                        let fields = [value.len() as u32, 16];
+                        let mut res =
+                            Self::completion(msg_type, request_id, STATUS_SUCCESS, &fields);
+                        res.extend(value);
+                        let len = res.len() as u32;
+                        res[4..8].copy_from_slice(&len.to_le_bytes());
+                        res
+                    }
+                    None => {
+                        debug!("Unsupported RNDIS query of OID {oid:#010x}");
+                        Self::completion(msg_type, request_id, STATUS_NOT_SUPPORTED, &[0, 0])
+                    }
+                };
+                self.respond(response);
+            }
+            SET_MSG => {
+                let oid = u32_at(msg, 12).ok_or_else(invalid)?;
+                let len = u32_at(msg, 16).ok_or_else(invalid)? as usize;
+                let offset = u32_at(msg, 20).ok_or_else(invalid)? as usize + 8;
+                let value = msg.get(offset..offset + len);
+                let status = match (oid, value) {
+                    (OID_GEN_CURRENT_PACKET_FILTER, Some(value)) if len == 4 => {
+                        self.packet_filter = u32_at(value, 0).unwrap();
+                        state.packet_filter = cdc_packet_filter(self.packet_filter);
+                        debug!("RNDIS packet filter {:#x}", self.packet_filter);
+                        STATUS_SUCCESS
+                    }
+                    (OID_802_3_MULTICAST_LIST | OID_GEN_RNDIS_CONFIG_PARAMETER, Some(_)) => {
+                        STATUS_SUCCESS
+                    }
+                    (_, None) => STATUS_INVALID_DATA,
+                    _ => STATUS_NOT_SUPPORTED,
+                };
+                self.respond(Self::completion(msg_type, request_id, status, &[]));
+            }
+            RESET_MSG => {
+                // Status in place of the request ID, and AddressingReset
+                let res = Self::completion(msg_type, STATUS_SUCCESS, 0, &[]);
+                self.respond(res);
+            }
+            KEEPALIVE_MSG => {
+                self.respond(Self::completion(msg_type, request_id, STATUS_SUCCESS, &[]));
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    /// Indicate a change of the link speed of the backend to the host
+    fn indicate_status(&mut self) {
+        let speed = self.state.lock().unwrap().backend.link_speed();
+        if !self.initialized || self.reported == speed {
+            return;
+        }
+        debug!("RNDIS link speed {:?}", speed);
+        self.reported = speed;
+        let status = match speed {
+            Some(_) => STATUS_MEDIA_CONNECT,
+            None => STATUS_MEDIA_DISCONNECT,
+        };
+        let mut msg = vec![];
+        msg.extend(INDICATE_STATUS_MSG.to_le_bytes());
+        msg.extend(20u32.to_le_bytes());
+        msg.extend(status.to_le_bytes());
+        // StatusBufferLength, StatusBufferOffset
+        msg.extend([0; 8]);
+        self.respond(msg);
+    }
+}
+
+impl UsbInterfaceHandler for RndisControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if !ep.is_ep0() {
+            // interrupt in
+            self.indicate_status();
+            if self.notifications == 0 {
+                return Ok(vec![]);
+            }
+            self.notifications -= 1;
+            // RESPONSE_AVAILABLE
+            return Ok(vec![0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        }
+        match (setup.request_type, setup.request) {
+            (0b00100001, SEND_ENCAPSULATED_COMMAND) => {
+                self.command(req)?;
+                Ok(vec![])
+            }
+            // a single zero byte without a response
+            (0b10100001, GET_ENCAPSULATED_RESPONSE) => {
+                Ok(self.responses.pop_front().unwrap_or(vec![0x00]))
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported RNDIS request: {setup:x?}"),
+            )),
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[SEND_ENCAPSULATED_COMMAND, GET_ENCAPSULATED_RESPONSE])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![
+            // Header
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x00, // Header
+            0x10, 0x01, // CDC 1.2
+            // Call Management
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x01, // Call Management
+            0x00, // bmCapabilities
+            0x01, // bDataInterface
+            // ACM
+            0x04, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x02, // ACM
+            0x00, // Capabilities
+            // Union
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x06, // Union
+            0x00, // bControlInterface
+            0x01, // bSubordinateInterface0
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A network adapter with the RNDIS control and data interfaces of `handler`
+    pub fn rndis(handler: UsbRndisHandler) -> Self {
+        let control = RndisControlHandler {
+            state: handler.state.clone(),
+            mac_address: handler.mac_address,
+            packet_filter: 0,
+            initialized: false,
+            reported: None,
+            responses: VecDeque::new(),
+            notifications: 0,
+        };
+        let endpoints = UsbCdcEcmHandler::endpoints();
+        let mut device = Self::new(0)
+            .with_interface(
+                ClassCode::WirelessController as u8,
+                RNDIS_SUBCLASS,
+                RNDIS_PROTOCOL,
+                None,
+                endpoints[..1].to_vec(),
+                shared_interface_handler(control),
+            )
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                Some("RNDIS"),
+                endpoints[1..].to_vec(),
+                shared_interface_handler(handler),
+            )
+            .with_interface_association(
+                0,
+                2,
+                ClassCode::WirelessController as u8,
+                RNDIS_SUBCLASS,
+                RNDIS_PROTOCOL,
+                Some("Virtual RNDIS"),
+            );
+        // Miscellaneous Device Class, Interface Association Descriptor
+        device.device_class = ClassCode::Misc as u8;
+        device.device_subclass = 0x02;
+        device.device_protocol = 0x01;
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x000B;
+        device.set_product_name("Virtual RNDIS Ethernet");
+        // the compatible ID of the inbox driver, for Windows versions matching no class
+        device.with_ms_os_descriptors(MsOsDescriptors {
+            vendor_code: 0x20,
+            compatible_ids: vec![MsCompatibleId {
+                first_interface: 0,
+                compatible_id: "RNDIS".to_string(),
+                sub_compatible_id: "5162001".to_string(),
+            }],
+            descriptor_set: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn message(msg_type: u32, request_id: u32, fields: &[u32]) -> Vec<u8> {
+        let mut msg = vec![];
+        msg.extend(msg_type.to_le_bytes());
+        msg.extend(((12 + 4 * fields.len()) as u32).to_le_bytes());
+        msg.extend(request_id.to_le_bytes());
+        for field in fields {
+            msg.extend(field.to_le_bytes());
+        }
+        msg
+    }
+
+    /// Send `msg`, and read the response announced on the interrupt endpoint
+    async fn command(device: &UsbDevice, msg: Vec<u8>) -> Vec<u8> {
+        let setup = SetupPacket {
+            request_type: 0b00100001,
+            request: SEND_ENCAPSULATED_COMMAND,
+            value: 0,
+            index: 0,
+            length: msg.len() as u16,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, setup, &msg)
+            .await
+            .unwrap();
+        let control = &device.interfaces[0];
+        let notify = UsbCdcEcmHandler::endpoints()[0];
+        let mut handler = control.handler.lock().await;
+        let notification = handler
+            .handle_urb(control, notify, 8, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(notification, [1, 0, 0, 0, 0, 0, 0, 0]);
+        drop(handler);
+        let setup = SetupPacket {
+            request_type: 0b10100001,
+            request: GET_ENCAPSULATED_RESPONSE,
+            value: 0,
+            index: 0,
+            length: 0x400,
+        };
+        device
+            .handle_urb(device.ep0_in, None, 0x400, setup, &[])
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rndis_messages() {
+        setup_test_logger();
+        let mac = [0x02, 0, 0, 0, 0, 0x01];
+        let (backend, to_host, mut from_host) = ChannelEthernetBackend::new();
+        let device = UsbDevice::rndis(UsbRndisHandler::new(mac, backend));
+        let desc = device.configuration_descriptor(0xFFFF);
+        verify_descriptor(&desc);
+        assert_eq!(device.interface_associations.len(), 1);
+
+        let [_, bulk_in, bulk_out] = UsbCdcEcmHandler::endpoints().try_into().unwrap();
+
+        let init = command(&device, message(INITIALIZE_MSG, 1, &[1, 0, 0x4000])).await;
+        assert_eq!(u32_at(&init, 0), Some(COMPLETION | INITIALIZE_MSG));
+        assert_eq!(u32_at(&init, 4), Some(init.len() as u32));
+        assert_eq!(init.len(), 52);
+        assert_eq!(
+            (u32_at(&init, 8), u32_at(&init, 12)),
+            (Some(1), Some(STATUS_SUCCESS))
+        );
+
+        let query = |oid| message(QUERY_MSG, 2, &[oid, 0, 20, 0]);
+        let address = command(&device, query(OID_802_3_PERMANENT_ADDRESS)).await;
+        assert_eq!(u32_at(&address, 12), Some(STATUS_SUCCESS));
+        assert_eq!(
+            (u32_at(&address, 16), u32_at(&address, 20)),
+            (Some(6), Some(16))
+        );
+        assert_eq!(address[24..], mac);
+        let status = command(&device, query(OID_GEN_MEDIA_CONNECT_STATUS)).await;
+        assert_eq!(u32_at(&status, 24), Some(0));
+        let unknown = command(&device, query(0x00FF_0000)).await;
+        assert_eq!(u32_at(&unknown, 12), Some(STATUS_NOT_SUPPORTED));
+
+        // no frames to the host until a packet filter is set
+        to_host.send([0xFF; 60].to_vec()).unwrap();
+        let data = &device.interfaces[1];
+        let read = || async {
+            let mut handler = data.handler.lock().await;
+            handler
+                .handle_urb(data, bulk_in, 0x4000, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        assert!(read().await.is_empty());
+        let filter = NDIS_PACKET_TYPE_DIRECTED | NDIS_PACKET_TYPE_BROADCAST;
+        let set = message(
+            SET_MSG,
+            3,
+            &[OID_GEN_CURRENT_PACKET_FILTER, 4, 20, 0, filter],
+        );
+        let set = command(&device, set).await;
+        assert_eq!(
+            (u32_at(&set, 8), u32_at(&set, 12)),
+            (Some(3), Some(STATUS_SUCCESS))
+        );
+        let query = command(&device, query(OID_GEN_CURRENT_PACKET_FILTER)).await;
+        assert_eq!(u32_at(&query, 24), Some(filter));
+
+        to_host.send([0xFF; 60].to_vec()).unwrap();
+        let msg = read().await;
+        assert_eq!(msg, packet(&[0xFF; 60]));
+        assert_eq!(parse_packets(&msg).unwrap(), [[0xFF; 60]]);
+
+        let mut frames = packet(&[0x01; 60]);
+        frames.extend(packet(&[0x02; 64]));
+        let mut handler = data.handler.lock().await;
+        handler
+            .handle_urb(data, bulk_out, 0x4000, SetupPacket::default(), &frames)
+            .unwrap();
+        drop(handler);
+        assert_eq!(from_host.recv().await.unwrap(), [0x01; 60]);
+        assert_eq!(from_host.recv().await.unwrap(), [0x02; 64]);
+        frames.truncate(100);
+        assert!(parse_packets(&frames).is_err());
+
+        let keepalive = command(&device, message(KEEPALIVE_MSG, 4, &[])).await;
+        assert_eq!(u32_at(&keepalive, 0), Some(COMPLETION | KEEPALIVE_MSG));
+
+        // the link goes down with the channel
+        drop(to_host);
+        let indication = command(&device, message(KEEPALIVE_MSG, 5, &[])).await;
+        assert_eq!(u32_at(&indication, 0), Some(COMPLETION | KEEPALIVE_MSG));
+        let setup = SetupPacket {
+            request_type: 0b10100001,
+            request: GET_ENCAPSULATED_RESPONSE,
+            value: 0,
+            index: 0,
+            length: 0x400,
+        };
+        let indication = device.handle_urb(device.ep0_in, None, 0x400, setup, &[]);
+        let indication = indication.await.unwrap();
+        assert_eq!(u32_at(&indication, 0), Some(INDICATE_STATUS_MSG));
+        assert_eq!(u32_at(&indication, 8), Some(STATUS_MEDIA_DISCONNECT));
+        let none = device.handle_urb(device.ep0_in, None, 0x400, setup, &[]);
+        assert_eq!(none.await.unwrap(), [0x00]);
+    }
+}
@@ -0,0 +1,279 @@
+//! Export a serial port of the host as a CDC ACM device
+//!
+//! [UsbCdcAcmSerialHandler] forwards the bulk endpoints of a
+//! [cdc::UsbCdcAcmHandler] to a port opened with tokio-serial, and applies
+//! the line coding, control lines and breaks the remote host requests.
+use super::super::*;
+use cdc::*;
+use std::time::Instant;
+use tokio_serial::{DataBits, Parity, SerialPort, SerialStream, StopBits};
+
+/// A CDC ACM handler bridged to a serial port of the host
+#[derive(Debug)]
+pub struct UsbCdcAcmSerialHandler {
+    acm: UsbCdcAcmHandler,
+    events: tokio::sync::mpsc::UnboundedReceiver<CdcAcmEvent>,
+    port: SerialStream,
+    /// Bytes from the host not written to the port yet
+    unwritten: Vec<u8>,
+    /// When to end a break of a limited duration
+    break_until: Option<Instant>,
+}
+
+impl UsbCdcAcmSerialHandler {
+    /// Bridge to `port`, which must be registered with the tokio runtime
+    pub fn new(port: SerialStream) -> Self {
+        let mut acm = UsbCdcAcmHandler::new();
+        let events = acm.events();
+        let mut res = Self {
+            acm,
+            events,
+            port,
+            unwritten: vec![],
+            break_until: None,
+        };
+        res.apply_line_coding(res.acm.line_coding);
+        res
+    }
+
+    /// Open the serial port at `path`, e.g. `/dev/ttyUSB0` or `COM3`, in a tokio runtime
+    pub fn open(path: &str) -> Result<Self> {
+        let line_coding = LineCoding::default();
+        let port = SerialStream::open(&tokio_serial::new(path, line_coding.baud_rate))?;
+        Ok(Self::new(port))
+    }
+
+    /// The CDC ACM state of the bridge, e.g. the line coding set by the host
+    pub fn acm(&mut self) -> &mut UsbCdcAcmHandler {
+        &mut self.acm
+    }
+
+    /// The bridged serial port
+    pub fn port(&mut self) -> &mut SerialStream {
+        &mut self.port
+    }
+
+    fn apply_line_coding(&mut self, line_coding: LineCoding) {
+        let data_bits = match line_coding.data_bits {
+            5 => Some(DataBits::Five),
+            6 => Some(DataBits::Six),
+            7 => Some(DataBits::Seven),
+            8 => Some(DataBits::Eight),
+            _ => None,
+        };
+        let parity = match line_coding.parity {
+            0 => Some(Parity::None),
+            1 => Some(Parity::Odd),
+            2 => Some(Parity::Even),
+            _ => None,
+        };
+        let stop_bits = match line_coding.stop_bits {
+            0 => Some(StopBits::One),
+            2 => Some(StopBits::Two),
+            _ => None,
+        };
+        let (Some(data_bits), Some(parity), Some(stop_bits)) = (data_bits, parity, stop_bits)
+        else {
+            warn!("Unsupported line coding {line_coding:?}");
+            return;
+        };
+        let res = self
+            .port
+            .set_baud_rate(line_coding.baud_rate)
+            .and_then(|_| self.port.set_data_bits(data_bits))
+            .and_then(|_| self.port.set_parity(parity))
+            .and_then(|_| self.port.set_stop_bits(stop_bits));
+        if let Err(err) = res {
+            warn!("Failed to set line coding {line_coding:?}: {err}");
+        }
+    }
+
+    /// Apply the requests of the host to the port
+    fn apply_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            let res = match event {
+                CdcAcmEvent::LineCoding(line_coding) => {
+                    self.apply_line_coding(line_coding);
+                    Ok(())
+                }
+                CdcAcmEvent::ControlLineState { dtr, rts } => self
+                    .port
+                    .write_data_terminal_ready(dtr)
+                    .and_then(|_| self.port.write_request_to_send(rts)),
+                CdcAcmEvent::Break(0) => {
+                    self.break_until = None;
+                    self.port.clear_break()
+                }
+                CdcAcmEvent::Break(duration) => {
+                    // 0xFFFF lasts until a break of 0
+                    self.break_until = (duration != 0xFFFF).then(|| {
+                        Instant::now() + std::time::Duration::from_millis(duration as u64)
+                    });
+                    self.port.set_break()
+                }
+            };
+            if let Err(err) = res {
+                warn!("Failed to apply {event:?} to the serial port: {err}");
+            }
+        }
+        if self
+            .break_until
+            .is_some_and(|until| Instant::now() >= until)
+        {
+            self.break_until = None;
+            if let Err(err) = self.port.clear_break() {
+                warn!("Failed to clear break of the serial port: {err}");
+            }
+        }
+    }
+
+    /// Write as much of the bytes from the host as the port takes
+    fn flush(&mut self) -> Result<()> {
+        while !self.unwritten.is_empty() {
+            match self.port.try_write(&self.unwritten) {
+                Ok(len) => {
+                    self.unwritten.drain(..len);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UsbInterfaceHandler for UsbCdcAcmSerialHandler {
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.attributes != EndpointAttributes::Bulk as u8 {
+            let res = self
+                .acm
+                .handle_urb(interface, ep, transfer_buffer_length, setup, req);
+            self.apply_events();
+            return res;
+        }
+        self.apply_events();
+        if let Direction::Out = ep.direction() {
+            self.unwritten.extend_from_slice(req);
+            self.flush()?;
+            return Ok(vec![]);
+        }
+        self.flush()?;
+        let mut buffer = vec![0; transfer_buffer_length.min(4096) as usize];
+        match self.port.try_read(&mut buffer) {
+            Ok(len) => {
+                buffer.truncate(len);
+                Ok(buffer)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        self.acm.supported_requests()
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.acm.get_class_specific_descriptor()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A USB serial adapter of the serial port of `handler`
+    pub fn serial_port(handler: UsbCdcAcmSerialHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::CDC as u8,
+            CDC_ACM_SUBCLASS,
+            0x00,
+            Some("Serial Port"),
+            UsbCdcAcmHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x000D;
+        device.set_product_name("Virtual Serial Adapter");
+        device
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serial_bridge() {
+        setup_test_logger();
+        let (mut remote, port) = SerialStream::pair().unwrap();
+        let device = UsbDevice::serial_port(UsbCdcAcmSerialHandler::new(port));
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        let line_coding = LineCoding {
+            baud_rate: 9600,
+            ..Default::default()
+        };
+        let set_line_coding = SetupPacket {
+            request_type: 0b00100001,
+            request: 0x20,
+            value: 0,
+            index: 0,
+            length: 7,
+        };
+        device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                set_line_coding,
+                &line_coding.to_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let intf = &device.interfaces[0];
+        let [_, bulk_in, bulk_out] = UsbCdcAcmHandler::endpoints().try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+        let bridge = handler
+            .as_any()
+            .downcast_mut::<UsbCdcAcmSerialHandler>()
+            .unwrap();
+        assert_eq!(bridge.acm().line_coding, line_coding);
+        assert_eq!(bridge.port().baud_rate().unwrap(), 9600);
+
+        handler
+            .handle_urb(intf, bulk_out, 5, SetupPacket::default(), b"hello")
+            .unwrap();
+        let mut received = vec![];
+        while received.len() < 5 {
+            remote.readable().await.unwrap();
+            let mut buffer = [0; 16];
+            match remote.try_read(&mut buffer) {
+                Ok(len) => received.extend_from_slice(&buffer[..len]),
+                Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock),
+            }
+        }
+        assert_eq!(received, b"hello");
+
+        remote.try_write(b"world").unwrap();
+        let mut sent = vec![];
+        while sent.len() < 5 {
+            let data = handler.handle_urb(intf, bulk_in, 512, SetupPacket::default(), &[]);
+            sent.extend(data.unwrap());
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        assert_eq!(sent, b"world");
+    }
+}
@@ -0,0 +1,548 @@
+//! Implement a USBTMC(USB Test and Measurement Class) instrument
+//!
+//! VISA libraries and the usbtmc driver of Linux talk to instruments with
+//! device dependent messages, most often SCPI commands and queries, framed
+//! by a USBTMC header on the bulk endpoints. The USB488 subclass adds
+//! triggers, status bytes and remote/local control like on GPIB. The
+//! messages go to a [ScpiHandler].
+use super::super::*;
+
+// reference:
+// Universal Serial Bus Test and Measurement Class, revision 1.0, and its USB488 subclass
+// https://www.usb.org/document-library/test-measurement-class-specification
+
+/// bInterfaceSubClass of USBTMC
+pub const USBTMC_SUBCLASS: u8 = 0x03;
+/// bInterfaceProtocol of USB488 interfaces
+pub const USB488_PROTOCOL: u8 = 0x01;
+
+// class requests
+const INITIATE_ABORT_BULK_OUT: u8 = 1;
+const CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+const INITIATE_ABORT_BULK_IN: u8 = 3;
+const CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+const INITIATE_CLEAR: u8 = 5;
+const CHECK_CLEAR_STATUS: u8 = 6;
+const GET_CAPABILITIES: u8 = 7;
+const READ_STATUS_BYTE: u8 = 128;
+const REN_CONTROL: u8 = 160;
+const GO_TO_LOCAL: u8 = 161;
+const LOCAL_LOCKOUT: u8 = 162;
+
+// status of the class requests
+const STATUS_SUCCESS: u8 = 0x01;
+
+// MsgID of the bulk messages
+const DEV_DEP_MSG_OUT: u8 = 1;
+const REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+const DEV_DEP_MSG_IN: u8 = 2;
+const TRIGGER: u8 = 128;
+
+/// Length of the header of the bulk messages
+const HEADER_LEN: usize = 12;
+/// bmTransferAttributes bit of the last transfer of a message
+const END_OF_MESSAGE: u8 = 1 << 0;
+/// bmTransferAttributes bit of REQUEST_DEV_DEP_MSG_IN to stop at TermChar
+const TERM_CHAR_ENABLED: u8 = 1 << 1;
+/// Message available bit of the status byte
+const STATUS_MAV: u8 = 1 << 4;
+
+/// The instrument behind a [UsbTmcHandler]
+///
+/// Only [message](ScpiHandler::message) is required, closures taking a
+/// message and returning the response implement it.
+pub trait ScpiHandler {
+    /// Handle a complete message from the host, e.g. `*IDN?\n`
+    ///
+    /// The returned response, usually only for queries, is queued for the host to read.
+    fn message(&mut self, message: &[u8]) -> Option<Vec<u8>>;
+
+    /// The status byte of a serial poll, without the message available bit
+    fn status_byte(&mut self) -> u8 {
+        0
+    }
+
+    /// A USB488 trigger, like `*TRG`
+    fn trigger(&mut self) {}
+
+    /// A device clear, which also discards the queued responses
+    fn clear(&mut self) {}
+}
+
+impl<F: FnMut(&[u8]) -> Option<Vec<u8>>> ScpiHandler for F {
+    fn message(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        self(message)
+    }
+}
+
+/// A request of the host to read a response
+#[derive(Clone, Copy, Debug)]
+struct ReadRequest {
+    tag: u8,
+    max_len: usize,
+    term_char: Option<u8>,
+}
+
+/// A handler of a USB488 instrument, passing the messages to a [ScpiHandler]
+pub struct UsbTmcHandler {
+    instrument: Box<dyn ScpiHandler + Send>,
+    /// Message from the host, until the transfer with the end of message
+    input: Vec<u8>,
+    /// Responses for the host
+    output: Vec<u8>,
+    read_request: Option<ReadRequest>,
+    /// Notifications of READ_STATUS_BYTE for the interrupt endpoint
+    notifications: VecDeque<[u8; 2]>,
+    /// Remote enable, set by REN_CONTROL
+    pub remote: bool,
+    /// Local lockout, set by LOCAL_LOCKOUT and cleared by GO_TO_LOCAL
+    pub local_lockout: bool,
+}
+
+impl std::fmt::Debug for UsbTmcHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbTmcHandler")
+            .field("input", &self.input)
+            .field("output", &self.output)
+            .field("read_request", &self.read_request)
+            .field("remote", &self.remote)
+            .field("local_lockout", &self.local_lockout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UsbTmcHandler {
+    pub fn new(instrument: impl ScpiHandler + Send + 'static) -> Self {
+        Self {
+            instrument: Box::new(instrument),
+            input: vec![],
+            output: vec![],
+            read_request: None,
+            notifications: VecDeque::new(),
+            remote: false,
+            local_lockout: false,
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x82,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // status byte notifications
+            UsbEndpoint {
+                address: 0x83,                                   // IN
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: 0x02,                           // 2 bytes
+                interval: 10,
+            },
+        ]
+    }
+
+    fn status_byte(&mut self) -> u8 {
+        let mav = if self.output.is_empty() {
+            0
+        } else {
+            STATUS_MAV
+        };
+        (self.instrument.status_byte() & !STATUS_MAV) | mav
+    }
+
+    fn capabilities() -> Vec<u8> {
+        let mut res = vec![STATUS_SUCCESS, 0x00];
+        res.extend(0x0100u16.to_le_bytes()); // bcdUSBTMC
+        res.push(0x00); // no indicator pulse, talks and listens
+        res.push(0x01); // TermChar supported
+        res.extend([0; 6]);
+        res.extend(0x0100u16.to_le_bytes()); // bcdUSB488
+        res.push(0x07); // USB488.2, REN_CONTROL, GO_TO_LOCAL, LOCAL_LOCKOUT and TRIGGER
+        res.push(0x0F); // SCPI, SR1, RL1 and DT1
+        res.extend([0; 8]);
+        res
+    }
+
+    fn control(&mut self, setup: SetupPacket) -> Result<Vec<u8>> {
+        match (setup.request_type, setup.request) {
+            (0b10100001, GET_CAPABILITIES) => Ok(Self::capabilities()),
+            (0b10100001, INITIATE_CLEAR) => {
+                self.input.clear();
+                self.output.clear();
+                self.read_request = None;
+                self.instrument.clear();
+                Ok(vec![STATUS_SUCCESS])
+            }
+            (0b10100001, CHECK_CLEAR_STATUS) => Ok(vec![STATUS_SUCCESS, 0x00]),
+            (0b10100010, INITIATE_ABORT_BULK_OUT) => {
+                self.input.clear();
+                Ok(vec![STATUS_SUCCESS, setup.value as u8])
+            }
+            (0b10100010, INITIATE_ABORT_BULK_IN) => {
+                self.read_request = None;
+                Ok(vec![STATUS_SUCCESS, setup.value as u8])
+            }
+            (0b10100010, CHECK_ABORT_BULK_OUT_STATUS | CHECK_ABORT_BULK_IN_STATUS) => {
+                // no bytes transferred since the abort
+                Ok(vec![STATUS_SUCCESS, 0, 0, 0, 0, 0, 0, 0])
+            }
+            (0b10100001, READ_STATUS_BYTE) => {
+                // the status byte itself comes on the interrupt endpoint
+                let tag = setup.value as u8 & 0x7F;
+                let status = self.status_byte();
+                self.notifications.push_back([0x80 | tag, status]);
+                Ok(vec![STATUS_SUCCESS, tag, 0x00])
+            }
+            (0b10100001, REN_CONTROL) => {
+                self.remote = setup.value & 0x01 != 0;
+                if !self.remote {
+                    self.local_lockout = false;
+                }
+                Ok(vec![STATUS_SUCCESS])
+            }
+            (0b10100001, GO_TO_LOCAL) => {
+                self.local_lockout = false;
+                Ok(vec![STATUS_SUCCESS])
+            }
+            (0b10100001, LOCAL_LOCKOUT) => {
+                self.local_lockout = true;
+                Ok(vec![STATUS_SUCCESS])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported USBTMC request: {setup:x?}"),
+            )),
+        }
+    }
+
+    fn bulk_out(&mut self, req: &[u8]) -> Result<()> {
+        let header = req.get(..HEADER_LEN).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Short USBTMC message {req:x?}"),
+            )
+        })?;
+        let (msg_id, tag) = (header[0], header[1]);
+        if tag != !header[2] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid USBTMC bTagInverse {header:x?}"),
+            ));
+        }
+        let transfer_size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let attributes = header[8];
+        match msg_id {
+            DEV_DEP_MSG_OUT => {
+                // the rest is padding to 4 bytes
+                let data = &req[HEADER_LEN..];
+                self.input
+                    .extend_from_slice(&data[..transfer_size.min(data.len())]);
+                if attributes & END_OF_MESSAGE != 0 {
+                    let message = std::mem::take(&mut self.input);
+                    debug!("USBTMC message {:?}", String::from_utf8_lossy(&message));
+                    if let Some(response) = self.instrument.message(&message) {
+                        self.output.extend(response);
+                    }
+                }
+            }
+            REQUEST_DEV_DEP_MSG_IN => {
+                self.read_request = Some(ReadRequest {
+                    tag,
+                    max_len: transfer_size,
+                    term_char: (attributes & TERM_CHAR_ENABLED != 0).then_some(header[9]),
+                });
+            }
+            TRIGGER => self.instrument.trigger(),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unsupported USBTMC message {header:x?}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The response to the last REQUEST_DEV_DEP_MSG_IN
+    fn bulk_in(&mut self, transfer_buffer_length: usize) -> Vec<u8> {
+        let Some(request) = self.read_request.take() else {
+            return vec![];
+        };
+        // the data is padded to 4 bytes
+        let mut len = self
+            .output
+            .len()
+            .min(request.max_len)
+            .min(transfer_buffer_length.saturating_sub(HEADER_LEN) / 4 * 4);
+        let mut attributes = 0;
+        if let Some(term_char) = request.term_char
+            && let Some(pos) = self.output[..len].iter().position(|c| *c == term_char)
+        {
+            len = pos + 1;
+            attributes |= TERM_CHAR_ENABLED;
+        }
+        if len == self.output.len() {
+            attributes |= END_OF_MESSAGE;
+        }
+        let mut resp = vec![DEV_DEP_MSG_IN, request.tag, !request.tag, 0x00];
+        resp.extend((len as u32).to_le_bytes());
+        resp.extend([attributes, 0, 0, 0]);
+        resp.extend(self.output.drain(..len));
+        resp.resize(resp.len().next_multiple_of(4), 0);
+        resp
+    }
+}
+
+impl UsbInterfaceHandler for UsbTmcHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return self.control(setup);
+        }
+        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+            return Ok(self
+                .notifications
+                .pop_front()
+                .map(|notification| notification.to_vec())
+                .unwrap_or_default());
+        }
+        if let Direction::Out = ep.direction() {
+            self.bulk_out(req)?;
+            return Ok(vec![]);
+        }
+        Ok(self.bulk_in(transfer_buffer_length as usize))
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[
+            INITIATE_ABORT_BULK_OUT,
+            CHECK_ABORT_BULK_OUT_STATUS,
+            INITIATE_ABORT_BULK_IN,
+            CHECK_ABORT_BULK_IN_STATUS,
+            INITIATE_CLEAR,
+            CHECK_CLEAR_STATUS,
+            GET_CAPABILITIES,
+            READ_STATUS_BYTE,
+            REN_CONTROL,
+            GO_TO_LOCAL,
+            LOCAL_LOCKOUT,
+        ])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A USB488 instrument with the SCPI handler of `handler`
+    pub fn usbtmc(handler: UsbTmcHandler) -> Self {
+        let mut device = Self::new(0).with_interface(
+            ClassCode::ApplicationSpecific as u8,
+            USBTMC_SUBCLASS,
+            USB488_PROTOCOL,
+            Some("USBTMC"),
+            UsbTmcHandler::endpoints(),
+            shared_interface_handler(handler),
+        );
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0011;
+        device.set_product_name("Virtual Instrument");
+        device.set_serial_number("0001");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn header(msg_id: u8, tag: u8, transfer_size: u32, attributes: u8, term_char: u8) -> Vec<u8> {
+        let mut res = vec![msg_id, tag, !tag, 0x00];
+        res.extend(transfer_size.to_le_bytes());
+        res.extend([attributes, term_char, 0, 0]);
+        res
+    }
+
+    fn message(tag: u8, data: &[u8], end: bool) -> Vec<u8> {
+        let attributes = if end { END_OF_MESSAGE } else { 0 };
+        let mut res = header(DEV_DEP_MSG_OUT, tag, data.len() as u32, attributes, 0);
+        res.extend_from_slice(data);
+        res.resize(res.len().next_multiple_of(4), 0);
+        res
+    }
+
+    #[derive(Default)]
+    struct Meter {
+        triggers: u32,
+    }
+
+    impl ScpiHandler for Meter {
+        fn message(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+            match message {
+                b"*IDN?\n" => Some(b"usbip,Virtual Meter,0001,1.0\n".to_vec()),
+                b"MEAS:TRIG?\n" => Some(format!("{}\n", self.triggers).into_bytes()),
+                _ => None,
+            }
+        }
+
+        fn status_byte(&mut self) -> u8 {
+            // event status bit
+            0x20
+        }
+
+        fn trigger(&mut self) {
+            self.triggers += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn usbtmc_queries() {
+        setup_test_logger();
+        let device = UsbDevice::usbtmc(UsbTmcHandler::new(Meter::default()));
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+
+        let get_capabilities = SetupPacket {
+            request_type: 0b10100001,
+            request: GET_CAPABILITIES,
+            value: 0,
+            index: 0,
+            length: 0x18,
+        };
+        let caps = device
+            .handle_urb(device.ep0_in, None, 0x18, get_capabilities, &[])
+            .await
+            .unwrap();
+        assert_eq!(caps.len(), 0x18);
+        assert_eq!(caps[0], STATUS_SUCCESS);
+
+        let intf = &device.interfaces[0];
+        let [bulk_out, bulk_in, interrupt] = UsbTmcHandler::endpoints().try_into().unwrap();
+        let mut handler = intf.handler.lock().await;
+        let mut send = |req: &[u8]| {
+            handler
+                .handle_urb(intf, bulk_out, 0, SetupPacket::default(), req)
+                .unwrap();
+        };
+
+        // a message split over two transfers
+        send(&message(1, b"*ID", false));
+        send(&message(2, b"N?\n", true));
+        send(&header(REQUEST_DEV_DEP_MSG_IN, 3, 1024, 0, 0));
+        let resp = handler
+            .handle_urb(intf, bulk_in, 1024, SetupPacket::default(), &[])
+            .unwrap();
+        let idn = b"usbip,Virtual Meter,0001,1.0\n";
+        assert_eq!(resp[..4], [DEV_DEP_MSG_IN, 3, !3, 0]);
+        assert_eq!(resp[4..8], (idn.len() as u32).to_le_bytes());
+        assert_eq!(resp[8], END_OF_MESSAGE);
+        assert_eq!(&resp[HEADER_LEN..HEADER_LEN + idn.len()], idn);
+        assert_eq!(resp.len() % 4, 0);
+
+        // a response read in parts, up to the term char first
+        let mut send = |req: &[u8]| {
+            handler
+                .handle_urb(intf, bulk_out, 0, SetupPacket::default(), req)
+                .unwrap();
+        };
+        send(&header(TRIGGER, 4, 0, 0, 0));
+        send(&message(5, b"MEAS:TRIG?\n", true));
+        send(&message(6, b"*IDN?\n", true));
+        send(&header(
+            REQUEST_DEV_DEP_MSG_IN,
+            7,
+            1024,
+            TERM_CHAR_ENABLED,
+            b'\n',
+        ));
+        let resp = handler
+            .handle_urb(intf, bulk_in, 1024, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp[4..8], 2u32.to_le_bytes());
+        assert_eq!(resp[8], TERM_CHAR_ENABLED);
+        assert_eq!(&resp[HEADER_LEN..HEADER_LEN + 2], b"1\n");
+        handler
+            .handle_urb(
+                intf,
+                bulk_out,
+                0,
+                SetupPacket::default(),
+                &header(REQUEST_DEV_DEP_MSG_IN, 8, 10, 0, 0),
+            )
+            .unwrap();
+        let resp = handler
+            .handle_urb(intf, bulk_in, 1024, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(resp[4..8], 10u32.to_le_bytes());
+        assert_eq!(resp[8], 0);
+        assert_eq!(&resp[HEADER_LEN..HEADER_LEN + 10], &idn[..10]);
+
+        // invalid tag inverse
+        let mut bad = header(REQUEST_DEV_DEP_MSG_IN, 9, 10, 0, 0);
+        bad[2] = 0;
+        assert!(
+            handler
+                .handle_urb(intf, bulk_out, 0, SetupPacket::default(), &bad)
+                .is_err()
+        );
+        drop(handler);
+
+        // serial poll, with the rest of the response still available
+        let read_status_byte = SetupPacket {
+            request_type: 0b10100001,
+            request: READ_STATUS_BYTE,
+            value: 2,
+            index: 0,
+            length: 3,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 3, read_status_byte, &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [STATUS_SUCCESS, 2, 0]);
+        let mut handler = intf.handler.lock().await;
+        let notification = handler
+            .handle_urb(intf, interrupt, 2, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(notification, [0x82, 0x20 | STATUS_MAV]);
+        drop(handler);
+
+        // device clear discards the response
+        let initiate_clear = SetupPacket {
+            request_type: 0b10100001,
+            request: INITIATE_CLEAR,
+            value: 0,
+            index: 0,
+            length: 1,
+        };
+        device
+            .handle_urb(device.ep0_in, None, 1, initiate_clear, &[])
+            .await
+            .unwrap();
+        let mut handler = intf.handler.lock().await;
+        let tmc = handler.as_any().downcast_mut::<UsbTmcHandler>().unwrap();
+        assert!(tmc.output.is_empty());
+    }
+}
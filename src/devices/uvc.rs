@@ -0,0 +1,630 @@
+//! Implement a USB Video Class 1.1 webcam
+//!
+//! [UsbDevice::uvc] builds a video control and a video streaming interface,
+//! in an interface association. The host negotiates one of the
+//! [UvcFrame] sizes of the [UsbVideoHandler] with the probe and commit
+//! controls, and then reads the frames of its source from a bulk endpoint,
+//! split into payloads with a 2 byte header.
+use super::super::*;
+
+// reference:
+// USB Video Class 1.1: https://www.usb.org/document-library/video-class-v11-document-set
+// Payload formats: USB_Video_Payload_MJPEG_1.1.pdf, USB_Video_Payload_Uncompressed_1.1.pdf
+
+const VIDEO_CONTROL_SUBCLASS: u8 = 0x01;
+const VIDEO_STREAMING_SUBCLASS: u8 = 0x02;
+const VIDEO_INTERFACE_COLLECTION: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+
+// class requests
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+const GET_MIN: u8 = 0x82;
+const GET_MAX: u8 = 0x83;
+const GET_LEN: u8 = 0x85;
+const GET_INFO: u8 = 0x86;
+const GET_DEF: u8 = 0x87;
+
+// video streaming control selectors
+const VS_PROBE_CONTROL: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+/// Length of the probe and commit controls of UVC 1.1
+const PROBE_LEN: usize = 34;
+/// Length of the header of each payload
+const HEADER_LEN: usize = 2;
+/// Upper bound of dwMaxPayloadTransferSize
+const MAX_PAYLOAD_LEN: u32 = 0x4000;
+/// dwClockFrequency, in Hz
+const CLOCK_FREQUENCY: u32 = 48_000_000;
+
+/// Payload format of the frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UvcEncoding {
+    /// A JPEG image per frame
+    Mjpeg,
+    /// Uncompressed YUV 4:2:2, 2 bytes per pixel
+    Yuy2,
+}
+
+/// A frame size of the stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UvcFrame {
+    pub width: u16,
+    pub height: u16,
+    /// Frames per second
+    pub fps: u32,
+}
+
+impl UvcFrame {
+    /// Bytes of an uncompressed frame, and the upper bound of MJPEG frames
+    pub fn max_frame_len(&self) -> u32 {
+        self.width as u32 * self.height as u32 * 2
+    }
+
+    /// dwFrameInterval, in 100 ns units
+    fn interval(&self) -> u32 {
+        10_000_000 / self.fps.max(1)
+    }
+}
+
+/// Returns the next frame of the given size, or `None` to repeat the last
+pub type UvcFrameSource = Box<dyn FnMut(&UvcFrame) -> Option<Vec<u8>> + Send>;
+
+/// The video probe and commit controls, with the fields the device negotiates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct VideoProbe {
+    hint: u16,
+    format_index: u8,
+    frame_index: u8,
+    frame_interval: u32,
+    max_video_frame_size: u32,
+    max_payload_transfer_size: u32,
+}
+
+impl VideoProbe {
+    fn from_bytes(data: &[u8]) -> Self {
+        let mut data = data.to_vec();
+        data.resize(PROBE_LEN, 0);
+        Self {
+            hint: u16::from_le_bytes([data[0], data[1]]),
+            format_index: data[2],
+            frame_index: data[3],
+            frame_interval: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            max_video_frame_size: u32::from_le_bytes(data[18..22].try_into().unwrap()),
+            max_payload_transfer_size: u32::from_le_bytes(data[22..26].try_into().unwrap()),
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PROBE_LEN);
+        data.extend(self.hint.to_le_bytes());
+        data.extend([self.format_index, self.frame_index]);
+        data.extend(self.frame_interval.to_le_bytes());
+        // wKeyFrameRate, wPFrameRate, wCompQuality, wCompWindowSize, wDelay
+        data.extend([0; 10]);
+        data.extend(self.max_video_frame_size.to_le_bytes());
+        data.extend(self.max_payload_transfer_size.to_le_bytes());
+        data.extend(CLOCK_FREQUENCY.to_le_bytes());
+        // bmFramingInfo, bPreferedVersion, bMinVersion, bMaxVersion
+        data.extend([0x03, 0x01, 0x01, 0x01]);
+        data
+    }
+}
+
+/// A handler of a video streaming interface
+///
+/// Frames are requested from the source whenever the host finished reading
+/// the previous one.
+pub struct UsbVideoHandler {
+    encoding: UvcEncoding,
+    frames: Vec<UvcFrame>,
+    source: UvcFrameSource,
+    probe: VideoProbe,
+    /// The committed control, while the host is streaming
+    commit: Option<VideoProbe>,
+    /// The frame being sent, and how much of it was
+    frame: Vec<u8>,
+    sent: usize,
+    /// The frame ID bit, toggled on every frame
+    fid: bool,
+}
+
+impl std::fmt::Debug for UsbVideoHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbVideoHandler")
+            .field("encoding", &self.encoding)
+            .field("frames", &self.frames)
+            .field("commit", &self.commit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UsbVideoHandler {
+    /// A stream of `encoding` frames in the sizes `frames`, produced by `source`
+    pub fn new(
+        encoding: UvcEncoding,
+        frames: Vec<UvcFrame>,
+        source: impl FnMut(&UvcFrame) -> Option<Vec<u8>> + Send + 'static,
+    ) -> Self {
+        assert!(
+            !frames.is_empty() && frames.len() < 256,
+            "Video streams need 1 to 255 frame sizes"
+        );
+        let mut res = Self {
+            encoding,
+            frames,
+            source: Box::new(source),
+            probe: VideoProbe::default(),
+            commit: None,
+            frame: vec![],
+            sent: 0,
+            fid: false,
+        };
+        res.probe = res.negotiate(VideoProbe::default());
+        res
+    }
+
+    /// A handler streaming the latest frame sent to the returned channel
+    ///
+    /// Frames queued while the host was reading are skipped, and the last
+    /// frame is repeated until a new one is sent.
+    pub fn channel(
+        encoding: UvcEncoding,
+        frames: Vec<UvcFrame>,
+    ) -> (Self, tokio::sync::mpsc::UnboundedSender<Vec<u8>>) {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let source = move |_: &UvcFrame| {
+            let mut latest = None;
+            while let Ok(frame) = receiver.try_recv() {
+                latest = Some(frame);
+            }
+            latest
+        };
+        (Self::new(encoding, frames, source), sender)
+    }
+
+    /// The frame size the host is streaming, if it is
+    pub fn current_frame(&self) -> Option<UvcFrame> {
+        self.commit
+            .map(|commit| self.frames[commit.frame_index as usize - 1])
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        }]
+    }
+
+    /// Fill in the fields of `probe` for one of our frames
+    fn negotiate(&self, mut probe: VideoProbe) -> VideoProbe {
+        if probe.frame_index == 0 || probe.frame_index as usize > self.frames.len() {
+            probe.frame_index = 1;
+        }
+        let frame = self.frames[probe.frame_index as usize - 1];
+        probe.hint = 0x0001;
+        probe.format_index = 1;
+        probe.frame_interval = frame.interval();
+        probe.max_video_frame_size = frame.max_frame_len();
+        probe.max_payload_transfer_size =
+            (frame.max_frame_len() + HEADER_LEN as u32).min(MAX_PAYLOAD_LEN);
+        probe
+    }
+
+    fn control(&mut self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>> {
+        let selector = (setup.value >> 8) as u8;
+        if selector != VS_PROBE_CONTROL && selector != VS_COMMIT_CONTROL {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported video streaming control {selector}"),
+            ));
+        }
+        match (setup.request_type, setup.request) {
+            (0b00100001, SET_CUR) => {
+                let probe = self.negotiate(VideoProbe::from_bytes(req));
+                if selector == VS_PROBE_CONTROL {
+                    self.probe = probe;
+                } else {
+                    debug!("Video stream committed {:?}", probe);
+                    self.probe = probe;
+                    self.commit = Some(probe);
+                    self.frame.clear();
+                    self.sent = 0;
+                }
+                Ok(vec![])
+            }
+            (0b10100001, GET_CUR) => Ok(self.probe.to_bytes()),
+            (0b10100001, GET_MIN | GET_DEF) => Ok(self.negotiate(VideoProbe::default()).to_bytes()),
+            (0b10100001, GET_MAX) => {
+                let probe = VideoProbe {
+                    frame_index: self.frames.len() as u8,
+                    ..Default::default()
+                };
+                Ok(self.negotiate(probe).to_bytes())
+            }
+            (0b10100001, GET_LEN) => Ok((PROBE_LEN as u16).to_le_bytes().to_vec()),
+            // supports GET and SET
+            (0b10100001, GET_INFO) => Ok(vec![0x03]),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported video streaming request: {setup:x?}"),
+            )),
+        }
+    }
+
+    /// The next payload of the stream, at most `len` bytes
+    fn payload(&mut self, len: usize) -> Vec<u8> {
+        let Some(commit) = self.commit else {
+            return vec![];
+        };
+        if self.sent >= self.frame.len() {
+            let frame = self.frames[commit.frame_index as usize - 1];
+            if let Some(data) = (self.source)(&frame) {
+                self.frame = data;
+            }
+            // repeat the last frame as a new one
+            self.sent = 0;
+            self.fid = !self.fid;
+        }
+        let len = len.min(commit.max_payload_transfer_size as usize);
+        if self.frame.is_empty() || len <= HEADER_LEN {
+            return vec![];
+        }
+        let end = (self.sent + len - HEADER_LEN).min(self.frame.len());
+        // bmHeaderInfo: end of header, end of frame, frame ID
+        let eof = end == self.frame.len();
+        let mut payload = vec![HEADER_LEN as u8, 0x80 | (eof as u8) << 1 | self.fid as u8];
+        payload.extend_from_slice(&self.frame[self.sent..end]);
+        self.sent = end;
+        payload
+    }
+
+    /// The class specific descriptors of the streaming interface
+    fn streaming_descriptor(&self) -> Vec<u8> {
+        let mut format = match self.encoding {
+            UvcEncoding::Mjpeg => vec![
+                0x0B,
+                CS_INTERFACE,
+                0x06, // bDescriptorSubtype: VS_FORMAT_MJPEG
+                0x01, // bFormatIndex
+                self.frames.len() as u8,
+                0x01, // bmFlags: fixed size samples
+                0x01, // bDefaultFrameIndex
+                0x00, // bAspectRatioX
+                0x00, // bAspectRatioY
+                0x00, // bmInterlaceFlags
+                0x00, // bCopyProtect
+            ],
+            UvcEncoding::Yuy2 => {
+                let mut format = vec![
+                    0x1B,
+                    CS_INTERFACE,
+                    0x04, // bDescriptorSubtype: VS_FORMAT_UNCOMPRESSED
+                    0x01, // bFormatIndex
+                    self.frames.len() as u8,
+                ];
+                // guidFormat: YUY2
+                format.extend(b"YUY2");
+                format.extend([
+                    0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+                ]);
+                format.extend([
+                    0x10, // bBitsPerPixel
+                    0x01, // bDefaultFrameIndex
+                    0x00, // bAspectRatioX
+                    0x00, // bAspectRatioY
+                    0x00, // bmInterlaceFlags
+                    0x00, // bCopyProtect
+                ]);
+                format
+            }
+        };
+        let subtype = match self.encoding {
+            // VS_FRAME_MJPEG
+            UvcEncoding::Mjpeg => 0x07,
+            // VS_FRAME_UNCOMPRESSED
+            UvcEncoding::Yuy2 => 0x05,
+        };
+        for (i, frame) in self.frames.iter().enumerate() {
+            let bit_rate = frame.max_frame_len() * 8 * frame.fps;
+            format.extend([0x1E, CS_INTERFACE, subtype, i as u8 + 1, 0x00]);
+            format.extend(frame.width.to_le_bytes());
+            format.extend(frame.height.to_le_bytes());
+            format.extend(bit_rate.to_le_bytes()); // dwMinBitRate
+            format.extend(bit_rate.to_le_bytes()); // dwMaxBitRate
+            format.extend(frame.max_frame_len().to_le_bytes()); // dwMaxVideoFrameBufferSize
+            format.extend(frame.interval().to_le_bytes()); // dwDefaultFrameInterval
+            format.push(0x01); // bFrameIntervalType: discrete
+            format.extend(frame.interval().to_le_bytes());
+        }
+
+        let total_len = 14 + format.len();
+        let mut desc = vec![
+            0x0E,
+            CS_INTERFACE,
+            0x01, // bDescriptorSubtype: VS_INPUT_HEADER
+            0x01, // bNumFormats
+            total_len as u8,
+            (total_len >> 8) as u8, // wTotalLength
+            Self::endpoints()[0].address,
+            0x00, // bmInfo
+            0x02, // bTerminalLink: the output terminal
+            0x00, // bStillCaptureMethod
+            0x00, // bTriggerSupport
+            0x00, // bTriggerUsage
+            0x01, // bControlSize
+            0x00, // bmaControls
+        ];
+        desc.extend(format);
+        desc
+    }
+}
+
+impl UsbInterfaceHandler for UsbVideoHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            self.control(setup, req)
+        } else if ep.direction() == Direction::In {
+            Ok(self.payload(transfer_buffer_length as usize))
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[
+            SET_CUR, GET_CUR, GET_MIN, GET_MAX, GET_LEN, GET_INFO, GET_DEF,
+        ])
+    }
+
+    fn set_alternate_setting(&mut self, _interface: &UsbInterface, alternate_setting: u8) {
+        if alternate_setting == 0 && self.commit.take().is_some() {
+            debug!("Video stream stopped");
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.streaming_descriptor()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The video control interface, with a camera connected to the streaming interface
+#[derive(Debug)]
+struct VideoControlHandler {}
+
+impl UsbInterfaceHandler for VideoControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported video control request: {setup:x?}"),
+        ))
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        Some(&[])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x0D,
+            CS_INTERFACE,
+            0x01, // bDescriptorSubtype: VC_HEADER
+            0x10,
+            0x01, // bcdUVC 1.10
+            0x28,
+            0x00, // wTotalLength
+        ];
+        desc.extend(CLOCK_FREQUENCY.to_le_bytes());
+        desc.extend([
+            0x01, // bInCollection
+            0x01, // baInterfaceNr
+            // camera terminal
+            0x12,
+            CS_INTERFACE,
+            0x02, // bDescriptorSubtype: VC_INPUT_TERMINAL
+            0x01, // bTerminalID
+            0x01,
+            0x02, // wTerminalType: ITT_CAMERA
+            0x00, // bAssocTerminal
+            0x00, // iTerminal
+            0x00,
+            0x00, // wObjectiveFocalLengthMin
+            0x00,
+            0x00, // wObjectiveFocalLengthMax
+            0x00,
+            0x00, // wOcularFocalLength
+            0x03, // bControlSize
+            0x00,
+            0x00,
+            0x00, // bmControls
+            // output terminal
+            0x09,
+            CS_INTERFACE,
+            0x03, // bDescriptorSubtype: VC_OUTPUT_TERMINAL
+            0x02, // bTerminalID
+            0x01,
+            0x01, // wTerminalType: TT_STREAMING
+            0x00, // bAssocTerminal
+            0x01, // bSourceID
+            0x00, // iTerminal
+        ]);
+        desc
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// A webcam with the video streaming interface of `handler`
+    pub fn uvc(handler: UsbVideoHandler) -> Self {
+        let mut device = Self::new(0)
+            .with_interface(
+                ClassCode::Video as u8,
+                VIDEO_CONTROL_SUBCLASS,
+                0x00,
+                None,
+                vec![],
+                shared_interface_handler(VideoControlHandler {}),
+            )
+            .with_interface(
+                ClassCode::Video as u8,
+                VIDEO_STREAMING_SUBCLASS,
+                0x00,
+                Some("Video"),
+                UsbVideoHandler::endpoints(),
+                shared_interface_handler(handler),
+            )
+            .with_interface_association(
+                0,
+                2,
+                ClassCode::Video as u8,
+                VIDEO_INTERFACE_COLLECTION,
+                0x00,
+                Some("Virtual Camera"),
+            );
+        // Miscellaneous Device Class, Interface Association Descriptor
+        device.device_class = ClassCode::Misc as u8;
+        device.device_subclass = 0x02;
+        device.device_protocol = 0x01;
+        // pid.codes test VID/PID
+        device.vendor_id = 0x1209;
+        device.product_id = 0x0008;
+        device.set_product_name("Virtual Camera");
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn control(request_type: u8, request: u8, selector: u8, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request,
+            value: (selector as u16) << 8,
+            index: 0x01,
+            length,
+        }
+    }
+
+    #[tokio::test]
+    async fn uvc_streaming() {
+        setup_test_logger();
+        let frames = vec![
+            UvcFrame {
+                width: 4,
+                height: 2,
+                fps: 30,
+            },
+            UvcFrame {
+                width: 640,
+                height: 480,
+                fps: 15,
+            },
+        ];
+        let mut count = 0u8;
+        let source = move |frame: &UvcFrame| {
+            // a new frame every other time
+            count += 1;
+            (count % 2 == 1).then(|| vec![count; frame.max_frame_len() as usize - 4])
+        };
+        let device = UsbDevice::uvc(UsbVideoHandler::new(UvcEncoding::Yuy2, frames, source));
+        let desc = device.configuration_descriptor(0xFFFF);
+        verify_descriptor(&desc);
+        assert_eq!(device.interface_associations.len(), 1);
+        // wTotalLength of both class specific descriptors
+        let control_handler = device.interfaces[0].handler.lock().await;
+        let vc = control_handler.get_class_specific_descriptor();
+        assert_eq!(vc[5] as usize, vc.len());
+        drop(control_handler);
+        let streaming = &device.interfaces[1];
+        let vs = &streaming.class_specific_descriptor;
+        assert_eq!(u16::from_le_bytes([vs[4], vs[5]]) as usize, vs.len());
+
+        let mut handler = streaming.handler.lock().await;
+        let mut request = |setup: SetupPacket, req: &[u8]| {
+            handler
+                .handle_urb(streaming, device.ep0_in, setup.length as u32, setup, req)
+                .unwrap()
+        };
+        assert_eq!(
+            request(control(0xA1, GET_LEN, VS_PROBE_CONTROL, 2), &[]),
+            [34, 0]
+        );
+        let max =
+            VideoProbe::from_bytes(&request(control(0xA1, GET_MAX, VS_PROBE_CONTROL, 34), &[]));
+        assert_eq!(max.frame_index, 2);
+        assert_eq!(max.max_payload_transfer_size, MAX_PAYLOAD_LEN);
+
+        // an unknown frame falls back to the default one
+        let probe = VideoProbe {
+            frame_index: 3,
+            ..Default::default()
+        };
+        request(
+            control(0x21, SET_CUR, VS_PROBE_CONTROL, 26),
+            &probe.to_bytes()[..26],
+        );
+        let probe =
+            VideoProbe::from_bytes(&request(control(0xA1, GET_CUR, VS_PROBE_CONTROL, 34), &[]));
+        assert_eq!(probe.frame_index, 1);
+        assert_eq!(probe.frame_interval, 333333);
+        assert_eq!(probe.max_video_frame_size, 16);
+        assert_eq!(probe.max_payload_transfer_size, 18);
+        request(
+            control(0x21, SET_CUR, VS_COMMIT_CONTROL, 34),
+            &probe.to_bytes(),
+        );
+
+        let [bulk_in] = UsbVideoHandler::endpoints().try_into().unwrap();
+        let mut read = |len| {
+            handler
+                .handle_urb(streaming, bulk_in, len, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        // the first frame in payloads of 8 bytes and less, then repeated with a new frame ID
+        assert_eq!(read(8), [2, 0x81, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(read(8), [2, 0x83, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(read(512), [2, 0x82, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(read(512), [2, 0x83, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3]);
+
+        let uvc = handler.as_any().downcast_mut::<UsbVideoHandler>().unwrap();
+        assert_eq!(uvc.current_frame().unwrap().width, 4);
+        uvc.set_alternate_setting(streaming, 0);
+        assert!(uvc.current_frame().is_none());
+        assert!(
+            uvc.handle_urb(streaming, bulk_in, 512, SetupPacket::default(), &[])
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
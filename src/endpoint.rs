@@ -28,4 +28,35 @@ impl UsbEndpoint {
     pub fn is_ep0(&self) -> bool {
         self.address & 0x7F == 0
     }
+
+    /// Convert a high speed endpoint to the limits and units of full speed
+    ///
+    /// Used for the other speed configuration descriptor.
+    pub fn to_full_speed(&self) -> Self {
+        // drop additional transactions per microframe
+        let max_packet_size = self.max_packet_size & 0x7FF;
+        let (max_packet_size, interval) = match FromPrimitive::from_u8(self.attributes & 0x3) {
+            Some(EndpointAttributes::Isochronous) => {
+                // 2^(bInterval-1) microframes to 2^(bInterval-1) frames
+                (
+                    max_packet_size.min(1023),
+                    self.interval.saturating_sub(3).max(1),
+                )
+            }
+            Some(EndpointAttributes::Interrupt) => {
+                // 2^(bInterval-1) microframes to bInterval frames
+                let microframes = 1u32 << (self.interval.clamp(1, 16) - 1);
+                (
+                    max_packet_size.min(64),
+                    (microframes / 8).clamp(1, 255) as u8,
+                )
+            }
+            _ => (max_packet_size.min(64), self.interval),
+        };
+        Self {
+            max_packet_size,
+            interval,
+            ..*self
+        }
+    }
 }
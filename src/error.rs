@@ -0,0 +1,95 @@
+//! Errors of the server
+use crate::usbip_protocol::ParseError;
+use std::fmt;
+
+/// Errors returned by the server and when reading commands
+#[derive(Debug)]
+pub enum UsbIpError {
+    /// The client sent something that is not valid USB/IP
+    Protocol(ParseError),
+    /// The device with this bus id is imported by another client
+    DeviceBusy(String),
+    /// No device has this bus id
+    DeviceNotFound(String),
+    /// The authenticator rejected the client at this address, if known
+    Unauthorized(Option<std::net::SocketAddr>),
+    /// A transfer to or from a device failed
+    Transfer(std::io::Error),
+    /// Reading from or writing to the connection failed
+    Io(std::io::Error),
+}
+
+impl UsbIpError {
+    /// Whether the client closed the connection
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, Self::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof)
+    }
+}
+
+impl fmt::Display for UsbIpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Protocol(err) => write!(f, "{err}"),
+            Self::DeviceBusy(bus_id) => write!(f, "Device {bus_id} is in use"),
+            Self::DeviceNotFound(bus_id) => write!(f, "Device {bus_id} not found"),
+            Self::Unauthorized(Some(peer)) => write!(f, "Client {peer} is not authorized"),
+            Self::Unauthorized(None) => write!(f, "Client is not authorized"),
+            Self::Transfer(err) => write!(f, "Transfer failed: {err}"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for UsbIpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Protocol(err) => Some(err),
+            Self::Transfer(err) | Self::Io(err) => Some(err),
+            Self::DeviceBusy(_) | Self::DeviceNotFound(_) | Self::Unauthorized(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for UsbIpError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ParseError> for UsbIpError {
+    fn from(err: ParseError) -> Self {
+        Self::Protocol(err)
+    }
+}
+
+/// For callers still working with [std::io::Error]
+impl From<UsbIpError> for std::io::Error {
+    fn from(err: UsbIpError) -> Self {
+        use std::io::ErrorKind;
+        match err {
+            UsbIpError::Transfer(err) | UsbIpError::Io(err) => err,
+            UsbIpError::Protocol(_) => std::io::Error::new(ErrorKind::InvalidData, err),
+            UsbIpError::DeviceBusy(_) => std::io::Error::new(ErrorKind::ResourceBusy, err),
+            UsbIpError::DeviceNotFound(_) => std::io::Error::new(ErrorKind::NotFound, err),
+            UsbIpError::Unauthorized(_) => std::io::Error::new(ErrorKind::PermissionDenied, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn convert_to_io_error() {
+        setup_test_logger();
+        let err = std::io::Error::from(UsbIpError::DeviceNotFound("1-1".to_string()));
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "Device 1-1 not found");
+
+        let err = std::io::Error::from(UsbIpError::from(ParseError::UnknownCommand(0x1005)));
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
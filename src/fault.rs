@@ -0,0 +1,303 @@
+//! Misbehaving devices for robustness testing
+//!
+//! [FaultInjectingHandler] wraps an interface handler and, according to a
+//! [UsbFaultPolicy], delays, truncates, stalls or fails its URBs at random,
+//! so client drivers can be tested against flaky hardware.
+use super::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// Probabilities of the faults of a [FaultInjectingHandler], from 0 to 1
+///
+/// A URB is delayed first, then stalled, failed or truncated. The default
+/// injects nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UsbFaultPolicy {
+    /// Delay the URB by `delay` before handling it
+    pub delay_probability: f64,
+    pub delay: Duration,
+    /// Complete the URB with -EPIPE, without passing it to the handler
+    pub stall_probability: f64,
+    /// Fail the URB as if the device stopped responding, see [UsbErrorBudget]
+    pub error_probability: f64,
+    /// Complete the URB with only part of the data
+    pub truncate_probability: f64,
+}
+
+/// What happens to a URB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fault {
+    Stall,
+    Error,
+    /// Keep this fraction of the data, in 1/65536
+    Truncate(u32),
+    None,
+}
+
+/// Injects faults into the URBs of the wrapped handler, see [UsbFaultPolicy]
+#[derive(Debug)]
+pub struct FaultInjectingHandler {
+    inner: Box<dyn UsbInterfaceHandler + Send>,
+    policy: UsbFaultPolicy,
+    /// xorshift64* state, never zero
+    state: u64,
+}
+
+impl FaultInjectingHandler {
+    pub fn new(handler: impl UsbInterfaceHandler + Send + 'static, policy: UsbFaultPolicy) -> Self {
+        let seed = std::time::UNIX_EPOCH
+            .elapsed()
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self {
+            inner: Box::new(handler),
+            policy,
+            state: 0,
+        }
+        .with_seed(seed)
+    }
+
+    /// Draw the faults from `seed`, to make a run reproducible
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.state = seed.max(1);
+        self
+    }
+
+    pub fn policy(&self) -> UsbFaultPolicy {
+        self.policy
+    }
+
+    /// Change the policy, e.g. after downcasting the handler with `as_any`
+    pub fn set_policy(&mut self, policy: UsbFaultPolicy) {
+        self.policy = policy;
+    }
+
+    /// The wrapped handler
+    pub fn inner(&mut self) -> &mut Box<dyn UsbInterfaceHandler + Send> {
+        &mut self.inner
+    }
+
+    /// Uniform in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let x = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Whether to delay the URB, and what else happens to it
+    fn roll(&mut self) -> (bool, Fault) {
+        let delay = self.next_f64() < self.policy.delay_probability;
+        let fault = if self.next_f64() < self.policy.stall_probability {
+            Fault::Stall
+        } else if self.next_f64() < self.policy.error_probability {
+            Fault::Error
+        } else if self.next_f64() < self.policy.truncate_probability {
+            Fault::Truncate((self.next_f64() * 65536.0) as u32)
+        } else {
+            Fault::None
+        };
+        if delay || fault != Fault::None {
+            debug!("Injecting delay {delay} and fault {fault:?}");
+        }
+        (delay, fault)
+    }
+
+    /// Apply `fault` to a URB before it is passed to the handler, true if it is handled
+    fn inject(fault: Fault, urb: &mut Urb) -> Result<bool> {
+        match fault {
+            Fault::Stall => {
+                urb.status = -(crate::usbip_protocol::EPIPE);
+                urb.actual_length = 0;
+                Ok(true)
+            }
+            Fault::Error => Err(std::io::Error::other("Injected fault")),
+            Fault::Truncate(_) | Fault::None => Ok(false),
+        }
+    }
+
+    /// Apply `fault` to a completed URB
+    fn truncate(fault: Fault, urb: &mut Urb) {
+        if let Fault::Truncate(fraction) = fault {
+            let len = ((urb.actual_length as u64 * fraction as u64) >> 16) as u32;
+            urb.actual_length = len;
+            if urb.direction() == Direction::In {
+                urb.buffer.truncate(len as usize);
+            }
+        }
+    }
+}
+
+impl UsbInterfaceHandler for FaultInjectingHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.inner.get_class_specific_descriptor()
+    }
+
+    fn get_class_specific_endpoint_descriptor(&self, endpoint: &UsbEndpoint) -> Vec<u8> {
+        self.inner.get_class_specific_endpoint_descriptor(endpoint)
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.inner
+            .handle_urb(interface, ep, transfer_buffer_length, setup, req)
+    }
+
+    fn submit_urb(&mut self, interface: &UsbInterface, urb: &mut Urb) -> Result<()> {
+        let (delay, fault) = self.roll();
+        if delay {
+            std::thread::sleep(self.policy.delay);
+        }
+        if !Self::inject(fault, urb)? {
+            self.inner.submit_urb(interface, urb)?;
+            Self::truncate(fault, urb);
+        }
+        Ok(())
+    }
+
+    fn submit_urb_async<'a>(
+        &'a mut self,
+        interface: &'a UsbInterface,
+        urb: &'a mut Urb,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let (delay, fault) = self.roll();
+            if delay {
+                tokio::time::sleep(self.policy.delay).await;
+            }
+            if !Self::inject(fault, urb)? {
+                self.inner.submit_urb_async(interface, urb).await?;
+                Self::truncate(fault, urb);
+            }
+            Ok(())
+        })
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        self.inner.supported_requests()
+    }
+
+    fn set_endpoint_halt(&mut self, interface: &UsbInterface, ep: UsbEndpoint, halted: bool) {
+        self.inner.set_endpoint_halt(interface, ep, halted)
+    }
+
+    fn set_alternate_setting(&mut self, interface: &UsbInterface, alternate_setting: u8) {
+        self.inner
+            .set_alternate_setting(interface, alternate_setting)
+    }
+
+    fn release(&mut self, interface: &UsbInterface) {
+        self.inner.release(interface)
+    }
+
+    fn on_suspend(&mut self, interface: &UsbInterface) {
+        self.inner.on_suspend(interface)
+    }
+
+    fn on_resume(&mut self, interface: &UsbInterface) {
+        self.inner.on_resume(interface)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    const BULK_IN: UsbEndpoint = UsbEndpoint {
+        address: 0x81,
+        attributes: EndpointAttributes::Bulk as u8,
+        max_packet_size: 512,
+        interval: 0,
+    };
+
+    fn bulk_in(len: u32) -> Urb {
+        Urb {
+            endpoint: BULK_IN,
+            transfer_buffer_length: len,
+            ..Default::default()
+        }
+    }
+
+    #[derive(Debug)]
+    struct Zeros;
+
+    impl UsbInterfaceHandler for Zeros {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![0; transfer_buffer_length as usize])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn faults_follow_policy() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            0xff,
+            0x00,
+            0x00,
+            None,
+            vec![BULK_IN],
+            shared_interface_handler(Zeros),
+        );
+        let interface = &device.interfaces[0];
+        let mut handler = FaultInjectingHandler::new(Zeros, UsbFaultPolicy::default()).with_seed(1);
+        let mut urb = bulk_in(64);
+        handler.submit_urb_async(interface, &mut urb).await.unwrap();
+        assert_eq!((urb.status, urb.actual_length), (0, 64));
+
+        handler.set_policy(UsbFaultPolicy {
+            stall_probability: 1.0,
+            ..Default::default()
+        });
+        let mut urb = bulk_in(64);
+        handler.submit_urb(interface, &mut urb).unwrap();
+        assert_eq!(urb.status, -crate::usbip_protocol::EPIPE);
+
+        handler.set_policy(UsbFaultPolicy {
+            error_probability: 1.0,
+            ..Default::default()
+        });
+        assert!(handler.submit_urb(interface, &mut bulk_in(64)).is_err());
+
+        handler.set_policy(UsbFaultPolicy {
+            truncate_probability: 1.0,
+            delay_probability: 1.0,
+            delay: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let start = std::time::Instant::now();
+        let mut urb = bulk_in(0x1000);
+        handler.submit_urb_async(interface, &mut urb).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert!(urb.actual_length < 0x1000);
+        assert_eq!(urb.buffer.len(), urb.actual_length as usize);
+    }
+}
@@ -0,0 +1,139 @@
+//! Running the server over framed transports
+//!
+//! Embedders tunneling USB/IP inside their own protocol have a stream of
+//! frames rather than a socket. [framed_handler] runs [handler](crate::handler)
+//! over such a transport, where each response is sent in a frame of its own
+//! and frames are read as one continuous stream. [length_delimited_handler]
+//! uses it with a length prefix on top of a plain byte stream.
+use super::*;
+use bytes::{Buf, Bytes};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Byte stream over a transport of frames
+struct FramedIo<T> {
+    transport: T,
+    /// Rest of the frame being read
+    pending: Bytes,
+    /// Whether frames were sent since the last flush
+    unflushed: bool,
+}
+
+impl<T, B> AsyncRead for FramedIo<T>
+where
+    T: Stream<Item = Result<B>> + Sink<Bytes, Error = std::io::Error> + Unpin,
+    B: Into<Bytes>,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        // the handler does not flush, send the responses before waiting for more commands
+        if self.unflushed {
+            ready!(Pin::new(&mut self.transport).poll_flush(cx))?;
+            self.unflushed = false;
+        }
+        while self.pending.is_empty() {
+            match ready!(Pin::new(&mut self.transport).poll_next(cx)) {
+                Some(frame) => self.pending = frame?.into(),
+                // end of stream
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncWrite for FramedIo<T>
+where
+    T: Sink<Bytes, Error = std::io::Error> + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        ready!(Pin::new(&mut self.transport).poll_ready(cx))?;
+        Pin::new(&mut self.transport).start_send(Bytes::copy_from_slice(buf))?;
+        self.unflushed = true;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(Pin::new(&mut self.transport).poll_flush(cx))?;
+        self.unflushed = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.transport).poll_close(cx)
+    }
+}
+
+/// Like [handler](crate::handler), over a transport of frames
+///
+/// Commands may be split across frames or share them. Every response is
+/// sent as a single frame, and the transport is flushed before reading the
+/// next command.
+pub async fn framed_handler<T, B>(
+    transport: T,
+    server: Arc<UsbIpServer>,
+) -> core::result::Result<(), UsbIpError>
+where
+    T: Stream<Item = Result<B>> + Sink<Bytes, Error = std::io::Error> + Unpin,
+    B: Into<Bytes>,
+{
+    let mut io = FramedIo {
+        transport,
+        pending: Bytes::new(),
+        unflushed: false,
+    };
+    crate::handler(&mut io, server).await
+}
+
+/// Like [handler](crate::handler), with every frame prefixed by its length
+///
+/// The default [LengthDelimitedCodec] is used, a 4 byte big endian length.
+pub async fn length_delimited_handler<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: T,
+    server: Arc<UsbIpServer>,
+) -> core::result::Result<(), UsbIpError> {
+    framed_handler(Framed::new(socket, LengthDelimitedCodec::new()), server).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usbip_protocol::{UsbIpCommand, UsbIpResponse};
+    use crate::util::tests::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn length_delimited_devlist() {
+        setup_test_logger();
+        let (mut client, socket) = tokio::io::duplex(0x1000);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let task = tokio::spawn(length_delimited_handler(socket, server));
+
+        let req = UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes();
+        client.write_u32(req.len() as u32).await.unwrap();
+        client.write_all(&req).await.unwrap();
+
+        let len = client.read_u32().await.unwrap();
+        let mut res = vec![0; len as usize];
+        client.read_exact(&mut res).await.unwrap();
+        assert_eq!(res, UsbIpResponse::op_rep_devlist(&[]).to_bytes());
+
+        drop(client);
+        assert!(task.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+//! Gadget-style composition of devices
+//!
+//! Like a configfs gadget of Linux, a [UsbGadget] is a set of reusable
+//! functions, e.g. ACM, mass storage, HID or NCM, bound into a configuration.
+//! Each function adds its interfaces after those of the previous one, so
+//! interface numbers, endpoint addresses, interface associations and class
+//! descriptors referring to interface numbers come out coherent.
+use super::*;
+
+/// Adds the interfaces of a [UsbGadgetFunction] to the device
+pub type UsbGadgetBind = Box<dyn FnOnce(UsbDevice) -> UsbDevice + Send>;
+
+/// A function of a [UsbGadget]
+///
+/// Besides the ready-made functions, any [UsbFunction] can be used with
+/// [UsbGadgetFunction::from_function], or a closure adding interfaces with
+/// [UsbGadgetFunction::new].
+pub struct UsbGadgetFunction {
+    /// Name of the function, like `acm` in `acm.usb0`, for logs
+    pub name: String,
+    bind: UsbGadgetBind,
+}
+
+impl std::fmt::Debug for UsbGadgetFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbGadgetFunction")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UsbGadgetFunction {
+    /// A function adding its interfaces with `bind`
+    ///
+    /// The first interface of the function is `device.interfaces.len()`, and
+    /// [UsbDevice::with_function] numbers the endpoints.
+    pub fn new(name: &str, bind: impl FnOnce(UsbDevice) -> UsbDevice + Send + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            bind: Box::new(bind),
+        }
+    }
+
+    pub fn from_function(function: UsbFunction) -> Self {
+        let name = function
+            .name
+            .clone()
+            .unwrap_or_else(|| "function".to_string());
+        Self::new(&name, move |device| device.with_function(function))
+    }
+
+    /// A CDC ACM serial port served by `handler`, see [UsbDevice::with_cdc_acm_function]
+    pub fn acm(handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>) -> Self {
+        Self::new("acm", move |device| {
+            device.with_cdc_acm_function(Some("Serial Port"), handler)
+        })
+    }
+}
+
+/// A device made of [UsbGadgetFunction]s in one configuration
+///
+/// Only one configuration can be bound, as a [UsbDevice] serves one.
+#[derive(Debug)]
+pub struct UsbGadget {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_bcd: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Name of the configuration, like `c.1` of configfs
+    pub configuration: Option<String>,
+    /// Maximum power of the configuration in mA
+    pub max_power: u16,
+    pub functions: Vec<UsbGadgetFunction>,
+}
+
+impl UsbGadget {
+    pub fn new(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            device_bcd: 0x0100,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            configuration: None,
+            max_power: 100,
+            functions: vec![],
+        }
+    }
+
+    pub fn with_strings(mut self, manufacturer: &str, product: &str, serial_number: &str) -> Self {
+        self.manufacturer = Some(manufacturer.to_string());
+        self.product = Some(product.to_string());
+        self.serial_number = Some(serial_number.to_string());
+        self
+    }
+
+    /// Name and maximum power in mA of the configuration
+    pub fn with_configuration(mut self, name: &str, max_power: u16) -> Self {
+        self.configuration = Some(name.to_string());
+        self.max_power = max_power;
+        self
+    }
+
+    /// Bind a function after the previous ones
+    pub fn with_function(mut self, function: UsbGadgetFunction) -> Self {
+        self.functions.push(function);
+        self
+    }
+
+    /// Assemble the device from the functions in order
+    pub fn build(self) -> UsbDevice {
+        let mut device = UsbDevice::composite(0);
+        device.vendor_id = self.vendor_id;
+        device.product_id = self.product_id;
+        device.device_bcd = self.device_bcd.into();
+        if let Some(manufacturer) = &self.manufacturer {
+            device.set_manufacturer_name(manufacturer);
+        }
+        if let Some(product) = &self.product {
+            device.set_product_name(product);
+        }
+        if let Some(serial_number) = &self.serial_number {
+            device.set_serial_number(serial_number);
+        }
+        if let Some(configuration) = &self.configuration {
+            device.set_configuration_name(configuration);
+        }
+        // bMaxPower is in 2mA units
+        device.max_power = (self.max_power / 2).min(0xFF) as u8;
+        for function in self.functions {
+            let first_interface = device.interfaces.len();
+            device = (function.bind)(device);
+            debug!(
+                "Gadget function {} has interfaces {first_interface}..{}",
+                function.name,
+                device.interfaces.len()
+            );
+        }
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn gadget_functions() {
+        setup_test_logger();
+        let (hid, _reports) = hid::GenericHidHandler::channel(
+            vec![
+                0x06, 0x00, 0xFF, // Usage Page (Vendor Defined)
+                0x09, 0x01, // Usage (1)
+                0xA1, 0x01, // Collection (Application)
+                0x15, 0x00, // Logical Minimum (0)
+                0x26, 0xFF, 0x00, // Logical Maximum (255)
+                0x75, 0x08, // Report Size (8)
+                0x95, 0x40, // Report Count (64)
+                0x09, 0x01, // Usage (1)
+                0x81, 0x02, // Input (Data, Variable, Absolute)
+                0xC0, // End Collection
+            ],
+            |_, _| {},
+        );
+        let (backend, _, _) = cdc::ChannelEthernetBackend::new();
+        let ncm = cdc::UsbCdcNcmHandler::new([0x02, 0, 0, 0, 0, 1], backend);
+        let device = UsbGadget::new(0x1209, 0x0001)
+            .with_strings("usbip", "Gadget", "000000000001")
+            .with_configuration("c.1", 500)
+            .with_function(UsbGadgetFunction::acm(shared_interface_handler(
+                cdc::UsbCdcAcmHandler::new(),
+            )))
+            .with_function(UsbGadgetFunction::mass_storage(
+                msc::UsbMassStorageHandler::from_memory(vec![0; 512 * 64]),
+            ))
+            .with_function(UsbGadgetFunction::hid(hid))
+            .with_function(UsbGadgetFunction::ncm(ncm))
+            .build();
+        verify_descriptor(&device.configuration_descriptor(0xFFFF));
+        assert_eq!(device.max_power, 250);
+
+        let classes: Vec<u8> = device
+            .interfaces
+            .iter()
+            .map(|intf| intf.interface_class)
+            .collect();
+        assert_eq!(
+            classes,
+            [
+                ClassCode::CDC as u8,
+                ClassCode::CDCData as u8,
+                ClassCode::MassStorage as u8,
+                ClassCode::HID as u8,
+                ClassCode::CDC as u8,
+                ClassCode::CDCData as u8,
+            ]
+        );
+        let addresses: Vec<Vec<u8>> = device
+            .interfaces
+            .iter()
+            .map(|intf| intf.all_endpoints().map(|ep| ep.address).collect())
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                vec![0x81],
+                vec![0x82, 0x01],
+                vec![0x83, 0x02],
+                vec![0x84],
+                vec![0x85],
+                vec![0x86, 0x03],
+            ]
+        );
+        let associations: Vec<(u8, u8)> = device
+            .interface_associations
+            .iter()
+            .map(|iad| (iad.first_interface, iad.interface_count))
+            .collect();
+        assert_eq!(associations, [(0, 2), (4, 2)]);
+
+        // the union of the NCM function refers to its own interfaces
+        let union = [0x05, 0x24, 0x06, 4, 5];
+        assert!(
+            device.interfaces[4]
+                .class_specific_descriptor
+                .windows(union.len())
+                .any(|window| window == union)
+        );
+    }
+}
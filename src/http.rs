@@ -0,0 +1,144 @@
+//! Just enough HTTP/1.1 for the metrics and admin endpoints
+//!
+//! Every connection carries a single request, answered with
+//! `Connection: close`.
+use std::io::{Error, ErrorKind, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Longest request head and body accepted
+const MAX_HEAD: usize = 0x10000;
+const MAX_BODY: usize = 0x100000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    /// Without the query
+    pub(crate) path: String,
+    /// Names in lowercase
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpRequest {
+    #[cfg(feature = "admin")]
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a request, `None` if the connection is closed before its head ends
+pub(crate) async fn read_request<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+) -> Result<Option<HttpRequest>> {
+    let mut request = vec![];
+    let mut buf = [0; 0x400];
+    let head_len = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if request.len() > MAX_HEAD {
+            return Err(Error::new(ErrorKind::InvalidData, "Request head too long"));
+        }
+        let len = socket.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..len]);
+    };
+
+    let head = std::str::from_utf8(&request[..head_len])
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let headers: Vec<_> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .map(|(_, value)| value.parse::<usize>())
+        .transpose()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        .unwrap_or(0);
+    if content_length > MAX_BODY {
+        return Err(Error::new(ErrorKind::InvalidData, "Request body too long"));
+    }
+
+    let mut body = request.split_off(head_len);
+    body.truncate(content_length);
+    let read = body.len();
+    body.resize(content_length, 0);
+    socket.read_exact(&mut body[read..]).await?;
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+/// Write a response and close the connection
+pub(crate) async fn write_response<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        501 => "Not Implemented",
+        _ => "Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read_request_with_body() {
+        setup_test_logger();
+        let (mut client, mut server) = tokio::io::duplex(0x1000);
+        client
+            .write_all(b"POST /devices?x=1 HTTP/1.1\r\nContent-Length: 4\r\n\r\nbo")
+            .await
+            .unwrap();
+        let request = tokio::spawn(async move { read_request(&mut server).await });
+        client.write_all(b"dy").await.unwrap();
+        assert_eq!(
+            request.await.unwrap().unwrap(),
+            Some(HttpRequest {
+                method: "POST".to_string(),
+                path: "/devices".to_string(),
+                headers: vec![("content-length".to_string(), "4".to_string())],
+                body: b"body".to_vec(),
+            })
+        );
+
+        let (client, mut server) = tokio::io::duplex(0x1000);
+        drop(client);
+        assert_eq!(read_request(&mut server).await.unwrap(), None);
+    }
+}
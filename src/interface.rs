@@ -1,4 +1,16 @@
 use super::*;
+use std::future::Future;
+use std::pin::Pin;
+
+/// An alternate setting of a [UsbInterface], other than the default setting 0
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbAlternateSetting {
+    pub endpoints: Vec<UsbEndpoint>,
+    pub class_specific_descriptor: Vec<u8>,
+    /// Class specific descriptors following the descriptor of an endpoint of this setting, by address
+    pub class_specific_endpoint_descriptors: HashMap<u8, Vec<u8>>,
+}
 
 /// Represent a USB interface
 #[derive(Clone, Debug)]
@@ -10,9 +22,257 @@ pub struct UsbInterface {
     pub endpoints: Vec<UsbEndpoint>,
     pub string_interface: u8,
     pub class_specific_descriptor: Vec<u8>,
+    /// Class specific descriptors following the descriptor of an endpoint of setting 0, by address
+    ///
+    /// Alternate settings reuse endpoint addresses, so they have their own.
+    pub class_specific_endpoint_descriptors: HashMap<u8, Vec<u8>>,
+    /// Alternate settings 1 and up, see [UsbDevice::with_alternate_setting]
+    pub alternate_settings: Vec<UsbAlternateSetting>,
 
     #[cfg_attr(feature = "serde", serde(skip))]
-    pub handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    pub handler: Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>>,
+
+    /// Index of this interface in the configuration
+    pub(crate) interface_number: u8,
+
+    /// Runtime state shared with the owning [UsbDevice]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) device_state: Arc<Mutex<UsbDeviceState>>,
+
+    /// Why transfers to this interface cannot be served, if they cannot
+    pub(crate) unavailable_reason: Option<String>,
+}
+
+impl UsbInterface {
+    /// Endpoints of the default and all alternate settings
+    pub fn all_endpoints(&self) -> impl Iterator<Item = &UsbEndpoint> {
+        self.endpoints.iter().chain(
+            self.alternate_settings
+                .iter()
+                .flat_map(|alt| &alt.endpoints),
+        )
+    }
+
+    /// Endpoints of the current alternate setting
+    pub fn current_endpoints(&self) -> &[UsbEndpoint] {
+        match self.alternate_setting() {
+            0 => &self.endpoints,
+            alt => self
+                .alternate_settings
+                .get(alt as usize - 1)
+                .map_or(&[], |setting| &setting.endpoints),
+        }
+    }
+
+    /// bInterfaceNumber of this interface
+    pub fn interface_number(&self) -> u8 {
+        self.interface_number
+    }
+
+    /// bConfigurationValue of the current configuration of the owning device
+    ///
+    /// Zero if the device is not configured.
+    pub fn current_configuration(&self) -> u8 {
+        self.device_state.lock().unwrap().configuration
+    }
+
+    /// Current alternate setting of this interface
+    pub fn alternate_setting(&self) -> u8 {
+        self.device_state
+            .lock()
+            .unwrap()
+            .alternate_setting(self.interface_number)
+    }
+
+    /// Why this interface is unavailable, e.g. a host interface that could not be claimed
+    ///
+    /// The interface is still exported so interface numbers stay intact,
+    /// but all transfers to it fail.
+    pub fn unavailable_reason(&self) -> Option<&str> {
+        self.unavailable_reason.as_deref()
+    }
+
+    /// Signal remote wakeup to the host on behalf of the owning device
+    ///
+    /// Returns whether the wakeup is allowed. Handlers call this while they
+    /// are locked, so the device cannot notify them from here: a suspended
+    /// device is marked [UsbDeviceState::wakeup_pending] instead, and
+    /// resumes with [UsbDevice::request_remote_wakeup] or [UsbDevice::resume].
+    pub fn request_remote_wakeup(&self) -> bool {
+        let mut state = self.device_state.lock().unwrap();
+        if !state.remote_wakeup() {
+            return false;
+        }
+        state.wakeup_pending |= state.suspended;
+        true
+    }
+}
+
+/// An interface association grouping consecutive interfaces into one function
+///
+/// Written as an Interface Association Descriptor right before the first interface.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbInterfaceAssociation {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_function: u8,
+}
+
+impl UsbInterfaceAssociation {
+    pub(crate) fn to_bytes(self) -> [u8; 8] {
+        [
+            0x08,                                       // bLength
+            DescriptorType::InterfaceAssociation as u8, // bDescriptorType
+            self.first_interface,                       // bFirstInterface
+            self.interface_count,                       // bInterfaceCount
+            self.function_class,                        // bFunctionClass
+            self.function_subclass,                     // bFunctionSubClass
+            self.function_protocol,                     // bFunctionProtocol
+            self.string_function,                       // iFunction
+        ]
+    }
+}
+
+/// Wrap an interface handler for [UsbDevice::with_interface]
+///
+/// The handler is behind a [tokio::sync::Mutex], which may be held across
+/// `.await` and is never poisoned. Keep a clone to reach the handler later.
+pub fn shared_interface_handler(
+    handler: impl UsbInterfaceHandler + Send + 'static,
+) -> Arc<AsyncMutex<Box<dyn UsbInterfaceHandler + Send>>> {
+    Arc::new(AsyncMutex::new(Box::new(handler)))
+}
+
+/// Run a synchronous handler on the blocking thread pool of tokio
+///
+/// URBs are submitted to the wrapped handler with [tokio::task::spawn_blocking],
+/// so handlers doing blocking I/O do not stall the server. The queue bounds how
+/// many URBs run or wait on the pool at a time, and can be shared by several
+/// handlers with [BlockingInterfaceHandler::with_queue].
+#[derive(Clone, Debug)]
+pub struct BlockingInterfaceHandler {
+    inner: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    queue: Arc<tokio::sync::Semaphore>,
+    supported_requests: Option<Vec<u8>>,
+}
+
+impl BlockingInterfaceHandler {
+    /// Wrap `handler` with a queue of `max_pending` URBs
+    pub fn new(handler: impl UsbInterfaceHandler + Send + 'static, max_pending: usize) -> Self {
+        Self::with_queue(handler, Arc::new(tokio::sync::Semaphore::new(max_pending)))
+    }
+
+    /// Wrap `handler`, taking a permit of `queue` for every URB
+    pub fn with_queue(
+        handler: impl UsbInterfaceHandler + Send + 'static,
+        queue: Arc<tokio::sync::Semaphore>,
+    ) -> Self {
+        Self {
+            supported_requests: handler.supported_requests().map(<[u8]>::to_vec),
+            inner: Arc::new(Mutex::new(Box::new(handler))),
+            queue,
+        }
+    }
+
+    /// The wrapped handler, e.g. to downcast it
+    pub fn inner(&self) -> &Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        &self.inner
+    }
+}
+
+impl UsbInterfaceHandler for BlockingInterfaceHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.inner.lock().unwrap().get_class_specific_descriptor()
+    }
+
+    fn get_class_specific_endpoint_descriptor(&self, endpoint: &UsbEndpoint) -> Vec<u8> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get_class_specific_endpoint_descriptor(endpoint)
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .handle_urb(interface, ep, transfer_buffer_length, setup, req)
+    }
+
+    fn submit_urb(&mut self, interface: &UsbInterface, urb: &mut Urb) -> Result<()> {
+        self.inner.lock().unwrap().submit_urb(interface, urb)
+    }
+
+    fn submit_urb_async<'a>(
+        &'a mut self,
+        interface: &'a UsbInterface,
+        urb: &'a mut Urb,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let _permit = self
+                .queue
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(std::io::Error::other)?;
+            let inner = self.inner.clone();
+            let interface = interface.clone();
+            let mut owned = std::mem::take(urb);
+            let (res, owned) = tokio::task::spawn_blocking(move || {
+                let res = inner.lock().unwrap().submit_urb(&interface, &mut owned);
+                (res, owned)
+            })
+            .await
+            .map_err(std::io::Error::other)?;
+            *urb = owned;
+            res
+        })
+    }
+
+    fn supported_requests(&self) -> Option<&[u8]> {
+        self.supported_requests.as_deref()
+    }
+
+    fn set_endpoint_halt(&mut self, interface: &UsbInterface, ep: UsbEndpoint, halted: bool) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_endpoint_halt(interface, ep, halted)
+    }
+
+    fn set_alternate_setting(&mut self, interface: &UsbInterface, alternate_setting: u8) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_alternate_setting(interface, alternate_setting)
+    }
+
+    fn release(&mut self, interface: &UsbInterface) {
+        self.inner.lock().unwrap().release(interface)
+    }
+
+    fn on_suspend(&mut self, interface: &UsbInterface) {
+        self.inner.lock().unwrap().on_suspend(interface)
+    }
+
+    fn on_resume(&mut self, interface: &UsbInterface) {
+        self.inner.lock().unwrap().on_resume(interface)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
 }
 
 /// A handler of a custom usb interface
@@ -20,6 +280,13 @@ pub trait UsbInterfaceHandler: std::fmt::Debug {
     /// Return the class specific descriptor which is inserted between interface descriptor and endpoint descriptor
     fn get_class_specific_descriptor(&self) -> Vec<u8>;
 
+    /// Return the class specific descriptor which is inserted after the descriptor of `endpoint`
+    ///
+    /// Empty by default. Audio and MIDI streaming interfaces describe their endpoints this way.
+    fn get_class_specific_endpoint_descriptor(&self, _endpoint: &UsbEndpoint) -> Vec<u8> {
+        vec![]
+    }
+
     /// Handle a URB(USB Request Block) targeting at this interface
     ///
     /// Can be one of: control transfer to ep0 or other types of transfer to its endpoint.
@@ -33,6 +300,73 @@ pub trait UsbInterfaceHandler: std::fmt::Debug {
         req: &[u8],
     ) -> Result<Vec<u8>>;
 
+    /// Handle a URB with all of its parameters
+    ///
+    /// The default implementation calls [handle_urb](UsbInterfaceHandler::handle_urb)
+    /// and completes `urb` with the result. Override it to use transfer flags or
+    /// isochronous packets, or to report another status.
+    fn submit_urb(&mut self, interface: &UsbInterface, urb: &mut Urb) -> Result<()> {
+        let data = self.handle_urb(
+            interface,
+            urb.endpoint,
+            urb.transfer_buffer_length,
+            urb.setup,
+            &urb.buffer,
+        )?;
+        urb.complete(data);
+        Ok(())
+    }
+
+    /// Asynchronous variant of [submit_urb](UsbInterfaceHandler::submit_urb), used by the server
+    ///
+    /// The default implementation calls `submit_urb` on the current task.
+    /// Override it to wait for transfers without blocking the runtime, or wrap
+    /// blocking handlers in [BlockingInterfaceHandler].
+    fn submit_urb_async<'a>(
+        &'a mut self,
+        interface: &'a UsbInterface,
+        urb: &'a mut Urb,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>
+    where
+        Self: Send,
+    {
+        Box::pin(async move { self.submit_urb(interface, urb) })
+    }
+
+    /// bRequest of the class requests this handler implements
+    ///
+    /// Other class requests to the interface are stalled without calling
+    /// [handle_urb](UsbInterfaceHandler::handle_urb). The default, `None`,
+    /// passes all of them.
+    fn supported_requests(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Called when the halt feature of one of its endpoints is set or cleared
+    ///
+    /// Clearing the halt feature resets the data toggle of the endpoint,
+    /// so emulated devices can reset their per-endpoint state here.
+    fn set_endpoint_halt(&mut self, _interface: &UsbInterface, _ep: UsbEndpoint, _halted: bool) {}
+
+    /// Called when the host selects an alternate setting with SET_INTERFACE
+    ///
+    /// Also called with 0 when SET_CONFIGURATION resets a non-zero setting.
+    fn set_alternate_setting(&mut self, _interface: &UsbInterface, _alternate_setting: u8) {}
+
+    /// Called before SET_CONFIGURATION reaches the device handler
+    ///
+    /// Handlers of host interfaces give up their claim, as the kernel
+    /// refuses to switch configurations while interfaces are claimed.
+    fn release(&mut self, _interface: &UsbInterface) {}
+
+    /// Called when the owning device is suspended, e.g. when the client detaches
+    ///
+    /// Emulated devices can checkpoint their state here.
+    fn on_suspend(&mut self, _interface: &UsbInterface) {}
+
+    /// Called when the owning device resumes from suspend
+    fn on_resume(&mut self, _interface: &UsbInterface) {}
+
     /// Helper to downcast to actual struct
     ///
     /// Please implement it as:
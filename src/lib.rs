@@ -1,36 +1,182 @@
 //! A library for running a USB/IP server
+//!
+//! With the default `std` feature, this provides simulated and host devices
+//! and the server. Without it, only [usbip_protocol] is available through the
+//! `protocol-only` feature, which needs nothing but `alloc`.
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
 use log::*;
+#[cfg(feature = "std")]
 use num_derive::FromPrimitive;
+#[cfg(feature = "std")]
 use num_traits::FromPrimitive;
 //use rusb::*;
+#[cfg(feature = "std")]
 use std::any::Any;
-use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "std")]
 use std::io::Result;
+#[cfg(feature = "std")]
 use std::sync::{Arc, Mutex};
+#[cfg(feature = "std")]
+use tokio::sync::Mutex as AsyncMutex;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "admin")]
+mod admin;
+#[cfg(feature = "codec")]
+mod codec;
+#[cfg(feature = "futures-io")]
+mod compat;
+#[cfg(feature = "std")]
+mod composite;
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "std")]
+mod conformance;
+#[cfg(feature = "std")]
 mod consts;
+#[cfg(feature = "serde")]
+mod definition;
+#[cfg(feature = "std")]
 mod device;
+#[cfg(feature = "std")]
 mod devices;
+#[cfg(feature = "std")]
 mod endpoint;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+mod fault;
+#[cfg(feature = "codec")]
+mod framed;
+#[cfg(feature = "std")]
+mod gadget;
+#[cfg(any(feature = "metrics", feature = "admin"))]
+mod http;
+#[cfg(feature = "std")]
 mod interface;
+#[cfg(feature = "std")]
+mod macros;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "std")]
+mod ms_os;
+#[cfg(feature = "std")]
+mod path;
+#[cfg(feature = "std")]
+mod pcap;
+#[cfg(feature = "std")]
+mod record;
+#[cfg(feature = "std")]
+mod replay;
+#[cfg(feature = "std")]
+mod scratch;
+#[cfg(feature = "std")]
+mod self_test;
+#[cfg(feature = "std")]
 mod setup;
+#[cfg(feature = "std")]
+mod shaping;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "std")]
+mod urb;
+#[cfg(feature = "protocol-only")]
 pub mod usbip_protocol;
+#[cfg(feature = "std")]
 mod util;
+#[cfg(feature = "std")]
+mod webusb;
+#[cfg(feature = "admin")]
+pub use admin::*;
+#[cfg(feature = "codec")]
+pub use codec::*;
+#[cfg(feature = "futures-io")]
+pub use compat::*;
+#[cfg(feature = "std")]
+pub use composite::*;
+#[cfg(feature = "config")]
+pub use config::*;
+#[cfg(feature = "std")]
+pub use conformance::*;
+#[cfg(feature = "std")]
 pub use consts::*;
+#[cfg(feature = "serde")]
+pub use definition::*;
+#[cfg(feature = "std")]
 pub use device::*;
 #[cfg(feature = "rusb")]
 pub use devices::host::*;
-pub use devices::{cdc, hid};
+#[cfg(feature = "serial")]
+pub use devices::serial;
+#[cfg(feature = "std")]
+pub use devices::{
+    audio, ccid, cdc, cp210x, ctap, ftdi, hid, hub, loopback, midi, msc, mtp, printer, raw, rndis,
+    usbtmc, uvc,
+};
+#[cfg(feature = "std")]
 pub use endpoint::*;
+#[cfg(feature = "std")]
+pub use error::*;
+#[cfg(feature = "std")]
+pub use fault::*;
+#[cfg(feature = "codec")]
+pub use framed::*;
+#[cfg(feature = "std")]
+pub use gadget::*;
+#[cfg(feature = "std")]
 pub use interface::*;
+#[cfg(feature = "mdns")]
+pub use mdns::*;
+#[cfg(feature = "metrics")]
+pub use metrics::*;
+#[cfg(feature = "std")]
+pub use ms_os::*;
+#[cfg(feature = "std")]
+pub use path::*;
+#[cfg(feature = "std")]
+pub use pcap::*;
+#[cfg(feature = "std")]
+pub use record::*;
+#[cfg(feature = "std")]
+pub use replay::*;
+#[cfg(feature = "std")]
+pub use scratch::*;
+#[cfg(feature = "std")]
+pub use self_test::*;
+#[cfg(feature = "std")]
 pub use setup::*;
+#[cfg(feature = "std")]
+pub use shaping::*;
+#[cfg(feature = "tls")]
+pub use tls::*;
+#[cfg(feature = "std")]
+pub use urb::*;
+#[cfg(feature = "std")]
 pub use util::*;
+#[cfg(feature = "std")]
+pub use webusb::*;
+#[cfg(feature = "std")]
 mod usbip_server;
+#[cfg(feature = "blocking")]
+pub use usbip_server::blocking::{blocking_handler, blocking_server};
+#[cfg(feature = "vsock")]
+pub use usbip_server::server::vsock_server;
+#[cfg(feature = "std")]
 pub use usbip_server::{
-    UsbIpServer,
-    server::{handler, server},
+    UsbErrorBudget, UsbIpDebugDelays, UsbIpEvent, UsbIpImport, UsbIpServer, UsbIpShard,
+    builder::{ListenAddr, UsbIpListeners, UsbIpServerBuilder},
+    server::{handler, peer_handler, server, shard_handler, shard_server},
+    stats::{ConnectionStats, DeviceStats, ServerStats, UrbStats},
 };
@@ -0,0 +1,121 @@
+//! Declarative device definitions
+
+/// Declare a simulated device with its interfaces and endpoints in one block
+///
+/// Device fields are assigned to a [UsbDevice](crate::UsbDevice) created with
+/// `UsbDevice::new(index)`, and interfaces are added in order. Field names and
+/// types are checked by the compiler.
+///
+/// ```ignore
+/// let device = usb_device! {
+///     index: 0,
+///     vendor_id: 0x1234,
+///     product_id: 0x5678,
+///     interface {
+///         class: ClassCode::HID as u8,
+///         subclass: 0x00,
+///         protocol: 0x00,
+///         name: "Keyboard",
+///         endpoints: [{
+///             address: 0x81,
+///             attributes: EndpointAttributes::Interrupt as u8,
+///             max_packet_size: 0x08,
+///             interval: 10,
+///         }],
+///         handler: hid::UsbHidKeyboardHandler::new_keyboard(),
+///     }
+/// };
+/// ```
+#[macro_export]
+macro_rules! usb_device {
+    (index: $index:expr $(, $($body:tt)*)?) => {{
+        #[allow(unused_mut)]
+        let mut device = $crate::UsbDevice::new($index);
+        $crate::usb_device!(@body device, $($($body)*)?);
+        device
+    }};
+    (@body $device:ident,) => {};
+    (@body $device:ident, interface { $($interface:tt)* } $($rest:tt)*) => {
+        $device = $crate::usb_device!(@interface $device, $($interface)*);
+        $crate::usb_device!(@body $device, $($rest)*);
+    };
+    (@body $device:ident, $field:ident: $value:expr $(, $($rest:tt)*)?) => {
+        $device.$field = $value;
+        $crate::usb_device!(@body $device, $($($rest)*)?);
+    };
+    (
+        @interface $device:ident,
+        class: $class:expr,
+        subclass: $subclass:expr,
+        protocol: $protocol:expr,
+        $(name: $name:expr,)?
+        endpoints: [$({ $($endpoint:tt)* }),* $(,)?],
+        handler: $handler:expr $(,)?
+    ) => {
+        $device.with_interface(
+            $class,
+            $subclass,
+            $protocol,
+            $crate::usb_device!(@name $($name)?),
+            vec![$($crate::UsbEndpoint { $($endpoint)* }),*],
+            $crate::shared_interface_handler($handler),
+        )
+    };
+    (@name) => {
+        None
+    };
+    (@name $name:expr) => {
+        Some($name)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+    use crate::*;
+
+    #[test]
+    fn declare_device() {
+        setup_test_logger();
+        let device = usb_device! {
+            index: 1,
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            interface {
+                class: ClassCode::CDC as u8,
+                subclass: cdc::CDC_ACM_SUBCLASS,
+                protocol: 0x00,
+                endpoints: [
+                    {
+                        address: 0x81,
+                        attributes: EndpointAttributes::Interrupt as u8,
+                        max_packet_size: 0x08,
+                        interval: 10,
+                    },
+                    {
+                        address: 0x02,
+                        attributes: EndpointAttributes::Bulk as u8,
+                        max_packet_size: 512,
+                        interval: 0,
+                    },
+                ],
+                handler: cdc::UsbCdcAcmHandler::new(),
+            }
+            interface {
+                class: ClassCode::HID as u8,
+                subclass: 0x00,
+                protocol: 0x00,
+                name: "Keyboard",
+                endpoints: [],
+                handler: hid::UsbHidKeyboardHandler::new_keyboard(),
+            }
+        };
+        assert_eq!(device.dev_num, 1);
+        assert_eq!(device.vendor_id, 0x1234);
+        assert_eq!(device.product_id, 0x5678);
+        assert_eq!(device.interfaces.len(), 2);
+        assert_eq!(device.interfaces[0].endpoints.len(), 2);
+        assert_eq!(device.interfaces[0].string_interface, 0);
+        assert_ne!(device.interfaces[1].string_interface, 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! Discovery of servers on the local network
+//!
+//! [advertise_mdns] announces the USB/IP service of a server with mDNS, so
+//! clients find it without knowing its address. The `devices` TXT property
+//! lists the VID:PID of the devices available for import, and follows
+//! changes through [UsbIpServer::subscribe].
+use super::*;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::IpAddr;
+
+/// Service type of USB/IP servers
+pub const MDNS_SERVICE_TYPE: &str = "_usbip._tcp.local.";
+
+/// Longest value of a TXT property, the record limits key, `=` and value to 255 bytes
+const MAX_TXT_VALUE: usize = 255 - "devices=".len();
+
+/// Announcement by [advertise_mdns], withdrawn when dropped
+pub struct MdnsAdvertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+    updates: tokio::task::AbortHandle,
+}
+
+impl MdnsAdvertisement {
+    /// Full service name, e.g. `name._usbip._tcp.local.`
+    pub fn fullname(&self) -> &str {
+        &self.fullname
+    }
+}
+
+impl Drop for MdnsAdvertisement {
+    fn drop(&mut self) {
+        self.updates.abort();
+        if let Err(err) = self.daemon.unregister(&self.fullname) {
+            warn!("Failed to withdraw mDNS announcement: {err}");
+        }
+        self.daemon.shutdown().ok();
+    }
+}
+
+/// Announce the server listening at `port` as `instance_name` with mDNS
+///
+/// The addresses of all network interfaces are announced, and kept up to
+/// date by the mDNS daemon.
+pub async fn advertise_mdns(
+    server: Arc<UsbIpServer>,
+    instance_name: &str,
+    port: u16,
+) -> Result<MdnsAdvertisement> {
+    let daemon = ServiceDaemon::new().map_err(std::io::Error::other)?;
+    let instance_name = instance_name.to_string();
+    let host_name = format!("{}.local.", instance_name.replace(['.', ' '], "-"));
+    let service = move |devices: &[UsbDevice]| {
+        let properties = [("devices", txt_devices(devices))];
+        ServiceInfo::new(
+            MDNS_SERVICE_TYPE,
+            &instance_name,
+            &host_name,
+            &[] as &[IpAddr],
+            port,
+            &properties[..],
+        )
+        .map(ServiceInfo::enable_addr_auto)
+        .map_err(std::io::Error::other)
+    };
+
+    // subscribe first to miss no change
+    let mut events = server.subscribe();
+    let info = service(&server.available_devices().await)?;
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info).map_err(std::io::Error::other)?;
+    info!("Announced {fullname} with mDNS");
+
+    let updates = tokio::spawn({
+        let daemon = daemon.clone();
+        async move {
+            loop {
+                match events.recv().await {
+                    // the device list did not change
+                    Ok(UsbIpEvent::TransferError { .. }) => continue,
+                    Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                }
+                let res = service(&server.available_devices().await)
+                    .and_then(|info| daemon.register(info).map_err(std::io::Error::other));
+                if let Err(err) = res {
+                    warn!("Failed to update mDNS announcement: {err}");
+                }
+            }
+        }
+    });
+    Ok(MdnsAdvertisement {
+        daemon,
+        fullname,
+        updates: updates.abort_handle(),
+    })
+}
+
+/// Comma separated VID:PID of `devices`, as many as fit into a TXT property
+fn txt_devices(devices: &[UsbDevice]) -> String {
+    let mut txt = String::new();
+    for device in devices {
+        let id = format!("{:04x}:{:04x}", device.vendor_id, device.product_id);
+        let separator = if txt.is_empty() { "" } else { "," };
+        if txt.len() + separator.len() + id.len() > MAX_TXT_VALUE {
+            break;
+        }
+        txt.push_str(separator);
+        txt.push_str(&id);
+    }
+    txt
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn txt_devices_fits_record() {
+        setup_test_logger();
+        assert_eq!(txt_devices(&[]), "");
+        let device = UsbDevice::sample_composite();
+        assert_eq!(txt_devices(std::slice::from_ref(&device)), "1209:0001");
+
+        let many = vec![device; 100];
+        let txt = txt_devices(&many);
+        assert!(txt.len() <= MAX_TXT_VALUE);
+        assert_eq!(txt.split(',').count(), (MAX_TXT_VALUE + 1) / 10);
+    }
+}
@@ -0,0 +1,189 @@
+//! Prometheus metrics of a server
+//!
+//! [UsbIpMetrics] collects [UsbIpServer::stats] whenever its registry is
+//! gathered, so the counters are never out of date. [metrics_server] serves
+//! a registry over HTTP for Prometheus to scrape.
+use super::*;
+use crate::http::{read_request, write_response};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, GaugeVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// [Collector] of the statistics of a [UsbIpServer]
+///
+/// Counters are labeled with the `bus_id` of the device, latencies are in
+/// seconds with a `quantile` label of 0.5 or 0.99.
+pub struct UsbIpMetrics {
+    server: Arc<UsbIpServer>,
+    submits: IntCounterVec,
+    completions: IntCounterVec,
+    errors: IntCounterVec,
+    bytes_in: IntCounterVec,
+    bytes_out: IntCounterVec,
+    latency: GaugeVec,
+    connections: IntGauge,
+    /// Serializes collections, which reset the metrics before filling them
+    collecting: Mutex<()>,
+}
+
+impl UsbIpMetrics {
+    pub fn new(server: Arc<UsbIpServer>) -> prometheus::Result<Self> {
+        let counter =
+            |name: &str, help: &str| IntCounterVec::new(Opts::new(name, help), &["bus_id"]);
+        Ok(Self {
+            server,
+            submits: counter("usbip_urb_submits_total", "USBIP_CMD_SUBMIT received")?,
+            completions: counter(
+                "usbip_urb_completions_total",
+                "URBs which completed successfully",
+            )?,
+            errors: counter("usbip_urb_errors_total", "URBs which failed")?,
+            bytes_in: counter(
+                "usbip_urb_in_bytes_total",
+                "Bytes sent to clients by IN transfers",
+            )?,
+            bytes_out: counter(
+                "usbip_urb_out_bytes_total",
+                "Bytes received from clients by OUT transfers",
+            )?,
+            latency: GaugeVec::new(
+                Opts::new(
+                    "usbip_urb_latency_seconds",
+                    "Time spent handling recent URBs",
+                ),
+                &["bus_id", "quantile"],
+            )?,
+            connections: IntGauge::new("usbip_connections", "Open connections")?,
+            collecting: Mutex::new(()),
+        })
+    }
+
+    fn counters(&self) -> [&IntCounterVec; 5] {
+        [
+            &self.submits,
+            &self.completions,
+            &self.errors,
+            &self.bytes_in,
+            &self.bytes_out,
+        ]
+    }
+}
+
+impl Collector for UsbIpMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs: Vec<_> = self.counters().iter().flat_map(|c| c.desc()).collect();
+        descs.extend(self.latency.desc());
+        descs.extend(self.connections.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _collecting = self.collecting.lock().unwrap();
+        let stats = self.server.stats();
+        // removed devices disappear
+        for counter in self.counters() {
+            counter.reset();
+        }
+        self.latency.reset();
+
+        for device in &stats.devices {
+            let bus_id = device.bus_id.as_str();
+            let urbs = &device.urbs;
+            let values = [
+                urbs.submits,
+                urbs.completions,
+                urbs.errors,
+                urbs.bytes_in,
+                urbs.bytes_out,
+            ];
+            for (counter, value) in self.counters().into_iter().zip(values) {
+                counter.with_label_values(&[bus_id]).inc_by(value);
+            }
+            for (quantile, latency) in [("0.5", urbs.latency_p50), ("0.99", urbs.latency_p99)] {
+                self.latency
+                    .with_label_values(&[bus_id, quantile])
+                    .set(latency.as_secs_f64());
+            }
+        }
+        self.connections.set(stats.connections.len() as i64);
+
+        let mut families: Vec<_> = self.counters().iter().flat_map(|c| c.collect()).collect();
+        families.extend(self.latency.collect());
+        families.extend(self.connections.collect());
+        families
+    }
+}
+
+/// Serve the metrics of `registry` at `addr` over HTTP, in the text format
+///
+/// Every request gets the metrics, whatever its path. Only returns if
+/// binding to `addr` fails.
+pub async fn metrics_server(addr: SocketAddr, registry: Registry) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let registry = Arc::new(registry);
+    loop {
+        match listener.accept().await {
+            Ok((mut socket, addr)) => {
+                trace!("Metrics requested by {addr:?}");
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    let res = serve_metrics(&mut socket, &registry).await;
+                    if let Err(err) = res {
+                        debug!("Failed to serve metrics: {err}");
+                    }
+                });
+            }
+            Err(err) => {
+                warn!("Got error {err:?}");
+            }
+        }
+    }
+}
+
+async fn serve_metrics<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    registry: &Registry,
+) -> Result<()> {
+    if read_request(socket).await?.is_none() {
+        return Ok(());
+    }
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    encoder
+        .encode(&registry.gather(), &mut body)
+        .map_err(std::io::Error::other)?;
+    write_response(socket, 200, encoder.format_type(), &body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn gather_metrics() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let registry = Registry::new();
+        registry
+            .register(Box::new(UsbIpMetrics::new(server).unwrap()))
+            .unwrap();
+
+        let (mut client, mut socket) = tokio::io::duplex(0x1000);
+        let task = tokio::spawn(async move { serve_metrics(&mut socket, &registry).await });
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        task.await.unwrap().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nusbip_connections 0\n"));
+    }
+}
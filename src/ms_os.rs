@@ -0,0 +1,156 @@
+//! Microsoft OS descriptors
+//!
+//! Windows asks devices for these to pick a driver without an INF file.
+//! MS OS 1.0 uses the string descriptor at [MS_OS_STRING_INDEX] followed by
+//! a vendor request for the extended compat ID descriptor. MS OS 2.0 is
+//! advertised through a platform capability in the BOS descriptor and fetched
+//! with a vendor request too. See [UsbDevice::with_ms_os_descriptors].
+use super::*;
+
+/// String descriptor index of the MS OS 1.0 string descriptor
+pub const MS_OS_STRING_INDEX: u8 = 0xEE;
+
+/// wIndex of the vendor request for the MS OS 1.0 extended compat ID descriptor
+pub const MS_OS_10_EXTENDED_COMPAT_ID_INDEX: u16 = 0x0004;
+
+/// wIndex of the vendor request for the MS OS 2.0 descriptor set
+pub const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x0007;
+
+/// Windows 8.1, the first version supporting MS OS 2.0 descriptors
+const MS_OS_20_WINDOWS_VERSION: u32 = 0x0603_0000;
+
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F} in little endian
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+
+/// A function entry of the MS OS 1.0 extended compat ID descriptor
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MsCompatibleId {
+    /// First interface of the function
+    pub first_interface: u8,
+    /// Compatible ID, e.g. `WINUSB`, at most 8 bytes
+    pub compatible_id: String,
+    /// Sub-compatible ID, at most 8 bytes
+    pub sub_compatible_id: String,
+}
+
+/// Microsoft OS descriptors of a device
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MsOsDescriptors {
+    /// bRequest of the vendor requests used to fetch the descriptors
+    pub vendor_code: u8,
+    /// Functions reported by MS OS 1.0, which is disabled if empty
+    pub compatible_ids: Vec<MsCompatibleId>,
+    /// MS OS 2.0 descriptor set, which is disabled if `None`
+    pub descriptor_set: Option<Vec<u8>>,
+}
+
+impl MsOsDescriptors {
+    /// Bind the whole device to WinUSB using both MS OS 1.0 and 2.0
+    pub fn winusb(vendor_code: u8) -> Self {
+        Self {
+            vendor_code,
+            compatible_ids: vec![MsCompatibleId {
+                first_interface: 0,
+                compatible_id: "WINUSB".to_string(),
+                sub_compatible_id: String::new(),
+            }],
+            descriptor_set: Some(Self::winusb_descriptor_set()),
+        }
+    }
+
+    /// MS OS 2.0 descriptor set binding the whole device to WinUSB
+    pub fn winusb_descriptor_set() -> Vec<u8> {
+        let mut desc = vec![
+            0x0A, 0x00, // wLength
+            0x00, 0x00, // wDescriptorType: MS_OS_20_SET_HEADER_DESCRIPTOR
+        ];
+        desc.extend_from_slice(&MS_OS_20_WINDOWS_VERSION.to_le_bytes()); // dwWindowsVersion
+        desc.extend_from_slice(&[0x00, 0x00]); // wTotalLength, patched below
+        desc.extend_from_slice(&[
+            0x14, 0x00, // wLength
+            0x03, 0x00, // wDescriptorType: MS_OS_20_FEATURE_COMPATIBLE_ID
+        ]);
+        desc.extend_from_slice(&Self::pad_id("WINUSB")); // CompatibleID
+        desc.extend_from_slice(&[0; 8]); // SubCompatibleID
+
+        let len = desc.len() as u16;
+        desc[8..10].copy_from_slice(&len.to_le_bytes());
+        desc
+    }
+
+    fn pad_id(id: &str) -> [u8; 8] {
+        let mut result = [0; 8];
+        let len = id.len().min(8);
+        result[..len].copy_from_slice(&id.as_bytes()[..len]);
+        result
+    }
+
+    /// The MS OS 1.0 string descriptor at [MS_OS_STRING_INDEX]
+    pub(crate) fn string_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x12,                         // bLength
+            DescriptorType::String as u8, // bDescriptorType
+        ];
+        for c in "MSFT100".encode_utf16() {
+            desc.extend_from_slice(&c.to_le_bytes()); // qwSignature
+        }
+        desc.push(self.vendor_code); // bMS_VendorCode
+        desc.push(0); // bPad
+        desc
+    }
+
+    /// The MS OS 1.0 extended compat ID descriptor
+    pub(crate) fn extended_compat_id_descriptor(&self) -> Vec<u8> {
+        let len = 16 + 24 * self.compatible_ids.len() as u32;
+        let mut desc = Vec::with_capacity(len as usize);
+        desc.extend_from_slice(&len.to_le_bytes()); // dwLength
+        desc.extend_from_slice(&[0x00, 0x01]); // bcdVersion
+        desc.extend_from_slice(&MS_OS_10_EXTENDED_COMPAT_ID_INDEX.to_le_bytes()); // wIndex
+        desc.push(self.compatible_ids.len() as u8); // bCount
+        desc.extend_from_slice(&[0; 7]); // reserved
+        for function in &self.compatible_ids {
+            desc.push(function.first_interface); // bFirstInterfaceNumber
+            desc.push(0x01); // reserved
+            desc.extend_from_slice(&Self::pad_id(&function.compatible_id));
+            desc.extend_from_slice(&Self::pad_id(&function.sub_compatible_id));
+            desc.extend_from_slice(&[0; 6]); // reserved
+        }
+        desc
+    }
+
+    /// The MS OS 2.0 platform capability for the BOS descriptor
+    pub(crate) fn platform_capability(&self) -> Option<Vec<u8>> {
+        let descriptor_set = self.descriptor_set.as_ref()?;
+        let mut data = MS_OS_20_WINDOWS_VERSION.to_le_bytes().to_vec(); // dwWindowsVersion
+        data.extend_from_slice(&(descriptor_set.len() as u16).to_le_bytes()); // wMSOSDescriptorSetTotalLength
+        data.push(self.vendor_code); // bMS_VendorCode
+        data.push(0x00); // bAltEnumCode
+        Some(platform_capability_descriptor(
+            &MS_OS_20_PLATFORM_UUID,
+            &data,
+        ))
+    }
+
+    /// Answer a vendor request for one of the descriptors
+    ///
+    /// Returns `None` if the request is not for a MS OS descriptor.
+    pub(crate) fn handle_request(&self, setup_packet: &SetupPacket) -> Option<Vec<u8>> {
+        if setup_packet.request != self.vendor_code {
+            return None;
+        }
+        match (setup_packet.request_type, setup_packet.index) {
+            // some hosts send this one to the interface recipient
+            (0b11000000 | 0b11000001, MS_OS_10_EXTENDED_COMPAT_ID_INDEX)
+                if !self.compatible_ids.is_empty() =>
+            {
+                Some(self.extended_compat_id_descriptor())
+            }
+            (0b11000000, MS_OS_20_DESCRIPTOR_INDEX) => self.descriptor_set.clone(),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Sysfs-style paths of exported devices
+//!
+//! USB/IP clients show the `path` and `bus_id` of a device, and some tools
+//! parse them. [UsbPathFormat] generates both from the bus number and the
+//! chain of hub ports leading to the device.
+use super::*;
+
+/// How the `path` and `bus_id` of a device are generated
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UsbPathFormat {
+    /// Same as the Linux kernel: bus id `1-2.3`, path `/sys/bus/usb/devices/1-2.3`
+    #[default]
+    Kernel,
+    /// Templates where `{bus}` and `{ports}` (dot separated) are replaced,
+    /// and `{bus_id}` too in the path
+    Custom { bus_id: String, path: String },
+}
+
+impl UsbPathFormat {
+    fn ports(ports: &[u8]) -> String {
+        ports
+            .iter()
+            .map(u8::to_string)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    pub fn bus_id(&self, bus_num: u32, ports: &[u8]) -> String {
+        match self {
+            // the root hub itself is named after the bus
+            Self::Kernel if ports.is_empty() => format!("usb{bus_num}"),
+            Self::Kernel => format!("{bus_num}-{}", Self::ports(ports)),
+            Self::Custom { bus_id, .. } => bus_id
+                .replace("{bus}", &bus_num.to_string())
+                .replace("{ports}", &Self::ports(ports)),
+        }
+    }
+
+    pub fn path(&self, bus_num: u32, ports: &[u8]) -> String {
+        let bus_id = self.bus_id(bus_num, ports);
+        match self {
+            Self::Kernel => format!("/sys/bus/usb/devices/{bus_id}"),
+            Self::Custom { path, .. } => path
+                .replace("{bus_id}", &bus_id)
+                .replace("{bus}", &bus_num.to_string())
+                .replace("{ports}", &Self::ports(ports)),
+        }
+    }
+}
+
+impl UsbDevice {
+    /// Place the device on a bus, behind a chain of hub ports
+    ///
+    /// `path` and `bus_id` are generated with [UsbPathFormat::Kernel].
+    pub fn with_location(mut self, bus_num: u32, ports: &[u8]) -> Self {
+        self.bus_num = bus_num;
+        self.ports = ports.to_vec();
+        self.with_path_format(&UsbPathFormat::Kernel)
+    }
+
+    /// Generate `path` and `bus_id` again with another format
+    pub fn with_path_format(mut self, format: &UsbPathFormat) -> Self {
+        self.path = format.path(self.bus_num, &self.ports);
+        self.bus_id = format.bus_id(self.bus_num, &self.ports);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn kernel_paths() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_location(1, &[2, 3]);
+        assert_eq!(device.bus_id, "1-2.3");
+        assert_eq!(device.path, "/sys/bus/usb/devices/1-2.3");
+        assert_eq!(UsbPathFormat::Kernel.bus_id(3, &[]), "usb3");
+    }
+
+    #[test]
+    fn custom_paths() {
+        setup_test_logger();
+        let format = UsbPathFormat::Custom {
+            bus_id: "{bus}-{ports}".to_string(),
+            path: "/sys/devices/platform/vhci_hcd.0/usb{bus}/{bus_id}".to_string(),
+        };
+        let device = UsbDevice::new(0)
+            .with_location(4, &[1])
+            .with_path_format(&format);
+        assert_eq!(device.bus_id, "4-1");
+        assert_eq!(device.path, "/sys/devices/platform/vhci_hcd.0/usb4/4-1");
+    }
+}
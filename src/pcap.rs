@@ -0,0 +1,213 @@
+//! Capture of URBs for Wireshark
+//!
+//! A [UsbmonCapture] writes URBs as a pcap file with the link type of Linux
+//! usbmon, like `usbmon` captures on a Linux host. Every URB appears twice:
+//! its submission with the data sent to the device, and its completion with
+//! the data returned. Captures are enabled per device with
+//! [UsbIpServer::capture_device], or per connection with
+//! [UsbIpServerBuilder::capture_sessions].
+use super::*;
+use crate::usbip_protocol::UsbIpResponse;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// LINKTYPE_USB_LINUX_MMAPPED, usbmon with the 64 byte header
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const USBMON_HEADER_LEN: usize = 64;
+/// Longest packet kept, including the usbmon header
+const SNAPLEN: u32 = 0x40000;
+const EINPROGRESS: i32 = 115;
+
+/// A pcap file of URBs, see the [module](self) docs
+pub struct UsbmonCapture {
+    out: std::sync::Mutex<Option<BufWriter<Box<dyn Write + Send>>>>,
+}
+
+impl std::fmt::Debug for UsbmonCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbmonCapture").finish_non_exhaustive()
+    }
+}
+
+/// A submitted URB, as seen by [UsbmonCapture]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CapturedUrb {
+    pub(crate) seqnum: u32,
+    /// With the direction bit
+    pub(crate) endpoint: u8,
+    /// bmAttributes of the endpoint
+    pub(crate) attributes: u8,
+    pub(crate) bus_num: u32,
+    pub(crate) dev_num: u32,
+    pub(crate) setup: [u8; 8],
+    pub(crate) transfer_flags: u32,
+    pub(crate) transfer_buffer_length: u32,
+    pub(crate) interval: u32,
+    pub(crate) start_frame: u32,
+}
+
+impl UsbmonCapture {
+    /// Write the capture to `out`, e.g. a pipe to `wireshark -k -i -`
+    ///
+    /// Every packet is flushed right away.
+    pub fn new(out: impl Write + Send + 'static) -> Result<Self> {
+        let mut out = BufWriter::new(Box::new(out) as Box<dyn Write + Send>);
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        // time zone and accuracy of time stamps
+        out.write_all(&[0; 8])?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes())?;
+        out.flush()?;
+        Ok(Self {
+            out: std::sync::Mutex::new(Some(out)),
+        })
+    }
+
+    /// Write the capture to a new file at `path`
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Record the submission of `urb` with the OUT `data`
+    pub(crate) fn submit(&self, urb: &CapturedUrb, data: &[u8]) {
+        let data = if urb.endpoint & 0x80 == 0 { data } else { &[] };
+        self.write_packet(urb, b'S', -EINPROGRESS, urb.transfer_buffer_length, data);
+    }
+
+    /// Record the completion of `urb` with its USBIP_RET_SUBMIT
+    pub(crate) fn complete(&self, urb: &CapturedUrb, res: &UsbIpResponse) {
+        if let UsbIpResponse::UsbIpRetSubmit {
+            status,
+            actual_length,
+            transfer_buffer,
+            ..
+        } = res
+        {
+            self.write_packet(urb, b'C', *status as i32, *actual_length, transfer_buffer);
+        }
+    }
+
+    fn write_packet(&self, urb: &CapturedUrb, kind: u8, status: i32, length: u32, data: &[u8]) {
+        let mut out = self.out.lock().unwrap();
+        let Some(writer) = out.as_mut() else {
+            return;
+        };
+        let data = &data[..data.len().min(SNAPLEN as usize - USBMON_HEADER_LEN)];
+        let since_epoch = UNIX_EPOCH.elapsed().unwrap_or_default();
+        let xfer_type = match urb.attributes & 0x03 {
+            0 => 2, // control
+            1 => 0, // isochronous
+            2 => 3, // bulk
+            _ => 1, // interrupt
+        };
+        let control = xfer_type == 2;
+
+        let mut packet = Vec::with_capacity(16 + USBMON_HEADER_LEN + data.len());
+        packet.extend((since_epoch.as_secs() as u32).to_le_bytes());
+        packet.extend(since_epoch.subsec_micros().to_le_bytes());
+        packet.extend(((USBMON_HEADER_LEN + data.len()) as u32).to_le_bytes());
+        packet.extend((USBMON_HEADER_LEN as u32 + length).to_le_bytes());
+
+        packet.extend((urb.seqnum as u64).to_le_bytes());
+        packet.push(kind);
+        packet.push(xfer_type);
+        packet.push(urb.endpoint);
+        packet.push(urb.dev_num as u8);
+        packet.extend((urb.bus_num as u16).to_le_bytes());
+        // 0 if the setup packet and data are present
+        packet.push(if kind == b'S' && control { 0 } else { b'-' });
+        packet.push(match (data.is_empty(), kind) {
+            (false, _) => 0,
+            (true, b'S') => b'<',
+            (true, _) => b'>',
+        });
+        packet.extend((since_epoch.as_secs() as i64).to_le_bytes());
+        packet.extend((since_epoch.subsec_micros() as i32).to_le_bytes());
+        packet.extend(status.to_le_bytes());
+        packet.extend(length.to_le_bytes());
+        packet.extend((data.len() as u32).to_le_bytes());
+        packet.extend(if control { urb.setup } else { [0; 8] });
+        packet.extend(urb.interval.to_le_bytes());
+        packet.extend(urb.start_frame.to_le_bytes());
+        packet.extend(urb.transfer_flags.to_le_bytes());
+        // no isochronous descriptors
+        packet.extend(0u32.to_le_bytes());
+        packet.extend_from_slice(data);
+
+        if let Err(err) = writer.write_all(&packet).and_then(|()| writer.flush()) {
+            warn!("Stopped capture after error {err}");
+            *out = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usbip_protocol::{USBIP_RET_SUBMIT, UsbIpHeaderBasic};
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn usbmon_packets() {
+        setup_test_logger();
+        let path = std::env::temp_dir().join(format!("usbip-capture-{}.pcap", std::process::id()));
+        let capture = UsbmonCapture::create(&path).unwrap();
+        let urb = CapturedUrb {
+            seqnum: 7,
+            endpoint: 0x80,
+            attributes: EndpointAttributes::Control as u8,
+            bus_num: 3,
+            dev_num: 2,
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+            transfer_flags: 0,
+            transfer_buffer_length: 0x12,
+            interval: 0,
+            start_frame: 0,
+        };
+        capture.submit(&urb, &[]);
+        capture.complete(
+            &urb,
+            &UsbIpResponse::UsbIpRetSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_RET_SUBMIT.into(),
+                    seqnum: 7,
+                    devid: 0,
+                    direction: 0,
+                    ep: 0,
+                },
+                status: 0,
+                actual_length: 2,
+                start_frame: 0,
+                number_of_packets: 0,
+                error_count: 0,
+                transfer_buffer: vec![0x12, 0x01],
+                iso_packet_descriptor: vec![],
+            },
+        );
+        drop(capture);
+        let pcap = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pcap.len(), 24 + (16 + 64) + (16 + 64 + 2));
+        assert_eq!(pcap[20..24], 220u32.to_le_bytes());
+        let submit = &pcap[24 + 16..24 + 16 + 64];
+        assert_eq!(submit[0], 7);
+        assert_eq!(&submit[8..12], &[b'S', 2, 0x80, 2]);
+        assert_eq!(submit[12..14], 3u16.to_le_bytes());
+        assert_eq!(&submit[14..16], &[0, b'<']);
+        assert_eq!(submit[28..32], (-115i32).to_le_bytes());
+        assert_eq!(submit[32..36], 0x12u32.to_le_bytes());
+        assert_eq!(submit[40..48], urb.setup);
+
+        let complete = &pcap[24 + 16 + 64 + 16..];
+        assert_eq!(complete[8], b'C');
+        assert_eq!(&complete[14..16], &[b'-', 0]);
+        assert_eq!(complete[36..40], 2u32.to_le_bytes());
+        assert_eq!(&complete[64..], &[0x12, 0x01]);
+    }
+}
@@ -0,0 +1,236 @@
+//! Recording of sessions
+//!
+//! A [RecordingSocket] wraps a connection and writes everything read from
+//! and written to it into a file. The commands of a [UsbIpRecording] can be
+//! replayed with [replay_recording] to reproduce a client bug offline, or to
+//! check that emulated devices still reply the same.
+//!
+//! A recording starts with `usbiprec`, followed by one record per read or
+//! write: a direction byte (0 for commands, 1 for replies), the length as
+//! big endian u32 and the bytes.
+use super::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const MAGIC: &[u8; 8] = b"usbiprec";
+const COMMAND: u8 = 0;
+const REPLY: u8 = 1;
+
+/// A connection that records its traffic
+///
+/// Failing to write the recording stops it, the connection goes on.
+pub struct RecordingSocket<T> {
+    inner: T,
+    file: Option<BufWriter<File>>,
+}
+
+impl<T> RecordingSocket<T> {
+    /// Record the traffic of `inner` into `file`
+    pub fn new(inner: T, file: File) -> Result<Self> {
+        let mut file = BufWriter::new(file);
+        file.write_all(MAGIC)?;
+        Ok(Self {
+            inner,
+            file: Some(file),
+        })
+    }
+
+    /// Record the traffic of `inner` into a new file at `path`
+    pub fn create(inner: T, path: &Path) -> Result<Self> {
+        Self::new(inner, File::create(path)?)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, direction: u8, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let res = file
+            .write_all(&[direction])
+            .and_then(|()| file.write_all(&(data.len() as u32).to_be_bytes()))
+            .and_then(|()| file.write_all(data));
+        if let Err(err) = res {
+            warn!("Stopped recording after error {err}");
+            self.file = None;
+        }
+    }
+
+    fn flush_recording(&mut self) {
+        if let Some(file) = &mut self.file
+            && let Err(err) = file.flush()
+        {
+            warn!("Stopped recording after error {err}");
+            self.file = None;
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RecordingSocket<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            self.record(COMMAND, &buf.filled()[before..]);
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RecordingSocket<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = res {
+            self.record(REPLY, &buf[..len]);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.flush_recording();
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.flush_recording();
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Traffic of a session recorded by a [RecordingSocket]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsbIpRecording {
+    /// Everything read from the client
+    pub commands: Vec<u8>,
+    /// Everything written to the client
+    pub replies: Vec<u8>,
+}
+
+impl UsbIpRecording {
+    /// Parse a recording, a truncated last record is ignored
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let Some(mut data) = data.strip_prefix(MAGIC) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Not a recording",
+            ));
+        };
+        let mut recording = Self::default();
+        while data.len() >= 5 {
+            let len = u32::from_be_bytes(data[1..5].try_into().unwrap()) as usize;
+            let Some(bytes) = data[5..].get(..len) else {
+                warn!("Ignoring truncated record");
+                break;
+            };
+            match data[0] {
+                COMMAND => recording.commands.extend_from_slice(bytes),
+                REPLY => recording.replies.extend_from_slice(bytes),
+                direction => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unknown record direction {direction}"),
+                    ));
+                }
+            }
+            data = &data[5 + len..];
+        }
+        Ok(recording)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+}
+
+/// Result of [replay_recording]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordingReplay {
+    pub outcome: ReplayOutcome,
+    /// Replies to the replayed commands
+    pub replies: Vec<u8>,
+    /// Offset of the first byte of the replies that differs from the recording
+    pub first_mismatch: Option<usize>,
+}
+
+impl RecordingReplay {
+    /// Whether the replay completed with the recorded replies
+    pub fn matches(&self) -> bool {
+        self.outcome == ReplayOutcome::Completed && self.first_mismatch.is_none()
+    }
+}
+
+/// Replay the commands of `recording` on a server with `devices`, and compare the replies
+///
+/// See [replay] for `max_output`. The replies only match if the devices
+/// behave like the recorded ones, so host devices are best replaced by
+/// simulated devices with the same descriptors.
+pub async fn replay_recording(
+    recording: &UsbIpRecording,
+    devices: Vec<UsbDevice>,
+    max_output: usize,
+) -> RecordingReplay {
+    let (outcome, replies) =
+        replay_with_output(recording.commands.clone(), devices, max_output).await;
+    let first_mismatch = replies
+        .iter()
+        .zip(&recording.replies)
+        .position(|(a, b)| a != b)
+        .or_else(|| {
+            (replies.len() != recording.replies.len())
+                .then(|| replies.len().min(recording.replies.len()))
+        });
+    if let Some(offset) = first_mismatch {
+        info!("Replies differ from the recording at byte {offset}");
+    }
+    RecordingReplay {
+        outcome,
+        replies,
+        first_mismatch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn parse_recording() {
+        setup_test_logger();
+        let mut data = MAGIC.to_vec();
+        data.extend([COMMAND, 0, 0, 0, 2, 1, 2]);
+        data.extend([REPLY, 0, 0, 0, 1, 3]);
+        data.extend([COMMAND, 0, 0, 0, 1, 4]);
+        data.extend([REPLY, 0, 0, 0, 9, 5]);
+        assert_eq!(
+            UsbIpRecording::parse(&data).unwrap(),
+            UsbIpRecording {
+                commands: vec![1, 2, 4],
+                replies: vec![3],
+            }
+        );
+        assert!(UsbIpRecording::parse(b"garbage").is_err());
+
+        let mut data = MAGIC.to_vec();
+        data.extend([7, 0, 0, 0, 0]);
+        assert!(UsbIpRecording::parse(&data).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Replay of recorded command streams
+//!
+//! Captured or fuzzer generated inputs can be kept in a directory and
+//! replayed with [replay_corpus] against custom devices, to check that none
+//! of them makes the server panic or reply without bounds.
+use super::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// What happened when replaying an input
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayOutcome {
+    /// The handler returned, successfully or not
+    Completed,
+    /// The handler panicked with this message
+    Panicked(String),
+    /// The replies exceeded the output limit
+    OutputLimitExceeded,
+}
+
+/// A connection reading from a buffer and keeping what is written
+struct ReplaySocket {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    output_len: usize,
+    max_output: usize,
+}
+
+impl AsyncRead for ReplaySocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ReplaySocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        self.output_len += buf.len();
+        if self.output_len > self.max_output {
+            return Poll::Ready(Err(std::io::Error::other("Output limit exceeded")));
+        }
+        self.output.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Replay the commands in `input` through [handler](crate::handler) on a server with `devices`
+///
+/// At most `max_output` bytes of replies are allowed.
+pub async fn replay(input: Vec<u8>, devices: Vec<UsbDevice>, max_output: usize) -> ReplayOutcome {
+    replay_with_output(input, devices, max_output).await.0
+}
+
+/// Like [replay], also returning the replies
+pub(crate) async fn replay_with_output(
+    input: Vec<u8>,
+    devices: Vec<UsbDevice>,
+    max_output: usize,
+) -> (ReplayOutcome, Vec<u8>) {
+    let server = Arc::new(UsbIpServer::new_simulated(devices));
+    let mut socket = ReplaySocket {
+        input: Cursor::new(input),
+        output: vec![],
+        output_len: 0,
+        max_output,
+    };
+    let task = tokio::spawn(async move {
+        let res = crate::handler(&mut socket, server).await;
+        debug!("Replay ended with {res:?}");
+        socket
+    });
+    let outcome = match task.await {
+        Ok(socket) if socket.output_len > socket.max_output => {
+            return (ReplayOutcome::OutputLimitExceeded, socket.output);
+        }
+        Ok(socket) => return (ReplayOutcome::Completed, socket.output),
+        Err(err) if err.is_panic() => {
+            let panic = err.into_panic();
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            ReplayOutcome::Panicked(message)
+        }
+        Err(err) => ReplayOutcome::Panicked(err.to_string()),
+    };
+    (outcome, vec![])
+}
+
+/// Replay every file in `dir`, in name order, each on fresh `devices()`
+///
+/// See [replay] for `max_output`.
+pub async fn replay_corpus(
+    dir: &Path,
+    devices: impl Fn() -> Vec<UsbDevice>,
+    max_output: usize,
+) -> Result<Vec<(PathBuf, ReplayOutcome)>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    let mut outcomes = vec![];
+    for path in paths {
+        let input = std::fs::read(&path)?;
+        let outcome = replay(input, devices(), max_output).await;
+        if outcome != ReplayOutcome::Completed {
+            warn!("Replay of {} ended with {outcome:?}", path.display());
+        }
+        outcomes.push((path, outcome));
+    }
+    Ok(outcomes)
+}
@@ -0,0 +1,79 @@
+//! Scratch buffers for building URB responses
+//!
+//! Handlers and the server serialize a lot of short-lived byte buffers
+//! (descriptors, HID reports, protocol packets). Instead of allocating a
+//! fresh [Vec] for each of them, a buffer can be taken from a small
+//! thread-local pool with [take_scratch_buffer]. The server hands the buffer
+//! back with [recycle_scratch_buffer] once the response carrying it has been
+//! written, so in steady state (e.g. interrupt polling) no allocation happens.
+use std::cell::RefCell;
+
+/// Maximum number of buffers kept per thread
+const MAX_POOLED_BUFFERS: usize = 16;
+
+/// Buffers with a larger capacity are released instead of pooled
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Take an empty buffer with at least `capacity` bytes of capacity
+///
+/// The buffer is reused from the thread-local pool when possible.
+pub fn take_scratch_buffer(capacity: usize) -> Vec<u8> {
+    let buffer = POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        let index = pool.iter().position(|buf| buf.capacity() >= capacity)?;
+        Some(pool.swap_remove(index))
+    });
+    match buffer {
+        Some(buffer) => buffer,
+        None => Vec::with_capacity(capacity),
+    }
+}
+
+/// Return a buffer to the thread-local pool
+///
+/// Called once a URB response has been sent. Buffers beyond the pool limits
+/// are simply dropped.
+pub fn recycle_scratch_buffer(mut buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    buffer.clear();
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(buffer);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn reuse_recycled_buffer() {
+        setup_test_logger();
+        let mut buffer = take_scratch_buffer(32);
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let ptr = buffer.as_ptr();
+        recycle_scratch_buffer(buffer);
+
+        let buffer = take_scratch_buffer(16);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn skip_large_buffer() {
+        setup_test_logger();
+        let pooled = POOL.with(|pool| pool.borrow().len());
+        recycle_scratch_buffer(Vec::with_capacity(MAX_POOLED_CAPACITY + 1));
+        assert_eq!(POOL.with(|pool| pool.borrow().len()), pooled);
+    }
+}
@@ -0,0 +1,273 @@
+//! Loopback health check of a deployment
+//!
+//! [self_test] starts a server with [UsbDevice::sample_composite] and talks
+//! to it over TCP like a USB/IP client would: it lists and imports the
+//! device, then exercises its control, bulk and interrupt endpoints.
+use super::*;
+use crate::usbip_protocol::{
+    USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK, USBIP_RET_SUBMIT, USBIP_RET_UNLINK, UsbIpCommand,
+    UsbIpHeaderBasic,
+};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Result of a step of [self_test]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfTestStep {
+    pub name: &'static str,
+    /// The error if the step failed
+    pub error: Option<String>,
+}
+
+/// Results of [self_test], steps after the first failure are not run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub steps: Vec<SelfTestStep>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        !self.steps.is_empty() && self.steps.iter().all(|step| step.error.is_none())
+    }
+}
+
+impl std::fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for step in &self.steps {
+            match &step.error {
+                None => writeln!(f, "PASS {}", step.name)?,
+                Some(err) => writeln!(f, "FAIL {}: {err}", step.name)?,
+            }
+        }
+        write!(f, "{}", if self.passed() { "PASSED" } else { "FAILED" })
+    }
+}
+
+/// Stops the server of a failed self test
+pub(crate) struct AbortOnDrop(pub(crate) tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Client side of the self test, also used by [check_conformance]
+pub(crate) struct SelfTestClient<T> {
+    pub(crate) connection: T,
+    pub(crate) seqnum: u32,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> SelfTestClient<T> {
+    /// Find the device in OP_REP_DEVLIST and return its entry
+    pub(crate) async fn devlist(&mut self, bus_id: &str) -> Result<Vec<u8>> {
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+        self.connection.write_all(&req.to_bytes()).await?;
+        let mut header = [0; 12];
+        self.connection.read_exact(&mut header).await?;
+        let count = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let mut found = None;
+        for _ in 0..count {
+            let mut device = vec![0; 0x138];
+            self.connection.read_exact(&mut device).await?;
+            let mut interfaces = vec![0; 4 * device[0x137] as usize];
+            self.connection.read_exact(&mut interfaces).await?;
+            if device[0x100..0x120].starts_with(bus_id.as_bytes()) {
+                found = Some(device);
+            }
+        }
+        found.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{bus_id} is not listed"),
+            )
+        })
+    }
+
+    /// Import the device and return its entry in OP_REP_IMPORT
+    pub(crate) async fn import(&mut self, bus_id: &str) -> Result<Vec<u8>> {
+        let mut busid = [0; 32];
+        busid[..bus_id.len()].copy_from_slice(bus_id.as_bytes());
+        let req = UsbIpCommand::OpReqImport { status: 0, busid };
+        self.connection.write_all(&req.to_bytes()).await?;
+        let mut header = [0; 8];
+        self.connection.read_exact(&mut header).await?;
+        if header[4..8] != [0; 4] {
+            return Err(std::io::Error::other("import refused"));
+        }
+        let mut device = vec![0; 0x138];
+        self.connection.read_exact(&mut device).await?;
+        Ok(device)
+    }
+
+    /// Submit a URB and return the received data
+    pub(crate) async fn submit(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        transfer_buffer_length: u32,
+    ) -> Result<Vec<u8>> {
+        let (status, data) = self.urb(ep, setup, data, transfer_buffer_length).await?;
+        if status != 0 {
+            return Err(std::io::Error::other(format!("URB failed with {status}")));
+        }
+        Ok(data)
+    }
+
+    /// Submit a URB and return its status and the received data
+    pub(crate) async fn urb(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        transfer_buffer_length: u32,
+    ) -> Result<(i32, Vec<u8>)> {
+        self.seqnum += 1;
+        let direction = ep >> 7;
+        let req = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: self.seqnum,
+                devid: 0,
+                direction: direction.into(),
+                ep: (ep & 0x0F).into(),
+            },
+            transfer_flags: 0,
+            transfer_buffer_length,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+            data,
+            iso_packet_descriptor: vec![],
+        };
+        self.connection.write_all(&req.to_bytes()).await?;
+        let status = self.reply(USBIP_RET_SUBMIT).await?;
+        let mut length = [0; 4];
+        self.connection.read_exact(&mut length).await?;
+        let actual_length = u32::from_be_bytes(length);
+        self.connection.read_exact(&mut [0; 20]).await?;
+        // only IN transfers carry data back
+        let len = if direction == 1 { actual_length } else { 0 };
+        let mut data = vec![0; len as usize];
+        self.connection.read_exact(&mut data).await?;
+        Ok((status, data))
+    }
+
+    /// Unlink the URB `unlink_seqnum` and return the status of USBIP_RET_UNLINK
+    pub(crate) async fn unlink(&mut self, unlink_seqnum: u32) -> Result<i32> {
+        self.seqnum += 1;
+        let req = UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum: self.seqnum,
+                devid: 0,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum,
+        };
+        self.connection.write_all(&req.to_bytes()).await?;
+        let status = self.reply(USBIP_RET_UNLINK).await?;
+        self.connection.read_exact(&mut [0; 24]).await?;
+        Ok(status)
+    }
+
+    /// Read the header of the reply to the last command and return its status
+    async fn reply(&mut self, command: u16) -> Result<i32> {
+        let mut header = [0; 24];
+        self.connection.read_exact(&mut header).await?;
+        let got = u32::from_be_bytes(header[0..4].try_into().unwrap());
+        let seqnum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if got != u32::from(command) || seqnum != self.seqnum {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Expected reply {command:#x} to {}, got {got:#x} to {seqnum}",
+                    self.seqnum
+                ),
+            ));
+        }
+        Ok(i32::from_be_bytes(header[20..24].try_into().unwrap()))
+    }
+}
+
+/// Serve [UsbDevice::sample_composite] at `addr` and exercise it as a client
+///
+/// The server stops when the test ends.
+pub async fn self_test(addr: SocketAddr) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    let device = UsbDevice::sample_composite();
+    let bus_id = device.bus_id.clone();
+    let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+
+    macro_rules! step {
+        ($name:expr, $body:expr) => {{
+            let res: Result<_> = $body;
+            report.steps.push(SelfTestStep {
+                name: $name,
+                error: res.as_ref().err().map(|err| err.to_string()),
+            });
+            match res {
+                Ok(value) => value,
+                Err(_) => return report,
+            }
+        }};
+    }
+
+    let listener = step!("bind", TcpListener::bind(addr).await);
+    let addr = step!("local address", listener.local_addr());
+    let accept = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await?;
+        handler(&mut socket, server)
+            .await
+            .map_err(std::io::Error::from)
+    });
+    let _abort = AbortOnDrop(accept.abort_handle());
+
+    let connection = step!("connect", TcpStream::connect(addr).await);
+    let mut client = SelfTestClient {
+        connection,
+        seqnum: 0,
+    };
+    step!("list devices", client.devlist(&bus_id).await.map(|_| ()));
+    step!("import device", client.import(&bus_id).await.map(|_| ()));
+    step!("get device descriptor", {
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        match client.submit(0x80, setup, vec![], 0x12).await {
+            Ok(desc) if desc.len() == 0x12 => Ok(()),
+            Ok(desc) => Err(std::io::Error::other(format!(
+                "{} byte descriptor",
+                desc.len()
+            ))),
+            Err(err) => Err(err),
+        }
+    });
+    step!(
+        "serial bulk out",
+        client
+            .submit(0x01, [0; 8], b"self test".to_vec(), 9)
+            .await
+            .map(|_| ())
+    );
+    step!(
+        "serial bulk in",
+        client.submit(0x82, [0; 8], vec![], 0x40).await.map(|_| ())
+    );
+    step!(
+        "keyboard interrupt in",
+        client.submit(0x83, [0; 8], vec![], 0x08).await.map(|_| ())
+    );
+
+    drop(client);
+    step!(
+        "disconnect",
+        accept
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|res| res)
+    );
+    report
+}
@@ -0,0 +1,77 @@
+//! Artificial bandwidth and latency of exported devices
+use super::*;
+use std::time::Duration;
+
+/// Limits of the simulated link to a device, see [UsbDevice::with_shaping]
+///
+/// URBs of a connection are handled one after the other, so holding every
+/// reply until its data could have crossed the link caps the throughput.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbLinkShaping {
+    /// Data of URBs per second, both directions together, unlimited if `None`
+    pub bytes_per_second: Option<u64>,
+    /// Added to every URB, e.g. the round trip time of a WAN link
+    pub latency: Duration,
+}
+
+impl UsbLinkShaping {
+    /// Typical throughput of bulk transfers at `speed`, without added latency
+    ///
+    /// Low speed devices have no bulk endpoints, theirs is that of interrupt
+    /// endpoints.
+    pub fn for_speed(speed: UsbSpeed) -> Self {
+        let bytes_per_second = match speed {
+            UsbSpeed::Unknown => None,
+            UsbSpeed::Low => Some(800),
+            UsbSpeed::Full => Some(1_000_000),
+            UsbSpeed::High | UsbSpeed::Wireless => Some(40_000_000),
+            UsbSpeed::Super => Some(400_000_000),
+            UsbSpeed::SuperPlus => Some(1_000_000_000),
+        };
+        Self {
+            bytes_per_second,
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Shortest time a URB moving `bytes` takes
+    pub fn duration(&self, bytes: u64) -> Duration {
+        let transfer = match self.bytes_per_second {
+            Some(0) | None => Duration::ZERO,
+            Some(rate) => Duration::from_secs_f64(bytes as f64 / rate as f64),
+        };
+        self.latency + transfer
+    }
+}
+
+impl UsbDevice {
+    /// Slow the URBs of the device down to `shaping`
+    pub fn with_shaping(mut self, shaping: UsbLinkShaping) -> Self {
+        self.shaping = Some(shaping);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn shaping_duration() {
+        setup_test_logger();
+        assert_eq!(UsbLinkShaping::default().duration(1 << 20), Duration::ZERO);
+        let full = UsbLinkShaping::for_speed(UsbSpeed::Full);
+        assert_eq!(full.duration(500_000), Duration::from_millis(500));
+        let wan = full.with_latency(Duration::from_millis(30));
+        assert_eq!(wan.duration(0), Duration::from_millis(30));
+        assert_eq!(wan.duration(1_000_000), Duration::from_millis(1030));
+    }
+}
@@ -0,0 +1,85 @@
+//! Helpers for testing interface and device handlers without a real client
+//!
+//! A [MockSocket] feeds prepared commands to [handler](crate::handler) and
+//! collects its replies:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use usbip::{test_util::MockSocket, usbip_protocol::*, UsbIpServer};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+//! let mut socket = MockSocket::new(UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes());
+//! usbip::handler(&mut socket, server).await.ok();
+//! assert_eq!(socket.output, UsbIpResponse::op_rep_devlist(&[]).to_bytes());
+//! # }
+//! ```
+use std::{
+    io::*,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+/// A connection reading from a buffer and keeping what is written
+///
+/// The handler sees the end of the input as a closed connection.
+pub struct MockSocket {
+    pub input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+}
+
+impl MockSocket {
+    pub fn new(input: Vec<u8>) -> Self {
+        Self {
+            input: Cursor::new(input),
+            output: vec![],
+        }
+    }
+}
+
+impl AsyncRead for MockSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<Result<()>> {
+        // safe, see https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
+        unsafe { self.map_unchecked_mut(|s| &mut s.input).poll_read(cx, buf) }
+    }
+}
+
+impl AsyncWrite for MockSocket {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.get_mut().output.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A loopback address with a port that was free a moment ago
+pub async fn get_free_address() -> SocketAddr {
+    let stream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    stream.local_addr().unwrap()
+}
+
+/// Connect to `addr`, retrying until a server listens there
+pub async fn poll_connect(addr: SocketAddr) -> TcpStream {
+    loop {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+    }
+}
+
+/// Print logs of the crate in test output, can be called by every test
+pub fn setup_test_logger() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
@@ -0,0 +1,135 @@
+//! TLS encrypted connections
+//!
+//! USB traffic carries keystrokes, storage blocks and the like, which
+//! USB/IP sends in plaintext. [server_tls] accepts TLS connections with a
+//! [ServerConfig] of rustls, e.g. from [tls_config_from_pem]. Clients that
+//! only speak plain USB/IP reach it through a local tunnel, see the README.
+//!
+//! With [tls_config_with_client_auth], clients need a certificate, and the
+//! names in it decide which devices they get, see
+//! [UsbIpServer::with_identity_policy].
+use super::*;
+use crate::usbip_server::server::{SessionOptions, session};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+
+/// Server configuration from a PEM certificate chain and private key
+pub fn tls_config_from_pem(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let (certs, key) = read_pem(cert, key)?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+/// Like [tls_config_from_pem], but clients need a certificate issued by one
+/// in the PEM file `client_ca`
+pub fn tls_config_with_client_auth(
+    cert: &Path,
+    key: &Path,
+    client_ca: &Path,
+) -> Result<Arc<ServerConfig>> {
+    let (certs, key) = read_pem(cert, key)?;
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(client_ca).map_err(invalid)? {
+        roots.add(ca.map_err(invalid)?).map_err(invalid)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(invalid)?;
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+fn read_pem(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(invalid)?
+        .collect::<core::result::Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(invalid)?;
+    Ok((certs, key))
+}
+
+/// The DNS and URI subject alternative names of a client certificate
+///
+/// SPIFFE IDs are URI names, e.g. `spiffe://example.org/ci`.
+pub fn client_identities(cert: &CertificateDer) -> Vec<String> {
+    match webpki::EndEntityCert::try_from(cert) {
+        Ok(cert) => cert
+            .valid_dns_names()
+            .chain(cert.valid_uri_names())
+            .map(String::from)
+            .collect(),
+        Err(err) => {
+            warn!("Failed to parse client certificate: {err}");
+            vec![]
+        }
+    }
+}
+
+fn invalid(err: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+/// Spawn a USB/IP server at `addr` accepting TLS connections only
+pub async fn server_tls(addr: SocketAddr, server: Arc<UsbIpServer>, config: Arc<ServerConfig>) {
+    let acceptor = TlsAcceptor::from(config);
+    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                info!("Got connection from {addr:?}");
+                server.configure_socket(&socket);
+                let new_server = server.clone();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut socket = match acceptor.accept(socket).await {
+                        Ok(socket) => socket,
+                        Err(err) => {
+                            warn!("TLS handshake with {addr:?} failed: {err}");
+                            return;
+                        }
+                    };
+                    let identities = socket
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|certs| certs.first())
+                        .map(client_identities)
+                        .unwrap_or_default();
+                    info!("Client {addr:?} has identities {identities:?}");
+                    let options = SessionOptions {
+                        identities,
+                        ..Default::default()
+                    };
+                    let res = session(
+                        &mut socket,
+                        new_server,
+                        &UsbIpShard::All,
+                        Some(addr),
+                        options,
+                    )
+                    .await;
+                    info!("Handler ended with {res:?}");
+                });
+            }
+            Err(err) => {
+                warn!("Got error {err:?}");
+            }
+        }
+    }
+}
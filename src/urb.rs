@@ -0,0 +1,190 @@
+//! USB request blocks
+use super::*;
+use crate::usbip_protocol::{USBIP_RET_SUBMIT, UsbIpHeaderBasic, UsbIpResponse};
+
+/// A URB(USB Request Block), from submission to completion
+///
+/// It carries the parameters of USBIP_CMD_SUBMIT to the handlers, which fill
+/// in the result reported by USBIP_RET_SUBMIT. `buffer` holds the data of OUT
+/// transfers, and the received data once IN transfers complete.
+#[derive(Clone, Debug, Default)]
+pub struct Urb {
+    pub seqnum: u32,
+    pub endpoint: UsbEndpoint,
+    pub transfer_flags: u32,
+    pub transfer_buffer_length: u32,
+    pub setup: SetupPacket,
+    pub buffer: Vec<u8>,
+    pub start_frame: u32,
+    pub number_of_packets: u32,
+    pub interval: u32,
+    /// Raw isochronous packet descriptors
+    pub iso_packet_descriptor: Vec<u8>,
+    /// Zero on success, a negative errno otherwise
+    pub status: i32,
+    pub actual_length: u32,
+    pub error_count: u32,
+}
+
+/// An isochronous packet of a [Urb], see [Urb::iso_packets]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IsoPacket {
+    /// Offset of the packet in the transfer buffer
+    pub offset: u32,
+    pub length: u32,
+    pub actual_length: u32,
+    /// Zero on success, a negative errno otherwise
+    pub status: i32,
+}
+
+impl Urb {
+    pub fn direction(&self) -> Direction {
+        self.endpoint.direction()
+    }
+
+    /// The isochronous packets, parsed from [Urb::iso_packet_descriptor]
+    pub fn iso_packets(&self) -> Vec<IsoPacket> {
+        let field = |packet: &[u8], i: usize| {
+            u32::from_be_bytes(packet[i * 4..i * 4 + 4].try_into().unwrap())
+        };
+        self.iso_packet_descriptor
+            .chunks_exact(16)
+            .map(|packet| IsoPacket {
+                offset: field(packet, 0),
+                length: field(packet, 1),
+                actual_length: field(packet, 2),
+                status: field(packet, 3) as i32,
+            })
+            .collect()
+    }
+
+    /// Complete an isochronous URB with the results of `packets`
+    ///
+    /// For IN transfers, `data` holds the data of the packets one after
+    /// another, without the gaps between their offsets, as USB/IP sends it.
+    pub fn complete_iso(&mut self, packets: &[IsoPacket], data: Vec<u8>) {
+        self.status = 0;
+        self.iso_packet_descriptor.clear();
+        for packet in packets {
+            for field in [
+                packet.offset,
+                packet.length,
+                packet.actual_length,
+                packet.status as u32,
+            ] {
+                self.iso_packet_descriptor.extend(field.to_be_bytes());
+            }
+        }
+        self.number_of_packets = packets.len() as u32;
+        self.error_count = packets.iter().filter(|packet| packet.status != 0).count() as u32;
+        self.actual_length = packets.iter().map(|packet| packet.actual_length).sum();
+        match self.direction() {
+            Direction::Out => recycle_scratch_buffer(data),
+            Direction::In => recycle_scratch_buffer(std::mem::replace(&mut self.buffer, data)),
+        }
+    }
+
+    /// Complete successfully with the `data` returned by a handler
+    ///
+    /// For OUT transfers, the whole buffer counts as transferred.
+    pub fn complete(&mut self, mut data: Vec<u8>) {
+        self.status = 0;
+        match self.direction() {
+            Direction::Out => {
+                recycle_scratch_buffer(data);
+                self.actual_length = self.buffer.len() as u32;
+            }
+            Direction::In => {
+                if data.len() > self.transfer_buffer_length as usize {
+                    // The client drops the connection on oversized replies
+                    warn!(
+                        "Truncating {} byte response to {}",
+                        data.len(),
+                        self.transfer_buffer_length
+                    );
+                    data.truncate(self.transfer_buffer_length as usize);
+                }
+                self.actual_length = data.len() as u32;
+                recycle_scratch_buffer(std::mem::replace(&mut self.buffer, data));
+            }
+        }
+    }
+
+    /// The USBIP_RET_SUBMIT reporting this URB
+    ///
+    /// `header` is the header of the USBIP_CMD_SUBMIT being answered.
+    pub fn into_ret_submit(self, header: &UsbIpHeaderBasic) -> UsbIpResponse {
+        let transfer_buffer = match self.direction() {
+            Direction::In if self.status == 0 => self.buffer,
+            _ => {
+                recycle_scratch_buffer(self.buffer);
+                vec![]
+            }
+        };
+        UsbIpResponse::UsbIpRetSubmit {
+            header: UsbIpHeaderBasic::reply(USBIP_RET_SUBMIT, header),
+            status: self.status as u32,
+            actual_length: self.actual_length,
+            start_frame: self.start_frame,
+            number_of_packets: self.number_of_packets,
+            error_count: self.error_count,
+            transfer_buffer,
+            iso_packet_descriptor: self.iso_packet_descriptor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usbip_protocol::USBIP_CMD_SUBMIT;
+    use crate::util::tests::*;
+
+    use super::*;
+
+    fn header() -> UsbIpHeaderBasic {
+        UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum: 1,
+            devid: 2,
+            direction: 1,
+            ep: 1,
+        }
+    }
+
+    #[test]
+    fn complete_in_urb() {
+        setup_test_logger();
+        let mut urb = Urb {
+            endpoint: UsbEndpoint {
+                address: 0x81,
+                ..Default::default()
+            },
+            transfer_buffer_length: 2,
+            ..Default::default()
+        };
+        urb.complete(vec![1, 2, 3]);
+        assert_eq!(
+            urb.into_ret_submit(&header()),
+            UsbIpResponse::usbip_ret_submit_success(&header(), 0, 0, 0, vec![1, 2], vec![])
+        );
+    }
+
+    #[test]
+    fn complete_out_urb() {
+        setup_test_logger();
+        let mut urb = Urb {
+            endpoint: UsbEndpoint {
+                address: 0x01,
+                ..Default::default()
+            },
+            buffer: vec![1, 2, 3],
+            ..Default::default()
+        };
+        urb.complete(vec![]);
+        assert_eq!(
+            urb.into_ret_submit(&header()),
+            UsbIpResponse::usbip_ret_submit_out_success(&header(), 3, 0, 0, 0, vec![])
+        );
+    }
+}
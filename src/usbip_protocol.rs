@@ -5,15 +5,35 @@
 //! and functions to send and receive them over a socket.
 //!
 //! They are based on the [Linux kernel documentation](https://docs.kernel.org/usb/usbip_protocol.html).
+//!
+//! Everything except the socket helpers only depends on `alloc`, so the wire
+//! types can be used without std with the `protocol-only` feature. Parsing is
+//! done by [UsbIpCommand::parse] on a byte slice, which is also what
+//! [UsbIpCommand::read_from_socket] uses.
 
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt;
 use log::trace;
+
+#[cfg(feature = "std")]
 use std::io::Result;
+#[cfg(feature = "std")]
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::UsbDevice;
+#[cfg(feature = "std")]
+use crate::{UsbDevice, recycle_scratch_buffer, take_scratch_buffer};
+
+/// Buffers are taken from the scratch pool when std is available
+#[cfg(feature = "std")]
+use crate::take_scratch_buffer as alloc_buffer;
+
+#[cfg(not(feature = "std"))]
+fn alloc_buffer(capacity: usize) -> Vec<u8> {
+    Vec::with_capacity(capacity)
+}
 
 /// USB/IP protocol version
 ///
@@ -30,6 +50,22 @@ pub const OP_REP_DEVLIST: u16 = 0x0005;
 /// Reply code: Reply to import
 pub const OP_REP_IMPORT: u16 = 0x0003;
 
+/// Command code: Negotiate protocol extensions
+///
+/// This is not part of the kernel protocol. Standard clients never send it,
+/// so extensions are only enabled for clients that opt in.
+pub const OP_REQ_EXTENSIONS: u16 = 0x80E0;
+/// Reply code: Protocol extensions enabled for this connection
+pub const OP_REP_EXTENSIONS: u16 = 0x00E0;
+
+/// Maximum number of extensions in one negotiation
+pub const MAX_EXTENSIONS: u32 = 256;
+
+/// Longest transfer buffer of a USBIP_CMD_SUBMIT, which bounds allocations
+pub const MAX_TRANSFER_BUFFER_LENGTH: u32 = 0x100_0000;
+/// Most isochronous packets of a USBIP_CMD_SUBMIT, as USBIP_MAX_ISO_PACKETS of Linux
+pub const MAX_ISO_PACKETS: u32 = 1024;
+
 /// Command code: Submit an URB
 pub const USBIP_CMD_SUBMIT: u16 = 0x0001;
 /// Command code: Unlink an URB
@@ -39,6 +75,21 @@ pub const USBIP_RET_SUBMIT: u16 = 0x0003;
 /// Reply code: Reply for URB unlink
 pub const USBIP_RET_UNLINK: u16 = 0x0004;
 
+/// Error number: Broken pipe, reported for stalled endpoints
+///
+/// Status fields carry negated Linux error numbers.
+pub const EPIPE: i32 = 32;
+/// Error number: Connection reset, reported for cancelled URBs
+pub const ECONNRESET: i32 = 104;
+/// Error number: No such device, reported once the device is gone
+pub const ENODEV: i32 = 19;
+/// Error number: Protocol error, e.g. a CRC or bit stuffing error on the bus
+pub const EPROTO: i32 = 71;
+/// Error number: Value too large, reported when a device sends more than requested
+pub const EOVERFLOW: i32 = 75;
+/// Error number: Timed out
+pub const ETIMEDOUT: i32 = 110;
+
 /// USB/IP direction
 ///
 /// NOTE: Must not be confused with rusb::Direction,
@@ -50,6 +101,91 @@ enum Direction {
     In = 1,
 }
 
+/// Errors when parsing a USB/IP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The packet uses a protocol version other than [USBIP_VERSION]
+    UnknownVersion(u16),
+    /// The command code is not known
+    UnknownCommand(u16),
+    /// The client requested more than [MAX_EXTENSIONS] extensions
+    TooManyExtensions(u32),
+    /// The transfer buffer is longer than [MAX_TRANSFER_BUFFER_LENGTH]
+    TransferTooLong(u32),
+    /// The URB has more than [MAX_ISO_PACKETS] isochronous packets
+    TooManyIsoPackets(u32),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownVersion(version) => write!(f, "Unknown version: {version:#04X}"),
+            Self::UnknownCommand(command) => write!(f, "Unknown command: {command:#04X}"),
+            Self::TooManyExtensions(count) => write!(f, "Too many extensions: {count}"),
+            Self::TransferTooLong(len) => write!(f, "Transfer too long: {len}"),
+            Self::TooManyIsoPackets(count) => write!(f, "Too many isochronous packets: {count}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// Result of parsing a packet from a byte slice
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Parsed<T> {
+    /// The packet and the number of bytes it occupied
+    Complete(T, usize),
+    /// At least this many bytes in total are needed to make progress
+    Incomplete(usize),
+}
+
+/// Why parsing stopped early
+enum Stop {
+    Incomplete(usize),
+    Error(ParseError),
+}
+
+impl From<ParseError> for Stop {
+    fn from(err: ParseError) -> Self {
+        Stop::Error(err)
+    }
+}
+
+/// Cursor over a byte slice that reports how many bytes are missing
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> core::result::Result<&'a [u8], Stop> {
+        let end = self.offset.saturating_add(len);
+        if end > self.bytes.len() {
+            return Err(Stop::Incomplete(end));
+        }
+        let result = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(result)
+    }
+
+    fn array<const N: usize>(&mut self) -> core::result::Result<[u8; N], Stop> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u16(&mut self) -> core::result::Result<u16, Stop> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> core::result::Result<u32, Stop> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+}
+
 /// Common header for all context sensitive packets
 ///
 /// All commands/responses which rely on a device being attached
@@ -66,17 +202,30 @@ pub struct UsbIpHeaderBasic {
 
 impl UsbIpHeaderBasic {
     /// Converts a byte array into a [UsbIpHeaderBasic].
+    ///
+    /// The direction should be 0 or 1, but is not checked.
     pub fn from_bytes(bytes: &[u8; 20]) -> Self {
-        let result = UsbIpHeaderBasic {
+        UsbIpHeaderBasic {
             command: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
             seqnum: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
             devid: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
             direction: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
             ep: u32::from_be_bytes(bytes[16..20].try_into().unwrap()),
-        };
-        // The direction should be 0 or 1
-        debug_assert!(result.direction & 1 == result.direction);
-        result
+        }
+    }
+
+    /// Header for a reply from the server to `request`
+    ///
+    /// Only the sequence number is kept, the kernel requires devid,
+    /// direction and ep to be zero in replies.
+    pub fn reply(command: u16, request: &UsbIpHeaderBasic) -> Self {
+        UsbIpHeaderBasic {
+            command: command.into(),
+            seqnum: request.seqnum,
+            devid: 0,
+            direction: 0,
+            ep: 0,
+        }
     }
 
     /// Converts the [UsbIpHeaderBasic] into a byte array.
@@ -89,25 +238,110 @@ impl UsbIpHeaderBasic {
         result[16..20].copy_from_slice(&self.ep.to_be_bytes());
         result
     }
+}
 
-    pub(crate) async fn read_from_socket_with_command<T: AsyncReadExt + Unpin>(
-        socket: &mut T,
-        command: u16,
-    ) -> Result<Self> {
-        let seqnum = socket.read_u32().await?;
-        let devid = socket.read_u32().await?;
-        let direction = socket.read_u32().await?;
-        // The direction should be 0 or 1
-        debug_assert!(direction & 1 == direction);
-        let ep = socket.read_u32().await?;
+/// Device information sent in OP_REP_DEVLIST and OP_REP_IMPORT
+///
+/// This is the wire representation of a `UsbDevice` without any of
+/// its runtime state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbIpDeviceInfo {
+    pub path: String,
+    pub bus_id: String,
+    pub bus_num: u32,
+    pub dev_num: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// bcdDevice, already BCD encoded
+    pub device_bcd: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    /// Class, subclass and protocol of each interface
+    pub interfaces: Vec<(u8, u8, u8)>,
+}
 
-        Ok(UsbIpHeaderBasic {
-            command: command.into(),
-            seqnum,
-            devid,
-            direction,
-            ep,
-        })
+impl UsbIpDeviceInfo {
+    /// Converts the [UsbIpDeviceInfo] into the 312 byte device block
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(312);
+
+        let mut path = self.path.as_bytes().to_vec();
+        debug_assert!(path.len() <= 256);
+        path.resize(256, 0);
+        result.extend_from_slice(path.as_slice());
+
+        let mut bus_id = self.bus_id.as_bytes().to_vec();
+        debug_assert!(bus_id.len() <= 32);
+        bus_id.resize(32, 0);
+        result.extend_from_slice(bus_id.as_slice());
+
+        result.extend_from_slice(&self.bus_num.to_be_bytes());
+        result.extend_from_slice(&self.dev_num.to_be_bytes());
+        result.extend_from_slice(&self.speed.to_be_bytes());
+        result.extend_from_slice(&self.vendor_id.to_be_bytes());
+        result.extend_from_slice(&self.product_id.to_be_bytes());
+        result.extend_from_slice(&self.device_bcd.to_be_bytes());
+        result.push(self.device_class);
+        result.push(self.device_subclass);
+        result.push(self.device_protocol);
+        result.push(self.configuration_value);
+        result.push(self.num_configurations);
+        result.push(self.interfaces.len() as u8);
+
+        result
+    }
+
+    /// Converts the [UsbIpDeviceInfo] into the device block followed by
+    /// one entry per interface, as used by OP_REP_DEVLIST
+    pub fn to_bytes_with_interfaces(&self) -> Vec<u8> {
+        let mut result = self.to_bytes();
+        result.reserve(4 * self.interfaces.len());
+
+        for (class, subclass, protocol) in &self.interfaces {
+            result.push(*class);
+            result.push(*subclass);
+            result.push(*protocol);
+            result.push(0); // padding
+        }
+
+        result
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<&UsbDevice> for UsbIpDeviceInfo {
+    fn from(device: &UsbDevice) -> Self {
+        Self {
+            path: device.path.clone(),
+            bus_id: device.bus_id.clone(),
+            bus_num: device.bus_num,
+            dev_num: device.dev_num,
+            speed: device.speed,
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            device_bcd: device.device_bcd.to_bcd(),
+            device_class: device.device_class,
+            device_subclass: device.device_subclass,
+            device_protocol: device.device_protocol,
+            configuration_value: device.configuration_value,
+            num_configurations: device.num_configurations,
+            interfaces: device
+                .interfaces
+                .iter()
+                .map(|intf| {
+                    (
+                        intf.interface_class,
+                        intf.interface_subclass,
+                        intf.interface_protocol,
+                    )
+                })
+                .collect(),
+        }
     }
 }
 
@@ -122,6 +356,11 @@ pub enum UsbIpCommand {
         status: u32,
         busid: [u8; 32],
     },
+    /// Extensions requested by the client, see [OP_REQ_EXTENSIONS]
+    OpReqExtensions {
+        status: u32,
+        extensions: Vec<u32>,
+    },
     UsbIpCmdSubmit {
         header: UsbIpHeaderBasic,
         transfer_flags: u32,
@@ -144,76 +383,129 @@ impl UsbIpCommand {
     ///
     /// This will consume a variable amount of bytes from the socket.
     /// It might fail if the bytes does not follow the USB/IP protocol properly.
-    pub async fn read_from_socket<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<UsbIpCommand> {
-        let version: u16 = socket.read_u16().await?;
+    #[cfg(feature = "std")]
+    pub async fn read_from_socket<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+    ) -> core::result::Result<UsbIpCommand, crate::UsbIpError> {
+        let mut buffer = take_scratch_buffer(48);
+        let mut needed = 4;
+        let result = loop {
+            let filled = buffer.len();
+            buffer.resize(needed, 0);
+            if let Err(err) = socket.read_exact(&mut buffer[filled..]).await {
+                break Err(err.into());
+            }
+            match Self::parse(&buffer) {
+                Ok(Parsed::Complete(command, _)) => break Ok(command),
+                Ok(Parsed::Incomplete(len)) => needed = len,
+                Err(err) => break Err(err.into()),
+            }
+        };
+        recycle_scratch_buffer(buffer);
+        result
+    }
+
+    /// Parses a [UsbIpCommand] from the start of a byte slice
+    ///
+    /// Returns [Parsed::Incomplete] with the total number of bytes needed
+    /// if the slice is too short, so callers can read more and try again.
+    pub fn parse(bytes: &[u8]) -> core::result::Result<Parsed<UsbIpCommand>, ParseError> {
+        let mut reader = Reader::new(bytes);
+        match Self::parse_from(&mut reader) {
+            Ok(command) => {
+                trace!(
+                    "Received command: {:#04X} ({})",
+                    u16::from_be_bytes([bytes[2], bytes[3]]),
+                    match command {
+                        UsbIpCommand::OpReqDevlist { .. } => "OP_REQ_DEVLIST",
+                        UsbIpCommand::OpReqImport { .. } => "OP_REQ_IMPORT",
+                        UsbIpCommand::OpReqExtensions { .. } => "OP_REQ_EXTENSIONS",
+                        UsbIpCommand::UsbIpCmdSubmit { .. } => "USBIP_CMD_SUBMIT",
+                        UsbIpCommand::UsbIpCmdUnlink { .. } => "USBIP_CMD_UNLINK",
+                    }
+                );
+                Ok(Parsed::Complete(command, reader.offset))
+            }
+            Err(Stop::Incomplete(len)) => Ok(Parsed::Incomplete(len)),
+            Err(Stop::Error(err)) => Err(err),
+        }
+    }
+
+    fn parse_from(reader: &mut Reader) -> core::result::Result<UsbIpCommand, Stop> {
+        let version = reader.u16()?;
 
         if version != 0 && version != USBIP_VERSION {
-            return Err(std::io::Error::other(format!(
-                "Unknown version: {version:#04X}"
-            )));
+            return Err(ParseError::UnknownVersion(version).into());
         }
 
-        let command: u16 = socket.read_u16().await?;
-
-        trace!(
-            "Received command: {:#04X} ({}), parsing...",
-            command,
-            match command {
-                OP_REQ_DEVLIST => "OP_REQ_DEVLIST",
-                OP_REQ_IMPORT => "OP_REQ_IMPORT",
-                USBIP_CMD_SUBMIT => "USBIP_CMD_SUBMIT",
-                USBIP_CMD_UNLINK => "USBIP_CMD_UNLINK",
-                _ => "Unknown",
-            }
-        );
+        let command = reader.u16()?;
 
         match command {
             OP_REQ_DEVLIST => {
-                let status = socket.read_u32().await?;
-                debug_assert!(status == 0);
+                let status = reader.u32()?;
 
                 Ok(UsbIpCommand::OpReqDevlist { status })
             }
             OP_REQ_IMPORT => {
-                let status = socket.read_u32().await?;
-                debug_assert!(status == 0);
-                let mut busid = [0; 32];
-                socket.read_exact(&mut busid).await?;
+                let status = reader.u32()?;
+                let busid = reader.array()?;
 
                 Ok(UsbIpCommand::OpReqImport { status, busid })
             }
+            OP_REQ_EXTENSIONS => {
+                let status = reader.u32()?;
+                let count = reader.u32()?;
+                if count > MAX_EXTENSIONS {
+                    return Err(ParseError::TooManyExtensions(count).into());
+                }
+                let extensions = reader
+                    .take(4 * count as usize)?
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+                    .collect();
+
+                Ok(UsbIpCommand::OpReqExtensions { status, extensions })
+            }
             USBIP_CMD_SUBMIT => {
-                let header =
-                    UsbIpHeaderBasic::read_from_socket_with_command(socket, USBIP_CMD_SUBMIT)
-                        .await?;
-                let transfer_flags = socket.read_u32().await?;
-                let transfer_buffer_length = socket.read_u32().await?;
-                let start_frame = socket.read_u32().await?;
-                let number_of_packets = socket.read_u32().await?;
-                let interval = socket.read_u32().await?;
-
-                let mut setup = [0; 8];
-                socket.read_exact(&mut setup).await?;
+                let header = Self::parse_header(reader, USBIP_CMD_SUBMIT)?;
+                let transfer_flags = reader.u32()?;
+                let transfer_buffer_length = reader.u32()?;
+                let start_frame = reader.u32()?;
+                let number_of_packets = reader.u32()?;
+                let interval = reader.u32()?;
+                let setup = reader.array()?;
+                if transfer_buffer_length > MAX_TRANSFER_BUFFER_LENGTH {
+                    return Err(ParseError::TransferTooLong(transfer_buffer_length).into());
+                }
 
-                let data = if header.direction == Direction::In as u32 {
-                    vec![]
+                let data_length = if header.direction == Direction::In as u32 {
+                    0
                 } else {
-                    let mut data = vec![0; transfer_buffer_length as usize];
-                    socket.read_exact(&mut data).await?;
-                    data
+                    transfer_buffer_length as usize
                 };
-
                 // The kernel docs specifies that this should be set to 0xFFFFFFFF for all
                 // non-ISO packets, however the actual implementation resorts to 0x00000000
                 // https://stackoverflow.com/questions/76899798/usb-ip-what-is-the-size-of-the-iso-packet-descriptor
-                let iso_packet_descriptor =
-                    if number_of_packets != 0 && number_of_packets != 0xFFFFFFFF {
-                        let mut result = vec![0; 16 * number_of_packets as usize];
-                        socket.read_exact(&mut result).await?;
-                        result
-                    } else {
-                        vec![]
-                    };
+                let iso_length = if number_of_packets != 0 && number_of_packets != 0xFFFFFFFF {
+                    if number_of_packets > MAX_ISO_PACKETS {
+                        return Err(ParseError::TooManyIsoPackets(number_of_packets).into());
+                    }
+                    16 * number_of_packets as usize
+                } else {
+                    0
+                };
+
+                // Make sure everything is there before allocating
+                let payload = reader.take(data_length.saturating_add(iso_length))?;
+
+                let data = if header.direction == Direction::In as u32 {
+                    vec![]
+                } else {
+                    let mut data = alloc_buffer(data_length);
+                    data.extend_from_slice(&payload[..data_length]);
+                    data
+                };
+                let iso_packet_descriptor = payload[data_length..].to_vec();
 
                 Ok(UsbIpCommand::UsbIpCmdSubmit {
                     header,
@@ -228,25 +520,40 @@ impl UsbIpCommand {
                 })
             }
             USBIP_CMD_UNLINK => {
-                let header =
-                    UsbIpHeaderBasic::read_from_socket_with_command(socket, USBIP_CMD_UNLINK)
-                        .await?;
-                let unlink_seqnum = socket.read_u32().await?;
+                let header = Self::parse_header(reader, USBIP_CMD_UNLINK)?;
+                let unlink_seqnum = reader.u32()?;
 
-                let mut _padding = [0; 24];
-                socket.read_exact(&mut _padding).await?;
+                let _padding = reader.take(24)?;
 
                 Ok(UsbIpCommand::UsbIpCmdUnlink {
                     header,
                     unlink_seqnum,
                 })
             }
-            _ => Err(std::io::Error::other(format!(
-                "Unknown command: {command:#04X}"
-            ))),
+            _ => Err(ParseError::UnknownCommand(command).into()),
         }
     }
 
+    /// Parses the rest of a [UsbIpHeaderBasic] whose command was already read
+    fn parse_header(
+        reader: &mut Reader,
+        command: u16,
+    ) -> core::result::Result<UsbIpHeaderBasic, Stop> {
+        let seqnum = reader.u32()?;
+        let devid = reader.u32()?;
+        // The direction should be 0 or 1, anything else is treated as OUT
+        let direction = reader.u32()?;
+        let ep = reader.u32()?;
+
+        Ok(UsbIpHeaderBasic {
+            command: command.into(),
+            seqnum,
+            devid,
+            direction,
+            ep,
+        })
+    }
+
     /// Converts the [UsbIpCommand] into a byte vector
     pub fn to_bytes(&self) -> Vec<u8> {
         match *self {
@@ -265,6 +572,20 @@ impl UsbIpCommand {
                 result.extend_from_slice(&busid);
                 result
             }
+            UsbIpCommand::OpReqExtensions {
+                status,
+                ref extensions,
+            } => {
+                let mut result = Vec::with_capacity(12 + 4 * extensions.len());
+                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+                result.extend_from_slice(&OP_REQ_EXTENSIONS.to_be_bytes());
+                result.extend_from_slice(&status.to_be_bytes());
+                result.extend_from_slice(&(extensions.len() as u32).to_be_bytes());
+                for extension in extensions {
+                    result.extend_from_slice(&extension.to_be_bytes());
+                }
+                result
+            }
             UsbIpCommand::UsbIpCmdSubmit {
                 ref header,
                 transfer_flags,
@@ -307,19 +628,98 @@ impl UsbIpCommand {
     }
 }
 
+/// Commands that [UsbIpCommand::to_bytes] encodes and [UsbIpCommand::parse] decodes back
+///
+/// Lengths and counts agree with the data, and stay within the limits of
+/// the parser, so fuzz targets can check the round trip.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for UsbIpCommand {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let header = |u: &mut arbitrary::Unstructured<'a>, command: u16, direction| {
+            Ok(UsbIpHeaderBasic {
+                command: command.into(),
+                seqnum: u.arbitrary()?,
+                devid: u.arbitrary()?,
+                direction,
+                ep: u.arbitrary()?,
+            })
+        };
+        Ok(match u.int_in_range(0..=4)? {
+            0 => UsbIpCommand::OpReqDevlist {
+                status: u.arbitrary()?,
+            },
+            1 => UsbIpCommand::OpReqImport {
+                status: u.arbitrary()?,
+                busid: u.arbitrary()?,
+            },
+            2 => {
+                let status = u.arbitrary()?;
+                let count = u.int_in_range(0..=MAX_EXTENSIONS)?;
+                UsbIpCommand::OpReqExtensions {
+                    status,
+                    extensions: (0..count)
+                        .map(|_| u.arbitrary())
+                        .collect::<arbitrary::Result<_>>()?,
+                }
+            }
+            3 => {
+                let direction = u.int_in_range(0..=1)?;
+                let header = header(u, USBIP_CMD_SUBMIT, direction)?;
+                let (transfer_buffer_length, data) = if direction == Direction::In as u32 {
+                    (u.int_in_range(0..=MAX_TRANSFER_BUFFER_LENGTH)?, vec![])
+                } else {
+                    let len =
+                        u.int_in_range(0..=u.len().min(MAX_TRANSFER_BUFFER_LENGTH as usize))?;
+                    (len as u32, u.bytes(len)?.to_vec())
+                };
+                let number_of_packets = match u.int_in_range(0..=2)? {
+                    0 => 0,
+                    1 => 0xFFFFFFFF,
+                    _ => u.int_in_range(1..=MAX_ISO_PACKETS)?,
+                };
+                let iso_packet_descriptor = if number_of_packets == 0xFFFFFFFF {
+                    vec![]
+                } else {
+                    u.bytes(16 * number_of_packets as usize)?.to_vec()
+                };
+                UsbIpCommand::UsbIpCmdSubmit {
+                    header,
+                    transfer_flags: u.arbitrary()?,
+                    transfer_buffer_length,
+                    start_frame: u.arbitrary()?,
+                    number_of_packets,
+                    interval: u.arbitrary()?,
+                    setup: u.arbitrary()?,
+                    data,
+                    iso_packet_descriptor,
+                }
+            }
+            _ => {
+                let direction = u.arbitrary()?;
+                UsbIpCommand::UsbIpCmdUnlink {
+                    header: header(u, USBIP_CMD_UNLINK, direction)?,
+                    unlink_seqnum: u.arbitrary()?,
+                }
+            }
+        })
+    }
+}
+
 /// Server side responses from the USB Host
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum UsbIpResponse {
     OpRepDevlist {
         status: u32,
         device_count: u32,
-        devices: Vec<UsbDevice>,
+        devices: Vec<UsbIpDeviceInfo>,
     },
     OpRepImport {
         status: u32,
-        device: Option<UsbDevice>,
+        device: Option<UsbIpDeviceInfo>,
     },
+    /// Extensions enabled by the server, see [OP_REP_EXTENSIONS]
+    OpRepExtensions { status: u32, extensions: Vec<u32> },
     UsbIpRetSubmit {
         header: UsbIpHeaderBasic,
         status: u32,
@@ -345,7 +745,7 @@ impl UsbIpResponse {
                 device_count,
                 ref devices,
             } => {
-                let mut result = Vec::with_capacity(
+                let mut result = alloc_buffer(
                     12 + devices.len() * 312
                         + devices
                             .iter()
@@ -362,7 +762,7 @@ impl UsbIpResponse {
                 result
             }
             Self::OpRepImport { status, ref device } => {
-                let mut result = Vec::with_capacity(320);
+                let mut result = alloc_buffer(320);
                 result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
                 result.extend_from_slice(&OP_REP_IMPORT.to_be_bytes());
                 result.extend_from_slice(&status.to_be_bytes());
@@ -371,6 +771,20 @@ impl UsbIpResponse {
                 }
                 result
             }
+            Self::OpRepExtensions {
+                status,
+                ref extensions,
+            } => {
+                let mut result = alloc_buffer(12 + 4 * extensions.len());
+                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+                result.extend_from_slice(&OP_REP_EXTENSIONS.to_be_bytes());
+                result.extend_from_slice(&status.to_be_bytes());
+                result.extend_from_slice(&(extensions.len() as u32).to_be_bytes());
+                for extension in extensions {
+                    result.extend_from_slice(&extension.to_be_bytes());
+                }
+                result
+            }
             Self::UsbIpRetSubmit {
                 ref header,
                 status,
@@ -382,14 +796,13 @@ impl UsbIpResponse {
                 ref iso_packet_descriptor,
             } => {
                 let mut result =
-                    Vec::with_capacity(48 + transfer_buffer.len() + iso_packet_descriptor.len());
+                    alloc_buffer(48 + transfer_buffer.len() + iso_packet_descriptor.len());
 
-                debug_assert!(header.command == USBIP_RET_SUBMIT.into());
-                debug_assert!(if header.direction == Direction::In as u32 {
-                    actual_length == transfer_buffer.len() as u32
-                } else {
-                    actual_length == 0
-                });
+                debug_assert!(header.command == u32::from(USBIP_RET_SUBMIT));
+                // OUT transfers carry no data but still report actual_length
+                debug_assert!(
+                    transfer_buffer.is_empty() || actual_length == transfer_buffer.len() as u32
+                );
 
                 result.extend_from_slice(&header.to_bytes());
                 result.extend_from_slice(&status.to_be_bytes());
@@ -403,9 +816,9 @@ impl UsbIpResponse {
                 result
             }
             Self::UsbIpRetUnlink { ref header, status } => {
-                let mut result = Vec::with_capacity(48);
+                let mut result = alloc_buffer(48);
 
-                debug_assert!(header.command == USBIP_RET_UNLINK.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_UNLINK));
 
                 result.extend_from_slice(&header.to_bytes());
                 result.extend_from_slice(&status.to_be_bytes());
@@ -415,24 +828,55 @@ impl UsbIpResponse {
         }
     }
 
+    #[cfg(feature = "std")]
     pub async fn write_to_socket<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
-        socket.write_all(&self.to_bytes()).await
+        let bytes = self.to_bytes();
+        socket.write_all(&bytes).await?;
+        recycle_scratch_buffer(bytes);
+        Ok(())
+    }
+
+    /// Return the buffers owned by this response to the scratch pool
+    ///
+    /// Call this after the response has been sent, see [take_scratch_buffer].
+    #[cfg(feature = "std")]
+    pub fn recycle(self) {
+        if let Self::UsbIpRetSubmit {
+            transfer_buffer,
+            iso_packet_descriptor,
+            ..
+        } = self
+        {
+            recycle_scratch_buffer(transfer_buffer);
+            recycle_scratch_buffer(iso_packet_descriptor);
+        }
     }
 
     /// Constructs a OP_REP_DEVLIST response
+    #[cfg(feature = "std")]
     pub fn op_rep_devlist(devices: &[UsbDevice]) -> Self {
         Self::OpRepDevlist {
             status: 0,
             device_count: devices.len() as u32,
-            devices: devices.to_vec(),
+            devices: devices.iter().map(UsbIpDeviceInfo::from).collect(),
         }
     }
 
     /// Constructs a successful OP_REP_IMPORT response
+    #[cfg(feature = "std")]
     pub fn op_rep_import_success(device: &UsbDevice) -> Self {
         Self::OpRepImport {
             status: 0,
-            device: Some(device.clone()),
+            device: Some(device.into()),
+        }
+    }
+
+    /// Constructs a failed OP_REP_DEVLIST response, listing no devices
+    pub fn op_rep_devlist_fail() -> Self {
+        Self::OpRepDevlist {
+            status: 1,
+            device_count: 0,
+            devices: vec![],
         }
     }
 
@@ -444,31 +888,77 @@ impl UsbIpResponse {
         }
     }
 
-    /// Constructs a successful OP_REP_IMPORT response
+    /// Constructs a OP_REP_EXTENSIONS response
+    ///
+    /// Only the extensions both requested by the client and supported
+    /// by the server are enabled, in the order requested by the client.
+    pub fn op_rep_extensions(requested: &[u32], supported: &[u32]) -> Self {
+        Self::OpRepExtensions {
+            status: 0,
+            extensions: requested
+                .iter()
+                .filter(|extension| supported.contains(extension))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Constructs a successful USBIP_RET_SUBMIT response for an IN transfer
+    ///
+    /// `header` is the header of the USBIP_CMD_SUBMIT being answered.
+    /// `actual_length` is the length of `transfer_buffer`.
     pub fn usbip_ret_submit_success(
         header: &UsbIpHeaderBasic,
         start_frame: u32,
         number_of_packets: u32,
+        error_count: u32,
         transfer_buffer: Vec<u8>,
         iso_packet_descriptor: Vec<u8>,
     ) -> Self {
         Self::UsbIpRetSubmit {
-            header: header.clone(),
+            header: UsbIpHeaderBasic::reply(USBIP_RET_SUBMIT, header),
             status: 0,
             actual_length: transfer_buffer.len() as u32,
             start_frame,
             number_of_packets,
-            error_count: 0,
+            error_count,
             transfer_buffer,
             iso_packet_descriptor,
         }
     }
 
-    /// Constructs a failed OP_REP_IMPORT response
+    /// Constructs a successful USBIP_RET_SUBMIT response for an OUT transfer
+    ///
+    /// `header` is the header of the USBIP_CMD_SUBMIT being answered.
+    /// No data is sent back, but `actual_length` still reports how many
+    /// bytes the device accepted.
+    pub fn usbip_ret_submit_out_success(
+        header: &UsbIpHeaderBasic,
+        actual_length: u32,
+        start_frame: u32,
+        number_of_packets: u32,
+        error_count: u32,
+        iso_packet_descriptor: Vec<u8>,
+    ) -> Self {
+        Self::UsbIpRetSubmit {
+            header: UsbIpHeaderBasic::reply(USBIP_RET_SUBMIT, header),
+            status: 0,
+            actual_length,
+            start_frame,
+            number_of_packets,
+            error_count,
+            transfer_buffer: vec![],
+            iso_packet_descriptor,
+        }
+    }
+
+    /// Constructs a failed USBIP_RET_SUBMIT response
+    ///
+    /// The status is -EPIPE, which the client reports as a stall.
     pub fn usbip_ret_submit_fail(header: &UsbIpHeaderBasic) -> Self {
         Self::UsbIpRetSubmit {
-            header: header.clone(),
-            status: 1,
+            header: UsbIpHeaderBasic::reply(USBIP_RET_SUBMIT, header),
+            status: (-EPIPE) as u32,
             actual_length: 0,
             start_frame: 0,
             number_of_packets: 0,
@@ -478,7 +968,9 @@ impl UsbIpResponse {
         }
     }
 
-    /// Constructs a successful OP_REP_IMPORT response
+    /// Constructs a USBIP_RET_UNLINK response for an URB that had already completed
+    ///
+    /// The status is 0, the client then waits for the USBIP_RET_SUBMIT instead.
     pub fn usbip_ret_unlink_success(header: &UsbIpHeaderBasic) -> Self {
         Self::UsbIpRetUnlink {
             header: header.clone(),
@@ -486,7 +978,17 @@ impl UsbIpResponse {
         }
     }
 
-    /// Constructs a failed OP_REP_IMPORT response.
+    /// Constructs a USBIP_RET_UNLINK response for an URB that was cancelled
+    ///
+    /// The status is -ECONNRESET and no USBIP_RET_SUBMIT is sent for the URB.
+    pub fn usbip_ret_unlink_cancelled(header: &UsbIpHeaderBasic) -> Self {
+        Self::UsbIpRetUnlink {
+            header: header.clone(),
+            status: (-ECONNRESET) as u32,
+        }
+    }
+
+    /// Constructs a failed USBIP_RET_UNLINK response
     pub fn usbip_ret_unlink_fail(header: &UsbIpHeaderBasic) -> Self {
         Self::UsbIpRetUnlink {
             header: header.clone(),
@@ -495,7 +997,7 @@ impl UsbIpResponse {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use crate::util::tests::*;
 
@@ -618,6 +1120,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::cloned_ref_to_slice_refs)]
     fn byte_serialize_op_rep_devlist() {
         setup_test_logger();
         let device = example_device();
@@ -629,7 +1132,7 @@ mod tests {
                 vec![0x00, 0x05],             // command
                 vec![0x00, 0x00, 0x00, 0x00], // status
                 vec![0x00, 0x00, 0x00, 0x01], // device_count
-                device.to_bytes()
+                UsbIpDeviceInfo::from(&device).to_bytes()
             ]
             .concat()
             .as_slice()
@@ -647,7 +1150,7 @@ mod tests {
                 vec![0x01, 0x11],             // version
                 vec![0x00, 0x03],             // command
                 vec![0x00, 0x00, 0x00, 0x00], // status
-                device.to_bytes()
+                UsbIpDeviceInfo::from(&device).to_bytes()
             ]
             .concat()
             .as_slice()
@@ -715,11 +1218,11 @@ mod tests {
                 command: USBIP_RET_SUBMIT.into(),
                 seqnum: 2,
                 devid: 3,
-                direction: Direction::Out as u32, // data section should be empty, but is not
+                direction: Direction::In as u32,
                 ep: 4,
             },
             status: 5,
-            actual_length: 4,
+            actual_length: 2, // does not match the data section
             start_frame: 7,
             number_of_packets: 8,
             error_count: 9,
@@ -730,6 +1233,115 @@ mod tests {
         res.to_bytes();
     }
 
+    fn cmd_submit_header(seqnum: u32, direction: Direction, ep: u32) -> UsbIpHeaderBasic {
+        UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum,
+            devid: 0x0001_0002,
+            direction: direction as u32,
+            ep,
+        }
+    }
+
+    #[test]
+    fn ret_submit_in_matches_kernel() {
+        setup_test_logger();
+        // GET_DESCRIPTOR(Device) as answered by the Linux usbip-host driver
+        let header = cmd_submit_header(0x0000_0007, Direction::In, 0);
+        let descriptor = vec![
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x6b, 0x1d, 0x04, 0x01, 0x10, 0x05,
+            0x03, 0x02, 0x01, 0x01,
+        ];
+        let res = UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, 0, descriptor, vec![]);
+
+        assert_eq!(
+            res.to_bytes(),
+            [
+                0x00, 0x00, 0x00, 0x03, // command
+                0x00, 0x00, 0x00, 0x07, // seqnum
+                0x00, 0x00, 0x00, 0x00, // devid
+                0x00, 0x00, 0x00, 0x00, // direction
+                0x00, 0x00, 0x00, 0x00, // ep
+                0x00, 0x00, 0x00, 0x00, // status
+                0x00, 0x00, 0x00, 0x12, // actual_length
+                0x00, 0x00, 0x00, 0x00, // start_frame
+                0x00, 0x00, 0x00, 0x00, // number_of_packets
+                0x00, 0x00, 0x00, 0x00, // error_count
+                0x00, 0x00, 0x00, 0x00, // padding
+                0x00, 0x00, 0x00, 0x00, //
+                0x12, 0x01, 0x00, 0x02, // transfer_buffer
+                0x00, 0x00, 0x00, 0x40, //
+                0x6b, 0x1d, 0x04, 0x01, //
+                0x10, 0x05, 0x03, 0x02, //
+                0x01, 0x01, //
+            ]
+        );
+    }
+
+    #[test]
+    fn ret_submit_out_matches_kernel() {
+        setup_test_logger();
+        // Bulk OUT of 64 bytes as answered by the Linux usbip-host driver
+        let header = cmd_submit_header(0x0000_0102, Direction::Out, 2);
+        let res = UsbIpResponse::usbip_ret_submit_out_success(&header, 64, 0, 0, 0, vec![]);
+
+        assert_eq!(
+            res.to_bytes(),
+            [
+                0x00, 0x00, 0x00, 0x03, // command
+                0x00, 0x00, 0x01, 0x02, // seqnum
+                0x00, 0x00, 0x00, 0x00, // devid
+                0x00, 0x00, 0x00, 0x00, // direction
+                0x00, 0x00, 0x00, 0x00, // ep
+                0x00, 0x00, 0x00, 0x00, // status
+                0x00, 0x00, 0x00, 0x40, // actual_length
+                0x00, 0x00, 0x00, 0x00, // start_frame
+                0x00, 0x00, 0x00, 0x00, // number_of_packets
+                0x00, 0x00, 0x00, 0x00, // error_count
+                0x00, 0x00, 0x00, 0x00, // padding
+                0x00, 0x00, 0x00, 0x00, //
+            ]
+        );
+    }
+
+    #[test]
+    fn ret_submit_stall_matches_kernel() {
+        setup_test_logger();
+        // Unsupported control request, the device stalled
+        let header = cmd_submit_header(0x0000_0009, Direction::In, 0);
+        let res = UsbIpResponse::usbip_ret_submit_fail(&header);
+
+        assert_eq!(
+            res.to_bytes(),
+            [
+                0x00, 0x00, 0x00, 0x03, // command
+                0x00, 0x00, 0x00, 0x09, // seqnum
+                0x00, 0x00, 0x00, 0x00, // devid
+                0x00, 0x00, 0x00, 0x00, // direction
+                0x00, 0x00, 0x00, 0x00, // ep
+                0xFF, 0xFF, 0xFF, 0xE0, // status (-EPIPE)
+                0x00, 0x00, 0x00, 0x00, // actual_length
+                0x00, 0x00, 0x00, 0x00, // start_frame
+                0x00, 0x00, 0x00, 0x00, // number_of_packets
+                0x00, 0x00, 0x00, 0x00, // error_count
+                0x00, 0x00, 0x00, 0x00, // padding
+                0x00, 0x00, 0x00, 0x00, //
+            ]
+        );
+    }
+
+    #[test]
+    fn ret_submit_reports_error_count() {
+        setup_test_logger();
+        let header = cmd_submit_header(1, Direction::In, 1);
+        let res = UsbIpResponse::usbip_ret_submit_success(&header, 8, 1, 1, vec![], vec![0; 16]);
+        let bytes = res.to_bytes();
+        assert_eq!(bytes[28..32], 8u32.to_be_bytes()); // start_frame
+        assert_eq!(bytes[32..36], 1u32.to_be_bytes()); // number_of_packets
+        assert_eq!(bytes[36..40], 1u32.to_be_bytes()); // error_count
+        assert_eq!(bytes.len(), 48 + 16);
+    }
+
     #[test]
     fn byte_serialize_usbip_ret_unlink() {
         setup_test_logger();
@@ -769,6 +1381,18 @@ mod tests {
         expected_result[5 * 4 + 3] = 1; // status
 
         assert_eq!(res.to_bytes(), expected_result,);
+
+        let res = UsbIpResponse::usbip_ret_unlink_cancelled(&UsbIpHeaderBasic {
+            command: USBIP_RET_UNLINK.into(),
+            seqnum: 1,
+            devid: 2,
+            direction: 3,
+            ep: 4,
+        });
+
+        expected_result[5 * 4..6 * 4].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0x98]); // -ECONNRESET
+
+        assert_eq!(res.to_bytes(), expected_result,);
     }
 
     #[tokio::test]
@@ -808,6 +1432,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn read_op_req_extensions_from_socket() -> Result<()> {
+        setup_test_logger();
+        let cmd = UsbIpCommand::OpReqExtensions {
+            status: 0,
+            extensions: vec![1, 0x12345678],
+        };
+        assert_eq!(
+            cmd.to_bytes(),
+            [
+                0x01, 0x11, // version
+                0x80, 0xE0, // command
+                0x00, 0x00, 0x00, 0x00, // status
+                0x00, 0x00, 0x00, 0x02, // count
+                0x00, 0x00, 0x00, 0x01, // extensions
+                0x12, 0x34, 0x56, 0x78, //
+            ]
+        );
+
+        assert_eq!(
+            cmd,
+            UsbIpCommand::read_from_socket(&mut MockSocket::new(cmd.to_bytes())).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_too_many_extensions_from_socket() {
+        setup_test_logger();
+        let cmd = UsbIpCommand::OpReqExtensions {
+            status: 0,
+            extensions: vec![0; MAX_EXTENSIONS as usize + 1],
+        };
+        let result = UsbIpCommand::read_from_socket(&mut MockSocket::new(cmd.to_bytes())).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn byte_serialize_op_rep_extensions() {
+        setup_test_logger();
+        let res = UsbIpResponse::op_rep_extensions(&[3, 1, 2], &[1, 3]);
+        assert_eq!(
+            res.to_bytes(),
+            [
+                0x01, 0x11, // version
+                0x00, 0xE0, // command
+                0x00, 0x00, 0x00, 0x00, // status
+                0x00, 0x00, 0x00, 0x02, // count
+                0x00, 0x00, 0x00, 0x03, // extensions
+                0x00, 0x00, 0x00, 0x01, //
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn read_usbip_cmd_submit_from_socket() -> Result<()> {
         setup_test_logger();
@@ -894,6 +1573,132 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parse_partial_usbip_cmd_submit() {
+        setup_test_logger();
+        let cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 2,
+                direction: Direction::Out as u32,
+                ep: 4,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 4,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![0x08, 0x09, 0x0A, 0x0B],
+            iso_packet_descriptor: vec![],
+        };
+        let mut bytes = cmd.to_bytes();
+
+        assert_eq!(UsbIpCommand::parse(&bytes[..2]), Ok(Parsed::Incomplete(4)));
+        assert_eq!(UsbIpCommand::parse(&bytes[..4]), Ok(Parsed::Incomplete(8)));
+        assert_eq!(
+            UsbIpCommand::parse(&bytes[..48]),
+            Ok(Parsed::Incomplete(52))
+        );
+
+        // Trailing bytes belong to the next packet
+        bytes.extend_from_slice(&[0xFF; 4]);
+        assert_eq!(UsbIpCommand::parse(&bytes), Ok(Parsed::Complete(cmd, 52)));
+    }
+
+    #[test]
+    fn parse_unknown_command() {
+        setup_test_logger();
+        assert_eq!(
+            UsbIpCommand::parse(&[0x01, 0x11, 0x10, 0x05]),
+            Err(ParseError::UnknownCommand(0x1005))
+        );
+    }
+
+    /// Deterministic bytes for the parser tests
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_garbage_without_panic() {
+        setup_test_logger();
+        for seed in 1..500u64 {
+            let command = [
+                OP_REQ_DEVLIST,
+                OP_REQ_IMPORT,
+                OP_REQ_EXTENSIONS,
+                USBIP_CMD_SUBMIT,
+                USBIP_CMD_UNLINK,
+            ][seed as usize % 5];
+            let mut bytes = USBIP_VERSION.to_be_bytes().to_vec();
+            bytes.extend(command.to_be_bytes());
+            bytes.extend(noise(64, seed));
+            for len in 0..=bytes.len() {
+                if let Ok(Parsed::Incomplete(needed)) = UsbIpCommand::parse(&bytes[..len]) {
+                    assert!(needed > len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_rejects_oversized_submit() {
+        setup_test_logger();
+        let submit = |transfer_buffer_length: u32, number_of_packets: u32| {
+            let mut bytes = UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1,
+                ep: 1,
+            }
+            .to_bytes()
+            .to_vec();
+            for field in [0, transfer_buffer_length, 0, number_of_packets, 0] {
+                bytes.extend(field.to_be_bytes());
+            }
+            bytes.extend([0; 8]);
+            UsbIpCommand::parse(&bytes)
+        };
+        assert_eq!(
+            submit(MAX_TRANSFER_BUFFER_LENGTH + 1, 0),
+            Err(ParseError::TransferTooLong(MAX_TRANSFER_BUFFER_LENGTH + 1))
+        );
+        assert_eq!(
+            submit(0, MAX_ISO_PACKETS + 1),
+            Err(ParseError::TooManyIsoPackets(MAX_ISO_PACKETS + 1))
+        );
+        assert_eq!(
+            submit(MAX_TRANSFER_BUFFER_LENGTH, 1),
+            Ok(Parsed::Incomplete(48 + 16))
+        );
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_commands_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+        setup_test_logger();
+        for seed in 1..500u64 {
+            let data = noise(0x200, seed);
+            let Ok(cmd) = UsbIpCommand::arbitrary(&mut Unstructured::new(&data)) else {
+                continue;
+            };
+            let bytes = cmd.to_bytes();
+            let len = bytes.len();
+            assert_eq!(UsbIpCommand::parse(&bytes), Ok(Parsed::Complete(cmd, len)));
+        }
+    }
+
     #[tokio::test]
     async fn byte_serialization_fails_on_old_usbip_version() {
         setup_test_logger();
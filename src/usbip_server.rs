@@ -1,61 +1,548 @@
-use crate::UsbDevice;
+use crate::{UsbDevice, UsbIpError, UsbmonCapture};
+use log::*;
 //use rusb::*;
 use std::collections::HashMap;
-use std::io::{ErrorKind, Result};
-use tokio::sync::RwLock;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Notify, RwLock, broadcast};
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod builder;
 #[cfg(feature = "nusb")]
 pub mod nusb_impl;
 #[cfg(feature = "rusb")]
 pub mod rusb_impl;
 pub mod server;
+pub mod stats;
 
 /// Main struct of a USB/IP server
 #[derive(Default, Debug)]
 pub struct UsbIpServer {
     available_devices: RwLock<Vec<UsbDevice>>,
-    used_devices: RwLock<HashMap<String, UsbDevice>>,
+    /// Shared with the sessions, which hold no lock while their URBs are in flight
+    used_devices: RwLock<HashMap<String, Arc<UsbDevice>>>,
+    extensions: Vec<u32>,
+    debug_delays: UsbIpDebugDelays,
+    error_budget: Option<UsbErrorBudget>,
+    /// See [UsbIpServer::with_device_reset]
+    device_reset: bool,
+    events: UsbIpEvents,
+    authenticator: Option<UsbIpAuthenticator>,
+    access_policy: Option<UsbIpAccessPolicy>,
+    identity_policy: Option<UsbIpIdentityPolicy>,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    /// Session of each imported device
+    sessions: std::sync::Mutex<HashMap<String, Arc<UsbIpSession>>>,
+    stats: stats::UsbIpStats,
+    /// By bus id, see [UsbIpServer::capture_device]
+    captures: std::sync::Mutex<HashMap<String, Arc<UsbmonCapture>>>,
 }
 
+/// A subset of the devices of a [UsbIpServer], exported on its own port
+///
+/// See [shard_server](crate::server::shard_server). All shards share the
+/// device lists of the server, which remains the single place to manage them.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub enum UsbIpShard {
+    /// Every device
+    #[default]
+    All,
+    /// Devices on one of these buses
+    Buses(Vec<u32>),
+    /// Devices with this tag, see [UsbDevice::with_tag]
+    Tag(String),
+}
+
+impl UsbIpShard {
+    /// Whether `device` is exported by this shard
+    pub fn contains(&self, device: &UsbDevice) -> bool {
+        match self {
+            Self::All => true,
+            Self::Buses(buses) => buses.contains(&device.bus_num),
+            Self::Tag(tag) => device.tag.as_ref() == Some(tag),
+        }
+    }
+}
+
+/// Delays inserted before replies, to reproduce timing issues of clients
+///
+/// Zero durations, the default, add no delay.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct UsbIpDebugDelays {
+    /// Before sending OP_REP_IMPORT
+    pub import: Duration,
+    /// Before sending USBIP_RET_SUBMIT
+    pub submit: Duration,
+    /// Before sending USBIP_RET_UNLINK
+    pub unlink: Duration,
+}
+
+/// How many failed URBs a device may have before it is taken offline
+///
+/// Meant for host devices, so that clients stop hammering hardware that
+/// stopped responding. See [UsbIpServer::with_error_budget].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbErrorBudget {
+    /// Failures tolerated within `window`, one more takes the device offline
+    pub max_failures: u32,
+    pub window: Duration,
+    /// Try [UsbDevice::reset] and export the device again if it succeeds
+    pub reset: bool,
+}
+
+/// Changes of the exported devices, see [UsbIpServer::subscribe]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UsbIpEvent {
+    /// The device was added with [UsbIpServer::add_device]
+    DeviceAdded { bus_id: String },
+    /// The device was removed with [UsbIpServer::remove_device]
+    DeviceRemoved { bus_id: String },
+    /// A client imported the device, `peer` is its address if known
+    DeviceImported {
+        bus_id: String,
+        peer: Option<SocketAddr>,
+    },
+    /// The client of the device disconnected or was detached, and the device is available again
+    DeviceReleased { bus_id: String },
+    /// A URB to the endpoint of the device failed
+    TransferError {
+        bus_id: String,
+        endpoint: u8,
+        error: String,
+    },
+    /// The device exceeded its [UsbErrorBudget] and is no longer exported
+    DeviceUnavailable { bus_id: String },
+    /// The device was reset after exceeding its [UsbErrorBudget] and is exported again
+    DeviceRecovered { bus_id: String },
+}
+
+/// An imported device, see [UsbIpServer::used_devices]
+#[derive(Clone, Debug)]
+pub struct UsbIpImport {
+    pub bus_id: String,
+    pub device: UsbDevice,
+    /// Address of the client, if known
+    pub peer: Option<SocketAddr>,
+    pub imported_at: SystemTime,
+    /// USBIP_CMD_SUBMIT received since the import
+    pub submitted_urbs: u64,
+    /// URBs which failed, including those to unknown endpoints
+    pub failed_urbs: u64,
+    /// USBIP_CMD_UNLINK received since the import
+    pub unlinked_urbs: u64,
+}
+
+/// State of the client which imported a device
+#[derive(Debug)]
+pub(crate) struct UsbIpSession {
+    /// Notified by [UsbIpServer::detach_device] to end the session
+    pub(crate) detach: Notify,
+    /// Notified once the session returned the device, see [UsbIpServer::release_device]
+    pub(crate) released: Notify,
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) imported_at: SystemTime,
+    pub(crate) submitted_urbs: AtomicU64,
+    pub(crate) failed_urbs: AtomicU64,
+    pub(crate) unlinked_urbs: AtomicU64,
+}
+
+impl UsbIpSession {
+    pub(crate) fn new(peer: Option<SocketAddr>) -> Self {
+        Self {
+            detach: Notify::new(),
+            released: Notify::new(),
+            peer,
+            imported_at: SystemTime::now(),
+            submitted_urbs: AtomicU64::new(0),
+            failed_urbs: AtomicU64::new(0),
+            unlinked_urbs: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Sender of [UsbIpEvent], which is dropped when nobody listens
+#[derive(Debug)]
+struct UsbIpEvents(broadcast::Sender<UsbIpEvent>);
+
+impl Default for UsbIpEvents {
+    fn default() -> Self {
+        Self(broadcast::channel(64).0)
+    }
+}
+
+/// A callback configured on the server, which cannot be printed
+struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> std::fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// Decides whether a client may list and import devices
+type UsbIpAuthenticator = Callback<dyn Fn(Option<SocketAddr>) -> bool + Send + Sync>;
+
+/// Decides whether a client may see and import a device
+type UsbIpAccessPolicy = Callback<dyn Fn(Option<SocketAddr>, &UsbDevice) -> bool + Send + Sync>;
+
+/// Decides whether a client with these identities may see and import a device
+type UsbIpIdentityPolicy = Callback<dyn Fn(&[String], &UsbDevice) -> bool + Send + Sync>;
+
 impl UsbIpServer {
     /// Create a [UsbIpServer] with simulated devices
     pub fn new_simulated(devices: Vec<UsbDevice>) -> Self {
         Self {
             available_devices: RwLock::new(devices),
-            used_devices: RwLock::new(HashMap::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Advertise protocol extensions to clients
+    ///
+    /// Clients opt in with [OP_REQ_EXTENSIONS](crate::usbip_protocol::OP_REQ_EXTENSIONS),
+    /// so standard clients are not affected.
+    pub fn with_extensions(mut self, extensions: Vec<u32>) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Slow down replies, for debugging only
+    pub fn with_debug_delays(mut self, debug_delays: UsbIpDebugDelays) -> Self {
+        self.debug_delays = debug_delays;
+        self
+    }
+
+    /// Enable TCP keepalive on accepted connections, probing after `idle` without traffic
+    ///
+    /// The operating system then closes connections to peers that silently
+    /// disappeared, which releases their devices.
+    pub fn with_keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Close connections that send no command for `timeout`
+    ///
+    /// Devices imported by the connection are made available again. Clients
+    /// send nothing while their devices are idle, so pick a generous value.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Apply the socket options of the server to an accepted connection
+    pub(crate) fn configure_socket<'a, S>(&self, socket: &'a S)
+    where
+        socket2::SockRef<'a>: From<&'a S>,
+    {
+        if let Some(idle) = self.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+            if let Err(err) = socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive) {
+                warn!("Failed to enable TCP keepalive: {err}");
+            }
+        }
+    }
+
+    /// Take devices offline when too many of their URBs fail
+    pub fn with_error_budget(mut self, error_budget: UsbErrorBudget) -> Self {
+        self.error_budget = Some(error_budget);
+        self
+    }
+
+    /// Reset imported devices with [UsbDevice::reset]
+    ///
+    /// Meant for host devices, to clear the state the previous client left
+    /// behind: devices are reset on OP_REQ_IMPORT, and when the client
+    /// resets their port, instead of passing SET_PORT_FEATURE(PORT_RESET)
+    /// on. Devices whose handler cannot reset are treated as before.
+    pub fn with_device_reset(mut self) -> Self {
+        self.device_reset = true;
+        self
+    }
+
+    /// Authenticate clients before their first OP_REQ_DEVLIST or OP_REQ_IMPORT
+    ///
+    /// `authenticator` gets the address of the client, `None` if the
+    /// connection has none, e.g. with [handler](crate::server::handler)
+    /// instead of [peer_handler](crate::server::peer_handler). Rejected
+    /// clients get an error status and are disconnected.
+    pub fn with_authenticator(
+        mut self,
+        authenticator: impl Fn(Option<SocketAddr>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authenticator = Some(Callback(Arc::new(authenticator)));
+        self
+    }
+
+    /// Whether the client at `peer` may use the server
+    fn authenticate(&self, peer: Option<SocketAddr>) -> bool {
+        let allowed = self
+            .authenticator
+            .as_ref()
+            .is_none_or(|authenticator| (authenticator.0)(peer));
+        if !allowed {
+            warn!("Rejected client {peer:?}");
         }
+        allowed
+    }
+
+    /// Restrict which devices each client may list and import
+    ///
+    /// `policy` gets the address of the client as with
+    /// [with_authenticator](UsbIpServer::with_authenticator), and a device.
+    /// Devices it rejects are left out of OP_REP_DEVLIST and cannot be imported.
+    pub fn with_access_policy(
+        mut self,
+        policy: impl Fn(Option<SocketAddr>, &UsbDevice) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.access_policy = Some(Callback(Arc::new(policy)));
+        self
+    }
+
+    /// Restrict which devices each client may list and import by its identities
+    ///
+    /// `policy` gets the identities of the client, the DNS and URI subject
+    /// alternative names of its TLS client certificate, e.g. SPIFFE IDs like
+    /// `spiffe://example.org/ci`, see [tls_config_with_client_auth](crate::tls_config_with_client_auth).
+    /// Clients without a certificate have none. Devices it rejects are left
+    /// out of OP_REP_DEVLIST and cannot be imported.
+    pub fn with_identity_policy(
+        mut self,
+        policy: impl Fn(&[String], &UsbDevice) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.identity_policy = Some(Callback(Arc::new(policy)));
+        self
+    }
+
+    /// Whether the client at `peer` with `identities` may see and import `device`
+    fn can_access(
+        &self,
+        peer: Option<SocketAddr>,
+        identities: &[String],
+        device: &UsbDevice,
+    ) -> bool {
+        self.access_policy
+            .as_ref()
+            .is_none_or(|policy| (policy.0)(peer, device))
+            && self
+                .identity_policy
+                .as_ref()
+                .is_none_or(|policy| (policy.0)(identities, device))
+    }
+
+    /// Receive [UsbIpEvent]s from now on
+    ///
+    /// Receivers lagging behind by more than 64 events miss the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<UsbIpEvent> {
+        self.events.0.subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: UsbIpEvent) {
+        trace!("Event {event:?}");
+        // fails only if nobody is subscribed
+        self.events.0.send(event).ok();
+    }
+
+    /// Protocol extensions advertised to clients
+    pub fn extensions(&self) -> &[u32] {
+        &self.extensions
     }
 
     pub async fn available_devices(&self) -> Vec<UsbDevice> {
         self.available_devices.read().await.clone()
     }
 
+    /// Imported devices with their clients, ordered by bus id
+    pub async fn used_devices(&self) -> Vec<UsbIpImport> {
+        let used_devices = self.used_devices.read().await;
+        let sessions = self.sessions.lock().unwrap();
+        let mut imports: Vec<_> = used_devices
+            .iter()
+            .filter_map(|(bus_id, device)| {
+                let session = sessions.get(bus_id)?;
+                Some(UsbIpImport {
+                    bus_id: bus_id.clone(),
+                    device: UsbDevice::clone(device),
+                    peer: session.peer,
+                    imported_at: session.imported_at,
+                    submitted_urbs: session.submitted_urbs.load(Ordering::Relaxed),
+                    failed_urbs: session.failed_urbs.load(Ordering::Relaxed),
+                    unlinked_urbs: session.unlinked_urbs.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        imports.sort_by(|a, b| a.bus_id.cmp(&b.bus_id));
+        imports
+    }
+
     pub async fn add_device(&self, device: UsbDevice) {
+        let bus_id = device.bus_id.clone();
         self.available_devices.write().await.push(device);
+        self.emit(UsbIpEvent::DeviceAdded { bus_id });
     }
 
-    pub async fn remove_device(&self, bus_id: &str) -> Result<()> {
+    pub async fn remove_device(&self, bus_id: &str) -> Result<(), UsbIpError> {
         let mut available_devices = self.available_devices.write().await;
 
         if let Some(device) = available_devices.iter().position(|d| d.bus_id == bus_id) {
             available_devices.remove(device);
+            self.stats.remove_device(bus_id);
+            self.emit(UsbIpEvent::DeviceRemoved {
+                bus_id: bus_id.to_string(),
+            });
             Ok(())
-        } else if let Some(device) = self
+        } else if self
             .used_devices
             .read()
             .await
             .values()
-            .find(|d| d.bus_id == bus_id)
+            .any(|d| d.bus_id == bus_id)
         {
-            Err(std::io::Error::other(format!(
-                "Device {} is in use",
-                device.bus_id
-            )))
+            Err(UsbIpError::DeviceBusy(bus_id.to_string()))
         } else {
-            Err(std::io::Error::new(
-                ErrorKind::NotFound,
-                format!("Device {bus_id} not found"),
-            ))
+            Err(UsbIpError::DeviceNotFound(bus_id.to_string()))
         }
     }
+
+    /// Kick the client off an imported device and make it available again
+    ///
+    /// The URB of the client in flight fails, e.g. the interrupt IN of a
+    /// device without events, and its session ends without reading further
+    /// commands. Returns once the device is available again. Does nothing
+    /// if the device is not imported.
+    pub async fn detach_device(&self, bus_id: &str) -> Result<(), UsbIpError> {
+        let session = self.sessions.lock().unwrap().get(bus_id).cloned();
+        let Some(session) = session else {
+            return if self
+                .available_devices
+                .read()
+                .await
+                .iter()
+                .any(|d| d.bus_id == bus_id)
+            {
+                Ok(())
+            } else {
+                Err(UsbIpError::DeviceNotFound(bus_id.to_string()))
+            };
+        };
+        info!("Detaching device {bus_id}");
+        session.detach.notify_one();
+        session.released.notified().await;
+        Ok(())
+    }
+
+    /// Replace an imported device with the same in another configuration
+    ///
+    /// Its session uses the new interfaces from its next URB on.
+    pub(crate) async fn reconfigure_device(&self, device: UsbDevice) {
+        if let Some(imported) = self.used_devices.write().await.get_mut(&device.bus_id) {
+            *imported = Arc::new(device);
+        }
+    }
+
+    /// Make the device of an ending session available again
+    ///
+    /// Does nothing if it was taken offline in the meantime.
+    pub(crate) async fn release_device(&self, bus_id: &str) {
+        let session = self.sessions.lock().unwrap().remove(bus_id);
+        let mut used_devices = self.used_devices.write().await;
+        let mut available_devices = self.available_devices.write().await;
+        if let Some(device) = used_devices.remove(bus_id) {
+            device.suspend().await;
+            available_devices.push(Arc::unwrap_or_clone(device));
+            self.emit(UsbIpEvent::DeviceReleased {
+                bus_id: bus_id.to_string(),
+            });
+        }
+        if let Some(session) = session {
+            session.released.notify_one();
+        }
+    }
+
+    /// Remove a host device that was unplugged, detaching its client first
+    #[cfg(feature = "nusb")]
+    pub(crate) async fn unplug_device(&self, bus_id: &str) {
+        info!("Host device {bus_id} unplugged");
+        if let Err(err) = self.detach_device(bus_id).await {
+            warn!("Failed to detach unplugged device {bus_id}: {err}");
+        }
+        if let Err(err) = self.remove_device(bus_id).await {
+            warn!("Failed to remove unplugged device {bus_id}: {err}");
+        }
+    }
+
+    /// Capture the URBs of the device into `capture`, or stop with `None`
+    ///
+    /// Takes effect with the next URB, and lasts across imports.
+    pub fn capture_device(&self, bus_id: &str, capture: Option<Arc<UsbmonCapture>>) {
+        let mut captures = self.captures.lock().unwrap();
+        match capture {
+            Some(capture) => {
+                info!("Capturing URBs of device {bus_id}");
+                captures.insert(bus_id.to_string(), capture);
+            }
+            None => {
+                captures.remove(bus_id);
+            }
+        }
+    }
+
+    pub(crate) fn device_capture(&self, bus_id: &str) -> Option<Arc<UsbmonCapture>> {
+        self.captures.lock().unwrap().get(bus_id).cloned()
+    }
+
+    /// Stop exporting an imported device that exceeded its error budget
+    ///
+    /// It is reset and made available again if the budget allows it.
+    async fn take_offline(&self, bus_id: &str) {
+        let Some(device) = self.used_devices.write().await.remove(bus_id) else {
+            return;
+        };
+        warn!(target: &device.log_target(), "Device {bus_id} exceeded its error budget");
+        self.sessions.lock().unwrap().remove(bus_id);
+        device.suspend().await;
+        self.emit(UsbIpEvent::DeviceUnavailable {
+            bus_id: bus_id.to_string(),
+        });
+
+        if self.error_budget.is_some_and(|budget| budget.reset) {
+            match device.reset().await {
+                Ok(()) => {
+                    info!(target: &device.log_target(), "Device {bus_id} recovered after reset");
+                    self.available_devices
+                        .write()
+                        .await
+                        .push(Arc::unwrap_or_clone(device));
+                    self.emit(UsbIpEvent::DeviceRecovered {
+                        bus_id: bus_id.to_string(),
+                    });
+                }
+                Err(err) => {
+                    warn!(target: &device.log_target(), "Failed to reset device {bus_id}: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Whether to export a plugged in host device with `key`
+///
+/// Devices are exported once, and only if they match `filter`, which is
+/// not asked again for exported devices.
+#[cfg(feature = "nusb")]
+pub(crate) fn exports_host_device<K: Eq + std::hash::Hash, V, D>(
+    exported: &HashMap<K, V>,
+    key: &K,
+    device: &D,
+    filter: &mut impl FnMut(&D) -> bool,
+) -> bool {
+    !exported.contains_key(key) && filter(device)
 }
@@ -0,0 +1,144 @@
+//! Server over [std::net] with a thread per connection
+//!
+//! Each connection runs [shard_handler](super::server::shard_handler) on a
+//! single threaded runtime of its own, so callers do not need to set up
+//! tokio. Devices and handlers are the same as with the async server.
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use crate::{UsbIpError, UsbIpServer, UsbIpShard};
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{Receiver, channel};
+
+/// A [TcpStream] read by a thread of its own
+///
+/// Reads must not block the runtime, or the idle timeout, the detach of
+/// the device and URB timeouts of the session could not fire. Writes are
+/// short and block.
+struct BlockingSocket {
+    stream: TcpStream,
+    /// Filled by the reader thread, empty data marks the end of the stream
+    received: Receiver<std::io::Result<Vec<u8>>>,
+    /// Received, but not read yet
+    pending: Vec<u8>,
+}
+
+impl BlockingSocket {
+    fn new(stream: TcpStream) -> std::io::Result<Self> {
+        let mut reader = stream.try_clone()?;
+        let (sender, received) = channel(4);
+        std::thread::spawn(move || {
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let res = reader.read(&mut buffer).map(|len| buffer[..len].to_vec());
+                let end = !matches!(&res, Ok(data) if !data.is_empty());
+                if sender.blocking_send(res).is_err() || end {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            stream,
+            received,
+            pending: vec![],
+        })
+    }
+}
+
+impl Drop for BlockingSocket {
+    fn drop(&mut self) {
+        // wakes up the reader thread and tells the client the session ended
+        self.stream.shutdown(std::net::Shutdown::Both).ok();
+    }
+}
+
+impl AsyncRead for BlockingSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.pending.is_empty() {
+            match ready!(self.received.poll_recv(cx)) {
+                Some(Ok(data)) => self.pending = data,
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                // the reader thread ended after the end of the stream
+                None => {}
+            }
+        }
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BlockingSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(self.stream.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.stream.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.stream.shutdown(std::net::Shutdown::Write))
+    }
+}
+
+/// Serve a single connection on the current thread until it is closed
+pub fn blocking_handler(
+    socket: TcpStream,
+    server: Arc<UsbIpServer>,
+    shard: &UsbIpShard,
+) -> Result<(), UsbIpError> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let peer = socket.peer_addr()?;
+    let mut socket = BlockingSocket::new(socket)?;
+    runtime.block_on(super::server::peer_handler(
+        &mut socket,
+        server,
+        shard,
+        peer,
+    ))
+}
+
+/// Run a USB/IP server at `addr` using [TcpListener], spawning a thread per connection
+///
+/// Only returns if binding to `addr` fails.
+pub fn blocking_server(
+    addr: SocketAddr,
+    server: Arc<UsbIpServer>,
+    shard: UsbIpShard,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let shard = Arc::new(shard);
+    loop {
+        match listener.accept() {
+            Ok((socket, addr)) => {
+                info!("Got connection from {addr:?}");
+                server.configure_socket(&socket);
+                let server = server.clone();
+                let shard = shard.clone();
+                std::thread::spawn(move || {
+                    let res = blocking_handler(socket, server, &shard);
+                    info!("Handler ended with {res:?}");
+                });
+            }
+            Err(err) => {
+                warn!("Got error {err:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! Configurable server
+//!
+//! [server](super::server::server) binds a single address with the
+//! defaults of the operating system. [UsbIpServerBuilder] listens on several
+//! TCP and Unix socket addresses at once, and sets up the listening sockets,
+//! the accepted connections and their sessions.
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::server::{SessionOptions, session};
+use crate::{RecordingSocket, UsbIpServer, UsbIpShard, UsbmonCapture};
+use log::*;
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Options of a server for a [UsbIpServer], see [UsbIpServerBuilder::serve]
+#[derive(Clone, Debug)]
+pub struct UsbIpServerBuilder {
+    addrs: Vec<ListenAddr>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    backlog: u32,
+    max_connections: Option<usize>,
+    urb_timeout: Option<Duration>,
+    recv_buffer_size: Option<u32>,
+    send_buffer_size: Option<u32>,
+    read_buffer_size: Option<usize>,
+    record_dir: Option<PathBuf>,
+    capture_dir: Option<PathBuf>,
+}
+
+impl Default for UsbIpServerBuilder {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            nodelay: false,
+            keepalive: None,
+            backlog: 1024,
+            max_connections: None,
+            urb_timeout: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            read_buffer_size: None,
+            record_dir: None,
+            capture_dir: None,
+        }
+    }
+}
+
+impl UsbIpServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept connections at `addr`, in addition to previous addresses
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(ListenAddr::Tcp(addr));
+        self
+    }
+
+    /// Set TCP_NODELAY on connections, so small replies are not delayed
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Like [UsbIpServer::with_keepalive], which applies if this is not set
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Connections waiting to be accepted, 1024 by default
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Stop accepting connections while `max` are open
+    ///
+    /// Further clients wait in the backlog until a connection closes.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Fail URBs which are not handled within `timeout`
+    pub fn urb_timeout(mut self, timeout: Duration) -> Self {
+        self.urb_timeout = Some(timeout);
+        self
+    }
+
+    /// Set SO_RCVBUF of connections
+    pub fn recv_buffer_size(mut self, size: u32) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set SO_SNDBUF of connections
+    pub fn send_buffer_size(mut self, size: u32) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Buffer reads from connections, with `size` bytes per connection
+    ///
+    /// Commands are then read with fewer system calls.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = Some(size);
+        self
+    }
+
+    /// Record every session into a new file in `dir`, see [RecordingSocket]
+    ///
+    /// Sessions whose file cannot be created are not recorded.
+    pub fn record_sessions(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.record_dir = Some(dir.into());
+        self
+    }
+
+    /// Capture the URBs of every session into a new pcap file in `dir`, see [UsbmonCapture]
+    ///
+    /// Sessions whose file cannot be created are not captured.
+    pub fn capture_sessions(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.capture_dir = Some(dir.into());
+        self
+    }
+
+    /// Accept connections at the Unix socket `path`, in addition to previous addresses
+    ///
+    /// `path` must not exist yet. Clients connecting to it have no address.
+    #[cfg(unix)]
+    pub fn listen_unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.addrs.push(ListenAddr::Unix(path.into()));
+        self
+    }
+
+    /// Serve `server` at every address until they all stop
+    ///
+    /// Only returns if binding to one of the addresses fails, or none was
+    /// given. See [start](Self::start) to control the listeners.
+    pub async fn serve(self, server: Arc<UsbIpServer>) -> std::io::Result<()> {
+        self.start(server)?.wait().await;
+        Ok(())
+    }
+
+    /// Bind every address and serve `server` in the background
+    ///
+    /// IPv6 addresses accept IPv4 clients as well where the operating
+    /// system allows it, so `[::]:3240` covers both.
+    pub fn start(self, server: Arc<UsbIpServer>) -> std::io::Result<UsbIpListeners> {
+        if self.addrs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No address to listen on",
+            ));
+        }
+        let listeners = self
+            .addrs
+            .iter()
+            .map(|addr| self.bind(addr))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let builder = Arc::new(self);
+        let connections = Arc::new(Semaphore::new(
+            builder.max_connections.unwrap_or(Semaphore::MAX_PERMITS),
+        ));
+        let mut tasks = JoinSet::new();
+        let mut addrs = vec![];
+        for (addr, listener) in listeners {
+            let enabled = Arc::new(AtomicBool::new(true));
+            addrs.push((addr, enabled.clone()));
+            tasks.spawn(builder.clone().accept_loop(
+                listener,
+                enabled,
+                server.clone(),
+                connections.clone(),
+            ));
+        }
+        Ok(UsbIpListeners { addrs, tasks })
+    }
+
+    fn bind(&self, addr: &ListenAddr) -> std::io::Result<(ListenAddr, Listener)> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+                socket.set_reuse_address(true)?;
+                if addr.is_ipv6() {
+                    // dual stack, not supported everywhere
+                    if let Err(err) = socket.set_only_v6(false) {
+                        debug!("Failed to accept IPv4 at {addr}: {err}");
+                    }
+                }
+                socket.bind(&(*addr).into())?;
+                socket.listen(self.backlog.try_into().unwrap_or(i32::MAX))?;
+                socket.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(socket.into())?;
+                // the port chosen by the system for port 0
+                let local_addr = listener.local_addr()?;
+                Ok((ListenAddr::Tcp(local_addr), Listener::Tcp(listener)))
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => Ok((
+                ListenAddr::Unix(path.clone()),
+                Listener::Unix(UnixListener::bind(path)?),
+            )),
+        }
+    }
+
+    async fn accept_loop(
+        self: Arc<Self>,
+        listener: Listener,
+        enabled: Arc<AtomicBool>,
+        server: Arc<UsbIpServer>,
+        connections: Arc<Semaphore>,
+    ) {
+        loop {
+            let permit = connections.clone().acquire_owned().await.unwrap();
+            let accepted = match &listener {
+                Listener::Tcp(listener) => listener.accept().await.map(|(socket, addr)| {
+                    info!("Got connection from {addr:?}");
+                    self.configure(&socket, &server);
+                    (Connection::Tcp(socket), Some(addr))
+                }),
+                #[cfg(unix)]
+                Listener::Unix(listener) => listener.accept().await.map(|(socket, addr)| {
+                    info!("Got connection at {addr:?}");
+                    (Connection::Unix(socket), None)
+                }),
+            };
+            match accepted {
+                Ok(_) if !enabled.load(Ordering::Relaxed) => {
+                    info!("Refused connection to a disabled listener");
+                }
+                Ok((connection, peer)) => {
+                    let builder = self.clone();
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        let res = match connection {
+                            Connection::Tcp(socket) => builder.handle(socket, server, peer).await,
+                            #[cfg(unix)]
+                            Connection::Unix(socket) => builder.handle(socket, server, peer).await,
+                        };
+                        info!("Handler ended with {res:?}");
+                        drop(permit);
+                    });
+                }
+                Err(err) => {
+                    warn!("Got error {err:?}");
+                }
+            }
+        }
+    }
+
+    fn configure(&self, socket: &TcpStream, server: &UsbIpServer) {
+        let res = (|| {
+            socket.set_nodelay(self.nodelay)?;
+            let sock_ref = socket2::SockRef::from(socket);
+            if let Some(size) = self.recv_buffer_size {
+                sock_ref.set_recv_buffer_size(size as usize)?;
+            }
+            if let Some(size) = self.send_buffer_size {
+                sock_ref.set_send_buffer_size(size as usize)?;
+            }
+            if let Some(idle) = self.keepalive {
+                sock_ref.set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(idle))?;
+            }
+            std::io::Result::Ok(())
+        })();
+        if let Err(err) = res {
+            warn!("Failed to configure connection: {err}");
+        }
+        if self.keepalive.is_none() {
+            server.configure_socket(socket);
+        }
+    }
+
+    async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        socket: T,
+        server: Arc<UsbIpServer>,
+        peer: Option<SocketAddr>,
+    ) -> Result<(), crate::UsbIpError> {
+        let Some(dir) = &self.record_dir else {
+            return self.handle_session(socket, server, peer).await;
+        };
+        let path = session_file(dir, "usbiprec");
+        match std::fs::File::create(&path) {
+            Ok(file) => match RecordingSocket::new(socket, file) {
+                Ok(socket) => {
+                    info!("Recording session of {peer:?} into {}", path.display());
+                    self.handle_session(socket, server, peer).await
+                }
+                Err(err) => Err(err.into()),
+            },
+            Err(err) => {
+                warn!("Failed to create recording {}: {err}", path.display());
+                self.handle_session(socket, server, peer).await
+            }
+        }
+    }
+
+    async fn handle_session<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut socket: T,
+        server: Arc<UsbIpServer>,
+        peer: Option<SocketAddr>,
+    ) -> Result<(), crate::UsbIpError> {
+        let capture = self.capture_dir.as_ref().and_then(|dir| {
+            let path = session_file(dir, "pcap");
+            match UsbmonCapture::create(&path) {
+                Ok(capture) => {
+                    info!("Capturing session of {peer:?} into {}", path.display());
+                    Some(Arc::new(capture))
+                }
+                Err(err) => {
+                    warn!("Failed to create capture {}: {err}", path.display());
+                    None
+                }
+            }
+        });
+        let options = SessionOptions {
+            urb_timeout: self.urb_timeout,
+            capture,
+            identities: vec![],
+        };
+        let shard = UsbIpShard::All;
+        match self.read_buffer_size {
+            Some(size) => {
+                let mut socket = BufReader::with_capacity(size, socket);
+                session(&mut socket, server, &shard, peer, options).await
+            }
+            None => session(&mut socket, server, &shard, peer, options).await,
+        }
+    }
+}
+
+/// A new file in `dir` for a session
+///
+/// Sessions accepted within the same clock tick get distinct names from a
+/// counter.
+fn session_file(dir: &Path, extension: &str) -> PathBuf {
+    static SESSIONS: AtomicU64 = AtomicU64::new(0);
+    let since_epoch = std::time::UNIX_EPOCH.elapsed().unwrap_or_default();
+    let session = SESSIONS.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!("{}-{session}.{extension}", since_epoch.as_nanos()))
+}
+
+/// An address of [UsbIpServerBuilder] to listen on
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Listeners started by [UsbIpServerBuilder::start]
+///
+/// Dropping it stops accepting connections, open connections go on.
+#[derive(Debug)]
+pub struct UsbIpListeners {
+    addrs: Vec<(ListenAddr, Arc<AtomicBool>)>,
+    tasks: JoinSet<()>,
+}
+
+impl UsbIpListeners {
+    /// Bound addresses, with the actual port of TCP addresses with port 0
+    pub fn addrs(&self) -> Vec<ListenAddr> {
+        self.addrs.iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+    /// Accept or refuse new connections at `addr`, all are enabled at first
+    ///
+    /// Connections accepted before are not affected. Returns false if the
+    /// server does not listen at `addr`.
+    pub fn set_enabled(&self, addr: &ListenAddr, enabled: bool) -> bool {
+        let mut found = false;
+        for (_, flag) in self.addrs.iter().filter(|(a, _)| a == addr) {
+            flag.store(enabled, Ordering::Relaxed);
+            found = true;
+        }
+        found
+    }
+
+    pub fn is_enabled(&self, addr: &ListenAddr) -> Option<bool> {
+        self.addrs
+            .iter()
+            .find(|(a, _)| a == addr)
+            .map(|(_, flag)| flag.load(Ordering::Relaxed))
+    }
+
+    /// Wait for the listeners, which only stop if they panic
+    pub async fn wait(mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn session_files_distinct() {
+        setup_test_logger();
+        let dir = Path::new("/tmp");
+        let files = (0..100)
+            .map(|_| session_file(dir, "pcap"))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(files.len(), 100);
+    }
+}
@@ -1,10 +1,20 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+use futures_core::Stream;
+use nusb::hotplug::HotplugEvent;
+
 use log::*;
 
+use super::exports_host_device;
 use crate::{
-    EndpointAttributes, NusbUsbHostDeviceHandler, NusbUsbHostInterfaceHandler, UsbDevice,
-    UsbEndpoint, UsbInterface, UsbInterfaceHandler, UsbIpServer,
+    DescriptorType, EndpointAttributes, NusbUsbHostDeviceHandler, NusbUsbHostInterfaceHandler,
+    UnavailableInterfaceHandler, UsbAlternateSetting, UsbDevice, UsbDeviceState, UsbEndpoint,
+    UsbInterface, UsbInterfaceHandler, UsbIpServer, UsbPathFormat, UsbSpeed,
 };
 
 impl UsbIpServer {
@@ -28,53 +38,27 @@ impl UsbIpServer {
                     continue;
                 }
             };
-            let mut interfaces = vec![];
-            for intf in cfg.interfaces() {
-                // ignore alternate settings
-                let intf_num = intf.interface_number();
-                let intf = dev.claim_interface(intf_num).unwrap();
-                let alt_setting = intf.descriptors().next().unwrap();
-                let mut endpoints = vec![];
-
-                for ep_desc in alt_setting.endpoints() {
-                    endpoints.push(UsbEndpoint {
-                        address: ep_desc.address(),
-                        attributes: ep_desc.transfer_type() as u8,
-                        max_packet_size: ep_desc.max_packet_size() as u16,
-                        interval: ep_desc.interval(),
-                    });
+            let state = Arc::new(Mutex::new(UsbDeviceState::configured(
+                cfg.configuration_value(),
+            )));
+            let interfaces = nusb_interfaces(&dev, &cfg, &state);
+            let speed = device_info
+                .speed()
+                .map_or(UsbSpeed::Unknown, UsbSpeed::from);
+            let (usb_version, ep0_max_packet_size) = match read_device_descriptor(&dev, speed) {
+                Some(fields) => fields,
+                None => {
+                    warn!(
+                        "Impossible to read device descriptor of {device_info:?}, guessing from speed",
+                    );
+                    (0x0200, speed.default_ep0_max_packet_size())
                 }
-
-                let handler = Arc::new(Mutex::new(Box::new(NusbUsbHostInterfaceHandler::new(
-                    Arc::new(Mutex::new(intf.clone())),
-                ))
-                    as Box<dyn UsbInterfaceHandler + Send>));
-                interfaces.push(UsbInterface {
-                    interface_class: alt_setting.class(),
-                    interface_subclass: alt_setting.subclass(),
-                    interface_protocol: alt_setting.protocol(),
-                    endpoints,
-                    string_interface: alt_setting.string_index().unwrap_or(0),
-                    class_specific_descriptor: Vec::new(),
-                    handler,
-                });
-            }
+            };
             let mut device = UsbDevice {
-                path: format!(
-                    "/sys/bus/{}/{}/{}",
-                    device_info.bus_number(),
-                    device_info.device_address(),
-                    0
-                ),
-                bus_id: format!(
-                    "{}-{}-{}",
-                    device_info.bus_number(),
-                    device_info.device_address(),
-                    0,
-                ),
                 bus_num: device_info.bus_number() as u32,
+                ports: port_chain(&device_info),
                 dev_num: 0,
-                speed: device_info.speed().unwrap() as u32,
+                speed: speed as u32,
                 vendor_id: device_info.vendor_id(),
                 product_id: device_info.product_id(),
                 device_class: device_info.class(),
@@ -83,24 +67,30 @@ impl UsbIpServer {
                 device_bcd: device_info.device_version().into(),
                 configuration_value: cfg.configuration_value(),
                 num_configurations: dev.configurations().count() as u8,
+                self_powered: cfg.attributes() & 0x40 != 0,
+                remote_wakeup: cfg.attributes() & 0x20 != 0,
+                max_power: cfg.max_power(),
                 ep0_in: UsbEndpoint {
                     address: 0x80,
                     attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: 16,
+                    max_packet_size: ep0_max_packet_size,
                     interval: 0,
                 },
                 ep0_out: UsbEndpoint {
                     address: 0x00,
                     attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: 16,
+                    max_packet_size: ep0_max_packet_size,
                     interval: 0,
                 },
                 interfaces,
-                device_handler: Some(Arc::new(Mutex::new(Box::new(
-                    NusbUsbHostDeviceHandler::new(Arc::new(Mutex::new(dev))),
+                state,
+                device_handler: Some(Arc::new(AsyncMutex::new(Box::new(
+                    NusbUsbHostDeviceHandler::new(dev),
                 )))),
+                usb_version: usb_version.into(),
                 ..UsbDevice::default()
-            };
+            }
+            .with_path_format(&UsbPathFormat::Kernel);
 
             // set strings
             if let Some(s) = device_info.manufacturer_string() {
@@ -117,3 +107,332 @@ impl UsbIpServer {
         devices
     }
 }
+
+impl UsbIpServer {
+    /// Keep the exported host devices in sync with the devices plugged in
+    ///
+    /// Exports the devices matching `filter` that are present, then those
+    /// plugged in later. Unplugged devices are removed, after detaching the
+    /// client that imported them. Runs as long as the hotplug events do, so
+    /// spawn it on a task of its own.
+    pub async fn watch_host_devices<F>(&self, mut filter: F) -> std::io::Result<()>
+    where
+        F: FnMut(&nusb::DeviceInfo) -> bool,
+    {
+        // watch before listing, so that no device falls in between
+        let mut watch = nusb::watch_devices()?;
+        let mut exported: HashMap<nusb::DeviceId, Vec<String>> = HashMap::new();
+        for device_info in nusb::list_devices()? {
+            self.export_host_device(device_info, &mut filter, &mut exported)
+                .await;
+        }
+        while let Some(event) = std::future::poll_fn(|cx| Pin::new(&mut watch).poll_next(cx)).await
+        {
+            match event {
+                HotplugEvent::Connected(device_info) => {
+                    self.export_host_device(device_info, &mut filter, &mut exported)
+                        .await;
+                }
+                HotplugEvent::Disconnected(id) => {
+                    for bus_id in exported.remove(&id).unwrap_or_default() {
+                        self.unplug_device(&bus_id).await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn export_host_device<F>(
+        &self,
+        device_info: nusb::DeviceInfo,
+        filter: &mut F,
+        exported: &mut HashMap<nusb::DeviceId, Vec<String>>,
+    ) where
+        F: FnMut(&nusb::DeviceInfo) -> bool,
+    {
+        let id = device_info.id();
+        if !exports_host_device(exported, &id, &device_info, filter) {
+            return;
+        }
+        let devices = Self::with_nusb_devices(vec![device_info]);
+        let bus_ids = devices.iter().map(|device| device.bus_id.clone()).collect();
+        for device in devices {
+            info!("Host device {} plugged in", device.bus_id);
+            self.add_device(device).await;
+        }
+        exported.insert(id, bus_ids);
+    }
+}
+
+/// The interfaces of `cfg`, claimed from `dev`
+///
+/// Interfaces held by another driver are described, but unavailable.
+pub(crate) fn nusb_interfaces(
+    dev: &nusb::Device,
+    cfg: &nusb::descriptors::Configuration,
+    state: &Arc<Mutex<UsbDeviceState>>,
+) -> Vec<UsbInterface> {
+    let mut interfaces = vec![];
+    for intf in cfg.interfaces() {
+        let intf_num = intf.interface_number();
+        if intf.alt_settings().next().is_none() {
+            warn!("Interface {intf_num} has no descriptors");
+            continue;
+        }
+        // an interface held by another driver must not abort the whole device
+        let (handler, unavailable_reason) = match dev.claim_interface(intf_num) {
+            Ok(intf) => (
+                Box::new(NusbUsbHostInterfaceHandler::new(intf))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+                None,
+            ),
+            Err(err) => {
+                warn!(
+                    "Impossible to claim interface {intf_num}: {err}, exporting it as unavailable"
+                );
+                let reason = format!("Failed to claim interface {intf_num}: {err}");
+                (
+                    Box::new(UnavailableInterfaceHandler::new(&reason))
+                        as Box<dyn UsbInterfaceHandler + Send>,
+                    Some(reason),
+                )
+            }
+        };
+        if let Some(mut interface) = nusb_interface(&intf, handler, state.clone()) {
+            interface.unavailable_reason = unavailable_reason;
+            interfaces.push(interface);
+        }
+    }
+    interfaces
+}
+
+/// The interface described by `intf`, `None` without descriptors
+fn nusb_interface(
+    intf: &nusb::descriptors::InterfaceGroup,
+    handler: Box<dyn UsbInterfaceHandler + Send>,
+    device_state: Arc<Mutex<UsbDeviceState>>,
+) -> Option<UsbInterface> {
+    let mut settings = intf.alt_settings();
+    let alt_setting = settings.next()?;
+    // many audio and video devices only stream in alternate settings
+    let alternate_settings = settings
+        .map(|setting| UsbAlternateSetting {
+            endpoints: nusb_endpoints(&setting),
+            class_specific_descriptor: Vec::new(),
+            class_specific_endpoint_descriptors: HashMap::new(),
+        })
+        .collect();
+    Some(UsbInterface {
+        interface_class: alt_setting.class(),
+        interface_subclass: alt_setting.subclass(),
+        interface_protocol: alt_setting.protocol(),
+        endpoints: nusb_endpoints(&alt_setting),
+        string_interface: alt_setting.string_index().unwrap_or(0),
+        class_specific_descriptor: Vec::new(),
+        class_specific_endpoint_descriptors: HashMap::new(),
+        alternate_settings,
+        handler: Arc::new(AsyncMutex::new(handler)),
+        interface_number: intf.interface_number(),
+        device_state,
+        unavailable_reason: None,
+    })
+}
+
+fn nusb_endpoints(setting: &nusb::descriptors::InterfaceAltSetting) -> Vec<UsbEndpoint> {
+    setting
+        .endpoints()
+        .map(|ep_desc| UsbEndpoint {
+            address: ep_desc.address(),
+            attributes: ep_desc.transfer_type() as u8,
+            max_packet_size: ep_desc.max_packet_size() as u16,
+            interval: ep_desc.interval(),
+        })
+        .collect()
+}
+
+/// bcdUSB and the max packet size of EP0 in bytes, from the device descriptor
+fn read_device_descriptor(dev: &nusb::Device, speed: UsbSpeed) -> Option<(u16, u16)> {
+    let desc = dev
+        .get_descriptor(DescriptorType::Device as u8, 0, 0, Duration::from_secs(1))
+        .ok()?;
+    device_descriptor_fields(&desc, speed)
+}
+
+/// bcdUSB and the EP0 max packet size of a raw device descriptor
+fn device_descriptor_fields(desc: &[u8], speed: UsbSpeed) -> Option<(u16, u16)> {
+    if desc.len() < 8 {
+        return None;
+    }
+    let usb_version = u16::from_le_bytes([desc[2], desc[3]]);
+    // SuperSpeed devices report bMaxPacketSize0 as an exponent of two
+    let max_packet_size = if matches!(speed, UsbSpeed::Super | UsbSpeed::SuperPlus) {
+        1u16.checked_shl(desc[7] as u32)?
+    } else {
+        desc[7] as u16
+    };
+    Some((usb_version, max_packet_size))
+}
+
+/// Hub ports leading to the device
+fn port_chain(device_info: &nusb::DeviceInfo) -> Vec<u8> {
+    // the sysfs directory is named after the kernel bus id, e.g. `1-2.3`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(ports) = device_info
+        .sysfs_path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split_once('-'))
+        .and_then(|(_, ports)| {
+            ports
+                .split('.')
+                .map(|port| port.parse().ok())
+                .collect::<Option<Vec<u8>>>()
+        })
+    {
+        return ports;
+    }
+    // the address is at least unique on the bus
+    vec![device_info.device_address()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::usbip_protocol::UsbIpCommand;
+    use crate::util::tests::*;
+    use crate::{UnavailableInterfaceHandler, UsbIpShard};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// A configuration with an audio streaming interface 2 of two alternate
+    /// settings and a HID interface 3
+    const CONFIGURATION: [u8; 50] = [
+        0x09, 0x02, 50, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+        0x09, 0x04, 0x02, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, // interface 2
+        0x09, 0x04, 0x02, 0x01, 0x01, 0x01, 0x02, 0x00, 0x05, // alternate setting 1
+        0x07, 0x05, 0x81, 0x05, 0xC0, 0x00, 0x01, // isochronous IN
+        0x09, 0x04, 0x03, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, // interface 3
+        0x07, 0x05, 0x82, 0x03, 0x08, 0x00, 0x0A, // interrupt IN
+    ];
+
+    fn handler() -> Box<dyn UsbInterfaceHandler + Send> {
+        Box::new(UnavailableInterfaceHandler::new("test"))
+    }
+
+    #[test]
+    fn device_descriptor_ep0_size() {
+        setup_test_logger();
+        let mut desc = [18, 1, 0x00, 0x02, 0, 0, 0, 64];
+        assert_eq!(
+            device_descriptor_fields(&desc, UsbSpeed::Full),
+            Some((0x0200, 64))
+        );
+
+        desc[2..4].copy_from_slice(&[0x20, 0x03]);
+        desc[7] = 9;
+        assert_eq!(
+            device_descriptor_fields(&desc, UsbSpeed::Super),
+            Some((0x0320, 512))
+        );
+        assert_eq!(
+            device_descriptor_fields(&desc, UsbSpeed::Super).map(|(_, size)| size),
+            Some(UsbSpeed::Super.default_ep0_max_packet_size())
+        );
+
+        desc[7] = 16;
+        assert_eq!(device_descriptor_fields(&desc, UsbSpeed::Super), None);
+        assert_eq!(device_descriptor_fields(&desc[..7], UsbSpeed::Full), None);
+    }
+
+    #[test]
+    fn interfaces_from_descriptors() {
+        setup_test_logger();
+        let cfg = nusb::descriptors::Configuration::new(&CONFIGURATION);
+        let state = Arc::new(Mutex::new(UsbDeviceState::configured(1)));
+        let interfaces: Vec<_> = cfg
+            .interfaces()
+            .filter_map(|intf| nusb_interface(&intf, handler(), state.clone()))
+            .collect();
+        assert_eq!(interfaces.len(), 2);
+
+        let audio = &interfaces[0];
+        assert_eq!(audio.interface_number(), 2);
+        assert_eq!(
+            (audio.interface_class, audio.interface_subclass),
+            (0x01, 0x02)
+        );
+        assert!(audio.endpoints.is_empty());
+        assert_eq!(audio.alternate_settings.len(), 1);
+        let iso = audio.alternate_settings[0].endpoints[0];
+        assert_eq!(
+            (
+                iso.address,
+                iso.attributes,
+                iso.max_packet_size,
+                iso.interval
+            ),
+            (0x81, EndpointAttributes::Isochronous as u8, 192, 1)
+        );
+
+        let hid = &interfaces[1];
+        assert_eq!(hid.interface_number(), 3);
+        assert_eq!(hid.interface_class, 0x03);
+        assert_eq!(hid.endpoints.len(), 1);
+        let interrupt = hid.endpoints[0];
+        assert_eq!(
+            (
+                interrupt.address,
+                interrupt.attributes,
+                interrupt.max_packet_size,
+                interrupt.interval
+            ),
+            (0x82, EndpointAttributes::Interrupt as u8, 8, 10)
+        );
+        assert!(hid.alternate_settings.is_empty());
+    }
+
+    #[test]
+    fn export_matching_devices_once() {
+        setup_test_logger();
+        let mut asked = vec![];
+        let mut filter = |vid: &u16| {
+            asked.push(*vid);
+            *vid == 0x1234
+        };
+        let mut exported = HashMap::new();
+        assert!(!exports_host_device(&exported, &1, &0x5678, &mut filter));
+        assert!(exports_host_device(&exported, &2, &0x1234, &mut filter));
+        exported.insert(2, "1-2".to_string());
+        // plugged in again before its removal was seen
+        assert!(!exports_host_device(&exported, &2, &0x1234, &mut filter));
+        assert_eq!(asked, [0x5678, 0x1234]);
+    }
+
+    #[tokio::test]
+    async fn unplug_ends_session() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_location(1, &[2]);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let (mut client, mut socket) = tokio::io::duplex(0x1000);
+        let session = tokio::spawn({
+            let server = server.clone();
+            async move {
+                crate::usbip_server::server::shard_handler(&mut socket, server, &UsbIpShard::All)
+                    .await
+            }
+        });
+
+        let mut busid = [0; 32];
+        busid[..3].copy_from_slice(b"1-2");
+        let import = UsbIpCommand::OpReqImport { status: 0, busid };
+        client.write_all(&import.to_bytes()).await.unwrap();
+        let mut reply = vec![0; 0x140];
+        client.read_exact(&mut reply).await.unwrap();
+
+        server.unplug_device("1-2").await;
+        assert!(session.await.unwrap().is_ok());
+        assert!(server.available_devices().await.is_empty());
+        assert!(server.used_devices.read().await.is_empty());
+    }
+}
@@ -1,18 +1,28 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 use log::*;
-use rusb::{Device, DeviceHandle, GlobalContext};
-use tokio::sync::RwLock;
+use rusb::{
+    ConfigDescriptor, Device, DeviceHandle, GlobalContext, Hotplug, HotplugBuilder, UsbContext,
+};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+use tokio::sync::{Mutex as AsyncMutex, RwLock};
 
+use super::exports_host_device;
 use crate::{
-    EndpointAttributes, RusbUsbHostDeviceHandler, RusbUsbHostInterfaceHandler, UsbDevice,
-    UsbEndpoint, UsbInterface, UsbInterfaceHandler, UsbIpServer,
+    EndpointAttributes, RusbUsbHostDeviceHandler, RusbUsbHostInterfaceHandler,
+    UnavailableInterfaceHandler, UsbAlternateSetting, UsbDevice, UsbDeviceState, UsbEndpoint,
+    UsbInterface, UsbInterfaceHandler, UsbIpServer, UsbPathFormat, UsbSpeed,
 };
 
 impl UsbIpServer {
     /// Create a [UsbIpServer] with Vec<[rusb::DeviceHandle]> for sharing host devices
-    pub fn with_rusb_device_handles(
-        device_handles: Vec<DeviceHandle<GlobalContext>>,
+    ///
+    /// The handles may belong to any libusb context, e.g. a [rusb::Context]
+    /// whose events and hotplug callbacks you handle yourself.
+    pub fn with_rusb_device_handles<T: UsbContext + 'static>(
+        device_handles: Vec<DeviceHandle<T>>,
     ) -> Vec<UsbDevice> {
         let mut devices = vec![];
         for open_device in device_handles {
@@ -37,61 +47,19 @@ impl UsbIpServer {
             };
 
             let handle = Arc::new(Mutex::new(open_device));
-            let mut interfaces = vec![];
+            let state = Arc::new(Mutex::new(UsbDeviceState::configured(cfg.number())));
             handle
                 .lock()
                 .unwrap()
                 .set_auto_detach_kernel_driver(true)
                 .ok();
-            for intf in cfg.interfaces() {
-                // ignore alternate settings
-                let intf_desc = intf.descriptors().next().unwrap();
-                handle
-                    .lock()
-                    .unwrap()
-                    .set_auto_detach_kernel_driver(true)
-                    .ok();
-                let mut endpoints = vec![];
-
-                for ep_desc in intf_desc.endpoint_descriptors() {
-                    endpoints.push(UsbEndpoint {
-                        address: ep_desc.address(),
-                        attributes: ep_desc.transfer_type() as u8,
-                        max_packet_size: ep_desc.max_packet_size(),
-                        interval: ep_desc.interval(),
-                    });
-                }
-
-                let handler = Arc::new(Mutex::new(Box::new(RusbUsbHostInterfaceHandler::new(
-                    handle.clone(),
-                ))
-                    as Box<dyn UsbInterfaceHandler + Send>));
-                interfaces.push(UsbInterface {
-                    interface_class: intf_desc.class_code(),
-                    interface_subclass: intf_desc.sub_class_code(),
-                    interface_protocol: intf_desc.protocol_code(),
-                    endpoints,
-                    string_interface: intf_desc.description_string_index().unwrap_or(0),
-                    class_specific_descriptor: Vec::from(intf_desc.extra()),
-                    handler,
-                });
-            }
+            let interfaces = rusb_interfaces(&handle, &cfg, &state);
             let mut device = UsbDevice {
-                path: format!(
-                    "/sys/bus/{}/{}/{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
-                bus_id: format!(
-                    "{}-{}-{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
                 bus_num: dev.bus_number() as u32,
+                // fall back to the address, which is at least unique on the bus
+                ports: dev.port_numbers().unwrap_or_else(|_| vec![dev.address()]),
                 dev_num: dev.port_number() as u32,
-                speed: dev.speed() as u32,
+                speed: UsbSpeed::from(dev.speed()) as u32,
                 vendor_id: desc.vendor_id(),
                 product_id: desc.product_id(),
                 device_class: desc.class_code(),
@@ -100,6 +68,9 @@ impl UsbIpServer {
                 device_bcd: desc.device_version().into(),
                 configuration_value: cfg.number(),
                 num_configurations: desc.num_configurations(),
+                self_powered: cfg.self_powered(),
+                remote_wakeup: cfg.remote_wakeup(),
+                max_power: (cfg.max_power() / 2) as u8,
                 ep0_in: UsbEndpoint {
                     address: 0x80,
                     attributes: EndpointAttributes::Control as u8,
@@ -113,12 +84,14 @@ impl UsbIpServer {
                     interval: 0,
                 },
                 interfaces,
-                device_handler: Some(Arc::new(Mutex::new(Box::new(
+                state,
+                device_handler: Some(Arc::new(AsyncMutex::new(Box::new(
                     RusbUsbHostDeviceHandler::new(handle.clone()),
                 )))),
                 usb_version: desc.usb_version().into(),
                 ..UsbDevice::default()
-            };
+            }
+            .with_path_format(&UsbPathFormat::Kernel);
 
             // set strings
             if let Some(index) = desc.manufacturer_string_index() {
@@ -153,7 +126,9 @@ impl UsbIpServer {
         devices
     }
 
-    fn with_rusb_devices(device_list: Vec<Device<GlobalContext>>) -> Vec<UsbDevice> {
+    pub(crate) fn with_rusb_devices<T: UsbContext + 'static>(
+        device_list: Vec<Device<T>>,
+    ) -> Vec<UsbDevice> {
         let mut device_handles = vec![];
 
         for dev in device_list {
@@ -179,7 +154,16 @@ impl UsbIpServer {
     where
         F: FnMut(&Device<GlobalContext>) -> bool,
     {
-        match rusb::devices() {
+        Self::new_from_rusb_context(&GlobalContext::default(), filter)
+    }
+
+    /// Like [UsbIpServer::new_from_host_with_filter], with the devices of a libusb `context`
+    pub fn new_from_rusb_context<T, F>(context: &T, filter: F) -> Self
+    where
+        T: UsbContext + 'static,
+        F: FnMut(&Device<T>) -> bool,
+    {
+        match context.devices() {
             Ok(list) => {
                 let mut devs = vec![];
                 for d in list.iter().filter(filter) {
@@ -194,3 +178,190 @@ impl UsbIpServer {
         }
     }
 }
+
+/// The interfaces of `cfg`, claimed from `handle`
+///
+/// Interfaces held by another driver are described, but unavailable.
+pub(crate) fn rusb_interfaces<T: UsbContext + 'static>(
+    handle: &Arc<Mutex<DeviceHandle<T>>>,
+    cfg: &ConfigDescriptor,
+    state: &Arc<Mutex<UsbDeviceState>>,
+) -> Vec<UsbInterface> {
+    let mut interfaces = vec![];
+    for intf in cfg.interfaces() {
+        let mut settings = intf.descriptors();
+        let intf_desc = settings.next().unwrap();
+        let mut endpoints = vec![];
+        let mut class_specific_endpoint_descriptors = HashMap::new();
+
+        for ep_desc in intf_desc.endpoint_descriptors() {
+            if let Some(extra) = ep_desc.extra() {
+                class_specific_endpoint_descriptors.insert(ep_desc.address(), extra.to_vec());
+            }
+            endpoints.push(UsbEndpoint {
+                address: ep_desc.address(),
+                attributes: ep_desc.transfer_type() as u8,
+                max_packet_size: ep_desc.max_packet_size(),
+                interval: ep_desc.interval(),
+            });
+        }
+
+        // many audio and video devices only stream in alternate settings
+        let mut alternate_settings = vec![];
+        for setting in settings {
+            let mut endpoints = vec![];
+            let mut class_specific_endpoint_descriptors = HashMap::new();
+            for ep_desc in setting.endpoint_descriptors() {
+                if let Some(extra) = ep_desc.extra() {
+                    class_specific_endpoint_descriptors.insert(ep_desc.address(), extra.to_vec());
+                }
+                endpoints.push(UsbEndpoint {
+                    address: ep_desc.address(),
+                    attributes: ep_desc.transfer_type() as u8,
+                    max_packet_size: ep_desc.max_packet_size(),
+                    interval: ep_desc.interval(),
+                });
+            }
+            alternate_settings.push(UsbAlternateSetting {
+                endpoints,
+                class_specific_descriptor: Vec::from(setting.extra()),
+                class_specific_endpoint_descriptors,
+            });
+        }
+
+        // released before SET_CONFIGURATION, and claimed again after it
+        let intf_num = intf_desc.interface_number();
+        let (handler, unavailable_reason) = match handle.lock().unwrap().claim_interface(intf_num) {
+            Ok(()) => (
+                Box::new(RusbUsbHostInterfaceHandler::new(handle.clone()))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+                None,
+            ),
+            Err(err) => {
+                warn!(
+                    "Impossible to claim interface {intf_num}: {err}, exporting it as unavailable"
+                );
+                let reason = format!("Failed to claim interface {intf_num}: {err}");
+                (
+                    Box::new(UnavailableInterfaceHandler::new(&reason))
+                        as Box<dyn UsbInterfaceHandler + Send>,
+                    Some(reason),
+                )
+            }
+        };
+        interfaces.push(UsbInterface {
+            interface_class: intf_desc.class_code(),
+            interface_subclass: intf_desc.sub_class_code(),
+            interface_protocol: intf_desc.protocol_code(),
+            endpoints,
+            string_interface: intf_desc.description_string_index().unwrap_or(0),
+            class_specific_descriptor: Vec::from(intf_desc.extra()),
+            class_specific_endpoint_descriptors,
+            alternate_settings,
+            handler: Arc::new(AsyncMutex::new(handler)),
+            interface_number: intf_num,
+            device_state: state.clone(),
+            unavailable_reason,
+        });
+    }
+    interfaces
+}
+
+/// Arrivals and removals reported by libusb
+enum RusbHotplugEvent<T: UsbContext> {
+    Arrived(Device<T>),
+    Left(Device<T>),
+}
+
+/// Forwards the hotplug callbacks, which must not open devices themselves
+struct RusbHotplug<T: UsbContext>(UnboundedSender<RusbHotplugEvent<T>>);
+
+impl<T: UsbContext> Hotplug<T> for RusbHotplug<T> {
+    fn device_arrived(&mut self, device: Device<T>) {
+        let _ = self.0.send(RusbHotplugEvent::Arrived(device));
+    }
+
+    fn device_left(&mut self, device: Device<T>) {
+        let _ = self.0.send(RusbHotplugEvent::Left(device));
+    }
+}
+
+impl UsbIpServer {
+    /// Keep the exported host devices in sync with libusb hotplug events
+    ///
+    /// Like [UsbIpServer::watch_host_devices], but with libusb: exports the
+    /// devices matching `filter` that are present or plugged in later, and
+    /// removes unplugged ones after ending the session of their client.
+    /// libusb does not support hotplug on every platform, e.g. Windows, see
+    /// [rusb::has_hotplug]. The events are handled on a thread of their own
+    /// until this future completes or is dropped.
+    pub async fn watch_rusb_devices<F>(&self, filter: F) -> std::io::Result<()>
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool,
+    {
+        self.watch_rusb_context(GlobalContext::default(), filter)
+            .await
+    }
+
+    /// Like [UsbIpServer::watch_rusb_devices], with the devices of a libusb `context`
+    ///
+    /// The events of `context` are handled on the thread of the watch, so
+    /// do not handle them elsewhere meanwhile.
+    pub async fn watch_rusb_context<T, F>(&self, context: T, mut filter: F) -> std::io::Result<()>
+    where
+        T: UsbContext + 'static,
+        F: FnMut(&Device<T>) -> bool,
+    {
+        if !rusb::has_hotplug() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "libusb does not support hotplug on this platform",
+            ));
+        }
+        let (sender, mut receiver) = unbounded_channel();
+        let registration = HotplugBuilder::new()
+            .enumerate(true)
+            .register(context.clone(), Box::new(RusbHotplug(sender.clone())))
+            .map_err(std::io::Error::other)?;
+        std::thread::spawn(move || {
+            let _registration = registration;
+            // stop once the watch is gone
+            while !sender.is_closed() {
+                if let Err(err) = context.handle_events(Some(Duration::from_millis(500))) {
+                    warn!("Failed to handle libusb events: {err}");
+                    break;
+                }
+            }
+        });
+
+        // bus id of the exported devices, by bus number and address
+        let mut exported: HashMap<(u8, u8), String> = HashMap::new();
+        while let Some(event) = receiver.recv().await {
+            match event {
+                RusbHotplugEvent::Arrived(device) => {
+                    let key = (device.bus_number(), device.address());
+                    if !exports_host_device(&exported, &key, &device, &mut filter) {
+                        continue;
+                    }
+                    // opening the device and reading its descriptors blocks
+                    let devices =
+                        tokio::task::spawn_blocking(move || Self::with_rusb_devices(vec![device]))
+                            .await
+                            .map_err(std::io::Error::other)?;
+                    for device in devices {
+                        info!("Host device {} plugged in", device.bus_id);
+                        exported.insert(key, device.bus_id.clone());
+                        self.add_device(device).await;
+                    }
+                }
+                RusbHotplugEvent::Left(device) => {
+                    let key = (device.bus_number(), device.address());
+                    if let Some(bus_id) = exported.remove(&key) {
+                        self.unplug_device(&bus_id).await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
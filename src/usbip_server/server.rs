@@ -1,34 +1,116 @@
 use std::{net::SocketAddr, sync::Arc};
 
+use super::UsbIpSession;
+use super::stats::UrbCounters;
+use crate::pcap::CapturedUrb;
 use crate::{
-    SetupPacket, UsbIpServer,
-    usbip_protocol::{USBIP_RET_SUBMIT, USBIP_RET_UNLINK, UsbIpCommand, UsbIpResponse},
+    SetupPacket, StandardRequest, Urb, UsbDevice, UsbInterface, UsbIpError, UsbIpEvent,
+    UsbIpServer, UsbIpShard, UsbmonCapture, recycle_scratch_buffer,
+    usbip_protocol::{
+        Parsed, USBIP_RET_UNLINK, UsbIpCommand, UsbIpDeviceInfo, UsbIpHeaderBasic, UsbIpResponse,
+    },
 };
 use log::*;
-use std::io::{ErrorKind, Result};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpListener,
 };
 
 pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+) -> Result<(), UsbIpError> {
+    shard_handler(socket, server, &UsbIpShard::All).await
+}
+
+/// Like [handler], but only list and import the devices of `shard`
+pub async fn shard_handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    shard: &UsbIpShard,
+) -> Result<(), UsbIpError> {
+    session(socket, server, shard, None, SessionOptions::default()).await
+}
+
+/// Like [shard_handler], for a connection from `peer`
+///
+/// The address is passed to the authenticator, see [UsbIpServer::with_authenticator].
+pub async fn peer_handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    shard: &UsbIpShard,
+    peer: SocketAddr,
+) -> Result<(), UsbIpError> {
+    session(socket, server, shard, Some(peer), SessionOptions::default()).await
+}
+
+/// Settings of a connection which are not part of the [UsbIpServer]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SessionOptions {
+    /// Fail URBs which take longer
+    pub(crate) urb_timeout: Option<Duration>,
+    /// Capture the URBs of the connection
+    pub(crate) capture: Option<Arc<UsbmonCapture>>,
+    /// Of the client, see [UsbIpServer::with_identity_policy]
+    pub(crate) identities: Vec<String>,
+}
+
+pub(crate) async fn session<T: AsyncReadExt + AsyncWriteExt + Unpin>(
     mut socket: &mut T,
     server: Arc<UsbIpServer>,
-) -> Result<()> {
+    shard: &UsbIpShard,
+    peer: Option<SocketAddr>,
+    options: SessionOptions,
+) -> Result<(), UsbIpError> {
+    let mut authenticated = false;
     let mut current_import_device_id: Option<String> = None;
+    let mut enabled_extensions: Vec<u32> = vec![];
+    // failed URBs of the imported device within the error budget window
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    // of the imported device
+    let mut import_session: Option<Arc<UsbIpSession>> = None;
+    let connection_stats = server.stats.connection(peer);
+    let mut device_stats: Option<Arc<UrbCounters>> = None;
+    let mut reader = CommandReader::default();
+    // read while an URB was in flight
+    let mut next_command = None;
     loop {
-        let command = UsbIpCommand::read_from_socket(&mut socket).await;
+        let command = match next_command.take() {
+            Some(command) => command,
+            None => {
+                let read = async {
+                    match server.idle_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, reader.read(&mut socket))
+                            .await
+                            .unwrap_or_else(|_| {
+                                Err(std::io::Error::new(ErrorKind::TimedOut, "Idle timeout").into())
+                            }),
+                        None => reader.read(&mut socket).await,
+                    }
+                };
+                match &import_session {
+                    Some(import_session) => tokio::select! {
+                        biased;
+                        _ = import_session.detach.notified() => {
+                            server.release_device(current_import_device_id.as_deref().unwrap()).await;
+                            info!("Device detached by the server");
+                            return Ok(());
+                        }
+                        command = read => command,
+                    },
+                    None => read.await,
+                }
+            }
+        };
         if let Err(err) = command {
             if let Some(dev_id) = current_import_device_id {
-                let mut used_devices = server.used_devices.write().await;
-                let mut available_devices = server.available_devices.write().await;
-                match used_devices.remove(&dev_id) {
-                    Some(dev) => available_devices.push(dev),
-                    None => unreachable!(),
-                }
+                server.release_device(&dev_id).await;
             }
 
-            if err.kind() == ErrorKind::UnexpectedEof {
+            if err.is_disconnect() {
                 info!("Remote closed the connection");
                 return Ok(());
             } else {
@@ -36,114 +118,370 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
             }
         }
 
-        let used_devices = server.used_devices.read().await;
-        let mut current_import_device = current_import_device_id
-            .clone()
-            .and_then(|ref id| used_devices.get(id));
+        // not borrowed from the map, whose lock would be held while URBs are in flight
+        let mut current_import_device = match &current_import_device_id {
+            Some(id) => server.used_devices.read().await.get(id).cloned(),
+            None => None,
+        };
 
         match command.unwrap() {
             UsbIpCommand::OpReqDevlist { .. } => {
                 trace!("Got OP_REQ_DEVLIST");
+                if !authenticated {
+                    authenticated = server.authenticate(peer);
+                    if !authenticated {
+                        UsbIpResponse::op_rep_devlist_fail()
+                            .write_to_socket(socket)
+                            .await?;
+                        return Err(UsbIpError::Unauthorized(peer));
+                    }
+                }
                 let devices = server.available_devices.read().await;
 
                 // OP_REP_DEVLIST
-                UsbIpResponse::op_rep_devlist(&devices)
-                    .write_to_socket(socket)
-                    .await?;
+                let devices: Vec<_> = devices
+                    .iter()
+                    .filter(|dev| {
+                        shard.contains(dev) && server.can_access(peer, &options.identities, dev)
+                    })
+                    .map(UsbIpDeviceInfo::from)
+                    .collect();
+                let res = UsbIpResponse::OpRepDevlist {
+                    status: 0,
+                    device_count: devices.len() as u32,
+                    devices,
+                };
+                res.write_to_socket(socket).await?;
                 trace!("Sent OP_REP_DEVLIST");
             }
             UsbIpCommand::OpReqImport { busid, .. } => {
                 trace!("Got OP_REQ_IMPORT");
+                if !authenticated {
+                    authenticated = server.authenticate(peer);
+                    if !authenticated {
+                        UsbIpResponse::op_rep_import_fail()
+                            .write_to_socket(socket)
+                            .await?;
+                        return Err(UsbIpError::Unauthorized(peer));
+                    }
+                }
 
                 current_import_device_id = None;
                 current_import_device = None;
-                std::mem::drop(used_devices);
+                import_session = None;
+                device_stats = None;
+                failures.clear();
 
                 let mut used_devices = server.used_devices.write().await;
                 let mut available_devices = server.available_devices.write().await;
                 let busid_compare =
                     &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
                 for (i, dev) in available_devices.iter().enumerate() {
-                    if busid_compare == dev.bus_id.as_bytes() {
+                    if busid_compare == dev.bus_id.as_bytes()
+                        && shard.contains(dev)
+                        && server.can_access(peer, &options.identities, dev)
+                    {
                         let dev = available_devices.remove(i);
+                        if server.device_reset {
+                            reset_device(&dev).await;
+                        } else {
+                            dev.reset_state();
+                        }
+                        dev.resume().await;
                         let dev_id = dev.bus_id.clone();
-                        used_devices.insert(dev.bus_id.clone(), dev);
+                        let dev = Arc::new(dev);
+                        used_devices.insert(dev_id.clone(), dev.clone());
+                        let new_session = Arc::new(UsbIpSession::new(peer));
+                        server
+                            .sessions
+                            .lock()
+                            .unwrap()
+                            .insert(dev_id.clone(), new_session.clone());
+                        import_session = Some(new_session);
+                        device_stats = Some(server.stats.device(&dev_id));
                         current_import_device_id = dev_id.clone().into();
-                        current_import_device = Some(used_devices.get(&dev_id).unwrap());
+                        current_import_device = Some(dev);
                         break;
                     }
                 }
 
-                let res = if let Some(dev) = current_import_device {
+                let res = if let Some(dev) = &current_import_device {
+                    server.emit(UsbIpEvent::DeviceImported {
+                        bus_id: dev.bus_id.clone(),
+                        peer,
+                    });
                     UsbIpResponse::op_rep_import_success(dev)
                 } else {
                     UsbIpResponse::op_rep_import_fail()
                 };
+                std::mem::drop(available_devices);
+                std::mem::drop(used_devices);
+                debug_delay(server.debug_delays.import).await;
                 res.write_to_socket(socket).await?;
                 trace!("Sent OP_REP_IMPORT");
             }
+            UsbIpCommand::OpReqExtensions { extensions, .. } => {
+                trace!("Got OP_REQ_EXTENSIONS for {extensions:x?}");
+                let res = UsbIpResponse::op_rep_extensions(&extensions, server.extensions());
+                if let UsbIpResponse::OpRepExtensions { extensions, .. } = &res {
+                    enabled_extensions.clone_from(extensions);
+                }
+                res.write_to_socket(socket).await?;
+                debug!("Enabled extensions {enabled_extensions:x?}");
+            }
             UsbIpCommand::UsbIpCmdSubmit {
-                mut header,
+                header,
+                transfer_flags,
                 transfer_buffer_length,
+                start_frame,
+                number_of_packets,
+                interval,
                 setup,
                 data,
-                ..
+                iso_packet_descriptor,
             } => {
-                trace!("Got USBIP_CMD_SUBMIT");
-                let device = current_import_device.unwrap();
+                let Some(device) = current_import_device.as_deref() else {
+                    // never imported, or detached right after the command was read
+                    warn!("Got USBIP_CMD_SUBMIT without an imported device");
+                    recycle_scratch_buffer(data);
+                    return Err(UsbIpError::DeviceNotFound(
+                        current_import_device_id.unwrap_or_default(),
+                    ));
+                };
+                trace!(target: &device.log_target(), "Got USBIP_CMD_SUBMIT");
+                let counters = import_session.as_deref().unwrap();
+                UsbIpSession::count(&counters.submitted_urbs);
+                let record = |ok, bytes_in, bytes_out, latency| {
+                    for stats in [&connection_stats.counters, device_stats.as_ref().unwrap()] {
+                        stats.record(ok, bytes_in, bytes_out, latency);
+                    }
+                };
 
-                let out = header.direction == 0;
+                let mut budget_exceeded = None;
+                // by the server while the URB was in flight
+                let mut detached = false;
+                // anything but IN is parsed as OUT
+                let out = header.direction != 1;
                 let real_ep = if out { header.ep } else { header.ep | 0x80 };
+                let found = device.find_ep(real_ep as u8);
 
-                header.command = USBIP_RET_SUBMIT.into();
+                let captures: Vec<_> = options
+                    .capture
+                    .iter()
+                    .cloned()
+                    .chain(server.device_capture(&device.bus_id))
+                    .collect();
+                let captured = CapturedUrb {
+                    seqnum: header.seqnum,
+                    endpoint: real_ep as u8,
+                    attributes: match found {
+                        Some((ep, _)) => ep.attributes,
+                        // unknown endpoints are captured as bulk
+                        None => 0x02,
+                    },
+                    bus_num: device.bus_num,
+                    dev_num: device.dev_num,
+                    setup,
+                    transfer_flags,
+                    transfer_buffer_length,
+                    interval,
+                    start_frame,
+                };
+                for capture in &captures {
+                    capture.submit(&captured, &data);
+                }
 
-                let res = match device.find_ep(real_ep as u8) {
+                let res = match found {
                     None => {
-                        warn!("Endpoint {real_ep:02x?} not found");
+                        warn!(target: &device.log_target(), "Endpoint {real_ep:02x?} not found");
+                        recycle_scratch_buffer(data);
+                        UsbIpSession::count(&counters.failed_urbs);
+                        record(false, 0, 0, None);
                         UsbIpResponse::usbip_ret_submit_fail(&header)
                     }
                     Some((ep, intf)) => {
-                        trace!("->Endpoint {ep:02x?}");
-                        trace!("->Setup {setup:02x?}");
-                        trace!("->Request {data:02x?}");
-                        let resp = device
-                            .handle_urb(
-                                ep,
-                                intf,
-                                transfer_buffer_length,
-                                SetupPacket::parse(&setup),
-                                &data,
-                            )
-                            .await;
-
-                        match resp {
-                            Ok(resp) => {
-                                if out {
-                                    trace!("<-Wrote {}", data.len());
-                                } else {
-                                    trace!("<-Resp {resp:02x?}");
+                        trace!(target: &device.log_target(), "->Endpoint {ep:02x?}");
+                        trace!(target: &device.log_target(), "->Setup {setup:02x?}");
+                        trace!(target: &device.log_target(), "->Request {data:02x?}");
+                        let mut urb = Urb {
+                            seqnum: header.seqnum,
+                            endpoint: ep,
+                            transfer_flags,
+                            transfer_buffer_length,
+                            setup: SetupPacket::parse(&setup),
+                            buffer: data,
+                            start_frame,
+                            number_of_packets,
+                            interval,
+                            iso_packet_descriptor,
+                            ..Default::default()
+                        };
+                        let out_len = if out { urb.buffer.len() as u64 } else { 0 };
+                        let start = Instant::now();
+                        let mut cancelled = None;
+                        let detach = &counters.detach;
+                        let res = {
+                            let submit = async {
+                                match options.urb_timeout {
+                                    Some(timeout) => tokio::time::timeout(
+                                        timeout,
+                                        submit_urb(&server, device, intf, &mut urb),
+                                    )
+                                    .await
+                                    .unwrap_or_else(|_| {
+                                        Err(std::io::Error::new(
+                                            ErrorKind::TimedOut,
+                                            "URB timed out",
+                                        ))
+                                    }),
+                                    None => submit_urb(&server, device, intf, &mut urb).await,
+                                }
+                            };
+                            tokio::pin!(submit);
+                            // the client may unlink the URB while it is in flight,
+                            // and the server may detach the device
+                            let raced = tokio::select! {
+                                biased;
+                                res = &mut submit => Ok(res),
+                                _ = detach.notified() => {
+                                    detached = true;
+                                    Ok(Err(std::io::Error::from(ErrorKind::ConnectionAborted)))
+                                }
+                                command = reader.read(&mut socket) => Err(command),
+                            };
+                            match raced {
+                                Ok(res) => res,
+                                Err(Ok(UsbIpCommand::UsbIpCmdUnlink {
+                                    header: unlink,
+                                    unlink_seqnum,
+                                })) if unlink_seqnum == header.seqnum => {
+                                    cancelled = Some(unlink);
+                                    Err(std::io::Error::new(
+                                        ErrorKind::ConnectionReset,
+                                        "URB unlinked",
+                                    ))
+                                }
+                                Err(command) => {
+                                    // handled once the URB completed
+                                    next_command = Some(command);
+                                    tokio::select! {
+                                        biased;
+                                        res = submit => res,
+                                        _ = detach.notified() => {
+                                            detached = true;
+                                            Err(std::io::Error::from(ErrorKind::ConnectionAborted))
+                                        }
+                                    }
                                 }
-                                UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, resp, vec![])
                             }
-                            Err(err) => {
-                                warn!("Error handling URB: {err}");
+                        };
+                        match cancelled {
+                            Some(unlink) => {
+                                // the URB gets no USBIP_RET_SUBMIT, only the USBIP_RET_UNLINK
+                                trace!(target: &device.log_target(), "<-Unlinked in flight");
+                                recycle_scratch_buffer(urb.buffer);
+                                UsbIpSession::count(&counters.unlinked_urbs);
+                                UsbIpResponse::usbip_ret_unlink_cancelled(&UsbIpHeaderBasic::reply(
+                                    USBIP_RET_UNLINK,
+                                    &unlink,
+                                ))
+                            }
+                            None if detached => {
+                                // cancelled, the session ends once the client knows
+                                trace!(target: &device.log_target(), "<-Detached in flight");
+                                recycle_scratch_buffer(urb.buffer);
+                                UsbIpSession::count(&counters.failed_urbs);
+                                record(false, 0, 0, None);
                                 UsbIpResponse::usbip_ret_submit_fail(&header)
                             }
+                            None => {
+                                if let Some(shaping) = device.shaping {
+                                    let bytes = match res {
+                                        Ok(()) if out => urb.buffer.len() as u64,
+                                        Ok(()) => urb.actual_length as u64,
+                                        Err(_) => 0,
+                                    };
+                                    let elapsed = start.elapsed();
+                                    let duration = shaping.duration(bytes);
+                                    if duration > elapsed {
+                                        tokio::time::sleep(duration - elapsed).await;
+                                    }
+                                }
+                                let latency = Some(start.elapsed());
+                                match res {
+                                    Ok(()) => {
+                                        trace!(target: &device.log_target(), "<-Completed {} bytes", urb.actual_length);
+                                        let in_len = if out { 0 } else { urb.actual_length as u64 };
+                                        record(true, in_len, out_len, latency);
+                                        urb.into_ret_submit(&header)
+                                    }
+                                    Err(err) => {
+                                        warn!(target: &device.log_target(), "Error handling URB: {err}");
+                                        server.emit(UsbIpEvent::TransferError {
+                                            bus_id: device.bus_id.clone(),
+                                            endpoint: ep.address,
+                                            error: err.to_string(),
+                                        });
+                                        recycle_scratch_buffer(urb.buffer);
+                                        UsbIpSession::count(&counters.failed_urbs);
+                                        record(false, 0, 0, latency);
+                                        if let Some(budget) = server.error_budget {
+                                            let now = Instant::now();
+                                            failures.push_back(now);
+                                            while failures
+                                                .front()
+                                                .is_some_and(|&time| now - time > budget.window)
+                                            {
+                                                failures.pop_front();
+                                            }
+                                            if failures.len() > budget.max_failures as usize {
+                                                budget_exceeded = Some(err);
+                                            }
+                                        }
+                                        UsbIpResponse::usbip_ret_submit_fail(&header)
+                                    }
+                                }
+                            }
                         }
                     }
                 };
+                for capture in &captures {
+                    capture.complete(&captured, &res);
+                }
+                debug_delay(server.debug_delays.submit).await;
                 res.write_to_socket(socket).await?;
-                trace!("Sent USBIP_RET_SUBMIT");
+                res.recycle();
+                trace!(target: &device.log_target(), "Sent the reply to USBIP_CMD_SUBMIT");
+
+                if detached {
+                    server.release_device(&device.bus_id).await;
+                    info!("Device detached by the server");
+                    return Ok(());
+                }
+
+                if let Some(err) = budget_exceeded {
+                    server
+                        .take_offline(&current_import_device_id.unwrap())
+                        .await;
+                    return Err(UsbIpError::Transfer(err));
+                }
             }
             UsbIpCommand::UsbIpCmdUnlink {
-                mut header,
+                header,
                 unlink_seqnum,
             } => {
                 trace!("Got USBIP_CMD_UNLINK for {unlink_seqnum:10x?}");
+                if let Some(import_session) = &import_session {
+                    UsbIpSession::count(&import_session.unlinked_urbs);
+                }
 
-                header.command = USBIP_RET_UNLINK.into();
-
-                let res = UsbIpResponse::usbip_ret_unlink_success(&header);
+                // URBs in flight are cancelled while they are submitted, so
+                // this one has completed and its USBIP_RET_SUBMIT was sent
+                let res = UsbIpResponse::usbip_ret_unlink_success(&UsbIpHeaderBasic::reply(
+                    USBIP_RET_UNLINK,
+                    &header,
+                ));
+                debug_delay(server.debug_delays.unlink).await;
                 res.write_to_socket(socket).await?;
                 trace!("Sent USBIP_RET_UNLINK");
             }
@@ -151,18 +489,126 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
     }
 }
 
+/// Reads commands without losing data when a read is cancelled
+///
+/// Received bytes stay in the reader, so reading the next command can race
+/// an URB in flight and start over later.
+#[derive(Default)]
+struct CommandReader {
+    buffer: Vec<u8>,
+}
+
+impl CommandReader {
+    async fn read<T: AsyncReadExt + Unpin>(
+        &mut self,
+        socket: &mut T,
+    ) -> Result<UsbIpCommand, UsbIpError> {
+        loop {
+            if !self.buffer.is_empty() {
+                match UsbIpCommand::parse(&self.buffer)? {
+                    Parsed::Complete(command, len) => {
+                        self.buffer.drain(..len);
+                        return Ok(command);
+                    }
+                    Parsed::Incomplete(_) => {}
+                }
+            }
+            // a single read is cancel safe, unlike read_exact
+            if socket.read_buf(&mut self.buffer).await? == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+/// Reset `device`, or only its runtime state if its handler cannot reset
+async fn reset_device(device: &UsbDevice) {
+    if let Err(err) = device.reset().await {
+        if err.kind() != ErrorKind::Unsupported {
+            warn!(target: &device.log_target(), "Failed to reset device {}: {err}", device.bus_id);
+        }
+        device.reset_state();
+    }
+}
+
+/// Whether `urb` is SET_PORT_FEATURE(PORT_RESET), sent by clients to reset the device
+fn is_port_reset(urb: &Urb) -> bool {
+    urb.endpoint.address & 0x7F == 0
+        && urb.setup.request_type == 0b00100011
+        && urb.setup.request == StandardRequest::SetFeature as u8
+        && urb.setup.value == 4
+}
+
+/// Whether `urb` is SET_CONFIGURATION, after which host devices export other interfaces
+fn is_set_configuration(urb: &Urb) -> bool {
+    urb.endpoint.address & 0x7F == 0
+        && urb.setup.request_type == 0b00000000
+        && urb.setup.request == StandardRequest::SetConfiguration as u8
+}
+
+/// Submit `urb` to `device`, resetting it instead if the client resets its port
+///
+/// See [UsbIpServer::with_device_reset]. After SET_CONFIGURATION, the
+/// device is exported with the interfaces of its configuration, see
+/// [UsbDevice::reconfigured].
+async fn submit_urb(
+    server: &UsbIpServer,
+    device: &UsbDevice,
+    intf: Option<&UsbInterface>,
+    urb: &mut Urb,
+) -> std::io::Result<()> {
+    if server.device_reset && is_port_reset(urb) {
+        match device.reset().await {
+            Err(err) if err.kind() == ErrorKind::Unsupported => {}
+            res => return res,
+        }
+    }
+    let res = device.submit_urb(intf, urb).await;
+    // also after failing, as the interfaces were released
+    if is_set_configuration(urb) {
+        match device.reconfigured().await {
+            Ok(Some(device)) => server.reconfigure_device(device).await,
+            Ok(None) => {}
+            Err(err) => {
+                warn!(target: &device.log_target(), "Failed to claim the interfaces again: {err}");
+            }
+        }
+    }
+    res
+}
+
+async fn debug_delay(delay: Duration) {
+    if !delay.is_zero() {
+        trace!("Delaying reply by {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
 /// Spawn a USB/IP server at `addr` using [TcpListener]
+///
+/// See [UsbIpServerBuilder](crate::UsbIpServerBuilder) for more options.
 pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
+    shard_server(addr, server, UsbIpShard::All).await
+}
+
+/// Spawn a USB/IP server at `addr` exporting only the devices of `shard`
+///
+/// Run one per shard to spread devices of a single [UsbIpServer] over
+/// several ports, each with its own accept loop.
+pub async fn shard_server(addr: SocketAddr, server: Arc<UsbIpServer>, shard: UsbIpShard) {
+    let shard = Arc::new(shard);
     let listener = TcpListener::bind(addr).await.expect("bind to addr");
 
     let server = async move {
         loop {
             match listener.accept().await {
-                Ok((mut socket, _addr)) => {
-                    info!("Got connection from {:?}", socket.peer_addr());
+                Ok((mut socket, addr)) => {
+                    info!("Got connection from {addr:?}");
+                    server.configure_socket(&socket);
                     let new_server = server.clone();
+                    let shard = shard.clone();
                     tokio::spawn(async move {
-                        let res = handler(&mut socket, new_server).await;
+                        let res = peer_handler(&mut socket, new_server, &shard, addr).await;
                         info!("Handler ended with {res:?}");
                     });
                 }
@@ -175,3 +621,28 @@ pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
 
     server.await
 }
+
+/// Spawn a USB/IP server at `addr` using [tokio_vsock::VsockListener]
+///
+/// Guests of Firecracker or cloud-hypervisor connect to it through their
+/// vsock device, without a TCP network between host and guest.
+#[cfg(feature = "vsock")]
+pub async fn vsock_server(addr: tokio_vsock::VsockAddr, server: Arc<UsbIpServer>) {
+    let listener = tokio_vsock::VsockListener::bind(addr).expect("bind to addr");
+
+    loop {
+        match listener.accept().await {
+            Ok((mut socket, addr)) => {
+                info!("Got vsock connection from {addr:?}");
+                let new_server = server.clone();
+                tokio::spawn(async move {
+                    let res = handler(&mut socket, new_server).await;
+                    info!("Handler ended with {res:?}");
+                });
+            }
+            Err(err) => {
+                warn!("Got error {err:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! URB statistics of a [UsbIpServer]
+//!
+//! The session handler counts every URB of a device and of the connection
+//! it arrived on. [UsbIpServer::stats] takes a snapshot of all counters.
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::UsbIpServer;
+
+/// Latencies kept to compute percentiles from
+const LATENCY_SAMPLES: usize = 1024;
+
+/// Snapshot of URB counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UrbStats {
+    /// USBIP_CMD_SUBMIT received
+    pub submits: u64,
+    /// URBs which completed successfully
+    pub completions: u64,
+    /// URBs which failed, including those to unknown endpoints
+    pub errors: u64,
+    /// Bytes sent to the client by IN transfers
+    pub bytes_in: u64,
+    /// Bytes received from the client by OUT transfers
+    pub bytes_out: u64,
+    /// Median time spent handling a URB, over the recent URBs
+    pub latency_p50: Duration,
+    /// 99th percentile time spent handling a URB, over the recent URBs
+    pub latency_p99: Duration,
+}
+
+/// Counters of an exported device, kept until it is removed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub bus_id: String,
+    pub urbs: UrbStats,
+}
+
+/// Counters of an open connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Unique among the connections of the server
+    pub id: u64,
+    /// Address of the client, if known
+    pub peer: Option<SocketAddr>,
+    pub urbs: UrbStats,
+}
+
+/// Snapshot of the statistics of a [UsbIpServer], ordered by bus id and connection id
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub devices: Vec<DeviceStats>,
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// Live counters behind [UrbStats]
+#[derive(Debug, Default)]
+pub(crate) struct UrbCounters {
+    submits: AtomicU64,
+    completions: AtomicU64,
+    errors: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    latencies: Mutex<VecDeque<Duration>>,
+}
+
+impl UrbCounters {
+    /// Count a URB which failed or completed after `latency`
+    ///
+    /// `bytes_in` and `bytes_out` are only counted for completed URBs, and
+    /// URBs never handed to a device have no latency.
+    pub(crate) fn record(
+        &self,
+        ok: bool,
+        bytes_in: u64,
+        bytes_out: u64,
+        latency: Option<Duration>,
+    ) {
+        self.submits.fetch_add(1, Ordering::Relaxed);
+        if ok {
+            self.completions.fetch_add(1, Ordering::Relaxed);
+            self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+            self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(latency) = latency {
+            let mut latencies = self.latencies.lock().unwrap();
+            if latencies.len() == LATENCY_SAMPLES {
+                latencies.pop_front();
+            }
+            latencies.push_back(latency);
+        }
+    }
+
+    fn snapshot(&self) -> UrbStats {
+        let mut latencies: Vec<_> = self.latencies.lock().unwrap().iter().copied().collect();
+        latencies.sort_unstable();
+        let percentile = |p: usize| {
+            latencies
+                .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+                .copied()
+                .unwrap_or_default()
+        };
+        UrbStats {
+            submits: self.submits.load(Ordering::Relaxed),
+            completions: self.completions.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            latency_p50: percentile(50),
+            latency_p99: percentile(99),
+        }
+    }
+}
+
+/// Counters of a connection with the address of its client
+type Connection = (Option<SocketAddr>, Arc<UrbCounters>);
+
+/// All counters of a server
+#[derive(Debug, Default)]
+pub(crate) struct UsbIpStats {
+    devices: Mutex<HashMap<String, Arc<UrbCounters>>>,
+    connections: Mutex<HashMap<u64, Connection>>,
+    next_connection: AtomicU64,
+}
+
+impl UsbIpStats {
+    /// Counters of the device, created on first use
+    pub(crate) fn device(&self, bus_id: &str) -> Arc<UrbCounters> {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(bus_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub(crate) fn remove_device(&self, bus_id: &str) {
+        self.devices.lock().unwrap().remove(bus_id);
+    }
+
+    /// Counters of a new connection, dropped with the returned guard
+    pub(crate) fn connection(&self, peer: Option<SocketAddr>) -> ConnectionGuard<'_> {
+        let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let counters = Arc::new(UrbCounters::default());
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, (peer, counters.clone()));
+        ConnectionGuard {
+            stats: self,
+            id,
+            counters,
+        }
+    }
+}
+
+/// Keeps the counters of a connection while it is open
+pub(crate) struct ConnectionGuard<'a> {
+    stats: &'a UsbIpStats,
+    id: u64,
+    pub(crate) counters: Arc<UrbCounters>,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UsbIpServer {
+    /// Snapshot of the URB counters of every device and open connection
+    pub fn stats(&self) -> ServerStats {
+        let mut devices: Vec<_> = self
+            .stats
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(bus_id, counters)| DeviceStats {
+                bus_id: bus_id.clone(),
+                urbs: counters.snapshot(),
+            })
+            .collect();
+        devices.sort_by(|a, b| a.bus_id.cmp(&b.bus_id));
+        let mut connections: Vec<_> = self
+            .stats
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, (peer, counters))| ConnectionStats {
+                id,
+                peer: *peer,
+                urbs: counters.snapshot(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        ServerStats {
+            devices,
+            connections,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn latency_percentiles() {
+        setup_test_logger();
+        let counters = UrbCounters::default();
+        for ms in 1..=100 {
+            counters.record(ms != 100, 1, 2, Some(Duration::from_millis(ms)));
+        }
+        let stats = counters.snapshot();
+        assert_eq!(stats.submits, 100);
+        assert_eq!(stats.completions, 99);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.bytes_in, 99);
+        assert_eq!(stats.bytes_out, 198);
+        assert_eq!(stats.latency_p50, Duration::from_millis(51));
+        assert_eq!(stats.latency_p99, Duration::from_millis(100));
+        assert_eq!(UrbCounters::default().snapshot(), UrbStats::default());
+    }
+
+    #[test]
+    fn connection_stats_removed_on_drop() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]);
+        let guard = server.stats.connection(None);
+        guard.counters.record(false, 0, 0, None);
+        assert_eq!(server.stats().connections.len(), 1);
+        assert_eq!(server.stats().connections[0].urbs.errors, 1);
+        assert_eq!(
+            server.stats().connections[0].urbs.latency_p99,
+            Duration::ZERO
+        );
+        drop(guard);
+        assert!(server.stats().connections.is_empty());
+    }
+}
@@ -8,5 +8,6 @@ pub fn verify_descriptor(desc: &[u8]) {
 }
 
 #[cfg(test)]
-#[path = "../tests/common/mod.rs"]
-pub(crate) mod tests;
+pub(crate) mod tests {
+    pub(crate) use crate::test_util::*;
+}
@@ -0,0 +1,81 @@
+//! WebUSB descriptors
+//!
+//! Browsers look for the WebUSB platform capability in the BOS descriptor.
+//! It carries a vendor code, which is used to fetch the landing page URL
+//! with a vendor request. See [UsbDevice::with_webusb].
+use super::*;
+
+/// wIndex of the vendor request for an URL descriptor
+pub const WEBUSB_GET_URL: u16 = 0x0002;
+
+/// bDescriptorType of an URL descriptor
+const WEBUSB_URL: u8 = 0x03;
+
+/// {3408B638-09A9-47A0-8BFD-A0768815B665} in little endian
+const WEBUSB_PLATFORM_UUID: [u8; 16] = [
+    0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47, 0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15, 0xB6, 0x65,
+];
+
+/// WebUSB descriptors of a device
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WebUsbDescriptors {
+    /// bRequest of the vendor request used to fetch URLs
+    pub vendor_code: u8,
+    /// Landing page shown by the browser, e.g. `https://example.com`
+    pub landing_page: Option<String>,
+}
+
+impl WebUsbDescriptors {
+    pub fn new(vendor_code: u8, landing_page: Option<&str>) -> Self {
+        Self {
+            vendor_code,
+            landing_page: landing_page.map(str::to_string),
+        }
+    }
+
+    /// The WebUSB platform capability for the BOS descriptor
+    pub(crate) fn platform_capability(&self) -> Vec<u8> {
+        let data = [
+            0x00,
+            0x01,                              // bcdVersion: 1.0
+            self.vendor_code,                  // bVendorCode
+            self.landing_page.is_some() as u8, // iLandingPage
+        ];
+        platform_capability_descriptor(&WEBUSB_PLATFORM_UUID, &data)
+    }
+
+    /// URL descriptor, the scheme is encoded in bScheme
+    fn url_descriptor(url: &str) -> Vec<u8> {
+        let (scheme, url) = if let Some(url) = url.strip_prefix("https://") {
+            (0x01, url)
+        } else if let Some(url) = url.strip_prefix("http://") {
+            (0x00, url)
+        } else {
+            (0xFF, url)
+        };
+        let mut desc = vec![
+            3 + url.len() as u8, // bLength
+            WEBUSB_URL,          // bDescriptorType
+            scheme,              // bScheme
+        ];
+        desc.extend_from_slice(url.as_bytes()); // URL
+        desc
+    }
+
+    /// Answer a vendor request for an URL descriptor
+    ///
+    /// Returns `None` if the request is not for a WebUSB descriptor.
+    pub(crate) fn handle_request(&self, setup_packet: &SetupPacket) -> Option<Vec<u8>> {
+        match (
+            setup_packet.request_type,
+            setup_packet.index,
+            setup_packet.value,
+        ) {
+            (0b11000000, WEBUSB_GET_URL, 1) if setup_packet.request == self.vendor_code => {
+                self.landing_page.as_deref().map(Self::url_descriptor)
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,11 +1,16 @@
-use std::sync::{Arc, Mutex};
+#![cfg(feature = "std")]
+
+use std::sync::Arc;
 
 use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 use tokio::{net::TcpStream, task::JoinSet};
 
 mod common;
 use common::*;
-use usbip::usbip_protocol::{USBIP_CMD_SUBMIT, UsbIpCommand, UsbIpHeaderBasic, UsbIpResponse};
+use usbip::usbip_protocol::{
+    USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK, USBIP_RET_SUBMIT, USBIP_RET_UNLINK, UsbIpCommand,
+    UsbIpHeaderBasic, UsbIpResponse,
+};
 use usbip::*;
 
 const SINGLE_DEVICE_BUSID: &str = "0-0-0";
@@ -17,9 +22,7 @@ fn new_server_with_single_device() -> UsbIpServer {
         0x00,
         Some("Test CDC ACM"),
         cdc::UsbCdcAcmHandler::endpoints(),
-        Arc::new(Mutex::new(
-            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
-        )),
+        shared_interface_handler(cdc::UsbCdcAcmHandler::new()),
     )])
 }
 
@@ -75,6 +78,94 @@ async fn req_sample_devlist() {
     assert_eq!(mock_socket.output.len(), 0xC + 0x138 + 4);
 }
 
+#[tokio::test]
+async fn shard_by_bus() {
+    setup_test_logger();
+    let server = Arc::new(UsbIpServer::new_simulated(vec![
+        UsbDevice::new(0).with_location(1, &[1]),
+        UsbDevice::new(1).with_location(2, &[1]),
+    ]));
+    let shard = UsbIpShard::Buses(vec![2]);
+
+    let req = UsbIpCommand::OpReqDevlist { status: 0 };
+    let mut mock_socket = MockSocket::new(req.to_bytes());
+    shard_handler(&mut mock_socket, server.clone(), &shard)
+        .await
+        .ok();
+    // header: 0xC, one device without interfaces: 0x138
+    assert_eq!(mock_socket.output.len(), 0xC + 0x138);
+
+    // devices of other shards cannot be imported
+    let mut mock_socket = MockSocket::new(op_req_import("1-1"));
+    shard_handler(&mut mock_socket, server.clone(), &shard)
+        .await
+        .ok();
+    assert_eq!(
+        mock_socket.output,
+        UsbIpResponse::op_rep_import_fail().to_bytes()
+    );
+
+    let mut mock_socket = MockSocket::new(op_req_import("2-1"));
+    shard_handler(&mut mock_socket, server, &shard).await.ok();
+    assert_eq!(mock_socket.output.len(), 0x140);
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_server_devlist() {
+    use std::io::{Read, Write};
+
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    std::thread::spawn(move || blocking_server(addr, server, UsbIpShard::All));
+
+    let mut connection = loop {
+        if let Ok(connection) = std::net::TcpStream::connect(addr) {
+            break connection;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    connection
+        .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+        .unwrap();
+    let mut reply = vec![0; 0xC + 0x138 + 4];
+    connection.read_exact(&mut reply).unwrap();
+    // number of devices
+    assert_eq!(reply[8..12], [0, 0, 0, 1]);
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_server_idle_timeout() {
+    use std::io::Read;
+
+    setup_test_logger();
+    let server = Arc::new(
+        new_server_with_single_device().with_idle_timeout(std::time::Duration::from_millis(100)),
+    );
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    std::thread::spawn(move || blocking_server(addr, server, UsbIpShard::All));
+
+    let mut connection = loop {
+        if let Ok(connection) = std::net::TcpStream::connect(addr) {
+            break connection;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    // a silent client is disconnected instead of pinning its thread
+    connection
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(connection.read(&mut [0; 1]).unwrap(), 0);
+}
+
 #[tokio::test]
 async fn req_import() {
     setup_test_logger();
@@ -88,6 +179,23 @@ async fn req_import() {
     assert_eq!(mock_socket.output.len(), 0x140);
 }
 
+#[tokio::test]
+async fn req_import_with_debug_delay() {
+    setup_test_logger();
+    let delay = std::time::Duration::from_millis(50);
+    let server = new_server_with_single_device().with_debug_delays(UsbIpDebugDelays {
+        import: delay,
+        ..Default::default()
+    });
+
+    let req = op_req_import(SINGLE_DEVICE_BUSID);
+    let mut mock_socket = MockSocket::new(req);
+    let start = std::time::Instant::now();
+    handler(&mut mock_socket, Arc::new(server)).await.ok();
+    assert!(start.elapsed() >= delay);
+    assert_eq!(mock_socket.output.len(), 0x140);
+}
+
 #[tokio::test]
 async fn add_and_remove_10_devices() {
     setup_test_logger();
@@ -245,7 +353,7 @@ async fn req_import_get_device_desc() {
                 ep: 0,
             },
             transfer_flags: 0,
-            transfer_buffer_length: 0,
+            transfer_buffer_length: 0x40,
             start_frame: 0,
             number_of_packets: 0,
             interval: 0,
@@ -262,3 +370,1380 @@ async fn req_import_get_device_desc() {
     // OP_REQ_IMPORT + USBIP_CMD_SUBMIT + Device Descriptor
     assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
 }
+
+#[tokio::test]
+async fn req_import_get_sample_composite_config_desc() {
+    setup_test_logger();
+    let device = UsbDevice::sample_composite();
+    let busid = device.bus_id.clone();
+    let server = UsbIpServer::new_simulated(vec![device]);
+
+    let mut req = op_req_import(&busid);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0xFF,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GetDescriptor to Configuration
+            setup: [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xFF, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+
+    let mut mock_socket = MockSocket::new(req);
+    handler(&mut mock_socket, Arc::new(server)).await.ok();
+    // OP_REQ_IMPORT + USBIP_CMD_SUBMIT
+    let desc = &mock_socket.output[0x140 + 0x30..];
+    let total_length = u16::from_le_bytes([desc[2], desc[3]]) as usize;
+    assert_eq!(desc.len(), total_length);
+    // bNumInterfaces
+    assert_eq!(desc[4], 3);
+    // the interface association comes right after the configuration
+    assert_eq!(desc[9 + 1], 0x0B);
+}
+
+#[tokio::test]
+async fn negotiate_extensions() {
+    setup_test_logger();
+    let server = UsbIpServer::new_simulated(vec![]).with_extensions(vec![1, 2]);
+    let req = UsbIpCommand::OpReqExtensions {
+        status: 0,
+        extensions: vec![2, 3],
+    };
+
+    let mut mock_socket = MockSocket::new(req.to_bytes());
+    handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+    assert_eq!(
+        mock_socket.output,
+        UsbIpResponse::OpRepExtensions {
+            status: 0,
+            extensions: vec![2],
+        }
+        .to_bytes(),
+    );
+}
+
+#[tokio::test]
+async fn unlink_completed_urb() {
+    setup_test_logger();
+    let server = new_server_with_single_device();
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x40,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GetDescriptor to Device
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    let unlink_header = UsbIpHeaderBasic {
+        command: USBIP_CMD_UNLINK.into(),
+        seqnum: 2,
+        devid: 0,
+        direction: 0,
+        ep: 0,
+    };
+    req.extend(
+        UsbIpCommand::UsbIpCmdUnlink {
+            header: unlink_header.clone(),
+            unlink_seqnum: 1,
+        }
+        .to_bytes(),
+    );
+
+    let mut mock_socket = MockSocket::new(req);
+    handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+    // The URB already completed, so the unlink reports status 0 after the RET_SUBMIT
+    let header = UsbIpHeaderBasic::reply(USBIP_RET_UNLINK, &unlink_header);
+    let ret_unlink = UsbIpResponse::usbip_ret_unlink_success(&header).to_bytes();
+    assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12 + 0x30);
+    assert!(mock_socket.output.ends_with(&ret_unlink));
+}
+
+/// Never completes its URBs, like an interrupt endpoint without events
+#[derive(Debug)]
+struct PendingHandler;
+
+impl UsbInterfaceHandler for PendingHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        unreachable!()
+    }
+
+    fn submit_urb_async<'a>(
+        &'a mut self,
+        _interface: &'a UsbInterface,
+        _urb: &'a mut Urb,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>> + Send + 'a>>
+    where
+        Self: Send,
+    {
+        Box::pin(std::future::pending())
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn unlink_urb_in_flight() {
+    setup_test_logger();
+    let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+        0xFF,
+        0x00,
+        0x00,
+        None,
+        vec![UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        }],
+        shared_interface_handler(PendingHandler),
+    )]);
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 1,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 8,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 10,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    let unlink_header = UsbIpHeaderBasic {
+        command: USBIP_CMD_UNLINK.into(),
+        seqnum: 2,
+        devid: 0,
+        direction: 0,
+        ep: 0,
+    };
+    req.extend(
+        UsbIpCommand::UsbIpCmdUnlink {
+            header: unlink_header.clone(),
+            unlink_seqnum: 1,
+        }
+        .to_bytes(),
+    );
+
+    let mut mock_socket = MockSocket::new(req);
+    handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+    // The URB was cancelled: no RET_SUBMIT, and the unlink reports -ECONNRESET
+    let header = UsbIpHeaderBasic::reply(USBIP_RET_UNLINK, &unlink_header);
+    let ret_unlink = UsbIpResponse::usbip_ret_unlink_cancelled(&header).to_bytes();
+    assert_eq!(mock_socket.output.len(), 0x140 + 0x30);
+    assert!(mock_socket.output.ends_with(&ret_unlink));
+}
+
+/// A device whose interrupt IN endpoint 0x81 never completes its URBs
+fn pending_device(bus_num: u32, port: u8) -> UsbDevice {
+    UsbDevice::new(0)
+        .with_location(bus_num, &[port])
+        .with_interface(
+            0xFF,
+            0x00,
+            0x00,
+            None,
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 8,
+                interval: 10,
+            }],
+            shared_interface_handler(PendingHandler),
+        )
+}
+
+/// USBIP_CMD_SUBMIT reading endpoint 0x81, like a client polling a keyboard
+fn interrupt_in_submit(seqnum: u32) -> Vec<u8> {
+    UsbIpCommand::UsbIpCmdSubmit {
+        header: UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum,
+            devid: 0,
+            direction: 1, // IN
+            ep: 1,
+        },
+        transfer_flags: 0,
+        transfer_buffer_length: 8,
+        start_frame: 0,
+        number_of_packets: 0,
+        interval: 10,
+        setup: [0; 8],
+        data: vec![],
+        iso_packet_descriptor: vec![],
+    }
+    .to_bytes()
+}
+
+/// Wait until the client of `bus_id` submitted `urbs`
+async fn wait_for_submitted_urbs(server: &UsbIpServer, bus_id: &str, urbs: u64) {
+    while !server
+        .used_devices()
+        .await
+        .iter()
+        .any(|import| import.bus_id == bus_id && import.submitted_urbs == urbs)
+    {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn urb_in_flight_does_not_block_imports() {
+    setup_test_logger();
+    let server_ = Arc::new(UsbIpServer::new_simulated(vec![
+        pending_device(1, 1),
+        pending_device(1, 2),
+    ]));
+    let addr = get_free_address().await;
+    tokio::spawn(server(addr, server_.clone()));
+
+    let mut idle_connection = poll_connect(addr).await;
+    assert_eq!(attach_device(&mut idle_connection, "1-1").await, 0);
+    idle_connection
+        .write_all(&interrupt_in_submit(1))
+        .await
+        .unwrap();
+    wait_for_submitted_urbs(&server_, "1-1", 1).await;
+
+    let mut connection = TcpStream::connect(addr).await.unwrap();
+    let result = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        attach_device(&mut connection, "1-2"),
+    )
+    .await
+    .expect("import waits for the URB in flight");
+    assert_eq!(result, 0);
+}
+
+/// Like a host device whose configuration `n` has `n` interfaces
+#[derive(Debug)]
+struct SwitchingDevice;
+
+impl UsbDeviceHandler for SwitchingDevice {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        unreachable!()
+    }
+
+    fn configuration_interfaces(
+        &mut self,
+        configuration: u8,
+    ) -> std::io::Result<Option<Vec<UsbInterface>>> {
+        let device = (0..configuration).fold(UsbDevice::new(0), |device, _| {
+            device.with_interface(
+                0xFF,
+                0x00,
+                0x00,
+                None,
+                vec![],
+                shared_interface_handler(PendingHandler),
+            )
+        });
+        Ok(Some(device.interfaces))
+    }
+
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn set_configuration_exports_its_interfaces() {
+    setup_test_logger();
+    let mut device = pending_device(1, 1)
+        .with_device_handler(Arc::new(tokio::sync::Mutex::new(Box::new(SwitchingDevice))));
+    device.num_configurations = 2;
+    let server_ = Arc::new(UsbIpServer::new_simulated(vec![device]));
+    let addr = get_free_address().await;
+    tokio::spawn(server(addr, server_.clone()));
+
+    let mut connection = poll_connect(addr).await;
+    assert_eq!(attach_device(&mut connection, "1-1").await, 0);
+    let header = UsbIpHeaderBasic {
+        command: USBIP_CMD_SUBMIT.into(),
+        seqnum: 1,
+        devid: 0,
+        direction: 0, // OUT
+        ep: 0,
+    };
+    let set_configuration = UsbIpCommand::UsbIpCmdSubmit {
+        header: header.clone(),
+        transfer_flags: 0,
+        transfer_buffer_length: 0,
+        start_frame: 0,
+        number_of_packets: 0,
+        interval: 0,
+        setup: [0x00, 0x09, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00],
+        data: vec![],
+        iso_packet_descriptor: vec![],
+    };
+    connection
+        .write_all(&set_configuration.to_bytes())
+        .await
+        .unwrap();
+    let header = UsbIpHeaderBasic::reply(USBIP_RET_SUBMIT, &header);
+    let expected =
+        UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, 0, vec![], vec![]).to_bytes();
+    let mut reply = vec![0; expected.len()];
+    connection.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, expected);
+
+    server_.detach_device("1-1").await.unwrap();
+    let device = &server_.available_devices().await[0];
+    assert_eq!(device.configuration_value, 2);
+    assert_eq!(device.interfaces.len(), 2);
+}
+
+#[derive(Debug)]
+struct PanickingHandler;
+
+impl UsbInterfaceHandler for PanickingHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        panic!("bulk transfer");
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn replay_corpus_reports_panics() {
+    setup_test_logger();
+    let dir = std::env::temp_dir().join(format!("usbip-corpus-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut bulk_in = op_req_import(SINGLE_DEVICE_BUSID);
+    bulk_in.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 1,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x40,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    std::fs::write(dir.join("1-bulk-in"), bulk_in).unwrap();
+    std::fs::write(
+        dir.join("2-devlist"),
+        UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("3-garbage"), [0x01, 0x11, 0xff, 0xff, 0x00]).unwrap();
+
+    let devices = || {
+        vec![UsbDevice::new(0).with_interface(
+            0xFF,
+            0x00,
+            0x00,
+            None,
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            }],
+            shared_interface_handler(PanickingHandler),
+        )]
+    };
+    let outcomes: Vec<_> = replay_corpus(&dir, devices, 0x1000)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(_, outcome)| outcome)
+        .collect();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        outcomes,
+        [
+            ReplayOutcome::Panicked("bulk transfer".to_string()),
+            ReplayOutcome::Completed,
+            ReplayOutcome::Completed,
+        ]
+    );
+
+    let outcome = replay(
+        UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes(),
+        devices(),
+        0x10,
+    )
+    .await;
+    assert_eq!(outcome, ReplayOutcome::OutputLimitExceeded);
+}
+
+#[derive(Debug)]
+struct FailingHandler;
+
+impl UsbInterfaceHandler for FailingHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        Err(std::io::Error::other("device is gone"))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[derive(Debug)]
+struct ResettableDevice;
+
+impl UsbDeviceHandler for ResettableDevice {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn error_budget_takes_device_offline() {
+    setup_test_logger();
+    let server = Arc::new(
+        UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0)
+                .with_interface(
+                    0xFF,
+                    0x00,
+                    0x00,
+                    None,
+                    vec![UsbEndpoint {
+                        address: 0x81,
+                        attributes: EndpointAttributes::Bulk as u8,
+                        max_packet_size: 512,
+                        interval: 0,
+                    }],
+                    shared_interface_handler(FailingHandler),
+                )
+                .with_device_handler(shared_device_handler(ResettableDevice)),
+        ])
+        .with_error_budget(UsbErrorBudget {
+            max_failures: 1,
+            window: std::time::Duration::from_secs(60),
+            reset: true,
+        }),
+    );
+    let mut events = server.subscribe();
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    for seqnum in 1..=3 {
+        req.extend(
+            UsbIpCommand::UsbIpCmdSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_SUBMIT.into(),
+                    seqnum,
+                    devid: 0,
+                    direction: 1, // IN
+                    ep: 1,
+                },
+                transfer_flags: 0,
+                transfer_buffer_length: 0x40,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup: [0; 8],
+                data: vec![],
+                iso_packet_descriptor: vec![],
+            }
+            .to_bytes(),
+        );
+    }
+
+    let mut mock_socket = MockSocket::new(req);
+    let res = handler(&mut mock_socket, server.clone()).await;
+    assert!(matches!(res, Err(UsbIpError::Transfer(_))));
+    // OP_REP_IMPORT + two failed USBIP_RET_SUBMIT
+    assert_eq!(mock_socket.output.len(), 0x140 + 2 * 0x30);
+
+    let bus_id = SINGLE_DEVICE_BUSID.to_string();
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        UsbIpEvent::DeviceImported { .. }
+    ));
+    for _ in 0..2 {
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            UsbIpEvent::TransferError { endpoint: 0x81, .. }
+        ));
+    }
+    assert_eq!(
+        events.try_recv().unwrap(),
+        UsbIpEvent::DeviceUnavailable {
+            bus_id: bus_id.clone()
+        }
+    );
+    assert_eq!(
+        events.try_recv().unwrap(),
+        UsbIpEvent::DeviceRecovered { bus_id }
+    );
+    assert_eq!(server.available_devices().await.len(), 1);
+}
+
+/// Counts resets and the requests it handles
+#[derive(Debug, Default)]
+struct CountingResetDevice {
+    resets: u32,
+    requests: u32,
+}
+
+impl UsbDeviceHandler for CountingResetDevice {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        self.requests += 1;
+        Ok(vec![])
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        self.resets += 1;
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn device_reset_on_import_and_port_reset() {
+    setup_test_logger();
+    let device_handler = shared_device_handler(CountingResetDevice::default());
+    let server = Arc::new(
+        UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0).with_device_handler(device_handler.clone()),
+        ])
+        .with_device_reset(),
+    );
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 0, // OUT
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // SET_PORT_FEATURE(PORT_RESET)
+            setup: [0x23, 0x03, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+
+    let mut mock_socket = MockSocket::new(req);
+    handler(&mut mock_socket, server).await.ok();
+    // OP_REP_IMPORT + USBIP_RET_SUBMIT
+    assert_eq!(mock_socket.output.len(), 0x140 + 0x30);
+    // status of USBIP_RET_SUBMIT
+    assert_eq!(mock_socket.output[0x140 + 0x14..0x140 + 0x18], [0, 0, 0, 0]);
+
+    let mut device_handler = device_handler.lock().await;
+    let device_handler = device_handler
+        .as_any()
+        .downcast_mut::<CountingResetDevice>()
+        .unwrap();
+    assert_eq!(device_handler.resets, 2);
+    assert_eq!(device_handler.requests, 0);
+}
+
+#[tokio::test]
+async fn loopback_self_test() {
+    setup_test_logger();
+    let report = self_test("127.0.0.1:0".parse().unwrap()).await;
+    assert!(report.passed(), "{report}");
+    assert_eq!(report.steps.len(), 10);
+}
+
+#[cfg(feature = "vsock")]
+#[tokio::test]
+async fn vsock_devlist() {
+    use tokio_vsock::{VMADDR_CID_LOCAL, VsockAddr, VsockListener, VsockStream};
+
+    setup_test_logger();
+    // needs the vsock_loopback module, which not every host has loaded
+    let Ok(listener) = VsockListener::bind(VsockAddr::new(VMADDR_CID_LOCAL, u32::MAX)) else {
+        return;
+    };
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let server = Arc::new(new_server_with_single_device());
+    tokio::spawn(vsock_server(addr, server.clone()));
+
+    let mut stream = loop {
+        match VsockStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    stream
+        .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+        .await
+        .unwrap();
+    let expected = UsbIpResponse::op_rep_devlist(&server.available_devices().await).to_bytes();
+    let mut res = vec![0; expected.len()];
+    stream.read_exact(&mut res).await.unwrap();
+    assert_eq!(res, expected);
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn tls_devlist() {
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+
+    setup_test_logger();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("usbip-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+    let config = tls_config_from_pem(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let addr = get_free_address().await;
+    let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+    tokio::spawn(server_tls(addr, server, config));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ));
+    let stream = poll_connect(addr).await;
+    let mut stream = connector
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+
+    stream
+        .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+        .await
+        .unwrap();
+    let expected = UsbIpResponse::op_rep_devlist(&[]).to_bytes();
+    let mut res = vec![0; expected.len()];
+    stream.read_exact(&mut res).await.unwrap();
+    assert_eq!(res, expected);
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn tls_client_identities_select_devices() {
+    use rcgen::{BasicConstraints, CertificateParams, Ia5String, IsCa, KeyPair, SanType};
+    use tokio_rustls::rustls::{
+        ClientConfig, RootCertStore,
+        pki_types::{PrivateKeyDer, ServerName},
+    };
+
+    setup_test_logger();
+    let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let client_key = KeyPair::generate().unwrap();
+    let mut client_params = CertificateParams::new(vec![]).unwrap();
+    client_params.subject_alt_names = vec![SanType::URI(
+        Ia5String::try_from("spiffe://example.org/ci").unwrap(),
+    )];
+    let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+    assert_eq!(
+        client_identities(client_cert.der()),
+        ["spiffe://example.org/ci"]
+    );
+
+    let dir = std::env::temp_dir().join(format!("usbip-mtls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("cert.pem"), server_cert.cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), server_cert.key_pair.serialize_pem()).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    let config = tls_config_with_client_auth(
+        &dir.join("cert.pem"),
+        &dir.join("key.pem"),
+        &dir.join("ca.pem"),
+    )
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let addr = get_free_address().await;
+    let server = Arc::new(
+        UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0).with_location(1, &[1]).with_tag("ci"),
+            UsbDevice::new(1).with_location(1, &[2]).with_tag("release"),
+        ])
+        .with_identity_policy(|identities, device| {
+            device
+                .tag
+                .as_ref()
+                .is_some_and(|tag| identities.contains(&format!("spiffe://example.org/{tag}")))
+        }),
+    );
+    tokio::spawn(server_tls(addr, server.clone(), config));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(server_cert.cert.der().clone()).unwrap();
+    let expected = UsbIpResponse::op_rep_devlist(&server.available_devices().await[..1]).to_bytes();
+    let devlist = |config: ClientConfig| async {
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = poll_connect(addr).await;
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+        stream
+            .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+            .await?;
+        let mut res = vec![0; expected.len()];
+        stream.read_exact(&mut res).await?;
+        Ok::<_, std::io::Error>(res)
+    };
+
+    let anonymous = ClientConfig::builder()
+        .with_root_certificates(roots.clone())
+        .with_no_client_auth();
+    assert!(devlist(anonymous).await.is_err());
+
+    let ci = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(
+            vec![client_cert.der().clone()],
+            PrivateKeyDer::Pkcs8(client_key.serialize_der().into()),
+        )
+        .unwrap();
+    assert_eq!(devlist(ci).await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn unauthenticated_client_rejected() {
+    setup_test_logger();
+    let server = Arc::new(
+        new_server_with_single_device()
+            .with_authenticator(|peer| peer.is_some_and(|peer| peer.ip().is_loopback())),
+    );
+
+    let mut mock_socket = MockSocket::new(UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes());
+    let res = handler(&mut mock_socket, server.clone()).await;
+    assert!(matches!(res, Err(UsbIpError::Unauthorized(None))));
+    assert_eq!(
+        mock_socket.output,
+        UsbIpResponse::op_rep_devlist_fail().to_bytes()
+    );
+
+    let mut mock_socket = MockSocket::new(op_req_import(SINGLE_DEVICE_BUSID));
+    let peer = "127.0.0.1:1234".parse().unwrap();
+    peer_handler(&mut mock_socket, server.clone(), &UsbIpShard::All, peer)
+        .await
+        .ok();
+    // OP_REP_IMPORT with the device
+    assert_eq!(mock_socket.output.len(), 0x140);
+}
+
+#[tokio::test]
+async fn access_policy_filters_devices() {
+    setup_test_logger();
+    let server = Arc::new(
+        UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0).with_location(1, &[1]).with_tag("yubikey"),
+            UsbDevice::new(1).with_location(1, &[2]),
+        ])
+        .with_access_policy(|peer, device| {
+            device.tag.as_deref() != Some("yubikey")
+                || peer.is_some_and(|peer| peer.ip().is_loopback())
+        }),
+    );
+
+    let mut mock_socket = MockSocket::new(UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes());
+    handler(&mut mock_socket, server.clone()).await.ok();
+    assert_eq!(
+        mock_socket.output,
+        UsbIpResponse::op_rep_devlist(&server.available_devices().await[1..]).to_bytes()
+    );
+
+    let mut mock_socket = MockSocket::new(op_req_import("1-1"));
+    handler(&mut mock_socket, server.clone()).await.ok();
+    assert_eq!(
+        mock_socket.output,
+        UsbIpResponse::op_rep_import_fail().to_bytes()
+    );
+
+    let mut mock_socket = MockSocket::new(op_req_import("1-1"));
+    let peer = "127.0.0.1:1234".parse().unwrap();
+    peer_handler(&mut mock_socket, server, &UsbIpShard::All, peer)
+        .await
+        .ok();
+    assert_eq!(mock_socket.output.len(), 0x140);
+}
+
+#[tokio::test]
+async fn detach_device_ends_session() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let (mut client, mut socket) = tokio::io::duplex(0x1000);
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move { handler(&mut socket, server).await }
+    });
+
+    client
+        .write_all(&op_req_import(SINGLE_DEVICE_BUSID))
+        .await
+        .unwrap();
+    let mut reply = vec![0; 0x140];
+    client.read_exact(&mut reply).await.unwrap();
+    assert!(server.available_devices().await.is_empty());
+
+    server.detach_device(SINGLE_DEVICE_BUSID).await.unwrap();
+    assert!(session.await.unwrap().is_ok());
+    assert_eq!(client.read(&mut reply).await.unwrap(), 0);
+    assert_eq!(server.available_devices().await.len(), 1);
+    assert!(matches!(
+        server.detach_device("1-1").await,
+        Err(UsbIpError::DeviceNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn detach_device_fails_urb_in_flight() {
+    setup_test_logger();
+    let server = Arc::new(UsbIpServer::new_simulated(vec![pending_device(1, 1)]));
+    let (mut client, mut socket) = tokio::io::duplex(0x1000);
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move { handler(&mut socket, server).await }
+    });
+
+    client.write_all(&op_req_import("1-1")).await.unwrap();
+    let mut reply = vec![0; 0x140];
+    client.read_exact(&mut reply).await.unwrap();
+    client.write_all(&interrupt_in_submit(1)).await.unwrap();
+    wait_for_submitted_urbs(&server, "1-1", 1).await;
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        server.detach_device("1-1"),
+    )
+    .await
+    .expect("detach waits for the URB in flight")
+    .unwrap();
+    assert_eq!(server.available_devices().await.len(), 1);
+    assert!(session.await.unwrap().is_ok());
+
+    let header = UsbIpHeaderBasic {
+        command: USBIP_CMD_SUBMIT.into(),
+        seqnum: 1,
+        devid: 0,
+        direction: 1,
+        ep: 1,
+    };
+    let mut reply = vec![];
+    client.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(
+        reply,
+        UsbIpResponse::usbip_ret_submit_fail(&header).to_bytes()
+    );
+}
+
+#[tokio::test]
+async fn idle_timeout_releases_device() {
+    setup_test_logger();
+    let server = Arc::new(
+        new_server_with_single_device().with_idle_timeout(std::time::Duration::from_millis(50)),
+    );
+    let (mut client, mut socket) = tokio::io::duplex(0x1000);
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move { handler(&mut socket, server).await }
+    });
+
+    client
+        .write_all(&op_req_import(SINGLE_DEVICE_BUSID))
+        .await
+        .unwrap();
+    let mut reply = vec![0; 0x140];
+    client.read_exact(&mut reply).await.unwrap();
+    assert!(server.available_devices().await.is_empty());
+
+    // the client goes silent without closing the connection
+    let res = session.await.unwrap();
+    assert!(matches!(res, Err(UsbIpError::Io(err)) if err.kind() == std::io::ErrorKind::TimedOut));
+    assert_eq!(server.available_devices().await.len(), 1);
+}
+
+#[tokio::test]
+async fn events_for_import_and_release() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let mut events = server.subscribe();
+    let (mut client, mut socket) = tokio::io::duplex(0x1000);
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move { handler(&mut socket, server).await }
+    });
+
+    client
+        .write_all(&op_req_import(SINGLE_DEVICE_BUSID))
+        .await
+        .unwrap();
+    let mut reply = vec![0; 0x140];
+    client.read_exact(&mut reply).await.unwrap();
+    drop(client);
+    assert!(session.await.unwrap().is_ok());
+
+    let bus_id = SINGLE_DEVICE_BUSID.to_string();
+    assert_eq!(
+        events.recv().await.unwrap(),
+        UsbIpEvent::DeviceImported {
+            bus_id: bus_id.clone(),
+            peer: None
+        }
+    );
+    assert_eq!(
+        events.recv().await.unwrap(),
+        UsbIpEvent::DeviceReleased { bus_id }
+    );
+}
+
+#[tokio::test]
+async fn used_devices_reports_client() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let (mut client, mut socket) = tokio::io::duplex(0x1000);
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move { handler(&mut socket, server).await }
+    });
+    assert!(server.used_devices().await.is_empty());
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 5,        // no such endpoint
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    client.write_all(&req).await.unwrap();
+    let mut reply = vec![0; 0x140 + 0x30];
+    client.read_exact(&mut reply).await.unwrap();
+
+    let used = server.used_devices().await;
+    assert_eq!(used.len(), 1);
+    assert_eq!(used[0].bus_id, SINGLE_DEVICE_BUSID);
+    assert_eq!(used[0].peer, None);
+    assert!(used[0].imported_at <= std::time::SystemTime::now());
+    assert_eq!(used[0].submitted_urbs, 1);
+    assert_eq!(used[0].failed_urbs, 1);
+    assert_eq!(used[0].unlinked_urbs, 0);
+
+    drop(client);
+    assert!(session.await.unwrap().is_ok());
+    assert!(server.used_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn stats_count_urbs() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let (mut client, mut socket) = tokio::io::duplex(0x1000);
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move { handler(&mut socket, server).await }
+    });
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x12,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GET_DESCRIPTOR of the device descriptor
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    client.write_all(&req).await.unwrap();
+    let mut reply = vec![0; 0x140 + 0x30 + 0x12];
+    client.read_exact(&mut reply).await.unwrap();
+
+    let stats = server.stats();
+    assert_eq!(stats.devices.len(), 1);
+    assert_eq!(stats.devices[0].bus_id, SINGLE_DEVICE_BUSID);
+    assert_eq!(stats.connections.len(), 1);
+    for urbs in [stats.devices[0].urbs, stats.connections[0].urbs] {
+        assert_eq!(urbs.submits, 1);
+        assert_eq!(urbs.completions, 1);
+        assert_eq!(urbs.errors, 0);
+        assert_eq!(urbs.bytes_in, 0x12);
+        assert_eq!(urbs.bytes_out, 0);
+    }
+
+    drop(client);
+    assert!(session.await.unwrap().is_ok());
+    let stats = server.stats();
+    assert!(stats.connections.is_empty());
+    assert_eq!(stats.devices[0].urbs.submits, 1);
+}
+
+#[tokio::test]
+async fn builder_serves_every_address() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let addrs = [get_free_address().await, get_free_address().await];
+    tokio::spawn(
+        UsbIpServerBuilder::new()
+            .listen(addrs[0])
+            .listen(addrs[1])
+            .nodelay(true)
+            .backlog(16)
+            .max_connections(4)
+            .urb_timeout(std::time::Duration::from_secs(1))
+            .read_buffer_size(0x1000)
+            .serve(server),
+    );
+
+    for addr in addrs {
+        let mut connection = poll_connect(addr).await;
+        connection
+            .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+            .await
+            .unwrap();
+        let mut header = [0; 12];
+        connection.read_exact(&mut header).await.unwrap();
+        assert_eq!(u32::from_be_bytes(header[8..12].try_into().unwrap()), 1);
+    }
+}
+
+#[tokio::test]
+async fn builder_without_address() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let res = UsbIpServerBuilder::new().serve(server).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn listeners_can_be_disabled() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let path = std::env::temp_dir().join(format!("usbip-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listeners = UsbIpServerBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .listen_unix(&path)
+        .start(server)
+        .unwrap();
+    let addrs = listeners.addrs();
+    let ListenAddr::Tcp(tcp_addr) = addrs[0] else {
+        panic!("{addrs:?}");
+    };
+    assert_ne!(tcp_addr.port(), 0);
+    assert_eq!(addrs[1], ListenAddr::Unix(path.clone()));
+
+    let devlist = UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes();
+    let mut unix = tokio::net::UnixStream::connect(&path).await.unwrap();
+    unix.write_all(&devlist).await.unwrap();
+    let mut header = [0; 12];
+    unix.read_exact(&mut header).await.unwrap();
+    assert_eq!(u32::from_be_bytes(header[8..12].try_into().unwrap()), 1);
+
+    assert!(listeners.set_enabled(&addrs[0], false));
+    assert_eq!(listeners.is_enabled(&addrs[0]), Some(false));
+    let mut tcp = TcpStream::connect(tcp_addr).await.unwrap();
+    let mut buf = vec![];
+    assert_eq!(tcp.read_to_end(&mut buf).await.unwrap_or(0), 0);
+
+    assert!(listeners.set_enabled(&addrs[0], true));
+    let mut tcp = TcpStream::connect(tcp_addr).await.unwrap();
+    tcp.write_all(&devlist).await.unwrap();
+    tcp.read_exact(&mut header).await.unwrap();
+
+    // the open connection outlives the listeners
+    drop(listeners);
+    unix.write_all(&devlist).await.unwrap();
+    unix.read_exact(&mut header).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn recorded_session_replays() {
+    setup_test_logger();
+    let dir = std::env::temp_dir().join(format!("usbip-recordings-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let addr = get_free_address().await;
+    tokio::spawn(
+        UsbIpServerBuilder::new()
+            .listen(addr)
+            .record_sessions(&dir)
+            .serve(Arc::new(new_server_with_single_device())),
+    );
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x40,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GetDescriptor to Device
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    let mut connection = poll_connect(addr).await;
+    connection.write_all(&req).await.unwrap();
+    let mut replies = vec![0; 0x140 + 0x30 + 0x12];
+    connection.read_exact(&mut replies).await.unwrap();
+    drop(connection);
+
+    // the recording is complete once the session ends
+    let recording = loop {
+        if let Some(entry) = std::fs::read_dir(&dir).unwrap().next() {
+            let recording = UsbIpRecording::from_file(&entry.unwrap().path()).unwrap_or_default();
+            if recording.replies.len() == replies.len() {
+                break recording;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(recording.commands, req);
+    assert_eq!(recording.replies, replies);
+
+    let devices = new_server_with_single_device().available_devices().await;
+    let replay = replay_recording(&recording, devices, 0x1000).await;
+    assert!(replay.matches());
+
+    let mut changed = new_server_with_single_device().available_devices().await;
+    changed[0].product_id += 1;
+    let replay = replay_recording(&recording, changed, 0x1000).await;
+    assert_eq!(replay.outcome, ReplayOutcome::Completed);
+    assert!(replay.first_mismatch.is_some());
+}
+
+#[tokio::test]
+async fn capture_device_urbs() {
+    setup_test_logger();
+    let server = Arc::new(new_server_with_single_device());
+    let path = std::env::temp_dir().join(format!("usbip-capture-{}.pcap", std::process::id()));
+    let capture = Arc::new(UsbmonCapture::create(&path).unwrap());
+    server.capture_device(SINGLE_DEVICE_BUSID, Some(capture));
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x40,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GetDescriptor to Device
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    let mut mock_socket = MockSocket::new(req);
+    handler(&mut mock_socket, server.clone()).await.ok();
+    server.capture_device(SINGLE_DEVICE_BUSID, None);
+
+    let pcap = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // pcap header, then submission and completion with the device descriptor
+    assert_eq!(pcap.len(), 24 + (16 + 64) + (16 + 64 + 0x12));
+    assert_eq!(pcap[24 + 16 + 8], b'S');
+    assert_eq!(pcap[24 + 2 * 16 + 64 + 8], b'C');
+}
+
+#[tokio::test]
+async fn shaping_delays_urbs() {
+    setup_test_logger();
+    let device = new_server_with_single_device().available_devices().await[0]
+        .clone()
+        .with_shaping(UsbLinkShaping {
+            bytes_per_second: Some(0x12 * 10),
+            latency: std::time::Duration::from_millis(50),
+        });
+    let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+
+    let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+    req.extend(
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1, // IN
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x40,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GetDescriptor to Device
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes(),
+    );
+    let start = std::time::Instant::now();
+    let mut mock_socket = MockSocket::new(req);
+    handler(&mut mock_socket, server).await.ok();
+    // 50ms of latency and 100ms for the 18 bytes of the descriptor
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
+}
@@ -0,0 +1,79 @@
+//! Allocations with and without the scratch pool
+//!
+//! A binary of its own, as the counting allocator replaces the allocator of
+//! every test in it.
+#![cfg(feature = "std")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+mod common;
+use common::*;
+use usbip::usbip_protocol::{USBIP_CMD_SUBMIT, UsbIpHeaderBasic, UsbIpResponse};
+use usbip::*;
+
+/// Counts the allocations of each thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // unavailable while the thread exits
+        ALLOCATIONS
+            .try_with(|count| count.set(count.get() + 1))
+            .ok();
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// Serialize a RET_SUBMIT with 64 bytes of data, like interrupt polling
+fn ret_submit(recycle: bool) {
+    let header = UsbIpHeaderBasic {
+        command: USBIP_CMD_SUBMIT.into(),
+        seqnum: 1,
+        devid: 0,
+        direction: 1,
+        ep: 1,
+    };
+    let mut data = if recycle {
+        take_scratch_buffer(64)
+    } else {
+        Vec::with_capacity(64)
+    };
+    data.extend_from_slice(&[0x55; 64]);
+    let response = UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, 0, data, vec![]);
+    let bytes = std::hint::black_box(response.to_bytes());
+    if recycle {
+        recycle_scratch_buffer(bytes);
+        response.recycle();
+    }
+}
+
+#[test]
+fn pool_avoids_allocations() {
+    setup_test_logger();
+    // the pool of this thread is still empty
+    assert_eq!(
+        allocations(|| (0..100).for_each(|_| ret_submit(false))),
+        200
+    );
+    // until the pool holds buffers for both the data and the response
+    (0..4).for_each(|_| ret_submit(true));
+    assert_eq!(allocations(|| (0..100).for_each(|_| ret_submit(true))), 0);
+}